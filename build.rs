@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 fn main() {
     // set by cargo, build scripts should use this directory for output files
@@ -18,11 +18,17 @@ fn main() {
     // let uefi_path = out_dir.join("uefi.img");
     // bootloader::UefiBoot::new(&kernel).create_disk_image(&uefi_path).unwrap();
 
-    // create a BIOS disk image
+    // create a BIOS disk image, with the cpio archive at $INITRAMFS (if set) loaded alongside the
+    // kernel as its initramfs
     let bios_path = out_dir.join("bios.img");
-    bootloader::BiosBoot::new(&kernel)
-        .create_disk_image(&bios_path)
-        .unwrap();
+    let mut bios = bootloader::BiosBoot::new(&kernel);
+    println!("cargo:rerun-if-env-changed=INITRAMFS");
+    if let Some(initramfs) = std::env::var_os("INITRAMFS") {
+        let initramfs = Path::new(&initramfs);
+        println!("cargo:rerun-if-changed={}", initramfs.display());
+        bios.set_ramdisk(initramfs);
+    }
+    bios.create_disk_image(&bios_path).unwrap();
 
     // pass the disk image paths as env variables to the `main.rs`
     // println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
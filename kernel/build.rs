@@ -0,0 +1,100 @@
+use std::env;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Works out what goes in klib/version.rs and hands it over as environment variables, so that a
+// kernel image can say which commit and toolchain it was built from.
+fn main() {
+    // Only rerun when the checked out commit or the index changes, otherwise every build would
+    // rebuild the kernel for a new timestamp
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/index");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    println!("cargo:rustc-env=KERNEL_GIT_HASH={}", git_hash());
+    println!("cargo:rustc-env=KERNEL_BUILD_TIME={}", build_time());
+    println!("cargo:rustc-env=KERNEL_RUSTC_VERSION={}", rustc_version());
+    println!("cargo:rustc-env=KERNEL_FEATURES={}", features());
+}
+
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// The short hash of HEAD, with "-dirty" on the end if there are uncommitted changes
+fn git_hash() -> String {
+    let Some(hash) = output("git", &["rev-parse", "--short", "HEAD"]) else {
+        return "unknown".to_string();
+    };
+
+    match output("git", &["status", "--porcelain", "--untracked-files=no"]) {
+        Some(status) if status.is_empty() => hash,
+        _ => format!("{}-dirty", hash),
+    }
+}
+
+// UTC, as YYYY-MM-DD HH:MM:SS. SOURCE_DATE_EPOCH overrides the clock for reproducible builds.
+fn build_time() -> String {
+    let seconds = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs())
+        });
+
+    let (days, time) = (seconds / 86400, seconds % 86400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+// Days since 1970-01-01 to a date in the proleptic Gregorian calendar, after Howard Hinnant's
+// `civil_from_days`
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn rustc_version() -> String {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string())
+}
+
+// The enabled features, named like in Cargo.toml and separated by spaces
+fn features() -> String {
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| {
+            let feature = name.strip_prefix("CARGO_FEATURE_")?;
+            (feature != "DEFAULT").then(|| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    features.join(" ")
+}
@@ -0,0 +1,106 @@
+// Memory for what boot needs before the heap is up, sized to what the machine turns out to have
+// rather than to a guess made when the kernel was built. The memory maps are the main users: the
+// frame allocator, and so the heap, is built from them. Allocations are carved one after the other
+// out of a region in the kernel image and never freed.
+//
+// `seal` is the hand-off, once the heap is up: from then on nothing more is handed out, and the
+// region is mapped read-only, so whatever boot left in it can't be scribbled over. Anything that
+// has to change later belongs on the heap.
+
+use crate::log_info;
+use core::cell::UnsafeCell;
+use core::mem::{align_of, size_of};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+/// How many bytes there are to hand out before the heap is up.
+pub const ARENA_SIZE: usize = 64 * 1024;
+
+const PAGE_SIZE: usize = 4096;
+
+// Page aligned and a whole number of pages, so that making it read-only doesn't take anything
+// else with it
+#[repr(C, align(4096))]
+struct Arena(UnsafeCell<[u8; ARENA_SIZE]>);
+
+// What's handed out never overlaps
+unsafe impl Sync for Arena {}
+
+static ARENA: Arena = Arena(UnsafeCell::new([0; ARENA_SIZE]));
+
+// How much of the arena has been handed out, or None once it has been sealed
+static NEXT: Mutex<Option<usize>> = Mutex::new(Some(0));
+
+/// Put everything `items` yields somewhere it stays for good. Fails if there isn't room left, or
+/// the heap is up.
+pub fn collect<T>(items: impl ExactSizeIterator<Item = T>) -> Result<&'static [T], ()> {
+    collect_with(items, |_| ())
+}
+
+/// `collect`, with `init` getting to change the items before they're handed out. Nothing can
+/// change them afterwards, as `seal` makes them read-only.
+pub fn collect_with<T>(
+    items: impl ExactSizeIterator<Item = T>,
+    init: impl FnOnce(&mut [T]),
+) -> Result<&'static [T], ()> {
+    let len = items.len();
+    let size = size_of::<T>().checked_mul(len).ok_or(())?;
+    if align_of::<T>() > PAGE_SIZE {
+        return Err(());
+    }
+
+    let offset = interrupts::without_interrupts(|| {
+        let mut next = NEXT.lock();
+        let start = next.ok_or(())?.next_multiple_of(align_of::<T>());
+        let end = start
+            .checked_add(size)
+            .filter(|&end| end <= ARENA_SIZE)
+            .ok_or(())?;
+        *next = Some(end);
+        Ok(start)
+    })?;
+
+    let start = unsafe { ARENA.0.get().cast::<u8>().add(offset).cast::<T>() };
+    let mut written = 0;
+    for item in items.take(len) {
+        unsafe { start.add(written).write(item) };
+        written += 1;
+    }
+    let slice = unsafe { core::slice::from_raw_parts_mut(start, written) };
+    init(slice);
+    Ok(slice)
+}
+
+/// Stop handing out memory and make what was handed out read-only. Called once the heap is up.
+pub fn seal(mapper: &mut OffsetPageTable) {
+    let used = interrupts::without_interrupts(|| NEXT.lock().take()).unwrap_or(0);
+
+    let start = VirtAddr::from_ptr(ARENA.0.get());
+    let first: Page<Size4KiB> = Page::containing_address(start);
+    let last: Page<Size4KiB> = Page::containing_address(start + ARENA_SIZE as u64);
+    let mut protected = 0;
+    for page in Page::range(first, last) {
+        // Huge pages are left as they are, along with whatever else is in them
+        let TranslateResult::Mapped { mut flags, .. } = mapper.translate(page.start_address())
+        else {
+            continue;
+        };
+        flags.remove(PageTableFlags::WRITABLE);
+        if let Ok(flush) = unsafe { mapper.update_flags(page, flags) } {
+            flush.flush();
+            protected += 1;
+        }
+    }
+
+    log_info!(
+        "Boot used {} of {} early bytes, {} pages of them made read-only",
+        used,
+        ARENA_SIZE,
+        protected
+    );
+}
@@ -0,0 +1,133 @@
+// Kernel address sanitizer-lite, enabled with the `kasan` feature.
+//
+// Every allocation gets a red zone on either side, filled with a known pattern and checked when
+// the allocation is freed, which catches small overflows and underflows. The left red zone also
+// holds a header with the requested size, so double frees and frees of pointers the allocator
+// never handed out show up too.
+//
+// Freed memory (and the whole heap, at startup) is filled with a poison pattern. Reads through a
+// dangling pointer then see obviously bogus values, and since neither allocator keeps its
+// bookkeeping inside the blocks it hands out, any write after free is caught the next time the
+// memory is allocated and found not to be poisoned anymore.
+
+use core::alloc::Layout;
+use core::cmp::max;
+use core::mem::size_of;
+
+const REDZONE_SIZE: usize = 32;
+const REDZONE_BYTE: u8 = 0xFC;
+pub const POISON_BYTE: u8 = 0x6B;
+
+// Marks a live allocation's header. Freed headers are poisoned like the rest of the memory.
+const HEADER_MAGIC: u64 = 0x4B41_5341_4E5F_4844;
+const POISON_WORD: u64 = u64::from_ne_bytes([POISON_BYTE; 8]);
+
+#[repr(C)]
+struct Header {
+    size: u64,
+    magic: u64,
+}
+
+fn left_redzone(layout: Layout) -> usize {
+    max(REDZONE_SIZE, layout.align())
+}
+
+/// The layout to get from the underlying allocator for `layout`, red zones included.
+pub fn outer_layout(layout: Layout) -> Option<Layout> {
+    let size = left_redzone(layout)
+        .checked_add(layout.size())?
+        .checked_add(REDZONE_SIZE)?;
+    Layout::from_size_align(size, max(layout.align(), 16)).ok()
+}
+
+// The first byte in `[start, start + len)` that isn't `byte`
+unsafe fn find_mismatch(start: *const u8, len: usize, byte: u8) -> Option<*const u8> {
+    (0..len).map(|i| start.add(i)).find(|&ptr| *ptr != byte)
+}
+
+/// Check that the memory from the underlying allocator hasn't been written to since it was
+/// freed, then set up the red zones. Returns the pointer to hand out.
+/// ### Safety
+/// `base` has to come from the underlying allocator with `outer_layout(layout)`.
+pub unsafe fn on_alloc(base: *mut u8, layout: Layout) -> *mut u8 {
+    let left = left_redzone(layout);
+    let total = left + layout.size() + REDZONE_SIZE;
+
+    if let Some(ptr) = find_mismatch(base, total, POISON_BYTE) {
+        panic!(
+            "kasan: use after free, {:#x} was written to while free (reallocated as {} bytes at {:#x})",
+            ptr as usize,
+            layout.size(),
+            base.add(left) as usize
+        );
+    }
+
+    base.add(size_of::<Header>())
+        .write_bytes(REDZONE_BYTE, left - size_of::<Header>());
+    base.add(left + layout.size())
+        .write_bytes(REDZONE_BYTE, REDZONE_SIZE);
+
+    (base as *mut Header).write(Header {
+        size: layout.size() as u64,
+        magic: HEADER_MAGIC,
+    });
+
+    base.add(left)
+}
+
+/// Check the red zones around an allocation being freed and poison all of it. Returns the
+/// pointer to give back to the underlying allocator.
+/// ### Safety
+/// `ptr` has to be a pointer returned by `on_alloc` with the same layout.
+pub unsafe fn on_dealloc(ptr: *mut u8, layout: Layout) -> *mut u8 {
+    let left = left_redzone(layout);
+    let base = ptr.sub(left);
+    let header = &*(base as *const Header);
+
+    match header.magic {
+        HEADER_MAGIC => {}
+        POISON_WORD => panic!(
+            "kasan: double free of {} bytes at {:#x}",
+            layout.size(),
+            ptr as usize
+        ),
+        _ => panic!(
+            "kasan: freeing {:#x}, which isn't an allocation or had its header overwritten",
+            ptr as usize
+        ),
+    }
+
+    if header.size != layout.size() as u64 {
+        panic!(
+            "kasan: freeing {} bytes at {:#x}, but it was allocated with {} bytes",
+            layout.size(),
+            ptr as usize,
+            header.size
+        );
+    }
+
+    let left_zone = base.add(size_of::<Header>());
+    if let Some(bad) = find_mismatch(left_zone, left - size_of::<Header>(), REDZONE_BYTE) {
+        panic!(
+            "kasan: heap underflow at {:#x}, {} bytes before the {} byte allocation at {:#x}",
+            bad as usize,
+            ptr as usize - bad as usize,
+            layout.size(),
+            ptr as usize
+        );
+    }
+
+    let right_zone = ptr.add(layout.size());
+    if let Some(bad) = find_mismatch(right_zone, REDZONE_SIZE, REDZONE_BYTE) {
+        panic!(
+            "kasan: heap overflow at {:#x}, {} bytes past the {} byte allocation at {:#x}",
+            bad as usize,
+            bad as usize - right_zone as usize,
+            layout.size(),
+            ptr as usize
+        );
+    }
+
+    base.write_bytes(POISON_BYTE, left + layout.size() + REDZONE_SIZE);
+    base
+}
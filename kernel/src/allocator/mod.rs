@@ -1,39 +1,78 @@
+pub mod early;
+#[cfg(feature = "kasan")]
+mod kasan;
 mod sleb;
 
 // use crate::println;
+use crate::arch::x86_64::paging;
+use crate::arch::x86_64::paging::{BootInfoFrameAllocator, MappingSize};
+use crate::klib::containers::static_vec::StaticVec;
+use crate::klib::layout;
+use alloc::boxed::Box;
 use core::alloc::GlobalAlloc;
 use core::alloc::Layout;
 use core::cmp::max;
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut, Range};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
 use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
-    },
+    structures::paging::{mapper::MapToError, OffsetPageTable, PageTableFlags, Size4KiB},
     VirtAddr,
 };
 
-pub const HEAP_START: u64 = 0x_4444_4444_0000u64;
 const KB: u64 = 1024;
 pub const HEAP_SIZE: u64 = 4096 * KB;
-pub const HEAP_END: u64 = HEAP_START + HEAP_SIZE;
+
+// The heap goes somewhere in here, aligned to its size, so that every block is aligned to its own
+// size and the heap can be mapped with huge pages
+const HEAP_RANGE: Range<u64> = 0x_4444_0000_0000..0x_4544_0000_0000;
+
+// Where `init_heap` put the heap
+static HEAP_START: AtomicU64 = AtomicU64::new(0);
 
 pub const PAGESIZE: u64 = 4096;
 const BLOCK_SIZE: u64 = PAGESIZE;
 
 const START_ORDER: u16 = PAGESIZE.ilog2() as u16;
 
-const NUM_ORDERS: u16 = HEAP_SIZE.ilog2() as u16 - START_ORDER;
+// The largest order is the whole heap
+const NUM_ORDERS: u16 = HEAP_SIZE.ilog2() as u16 - START_ORDER + 1;
 const NUM_BLOCKS: u64 = HEAP_SIZE / 4096;
 
 const NO_BLOCK: u16 = 0xFFFF;
 
+// In debug builds, the heap is checked over every this many allocations
+#[cfg(debug_assertions)]
+const VALIDATE_INTERVAL: u64 = 1024;
+
+#[cfg(debug_assertions)]
+static NUM_ALLOCS: AtomicU64 = AtomicU64::new(0);
+
+const MAX_RECLAIMERS: usize = 8;
+
+/// Frees memory some subsystem can do without, e.g. clean cached blocks, when the heap runs out,
+/// and returns roughly how many bytes it freed. It runs inside the allocation that failed, which
+/// may have been made with any lock held, so it must not wait on a lock (try it, and give up if
+/// it's taken) and shouldn't allocate.
+pub type Reclaim = fn() -> usize;
+
+static RECLAIMERS: spin::Mutex<StaticVec<(&'static str, Reclaim), MAX_RECLAIMERS>> =
+    spin::Mutex::new(StaticVec::new());
+
+// Set while the reclaimers run, so that an allocation failing in one of them doesn't start over
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+
 #[global_allocator]
 static ALLOCATOR: Locked<BuddyAllocator> = Locked::new(BuddyAllocator::new());
 
 lazy_static! {
     static ref SLEB_ALLOCATOR: Locked<&'static mut sleb::SlebMetadataPage> = {
         unsafe {
-            let ptr = ALLOCATOR.alloc(Layout::from_size_align_unchecked(1048576, 1));
+            let ptr = ALLOCATOR.alloc_block(Layout::from_size_align_unchecked(1048576, 1));
             let sleb = sleb::SlebMetadataPage::init(ptr);
 
             Locked::new(&mut *(sleb))
@@ -52,8 +91,64 @@ impl<A> Locked<A> {
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
-        self.inner.lock()
+    /// Lock it, with interrupts off until the guard is dropped. Otherwise the timer could preempt
+    /// the holder for a higher-priority task that then spins on the lock for good, or an interrupt
+    /// handler could allocate and spin on it itself.
+    pub fn lock(&self) -> LockedGuard<A> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        LockedGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            enabled,
+        }
+    }
+
+    /// Lock it if no one else has, like `lock`.
+    pub fn try_lock(&self) -> Option<LockedGuard<A>> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(LockedGuard {
+                guard: ManuallyDrop::new(guard),
+                enabled,
+            }),
+            None => {
+                if enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+}
+
+pub struct LockedGuard<'a, A> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, A>>,
+    // Whether interrupts were on before locking, and go back on after
+    enabled: bool,
+}
+
+impl<A> Deref for LockedGuard<'_, A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.guard
+    }
+}
+
+impl<A> DerefMut for LockedGuard<'_, A> {
+    fn deref_mut(&mut self) -> &mut A {
+        &mut self.guard
+    }
+}
+
+impl<A> Drop for LockedGuard<'_, A> {
+    fn drop(&mut self) {
+        // Unlocked before interrupts go back on
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.enabled {
+            interrupts::enable();
+        }
     }
 }
 
@@ -77,11 +172,11 @@ impl Block {
 
     fn index_to_ptr(index: u16) -> *mut u8 {
         let index_64 = index as u64;
-        return (HEAP_START + index_64 * BLOCK_SIZE) as *mut u8;
+        return (heap_start() + index_64 * BLOCK_SIZE) as *mut u8;
     }
 
     fn get_buddy_index(order: u16, address: u64) -> Option<u16> {
-        let shifted_address = address - HEAP_START;
+        let shifted_address = address - heap_start();
         let is_lower = shifted_address % (1 << (order + 1 + START_ORDER)) == 0;
         let buddy_address = if is_lower {
             shifted_address.checked_add(1 << (order + START_ORDER))
@@ -92,7 +187,7 @@ impl Block {
         match buddy_address {
             None => None,
             Some(address) => {
-                if address + HEAP_START < HEAP_END {
+                if address < HEAP_SIZE {
                     Some((address / PAGESIZE) as u16)
                 } else {
                     None
@@ -128,7 +223,7 @@ impl BuddyAllocator {
 
     fn get_block_index(block_ptr: *mut u8) -> usize {
         let block_addr = block_ptr as u64;
-        let block_offset = block_addr - HEAP_START;
+        let block_offset = block_addr - heap_start();
         (block_offset / PAGESIZE) as usize
     }
 
@@ -144,7 +239,12 @@ impl BuddyAllocator {
         self.blocks[head_index as usize].free = false;
 
         let head = self.blocks[head_index as usize];
-        self.heads[order as usize] = head.next; // crash, index 256, len 10
+        self.heads[order as usize] = head.next;
+        // The new head would otherwise still point back at the block that was handed out
+        if head.next != NO_BLOCK {
+            self.blocks[head.next as usize].previous = NO_BLOCK;
+            self.blocks[head_index as usize].next = NO_BLOCK;
+        }
 
         Some(head_index)
     }
@@ -183,6 +283,98 @@ impl BuddyAllocator {
             self.blocks[next_index as usize].previous = prev_index;
         }
     }
+
+    // Walk the free lists, checking that every free block is where its order says it should be,
+    // that the lists are linked both ways, that no two free blocks overlap, and that no free block
+    // has a free buddy it should have been merged with.
+    fn validate(&self) -> Result<(), HeapError> {
+        // Which pages are covered by a free block
+        let mut covered = [0u64; NUM_BLOCKS as usize / 64];
+        let mut num_free = 0;
+
+        for (order, &head) in self.heads.iter().enumerate() {
+            let order = order as u16;
+            let mut previous = NO_BLOCK;
+            let mut index = head;
+
+            while index != NO_BLOCK {
+                let error = |problem| {
+                    Err(HeapError {
+                        block: index,
+                        problem,
+                    })
+                };
+                let pages = 1u64 << order;
+
+                if index as u64 % pages != 0 || index as u64 + pages > NUM_BLOCKS {
+                    return error("misaligned for its order, or past the end of the heap");
+                }
+
+                let block = self.blocks[index as usize];
+                if !block.free {
+                    return error("on a free list but not marked free");
+                }
+                if block.order != order {
+                    return error("on the free list of another order");
+                }
+                if block.previous != previous {
+                    return error("previous link doesn't point back along the list");
+                }
+
+                for page in index as usize..(index as u64 + pages) as usize {
+                    if covered[page / 64] & (1 << (page % 64)) != 0 {
+                        return error("overlaps another free block");
+                    }
+                    covered[page / 64] |= 1 << (page % 64);
+                }
+
+                if let Some(buddy) =
+                    Block::get_buddy_index(order, Block::index_to_ptr(index) as u64)
+                {
+                    let buddy = self.blocks[buddy as usize];
+                    if buddy.free && buddy.order == order {
+                        return error("buddy is free too but they weren't merged");
+                    }
+                }
+
+                // A list with a cycle in it would go on forever
+                num_free += 1;
+                if num_free > NUM_BLOCKS {
+                    return error("free lists loop back on themselves");
+                }
+
+                previous = index;
+                index = block.next;
+            }
+        }
+
+        // Anything else marked free is lost: it can never be handed out again
+        for (index, block) in self.blocks.iter().enumerate() {
+            if block.free && covered[index / 64] & (1 << (index % 64)) == 0 {
+                return Err(HeapError {
+                    block: index as u16,
+                    problem: "marked free but on no free list",
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A broken invariant `validate` found in the buddy allocator's metadata.
+#[derive(Debug)]
+pub struct HeapError {
+    /// Index of the block (in pages from the start of the heap) the problem was found at
+    pub block: u16,
+    pub problem: &'static str,
+}
+
+impl fmt::Display for HeapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let address = Block::index_to_ptr(self.block) as u64;
+        write!(f, "block {} ({:#x}): {}", self.block, address, self.problem)
+    }
 }
 
 fn round_up_pow2(mut num: u64) -> u64 {
@@ -196,8 +388,18 @@ fn round_up_pow2(mut num: u64) -> u64 {
     num + 1
 }
 
-unsafe impl GlobalAlloc for Locked<BuddyAllocator> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+impl Locked<BuddyAllocator> {
+    // Allocate straight from the slab and buddy allocators, without any sanitizer red zones
+    unsafe fn alloc_block(&self, layout: Layout) -> *mut u8 {
+        // With the allocator unlocked again, as the panic handler may allocate
+        #[cfg(debug_assertions)]
+        if NUM_ALLOCS.fetch_add(1, Ordering::Relaxed) % VALIDATE_INTERVAL == VALIDATE_INTERVAL - 1 {
+            let result = self.lock().validate();
+            if let Err(error) = result {
+                panic!("Heap is corrupted: {}", error);
+            }
+        }
+
         let size = max(layout.size(), layout.align());
         if size <= 2048 {
             let mut sleb_alloc = SLEB_ALLOCATOR.lock();
@@ -241,7 +443,7 @@ unsafe impl GlobalAlloc for Locked<BuddyAllocator> {
         0u64 as *mut u8
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+    unsafe fn dealloc_block(&self, ptr: *mut u8) {
         {
             let mut sleb = SLEB_ALLOCATOR.lock();
             if sleb.within_bounds(ptr) {
@@ -249,41 +451,245 @@ unsafe impl GlobalAlloc for Locked<BuddyAllocator> {
             }
         };
 
-        let block_index = BuddyAllocator::get_block_index(ptr);
-        let block_addr = ptr as u64;
+        let mut block_index = BuddyAllocator::get_block_index(ptr) as u16;
         let mut allocator = self.lock();
-        let mut order = allocator.blocks[block_index].order;
+        let mut order = allocator.blocks[block_index as usize].order;
 
-        while let Some(buddy_index) = Block::get_buddy_index(order, block_addr) {
-            if !allocator.blocks[buddy_index as usize].free {
+        // The buddy has to be free as a whole: a free block at the same index but of a lower
+        // order is only part of it. Once merged, the block starts at whichever of the two is lower.
+        while let Some(buddy_index) =
+            Block::get_buddy_index(order, Block::index_to_ptr(block_index) as u64)
+        {
+            let buddy = allocator.blocks[buddy_index as usize];
+            if !buddy.free || buddy.order != order {
                 break;
             }
             allocator.remove_block(order, buddy_index);
+            block_index = block_index.min(buddy_index);
             order += 1;
         }
 
-        allocator.push_block(order, block_index as u16);
+        allocator.push_block(order, block_index);
     }
 }
 
+impl Locked<BuddyAllocator> {
+    // Allocate, and if the heap is full, have the reclaimers free what they can and try again
+    // after each one that freed anything. Null if that still isn't enough.
+    unsafe fn alloc_or_reclaim(&self, layout: Layout) -> *mut u8 {
+        let mut ptr = self.alloc_sanitized(layout);
+        if !ptr.is_null() || RECLAIMING.swap(true, Ordering::Acquire) {
+            return ptr;
+        }
+
+        // A copy, as a reclaimer may free something that was registered as one
+        let reclaimers = match RECLAIMERS.try_lock() {
+            Some(reclaimers) => reclaimers.clone(),
+            None => StaticVec::new(),
+        };
+        for &(_, reclaim) in reclaimers.iter() {
+            if reclaim() > 0 {
+                ptr = self.alloc_sanitized(layout);
+                if !ptr.is_null() {
+                    break;
+                }
+            }
+        }
+
+        RECLAIMING.store(false, Ordering::Release);
+        ptr
+    }
+
+    unsafe fn alloc_sanitized(&self, layout: Layout) -> *mut u8 {
+        #[cfg(not(feature = "kasan"))]
+        return self.alloc_block(layout);
+
+        #[cfg(feature = "kasan")]
+        {
+            let Some(outer) = kasan::outer_layout(layout) else {
+                return 0u64 as *mut u8;
+            };
+
+            let base = self.alloc_block(outer);
+            if base.is_null() {
+                return base;
+            }
+
+            kasan::on_alloc(base, layout)
+        }
+    }
+}
+
+// Everything that goes through the global allocator is taken to be unable to cope with running
+// out, `Vec::try_reserve` included, so a failed allocation panics here with what the heap looks
+// like, rather than coming back null. Only `try_alloc` and `try_box_zeroed` can fail.
+unsafe impl GlobalAlloc for Locked<BuddyAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.alloc_or_reclaim(layout);
+        if ptr.is_null() {
+            out_of_memory(layout);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(not(feature = "kasan"))]
+        let _ = layout;
+
+        #[cfg(feature = "kasan")]
+        let ptr = kasan::on_dealloc(ptr, layout);
+
+        self.dealloc_block(ptr);
+    }
+}
+
+/// Bytes sitting in the buddy allocator's free lists. Small allocations come out of the slab
+/// allocator's pages, which count as used here. Returns None if the allocator is locked, e.g.
+/// when called while panicking in the middle of an allocation.
+pub fn free_bytes() -> Option<u64> {
+    let allocator = ALLOCATOR.try_lock()?;
+    let mut free = 0;
+
+    for (order, &head) in allocator.heads.iter().enumerate() {
+        let mut index = head;
+        while index != NO_BLOCK {
+            free += PAGESIZE << order;
+            index = allocator.blocks[index as usize].next;
+        }
+    }
+
+    Some(free)
+}
+
+/// How many free blocks the buddy allocator has of each order, smallest (one page) first. None if
+/// the allocator is locked.
+pub fn free_blocks() -> Option<[usize; NUM_ORDERS as usize]> {
+    let allocator = ALLOCATOR.try_lock()?;
+    let mut counts = [0; NUM_ORDERS as usize];
+
+    for (order, &head) in allocator.heads.iter().enumerate() {
+        let mut index = head;
+        while index != NO_BLOCK {
+            counts[order] += 1;
+            index = allocator.blocks[index as usize].next;
+        }
+    }
+
+    Some(counts)
+}
+
+/// Check the buddy allocator's free lists and block metadata for corruption, see
+/// `BuddyAllocator::validate`. Also done every so many allocations in debug builds.
+pub fn validate() -> Result<(), HeapError> {
+    ALLOCATOR.lock().validate()
+}
+
+/// How fragmented the free part of the heap is: the free bytes, and the largest block of them
+/// that one allocation could get.
+pub fn fragmentation() -> Option<(u64, u64)> {
+    let counts = free_blocks()?;
+    let free = counts
+        .iter()
+        .enumerate()
+        .map(|(order, &count)| (PAGESIZE << order) * count as u64)
+        .sum();
+    let largest = counts
+        .iter()
+        .rposition(|&count| count > 0)
+        .map_or(0, |order| PAGESIZE << order);
+
+    Some((free, largest))
+}
+
+/// Whether someone is in the middle of allocating or freeing. Allocating from an interrupt handler
+/// that interrupted them would deadlock.
+pub fn is_locked() -> bool {
+    ALLOCATOR.inner.is_locked()
+}
+
+/// Allocate `layout` from the heap, for code that can cope with running out, e.g. a driver setting
+/// up a device that can carry on without it. The reclaimers get a go first, like for any other
+/// allocation, but if that isn't enough this fails instead of panicking. The memory is freed with
+/// `alloc::alloc::dealloc`.
+pub fn try_alloc(layout: Layout) -> Result<NonNull<u8>, ()> {
+    if layout.size() == 0 {
+        // Like `Layout::dangling`: nothing may be read or written through it anyway
+        return NonNull::new(layout.align() as *mut u8).ok_or(());
+    }
+    NonNull::new(unsafe { ALLOCATOR.alloc_or_reclaim(layout) }).ok_or(())
+}
+
+/// A zeroed `T` on the heap, or an error if there is no room for it, see `try_alloc`. For the
+/// large DMA structures drivers otherwise make with `Box::new(core::mem::zeroed())`.
+/// ### Safety
+/// All zeroes has to be a valid `T`.
+pub unsafe fn try_box_zeroed<T>() -> Result<Box<T>, ()> {
+    let layout = Layout::new::<T>();
+    let ptr = try_alloc(layout)?.as_ptr();
+    ptr.write_bytes(0, layout.size());
+    Ok(Box::from_raw(ptr as *mut T))
+}
+
+/// Have `reclaim` called when an allocation finds the heap full, after everything registered
+/// earlier.
+pub fn register_reclaim(name: &'static str, reclaim: Reclaim) -> Result<(), ()> {
+    interrupts::without_interrupts(|| RECLAIMERS.lock().push((name, reclaim)).map_err(|_| ()))
+}
+
+/// Run every reclaimer, as if the heap had run out, e.g. to see how much the caches are holding
+/// on to. Returns how many bytes they freed, by their own count.
+pub fn reclaim() -> usize {
+    let reclaimers = interrupts::without_interrupts(|| RECLAIMERS.lock().clone());
+    reclaimers.iter().map(|&(_, reclaim)| reclaim()).sum()
+}
+
+#[cold]
+fn out_of_memory(layout: Layout) -> ! {
+    match fragmentation() {
+        Some((free, largest)) => panic!(
+            "Out of memory allocating {} bytes (aligned to {}): {} KiB of {} KiB free, largest \
+             free block {} KiB",
+            layout.size(),
+            layout.align(),
+            free / 1024,
+            HEAP_SIZE / 1024,
+            largest / 1024
+        ),
+        None => panic!(
+            "Out of memory allocating {} bytes (aligned to {})",
+            layout.size(),
+            layout.align()
+        ),
+    }
+}
+
+/// The lowest address of the heap.
+pub fn heap_start() -> u64 {
+    HEAP_START.load(Ordering::Relaxed)
+}
+
+/// Pick where the heap goes and map it. Nothing can be allocated before this.
 pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
 ) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let start = layout::place("heap", HEAP_RANGE, HEAP_SIZE, HEAP_SIZE);
+
+    paging::map_memory(
+        mapper,
+        frame_allocator,
+        VirtAddr::new(start),
+        HEAP_SIZE,
+        flags,
+        MappingSize::Huge,
+    )?;
+    HEAP_START.store(start, Ordering::Relaxed);
+
+    // Everything handed out has to start poisoned for the sanitizer to spot writes after free
+    #[cfg(feature = "kasan")]
+    unsafe {
+        (start as *mut u8).write_bytes(kasan::POISON_BYTE, HEAP_SIZE as usize);
     }
 
     Ok(())
@@ -0,0 +1,73 @@
+// Everything that depends on the processor architecture, one module per architecture. Generic
+// code (drivers, file systems, the scheduler) goes through the traits here, implemented by `Arch`
+// for the architecture being built for, rather than through instructions, I/O ports or page
+// tables directly. Code that is inherently tied to one architecture, like the x86 interrupt
+// controllers, lives in that architecture's module.
+
+#[cfg(target_arch = "x86_64")]
+pub mod x86_64;
+
+pub mod ports;
+
+/// The architecture being built for.
+#[cfg(target_arch = "x86_64")]
+pub type Arch = self::x86_64::X86_64;
+
+/// Controlling the processor the caller is running on.
+pub trait Cpu {
+    /// Wait for the next interrupt.
+    fn halt();
+
+    /// Tell the processor it is in a spin loop.
+    fn pause();
+
+    fn interrupts_enabled() -> bool;
+
+    fn enable_interrupts();
+
+    fn disable_interrupts();
+
+    /// Run `f` with interrupts off, turning them back on afterwards if they were on before.
+    fn without_interrupts<R>(f: impl FnOnce() -> R) -> R;
+
+    /// A counter that goes up at a fixed rate, for measuring short intervals and for entropy.
+    fn timestamp() -> u64;
+
+    /// Trap into the breakpoint handler.
+    fn breakpoint();
+}
+
+/// Port-mapped I/O, for architectures that have a separate I/O address space.
+pub trait PortIo {
+    /// ### Safety
+    /// Reading some ports has side effects on the device behind them.
+    unsafe fn read_u8(port: u16) -> u8;
+    /// ### Safety
+    /// Same as `read_u8`.
+    unsafe fn read_u16(port: u16) -> u16;
+    /// ### Safety
+    /// Same as `read_u8`.
+    unsafe fn read_u32(port: u16) -> u32;
+    /// ### Safety
+    /// The write has to be what the device behind the port expects.
+    unsafe fn write_u8(port: u16, data: u8);
+    /// ### Safety
+    /// Same as `write_u8`.
+    unsafe fn write_u16(port: u16, data: u16);
+    /// ### Safety
+    /// Same as `write_u8`.
+    unsafe fn write_u32(port: u16, data: u32);
+
+    /// Wait a moment, for old devices that need time between accesses.
+    /// ### Safety
+    /// Does an I/O access of its own on some architectures.
+    unsafe fn io_wait();
+}
+
+/// The kernel's view of memory.
+pub trait Paging {
+    const PAGE_SIZE: u64;
+
+    /// The physical address a kernel virtual address is mapped to, if it is mapped.
+    fn translate(addr: u64) -> Option<u64>;
+}
@@ -0,0 +1,168 @@
+// Typed I/O ports, and a record of who drives which. A `Port<T>` reads and writes `T`s with the
+// instruction for that width, so a 16-bit register can't be read a byte at a time by mistake.
+// Drivers claim the ports they drive with `claim`, like vectors are reserved, so that two of them
+// can't end up driving the same device without anyone noticing. Ports at fixed addresses are
+// claimed at boot; ones that come from firmware tables or PCI BARs are claimed when the driver
+// finds them.
+
+use super::{Arch, Cpu, PortIo};
+use crate::klib::containers::static_vec::StaticVec;
+use core::marker::PhantomData;
+use spin::Mutex;
+
+// Claims next to each other aren't merged, so this has to cover every one
+const MAX_CLAIMS: usize = 64;
+
+/// A value that can be read from and written to a port in a single access.
+pub trait PortValue: Copy {
+    /// ### Safety
+    /// See `PortIo::read_u8`.
+    unsafe fn read_from(port: u16) -> Self;
+    /// ### Safety
+    /// See `PortIo::write_u8`.
+    unsafe fn write_to(port: u16, value: Self);
+}
+
+impl PortValue for u8 {
+    #[inline]
+    unsafe fn read_from(port: u16) -> Self {
+        Arch::read_u8(port)
+    }
+
+    #[inline]
+    unsafe fn write_to(port: u16, value: Self) {
+        Arch::write_u8(port, value)
+    }
+}
+
+impl PortValue for u16 {
+    #[inline]
+    unsafe fn read_from(port: u16) -> Self {
+        Arch::read_u16(port)
+    }
+
+    #[inline]
+    unsafe fn write_to(port: u16, value: Self) {
+        Arch::write_u16(port, value)
+    }
+}
+
+impl PortValue for u32 {
+    #[inline]
+    unsafe fn read_from(port: u16) -> Self {
+        Arch::read_u32(port)
+    }
+
+    #[inline]
+    unsafe fn write_to(port: u16, value: Self) {
+        Arch::write_u32(port, value)
+    }
+}
+
+/// An I/O port that is read and written `T` at a time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Port<T> {
+    port: u16,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: PortValue> Port<T> {
+    pub const fn new(port: u16) -> Self {
+        Self {
+            port,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// The port `offset` ports further on, e.g. the next register of a device.
+    pub const fn offset(&self, offset: u16) -> Self {
+        Self::new(self.port + offset)
+    }
+
+    pub fn number(&self) -> u16 {
+        self.port
+    }
+
+    /// ### Safety
+    /// Reading some ports has side effects on the device behind them.
+    #[inline]
+    pub unsafe fn read(&self) -> T {
+        T::read_from(self.port)
+    }
+
+    /// ### Safety
+    /// The write has to be what the device behind the port expects.
+    #[inline]
+    pub unsafe fn write(&self, value: T) {
+        T::write_to(self.port, value)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Claim {
+    pub first: u16,
+    pub count: u16,
+    pub owner: &'static str,
+}
+
+impl Claim {
+    fn overlaps(&self, first: u16, count: u16) -> bool {
+        let (start, end) = (first as u32, first as u32 + count as u32);
+        (self.first as u32) < end && start < self.first as u32 + self.count as u32
+    }
+}
+
+static CLAIMS: Mutex<StaticVec<Claim, MAX_CLAIMS>> = Mutex::new(StaticVec::new());
+
+/// Claim `count` ports in a row starting at `first` for `owner`. Fails if any of them is already
+/// claimed, or there's no room to keep track of another claim.
+pub fn claim(first: u16, count: u16, owner: &'static str) -> Result<(), ()> {
+    if count == 0 || first as u32 + count as u32 > u16::MAX as u32 + 1 {
+        return Err(());
+    }
+
+    Arch::without_interrupts(|| {
+        let mut claims = CLAIMS.lock();
+        if claims.iter().any(|claim| claim.overlaps(first, count)) {
+            return Err(());
+        }
+
+        claims
+            .push(Claim {
+                first,
+                count,
+                owner,
+            })
+            .map_err(|_| ())
+    })
+}
+
+/// Give back the claim that starts at `first`, e.g. when a driver is torn down.
+pub fn release(first: u16) {
+    Arch::without_interrupts(|| {
+        let mut claims = CLAIMS.lock();
+        if let Some(index) = claims.iter().position(|claim| claim.first == first) {
+            let last = claims.len() - 1;
+            claims.swap(index, last);
+            claims.pop();
+        }
+    });
+}
+
+/// Who has claimed `port`, if anyone.
+pub fn owner(port: u16) -> Option<&'static str> {
+    Arch::without_interrupts(|| {
+        CLAIMS
+            .lock()
+            .iter()
+            .find(|claim| claim.overlaps(port, 1))
+            .map(|claim| claim.owner)
+    })
+}
+
+/// Every claim, lowest ports first.
+pub fn claims() -> StaticVec<Claim, MAX_CLAIMS> {
+    let mut claims = Arch::without_interrupts(|| CLAIMS.lock().clone());
+    claims.sort_unstable_by_key(|claim| claim.first);
+    claims
+}
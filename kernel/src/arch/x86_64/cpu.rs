@@ -0,0 +1,130 @@
+use super::paging::CanonicalAddress;
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count, _rdtsc};
+
+// CPUID leaf 1, ECX
+const CPUID_SSE42: u32 = 1 << 20;
+const CPUID_RDRAND: u32 = 1 << 30;
+// CPUID leaf 7, EBX
+const CPUID_RDSEED: u32 = 1 << 18;
+
+#[inline]
+pub fn hlt() {
+    unsafe { asm!("hlt", options(nomem, nostack, preserves_flags)) }
+}
+
+#[inline]
+pub fn pause() {
+    unsafe { asm!("pause", options(nomem, nostack, preserves_flags)) }
+}
+
+/// Read the "cs" register.
+#[inline]
+pub fn read_cs() -> u16 {
+    let cs: u16;
+    unsafe {
+        asm!("mov {0:x}, cs", out(reg) cs, options(nomem, nostack, preserves_flags));
+    }
+    cs
+}
+
+/// Read the stack pointer.
+#[inline(always)]
+pub fn read_rsp() -> u64 {
+    let rsp: u64;
+    unsafe {
+        asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+    }
+    rsp
+}
+
+/// Read the frame pointer, which points at the saved frame pointer and return address of the
+/// function this is inlined into.
+#[inline(always)]
+pub fn read_rbp() -> u64 {
+    let rbp: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    rbp
+}
+
+/// Load idt located at the specified descriptor table pointer.
+#[inline]
+pub unsafe fn lidt(idt: &DescriptorTablePointer) {
+    unsafe { asm!("lidt [{}]", in(reg) idt, options(readonly, nostack, preserves_flags)) }
+}
+
+/// The time stamp counter, which counts up at a constant rate from when the processor was reset.
+#[inline]
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+#[inline]
+pub fn int3() {
+    unsafe { asm!("int3", options(nostack, nomem)) }
+}
+
+/// Whether the processor has SSE4.2, and so the `crc32` instruction.
+pub fn has_sse42() -> bool {
+    unsafe { __cpuid(1) }.ecx & CPUID_SSE42 != 0
+}
+
+pub fn has_rdrand() -> bool {
+    unsafe { __cpuid(1) }.ecx & CPUID_RDRAND != 0
+}
+
+pub fn has_rdseed() -> bool {
+    unsafe { __cpuid(0) }.eax >= 7 && unsafe { __cpuid_count(7, 0) }.ebx & CPUID_RDSEED != 0
+}
+
+/// A random number from the processor's generator, or None if it has none ready right now.
+/// Has to only be called if the processor has RDRAND.
+#[inline]
+pub unsafe fn rdrand() -> Option<u64> {
+    let value: u64;
+    let ok: u8;
+    asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+    (ok != 0).then_some(value)
+}
+
+/// Like `rdrand`, but straight from the entropy source. Has to only be called if the processor
+/// has RDSEED.
+#[inline]
+pub unsafe fn rdseed() -> Option<u64> {
+    let value: u64;
+    let ok: u8;
+    asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+    (ok != 0).then_some(value)
+}
+
+/// Write back and invalidate every cache, e.g. before they lose their contents in a sleep state.
+#[inline]
+pub unsafe fn wbinvd() {
+    asm!("wbinvd", options(nostack, preserves_flags));
+}
+
+/// Add 8 bytes to a CRC32C. Has to only be called if the processor has SSE4.2.
+#[inline]
+pub unsafe fn crc32_u64(crc: u64, word: u64) -> u64 {
+    let mut crc = crc;
+    asm!("crc32 {crc}, {word}", crc = inout(reg) crc, word = in(reg) word,
+        options(pure, nomem, nostack));
+    crc
+}
+
+/// Add a byte to a CRC32C. Has to only be called if the processor has SSE4.2.
+#[inline]
+pub unsafe fn crc32_u8(crc: u32, byte: u8) -> u32 {
+    let mut crc = crc;
+    asm!("crc32 {crc:e}, {byte}", crc = inout(reg) crc, byte = in(reg_byte) byte,
+        options(pure, nomem, nostack));
+    crc
+}
+
+#[repr(C, packed(2))]
+pub struct DescriptorTablePointer {
+    pub limit: u16,
+    pub base: CanonicalAddress,
+}
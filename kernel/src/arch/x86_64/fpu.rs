@@ -0,0 +1,175 @@
+// Floating point and SIMD registers. The kernel is built without SSE, so its own code never
+// touches them, but code running in tasks may (and user code will), so every task keeps its own
+// copy in its `Context`. They are switched eagerly: `schedule` saves the old task's registers and
+// loads the new task's on every switch. Switching lazily (setting CR0.TS and waiting for the
+// `#NM` on first use) would skip the copy for tasks that never use them, but costs a fault for
+// those that do, which isn't worth it for the handful of tasks there are. CR0.TS is never set,
+// so a `#NM` can only mean the FPU got turned off.
+//
+// With XSAVE, the x87, SSE and AVX registers are kept; without it, just x87 and SSE, with FXSAVE.
+
+use crate::log_info;
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+// Enough for the legacy area, the XSAVE header and the AVX registers (832 bytes)
+const AREA_SIZE: usize = 1024;
+
+// CPUID leaf 1, ECX
+const CPUID_XSAVE: u32 = 1 << 26;
+const CPUID_AVX: u32 = 1 << 28;
+
+// XCR0: the state components XSAVE manages
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+// Where the control words are in the legacy (FXSAVE) part of the area
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+
+// Every exception masked and rounding to nearest, which is what the processor resets to
+const DEFAULT_FCW: u16 = 0x037F;
+pub const DEFAULT_MXCSR: u32 = 0x1F80;
+
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+
+/// A task's floating point and SIMD registers, in the format FXSAVE or XSAVE stores them.
+#[repr(C, align(64))]
+#[derive(Clone)]
+pub struct FpuState([u8; AREA_SIZE]);
+
+impl FpuState {
+    /// The registers as they are after a reset. The XSAVE header is left zeroed, which has XRSTOR
+    /// put every component in its initial state.
+    pub const fn new() -> Self {
+        let mut area = [0; AREA_SIZE];
+
+        let fcw = DEFAULT_FCW.to_le_bytes();
+        area[FCW_OFFSET] = fcw[0];
+        area[FCW_OFFSET + 1] = fcw[1];
+
+        let mxcsr = DEFAULT_MXCSR.to_le_bytes();
+        let mut i = 0;
+        while i < mxcsr.len() {
+            area[MXCSR_OFFSET + i] = mxcsr[i];
+            i += 1;
+        }
+
+        Self(area)
+    }
+
+    /// Store the processor's registers in `self`.
+    /// ### Safety
+    /// `init` must have been called.
+    #[inline]
+    pub unsafe fn save(&mut self) {
+        let area = self.0.as_mut_ptr();
+        if USE_XSAVE.load(Ordering::Relaxed) {
+            asm!(
+                "xsave64 [{}]",
+                in(reg) area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+                options(nostack, preserves_flags)
+            );
+        } else {
+            asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags));
+        }
+    }
+
+    /// Load the processor's registers from `self`.
+    /// ### Safety
+    /// `init` must have been called, and `self` must hold either `new()` or what `save` stored.
+    #[inline]
+    pub unsafe fn restore(&self) {
+        let area = self.0.as_ptr();
+        if USE_XSAVE.load(Ordering::Relaxed) {
+            asm!(
+                "xrstor64 [{}]",
+                in(reg) area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+                options(readonly, nostack, preserves_flags)
+            );
+        } else {
+            asm!("fxrstor64 [{}]", in(reg) area, options(readonly, nostack, preserves_flags));
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Turn on the FPU and SSE, and XSAVE with AVX if the processor has them. Has to be called before
+/// the first task switch.
+pub fn init() {
+    unsafe {
+        Cr0::update(|cr0| {
+            cr0.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            cr0.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|cr4| cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+
+    let features = unsafe { __cpuid(1) }.ecx;
+    if features & CPUID_XSAVE != 0 {
+        let mut xcr0 = XCR0_X87 | XCR0_SSE;
+        if features & CPUID_AVX != 0 {
+            xcr0 |= XCR0_AVX;
+        }
+
+        unsafe {
+            Cr4::update(|cr4| cr4.insert(Cr4Flags::OSXSAVE));
+            xsetbv(xcr0);
+        }
+
+        // How much XSAVE stores with what is enabled now
+        if unsafe { __cpuid_count(0xD, 0) }.ebx as usize > AREA_SIZE {
+            xcr0 = XCR0_X87 | XCR0_SSE;
+            unsafe { xsetbv(xcr0) };
+        }
+
+        USE_XSAVE.store(true, Ordering::Relaxed);
+        log_info!("FPU state saved with XSAVE, components {:#x}", xcr0);
+    } else {
+        log_info!("FPU state saved with FXSAVE");
+    }
+
+    unsafe {
+        asm!("fninit", options(nomem, nostack));
+        set_mxcsr(DEFAULT_MXCSR);
+    }
+}
+
+/// The SSE control and status register.
+#[inline]
+pub fn mxcsr() -> u32 {
+    let mut mxcsr: u32 = 0;
+    unsafe {
+        asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack, preserves_flags));
+    }
+    mxcsr
+}
+
+/// ### Safety
+/// Unmasking exceptions makes SSE instructions fault where they otherwise wouldn't.
+#[inline]
+pub unsafe fn set_mxcsr(mxcsr: u32) {
+    asm!("ldmxcsr [{}]", in(reg) &mxcsr, options(readonly, nostack, preserves_flags));
+}
+
+unsafe fn xsetbv(xcr0: u64) {
+    asm!(
+        "xsetbv",
+        in("ecx") 0,
+        in("eax") xcr0 as u32,
+        in("edx") (xcr0 >> 32) as u32,
+        options(nomem, nostack, preserves_flags)
+    );
+}
@@ -0,0 +1,120 @@
+use crate::arch::x86_64::cpu::pause;
+use crate::klib::acpi::madt::{InterruptRoute, Processor, MADT};
+use crate::klib::once_lock::OnceLock;
+use crate::klib::util;
+use crate::BootInfoFrameAllocator;
+use x86_64::registers::model_specific::Msr;
+
+const APIC_BASE_MSR: u32 = 0x1B;
+const APIC_BASE_ADDR_MASK: u64 = 0xF_FFFF_F000;
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+// Register offsets from the APIC base
+const ID_REGISTER: u64 = 0x20;
+const EOI_REGISTER: u64 = 0xB0;
+const SPURIOUS_REGISTER: u64 = 0xF0;
+const ICR_LOW_REGISTER: u64 = 0x300;
+const ICR_HIGH_REGISTER: u64 = 0x310;
+
+// Interrupt command register bits
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+const SOFTWARE_ENABLE: u32 = 0x100;
+
+/// The local APIC sends this when an interrupt goes away before it could be delivered. It must
+/// not be acknowledged with an end of interrupt.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// MSIs are written to this address (plus the destination APIC ID << 12).
+pub const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+
+pub static LOCAL_APIC: OnceLock<LocalApic> = OnceLock::new();
+
+/// Only just enough of the local APIC to receive MSIs. Legacy interrupts still go through the PIC,
+/// which the APIC passes through untouched.
+pub struct LocalApic {
+    base: u64,
+}
+
+impl LocalApic {
+    pub fn id(&self) -> u8 {
+        (self.read(ID_REGISTER) >> 24) as u8
+    }
+
+    /// Acknowledge an interrupt delivered by the local APIC, i.e. an MSI. Not for PIC interrupts.
+    pub fn end_of_interrupt(&self) {
+        self.write(EOI_REGISTER, 0);
+    }
+
+    /// Send a fixed interrupt with `vector` to the processor with local APIC ID `apic_id`.
+    pub fn send_ipi(&self, apic_id: u8, vector: u8) {
+        self.write(ICR_HIGH_REGISTER, (apic_id as u32) << 24);
+        // Writing the low half sends it
+        self.write(ICR_LOW_REGISTER, vector as u32);
+
+        while self.read(ICR_LOW_REGISTER) & ICR_DELIVERY_PENDING != 0 {
+            pause();
+        }
+    }
+
+    fn read(&self, register: u64) -> u32 {
+        unsafe { ((self.base + register) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, register: u64, value: u32) {
+        unsafe { ((self.base + register) as *mut u32).write_volatile(value) }
+    }
+}
+
+/// Map and software-enable the local APIC so that MSIs get delivered.
+/// ### Safety
+/// Should only be called once, after the kernel page table has been set up.
+pub unsafe fn init(frame_allocator: &mut BootInfoFrameAllocator) -> Result<(), ()> {
+    let phys_addr = Msr::new(APIC_BASE_MSR).read() & APIC_BASE_ADDR_MASK;
+    util::map_mmio(frame_allocator, phys_addr, 0x1000, "local apic")?;
+
+    let apic = LocalApic {
+        base: util::physical_to_kernel_address(phys_addr),
+    };
+    enable(&apic);
+
+    LOCAL_APIC.set(apic).map_err(|_| ())
+}
+
+/// Enable the local APIC again after it was reset by sleeping, like `init` did.
+pub fn resume() -> Result<(), ()> {
+    let apic = LOCAL_APIC.get().ok_or(())?;
+    unsafe { enable(apic) };
+    Ok(())
+}
+
+unsafe fn enable(apic: &LocalApic) {
+    let mut base_msr = Msr::new(APIC_BASE_MSR);
+    let base = base_msr.read();
+
+    if base & APIC_GLOBAL_ENABLE == 0 {
+        base_msr.write(base | APIC_GLOBAL_ENABLE);
+    }
+
+    let spurious = apic.read(SPURIOUS_REGISTER);
+    apic.write(
+        SPURIOUS_REGISTER,
+        (spurious & !0xFF) | SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32,
+    );
+}
+
+/// Where ISA IRQ `irq` ends up once it goes through an IOAPIC rather than the PIC. `None` if
+/// there is no MADT to tell.
+pub fn legacy_irq_route(irq: u8) -> Option<InterruptRoute> {
+    MADT.get().map(|madt| madt.route_irq(irq))
+}
+
+/// The processors other than the one we booted on that can be started.
+pub fn application_processors() -> impl Iterator<Item = &'static Processor> {
+    let bsp = LOCAL_APIC.get().map(|apic| apic.id() as u32);
+
+    MADT.get()
+        .into_iter()
+        .flat_map(|madt| madt.usable_processors())
+        .filter(move |processor| Some(processor.apic_id) != bsp)
+}
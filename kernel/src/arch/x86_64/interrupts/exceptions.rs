@@ -0,0 +1,231 @@
+// Handlers for the CPU exceptions that mean the kernel did something wrong. None of them can be
+// recovered from, so each one prints what it can work out about the fault (which exception, what
+// its error code says, the bytes of the faulting instruction and a backtrace) and halts, rather
+// than leaving the CPU to triple fault and reset without a word.
+//
+// Breakpoints and the FPU being unavailable are handled in `main`, as those aren't fatal, and
+// NMIs and machine checks in `machine_check`, as those may not be. There are no IST stacks yet, so
+// a kernel stack overflow still triple faults: the double fault handler has nowhere to run.
+
+use super::idt::{DescriptorTable, PageFaultErrorCode, StackFrame};
+use super::machine_check;
+use crate::arch::x86_64::cpu;
+use crate::klib::crashdump;
+use crate::klib::graphics::framebuffer;
+use crate::{print, println};
+use x86_64::registers::control::Cr2;
+
+// Bytes of the faulting instruction to show; no instruction is longer than this
+const INSTRUCTION_BYTES: u64 = 15;
+
+// What an exception's error code says, for those that push one
+pub(super) enum ErrorCode {
+    None,
+    Raw(u64),
+    // A segment selector, or 0 if the fault had nothing to do with one
+    Selector(u64),
+    PageFault(PageFaultErrorCode),
+}
+
+/// Point every fatal exception at a handler that reports it.
+pub fn install(idt: &mut DescriptorTable) {
+    idt.division_error.set_handler_fn(division_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    idt.non_maskable.set_handler_fn(machine_check::nmi_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded
+        .set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.double_fault.set_handler_fn(double_fault_handler);
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present
+        .set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault
+        .set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_fault_handler);
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt.x87_floating_point_exception
+        .set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.machine_check
+        .set_handler_fn(machine_check::machine_check_handler);
+    idt.simd_floating_point_exception
+        .set_handler_fn(simd_floating_point_handler);
+    idt.virtualization_exception
+        .set_handler_fn(virtualization_handler);
+    idt.control_protection_exception
+        .set_handler_fn(control_protection_handler);
+    idt.hypervisor_injection_exception
+        .set_handler_fn(hypervisor_injection_handler);
+    idt.vmm_communication_exception
+        .set_handler_fn(vmm_communication_handler);
+    idt.security_exception.set_handler_fn(security_handler);
+}
+
+macro_rules! exception_handler {
+    ($handler:ident, $name:literal) => {
+        extern "x86-interrupt" fn $handler(stack_frame: StackFrame) {
+            fatal($name, &stack_frame, ErrorCode::None, cpu::read_rbp());
+        }
+    };
+    ($handler:ident, $name:literal, $decode:expr) => {
+        extern "x86-interrupt" fn $handler(stack_frame: StackFrame, error_code: u64) {
+            fatal($name, &stack_frame, $decode(error_code), cpu::read_rbp());
+        }
+    };
+}
+
+exception_handler!(division_error_handler, "Division error");
+exception_handler!(debug_handler, "Debug exception");
+exception_handler!(overflow_handler, "Overflow");
+exception_handler!(bound_range_exceeded_handler, "Bound range exceeded");
+exception_handler!(invalid_opcode_handler, "Invalid opcode");
+exception_handler!(invalid_tss_handler, "Invalid TSS", ErrorCode::Selector);
+exception_handler!(
+    segment_not_present_handler,
+    "Segment not present",
+    ErrorCode::Selector
+);
+exception_handler!(
+    stack_segment_fault_handler,
+    "Stack segment fault",
+    ErrorCode::Selector
+);
+exception_handler!(
+    general_protection_fault_handler,
+    "General protection fault",
+    ErrorCode::Selector
+);
+exception_handler!(x87_floating_point_handler, "x87 floating point exception");
+exception_handler!(alignment_check_handler, "Alignment check", ErrorCode::Raw);
+exception_handler!(simd_floating_point_handler, "SIMD floating point exception");
+exception_handler!(virtualization_handler, "Virtualization exception");
+exception_handler!(
+    control_protection_handler,
+    "Control protection exception",
+    ErrorCode::Raw
+);
+exception_handler!(
+    hypervisor_injection_handler,
+    "Hypervisor injection exception"
+);
+exception_handler!(
+    vmm_communication_handler,
+    "VMM communication exception",
+    ErrorCode::Raw
+);
+exception_handler!(security_handler, "Security exception", ErrorCode::Raw);
+
+// The error code is always 0
+extern "x86-interrupt" fn double_fault_handler(stack_frame: StackFrame, _error_code: u64) -> ! {
+    fatal(
+        "Double fault",
+        &stack_frame,
+        ErrorCode::None,
+        cpu::read_rbp(),
+    );
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: StackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    fatal(
+        "Page fault",
+        &stack_frame,
+        ErrorCode::PageFault(error_code),
+        cpu::read_rbp(),
+    );
+}
+
+// `handler_rbp` is the handler's frame pointer, which points at the interrupted code's
+#[inline(never)]
+pub(super) fn fatal(
+    name: &str,
+    stack_frame: &StackFrame,
+    error_code: ErrorCode,
+    handler_rbp: u64,
+) -> ! {
+    // Nothing else runs from here on, so whatever was printing isn't coming back to finish
+    unsafe { framebuffer::force_unlock() };
+    println!("{} at {:#018x}", name, stack_frame.rip());
+
+    match error_code {
+        ErrorCode::None => {}
+        ErrorCode::Raw(code) => println!("Error code: {:#x}", code),
+        ErrorCode::Selector(0) => println!("Error code: 0 (not caused by a segment)"),
+        ErrorCode::Selector(code) => {
+            let table = match (code >> 1) & 0b11 {
+                0b00 => "GDT",
+                0b10 => "LDT",
+                _ => "IDT",
+            };
+            let external = if code & 1 != 0 { ", external" } else { "" };
+            println!(
+                "Error code: {:#x} ({} index {}{})",
+                code,
+                table,
+                (code >> 3) & 0x1FFF,
+                external
+            );
+        }
+        ErrorCode::PageFault(code) => {
+            let access = if code.instruction_fetch() {
+                "instruction fetch"
+            } else if code.write() {
+                "write"
+            } else {
+                "read"
+            };
+            let cause = if code.reserved() {
+                "reserved bit set"
+            } else if code.present() {
+                "protection violation"
+            } else {
+                "page not present"
+            };
+            let mode = if code.user() { "user" } else { "kernel" };
+            println!(
+                "Error code: {:#x} ({} {}, {})",
+                code.bits(),
+                mode,
+                access,
+                cause
+            );
+            println!("Faulting address: {:#018x}", Cr2::read().as_u64());
+        }
+    }
+
+    print_instruction(stack_frame.rip());
+    println!("{:#?}", stack_frame);
+
+    // The faulting instruction is already printed above, so the backtrace starts with its caller
+    let interrupted_rbp = unsafe { (handler_rbp as *const u64).read() };
+    crashdump::print_backtrace_from(interrupted_rbp);
+
+    #[cfg(feature = "selftest")]
+    crate::selftest::exit(crate::selftest::ExitCode::Failure);
+
+    loop {
+        super::disable_interrupts();
+        cpu::hlt();
+    }
+}
+
+fn print_instruction(rip: u64) {
+    // The bytes may cross into the next page, so both ends have to be mapped
+    let end = rip.wrapping_add(INSTRUCTION_BYTES - 1);
+    if !crashdump::is_mapped(rip) || !crashdump::is_mapped(end) {
+        println!("Instruction: <not mapped>");
+        return;
+    }
+
+    let bytes =
+        unsafe { core::slice::from_raw_parts(rip as *const u8, INSTRUCTION_BYTES as usize) };
+    print!("Instruction:");
+    for byte in bytes {
+        print!(" {:02x}", byte);
+    }
+    println!();
+}
@@ -1,8 +1,9 @@
-use crate::klib::x86_64;
-use x86_64::CanonicalAddress;
+use crate::arch::x86_64::cpu;
+use crate::arch::x86_64::paging::CanonicalAddress;
 
 use core::fmt;
 use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 #[repr(C)]
 #[repr(align(16))]
@@ -25,7 +26,8 @@ pub struct DescriptorTable {
     _reserved: Entry<Handler>,
     pub x87_floating_point_exception: Entry<Handler>,
     pub alignment_check: Entry<ErrorCodeHandler>,
-    pub machine_check: Entry<HandlerNoReturn>,
+    // Can be returned from, if the processor says the interrupted code can go on
+    pub machine_check: Entry<Handler>,
     pub simd_floating_point_exception: Entry<Handler>,
     pub virtualization_exception: Entry<Handler>,
     pub control_protection_exception: Entry<ErrorCodeHandler>,
@@ -40,12 +42,12 @@ pub struct DescriptorTable {
 impl DescriptorTable {
     #[inline]
     pub fn load(&self) {
-        unsafe { x86_64::lidt(&self.pointer()) }
+        unsafe { cpu::lidt(&self.pointer()) }
     }
 
     #[inline]
-    pub fn pointer(&self) -> x86_64::DescriptorTablePointer {
-        x86_64::DescriptorTablePointer {
+    pub fn pointer(&self) -> cpu::DescriptorTablePointer {
+        cpu::DescriptorTablePointer {
             limit: (core::mem::size_of::<Self>() - 1) as u16,
             base: unsafe { CanonicalAddress::new_unsafe(self as *const _ as u64) },
         }
@@ -101,12 +103,24 @@ pub struct Entry<F> {
 }
 
 impl<F> Entry<F> {
-    fn set_handler_addr(&mut self, handler_addr: u64) {
+    fn set_handler_addr(&mut self, handler_addr: u64) -> &mut EntryOptions {
         self.pointer_low = handler_addr as u16;
         self.pointer_middle = (handler_addr >> 16) as u16;
         self.pointer_high = (handler_addr >> 32) as u32;
-        self.gdt_selector = x86_64::read_cs();
-        self.options.set_present(true);
+        self.gdt_selector = cpu::read_cs();
+        self.options.set_present(true)
+    }
+
+    #[inline]
+    pub fn options(&self) -> EntryOptions {
+        self.options
+    }
+
+    /// The options of this entry, e.g. to make it a trap gate with
+    /// `entry.options_mut().set_gate_type(GateType::Trap)`.
+    #[inline]
+    pub fn options_mut(&mut self) -> &mut EntryOptions {
+        &mut self.options
     }
 }
 
@@ -148,56 +162,130 @@ impl<T> PartialEq for Entry<T> {
 }
 
 #[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum PrivilegeLevel {
     Kernel = 0u8,
     User = 3u8, // We are not using 1-2; these are probably going to be deprecated
                 // as ring 1/2 are not used
 }
 
+/// What the CPU does with interrupts while it runs the handler of an entry.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GateType {
+    /// Interrupts are disabled until the handler returns.
+    Interrupt = 0xE,
+    /// Interrupts are left as they were, so the handler can be interrupted.
+    Trap = 0xF,
+}
+
+// The options word of a gate descriptor: bits 0-2 pick an IST stack (0 for none), bits 8-11 are
+// the gate type, bits 13-14 the lowest privilege level allowed to use `int` on the vector, and bit
+// 15 is the present bit. Everything else must be zero.
+const STACK_INDEX_MASK: u16 = 0b111;
+const GATE_TYPE_SHIFT: u16 = 8;
+const GATE_TYPE_MASK: u16 = 0xF << GATE_TYPE_SHIFT;
+const PRIVILEGE_LEVEL_SHIFT: u16 = 13;
+const PRIVILEGE_LEVEL_MASK: u16 = 0b11 << PRIVILEGE_LEVEL_SHIFT;
+const PRESENT: u16 = 1 << 15;
+
 #[repr(transparent)]
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct EntryOptions(u16);
 
 impl EntryOptions {
+    /// A non-present interrupt gate, only usable from the kernel, without an IST stack.
     #[inline]
-    /// Set the present bit, as well as the privilege bit to be 3 (or, user mode allowed)
-    pub fn minimal() -> Self {
-        Self(0b1110_0000_0000)
+    pub const fn minimal() -> Self {
+        Self((GateType::Interrupt as u16) << GATE_TYPE_SHIFT)
     }
 
     #[inline]
     pub fn set_present(&mut self, present: bool) -> &mut Self {
         if present {
-            self.0 |= 1 << 15;
+            self.0 |= PRESENT;
         } else {
-            self.0 &= 0x7FFF;
+            self.0 &= !PRESENT;
         }
         self
     }
 
     #[inline]
-    pub fn disable_interrupts_when_invoked(&mut self, present: bool) -> &mut Self {
-        // Switch between an interrupt gate and a trap gate
-        // FIXME
-        self.0 &= ((!1) | (present as u16)) << 8;
+    pub fn present(&self) -> bool {
+        self.0 & PRESENT != 0
+    }
+
+    #[inline]
+    pub fn set_gate_type(&mut self, gate_type: GateType) -> &mut Self {
+        self.0 = (self.0 & !GATE_TYPE_MASK) | ((gate_type as u16) << GATE_TYPE_SHIFT);
         self
     }
 
+    #[inline]
+    pub fn gate_type(&self) -> GateType {
+        if (self.0 & GATE_TYPE_MASK) >> GATE_TYPE_SHIFT == GateType::Trap as u16 {
+            GateType::Trap
+        } else {
+            GateType::Interrupt
+        }
+    }
+
+    /// Make this an interrupt gate if `disable` is set, or a trap gate otherwise.
+    #[inline]
+    pub fn disable_interrupts_when_invoked(&mut self, disable: bool) -> &mut Self {
+        self.set_gate_type(if disable {
+            GateType::Interrupt
+        } else {
+            GateType::Trap
+        })
+    }
+
     #[inline]
     pub fn set_privilege_level(&mut self, privilege_level: PrivilegeLevel) -> &mut Self {
-        self.0 &= ((!0b11) | (privilege_level as u16)) << 13;
+        self.0 =
+            (self.0 & !PRIVILEGE_LEVEL_MASK) | ((privilege_level as u16) << PRIVILEGE_LEVEL_SHIFT);
         self
     }
 
+    /// The raw descriptor privilege level, 0 to 3.
+    #[inline]
+    pub fn privilege_level(&self) -> u8 {
+        ((self.0 & PRIVILEGE_LEVEL_MASK) >> PRIVILEGE_LEVEL_SHIFT) as u8
+    }
+
     /// ## Safety
     /// This function must be called with a value in the range [0, 6]. In addition, the
     /// caller must ensure that the passed stack index value is valid and not used by other
     /// interrupts.
     #[inline]
     pub unsafe fn set_stack_index(&mut self, index: u16) -> &mut Self {
-        self.0 &= (!0b111) | (index + 1);
+        debug_assert!(index < 7);
+        // The IST field is 1-based, as 0 means no stack switch
+        self.0 = (self.0 & !STACK_INDEX_MASK) | ((index + 1) & STACK_INDEX_MASK);
         self
     }
+
+    /// Go back to running the handler on the interrupted stack.
+    #[inline]
+    pub fn clear_stack_index(&mut self) -> &mut Self {
+        self.0 &= !STACK_INDEX_MASK;
+        self
+    }
+
+    /// The IST stack the handler runs on, if any, as passed to `set_stack_index`.
+    #[inline]
+    pub fn stack_index(&self) -> Option<u16> {
+        match self.0 & STACK_INDEX_MASK {
+            0 => None,
+            ist => Some(ist - 1),
+        }
+    }
+
+    /// The raw options word.
+    #[inline]
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
 }
 
 #[repr(C)]
@@ -205,6 +293,14 @@ pub struct StackFrame {
     info: StackFrameInfo,
 }
 
+impl StackFrame {
+    /// The address of the instruction the interrupt came in on.
+    #[inline]
+    pub fn rip(&self) -> u64 {
+        self.info.rip.as_u64()
+    }
+}
+
 impl fmt::Debug for StackFrame {
     #[inline]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -227,28 +323,36 @@ pub struct StackFrameInfo {
 pub struct PageFaultErrorCode(u64);
 
 impl PageFaultErrorCode {
+    /// The raw error code.
+    #[inline]
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Whether the page was present, so the fault was a protection violation.
     #[inline]
-    fn present(&self) -> bool {
+    pub fn present(&self) -> bool {
         self.0 & 1 != 0
     }
 
     #[inline]
-    fn write(&self) -> bool {
+    pub fn write(&self) -> bool {
         self.0 & (1 << 1) != 0
     }
 
     #[inline]
-    fn user(&self) -> bool {
+    pub fn user(&self) -> bool {
         self.0 & (1 << 2) != 0
     }
 
+    /// Whether a reserved bit was set in one of the page table entries.
     #[inline]
-    fn reserved(&self) -> bool {
+    pub fn reserved(&self) -> bool {
         self.0 & (1 << 3) != 0
     }
 
     #[inline]
-    fn instruction_fetch(&self) -> bool {
+    pub fn instruction_fetch(&self) -> bool {
         self.0 & (1 << 4) != 0
     }
 }
@@ -265,8 +369,9 @@ macro_rules! impl_set_handler_fn {
         impl Entry<$h> {
             /// Set this IDT entry to use the passed handler function.
             /// The IDT entry will also automatically use the current code segment.
+            /// Returns the entry's options, to change them from the defaults.
             #[inline]
-            pub fn set_handler_fn(&mut self, handler: $h) {
+            pub fn set_handler_fn(&mut self, handler: $h) -> &mut EntryOptions {
                 self.set_handler_addr(handler as u64)
             }
         }
@@ -279,3 +384,24 @@ impl_set_handler_fn!(PageFaultHandler);
 
 impl_set_handler_fn!(HandlerNoReturn);
 impl_set_handler_fn!(ErrorCodeHandlerNoReturn);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+// Interrupts taken so far, by vector
+static COUNTS: [AtomicU64; 256] = [NO_INTERRUPTS; 256];
+
+/// Count an interrupt on `vector`. Called by the handlers as they come in.
+#[inline]
+pub fn count(vector: u8) {
+    COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// How many times each vector has fired, as (vector, count), leaving out those that never have.
+pub fn counts() -> impl Iterator<Item = (u8, u64)> {
+    COUNTS
+        .iter()
+        .enumerate()
+        .map(|(vector, count)| (vector as u8, count.load(Ordering::Relaxed)))
+        .filter(|&(_, count)| count != 0)
+}
@@ -0,0 +1,349 @@
+// Hardware errors: NMIs, which the chipset raises for memory parity and I/O channel errors, and
+// machine checks, which the processor raises for errors it couldn't correct (in its caches, in
+// memory, on a bus and so on) and describes in the MSRs of its machine check banks.
+//
+// What happens once one is reported is up to the policy, from the `hwerror` command line option.
+// `hwerror=panic`, the default, halts like any other fatal exception. `hwerror=continue` logs the
+// error and carries on, unless the processor says the interrupted code can't be carried on with.
+// Neither NMIs nor machine checks are held off by disabling interrupts, so the handler may have
+// interrupted code that holds the console or the log. When carrying on, it only keeps the error,
+// and a periodic timer logs it afterwards.
+
+use super::exceptions::{self, ErrorCode};
+use super::idt::StackFrame;
+use crate::arch::ports::Port;
+use crate::arch::x86_64::cpu;
+use crate::klib::cmdline;
+use crate::klib::containers::static_vec::StaticVec;
+use crate::klib::graphics::framebuffer;
+use crate::klib::timer;
+use crate::{log_error, log_info, log_warn, println};
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Msr;
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17A;
+const IA32_MCG_CTL: u32 = 0x17B;
+// Each bank has four MSRs from here on: control, status, address and misc
+const IA32_MC0_CTL: u32 = 0x400;
+
+const MCG_CAP_COUNT: u64 = 0xFF;
+const MCG_CAP_CTL_PRESENT: u64 = 1 << 8;
+// Set if the interrupted code can be restarted at the pushed RIP
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+
+const STATUS_VALID: u64 = 1 << 63;
+const STATUS_OVERFLOW: u64 = 1 << 62;
+const STATUS_UNCORRECTED: u64 = 1 << 61;
+const STATUS_ENABLED: u64 = 1 << 60;
+const STATUS_MISC_VALID: u64 = 1 << 59;
+const STATUS_ADDRESS_VALID: u64 = 1 << 58;
+const STATUS_CONTEXT_CORRUPT: u64 = 1 << 57;
+
+// Banks past this are left alone
+const MAX_BANKS: u32 = 32;
+
+const CPUID_MCE: u32 = 1 << 7;
+const CPUID_MCA: u32 = 1 << 14;
+
+// System control port B, which says why the chipset raised an NMI. The PC speaker is gated through
+// it too, and claims it for both.
+const NMI_REASON: Port<u8> = Port::new(0x61);
+const NMI_REASON_SERR: u8 = 1 << 7;
+const NMI_REASON_IOCHK: u8 = 1 << 6;
+// Setting these clears the matching reason; only the low 4 bits of the port can be written
+const NMI_CLEAR_SERR: u8 = 1 << 2;
+const NMI_CLEAR_IOCHK: u8 = 1 << 3;
+const NMI_CONTROL_MASK: u8 = 0x0F;
+
+// Errors kept until the report timer logs them; any more are only counted
+const MAX_PENDING: usize = 8;
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What to do after reporting a hardware error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    Panic,
+    Continue,
+}
+
+static CONTINUE: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy)]
+enum HardwareError {
+    Nmi {
+        reason: u8,
+    },
+    MachineCheck {
+        bank: u32,
+        status: u64,
+        address: Option<u64>,
+        misc: Option<u64>,
+    },
+}
+
+// Only ever try-locked by the handlers, so that one that interrupts the report timer can't hang
+static PENDING: Mutex<StaticVec<HardwareError, MAX_PENDING>> = Mutex::new(StaticVec::new());
+static LOST: AtomicU64 = AtomicU64::new(0);
+
+// Number of banks, 0 if the processor has no machine check architecture
+static BANKS: AtomicU64 = AtomicU64::new(0);
+
+pub fn policy() -> Policy {
+    if CONTINUE.load(Ordering::Relaxed) {
+        Policy::Continue
+    } else {
+        Policy::Panic
+    }
+}
+
+pub fn set_policy(policy: Policy) {
+    CONTINUE.store(policy == Policy::Continue, Ordering::Relaxed);
+}
+
+/// Pick the policy from the command line, report errors the banks kept from before the last
+/// reset, and turn machine checks on. Until then, a machine check shuts the processor down.
+pub fn init() {
+    match cmdline::value("hwerror") {
+        Some("continue") => set_policy(Policy::Continue),
+        Some("panic") | None => set_policy(Policy::Panic),
+        Some(other) => log_warn!("Unknown hwerror policy {:?}, panicking on errors", other),
+    }
+
+    let features = unsafe { __cpuid(1) }.edx;
+    if features & CPUID_MCE == 0 {
+        log_warn!("No machine check exceptions on this processor");
+        return;
+    }
+
+    if features & CPUID_MCA != 0 {
+        let cap = unsafe { Msr::new(IA32_MCG_CAP).read() };
+        let banks = ((cap & MCG_CAP_COUNT) as u32).min(MAX_BANKS);
+        BANKS.store(banks as u64, Ordering::Relaxed);
+
+        for bank in 0..banks {
+            if let Some(error) = read_bank(bank) {
+                log_warn!("Logged before boot: {}", error);
+            }
+            unsafe {
+                // Bank 0's control belongs to the firmware on older processors
+                if bank != 0 {
+                    Msr::new(IA32_MC0_CTL + bank * 4).write(u64::MAX);
+                }
+                Msr::new(IA32_MC0_CTL + bank * 4 + 1).write(0);
+            }
+        }
+        if cap & MCG_CAP_CTL_PRESENT != 0 {
+            unsafe { Msr::new(IA32_MCG_CTL).write(u64::MAX) };
+        }
+        log_info!("{} machine check banks", banks);
+    }
+
+    unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION)) };
+}
+
+/// Start logging the errors that were kept rather than printed. Has to be called after
+/// `timer::init`.
+pub fn start_reporting() {
+    timer::every(REPORT_INTERVAL, report_pending).detach();
+}
+
+fn report_pending() {
+    let lost = LOST.swap(0, Ordering::Relaxed);
+    if lost != 0 {
+        log_error!("{} more hardware errors weren't kept", lost);
+    }
+
+    loop {
+        let Some(error) = super::without_interrupts(|| PENDING.lock().pop()) else {
+            break;
+        };
+        log_error!("{}", error);
+    }
+}
+
+// The error in `bank`, if it has one
+fn read_bank(bank: u32) -> Option<HardwareError> {
+    let status = unsafe { Msr::new(IA32_MC0_CTL + bank * 4 + 1).read() };
+    if status & STATUS_VALID == 0 {
+        return None;
+    }
+
+    let address = (status & STATUS_ADDRESS_VALID != 0)
+        .then(|| unsafe { Msr::new(IA32_MC0_CTL + bank * 4 + 2).read() });
+    let misc = (status & STATUS_MISC_VALID != 0)
+        .then(|| unsafe { Msr::new(IA32_MC0_CTL + bank * 4 + 3).read() });
+    Some(HardwareError::MachineCheck {
+        bank,
+        status,
+        address,
+        misc,
+    })
+}
+
+// Print the errors and halt, or keep them to be logged and carry on. `handler_rbp` is as for
+// `exceptions::fatal`.
+fn handle(
+    name: &str,
+    errors: &[HardwareError],
+    stack_frame: &StackFrame,
+    can_continue: bool,
+    handler_rbp: u64,
+) {
+    if can_continue && policy() == Policy::Continue {
+        match PENDING.try_lock() {
+            Some(mut pending) => {
+                for &error in errors {
+                    if pending.push(error).is_err() {
+                        LOST.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+            None => {
+                LOST.fetch_add(errors.len() as u64, Ordering::Relaxed);
+            }
+        }
+        return;
+    }
+
+    unsafe { framebuffer::force_unlock() };
+    for error in errors {
+        println!("{}", error);
+    }
+    exceptions::fatal(name, stack_frame, ErrorCode::None, handler_rbp);
+}
+
+pub(super) extern "x86-interrupt" fn nmi_handler(stack_frame: StackFrame) {
+    let reason = unsafe { NMI_REASON.read() };
+    if reason & (NMI_REASON_SERR | NMI_REASON_IOCHK) != 0 {
+        // Clear the reason, so that the chipset can raise the next one
+        let control = reason & NMI_CONTROL_MASK;
+        unsafe {
+            NMI_REASON.write(control | NMI_CLEAR_SERR | NMI_CLEAR_IOCHK);
+            NMI_REASON.write(control);
+        }
+    }
+
+    let error = HardwareError::Nmi {
+        reason: reason & (NMI_REASON_SERR | NMI_REASON_IOCHK),
+    };
+    handle(
+        "Non-maskable interrupt",
+        &[error],
+        &stack_frame,
+        true,
+        cpu::read_rbp(),
+    );
+}
+
+pub(super) extern "x86-interrupt" fn machine_check_handler(stack_frame: StackFrame) {
+    let mut errors = StaticVec::<HardwareError, { MAX_BANKS as usize }>::new();
+    let mut context_corrupt = false;
+    for bank in 0..BANKS.load(Ordering::Relaxed) as u32 {
+        if let Some(error @ HardwareError::MachineCheck { status, .. }) = read_bank(bank) {
+            context_corrupt |= status & STATUS_CONTEXT_CORRUPT != 0;
+            let _ = errors.push(error);
+            // Cleared so that the next machine check doesn't report it again
+            unsafe { Msr::new(IA32_MC0_CTL + bank * 4 + 1).write(0) };
+        }
+    }
+
+    let mcg_status = unsafe { Msr::new(IA32_MCG_STATUS).read() };
+    let can_continue = mcg_status & MCG_STATUS_RIPV != 0 && !context_corrupt;
+    if can_continue {
+        // Clearing MCIP lets the next machine check through; while it's set, one shuts down
+        unsafe { Msr::new(IA32_MCG_STATUS).write(0) };
+    }
+    handle(
+        "Machine check",
+        &errors,
+        &stack_frame,
+        can_continue,
+        cpu::read_rbp(),
+    );
+}
+
+// The kind of error a machine check error code stands for, from the Intel SDM's "Interpreting the
+// MCA Error Codes". Bit 12 (the filter bit) is left out of the compound codes.
+fn error_kind(code: u16) -> &'static str {
+    match code {
+        0x0000 => "no error",
+        0x0001 => "unclassified",
+        0x0002 => "microcode ROM parity error",
+        0x0003 => "external error",
+        0x0004 => "FRC error",
+        0x0005 => "internal parity error",
+        0x0006 => "SMM handler code access violation",
+        0x0400 => "internal timer error",
+        _ if code & 0xFC00 == 0x0400 => "internal unclassified error",
+        _ if code & 0xE800 == 0x0800 => "bus or interconnect error",
+        _ if code & 0xEF00 == 0x0100 => "cache hierarchy error",
+        _ if code & 0xEF80 == 0x0080 => "memory controller error",
+        _ if code & 0xEFF0 == 0x0010 => "TLB error",
+        _ => "unknown error",
+    }
+}
+
+impl fmt::Display for HardwareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            HardwareError::Nmi { reason } => {
+                write!(f, "NMI: ")?;
+                match reason {
+                    0 => write!(f, "no reason given (maybe a watchdog or a debugger)"),
+                    _ => {
+                        if reason & NMI_REASON_SERR != 0 {
+                            write!(f, "system error (memory parity or PCI SERR#) ")?;
+                        }
+                        if reason & NMI_REASON_IOCHK != 0 {
+                            write!(f, "I/O channel check")?;
+                        }
+                        Ok(())
+                    }
+                }
+            }
+            HardwareError::MachineCheck {
+                bank,
+                status,
+                address,
+                misc,
+            } => {
+                let code = status as u16;
+                write!(
+                    f,
+                    "Machine check in bank {}: {} ({:#06x}), model specific code {:#06x}, {}",
+                    bank,
+                    error_kind(code),
+                    code,
+                    (status >> 16) as u16,
+                    if status & STATUS_UNCORRECTED != 0 {
+                        "uncorrected"
+                    } else {
+                        "corrected"
+                    }
+                )?;
+                if status & STATUS_CONTEXT_CORRUPT != 0 {
+                    write!(f, ", processor context corrupt")?;
+                }
+                if status & STATUS_OVERFLOW != 0 {
+                    write!(f, ", earlier errors lost")?;
+                }
+                if status & STATUS_ENABLED == 0 {
+                    write!(f, ", not signaled")?;
+                }
+                if let Some(address) = address {
+                    write!(f, ", address {:#x}", address)?;
+                }
+                if let Some(misc) = misc {
+                    write!(f, ", misc {:#x}", misc)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,56 @@
+pub mod apic;
+pub mod exceptions;
+pub mod idt;
+pub mod machine_check;
+pub mod pic;
+pub mod vectors;
+
+use core::arch::asm;
+
+#[inline]
+pub fn enable_interrupts() {
+    unsafe { asm!("sti", options(nostack, nomem)) }
+}
+
+#[inline]
+pub fn disable_interrupts() {
+    unsafe { asm!("cli", options(nostack, nomem)) }
+}
+
+#[inline]
+pub fn read_rflags() -> u64 {
+    let r: u64;
+
+    unsafe {
+        asm!("pushfq; pop {}", out(reg) r, options(nomem, preserves_flags));
+    }
+
+    r
+}
+
+#[inline]
+pub fn interrupts_enabled() -> bool {
+    let rflags = read_rflags();
+
+    rflags & (1 << 9) > 0
+}
+
+#[inline]
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let enabled = interrupts_enabled();
+
+    if enabled {
+        disable_interrupts();
+    }
+
+    let result = f();
+
+    if enabled {
+        enable_interrupts();
+    }
+
+    result
+}
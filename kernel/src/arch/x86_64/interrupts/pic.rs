@@ -0,0 +1,202 @@
+use lazy_static::lazy_static;
+use crate::arch::ports::Port;
+use crate::arch::{Arch, PortIo};
+use spin::Mutex;
+
+pub const BASE_COMMAND_PORT: u16 = 0x20;
+pub const HIGHER_COMMAND_PORT: u16 = 0xA0;
+
+const BASE_COMMAND: Port<u8> = Port::new(BASE_COMMAND_PORT);
+const BASE_DATA: Port<u8> = BASE_COMMAND.offset(1);
+
+const HIGHER_COMMAND: Port<u8> = Port::new(HIGHER_COMMAND_PORT);
+const HIGHER_DATA: Port<u8> = HIGHER_COMMAND.offset(1);
+
+const ICW1_INIT: u8 = 0x10;
+const ICW1_ICW4: u8 = 0x1;
+
+const ICW4_8086: u8 = 0x1;
+
+const END_OF_INTERRUPT: u8 = 0x20;
+
+// OCW3 command to read the in-service register on the next read of the command port
+const READ_ISR: u8 = 0x0B;
+
+// The base PIC's line the higher PIC is chained to
+const CASCADE_IRQ: u8 = 2;
+
+pub const PIC_IRQ_OFFSET: u8 = 0x20;
+
+#[repr(u8)]
+pub enum Irq {
+    Timer = 0x0,
+    Keyboard = 0x1,
+    /// Nothing is on this line; it is where the base PIC sends its spurious interrupts.
+    Spurious = 0x7,
+    /// What QEMU's firmware routes the AHCI controller to, if its PCI interrupt line is unset.
+    Ahci = 0xA,
+    PrimaryAta = 0xE,
+    /// Also where the higher PIC sends its spurious interrupts.
+    SecondaryAta = 0xF,
+}
+
+lazy_static! {
+    pub static ref PIC: Mutex<PicPair> = { 
+        Mutex::new(PicPair::new(PIC_IRQ_OFFSET, PIC_IRQ_OFFSET + 8))
+    };
+}
+
+#[derive(Debug)]
+pub struct PicPair {
+    pub base_pic: Pic,
+    pub higher_pic: Pic,
+}
+
+#[derive(Debug)]
+pub struct Pic {
+    /// The interrupt vector offset this PIC handles
+    offset: u8,
+}
+
+impl PicPair {
+    /// Creates and initializes both PIC interfaces.
+    pub fn new(offset1: u8, offset2: u8) -> Self {
+        Self {
+            base_pic: Pic { offset: offset1 },
+            higher_pic: Pic { offset: offset2 },
+        }
+    }
+
+    /// ## Safety
+    /// The function should only be called on one PicPair object, ever. Calling more than once will result in undefined
+    /// behavior. In addition, both offsets should have a distance of 8 from each other.
+    pub unsafe fn initialize(&mut self) {
+        let mask1 = BASE_DATA.read();
+        let mask2 = HIGHER_DATA.read();
+
+        BASE_COMMAND.write(ICW1_INIT | ICW1_ICW4);
+        Arch::io_wait();
+        HIGHER_COMMAND.write(ICW1_INIT | ICW1_ICW4);
+        Arch::io_wait();
+
+        BASE_DATA.write(self.base_pic.offset);
+        Arch::io_wait();
+        HIGHER_DATA.write(self.higher_pic.offset);
+        Arch::io_wait();
+
+        // Set up chaining on these PICs
+        BASE_DATA.write(0x04);
+        Arch::io_wait();
+        HIGHER_DATA.write(0x02);
+        Arch::io_wait();
+
+        BASE_DATA.write(ICW4_8086);
+        Arch::io_wait();
+        HIGHER_DATA.write(ICW4_8086);
+        Arch::io_wait();
+
+        self.write_interrupt_masks(mask1, mask2);
+    }
+
+    /// The base and the higher PIC's masks, as `write_interrupt_masks` takes them.
+    #[inline]
+    pub unsafe fn read_interrupt_masks(&mut self) -> (u8, u8) {
+        (BASE_DATA.read(), HIGHER_DATA.read())
+    }
+
+    #[inline]
+    pub unsafe fn write_interrupt_masks(&mut self, mask1: u8, mask2: u8) {
+        BASE_DATA.write(mask1);
+        HIGHER_DATA.write(mask2);
+    }
+
+    /// Stop `irq` from raising interrupts.
+    pub unsafe fn mask_irq(&mut self, irq: Irq) {
+        self.mask_line(irq as u8)
+    }
+
+    /// Let `irq` raise interrupts. Lines start out masked, so drivers unmask the ones they use.
+    pub unsafe fn unmask_irq(&mut self, irq: Irq) {
+        self.unmask_line(irq as u8)
+    }
+
+    /// Like `mask_irq`, for lines only known at runtime (e.g. a PCI device's interrupt line).
+    pub unsafe fn mask_line(&mut self, line: u8) {
+        let (port, bit) = Self::mask_port(line);
+        port.write(port.read() | bit);
+    }
+
+    /// Like `unmask_irq`, for lines only known at runtime (e.g. a PCI device's interrupt line).
+    pub unsafe fn unmask_line(&mut self, line: u8) {
+        let (port, bit) = Self::mask_port(line);
+        port.write(port.read() & !bit);
+
+        // Nothing from the higher PIC gets through unless the line it is chained to is unmasked
+        if line >= 8 {
+            self.unmask_line(CASCADE_IRQ);
+        }
+    }
+
+    // The data port with the mask for `line`, and its bit in that mask
+    fn mask_port(line: u8) -> (Port<u8>, u8) {
+        if line < 8 {
+            (BASE_DATA, 1 << line)
+        } else {
+            (HIGHER_DATA, 1 << (line - 8))
+        }
+    }
+
+    /// The lines currently being serviced, the higher PIC's in the top byte.
+    pub unsafe fn in_service(&mut self) -> u16 {
+        BASE_COMMAND.write(READ_ISR);
+        HIGHER_COMMAND.write(READ_ISR);
+        let base = BASE_COMMAND.read();
+        let higher = HIGHER_COMMAND.read();
+        (higher as u16) << 8 | base as u16
+    }
+
+    /// Whether an interrupt on `irq` is spurious, i.e. the line went away before the CPU took the
+    /// interrupt and the PIC raised its lowest priority line (7 or 15) instead. Handlers for those
+    /// lines should check this first, and return without doing anything or sending an end of
+    /// interrupt if it is. The base PIC did see a real interrupt on the cascade line for a
+    /// spurious IRQ 15 though, so that one is acknowledged here.
+    pub unsafe fn is_spurious(&mut self, irq: u8) -> bool {
+        if irq != 7 && irq != 15 {
+            return false;
+        }
+
+        if self.in_service() & (1 << irq) != 0 {
+            return false;
+        }
+
+        if irq == 15 {
+            BASE_COMMAND.write(END_OF_INTERRUPT);
+        }
+        true
+    }
+
+    #[inline]
+    pub unsafe fn disable(&mut self) {
+        self.write_interrupt_masks(0xFF, 0xFF)
+    }
+
+    #[inline]
+    pub unsafe fn enable_all(&mut self) {
+        self.write_interrupt_masks(0x00, 0x00)
+    }
+
+    pub unsafe fn end_of_interrupt(&mut self, irq: u8) {
+        if self.higher_pic.handles_interrupt(self.base_pic.offset + irq) {
+            HIGHER_COMMAND.write(END_OF_INTERRUPT);
+        }
+
+        BASE_COMMAND.write(END_OF_INTERRUPT)
+    }
+}
+
+impl Pic {
+    #[inline]
+    pub fn handles_interrupt(&self, vector: u8) -> bool {
+        self.offset <= vector && vector < self.offset + 8
+    }
+}
@@ -0,0 +1,77 @@
+// Keeps track of which IDT vectors are in use and by whom. Vectors below 32 are the CPU's
+// exceptions and are never handed out. Fixed vectors (the PIC's range, the local APIC's spurious
+// vector, ...) are reserved by whoever owns them before anything is allocated, and drivers that
+// can be told which vector to use (MSI, MSI-X, IOAPIC routes) get theirs from `allocate`.
+
+use super::without_interrupts;
+use spin::Mutex;
+
+const FIRST_VECTOR: usize = 32;
+const NUM_VECTORS: usize = 256;
+
+// The owner of each vector, if it has one
+static OWNERS: Mutex<[Option<&'static str>; NUM_VECTORS]> = Mutex::new([None; NUM_VECTORS]);
+
+/// Claim a vector that is fixed in hardware or by convention. Fails if it is an exception vector
+/// or already taken.
+pub fn reserve(vector: u8, owner: &'static str) -> Result<(), ()> {
+    if (vector as usize) < FIRST_VECTOR {
+        return Err(());
+    }
+
+    without_interrupts(|| {
+        let mut owners = OWNERS.lock();
+        match owners[vector as usize] {
+            Some(_) => Err(()),
+            None => {
+                owners[vector as usize] = Some(owner);
+                Ok(())
+            }
+        }
+    })
+}
+
+/// Like `reserve`, for `count` vectors in a row starting at `first`. Nothing is reserved if any of
+/// them is taken.
+pub fn reserve_range(first: u8, count: u8, owner: &'static str) -> Result<(), ()> {
+    let range = first as usize..first as usize + count as usize;
+    if range.start < FIRST_VECTOR || range.end > NUM_VECTORS {
+        return Err(());
+    }
+
+    without_interrupts(|| {
+        let mut owners = OWNERS.lock();
+        if owners[range.clone()].iter().any(Option::is_some) {
+            return Err(());
+        }
+
+        owners[range].fill(Some(owner));
+        Ok(())
+    })
+}
+
+/// Hand out a free vector. Lower vectors have lower priority on the local APIC, so they are
+/// handed out from the top down, leaving the low ones for whatever asks last. `None` if every
+/// vector is taken.
+pub fn allocate(owner: &'static str) -> Option<u8> {
+    without_interrupts(|| {
+        let mut owners = OWNERS.lock();
+        let vector = (FIRST_VECTOR..NUM_VECTORS)
+            .rev()
+            .find(|&vector| owners[vector].is_none())?;
+
+        owners[vector] = Some(owner);
+        Some(vector as u8)
+    })
+}
+
+/// Give back a vector from `allocate` or `reserve`, e.g. when a driver is torn down. Its handler
+/// should be gone from the IDT, and the device should no longer be raising it.
+pub fn free(vector: u8) {
+    without_interrupts(|| OWNERS.lock()[vector as usize] = None);
+}
+
+/// Who has `vector`, if anyone.
+pub fn owner(vector: u8) -> Option<&'static str> {
+    without_interrupts(|| OWNERS.lock()[vector as usize])
+}
@@ -0,0 +1,145 @@
+// The physical memory map, built once at boot from the regions the bootloader hands over (see
+// `bootinfo`), sorted and with adjacent regions of the same type merged. It's needed before the
+// heap, so it lives in the early allocator's arena.
+// The frame allocator takes its RAM from here, and the SRAT (if there is one) says which NUMA
+// node each part of it belongs to.
+
+use crate::allocator::early;
+use crate::klib::acpi::srat::SRAT;
+use crate::klib::once_lock::OnceLock;
+use crate::log_warn;
+use alloc::vec::Vec;
+
+static MEMORY_MAP: OnceLock<&'static [Region]> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionType {
+    /// Free RAM, for the frame allocator to hand out
+    Usable,
+    /// RAM the bootloader put the kernel, its page tables and the boot info in
+    Bootloader,
+    /// RAM holding ACPI tables, which can be used once they have been read
+    AcpiReclaim,
+    /// RAM the firmware keeps for itself across sleep states
+    AcpiNvs,
+    /// Device memory the firmware knows about
+    Mmio,
+    /// RAM with errors in it
+    Unusable,
+    /// Anything else the firmware keeps for itself
+    Reserved,
+}
+
+impl RegionType {
+    /// Whether the region is RAM, as opposed to device memory or a hole.
+    pub fn is_ram(self) -> bool {
+        matches!(
+            self,
+            RegionType::Usable
+                | RegionType::Bootloader
+                | RegionType::AcpiReclaim
+                | RegionType::AcpiNvs
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub start: u64,
+    pub end: u64,
+    pub region_type: RegionType,
+}
+
+impl Region {
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// The NUMA node the region starts in, if the SRAT says.
+    pub fn domain(&self) -> Option<u32> {
+        SRAT.get()?.domain_of(self.start)
+    }
+}
+
+/// Build the memory map. Has to be called before the frame allocator is set up. The map is empty
+/// if there's no room for it.
+pub fn init(memory_regions: &[Region]) -> &'static [Region] {
+    let mut len = 0;
+    let Ok(map) = early::collect_with(memory_regions.iter().copied(), |map| {
+        map.sort_unstable_by_key(|region| region.start);
+
+        // Merged in place, into the start of the map
+        for i in 0..map.len() {
+            let region = map[i];
+            match len.checked_sub(1).map(|last| &mut map[last]) {
+                Some(last)
+                    if last.end == region.start && last.region_type == region.region_type =>
+                {
+                    last.end = region.end;
+                }
+                _ => {
+                    map[len] = region;
+                    len += 1;
+                }
+            }
+        }
+    }) else {
+        log_warn!("No room for {} memory regions", memory_regions.len());
+        return regions();
+    };
+
+    let _ = MEMORY_MAP.set(&map[..len]);
+    regions()
+}
+
+/// Every region, lowest first. Empty before `init`.
+pub fn regions() -> &'static [Region] {
+    MEMORY_MAP.get().copied().unwrap_or(&[])
+}
+
+/// How many bytes of RAM the machine has, and how many of them the frame allocator can use.
+pub fn ram() -> (u64, u64) {
+    let total = regions()
+        .iter()
+        .filter(|region| region.region_type.is_ram())
+        .map(Region::size)
+        .sum();
+    let usable = regions()
+        .iter()
+        .filter(|region| region.region_type == RegionType::Usable)
+        .map(Region::size)
+        .sum();
+
+    (total, usable)
+}
+
+/// Usable RAM in each NUMA node, as (node, bytes), from the SRAT. Empty if there is no SRAT.
+pub fn ram_by_domain() -> Vec<(u32, u64)> {
+    let Some(srat) = SRAT.get() else {
+        return Vec::new();
+    };
+
+    srat.domains()
+        .into_iter()
+        .map(|domain| {
+            let bytes = srat
+                .memory
+                .iter()
+                .filter(|range| range.domain == domain)
+                .map(|range| usable_between(range.base, range.base.saturating_add(range.length)))
+                .sum();
+            (domain, bytes)
+        })
+        .collect()
+}
+
+// Usable RAM between two physical addresses
+fn usable_between(start: u64, end: u64) -> u64 {
+    regions()
+        .iter()
+        .filter(|region| region.region_type == RegionType::Usable)
+        .map(|region| (region.start.max(start), region.end.min(end)))
+        .filter(|(start, end)| start < end)
+        .map(|(start, end)| end - start)
+        .sum()
+}
@@ -0,0 +1,102 @@
+pub mod cpu;
+pub mod fpu;
+pub mod interrupts;
+pub mod memory_map;
+pub mod nx;
+pub mod paging;
+pub mod port;
+pub mod reserved;
+pub mod smap;
+pub mod wakeup;
+
+use super::{Cpu, Paging, PortIo};
+
+pub struct X86_64;
+
+impl Cpu for X86_64 {
+    #[inline]
+    fn halt() {
+        cpu::hlt();
+    }
+
+    #[inline]
+    fn pause() {
+        cpu::pause();
+    }
+
+    #[inline]
+    fn interrupts_enabled() -> bool {
+        interrupts::interrupts_enabled()
+    }
+
+    #[inline]
+    fn enable_interrupts() {
+        interrupts::enable_interrupts();
+    }
+
+    #[inline]
+    fn disable_interrupts() {
+        interrupts::disable_interrupts();
+    }
+
+    #[inline]
+    fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+        interrupts::without_interrupts(f)
+    }
+
+    #[inline]
+    fn timestamp() -> u64 {
+        cpu::rdtsc()
+    }
+
+    #[inline]
+    fn breakpoint() {
+        cpu::int3();
+    }
+}
+
+impl PortIo for X86_64 {
+    #[inline]
+    unsafe fn read_u8(port: u16) -> u8 {
+        port::port_read_u8(port)
+    }
+
+    #[inline]
+    unsafe fn read_u16(port: u16) -> u16 {
+        port::port_read_u16(port)
+    }
+
+    #[inline]
+    unsafe fn read_u32(port: u16) -> u32 {
+        port::port_read_u32(port)
+    }
+
+    #[inline]
+    unsafe fn write_u8(port: u16, data: u8) {
+        port::port_write_u8(port, data)
+    }
+
+    #[inline]
+    unsafe fn write_u16(port: u16, data: u16) {
+        port::port_write_u16(port, data)
+    }
+
+    #[inline]
+    unsafe fn write_u32(port: u16, data: u32) {
+        port::port_write_u32(port, data)
+    }
+
+    #[inline]
+    unsafe fn io_wait() {
+        port::io_wait()
+    }
+}
+
+impl Paging for X86_64 {
+    const PAGE_SIZE: u64 = 4096;
+
+    #[inline]
+    fn translate(addr: u64) -> Option<u64> {
+        paging::translate(addr)
+    }
+}
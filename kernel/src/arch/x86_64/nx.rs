@@ -0,0 +1,131 @@
+// Keeping data from being run and code from being written. Every page the kernel maps is either
+// writable or executable, never both (W^X): data pages carry NO_EXECUTE, and the kernel's own code
+// and read-only data are made read-only once paging is set up. With write protection on, that
+// holds for the kernel itself as well as for anything running below it.
+//
+// The kernel's segments are found from its ELF program headers, which the bootloader leaves in
+// memory with the rest of the image.
+
+use crate::{log_info, log_warn};
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+// ELF header and program header fields
+const ELF_MAGIC: [u8; 4] = *b"\x7FELF";
+const E_ENTRY: u64 = 0x18;
+const E_PHOFF: u64 = 0x20;
+const E_PHENTSIZE: u64 = 0x36;
+const E_PHNUM: u64 = 0x38;
+const P_TYPE: u64 = 0x00;
+const P_FLAGS: u64 = 0x04;
+const P_VADDR: u64 = 0x10;
+const P_MEMSZ: u64 = 0x28;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+
+extern "C" {
+    // The kernel's entry point, from `bootinfo`'s `entry_point!`
+    fn _start() -> !;
+}
+
+/// Turn on the NX bit, so that NO_EXECUTE in a page table entry means something rather than being
+/// a reserved bit, and write protection, so that read-only pages are read-only to the kernel too.
+/// Has to be called before anything is mapped with NO_EXECUTE.
+pub fn enable() {
+    unsafe {
+        Efer::update(|efer| efer.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|cr0| cr0.insert(Cr0Flags::WRITE_PROTECT));
+    }
+}
+
+/// Whether `flags` would make a page both writable and executable.
+pub fn is_writable_executable(flags: PageTableFlags) -> bool {
+    flags.contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+        && !flags.contains(PageTableFlags::NO_EXECUTE)
+}
+
+/// Catch mappings that would be writable and executable. Called by everything that maps pages;
+/// only checks in debug builds.
+#[track_caller]
+pub fn check(flags: PageTableFlags) {
+    debug_assert!(
+        !is_writable_executable(flags),
+        "Mapping a page writable and executable: {:?}",
+        flags
+    );
+}
+
+/// Set the kernel image's pages to what its ELF segments say: code read-only and executable,
+/// read-only data read-only, and everything else not executable. Pages shared by two segments
+/// are left as the bootloader mapped them.
+/// ### Safety
+/// `kernel_addr` has to be the physical address the bootloader loaded the kernel's ELF file at.
+pub unsafe fn protect_kernel(mapper: &mut OffsetPageTable, kernel_addr: u64) {
+    let image = mapper.phys_offset().as_u64() + kernel_addr;
+    let read =
+        |offset: u64, len: usize| core::slice::from_raw_parts((image + offset) as *const u8, len);
+    let read_u16 = |offset| u16::from_le_bytes(read(offset, 2).try_into().unwrap());
+    let read_u32 = |offset| u32::from_le_bytes(read(offset, 4).try_into().unwrap());
+    let read_u64 = |offset| u64::from_le_bytes(read(offset, 8).try_into().unwrap());
+
+    if read(0, 4) != ELF_MAGIC {
+        log_warn!("The kernel image isn't an ELF file, not protecting it");
+        return;
+    }
+
+    // A position independent kernel is loaded somewhere other than where it was linked
+    let load_offset = (_start as usize as u64).wrapping_sub(read_u64(E_ENTRY));
+
+    let phoff = read_u64(E_PHOFF);
+    let phentsize = read_u16(E_PHENTSIZE) as u64;
+    let mut protected = 0;
+    for i in 0..read_u16(E_PHNUM) as u64 {
+        let header = phoff + i * phentsize;
+        if read_u32(header + P_TYPE) != PT_LOAD {
+            continue;
+        }
+
+        let start = read_u64(header + P_VADDR).wrapping_add(load_offset);
+        let end = start + read_u64(header + P_MEMSZ);
+        let segment_flags = read_u32(header + P_FLAGS);
+
+        // Only the pages wholly inside the segment
+        let first: Page = Page::containing_address(VirtAddr::new(start).align_up(Size4KiB::SIZE));
+        let last: Page = Page::containing_address(VirtAddr::new(end).align_down(Size4KiB::SIZE));
+        for page in Page::range(first, last) {
+            if set_segment_flags(mapper, page, segment_flags) {
+                protected += 1;
+            }
+        }
+    }
+
+    log_info!("Protected {} pages of the kernel image", protected);
+}
+
+// Returns whether the page was updated. Huge pages and pages that aren't mapped are skipped.
+fn set_segment_flags(mapper: &mut OffsetPageTable, page: Page, segment_flags: u32) -> bool {
+    let TranslateResult::Mapped { mut flags, .. } = mapper.translate(page.start_address()) else {
+        return false;
+    };
+
+    flags.set(PageTableFlags::WRITABLE, segment_flags & PF_W != 0);
+    flags.set(PageTableFlags::NO_EXECUTE, segment_flags & PF_X == 0);
+    if is_writable_executable(flags) {
+        log_warn!("Kernel segment at {:?} is writable and executable", page);
+        return false;
+    }
+
+    match unsafe { mapper.update_flags(page, flags) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => false,
+    }
+}
@@ -1,4 +1,4 @@
-use crate::x86_64::PhysicalAddress;
+use super::paging::PhysicalAddress;
 
 #[derive(Clone, Copy)]
 #[repr(transparent)]
@@ -0,0 +1,315 @@
+use super::memory_map::{Region, RegionType};
+use super::{nx, reserved};
+use crate::KERNEL_PAGETABLE;
+use core::fmt;
+use x86_64::{
+    structures::paging::mapper::MapToError, structures::paging::mapper::TranslateError,
+    structures::paging::FrameAllocator, structures::paging::Mapper,
+    structures::paging::OffsetPageTable, structures::paging::Page, structures::paging::PageSize,
+    structures::paging::PageTable, structures::paging::PageTableFlags,
+    structures::paging::PhysFrame, structures::paging::Size2MiB, structures::paging::Size4KiB,
+    structures::paging::Translate, PhysAddr, VirtAddr,
+};
+
+/// Which pages `map_memory` and `map_physical` should use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingSize {
+    /// 4KiB pages only
+    Small,
+    /// 2MiB pages for every aligned 2MiB of the range, and 4KiB pages for the rest or wherever a
+    /// huge page can't be used
+    Huge,
+}
+
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static [Region],
+    next: usize,
+}
+
+impl BootInfoFrameAllocator {
+    /// Hand out the usable RAM in `memory_map`, see `memory_map::init`.
+    pub unsafe fn init(memory_map: &'static [Region]) -> Self {
+        Self {
+            memory_map,
+            next: 0usize,
+        }
+    }
+
+    // Reserved frames are left out, see `reserved`
+    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
+        let regions = self.memory_map.iter();
+        let usable_regions = regions.filter(|r| r.region_type == RegionType::Usable);
+        let addr_ranges = usable_regions.map(|r| r.start..r.end);
+        let frame_addresses = addr_ranges
+            .flat_map(|r| r.step_by(4096))
+            .filter(|&addr| !reserved::is_reserved(addr, 4096));
+
+        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+
+    /// Whether any of `size` bytes at `start` is RAM the allocator could hand out.
+    pub fn overlaps_usable(&self, start: u64, size: u64) -> bool {
+        let end = start.saturating_add(size);
+        self.memory_map
+            .iter()
+            .any(|r| r.region_type == RegionType::Usable && r.start < end && start < r.end)
+    }
+
+    /// Allocate `count` physically contiguous frames, the first of which is aligned to `align`
+    /// bytes. Frames skipped over to find such a run are not handed out again.
+    pub fn allocate_contiguous(&mut self, count: usize, align: u64) -> Option<PhysFrame> {
+        let mut run_start: Option<(usize, PhysFrame)> = None;
+        let mut run_length = 0;
+
+        for (index, frame) in self.usable_frames().enumerate().skip(self.next) {
+            let continues_run =
+                run_start.is_some_and(|(_, start)| start + run_length as u64 == frame);
+
+            if continues_run {
+                run_length += 1;
+            } else if frame.start_address().is_aligned(align) {
+                run_start = Some((index, frame));
+                run_length = 1;
+            } else {
+                run_start = None;
+                run_length = 0;
+            }
+
+            if let Some((start_index, start)) = run_start.filter(|_| run_length == count) {
+                self.next = start_index + count;
+                return Some(start);
+            }
+        }
+
+        None
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.usable_frames().nth(self.next);
+        self.next += 1;
+        frame
+    }
+}
+
+pub unsafe fn init_page_table(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}
+
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr // unsafe
+}
+
+/// The physical address `addr` is mapped to in the kernel page table, if it is mapped. Waits for
+/// the kernel page table to be set up if it isn't yet.
+pub fn translate(addr: u64) -> Option<u64> {
+    let page_table = KERNEL_PAGETABLE.wait().read();
+    page_table
+        .translate_addr(VirtAddr::new(addr))
+        .map(|phys| phys.as_u64())
+}
+
+/// Back `size` bytes of virtual memory at `start` with newly allocated frames.
+pub fn map_memory(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapping_size: MappingSize,
+) -> Result<(), MapToError<Size4KiB>> {
+    map_range(
+        mapper,
+        frame_allocator,
+        start,
+        None,
+        size,
+        flags,
+        mapping_size,
+    )
+}
+
+/// Map `size` bytes of physical memory at `phys` to `virt`. Pages that are already mapped are
+/// left alone.
+pub fn map_physical(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapping_size: MappingSize,
+) -> Result<(), MapToError<Size4KiB>> {
+    map_range(
+        mapper,
+        frame_allocator,
+        virt,
+        Some(phys),
+        size,
+        flags,
+        mapping_size,
+    )
+}
+
+// With `phys` set, maps the range to it; otherwise maps fresh frames.
+fn map_range(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    start: VirtAddr,
+    phys: Option<PhysAddr>,
+    size: u64,
+    flags: PageTableFlags,
+    mapping_size: MappingSize,
+) -> Result<(), MapToError<Size4KiB>> {
+    nx::check(flags);
+    let end = (start + size).align_up(Size4KiB::SIZE);
+    let start = start.align_down(Size4KiB::SIZE);
+    let phys_of =
+        |addr: VirtAddr| phys.map(|phys| phys.align_down(Size4KiB::SIZE) + (addr - start));
+
+    let mut addr = start;
+    while addr < end {
+        let huge_fits = mapping_size == MappingSize::Huge
+            && addr.is_aligned(Size2MiB::SIZE)
+            && end - addr >= Size2MiB::SIZE
+            && phys_of(addr).is_none_or(|phys| phys.is_aligned(Size2MiB::SIZE));
+
+        if huge_fits && map_huge(mapper, frame_allocator, addr, phys_of(addr), flags) {
+            addr += Size2MiB::SIZE;
+            continue;
+        }
+
+        // Fall back to splitting this part of the range into small pages
+        let chunk_end = if huge_fits {
+            addr + Size2MiB::SIZE
+        } else {
+            addr + Size4KiB::SIZE
+        };
+        while addr < chunk_end {
+            let page: Page<Size4KiB> = Page::containing_address(addr);
+            let frame = match phys_of(addr) {
+                Some(phys) => PhysFrame::containing_address(phys),
+                None => frame_allocator
+                    .allocate_frame()
+                    .ok_or(MapToError::FrameAllocationFailed)?,
+            };
+
+            match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => flush.flush(),
+                Err(MapToError::PageAlreadyMapped(_) | MapToError::ParentEntryHugePage)
+                    if phys.is_some() => {}
+                Err(err) => return Err(err),
+            }
+
+            addr += Size4KiB::SIZE;
+        }
+    }
+
+    Ok(())
+}
+
+// Try to map a single 2MiB page at `addr`. Fails if there's no 2MiB of contiguous memory left,
+// or if part of it is already mapped with small pages.
+fn map_huge(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    addr: VirtAddr,
+    phys: Option<PhysAddr>,
+    flags: PageTableFlags,
+) -> bool {
+    let page: Page<Size2MiB> = Page::containing_address(addr);
+
+    // The entry might already be a table of small pages, some of which are in use
+    if !matches!(
+        mapper.translate_page(page),
+        Err(TranslateError::PageNotMapped)
+    ) {
+        return false;
+    }
+
+    let frame = match phys {
+        Some(phys) => PhysFrame::containing_address(phys),
+        None => {
+            let frames = (Size2MiB::SIZE / Size4KiB::SIZE) as usize;
+            match frame_allocator.allocate_contiguous(frames, Size2MiB::SIZE) {
+                Some(frame) => PhysFrame::containing_address(frame.start_address()),
+                None => return false,
+            }
+        }
+    };
+
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+#[repr(transparent)]
+#[derive(Clone, Copy)]
+pub struct CanonicalAddress(u64);
+
+impl CanonicalAddress {
+    /// ## Safety
+    /// This must be a valid canonical address (i.e., an address with its 47th bit sign-extended up
+    /// to 64 bits, i.e. in the ranges 0x0000_0000_0000_0000..=0x0000_7FFF_FFFF_FFFF OR 0xFFFF_F000_0000_0000..)
+    #[inline]
+    pub unsafe fn new_unsafe(addr: u64) -> Self {
+        Self(addr)
+    }
+
+    /// ## Panics
+    /// This function will panic if passed an invalid canonical address (see new_unsafe for
+    /// details).
+    pub fn new(addr: u64) -> Self {
+        let mask = addr & 0xFFFF_0000_0000_0000;
+
+        if (mask != 0xFFFF_0000_0000_0000 && mask != 0x0)
+            || (mask > 0 && addr & 0x8000_0000_0000 == 0)
+            || (addr & 0x8000_0000_0000 != 0)
+        {
+            panic!("Invalid address for canonical address");
+        }
+
+        Self(addr)
+    }
+
+    #[inline]
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Debug for CanonicalAddress {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("CanonicalAddress")
+            .field(&format_args!("{:#x}", self.0))
+            .finish()
+    }
+}
+
+#[repr(transparent)]
+#[derive(Clone, Copy)]
+pub struct PhysicalAddress(u64);
+
+impl PhysicalAddress {
+    /// ## Safety
+    /// Bits 52 to 64 must be 0.
+    pub unsafe fn new_unsafe(addr: u64) -> Self {
+        PhysicalAddress(addr)
+    }
+}
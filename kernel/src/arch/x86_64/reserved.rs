@@ -0,0 +1,148 @@
+// Physical memory that may only be used by its owner: whatever the bootloader left behind (page
+// tables, the boot info, the kernel image), tables the firmware hands the OS, and device register
+// windows. The frame allocator never hands out a reserved frame, and `util::map_mmio` registers
+// every window it maps, refusing ones that overlap RAM or what the bootloader and kernel use.
+//
+// Reservations are made at boot, before the frames they might cover could have been allocated.
+
+use super::interrupts::without_interrupts;
+use super::memory_map::{Region, RegionType};
+use crate::klib::containers::static_vec::StaticVec;
+use crate::log_warn;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+// Adjacent and repeated reservations are merged, so this only has to cover distinct areas
+const MAX_RANGES: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// What the bootloader set up for the kernel, e.g. page tables and the boot info
+    Bootloader,
+    /// The kernel's own code and data
+    Kernel,
+    /// Memory the firmware keeps for itself or left tables in, like ACPI's
+    Firmware,
+    /// Device registers
+    Mmio,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Range {
+    pub start: u64,
+    pub end: u64,
+    pub kind: Kind,
+    pub owner: &'static str,
+}
+
+impl Range {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+impl Kind {
+    // Whether ranges of the two kinds can't overlap. Firmware often marks whole areas of device
+    // windows as reserved, so those are allowed to have register windows in them.
+    fn excludes(self, other: Kind) -> bool {
+        use Kind::*;
+        matches!(
+            (self, other),
+            (Mmio, Bootloader | Kernel) | (Bootloader | Kernel, Mmio)
+        )
+    }
+}
+
+static RESERVED: Mutex<StaticVec<Range, MAX_RANGES>> = Mutex::new(StaticVec::new());
+
+/// Reserve what the bootloader's memory map says isn't free RAM, and the kernel image.
+pub fn init(memory_regions: &[Region], kernel_addr: u64, kernel_len: u64) {
+    for region in memory_regions.iter() {
+        let (kind, owner) = match region.region_type {
+            RegionType::Usable => continue,
+            RegionType::Bootloader => (Kind::Bootloader, "bootloader"),
+            _ => (Kind::Firmware, "firmware"),
+        };
+
+        if reserve(region.start, region.end - region.start, kind, owner).is_err() {
+            log_warn!(
+                "Couldn't reserve {:#x}..{:#x} for the {}",
+                region.start,
+                region.end,
+                owner
+            );
+        }
+    }
+
+    if reserve(kernel_addr, kernel_len, Kind::Kernel, "kernel image").is_err() {
+        log_warn!("Couldn't reserve the kernel image");
+    }
+}
+
+/// Reserve `size` bytes of physical memory at `start` for `owner`. Fails if it overlaps a range it
+/// can't share with (device registers over memory the bootloader or kernel uses, or the other way
+/// around), or if there is no room left to track it.
+pub fn reserve(start: u64, size: u64, kind: Kind, owner: &'static str) -> Result<(), ()> {
+    let end = start.checked_add(size).filter(|_| size > 0).ok_or(())?;
+
+    without_interrupts(|| {
+        let mut ranges = RESERVED.lock();
+
+        let clashes = ranges
+            .iter()
+            .any(|range| range.overlaps(start, end) && range.kind.excludes(kind));
+        if clashes {
+            return Err(());
+        }
+
+        // Already covered, e.g. an ACPI table in memory the firmware map reserved as a whole
+        if ranges
+            .iter()
+            .any(|range| range.kind == kind && range.start <= start && end <= range.end)
+        {
+            return Ok(());
+        }
+
+        // Grow a range of the same owner that this touches, e.g. a window being mapped in parts
+        let touching = ranges.iter_mut().find(|range| {
+            range.kind == kind && range.owner == owner && range.start <= end && start <= range.end
+        });
+        if let Some(range) = touching {
+            range.start = range.start.min(start);
+            range.end = range.end.max(end);
+            return Ok(());
+        }
+
+        ranges
+            .push(Range {
+                start,
+                end,
+                kind,
+                owner,
+            })
+            .map_err(|_| ())
+    })
+}
+
+/// The reserved range overlapping `size` bytes at `start`, if any.
+pub fn find(start: u64, size: u64) -> Option<Range> {
+    let end = start.saturating_add(size);
+    without_interrupts(|| {
+        RESERVED
+            .lock()
+            .iter()
+            .find(|range| range.overlaps(start, end))
+            .copied()
+    })
+}
+
+pub fn is_reserved(start: u64, size: u64) -> bool {
+    find(start, size).is_some()
+}
+
+/// Every reserved range, lowest first.
+pub fn ranges() -> Vec<Range> {
+    let mut ranges: Vec<Range> = without_interrupts(|| RESERVED.lock().iter().copied().collect());
+    ranges.sort_unstable_by_key(|range| range.start);
+    ranges
+}
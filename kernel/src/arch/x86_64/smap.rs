@@ -0,0 +1,60 @@
+// Supervisor mode execution and access prevention. With SMEP the processor faults if the kernel
+// ever jumps to a user page, and with SMAP if it reads or writes one outside of `user_access`. A
+// kernel bug that follows a pointer into user memory then faults instead of doing what the user
+// put there. Both are only turned on if the processor has them.
+//
+// Nothing maps user pages yet, so for now all this does is make sure the kernel never starts
+// relying on touching them.
+
+use crate::log_info;
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+
+// CPUID leaf 7, EBX
+const CPUID_SMEP: u32 = 1 << 7;
+const CPUID_SMAP: u32 = 1 << 20;
+
+static SMAP: AtomicBool = AtomicBool::new(false);
+
+/// Turn on SMEP and SMAP if the processor has them.
+pub fn init() {
+    if unsafe { __cpuid(0) }.eax < 7 {
+        return;
+    }
+    let features = unsafe { __cpuid_count(7, 0) }.ebx;
+
+    let mut flags = Cr4Flags::empty();
+    if features & CPUID_SMEP != 0 {
+        flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if features & CPUID_SMAP != 0 {
+        flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+        SMAP.store(true, Ordering::Relaxed);
+    }
+    unsafe { Cr4::update(|cr4| cr4.insert(flags)) };
+
+    log_info!(
+        "SMEP: {}, SMAP: {}",
+        features & CPUID_SMEP != 0,
+        features & CPUID_SMAP != 0
+    );
+}
+
+/// Run `f` with access to user pages allowed. Interrupts are off meanwhile, as they don't clear
+/// the flag that allows it, and handlers shouldn't get to touch user pages either. Only for
+/// copying to and from user memory.
+pub fn user_access<R>(f: impl FnOnce() -> R) -> R {
+    if !SMAP.load(Ordering::Relaxed) {
+        return f();
+    }
+
+    interrupts::without_interrupts(|| {
+        unsafe { asm!("stac", options(nostack)) };
+        let result = f();
+        unsafe { asm!("clac", options(nostack)) };
+        result
+    })
+}
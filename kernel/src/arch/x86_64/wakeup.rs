@@ -0,0 +1,387 @@
+// Coming back from ACPI S3 (suspend to RAM). Memory keeps its contents while the machine sleeps,
+// but the processor doesn't: it wakes up in real mode at the waking vector the FACS gives the
+// firmware. That points at a trampoline copied to a page below 1 MiB, which gets back into long
+// mode on the kernel's page table and jumps to `wakeup_resume`. That restores the rest of what
+// `sleep` saved and returns from `sleep` a second time, as if the sleep had been a call.
+//
+// The firmware jumps to the page with CS = page >> 4 and IP = 0. The page is identity mapped in
+// the kernel's page table, so the trampoline is still where it runs from once paging is back on,
+// and the page table itself has to be below 4 GiB, as it is loaded from 32-bit code. The identity
+// mapping is read-only, as the page is code; the trampoline is written through the physical
+// memory mapping instead.
+
+use super::fpu::{self, FpuState};
+use super::interrupts::machine_check;
+use super::memory_map::{Region, RegionType};
+use super::paging::{self, MappingSize};
+use super::reserved;
+use crate::{BootInfoFrameAllocator, KERNEL_PAGETABLE};
+use core::arch::global_asm;
+use core::mem::offset_of;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+const PAGE_SIZE: u64 = 4096;
+
+// The trampoline has to be reachable from real mode
+const REAL_MODE_LIMIT: u64 = 0x10_0000;
+
+// Selectors in the trampoline's own GDT
+const CODE_64: u16 = 0x08;
+const DATA: u16 = 0x10;
+const CODE_32: u16 = 0x18;
+
+const CR0_PE: u32 = 1 << 0;
+const CR0_PG: u32 = 1 << 31;
+const CR4_PAE: u32 = 1 << 5;
+const IA32_EFER: u32 = 0xC000_0080;
+const EFER_LMA: u64 = 1 << 10;
+
+// Everything `wakeup_resume` restores. Registers that aren't here are saved on the stack.
+#[repr(C)]
+struct CpuState {
+    rsp: u64,
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+    // Descriptor table pointers, as stored by sgdt and sidt: a 2-byte limit and an 8-byte base
+    gdtr: [u8; 16],
+    idtr: [u8; 16],
+    cs: u16,
+    ss: u16,
+}
+
+static mut STATE: CpuState = CpuState {
+    rsp: 0,
+    cr0: 0,
+    cr3: 0,
+    cr4: 0,
+    efer: 0,
+    gdtr: [0; 16],
+    idtr: [0; 16],
+    cs: 0,
+    ss: 0,
+};
+
+// Physical address of the page kept for the trampoline, 0 if there isn't one
+static TRAMPOLINE: AtomicU64 = AtomicU64::new(0);
+
+// Where the page is in the physical memory mapping, for writing to it
+static TRAMPOLINE_ALIAS: AtomicU64 = AtomicU64::new(0);
+
+// Whether `init` has put the trampoline in its page
+static READY: AtomicBool = AtomicBool::new(false);
+
+// The trampoline, assembled into the kernel and copied down by `init`. The fields after the code
+// are filled in by `init` and `sleep`.
+global_asm!(
+    ".global wakeup_trampoline_start",
+    ".global wakeup_trampoline_end",
+    ".global wakeup_gdt",
+    ".global wakeup_gdt_pointer",
+    ".global wakeup_protected_jump",
+    ".global wakeup_protected",
+    ".global wakeup_efer",
+    ".global wakeup_cr3",
+    ".global wakeup_resume_address",
+    ".code16",
+    "wakeup_trampoline_start:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    "mov ss, ax",
+    "mov sp, {page_size}",
+    // The page's physical address, for once segments stop doing the adding
+    "xor ebx, ebx",
+    "mov bx, ax",
+    "shl ebx, 4",
+    "lgdt [wakeup_gdt_pointer - wakeup_trampoline_start]",
+    "mov eax, cr0",
+    "or eax, {cr0_pe}",
+    "mov cr0, eax",
+    // jmp far CODE_32:<32-bit offset>, with the offset filled in by `init`
+    ".byte 0x66, 0xEA",
+    "wakeup_protected_jump:",
+    ".long 0",
+    ".word {code_32}",
+    ".code32",
+    "wakeup_protected:",
+    "mov ax, {data}",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "lea esp, [ebx + {page_size}]",
+    "mov eax, {cr4_pae}",
+    "mov cr4, eax",
+    "mov eax, [ebx + wakeup_cr3 - wakeup_trampoline_start]",
+    "mov cr3, eax",
+    "mov ecx, {efer}",
+    "mov eax, [ebx + wakeup_efer - wakeup_trampoline_start]",
+    "mov edx, [ebx + wakeup_efer - wakeup_trampoline_start + 4]",
+    "wrmsr",
+    "mov eax, cr0",
+    "or eax, {cr0_pg}",
+    "mov cr0, eax",
+    "lea eax, [ebx + wakeup_long - wakeup_trampoline_start]",
+    "push {code_64}",
+    "push eax",
+    "retf",
+    ".code64",
+    "wakeup_long:",
+    "jmp qword ptr [rip + wakeup_resume_address]",
+    ".align 8",
+    "wakeup_gdt:",
+    ".quad 0",
+    ".quad 0x00AF9A000000FFFF",
+    ".quad 0x00CF92000000FFFF",
+    ".quad 0x00CF9A000000FFFF",
+    "wakeup_gdt_end:",
+    "wakeup_resume_address:",
+    ".quad 0",
+    "wakeup_efer:",
+    ".quad 0",
+    "wakeup_cr3:",
+    ".long 0",
+    "wakeup_gdt_pointer:",
+    ".word wakeup_gdt_end - wakeup_gdt - 1",
+    ".long 0",
+    "wakeup_trampoline_end:",
+    page_size = const PAGE_SIZE,
+    cr0_pe = const CR0_PE,
+    cr0_pg = const CR0_PG,
+    cr4_pae = const CR4_PAE,
+    efer = const IA32_EFER,
+    code_32 = const CODE_32,
+    code_64 = const CODE_64,
+    data = const DATA,
+);
+
+// Callee-saved registers and the flags are pushed, the rest of the state is stored in STATE, and
+// `enter` is called to put the machine to sleep. If it returns, the machine didn't sleep, and this
+// returns 0. On wakeup the trampoline jumps to `wakeup_resume` instead, which puts everything back
+// and returns 1.
+global_asm!(
+    ".global wakeup_save_and_sleep",
+    "wakeup_save_and_sleep:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "pushfq",
+    "lea r8, [rip + {state}]",
+    "mov [r8 + {rsp}], rsp",
+    "mov rax, cr0",
+    "mov [r8 + {cr0}], rax",
+    "mov rax, cr3",
+    "mov [r8 + {cr3}], rax",
+    "mov rax, cr4",
+    "mov [r8 + {cr4}], rax",
+    "mov ecx, {efer}",
+    "rdmsr",
+    "mov [r8 + {efer_offset}], eax",
+    "mov [r8 + {efer_offset} + 4], edx",
+    "sgdt [r8 + {gdtr}]",
+    "sidt [r8 + {idtr}]",
+    "mov ax, cs",
+    "mov [r8 + {cs}], ax",
+    "mov ax, ss",
+    "mov [r8 + {ss}], ax",
+    "call rdi",
+    "xor eax, eax",
+    "jmp 2f",
+    "",
+    ".global wakeup_resume",
+    "wakeup_resume:",
+    "lea r8, [rip + {state}]",
+    "mov ecx, {efer}",
+    "mov eax, [r8 + {efer_offset}]",
+    "mov edx, [r8 + {efer_offset} + 4]",
+    "wrmsr",
+    "mov rax, [r8 + {cr4}]",
+    "mov cr4, rax",
+    "mov rax, [r8 + {cr3}]",
+    "mov cr3, rax",
+    "mov rax, [r8 + {cr0}]",
+    "mov cr0, rax",
+    "lgdt [r8 + {gdtr}]",
+    "lidt [r8 + {idtr}]",
+    "mov ax, [r8 + {ss}]",
+    "mov ss, ax",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov rsp, [r8 + {rsp}]",
+    "movzx rax, word ptr [r8 + {cs}]",
+    "push rax",
+    "lea rax, [rip + 1f]",
+    "push rax",
+    "retfq",
+    "1:",
+    "mov eax, 1",
+    "2:",
+    "popfq",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    state = sym STATE,
+    rsp = const offset_of!(CpuState, rsp),
+    cr0 = const offset_of!(CpuState, cr0),
+    cr3 = const offset_of!(CpuState, cr3),
+    cr4 = const offset_of!(CpuState, cr4),
+    efer_offset = const offset_of!(CpuState, efer),
+    gdtr = const offset_of!(CpuState, gdtr),
+    idtr = const offset_of!(CpuState, idtr),
+    cs = const offset_of!(CpuState, cs),
+    ss = const offset_of!(CpuState, ss),
+    efer = const IA32_EFER,
+);
+
+extern "C" {
+    static wakeup_trampoline_start: u8;
+    static wakeup_trampoline_end: u8;
+    static wakeup_gdt: u8;
+    static wakeup_gdt_pointer: u8;
+    static wakeup_protected_jump: u8;
+    static wakeup_protected: u8;
+    static wakeup_efer: u8;
+    static wakeup_cr3: u8;
+    static wakeup_resume_address: u8;
+
+    fn wakeup_save_and_sleep(enter: extern "C" fn()) -> u64;
+    fn wakeup_resume();
+}
+
+// Where `label` ended up in the copy of the trampoline at `page`
+fn relocate(page: u64, label: *const u8) -> u64 {
+    let start = unsafe { addr_of!(wakeup_trampoline_start) };
+    page + (label as u64 - start as u64)
+}
+
+/// Keep a page of free RAM below 1 MiB for the trampoline. Has to be called with the other
+/// reservations, before any frames are handed out.
+pub fn reserve(memory_regions: &[Region]) {
+    // The highest free page, as the lowest ones hold the real mode IVT and the BIOS data area
+    let page = memory_regions
+        .iter()
+        .filter(|region| region.region_type == RegionType::Usable)
+        .filter_map(|region| {
+            let page =
+                (region.end.min(REAL_MODE_LIMIT) & !(PAGE_SIZE - 1)).checked_sub(PAGE_SIZE)?;
+            (page >= region.start.max(PAGE_SIZE)).then_some(page)
+        })
+        .max();
+
+    let Some(page) = page else {
+        return;
+    };
+    if reserved::reserve(page, PAGE_SIZE, reserved::Kind::Kernel, "acpi wakeup").is_ok() {
+        TRAMPOLINE.store(page, Ordering::Relaxed);
+    }
+}
+
+/// Identity map the page `reserve` kept and copy the trampoline to it. Has to be called after the
+/// kernel page table has been set up.
+pub fn init(frame_allocator: &mut BootInfoFrameAllocator) -> Result<(), ()> {
+    let page = TRAMPOLINE.load(Ordering::Relaxed);
+    if page == 0 {
+        return Err(());
+    }
+
+    let alias = {
+        let mut page_table = KERNEL_PAGETABLE.get().ok_or(())?.write();
+        paging::map_physical(
+            &mut page_table,
+            frame_allocator,
+            VirtAddr::new(page),
+            PhysAddr::new(page),
+            PAGE_SIZE,
+            PageTableFlags::PRESENT,
+            MappingSize::Small,
+        )
+        .map_err(|_| ())?;
+        page_table.phys_offset().as_u64() + page
+    };
+    // It may have been mapped somewhere else already
+    if paging::translate(page) != Some(page) {
+        return Err(());
+    }
+    TRAMPOLINE_ALIAS.store(alias, Ordering::Relaxed);
+
+    unsafe {
+        let start = addr_of!(wakeup_trampoline_start);
+        let len = addr_of!(wakeup_trampoline_end) as usize - start as usize;
+        core::ptr::copy_nonoverlapping(start, alias as *mut u8, len);
+
+        // The GDT's base, the 32-bit code's address and where to go once in long mode are fixed
+        let gdt = relocate(page, addr_of!(wakeup_gdt));
+        let gdt_base = relocate(alias, addr_of!(wakeup_gdt_pointer)) + 2;
+        (gdt_base as *mut u32).write_unaligned(gdt as u32);
+        let protected = relocate(page, addr_of!(wakeup_protected));
+        (relocate(alias, addr_of!(wakeup_protected_jump)) as *mut u32)
+            .write_unaligned(protected as u32);
+        (relocate(alias, addr_of!(wakeup_resume_address)) as *mut u64)
+            .write_unaligned(wakeup_resume as usize as u64);
+    }
+
+    READY.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// The address for the FACS's firmware waking vector, if the trampoline is in place.
+pub fn waking_vector() -> Option<u32> {
+    READY
+        .load(Ordering::Relaxed)
+        .then(|| TRAMPOLINE.load(Ordering::Relaxed) as u32)
+}
+
+/// Save the processor's state and call `enter`, which should put the machine to sleep, returning
+/// once it wakes up again. Fails if `enter` returns, i.e. the machine didn't sleep. Has to be
+/// called with interrupts off.
+/// ### Safety
+/// Devices have to have been stopped already, and the waking vector set.
+pub unsafe fn sleep(enter: extern "C" fn()) -> Result<(), ()> {
+    if !READY.load(Ordering::Relaxed) {
+        return Err(());
+    }
+    let alias = TRAMPOLINE_ALIAS.load(Ordering::Relaxed);
+
+    // The trampoline loads the page table from 32-bit code
+    let cr3 = Cr3::read().0.start_address().as_u64();
+    if cr3 > u32::MAX as u64 {
+        return Err(());
+    }
+    (relocate(alias, addr_of!(wakeup_cr3)) as *mut u32).write_unaligned(cr3 as u32);
+
+    // Long mode is turned on by paging, so LMA has to start out clear
+    let efer = Msr::new(IA32_EFER).read() & !EFER_LMA;
+    (relocate(alias, addr_of!(wakeup_efer)) as *mut u64).write_unaligned(efer);
+
+    // The task's FPU registers are lost along with everything else
+    let mut fpu_state = FpuState::new();
+    fpu_state.save();
+
+    let woke = wakeup_save_and_sleep(enter) != 0;
+    if woke {
+        // XCR0 and the machine check banks are set up through registers the firmware resets
+        fpu::init();
+        machine_check::init();
+    }
+    fpu_state.restore();
+
+    if woke {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
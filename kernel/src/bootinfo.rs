@@ -0,0 +1,150 @@
+// What the kernel needs from the bootloader, in the kernel's own types: the memory map, where the
+// kernel image and the physical memory mapping are, the framebuffer, the RSDP, the initramfs and
+// the command line. The bootloader's entry point is here too, and hands `kernel_main` a `BootInfo`
+// built from whatever the bootloader passed, so booting from another loader only means changing
+// this module.
+//
+// This is the `bootloader` crate's. It passes the firmware's memory types along as they are, so
+// they are decoded here, and it has no way to hand over a command line, so one is baked in when
+// the kernel is built, from the KERNEL_CMDLINE environment variable (e.g.
+// `KERNEL_CMDLINE="hwerror=continue" cargo run`).
+
+use crate::allocator::early;
+use crate::arch::x86_64::memory_map::{Region, RegionType};
+use crate::klib::graphics::{DisplayInfo, PixelLayout};
+use crate::{log_warn, task};
+use bootloader_api::config::{BootloaderConfig, Mapping};
+use bootloader_api::entry_point;
+use bootloader_api::info::{FrameBuffer, MemoryRegionKind, PixelFormat};
+
+// E820 types, for BIOS boots
+const E820_ACPI_RECLAIM: u32 = 3;
+const E820_ACPI_NVS: u32 = 4;
+const E820_UNUSABLE: u32 = 5;
+
+// UEFI memory types
+const UEFI_UNUSABLE: u32 = 8;
+const UEFI_ACPI_RECLAIM: u32 = 9;
+const UEFI_ACPI_NVS: u32 = 10;
+const UEFI_MMIO: u32 = 11;
+const UEFI_MMIO_PORT_SPACE: u32 = 12;
+
+const CMDLINE: &str = match option_env!("KERNEL_CMDLINE") {
+    Some(cmdline) => cmdline,
+    None => "",
+};
+
+static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config.kernel_stack_size = task::BOOT_STACK_SIZE;
+    config
+};
+
+entry_point!(start, config = &BOOTLOADER_CONFIG);
+
+pub struct BootInfo {
+    /// Physical memory as the bootloader describes it, in its order and without merging
+    pub memory_regions: &'static [Region],
+    /// Where the kernel image was loaded in physical memory, and its size
+    pub kernel_addr: u64,
+    pub kernel_len: u64,
+    /// Where all of physical memory is mapped in the kernel's address space
+    pub physical_memory_offset: u64,
+    pub framebuffer: Option<Framebuffer>,
+    pub rsdp_addr: Option<u64>,
+    /// The archive loaded alongside the kernel, if there is one, see fs/initramfs.rs
+    pub initramfs: Option<&'static [u8]>,
+    pub cmdline: &'static str,
+}
+
+/// The display memory the bootloader set up, and how to draw to it.
+pub struct Framebuffer {
+    pub buffer: &'static mut [u8],
+    pub info: DisplayInfo,
+}
+
+fn start(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
+    unsafe { task::fill_boot_stack() };
+    crate::kernel_main(from_bootloader(boot_info))
+}
+
+fn from_bootloader(boot_info: &'static mut bootloader_api::BootInfo) -> BootInfo {
+    let regions = boot_info.memory_regions.iter().map(|region| Region {
+        start: region.start,
+        end: region.end,
+        region_type: region_type(region.kind),
+    });
+    let memory_regions = early::collect(regions).unwrap_or_else(|()| {
+        log_warn!(
+            "No room for {} memory regions",
+            boot_info.memory_regions.len()
+        );
+        &[]
+    });
+
+    BootInfo {
+        memory_regions,
+        kernel_addr: boot_info.kernel_addr,
+        kernel_len: boot_info.kernel_len,
+        // Asked for in `BOOTLOADER_CONFIG`
+        physical_memory_offset: boot_info.physical_memory_offset.into_option().unwrap(),
+        framebuffer: boot_info.framebuffer.as_mut().map(framebuffer),
+        rsdp_addr: boot_info.rsdp_addr.into_option(),
+        initramfs: initramfs(boot_info),
+        cmdline: CMDLINE,
+    }
+}
+
+// The bootloader's ramdisk, which it maps into the kernel's address space and leaves there
+fn initramfs(boot_info: &bootloader_api::BootInfo) -> Option<&'static [u8]> {
+    let addr = boot_info.ramdisk_addr.into_option()?;
+    let len = usize::try_from(boot_info.ramdisk_len).ok()?;
+    Some(unsafe { core::slice::from_raw_parts(addr as *const u8, len) })
+}
+
+fn region_type(kind: MemoryRegionKind) -> RegionType {
+    match kind {
+        MemoryRegionKind::Usable => RegionType::Usable,
+        MemoryRegionKind::Bootloader => RegionType::Bootloader,
+        MemoryRegionKind::UnknownBios(E820_ACPI_RECLAIM)
+        | MemoryRegionKind::UnknownUefi(UEFI_ACPI_RECLAIM) => RegionType::AcpiReclaim,
+        MemoryRegionKind::UnknownBios(E820_ACPI_NVS)
+        | MemoryRegionKind::UnknownUefi(UEFI_ACPI_NVS) => RegionType::AcpiNvs,
+        MemoryRegionKind::UnknownUefi(UEFI_MMIO | UEFI_MMIO_PORT_SPACE) => RegionType::Mmio,
+        MemoryRegionKind::UnknownBios(E820_UNUSABLE)
+        | MemoryRegionKind::UnknownUefi(UEFI_UNUSABLE) => RegionType::Unusable,
+        _ => RegionType::Reserved,
+    }
+}
+
+fn framebuffer(framebuffer: &'static mut FrameBuffer) -> Framebuffer {
+    let info = framebuffer.info();
+    let layout = match info.pixel_format {
+        PixelFormat::Rgb => PixelLayout::Rgb,
+        PixelFormat::Bgr => PixelLayout::Bgr,
+        PixelFormat::U8 => PixelLayout::Grayscale,
+        PixelFormat::Unknown {
+            red_position,
+            green_position,
+            blue_position,
+        } => PixelLayout::BitMask {
+            red_position,
+            green_position,
+            blue_position,
+        },
+        // Nothing sensible to draw with; every pixel comes out black
+        _ => PixelLayout::Unsupported,
+    };
+
+    Framebuffer {
+        buffer: framebuffer.buffer_mut(),
+        info: DisplayInfo {
+            width: info.width,
+            height: info.height,
+            stride: info.stride,
+            bytes_per_pixel: info.bytes_per_pixel,
+            layout,
+        },
+    }
+}
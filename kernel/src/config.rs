@@ -0,0 +1,34 @@
+// Which optional parts of the kernel are in this build, as picked with the cargo features in
+// kernel/Cargo.toml.
+
+use crate::log_info;
+use alloc::vec::Vec;
+
+/// Every feature, and whether this build has it.
+pub const FEATURES: &[(&str, bool)] = &[
+    ("driver-ahci", cfg!(feature = "driver-ahci")),
+    ("driver-ide", cfg!(feature = "driver-ide")),
+    ("driver-nvme", cfg!(feature = "driver-nvme")),
+    ("driver-xhci", cfg!(feature = "driver-xhci")),
+    ("fs-ext2", cfg!(feature = "fs-ext2")),
+    ("net", cfg!(feature = "net")),
+    ("gui", cfg!(feature = "gui")),
+    ("kasan", cfg!(feature = "kasan")),
+    ("qemu", cfg!(feature = "qemu")),
+    ("selftest", cfg!(feature = "selftest")),
+];
+
+/// Log which features are on and which are off. Needs the heap.
+pub fn log_features() {
+    let names = |on: bool| {
+        FEATURES
+            .iter()
+            .filter(|&&(_, enabled)| enabled == on)
+            .map(|&(name, _)| name)
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+
+    log_info!("Features on: {}", names(true));
+    log_info!("Features off: {}", names(false));
+}
@@ -0,0 +1,326 @@
+// A read-only consistency check, like a much smaller fsck. It looks at the superblock, at whether
+// the bitmaps agree with the free counts in the group descriptors, at every directory reachable
+// from the root, and for inodes that are in use without being in any directory. Nothing is fixed:
+// problems are logged as warnings, the first `MAX_REPORTED` of them anyway, and counted.
+
+use super::{
+    Ext2Fs, INode, DIRENT_HEADER_SIZE, MAX_LOG_BLOCK_SIZE, ROOT_INO, TYPE_DIRECTORY, TYPE_MASK,
+};
+use crate::klib::block::{self, BlockDevice, IOError};
+use crate::{log_info, log_warn};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem::MaybeUninit;
+
+const MAX_REPORTED: usize = 32;
+
+// Revision 0 filesystems reserve the first 10 inodes, and don't say so in the superblock
+const GOOD_OLD_FIRST_INO: u32 = 11;
+
+// Directories bigger than this are assumed to be corrupted rather than read
+const MAX_DIRECTORY_SIZE: u64 = 16 * 1024 * 1024;
+
+struct Checker<'a> {
+    fs: &'a Ext2Fs,
+    disk: &'a dyn BlockDevice,
+    problems: usize,
+}
+
+/// Check the filesystem on `disk`, logging every problem found. Returns how many there were.
+pub fn check(fs: &Ext2Fs, disk: &dyn BlockDevice) -> Result<usize, IOError> {
+    let mut checker = Checker {
+        fs,
+        disk,
+        problems: 0,
+    };
+
+    checker.check_superblock();
+    // The rest depends on the superblock's numbers being right
+    if checker.problems == 0 {
+        let inode_bitmap = checker.check_groups()?;
+        let reached = checker.check_directories(&inode_bitmap)?;
+        checker.check_orphans(&inode_bitmap, &reached)?;
+    }
+
+    match checker.problems {
+        0 => log_info!("ext2: no problems found"),
+        problems if problems > MAX_REPORTED => log_warn!(
+            "ext2: found {} problems, only the first {} were logged",
+            problems,
+            MAX_REPORTED
+        ),
+        problems => log_warn!("ext2: found {} problems", problems),
+    }
+    if checker.problems > 0 {
+        fs.error(format_args!("the filesystem isn't consistent"));
+    }
+
+    Ok(checker.problems)
+}
+
+impl Checker<'_> {
+    fn problem(&mut self, args: fmt::Arguments) {
+        if self.problems < MAX_REPORTED {
+            log_warn!("ext2: {}", args);
+        }
+        self.problems += 1;
+    }
+
+    fn first_ino(&self) -> u32 {
+        match self.fs.superblock.rev_level {
+            0 => GOOD_OLD_FIRST_INO,
+            _ => self.fs.superblock.first_ino,
+        }
+    }
+
+    fn check_superblock(&mut self) {
+        let fs = self.fs;
+        let sb = &fs.superblock;
+        let block_size = fs.block_size() as u32;
+        let num_groups = fs.block_groups.len() as u32;
+
+        if sb.log_block_size > MAX_LOG_BLOCK_SIZE {
+            self.problem(format_args!("block size 1024 << {}", sb.log_block_size));
+            return;
+        }
+        // The first block group starts right after the superblock
+        let first_data_block = (block_size == 1024) as u32;
+        if sb.first_data_block != first_data_block {
+            self.problem(format_args!(
+                "first data block is {}, not {}",
+                sb.first_data_block, first_data_block
+            ));
+        }
+        if sb.blocks_per_group > block_size * 8 || sb.inodes_per_group > block_size * 8 {
+            self.problem(format_args!(
+                "{} blocks and {} inodes per group don't fit in one block's bitmap",
+                sb.blocks_per_group, sb.inodes_per_group
+            ));
+        }
+        if sb.inodes_count != sb.inodes_per_group.saturating_mul(num_groups) {
+            self.problem(format_args!(
+                "{} inodes, but {} groups of {}",
+                sb.inodes_count, num_groups, sb.inodes_per_group
+            ));
+        }
+        if sb.free_blocks_count > sb.blocks_count || sb.free_inodes_count > sb.inodes_count {
+            self.problem(format_args!(
+                "more free blocks ({}) or inodes ({}) than there are",
+                sb.free_blocks_count, sb.free_inodes_count
+            ));
+        }
+
+        let inode_size = fs.inode_size() as u32;
+        if !inode_size.is_power_of_two()
+            || inode_size < core::mem::size_of::<INode>() as u32
+            || inode_size > block_size
+        {
+            self.problem(format_args!("inode size {}", inode_size));
+        }
+        if self.first_ino() <= ROOT_INO {
+            self.problem(format_args!("first usable inode is {}", self.first_ino()));
+        }
+    }
+
+    // Check each group's bitmaps against its descriptor, and the totals against the superblock.
+    // Returns the inode bitmaps of every group, one after another.
+    fn check_groups(&mut self) -> Result<Vec<u8>, IOError> {
+        let fs = self.fs;
+        let sb = &fs.superblock;
+        let block_size = fs.block_size();
+        let inodes_per_group = sb.inodes_per_group as usize;
+        let table_blocks =
+            (inodes_per_group as u64 * fs.inode_size() as u64).div_ceil(block_size) as u32;
+        let in_fs = |block: u32| (sb.first_data_block..sb.blocks_count).contains(&block);
+
+        let mut inode_bitmap = Vec::with_capacity(fs.block_groups.len() * inodes_per_group / 8);
+        let mut bitmap = alloc::vec![MaybeUninit::uninit(); block_size as usize];
+        let (mut free_blocks, mut free_inodes) = (0, 0);
+
+        for (index, group) in fs.block_groups.iter().enumerate() {
+            if !in_fs(group.block_bitmap)
+                || !in_fs(group.inode_bitmap)
+                || !in_fs(group.inode_table)
+                || !in_fs(group.inode_table.saturating_add(table_blocks - 1))
+            {
+                self.problem(format_args!(
+                    "group {}'s bitmaps or inode table are outside the filesystem",
+                    index
+                ));
+                continue;
+            }
+
+            // The last group may be cut short
+            let group_start = sb.first_data_block + index as u32 * sb.blocks_per_group;
+            let group_blocks =
+                sb.blocks_per_group
+                    .min(sb.blocks_count.saturating_sub(group_start)) as usize;
+
+            let blocks = self.read_block(group.block_bitmap, &mut bitmap)?;
+            let free = count_clear(blocks, group_blocks);
+            if free != group.free_blocks_count as usize {
+                self.problem(format_args!(
+                    "group {} has {} free blocks, its descriptor says {}",
+                    index, free, group.free_blocks_count
+                ));
+            }
+            free_blocks += free;
+
+            let inodes = self.read_block(group.inode_bitmap, &mut bitmap)?;
+            let free = count_clear(inodes, inodes_per_group);
+            if free != group.free_inodes_count as usize {
+                self.problem(format_args!(
+                    "group {} has {} free inodes, its descriptor says {}",
+                    index, free, group.free_inodes_count
+                ));
+            }
+            free_inodes += free;
+            inode_bitmap.extend_from_slice(&inodes[..inodes_per_group.div_ceil(8)]);
+        }
+
+        if free_blocks != sb.free_blocks_count as usize
+            || free_inodes != sb.free_inodes_count as usize
+        {
+            self.problem(format_args!(
+                "{} free blocks and {} free inodes, the superblock says {} and {}",
+                free_blocks, free_inodes, sb.free_blocks_count, sb.free_inodes_count
+            ));
+        }
+
+        Ok(inode_bitmap)
+    }
+
+    // Go through every directory reachable from the root, checking its entries. Returns which
+    // inodes were found in a directory, indexed by inode number.
+    fn check_directories(&mut self, inode_bitmap: &[u8]) -> Result<Vec<bool>, IOError> {
+        let inodes_count = self.fs.superblock.inodes_count;
+        let block_size = self.fs.block_size() as usize;
+        let mut reached = alloc::vec![false; inodes_count as usize + 1];
+        let mut directories = VecDeque::from([ROOT_INO]);
+        reached[ROOT_INO as usize] = true;
+
+        if !in_use(inode_bitmap, ROOT_INO) {
+            self.problem(format_args!("the root directory's inode is free"));
+        }
+
+        while let Some(directory) = directories.pop_front() {
+            let inode = self.fs.read_inode(self.disk, directory)?;
+            if inode.mode & TYPE_MASK != TYPE_DIRECTORY {
+                self.problem(format_args!("inode {} is not a directory", directory));
+                continue;
+            }
+            let size = inode.size();
+            if size > MAX_DIRECTORY_SIZE || size % block_size as u64 != 0 {
+                self.problem(format_args!("directory {} is {} bytes", directory, size));
+                continue;
+            }
+
+            let mut contents = alloc::vec![MaybeUninit::uninit(); size as usize];
+            let contents = self.fs.read_file(self.disk, directory, 0, &mut contents)?;
+
+            for (block_index, block) in contents.chunks(block_size).enumerate() {
+                let mut offset = 0;
+                while offset + DIRENT_HEADER_SIZE <= block.len() {
+                    let entry = &block[offset..];
+                    let inode_number = u32::from_le_bytes(entry[..4].try_into().unwrap());
+                    let rec_len = u16::from_le_bytes(entry[4..6].try_into().unwrap()) as usize;
+                    let name_len = entry[6] as usize;
+
+                    if rec_len < DIRENT_HEADER_SIZE
+                        || rec_len % 4 != 0
+                        || rec_len > entry.len()
+                        || name_len > rec_len - DIRENT_HEADER_SIZE
+                    {
+                        self.problem(format_args!(
+                            "directory {} has a bad entry at {}",
+                            directory,
+                            block_index * block_size + offset
+                        ));
+                        break;
+                    }
+
+                    let name = &entry[DIRENT_HEADER_SIZE..DIRENT_HEADER_SIZE + name_len];
+                    let first = block_index == 0 && offset == 0;
+                    offset += rec_len;
+
+                    if first && (name != b"." || inode_number != directory) {
+                        self.problem(format_args!("directory {} doesn't start with .", directory));
+                    }
+                    // Unused entry
+                    if inode_number == 0 {
+                        continue;
+                    }
+                    if inode_number > inodes_count || !in_use(inode_bitmap, inode_number) {
+                        self.problem(format_args!(
+                            "directory {} has an entry for inode {}, which isn't in use",
+                            directory, inode_number
+                        ));
+                        continue;
+                    }
+                    if name == b"." || name == b".." || reached[inode_number as usize] {
+                        continue;
+                    }
+
+                    reached[inode_number as usize] = true;
+                    let child = self.fs.read_inode(self.disk, inode_number)?;
+                    if child.links_count == 0 {
+                        self.problem(format_args!(
+                            "inode {} is in directory {}, but has no links",
+                            inode_number, directory
+                        ));
+                    }
+                    if child.mode & TYPE_MASK == TYPE_DIRECTORY {
+                        directories.push_back(inode_number);
+                    }
+                }
+            }
+        }
+
+        Ok(reached)
+    }
+
+    // Look for inodes that are in use, but weren't found in any directory
+    fn check_orphans(&mut self, inode_bitmap: &[u8], reached: &[bool]) -> Result<(), IOError> {
+        for inode_number in self.first_ino()..=self.fs.superblock.inodes_count {
+            if !in_use(inode_bitmap, inode_number) || reached[inode_number as usize] {
+                continue;
+            }
+
+            // Deleted, but still marked in use; the bitmap counts already cover that
+            let inode = self.fs.read_inode(self.disk, inode_number)?;
+            if inode.links_count > 0 && inode.dtime == 0 {
+                self.problem(format_args!(
+                    "inode {} is in use, but not in any directory",
+                    inode_number
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_block<'b>(
+        &self,
+        block: u32,
+        buf: &'b mut [MaybeUninit<u8>],
+    ) -> Result<&'b mut [u8], IOError> {
+        let address = block as u64 * self.fs.block_size();
+        block::read_bytes(self.disk, buf, address as usize)
+    }
+}
+
+// How many of the first `bits` bits of `bitmap` are clear
+fn count_clear(bitmap: &[u8], bits: usize) -> usize {
+    (0..bits)
+        .filter(|bit| bitmap[bit / 8] & (1 << (bit % 8)) == 0)
+        .count()
+}
+
+// Whether the inode bitmaps, one group's after another, say `inode_number` is in use
+fn in_use(inode_bitmap: &[u8], inode_number: u32) -> bool {
+    let bit = inode_number as usize - 1;
+    inode_bitmap
+        .get(bit / 8)
+        .is_some_and(|byte| byte & (1 << (bit % 8)) != 0)
+}
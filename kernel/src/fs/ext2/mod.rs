@@ -1,54 +1,481 @@
 use super::super::klib;
+use crate::{log_error, log_info, log_warn};
+use alloc::vec::Vec;
+use core::fmt;
 use core::mem;
 use core::mem::MaybeUninit;
-use klib::ahci::ahcistate::AHCIState;
-use klib::ahci::ahcistate::Command;
-use klib::ahci::ahcistate::IOError;
+use core::sync::atomic::{AtomicBool, Ordering};
+use klib::block;
+use klib::block::BlockDevice;
+use klib::block::IOError;
+use klib::time::Timestamp;
+use klib::uuid::Uuid;
 use mem::size_of;
-use spin::RwLock;
+
+mod check;
+
+pub use check::check;
 
 const SUPERBLOCK_MAGIC: u16 = 0xEF53;
 const ROOT_INO: u32 = 2;
+// Revision 0 filesystems don't have the inode size in the superblock
+const GOOD_OLD_INODE_SIZE: u16 = 128;
+
+// 64 KiB blocks
+const MAX_LOG_BLOCK_SIZE: u32 = 6;
+// The descriptor table is read onto the heap whole, so this bounds it to 256 KiB
+const MAX_GROUPS: u32 = 8192;
+
+// An inode's block pointers: the first data blocks of the file, then a block of pointers to data
+// blocks, one of pointers to those, and one of pointers to those
+const NUM_DIRECT: usize = 12;
+const SINGLY_INDIRECT: usize = 12;
+const DOUBLY_INDIRECT: usize = 13;
+const TRIPLY_INDIRECT: usize = 14;
+
+const TYPE_MASK: u16 = 0xF000;
+const TYPE_DIRECTORY: u16 = 0x4000;
+const TYPE_REGULAR: u16 = 0x8000;
+
+// A directory entry: inode (4 bytes), record length (2), name length (1), file type (1), name
+const DIRENT_HEADER_SIZE: usize = 8;
+
+/// How a filesystem is mounted, parsed from a comma-separated list like "ro,errors=panic".
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MountOptions {
+    pub read_only: bool,
+    /// What to do about inconsistencies, instead of what the superblock says
+    pub errors: Option<ErrorHandling>,
+}
+
+impl MountOptions {
+    pub fn parse(options: &str) -> Result<Self, ()> {
+        let mut parsed = Self::default();
+        for option in options.split(',').filter(|option| !option.is_empty()) {
+            match option {
+                "ro" => parsed.read_only = true,
+                "rw" => parsed.read_only = false,
+                "errors=continue" => parsed.errors = Some(ErrorHandling::Ignore),
+                "errors=remount-ro" => parsed.errors = Some(ErrorHandling::RemountReadOnly),
+                "errors=panic" => parsed.errors = Some(ErrorHandling::KernelPanic),
+                _ => return Err(()),
+            }
+        }
+        Ok(parsed)
+    }
+}
 
 pub struct Ext2Fs {
     superblock: Superblock,
+    block_groups: Vec<BlockGroupDescriptor>,
+    read_only: AtomicBool,
+    errors: ErrorHandling,
 }
 
 impl Ext2Fs {
-    pub fn new(hdd_lock: &RwLock<&mut AHCIState>) -> Result<Self, IOError> {
-        let superblock = Superblock::new(hdd_lock)?;
-        Ok(Self { superblock })
+    pub fn mount(disk: &dyn BlockDevice, options: MountOptions) -> Result<Self, IOError> {
+        let superblock = Superblock::new(disk)?;
+
+        // Everything else is worked out from these, so they're checked first
+        if let Err(problem) = superblock.check_geometry() {
+            log_warn!("ext2: not mounting, {}", problem);
+            return Err(IOError::BadData);
+        }
+
+        let block_groups = Self::read_block_groups(disk, &superblock)?;
+
+        if !options.read_only && FsState::try_from(superblock.state) == Ok(FsState::HasErrors) {
+            log_warn!("ext2: mounting a filesystem with errors read-write, it should be checked");
+        }
+        // An unknown policy in the superblock is taken to mean carrying on
+        let errors = options
+            .errors
+            .unwrap_or(ErrorHandling::try_from(superblock.errors).unwrap_or(ErrorHandling::Ignore));
+        let mode = match options.read_only {
+            true => "read-only",
+            false => "read-write",
+        };
+        log_info!(
+            "ext2: mounted {} {}, on errors: {:?}",
+            superblock.uuid,
+            mode,
+            errors
+        );
+
+        Ok(Self {
+            superblock,
+            block_groups,
+            read_only: AtomicBool::new(options.read_only),
+            errors,
+        })
     }
 
-    fn read_inode(
-        &self,
-        hdd_lock: &RwLock<&mut AHCIState>,
-        inode_number: u32,
-    ) -> Result<INode, IOError> {
-        let inode_size = self.superblock.inode_size;
-        let block_size = (1024 << self.superblock.log_block_size) as u32;
+    pub fn uuid(&self) -> Uuid {
+        self.superblock.uuid
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Switch between read-only and read-write.
+    pub fn remount(&self, options: MountOptions) {
+        self.read_only.store(options.read_only, Ordering::Relaxed);
+    }
+
+    /// Every write path has to go through this first, and give up on `ReadOnly`.
+    pub fn check_writable(&self) -> Result<(), IOError> {
+        match self.is_read_only() {
+            true => Err(IOError::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    /// Report an inconsistency in the filesystem, and do what the error policy says about it.
+    fn error(&self, args: fmt::Arguments) {
+        log_error!("ext2: {}", args);
+
+        match self.errors {
+            ErrorHandling::Ignore => {}
+            ErrorHandling::RemountReadOnly => {
+                if !self.read_only.swap(true, Ordering::Relaxed) {
+                    log_warn!("ext2: remounted read-only");
+                }
+            }
+            ErrorHandling::KernelPanic => panic!("ext2: {}", args),
+        }
+    }
+
+    fn block_size(&self) -> u64 {
+        self.superblock.block_size()
+    }
+
+    fn inode_size(&self) -> u16 {
+        match self.superblock.rev_level {
+            0 => GOOD_OLD_INODE_SIZE,
+            _ => self.superblock.inode_size,
+        }
+    }
+
+    /// The block group descriptor table starts in the block after the superblock.
+    fn read_block_groups(
+        disk: &dyn BlockDevice,
+        superblock: &Superblock,
+    ) -> Result<Vec<BlockGroupDescriptor>, IOError> {
+        let num_groups = superblock.num_groups() as usize;
+        let table_offset = (superblock.first_data_block as u64 + 1) * superblock.block_size();
+
+        let mut block_groups: Vec<BlockGroupDescriptor> = Vec::with_capacity(num_groups);
+        let table = &mut block_groups.spare_capacity_mut()[..num_groups];
+
+        block::read_bytes(
+            disk,
+            MaybeUninit::slice_as_bytes_mut(table),
+            table_offset as usize,
+        )?;
+
+        // Safety: the read filled in every descriptor
+        unsafe { block_groups.set_len(num_groups) };
+
+        Ok(block_groups)
+    }
+
+    // Where on the disk the inode `inode_number` is
+    fn inode_offset(&self, inode_number: u32) -> Result<u64, IOError> {
         let inodes_per_group = self.superblock.inodes_per_group;
-        let inode_table_block = self.superblock.first_data_block + 2; // FIXME: assuming inode table starts right after block group descriptor
+
+        if inode_number == 0 || inode_number > self.superblock.inodes_count {
+            return Err(IOError::BadData);
+        }
 
         let inode_index = inode_number - 1;
         let group = inode_index / inodes_per_group;
         let index = inode_index % inodes_per_group;
 
-        let inode_offset = (inode_table_block as u64 * block_size as u64)
-            + (group as u64 * inodes_per_group as u64 * inode_size as u64)
-            + (index as u64 * inode_size as u64);
+        let Some(group) = self.block_groups.get(group as usize) else {
+            self.error(format_args!("no group for inode {}", inode_number));
+            return Err(IOError::BadData);
+        };
+        let inode_table_block = group.inode_table;
+
+        Ok((inode_table_block as u64 * self.block_size())
+            + (index as u64 * self.inode_size() as u64))
+    }
+
+    fn read_inode(&self, disk: &dyn BlockDevice, inode_number: u32) -> Result<INode, IOError> {
+        let inode_offset = self.inode_offset(inode_number)?;
 
         let mut inode: MaybeUninit<INode> = MaybeUninit::uninit();
 
-        AHCIState::read_or_write(
-            hdd_lock,
-            Command::Read,
-            inode.as_bytes_mut(),
-            inode_offset as usize,
-        )?;
+        block::read_bytes(disk, inode.as_bytes_mut(), inode_offset as usize)?;
 
         unsafe { Ok(inode.assume_init()) }
     }
+
+    // Only the fields `INode` has are written; the rest of a bigger on-disk inode is left alone
+    fn write_inode(
+        &self,
+        disk: &dyn BlockDevice,
+        inode_number: u32,
+        inode: &INode,
+    ) -> Result<(), IOError> {
+        let inode_offset = self.inode_offset(inode_number)?;
+        let bytes = unsafe {
+            core::slice::from_raw_parts(inode as *const INode as *const u8, size_of::<INode>())
+        };
+        block::write_bytes(disk, bytes, inode_offset as usize)
+    }
+
+    /// The inode number of the file or directory at `path`, e.g. "/images/cat.bmp", from the root.
+    pub fn lookup(&self, disk: &dyn BlockDevice, path: &str) -> Result<u32, IOError> {
+        let mut inode_number = ROOT_INO;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            inode_number = self
+                .find_entry(disk, inode_number, name.as_bytes())?
+                .ok_or(IOError::NotFound)?;
+        }
+        Ok(inode_number)
+    }
+
+    // The inode `name` is in the directory `directory`, if it is there
+    fn find_entry(
+        &self,
+        disk: &dyn BlockDevice,
+        directory: u32,
+        name: &[u8],
+    ) -> Result<Option<u32>, IOError> {
+        let inode = self.read_inode(disk, directory)?;
+        if inode.mode & TYPE_MASK != TYPE_DIRECTORY {
+            return Err(IOError::NotFound);
+        }
+
+        let block_size = self.block_size();
+        let mut buf = alloc::vec![MaybeUninit::uninit(); block_size as usize];
+        for offset in (0..inode.size()).step_by(block_size as usize) {
+            let block = self.read_file(disk, directory, offset, &mut buf)?;
+
+            // Entries never cross into the next block
+            let mut position = 0;
+            while position + DIRENT_HEADER_SIZE <= block.len() {
+                let entry = &block[position..];
+                let inode_number = u32::from_le_bytes(entry[..4].try_into().unwrap());
+                let rec_len = u16::from_le_bytes(entry[4..6].try_into().unwrap()) as usize;
+                let name_len = entry[6] as usize;
+
+                if rec_len < DIRENT_HEADER_SIZE + name_len || rec_len > entry.len() {
+                    self.error(format_args!("bad entry in directory {}", directory));
+                    return Err(IOError::BadData);
+                }
+                if inode_number != 0 && &entry[DIRENT_HEADER_SIZE..][..name_len] == name {
+                    return Ok(Some(inode_number));
+                }
+                position += rec_len;
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Size in bytes of the file or directory `inode_number`.
+    pub fn file_size(&self, disk: &dyn BlockDevice, inode_number: u32) -> Result<u64, IOError> {
+        Ok(self.read_inode(disk, inode_number)?.size())
+    }
+
+    /// When the file or directory `inode_number` was last changed.
+    pub fn modified(
+        &self,
+        disk: &dyn BlockDevice,
+        inode_number: u32,
+    ) -> Result<Timestamp, IOError> {
+        let mtime = self.read_inode(disk, inode_number)?.mtime;
+        Ok(Timestamp::from_unix(mtime as u64))
+    }
+
+    /// Read from byte `offset` of the file `inode_number` into `buf`, stopping at the end of the
+    /// file. Returns the part of `buf` that was read into, which is empty at or past the end.
+    pub fn read_file<'a>(
+        &self,
+        disk: &dyn BlockDevice,
+        inode_number: u32,
+        offset: u64,
+        buf: &'a mut [MaybeUninit<u8>],
+    ) -> Result<&'a mut [u8], IOError> {
+        let inode = self.read_inode(disk, inode_number)?;
+        let block_size = self.block_size();
+        let len = inode.size().saturating_sub(offset).min(buf.len() as u64) as usize;
+        let buf = &mut buf[..len];
+
+        let mut map = BlockMap::new(disk, &inode, block_size);
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let skip = (position % block_size) as usize;
+            let chunk = (len - done).min(block_size as usize - skip);
+            let dst = &mut buf[done..done + chunk];
+
+            match map.block(position / block_size)? {
+                // A hole in a sparse file, which reads as zeroes
+                0 => {
+                    for byte in dst {
+                        byte.write(0);
+                    }
+                }
+                block if block >= self.superblock.blocks_count => {
+                    self.error(format_args!(
+                        "inode {} points to block {}, past the end",
+                        inode_number, block
+                    ));
+                    return Err(IOError::BadData);
+                }
+                block => {
+                    let address = block as u64 * block_size + skip as u64;
+                    block::read_bytes(disk, dst, address as usize)?;
+                }
+            }
+            done += chunk;
+        }
+
+        Ok(unsafe { MaybeUninit::slice_assume_init_mut(buf) })
+    }
+
+    /// Replace the contents of the regular file `inode_number` with `data`. Nothing can allocate
+    /// or free blocks yet, so the new contents have to take up as many blocks as the old ones did
+    /// (`NoSpace` if they take more, `Invalid` if fewer), and are written over them.
+    pub fn overwrite(
+        &self,
+        disk: &dyn BlockDevice,
+        inode_number: u32,
+        data: &[u8],
+    ) -> Result<(), IOError> {
+        self.check_writable()?;
+
+        let mut inode = self.read_inode(disk, inode_number)?;
+        if inode.mode & TYPE_MASK != TYPE_REGULAR {
+            return Err(IOError::Invalid);
+        }
+
+        let block_size = self.block_size();
+        let num_blocks = (data.len() as u64).div_ceil(block_size);
+        match num_blocks.cmp(&inode.size().div_ceil(block_size)) {
+            core::cmp::Ordering::Greater => return Err(IOError::NoSpace),
+            core::cmp::Ordering::Less => return Err(IOError::Invalid),
+            core::cmp::Ordering::Equal => {}
+        }
+
+        // Find every block before writing any, so a bad pointer doesn't leave the file half written
+        let mut map = BlockMap::new(disk, &inode, block_size);
+        let mut blocks = Vec::with_capacity(num_blocks as usize);
+        for index in 0..num_blocks {
+            match map.block(index)? {
+                // A hole, which would need a block allocated for it
+                0 => return Err(IOError::NoSpace),
+                block if block >= self.superblock.blocks_count => {
+                    self.error(format_args!(
+                        "inode {} points to block {}, past the end",
+                        inode_number, block
+                    ));
+                    return Err(IOError::BadData);
+                }
+                block => blocks.push(block),
+            }
+        }
+
+        // The data goes out before the inode says how much of it there is
+        for (chunk, block) in data.chunks(block_size as usize).zip(blocks) {
+            block::write_bytes(disk, chunk, (block as u64 * block_size) as usize)?;
+        }
+
+        let size = data.len() as u64;
+        inode.size = size as u32;
+        inode.dir_acl = (size >> 32) as u32;
+        self.write_inode(disk, inode_number, &inode)
+    }
+}
+
+// Looks up where a file's blocks are on the disk. The indirect block last read at each depth is
+// kept, so reading a file front to back reads each of them once.
+struct BlockMap<'a> {
+    disk: &'a dyn BlockDevice,
+    pointers: [u32; 15],
+    block_size: u64,
+    cached: [(u32, Vec<u32>); 3],
+}
+
+impl<'a> BlockMap<'a> {
+    fn new(disk: &'a dyn BlockDevice, inode: &INode, block_size: u64) -> Self {
+        Self {
+            disk,
+            pointers: inode.block,
+            block_size,
+            cached: Default::default(),
+        }
+    }
+
+    // The block the `index`th block of the file is in, or 0 if the file has a hole there
+    fn block(&mut self, index: u64) -> Result<u32, IOError> {
+        let per_block = self.block_size / 4;
+
+        if index < NUM_DIRECT as u64 {
+            return Ok(self.pointers[index as usize]);
+        }
+
+        let index = index - NUM_DIRECT as u64;
+        if index < per_block {
+            return self.follow(self.pointers[SINGLY_INDIRECT], &[index]);
+        }
+
+        let index = index - per_block;
+        if index < per_block * per_block {
+            let indices = [index / per_block, index % per_block];
+            return self.follow(self.pointers[DOUBLY_INDIRECT], &indices);
+        }
+
+        let index = index - per_block * per_block;
+        if index < per_block * per_block * per_block {
+            let indices = [
+                index / (per_block * per_block),
+                index / per_block % per_block,
+                index % per_block,
+            ];
+            return self.follow(self.pointers[TRIPLY_INDIRECT], &indices);
+        }
+
+        Err(IOError::BadData)
+    }
+
+    // Go down through indirect blocks from `block`, taking the pointer at each of `indices`
+    fn follow(&mut self, mut block: u32, indices: &[u64]) -> Result<u32, IOError> {
+        for (depth, &index) in indices.iter().enumerate() {
+            if block == 0 {
+                return Ok(0);
+            }
+            block = self.pointers_in(depth, block)?[index as usize];
+        }
+        Ok(block)
+    }
+
+    // The pointers in indirect block `block`, which is `depth` levels below the inode
+    fn pointers_in(&mut self, depth: usize, block: u32) -> Result<&[u32], IOError> {
+        let (cached_block, pointers) = &mut self.cached[depth];
+        if *cached_block != block || pointers.is_empty() {
+            let mut bytes = alloc::vec![MaybeUninit::uninit(); self.block_size as usize];
+            let bytes = block::read_bytes(
+                self.disk,
+                &mut bytes,
+                (block as u64 * self.block_size) as usize,
+            )?;
+
+            pointers.clear();
+            pointers.extend(
+                bytes
+                    .chunks_exact(4)
+                    .map(|pointer| u32::from_le_bytes(pointer.try_into().unwrap())),
+            );
+            *cached_block = block;
+        }
+
+        Ok(pointers)
+    }
 }
 
 #[repr(C)]
@@ -70,8 +497,9 @@ pub struct Superblock {
     pub mnt_count: u16,
     pub max_mnt_count: i16,
     pub magic: u16,
-    pub state: FsState,
-    pub errors: ErrorHandling,
+    // An `FsState` and an `ErrorHandling`, if they're anything known
+    pub state: u16,
+    pub errors: u16,
     pub minor_rev_level: u16,
     pub lastcheck: u32,
     pub checkinterval: u32,
@@ -86,7 +514,7 @@ pub struct Superblock {
     pub feature_compat: u32,
     pub feature_incompat: u32,
     pub feature_ro_compat: u32,
-    pub uuid: [u8; 16],
+    pub uuid: Uuid,
     pub volume_name: [u8; 16],
     pub last_mounted: [u8; 64],
     pub algorithm_usage_bitmap: u32,
@@ -95,7 +523,7 @@ pub struct Superblock {
     pub prealloc_dir_blocks: u8,
     pub reserved_gdt_blocks: u16,
     // Journaling support (unused)
-    pub journal_uuid: [u8; 16],
+    pub journal_uuid: Uuid,
     pub journal_inum: u32,
     pub journal_device: u32,
     pub last_orphan: u32,
@@ -108,6 +536,26 @@ const _: () = assert!(
     "Ext2 Superblock must be exactly 1024 bytes"
 );
 
+/// One entry in the block group descriptor table, saying where a group's bitmaps and inode
+/// table are.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct BlockGroupDescriptor {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+    free_blocks_count: u16,
+    free_inodes_count: u16,
+    used_dirs_count: u16,
+    pad: u16,
+    reserved: [u8; 12],
+}
+
+const _: () = assert!(
+    size_of::<BlockGroupDescriptor>() == 32,
+    "Ext2 block group descriptors must be exactly 32 bytes"
+);
+
 #[repr(C)]
 struct INode {
     mode: u16,
@@ -130,29 +578,66 @@ struct INode {
     osd2: [u8; 12],
 }
 
+impl INode {
+    /// Size in bytes. Regular files keep the top 32 bits of theirs in `dir_acl`, which only
+    /// directories use for its original purpose.
+    fn size(&self) -> u64 {
+        if self.mode & TYPE_MASK == TYPE_REGULAR {
+            ((self.dir_acl as u64) << 32) | self.size as u64
+        } else {
+            self.size as u64
+        }
+    }
+}
+
 #[repr(u16)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FsState {
     Clean = 1,
     HasErrors = 2,
 }
 
 #[repr(u16)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ErrorHandling {
     Ignore = 1,
     RemountReadOnly = 2,
     KernelPanic = 3,
 }
 
+impl TryFrom<u16> for FsState {
+    type Error = ();
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(FsState::Clean),
+            2 => Ok(FsState::HasErrors),
+            _ => Err(()),
+        }
+    }
+}
+
+impl TryFrom<u16> for ErrorHandling {
+    type Error = ();
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(ErrorHandling::Ignore),
+            2 => Ok(ErrorHandling::RemountReadOnly),
+            3 => Ok(ErrorHandling::KernelPanic),
+            _ => Err(()),
+        }
+    }
+}
+
 impl Superblock {
     /// Try to read the superblock into memory.
     /// Returns an error if this disk does not have the EXT2 magic, or if there is an error reading
     /// the disk.
-    pub fn new(drive_lock: &RwLock<&mut AHCIState>) -> Result<Self, IOError> {
+    pub fn new(disk: &dyn BlockDevice) -> Result<Self, IOError> {
         let mut uninit_self: MaybeUninit<Self> = MaybeUninit::uninit();
 
-        AHCIState::read_or_write(drive_lock, Command::Read, uninit_self.as_bytes_mut(), 1024)?;
+        block::read_bytes(disk, uninit_self.as_bytes_mut(), 1024)?;
 
         unsafe {
             let has_sig = uninit_self.assume_init_ref().has_signature();
@@ -166,4 +651,44 @@ impl Superblock {
     pub fn has_signature(&self) -> bool {
         self.magic == SUPERBLOCK_MAGIC
     }
+
+    /// Only once `check_geometry` has passed; it overflows for a big enough `log_block_size`.
+    pub fn block_size(&self) -> u64 {
+        1024 << self.log_block_size
+    }
+
+    /// How many block groups there are. Only once `check_geometry` has passed.
+    pub fn num_groups(&self) -> u32 {
+        self.blocks_count
+            .saturating_sub(self.first_data_block)
+            .div_ceil(self.blocks_per_group)
+    }
+
+    /// Whether the numbers the rest of the layout is worked out from make sense, before any of
+    /// it is: the block size, group sizes, inode size and group count.
+    pub fn check_geometry(&self) -> Result<(), &'static str> {
+        if self.log_block_size > MAX_LOG_BLOCK_SIZE {
+            return Err("the block size is too big");
+        }
+        let block_size = self.block_size() as u32;
+        // The group's block and inode bitmaps are a block each
+        if !(1..=block_size * 8).contains(&self.blocks_per_group)
+            || !(1..=block_size * 8).contains(&self.inodes_per_group)
+        {
+            return Err("the groups are empty or bigger than a bitmap block");
+        }
+        if self.rev_level > 0 {
+            let inode_size = self.inode_size as u32;
+            if !inode_size.is_power_of_two()
+                || inode_size < GOOD_OLD_INODE_SIZE as u32
+                || inode_size > block_size
+            {
+                return Err("the inode size isn't a power of two from 128 to the block size");
+            }
+        }
+        if self.num_groups() == 0 || self.num_groups() > MAX_GROUPS {
+            return Err("there are no block groups, or too many");
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,128 @@
+// The initramfs: files the bootloader loads into memory along with the kernel, so there's a root
+// filesystem to read from before (or without) the disk drivers and ext2. It is a cpio archive in
+// the "newc" format, gzipped or not, e.g.
+//
+//     cd initramfs && find . | cpio -o -H newc | gzip > ../initramfs.cpio.gz
+//     INITRAMFS=initramfs.cpio.gz cargo run
+//
+// The archive stays where the bootloader put it, in memory marked as the bootloader's, and files
+// are slices of it. A gzipped archive is unpacked onto the heap first, and kept there for good.
+//
+// Only regular files are kept; directories are whatever the paths of the files make of them, and
+// everything else (symlinks, devices) is left out. The files can't be changed.
+
+use crate::klib::inflate;
+use crate::klib::time::Timestamp;
+use crate::{log_info, log_warn};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// The most a gzipped archive may unpack to.
+pub const MAX_UNPACKED: usize = 1024 * 1024;
+
+const MAGIC: &[u8] = b"070701";
+// The same, with checksums of the files that aren't checked here
+const MAGIC_CRC: &[u8] = b"070702";
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+const TRAILER: &str = "TRAILER!!!";
+
+// The magic, then 13 fields of 8 hex digits
+const HEADER_SIZE: usize = 110;
+const FIELD_MODE: usize = 1;
+const FIELD_MTIME: usize = 5;
+const FIELD_FILE_SIZE: usize = 6;
+const FIELD_NAME_SIZE: usize = 11;
+
+const MODE_TYPE: u32 = 0o170000;
+const MODE_REGULAR: u32 = 0o100000;
+
+pub struct File {
+    /// From the root, e.g. "/etc/motd"
+    pub path: String,
+    pub data: &'static [u8],
+    pub modified: Timestamp,
+}
+
+pub struct Initramfs {
+    files: Vec<File>,
+}
+
+impl Initramfs {
+    /// Unpack the archive in `image`, which has to stay where it is for as long as the kernel
+    /// runs.
+    pub fn new(image: &'static [u8]) -> Result<Self, ()> {
+        let archive = if image.starts_with(GZIP_MAGIC) {
+            let unpacked = inflate::gunzip(image, MAX_UNPACKED).map_err(|err| {
+                log_warn!("Couldn't unpack the initramfs: {:?}", err);
+            })?;
+            &*unpacked.leak()
+        } else {
+            image
+        };
+
+        let mut files = Vec::new();
+        let mut offset = 0;
+        loop {
+            let header = archive.get(offset..offset + HEADER_SIZE).ok_or(())?;
+            if !header.starts_with(MAGIC) && !header.starts_with(MAGIC_CRC) {
+                log_warn!("No cpio header at {} in the initramfs", offset);
+                return Err(());
+            }
+            let mode = field(header, FIELD_MODE)?;
+            let modified = Timestamp::from_unix(field(header, FIELD_MTIME)? as u64);
+            let file_size = field(header, FIELD_FILE_SIZE)? as usize;
+            let name_size = field(header, FIELD_NAME_SIZE)? as usize;
+
+            // The name ends in a NUL, and the name and data both start on 4 byte boundaries
+            let name_start = offset + HEADER_SIZE;
+            let name = archive
+                .get(name_start..name_start + name_size.saturating_sub(1))
+                .and_then(|name| core::str::from_utf8(name).ok())
+                .ok_or(())?;
+            let data_start = (name_start + name_size).next_multiple_of(4);
+            let data = archive.get(data_start..data_start + file_size).ok_or(())?;
+            offset = (data_start + file_size).next_multiple_of(4);
+
+            if name == TRAILER {
+                break;
+            }
+            if mode & MODE_TYPE != MODE_REGULAR {
+                continue;
+            }
+            let name = name.trim_start_matches("./").trim_start_matches('/');
+            let mut path = String::with_capacity(name.len() + 1);
+            path.push('/');
+            path.push_str(name);
+            files.push(File {
+                path,
+                data,
+                modified,
+            });
+        }
+
+        let initramfs = Self { files };
+        log_info!(
+            "Initramfs has {} files, {} bytes",
+            initramfs.files.len(),
+            initramfs.size()
+        );
+        Ok(initramfs)
+    }
+
+    /// The file at `path`, from the initramfs's root.
+    pub fn get(&self, path: &str) -> Option<&File> {
+        self.files.iter().find(|file| file.path == path)
+    }
+
+    /// How many bytes all the files add up to.
+    pub fn size(&self) -> usize {
+        self.files.iter().map(|file| file.data.len()).sum()
+    }
+}
+
+// Field `index` of a header, after the magic
+fn field(header: &[u8], index: usize) -> Result<u32, ()> {
+    let start = MAGIC.len() + index * 8;
+    let digits = core::str::from_utf8(&header[start..start + 8]).map_err(|_| ())?;
+    u32::from_str_radix(digits, 16).map_err(|_| ())
+}
@@ -1 +1,242 @@
+// Filesystems, and the table of what is mounted where. There is no VFS yet: `read` knows about
+// /proc, the initramfs and the ext2 filesystem mounted at the root, and everything else uses a
+// filesystem directly.
+//
+// The initramfs is the root until ext2 is mounted, and from then on is at `INITRAMFS_MOUNT`.
+
+#[cfg(feature = "fs-ext2")]
 pub mod ext2;
+pub mod initramfs;
+pub mod procfs;
+
+use crate::arch::x86_64::interrupts::without_interrupts;
+#[cfg(feature = "fs-ext2")]
+use crate::klib::block;
+use crate::klib::block::{BlockDevice, IOError};
+use crate::klib::once_lock::OnceLock;
+use crate::klib::time::Timestamp;
+use crate::klib::uuid::Uuid;
+use crate::log_info;
+#[cfg(feature = "fs-ext2")]
+use crate::log_warn;
+use alloc::string::String;
+#[cfg(feature = "fs-ext2")]
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+#[cfg(feature = "fs-ext2")]
+use core::mem::MaybeUninit;
+use initramfs::Initramfs;
+use spin::RwLock;
+
+/// Where the initramfs is once it isn't the root.
+pub const INITRAMFS_MOUNT: &str = "/initramfs";
+
+// Bigger files can't be read whole into the heap
+const MAX_READ_SIZE: u64 = 1024 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct Mount {
+    /// The device the filesystem is on, or the filesystem's name if it isn't on one
+    pub source: String,
+    pub path: String,
+    pub fs_type: &'static str,
+    pub options: String,
+}
+
+/// What `stat` says about a file.
+pub struct Stat {
+    pub size: u64,
+    /// When it was last changed, if its filesystem keeps track
+    pub modified: Option<Timestamp>,
+}
+
+static MOUNTS: RwLock<Vec<Mount>> = RwLock::new(Vec::new());
+
+// The root filesystem, and the name of the block device it is on. The device is looked up on
+// every read, so reads go through whatever is registered under that name by then (e.g. a request
+// queue).
+#[cfg(feature = "fs-ext2")]
+static ROOT: OnceLock<(ext2::Ext2Fs, String)> = OnceLock::new();
+
+static INITRAMFS: OnceLock<Initramfs> = OnceLock::new();
+
+/// Record that a filesystem has been mounted at `path`.
+pub fn add_mount(source: &str, path: &str, fs_type: &'static str, options: &str) {
+    let mount = Mount {
+        source: String::from(source),
+        path: String::from(path),
+        fs_type,
+        options: String::from(options),
+    };
+    without_interrupts(|| MOUNTS.write().push(mount));
+}
+
+/// Everything mounted, in the order it was mounted.
+pub fn mounts() -> Vec<Mount> {
+    without_interrupts(|| MOUNTS.read().clone())
+}
+
+/// Mount `fs`, on the block device registered as `device`, at the root. Only one filesystem can
+/// be.
+#[cfg(feature = "fs-ext2")]
+pub fn mount_root(fs: ext2::Ext2Fs, device: &str) -> Result<(), ()> {
+    let options = match fs.is_read_only() {
+        true => "ro",
+        false => "rw",
+    };
+    ROOT.set((fs, String::from(device))).map_err(|_| ())?;
+    if INITRAMFS.get().is_some() {
+        without_interrupts(|| {
+            let mut mounts = MOUNTS.write();
+            let initramfs = mounts.iter_mut().find(|mount| mount.fs_type == "initramfs");
+            if let Some(mount) = initramfs {
+                mount.path = String::from(INITRAMFS_MOUNT);
+            }
+        });
+        log_info!("The initramfs moved to {}", INITRAMFS_MOUNT);
+    }
+    add_mount(device, "/", "ext2", options);
+    if block::on_remove("filesystems", unmount_device).is_err() {
+        log_warn!("Couldn't have the filesystems told about disks going away");
+    }
+    Ok(())
+}
+
+/// The UUID of the filesystem on `disk`, if there's one the kernel can read and it has a UUID.
+pub fn uuid(disk: &dyn BlockDevice) -> Option<Uuid> {
+    #[cfg(feature = "fs-ext2")]
+    if let Ok(superblock) = ext2::Superblock::new(disk) {
+        return Some(superblock.uuid).filter(|uuid| !uuid.is_nil());
+    }
+    #[cfg(not(feature = "fs-ext2"))]
+    let _ = disk;
+    None
+}
+
+/// Mount the initramfs, at the root unless a disk already is.
+pub fn mount_initramfs(initramfs: Initramfs) -> Result<(), ()> {
+    INITRAMFS.set(initramfs).map_err(|_| ())?;
+    #[cfg(feature = "fs-ext2")]
+    let path = match ROOT.get() {
+        Some(_) => INITRAMFS_MOUNT,
+        None => "/",
+    };
+    #[cfg(not(feature = "fs-ext2"))]
+    let path = "/";
+    add_mount("initramfs", path, "initramfs", "ro");
+    log_info!("Mounted the initramfs at {}", path);
+    Ok(())
+}
+
+// The initramfs, and where `path` is on it, if that's where `path` is
+fn on_initramfs(path: &str) -> Option<(&'static Initramfs, &str)> {
+    let initramfs = INITRAMFS.get()?;
+    #[cfg(feature = "fs-ext2")]
+    if ROOT.get().is_some() {
+        let path = path
+            .strip_prefix(INITRAMFS_MOUNT)
+            .filter(|rest| rest.starts_with('/'))?;
+        return Some((initramfs, path));
+    }
+    Some((initramfs, path))
+}
+
+// Take everything on a device that went away out of the mount table. The root stays where it
+// was, but its reads and writes fail from then on, as its device can't be found.
+#[cfg(feature = "fs-ext2")]
+fn unmount_device(name: &str, _device: &Arc<dyn block::BlockDevice>) {
+    let mut unmounted = Vec::new();
+    without_interrupts(|| {
+        MOUNTS.write().retain(|mount| {
+            if mount.source == name {
+                unmounted.push(mount.path.clone());
+            }
+            mount.source != name
+        })
+    });
+
+    for path in unmounted {
+        log_warn!("{} is gone, unmounted {}", name, path);
+    }
+}
+
+/// The whole of the file at `path`, e.g. "/proc/meminfo" or "/images/cat.bmp".
+pub fn read(path: &str) -> Result<Vec<u8>, IOError> {
+    if path.starts_with(procfs::MOUNT_POINT) {
+        return procfs::read(path)
+            .map(String::into_bytes)
+            .ok_or(IOError::NotFound);
+    }
+
+    if let Some((initramfs, path)) = on_initramfs(path) {
+        let file = initramfs.get(path).ok_or(IOError::NotFound)?;
+        if file.data.len() as u64 > MAX_READ_SIZE {
+            return Err(IOError::Invalid);
+        }
+        return Ok(file.data.to_vec());
+    }
+
+    #[cfg(feature = "fs-ext2")]
+    if let Some((fs, device)) = ROOT.get() {
+        let disk = block::get(device).ok_or(IOError::DeviceGone)?;
+        let inode_number = fs.lookup(&*disk, path)?;
+        let size = fs.file_size(&*disk, inode_number)?;
+        if size > MAX_READ_SIZE {
+            return Err(IOError::Invalid);
+        }
+
+        let mut buf = alloc::vec![MaybeUninit::uninit(); size as usize];
+        return Ok(fs.read_file(&*disk, inode_number, 0, &mut buf)?.to_vec());
+    }
+
+    Err(IOError::NotFound)
+}
+
+/// The size of the file at `path`, and when it was last changed.
+pub fn stat(path: &str) -> Result<Stat, IOError> {
+    if path.starts_with(procfs::MOUNT_POINT) {
+        // Made up as they're read, so they're always new
+        let contents = procfs::read(path).ok_or(IOError::NotFound)?;
+        return Ok(Stat {
+            size: contents.len() as u64,
+            modified: Timestamp::now(),
+        });
+    }
+
+    if let Some((initramfs, path)) = on_initramfs(path) {
+        let file = initramfs.get(path).ok_or(IOError::NotFound)?;
+        return Ok(Stat {
+            size: file.data.len() as u64,
+            modified: Some(file.modified),
+        });
+    }
+
+    #[cfg(feature = "fs-ext2")]
+    if let Some((fs, device)) = ROOT.get() {
+        let disk = block::get(device).ok_or(IOError::DeviceGone)?;
+        let inode_number = fs.lookup(&*disk, path)?;
+        return Ok(Stat {
+            size: fs.file_size(&*disk, inode_number)?,
+            modified: Some(fs.modified(&*disk, inode_number)?),
+        });
+    }
+
+    Err(IOError::NotFound)
+}
+
+/// Replace the contents of the file at `path` with `data`. Only files that are already there can
+/// be written, and only within the blocks they have, see `Ext2Fs::overwrite`.
+pub fn write(path: &str, data: &[u8]) -> Result<(), IOError> {
+    if path.starts_with(procfs::MOUNT_POINT) || on_initramfs(path).is_some() {
+        return Err(IOError::ReadOnly);
+    }
+
+    #[cfg(feature = "fs-ext2")]
+    if let Some((fs, device)) = ROOT.get() {
+        let disk = block::get(device).ok_or(IOError::DeviceGone)?;
+        let inode_number = fs.lookup(&*disk, path)?;
+        return fs.overwrite(&*disk, inode_number, data);
+    }
+
+    Err(IOError::NotFound)
+}
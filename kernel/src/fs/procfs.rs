@@ -0,0 +1,230 @@
+// A filesystem with nothing behind it: each file is made up from the kernel's state when it is
+// read, for the shell (and one day, user programs) to look at. It is mounted at `MOUNT_POINT`.
+
+use crate::arch::ports;
+use crate::arch::x86_64::interrupts::{idt, vectors};
+use crate::arch::x86_64::memory_map;
+use crate::klib::initgraph;
+use crate::klib::iommu;
+use crate::klib::layout;
+use crate::klib::pci::pcistate::PCI_STATE;
+use crate::klib::pci::power::{self, PowerState};
+use crate::klib::version;
+use crate::task::{self, Priority, TaskState};
+use crate::{allocator, TIMER};
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::sync::atomic::Ordering;
+
+pub const MOUNT_POINT: &str = "/proc";
+
+struct File {
+    name: &'static str,
+    generate: fn(&mut String) -> fmt::Result,
+}
+
+const FILES: &[File] = &[
+    File {
+        name: "meminfo",
+        generate: meminfo,
+    },
+    File {
+        name: "interrupts",
+        generate: interrupts,
+    },
+    File {
+        name: "ioports",
+        generate: ioports,
+    },
+    File {
+        name: "tasks",
+        generate: tasks,
+    },
+    File {
+        name: "pci",
+        generate: pci,
+    },
+    File {
+        name: "iommu",
+        generate: iommu,
+    },
+    File {
+        name: "mounts",
+        generate: mounts,
+    },
+    File {
+        name: "version",
+        generate: version,
+    },
+    File {
+        name: "init",
+        generate: init_stages,
+    },
+    File {
+        name: "layout",
+        generate: layout,
+    },
+];
+
+pub fn init() {
+    super::add_mount("proc", MOUNT_POINT, "proc", "ro");
+}
+
+/// The name of every file, e.g. "meminfo".
+pub fn files() -> impl Iterator<Item = &'static str> {
+    FILES.iter().map(|file| file.name)
+}
+
+/// The contents of the file at `path`, e.g. "/proc/meminfo", or None if there is no such file.
+pub fn read(path: &str) -> Option<String> {
+    let name = path.strip_prefix(MOUNT_POINT)?.strip_prefix('/')?;
+    let file = FILES.iter().find(|file| file.name == name)?;
+
+    let mut contents = String::new();
+    // Writing to a String can't fail
+    let _ = (file.generate)(&mut contents);
+    Some(contents)
+}
+
+fn meminfo(out: &mut String) -> fmt::Result {
+    let (total, usable) = memory_map::ram();
+    writeln!(out, "MemTotal: {} kB", total / 1024)?;
+    writeln!(out, "MemUsable: {} kB", usable / 1024)?;
+    writeln!(out, "HeapTotal: {} kB", allocator::HEAP_SIZE / 1024)?;
+    if let Some(free) = allocator::free_bytes() {
+        writeln!(out, "HeapFree: {} kB", free / 1024)?;
+    }
+    for (node, bytes) in memory_map::ram_by_domain() {
+        writeln!(out, "Node{}Usable: {} kB", node, bytes / 1024)?;
+    }
+    Ok(())
+}
+
+fn interrupts(out: &mut String) -> fmt::Result {
+    for (vector, count) in idt::counts() {
+        let owner = vectors::owner(vector).unwrap_or("-");
+        writeln!(out, "{:#04x}: {} {}", vector, count, owner)?;
+    }
+    Ok(())
+}
+
+fn ioports(out: &mut String) -> fmt::Result {
+    for claim in ports::claims().iter() {
+        let last = claim.first as u32 + claim.count as u32 - 1;
+        writeln!(out, "{:04x}-{:04x}: {}", claim.first, last, claim.owner)?;
+    }
+    Ok(())
+}
+
+fn tasks(out: &mut String) -> fmt::Result {
+    writeln!(out, "uptime {}", TIMER.load(Ordering::SeqCst))?;
+    for info in task::snapshot() {
+        let state = match info.state {
+            TaskState::Running => "running",
+            TaskState::Ready => "ready",
+            TaskState::Blocked => "blocked",
+            TaskState::Sleeping(_) => "sleeping",
+            TaskState::Dead => "dead",
+        };
+        let priority = match info.priority {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Idle => "idle",
+        };
+        writeln!(
+            out,
+            "{} {} {} {} runtime {} wait {} wakes {}",
+            info.id,
+            info.name,
+            state,
+            priority,
+            info.stats.runtime,
+            info.stats.wait_time,
+            info.stats.wake_count
+        )?;
+    }
+    Ok(())
+}
+
+fn pci(out: &mut String) -> fmt::Result {
+    let devices = PCI_STATE.lock().devices().to_vec();
+    for device in devices {
+        // Functions without power management are always in D0
+        let state = power::state(device.bus, device.slot, device.func).unwrap_or(PowerState::D0);
+        writeln!(
+            out,
+            "{:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}{:02x}{:02x} {}",
+            device.bus,
+            device.slot,
+            device.func,
+            device.vendor_id,
+            device.device_id,
+            device.class,
+            device.subclass,
+            device.prog_if,
+            state
+        )?;
+    }
+    Ok(())
+}
+
+fn iommu(out: &mut String) -> fmt::Result {
+    for unit in iommu::units() {
+        writeln!(
+            out,
+            "{:#x} segment {} version {}.{} translation {}{}",
+            unit.base,
+            unit.segment,
+            unit.version.0,
+            unit.version.1,
+            unit.mode,
+            if unit.interrupt_remapping {
+                ", interrupt remapping turned off"
+            } else {
+                ""
+            }
+        )?;
+    }
+    Ok(())
+}
+
+fn version(out: &mut String) -> fmt::Result {
+    writeln!(out, "{}", version::build_info())
+}
+
+// Each boot stage in the order it ran, and how long it took: in microseconds, or TSC cycles if
+// they were never measured against the timer
+fn init_stages(out: &mut String) -> fmt::Result {
+    for record in initgraph::records().iter() {
+        write!(out, "{:<20} {:<8} ", record.name, record.outcome)?;
+        match record.micros() {
+            Some(micros) => writeln!(out, "{} us", micros)?,
+            None => writeln!(out, "{} cycles", record.cycles)?,
+        }
+    }
+    Ok(())
+}
+
+fn layout(out: &mut String) -> fmt::Result {
+    for region in layout::regions().iter() {
+        writeln!(
+            out,
+            "{:#014x}-{:#014x} {}",
+            region.start,
+            region.start + region.size,
+            region.name
+        )?;
+    }
+    Ok(())
+}
+
+fn mounts(out: &mut String) -> fmt::Result {
+    for mount in super::mounts() {
+        writeln!(
+            out,
+            "{} {} {} {}",
+            mount.source, mount.path, mount.fs_type, mount.options
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,72 @@
+// Just enough AML to pull constant packages like \_S5 out of the DSDT. There is no real
+// interpreter: we look for the encoded `Name(_S5_, Package() {...})` and decode the constants in
+// it, which is how every firmware we care about defines the sleep states.
+
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0A;
+const WORD_PREFIX: u8 = 0x0B;
+const ROOT_CHAR: u8 = b'\\';
+
+/// The first `N` integer elements of the package named `name`, if it is defined with constants.
+pub fn find_package<const N: usize>(aml: &[u8], name: &[u8; 4]) -> Option<[u64; N]> {
+    let mut start = 0;
+
+    while let Some(pos) = aml[start..].windows(4).position(|window| window == name) {
+        let pos = start + pos;
+        start = pos + 1;
+
+        // The name has to come right after a NameOp, possibly with a root prefix in between
+        let named = match pos {
+            0 => false,
+            1 => aml[0] == NAME_OP,
+            _ => aml[pos - 1] == NAME_OP || (aml[pos - 1] == ROOT_CHAR && aml[pos - 2] == NAME_OP),
+        };
+
+        if !named || aml.get(pos + 4) != Some(&PACKAGE_OP) {
+            continue;
+        }
+
+        if let Some(elements) = parse_package(&aml[pos + 5..]) {
+            return Some(elements);
+        }
+    }
+
+    None
+}
+
+// `aml` starts at the PkgLength of a package
+fn parse_package<const N: usize>(aml: &[u8]) -> Option<[u64; N]> {
+    // The top two bits of the first byte say how many more bytes the length has
+    let length_bytes = (*aml.first()? >> 6) as usize;
+    let mut pos = 1 + length_bytes;
+
+    let num_elements = *aml.get(pos)? as usize;
+    pos += 1;
+
+    if num_elements < N {
+        return None;
+    }
+
+    let mut elements = [0u64; N];
+    for element in elements.iter_mut() {
+        let (value, size) = parse_integer(aml.get(pos..)?)?;
+        *element = value;
+        pos += size;
+    }
+
+    Some(elements)
+}
+
+// Returns the value and how many bytes it took up
+fn parse_integer(aml: &[u8]) -> Option<(u64, usize)> {
+    match *aml.first()? {
+        ZERO_OP => Some((0, 1)),
+        ONE_OP => Some((1, 1)),
+        BYTE_PREFIX => Some((*aml.get(1)? as u64, 2)),
+        WORD_PREFIX => Some((u16::from_le_bytes([*aml.get(1)?, *aml.get(2)?]) as u64, 3)),
+        _ => None,
+    }
+}
@@ -0,0 +1,154 @@
+use super::super::once_lock::OnceLock;
+use super::Sdt;
+use alloc::vec::Vec;
+
+// Remapping structure types in the DMAR, after the fixed part
+const ENTRY_HARDWARE_UNIT: u16 = 0;
+const ENTRY_RESERVED_MEMORY: u16 = 1;
+
+// The host address width, flags and 10 reserved bytes come right after the header
+const HOST_ADDRESS_WIDTH_OFFSET: usize = 0;
+const FLAGS_OFFSET: usize = 1;
+const ENTRIES_OFFSET: usize = 12;
+
+// DMAR flags
+const INTERRUPT_REMAPPING: u8 = 0x1;
+const DMA_CONTROL_OPT_IN: u8 = 0x4;
+
+// Hardware unit flags: the unit covers every device on its segment not claimed by another unit
+const INCLUDE_PCI_ALL: u8 = 0x1;
+
+// Device scopes start this far into a hardware unit and a reserved memory region
+const HARDWARE_UNIT_SCOPES_OFFSET: usize = 16;
+const RESERVED_MEMORY_SCOPES_OFFSET: usize = 24;
+
+/// Only set if the firmware has a DMAR, i.e. the machine has Intel VT-d IOMMUs.
+pub static DMAR: OnceLock<Dmar> = OnceLock::new();
+
+/// A PCI function, or the bridge a hierarchy hangs off, that a remapping structure applies to.
+/// Only scopes whose path is a single step are kept, as longer ones would need walking the
+/// bridges along it to find the bus.
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceScope {
+    /// 1 for a function, 2 for a bridge and everything below it, and higher for IOAPICs, HPETs
+    /// and ACPI namespace devices
+    pub kind: u8,
+    pub bus: u8,
+    pub slot: u8,
+    pub func: u8,
+}
+
+/// A DMA remapping hardware unit (DRHD), i.e. an IOMMU.
+#[derive(Debug)]
+pub struct HardwareUnit {
+    /// The physical address of its registers
+    pub base: u64,
+    pub segment: u16,
+    /// Whether it covers every device on the segment that no other unit lists
+    pub include_all: bool,
+    pub devices: Vec<DeviceScope>,
+}
+
+/// Memory that devices keep doing DMA to behind the kernel's back (an RMRR), e.g. USB controllers
+/// emulating a PS/2 keyboard for the firmware. It has to stay mapped for them.
+#[derive(Debug)]
+pub struct ReservedMemory {
+    pub base: u64,
+    /// The last byte of the region
+    pub limit: u64,
+    pub devices: Vec<DeviceScope>,
+}
+
+/// The DMAR (DMA Remapping Reporting table).
+#[derive(Debug)]
+pub struct Dmar {
+    /// The widest physical address DMA can use, in bits
+    pub host_address_width: u8,
+    pub interrupt_remapping: bool,
+    /// The firmware asks for DMA protection to be kept on from boot
+    pub dma_control_opt_in: bool,
+    pub units: Vec<HardwareUnit>,
+    pub reserved_memory: Vec<ReservedMemory>,
+}
+
+impl Dmar {
+    pub fn parse(sdt: &Sdt) -> Result<Self, ()> {
+        if sdt.signature() != *b"DMAR" {
+            return Err(());
+        }
+
+        let body = sdt.body();
+        let read_u16 = |offset| sdt.read::<u16>(offset).ok_or(());
+        let read_u64 = |offset| sdt.read::<u64>(offset).ok_or(());
+
+        let flags = *body.get(FLAGS_OFFSET).ok_or(())?;
+        let mut dmar = Self {
+            // Stored as one less than the width
+            host_address_width: body[HOST_ADDRESS_WIDTH_OFFSET] + 1,
+            interrupt_remapping: flags & INTERRUPT_REMAPPING != 0,
+            dma_control_opt_in: flags & DMA_CONTROL_OPT_IN != 0,
+            units: Vec::new(),
+            reserved_memory: Vec::new(),
+        };
+
+        let mut offset = ENTRIES_OFFSET;
+        while offset + 4 <= body.len() {
+            let entry_type = read_u16(offset)?;
+            let length = read_u16(offset + 2)? as usize;
+
+            if length < 4 || offset + length > body.len() {
+                return Err(());
+            }
+            let entry = &body[offset..offset + length];
+
+            match entry_type {
+                ENTRY_HARDWARE_UNIT if length >= HARDWARE_UNIT_SCOPES_OFFSET => {
+                    dmar.units.push(HardwareUnit {
+                        base: read_u64(offset + 8)?,
+                        segment: read_u16(offset + 6)?,
+                        include_all: entry[4] & INCLUDE_PCI_ALL != 0,
+                        devices: device_scopes(&entry[HARDWARE_UNIT_SCOPES_OFFSET..])?,
+                    });
+                }
+                ENTRY_RESERVED_MEMORY if length >= RESERVED_MEMORY_SCOPES_OFFSET => {
+                    dmar.reserved_memory.push(ReservedMemory {
+                        base: read_u64(offset + 8)?,
+                        limit: read_u64(offset + 16)?,
+                        devices: device_scopes(&entry[RESERVED_MEMORY_SCOPES_OFFSET..])?,
+                    });
+                }
+                _ => {}
+            }
+
+            offset += length;
+        }
+
+        Ok(dmar)
+    }
+}
+
+// Each scope is a type, its length, 2 reserved bytes, an enumeration ID, the starting bus, then a
+// (device, function) pair for each step of the path from there
+fn device_scopes(mut scopes: &[u8]) -> Result<Vec<DeviceScope>, ()> {
+    let mut devices = Vec::new();
+
+    while scopes.len() >= 2 {
+        let length = scopes[1] as usize;
+        if length < 6 || length > scopes.len() {
+            return Err(());
+        }
+
+        if length == 8 {
+            devices.push(DeviceScope {
+                kind: scopes[0],
+                bus: scopes[5],
+                slot: scopes[6],
+                func: scopes[7],
+            });
+        }
+
+        scopes = &scopes[length..];
+    }
+
+    Ok(devices)
+}
@@ -1,6 +1,14 @@
-use super::SdtHeader;
+use super::{Sdt, SdtHeader};
+use core::mem::{offset_of, size_of};
+
+// GenericAddressStructure address spaces
+const ADDRESS_SPACE_IO: u8 = 1;
+
+// FADT flags
+const RESET_REG_SUP: u32 = 1 << 10;
 
 #[repr(C, packed)]
+#[derive(Clone, Copy)]
 pub struct GenericAddressStructure {
     pub address_space: u8,
     pub bit_width: u8,
@@ -64,3 +72,108 @@ pub struct Fadt {
     pub x_gpe0_block: GenericAddressStructure,
     pub x_gpe1_block: GenericAddressStructure,
 }
+
+/// The parts of the FADT the kernel uses. The FADT has grown over the revisions, so fields that
+/// don't fit the table we were given are zero.
+#[derive(Debug, Clone, Copy)]
+pub struct FadtInfo {
+    /// The legacy IRQ the SCI (System Control Interrupt) is wired to
+    pub sci_interrupt: u16,
+    pub smi_command_port: u16,
+    pub acpi_enable: u8,
+    pub pm1a_event_block: u16,
+    pub pm1b_event_block: u16,
+    pub pm1_event_length: u8,
+    pub pm1a_control_block: u16,
+    pub pm1b_control_block: u16,
+    /// The CMOS RTC register holding the century, or 0 if there is none
+    pub century: u8,
+    /// The I/O port that resets the machine when `reset_value` is written to it, or 0 if there is
+    /// none we can use
+    pub reset_port: u16,
+    pub reset_value: u8,
+    pub dsdt: u64,
+    /// The physical address of the FACS, which holds the waking vector, or 0 if there is none
+    pub facs: u64,
+}
+
+impl FadtInfo {
+    pub fn parse(sdt: &Sdt) -> Result<Self, ()> {
+        if sdt.signature() != *b"FACP" {
+            return Err(());
+        }
+
+        let dsdt = read::<u64>(sdt, offset_of!(Fadt, x_dsdt))
+            .filter(|&addr| addr != 0)
+            .or_else(|| read::<u32>(sdt, offset_of!(Fadt, dsdt)).map(|addr| addr as u64))
+            .ok_or(())?;
+
+        let facs = read::<u64>(sdt, offset_of!(Fadt, x_firmware_control))
+            .filter(|&addr| addr != 0)
+            .or_else(|| read::<u32>(sdt, offset_of!(Fadt, firmware_ctrl)).map(|addr| addr as u64))
+            .unwrap_or(0);
+
+        Ok(Self {
+            sci_interrupt: read(sdt, offset_of!(Fadt, sci_interrupt)).ok_or(())?,
+            smi_command_port: read::<u32>(sdt, offset_of!(Fadt, smi_command_port)).ok_or(())?
+                as u16,
+            acpi_enable: read(sdt, offset_of!(Fadt, acpi_enable)).ok_or(())?,
+            pm1a_event_block: io_block(
+                sdt,
+                offset_of!(Fadt, pm1a_event_block),
+                offset_of!(Fadt, x_pm1a_event_block),
+            ),
+            pm1b_event_block: io_block(
+                sdt,
+                offset_of!(Fadt, pm1b_event_block),
+                offset_of!(Fadt, x_pm1b_event_block),
+            ),
+            pm1_event_length: read(sdt, offset_of!(Fadt, pm1_event_length)).unwrap_or(0),
+            pm1a_control_block: io_block(
+                sdt,
+                offset_of!(Fadt, pm1a_control_block),
+                offset_of!(Fadt, x_pm1a_control_block),
+            ),
+            pm1b_control_block: io_block(
+                sdt,
+                offset_of!(Fadt, pm1b_control_block),
+                offset_of!(Fadt, x_pm1b_control_block),
+            ),
+            century: read(sdt, offset_of!(Fadt, century)).unwrap_or(0),
+            reset_port: reset_port(sdt),
+            reset_value: read(sdt, offset_of!(Fadt, reset_value)).unwrap_or(0),
+            dsdt,
+            facs,
+        })
+    }
+}
+
+// `offset` is from the start of the table, header included
+fn read<T: Copy>(sdt: &Sdt, offset: usize) -> Option<T> {
+    sdt.read(offset - size_of::<SdtHeader>())
+}
+
+// The I/O port of a register block, from the 32-bit field or, failing that, the extended one.
+// 0 if the block doesn't exist or isn't in I/O space.
+fn io_block(sdt: &Sdt, offset: usize, extended_offset: usize) -> u16 {
+    match read::<u32>(sdt, offset) {
+        Some(port) if port != 0 => port as u16,
+        _ => match read::<GenericAddressStructure>(sdt, extended_offset) {
+            Some(gas) if gas.address_space == ADDRESS_SPACE_IO => gas.address as u16,
+            _ => 0,
+        },
+    }
+}
+
+// The reset register, if the firmware says it works and it is in I/O space
+fn reset_port(sdt: &Sdt) -> u16 {
+    let flags: u32 = read(sdt, offset_of!(Fadt, flags)).unwrap_or(0);
+    if flags & RESET_REG_SUP == 0 {
+        return 0;
+    }
+
+    match read::<GenericAddressStructure>(sdt, offset_of!(Fadt, reset_reg)) {
+        Some(gas) if gas.address_space == ADDRESS_SPACE_IO => gas.address as u16,
+        _ => 0,
+    }
+}
@@ -0,0 +1,226 @@
+use super::super::once_lock::OnceLock;
+use super::Sdt;
+use alloc::vec::Vec;
+
+// Entry types in the MADT, after the fixed part
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_SOURCE_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS: u8 = 5;
+const ENTRY_LOCAL_X2APIC: u8 = 9;
+
+// The local APIC address and flags come right after the header
+const LOCAL_APIC_ADDRESS_OFFSET: usize = 0;
+const FLAGS_OFFSET: usize = 4;
+const ENTRIES_OFFSET: usize = 8;
+
+// MADT flags: there are also 8259 PICs, which have to be masked before using the IOAPICs
+const PCAT_COMPAT: u32 = 0x1;
+
+const PROCESSOR_ENABLED: u32 = 0x1;
+const PROCESSOR_ONLINE_CAPABLE: u32 = 0x2;
+
+// Processor UID meaning "all processors" in NMI entries
+const ALL_PROCESSORS: u32 = 0xFF;
+
+pub static MADT: OnceLock<Madt> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub processor_uid: u32,
+    pub apic_id: u32,
+    /// Not enabled but online capable processors can be brought up later on; processors that
+    /// are neither should be ignored.
+    pub enabled: bool,
+    pub online_capable: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u64,
+    /// The first global system interrupt (GSI) handled by this IOAPIC
+    pub gsi_base: u32,
+}
+
+/// Where an ISA IRQ actually ends up. Without an override, IRQ n is GSI n, edge triggered and
+/// active high.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptRoute {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SourceOverride {
+    pub irq: u8,
+    pub route: InterruptRoute,
+}
+
+/// The LINT pin of a local APIC the NMI is wired to.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    /// `None` if the NMI is wired the same way on all processors
+    pub processor_uid: Option<u32>,
+    pub lint: u8,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// Everything in the MADT (Multiple APIC Description Table) we care about: the processors, the
+/// IOAPICs, and how the legacy IRQs are wired to them.
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub has_legacy_pics: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<SourceOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+impl Madt {
+    pub fn parse(sdt: &Sdt) -> Result<Self, ()> {
+        if sdt.signature() != *b"APIC" {
+            return Err(());
+        }
+
+        let body = sdt.body();
+        let read_u16 = |offset| sdt.read::<u16>(offset).ok_or(());
+        let read_u32 = |offset| sdt.read::<u32>(offset).ok_or(());
+        let read_u64 = |offset| sdt.read::<u64>(offset).ok_or(());
+
+        let mut madt = Self {
+            local_apic_address: read_u32(LOCAL_APIC_ADDRESS_OFFSET)? as u64,
+            has_legacy_pics: read_u32(FLAGS_OFFSET)? & PCAT_COMPAT != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmis: Vec::new(),
+        };
+
+        let mut offset = ENTRIES_OFFSET;
+        while offset + 2 <= body.len() {
+            let entry_type = body[offset];
+            let length = body[offset + 1] as usize;
+
+            if length < 2 || offset + length > body.len() {
+                return Err(());
+            }
+
+            match entry_type {
+                ENTRY_LOCAL_APIC if length >= 8 => {
+                    let flags = read_u32(offset + 4)?;
+                    madt.processors.push(Processor {
+                        processor_uid: body[offset + 2] as u32,
+                        apic_id: body[offset + 3] as u32,
+                        enabled: flags & PROCESSOR_ENABLED != 0,
+                        online_capable: flags & PROCESSOR_ONLINE_CAPABLE != 0,
+                    });
+                }
+                ENTRY_LOCAL_X2APIC if length >= 16 => {
+                    let flags = read_u32(offset + 8)?;
+                    madt.processors.push(Processor {
+                        processor_uid: read_u32(offset + 12)?,
+                        apic_id: read_u32(offset + 4)?,
+                        enabled: flags & PROCESSOR_ENABLED != 0,
+                        online_capable: flags & PROCESSOR_ONLINE_CAPABLE != 0,
+                    });
+                }
+                ENTRY_IO_APIC if length >= 12 => madt.io_apics.push(IoApic {
+                    id: body[offset + 2],
+                    address: read_u32(offset + 4)? as u64,
+                    gsi_base: read_u32(offset + 8)?,
+                }),
+                ENTRY_SOURCE_OVERRIDE if length >= 10 => {
+                    let (polarity, trigger) = decode_flags(read_u16(offset + 8)?);
+                    madt.overrides.push(SourceOverride {
+                        irq: body[offset + 3],
+                        route: InterruptRoute {
+                            gsi: read_u32(offset + 4)?,
+                            polarity,
+                            trigger,
+                        },
+                    });
+                }
+                ENTRY_LOCAL_APIC_NMI if length >= 6 => {
+                    let uid = body[offset + 2] as u32;
+                    let (polarity, trigger) = decode_flags(read_u16(offset + 3)?);
+                    madt.nmis.push(LocalApicNmi {
+                        processor_uid: (uid != ALL_PROCESSORS).then_some(uid),
+                        lint: body[offset + 5],
+                        polarity,
+                        trigger,
+                    });
+                }
+                ENTRY_LOCAL_APIC_ADDRESS if length >= 12 => {
+                    madt.local_apic_address = read_u64(offset + 4)?;
+                }
+                _ => {}
+            }
+
+            offset += length;
+        }
+
+        Ok(madt)
+    }
+
+    /// Where ISA IRQ `irq` is delivered, taking the interrupt source overrides into account.
+    pub fn route_irq(&self, irq: u8) -> InterruptRoute {
+        self.overrides
+            .iter()
+            .find(|o| o.irq == irq)
+            .map(|o| o.route)
+            .unwrap_or(InterruptRoute {
+                gsi: irq as u32,
+                polarity: Polarity::ActiveHigh,
+                trigger: TriggerMode::Edge,
+            })
+    }
+
+    /// The IOAPIC that handles global system interrupt `gsi`.
+    pub fn io_apic_for(&self, gsi: u32) -> Option<&IoApic> {
+        // IOAPICs don't say how many inputs they have, so take the closest base below `gsi`
+        self.io_apics
+            .iter()
+            .filter(|io_apic| io_apic.gsi_base <= gsi)
+            .max_by_key(|io_apic| io_apic.gsi_base)
+    }
+
+    /// The processors that are usable now or can be brought online later.
+    pub fn usable_processors(&self) -> impl Iterator<Item = &Processor> {
+        self.processors
+            .iter()
+            .filter(|p| p.enabled || p.online_capable)
+    }
+}
+
+// MPS INTI flags, shared by the override and NMI entries. "Conforms to the bus" means the ISA
+// defaults: active high, edge triggered.
+fn decode_flags(flags: u16) -> (Polarity, TriggerMode) {
+    let polarity = match flags & 0x3 {
+        0x3 => Polarity::ActiveLow,
+        _ => Polarity::ActiveHigh,
+    };
+
+    let trigger = match (flags >> 2) & 0x3 {
+        0x3 => TriggerMode::Level,
+        _ => TriggerMode::Edge,
+    };
+
+    (polarity, trigger)
+}
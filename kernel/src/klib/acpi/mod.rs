@@ -1,9 +1,26 @@
 pub mod rsdp;
 pub mod xsdt;
 pub mod fadt;
-use core::slice::from_raw_parts;
+pub mod madt;
+pub mod aml;
+pub mod pm;
+pub mod srat;
+pub mod dmar;
+use super::phys_mapper::{PhysMapper, PhysRegion};
+use crate::arch::x86_64::reserved;
+use crate::log_warn;
+use fadt::FadtInfo;
+use madt::{Madt, MADT};
+use rsdp::Rsdp;
+use srat::{Srat, SRAT};
+use dmar::{Dmar, DMAR};
+use xsdt::Xsdt;
+use core::mem::size_of;
 
-#[repr(C)]
+// The FACS is at least this long, and has no checksum to go by
+const FACS_MIN_LENGTH: usize = 64;
+
+#[repr(C, packed)]
 pub struct SdtHeader {
     pub signature: [u8; 4],
     pub length: u32,
@@ -16,19 +33,142 @@ pub struct SdtHeader {
     pub creator_revision: u32,
 }
 
-impl SdtHeader {
+/// A system description table mapped in full, i.e. `header.length` bytes.
+pub struct Sdt<'m> {
+    region: PhysRegion<'m>,
+}
+
+impl<'m> Sdt<'m> {
+    /// Map the table at `phys_addr`. Fails if the table doesn't fit its own header or its
+    /// checksum is wrong.
+    pub fn map(mapper: &'m PhysMapper, phys_addr: u64) -> Result<Self, ()> {
+        let length = mapper
+            .map(phys_addr, size_of::<SdtHeader>())?
+            .read::<u32>(4)
+            .ok_or(())? as usize;
+
+        if length < size_of::<SdtHeader>() {
+            return Err(());
+        }
+
+        let sdt = Self {
+            region: mapper.map(phys_addr, length)?,
+        };
+
+        if !sdt.validate_checksum() {
+            return Err(());
+        }
+
+        if reserved::reserve(phys_addr, length as u64, reserved::Kind::Firmware, "acpi").is_err() {
+            log_warn!("ACPI: couldn't reserve the table at {:#x}", phys_addr);
+        }
+
+        Ok(sdt)
+    }
+
+    pub fn header(&self) -> &'m SdtHeader {
+        // `map` made sure the header fits
+        self.region.get(0).unwrap()
+    }
+
+    pub fn signature(&self) -> [u8; 4] {
+        self.header().signature
+    }
+
+    /// The bytes after the header.
+    pub fn body(&self) -> &'m [u8] {
+        &self.region.as_bytes()[size_of::<SdtHeader>()..]
+    }
+
+    /// Copy out a `T` at `offset` bytes into the body, which doesn't need to be aligned.
+    pub fn read<T: Copy>(&self, offset: usize) -> Option<T> {
+        self.region.read(size_of::<SdtHeader>() + offset)
+    }
+
+    /// The whole table as a `T`, if it is long enough. `T` has to start with an `SdtHeader` and
+    /// be `packed`.
+    pub fn get<T>(&self) -> Option<&'m T> {
+        self.region.get(0)
+    }
+
     fn validate_checksum(&self) -> bool {
-        let checksum = self.as_u8_slice().iter().fold(0u8, |acc, &x| acc.wrapping_add(x));
-        
-        checksum.wrapping_sub(self.checksum) == 0u8
-    }
-    
-    fn as_u8_slice(&self) -> &[u8] {
-        unsafe {
-            from_raw_parts(
-                (self as *const Self) as *const u8,
-                self.length as usize,
-            )
+        let bytes = self.region.as_bytes();
+        bytes.iter().fold(0u8, |acc, &x| acc.wrapping_add(x)) == 0
+    }
+}
+
+/// Find and parse the tables the rest of the kernel needs, starting from the RSDP.
+pub fn init(mapper: &PhysMapper, rsdp_addr: u64) -> Result<(), ()> {
+    let rsdp = Rsdp::get(mapper, rsdp_addr)?;
+
+    if !rsdp.validate_signature() {
+        return Err(());
+    }
+
+    let xsdt = Xsdt::map(mapper, rsdp.get_sdt_addr().ok_or(())?)?;
+
+    match xsdt.find(mapper, b"APIC").map(|sdt| Madt::parse(&sdt)) {
+        Some(Ok(madt)) => {
+            let _ = MADT.set(madt);
         }
+        _ => log_warn!("No usable MADT"),
     }
+
+    // Only machines with more than one NUMA node tend to have one
+    match xsdt.find(mapper, b"SRAT").map(|sdt| Srat::parse(&sdt)) {
+        Some(Ok(srat)) => {
+            let _ = SRAT.set(srat);
+        }
+        Some(Err(())) => log_warn!("Couldn't parse the SRAT"),
+        None => {}
+    }
+
+    // Only there if the machine has VT-d IOMMUs
+    match xsdt.find(mapper, b"DMAR").map(|sdt| Dmar::parse(&sdt)) {
+        Some(Ok(dmar)) => {
+            let _ = DMAR.set(dmar);
+        }
+        Some(Err(())) => log_warn!("Couldn't parse the DMAR"),
+        None => {}
+    }
+
+    match xsdt.find_fadt(mapper).map(|sdt| FadtInfo::parse(&sdt)) {
+        Some(Ok(fadt)) => {
+            let dsdt = Sdt::map(mapper, fadt.dsdt)
+                .ok()
+                .filter(|dsdt| dsdt.signature() == *b"DSDT");
+
+            let facs = map_facs(mapper, fadt.facs);
+            if facs.is_none() {
+                log_warn!("No usable FACS, can't suspend");
+            }
+
+            if pm::init(fadt, dsdt.as_ref(), facs).is_err() {
+                log_warn!("No ACPI power management");
+            }
+        }
+        _ => log_warn!("No usable FADT"),
+    }
+
+    Ok(())
+}
+
+// Map the FACS so that the waking vector can be written, returning its virtual address
+fn map_facs(mapper: &PhysMapper, phys_addr: u64) -> Option<u64> {
+    if phys_addr == 0 {
+        return None;
+    }
+
+    let facs = mapper.map_writable(phys_addr, FACS_MIN_LENGTH).ok()?;
+    let signature = unsafe { (facs as *const [u8; 4]).read_unaligned() };
+    if signature != *b"FACS" {
+        return None;
+    }
+
+    let length = FACS_MIN_LENGTH as u64;
+    if reserved::reserve(phys_addr, length, reserved::Kind::Firmware, "acpi").is_err() {
+        log_warn!("ACPI: couldn't reserve the FACS at {:#x}", phys_addr);
+    }
+
+    Some(facs)
 }
@@ -0,0 +1,356 @@
+use super::super::once_lock::OnceLock;
+use super::super::{shutdown, suspend};
+use super::aml;
+use super::fadt::FadtInfo;
+use super::Sdt;
+use crate::arch::ports::{self, Port};
+use crate::arch::x86_64::cpu::{self, lidt, DescriptorTablePointer};
+use crate::arch::x86_64::paging::CanonicalAddress;
+use crate::arch::x86_64::wakeup;
+use crate::arch::{Arch, Cpu};
+use crate::task::{self, Priority, WaitQueue};
+use crate::{log_info, log_warn};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+
+// PM1 status and enable register bits
+const PM1_POWER_BUTTON: u16 = 1 << 8;
+const PM1_WAKE: u16 = 1 << 15;
+
+// PM1 control register bits
+const PM1_SCI_ENABLE: u16 = 1 << 0;
+const PM1_SLEEP_TYPE_SHIFT: u16 = 10;
+const PM1_SLEEP_TYPE_MASK: u16 = 0x7 << PM1_SLEEP_TYPE_SHIFT;
+const PM1_SLEEP_ENABLE: u16 = 1 << 13;
+// In ports; the FADT gives it, but it's always a single 16-bit register
+const PM1_CONTROL_LENGTH: u16 = 2;
+
+// Iterations to wait for the firmware to hand over to ACPI mode
+const ACPI_ENABLE_TIMEOUT: usize = 10_000_000;
+
+// The PS/2 controller's command port, and the command that pulses the CPU reset line. The port is
+// the PS/2 driver's, but nothing else runs by the time it is used for this.
+const PS2_COMMAND: Port<u8> = Port::new(0x64);
+const PS2_INPUT_FULL: u8 = 1 << 1;
+const PS2_PULSE_RESET: u8 = 0xFE;
+
+// Iterations to wait for a reset to take before trying the next way
+const RESET_TIMEOUT: usize = 1_000_000;
+
+// Iterations to wait for the machine to go to sleep before giving up
+const SLEEP_TIMEOUT: usize = 10_000_000;
+
+// FACS fields
+const FACS_WAKING_VECTOR: u64 = 12;
+const FACS_X_WAKING_VECTOR: u64 = 24;
+
+static POWER: OnceLock<PowerManagement> = OnceLock::new();
+
+// Shutting down waits for the disks, so the SCI handler leaves it to the power button task
+static POWER_BUTTON_PRESSED: AtomicBool = AtomicBool::new(false);
+static POWER_BUTTON: WaitQueue = WaitQueue::new();
+
+/// ACPI fixed-feature power management: the power button, suspend to RAM and soft off, through
+/// the PM1 register blocks described by the FADT.
+struct PowerManagement {
+    fadt: FadtInfo,
+    /// SLP_TYPa and SLP_TYPb for S3 (suspend to RAM), from the \_S3 package in the DSDT
+    s3_sleep_type: Option<(u16, u16)>,
+    /// SLP_TYPa and SLP_TYPb for S5 (soft off), from the \_S5 package in the DSDT
+    s5_sleep_type: Option<(u16, u16)>,
+    /// Where the FACS is mapped
+    facs: Option<u64>,
+}
+
+impl PowerManagement {
+    // The enable register follows the status register in each event block
+    fn enable_register(&self, block: u16) -> u16 {
+        block + self.fadt.pm1_event_length as u16 / 2
+    }
+
+    unsafe fn read_status(&self) -> u16 {
+        let mut status = Port::<u16>::new(self.fadt.pm1a_event_block).read();
+        if self.fadt.pm1b_event_block != 0 {
+            status |= Port::<u16>::new(self.fadt.pm1b_event_block).read();
+        }
+        status
+    }
+
+    // Status bits are cleared by writing 1 to them
+    unsafe fn clear_status(&self, bits: u16) {
+        Port::new(self.fadt.pm1a_event_block).write(bits);
+        if self.fadt.pm1b_event_block != 0 {
+            Port::new(self.fadt.pm1b_event_block).write(bits);
+        }
+    }
+
+    unsafe fn write_enable(&self, bits: u16) {
+        Port::new(self.enable_register(self.fadt.pm1a_event_block)).write(bits);
+        if self.fadt.pm1b_event_block != 0 {
+            Port::new(self.enable_register(self.fadt.pm1b_event_block)).write(bits);
+        }
+    }
+
+    unsafe fn acpi_enabled(&self) -> bool {
+        Port::<u16>::new(self.fadt.pm1a_control_block).read() & PM1_SCI_ENABLE != 0
+    }
+
+    // Ask the firmware to stop handling power management events through SMIs and send us SCIs.
+    unsafe fn enable_acpi(&self) -> Result<(), ()> {
+        if self.acpi_enabled() {
+            return Ok(());
+        }
+
+        // Without an SMI command port there is no legacy mode to switch out of
+        if self.fadt.smi_command_port == 0 || self.fadt.acpi_enable == 0 {
+            return Err(());
+        }
+
+        Port::new(self.fadt.smi_command_port).write(self.fadt.acpi_enable);
+
+        for _ in 0..ACPI_ENABLE_TIMEOUT {
+            if self.acpi_enabled() {
+                return Ok(());
+            }
+            Arch::pause();
+        }
+
+        Err(())
+    }
+
+    unsafe fn enter_sleep_state(&self, (sleep_type_a, sleep_type_b): (u16, u16)) {
+        if self.fadt.pm1b_control_block != 0 {
+            let control =
+                Port::<u16>::new(self.fadt.pm1b_control_block).read() & !PM1_SLEEP_TYPE_MASK;
+            Port::new(self.fadt.pm1b_control_block)
+                .write(control | (sleep_type_b << PM1_SLEEP_TYPE_SHIFT) | PM1_SLEEP_ENABLE);
+        }
+
+        let control = Port::<u16>::new(self.fadt.pm1a_control_block).read() & !PM1_SLEEP_TYPE_MASK;
+        Port::new(self.fadt.pm1a_control_block)
+            .write(control | (sleep_type_a << PM1_SLEEP_TYPE_SHIFT) | PM1_SLEEP_ENABLE);
+    }
+}
+
+/// Set up power management from the FADT, DSDT and FACS, the last given by where it is mapped.
+/// Nothing is enabled until `enable`.
+pub fn init(fadt: FadtInfo, dsdt: Option<&Sdt>, facs: Option<u64>) -> Result<(), ()> {
+    if fadt.pm1a_event_block == 0 || fadt.pm1a_control_block == 0 {
+        return Err(());
+    }
+
+    let sleep_type = |name| {
+        dsdt.and_then(|dsdt| aml::find_package::<2>(dsdt.body(), name))
+            .map(|[a, b]| (a as u16 & 0x7, b as u16 & 0x7))
+    };
+    let s3_sleep_type = sleep_type(b"_S3_");
+    let s5_sleep_type = sleep_type(b"_S5_");
+
+    if s5_sleep_type.is_none() {
+        log_warn!("No \\_S5 in the DSDT, can't power off");
+    }
+
+    // The reset register isn't claimed, as it's usually in the PCI configuration ports
+    let blocks = [
+        (fadt.pm1a_event_block, fadt.pm1_event_length as u16),
+        (fadt.pm1b_event_block, fadt.pm1_event_length as u16),
+        (fadt.pm1a_control_block, PM1_CONTROL_LENGTH),
+        (fadt.pm1b_control_block, PM1_CONTROL_LENGTH),
+        (fadt.smi_command_port, 1),
+    ];
+    for (first, count) in blocks.into_iter().filter(|&(first, _)| first != 0) {
+        if ports::claim(first, count, "acpi").is_err() {
+            log_warn!("ACPI ports from {:#x} are already claimed", first);
+            return Err(());
+        }
+    }
+
+    POWER
+        .set(PowerManagement {
+            fadt,
+            s3_sleep_type,
+            s5_sleep_type,
+            facs,
+        })
+        .map_err(|_| ())
+}
+
+/// The IRQ the SCI arrives on, which needs a handler before calling `enable`.
+pub fn sci_irq() -> Option<u8> {
+    POWER.get().map(|power| power.fadt.sci_interrupt as u8)
+}
+
+/// Switch to ACPI mode and turn on power button events.
+pub fn enable() -> Result<(), ()> {
+    let power = POWER.get().ok_or(())?;
+
+    unsafe {
+        power.enable_acpi()?;
+        power.clear_status(PM1_POWER_BUTTON);
+        power.write_enable(PM1_POWER_BUTTON);
+    }
+
+    Ok(())
+}
+
+/// Handle an SCI. Has the power button task shut down if the power button was pressed.
+pub fn handle_sci() {
+    let Some(power) = POWER.get() else {
+        return;
+    };
+
+    let status = unsafe { power.read_status() };
+
+    if status & PM1_POWER_BUTTON != 0 {
+        unsafe { power.clear_status(PM1_POWER_BUTTON) };
+        POWER_BUTTON_PRESSED.store(true, Ordering::SeqCst);
+        POWER_BUTTON.wake_one();
+    }
+}
+
+/// Start the task that shuts down when the power button is pressed. Has to be called after
+/// `task::init`; a press before then shuts down once it is.
+pub fn start_power_button() {
+    let power_button = task::spawn("power button", Priority::High, || {
+        POWER_BUTTON.wait_while(|| !POWER_BUTTON_PRESSED.load(Ordering::SeqCst));
+        log_info!("Power button pressed, shutting down");
+        shutdown();
+    });
+
+    if power_button.is_err() {
+        log_warn!("Couldn't start the power button task, the power button won't work");
+    }
+}
+
+/// Stop everything that registered a teardown, then turn the machine off. Has to be called from a
+/// task. Halts forever if turning off isn't possible.
+pub fn shutdown() -> ! {
+    shutdown::run();
+    interrupts::disable();
+
+    if let Some((power, sleep_type)) = POWER
+        .get()
+        .and_then(|power| Some((power, power.s5_sleep_type?)))
+    {
+        unsafe { power.enter_sleep_state(sleep_type) };
+    }
+
+    // Either there is no ACPI, or the write didn't take
+    log_warn!("Failed to power off, halting");
+    loop {
+        Arch::halt();
+    }
+}
+
+/// Suspend to RAM (S3) until the power button is pressed: suspend every device that registered
+/// hooks, sleep, and resume them again. Has to be called from a task. Experimental: devices that
+/// didn't register hooks are left however the firmware leaves them.
+pub fn suspend() -> Result<(), ()> {
+    let power = POWER.get().ok_or(())?;
+    let facs = power.facs.ok_or(())?;
+    let vector = wakeup::waking_vector().ok_or(())?;
+    if power.s3_sleep_type.is_none() {
+        return Err(());
+    }
+
+    // Without the 64-bit vector the firmware jumps to the 32-bit one in real mode
+    unsafe {
+        ((facs + FACS_WAKING_VECTOR) as *mut u32).write_volatile(vector);
+        ((facs + FACS_X_WAKING_VECTOR) as *mut u64).write_volatile(0);
+    }
+
+    suspend::suspend_devices()?;
+
+    interrupts::disable();
+    let slept = unsafe {
+        power.clear_status(PM1_WAKE | PM1_POWER_BUTTON);
+        wakeup::sleep(enter_s3)
+    };
+    suspend::resume_devices();
+
+    // The button press that woke us shouldn't shut down as well
+    unsafe {
+        power.clear_status(PM1_WAKE | PM1_POWER_BUTTON);
+        if power.enable_acpi().is_err() {
+            log_warn!("Firmware didn't hand ACPI back after waking");
+        }
+        power.write_enable(PM1_POWER_BUTTON);
+    }
+    interrupts::enable();
+
+    if slept.is_err() {
+        log_warn!("Failed to suspend");
+    }
+    slept
+}
+
+// Called by `wakeup::sleep` once the processor's state is saved. Only returns if the machine
+// didn't go to sleep.
+extern "C" fn enter_s3() {
+    let Some((power, sleep_type)) = POWER
+        .get()
+        .and_then(|power| Some((power, power.s3_sleep_type?)))
+    else {
+        return;
+    };
+
+    unsafe {
+        // Caches lose their contents in S3
+        cpu::wbinvd();
+        power.enter_sleep_state(sleep_type);
+    }
+
+    for _ in 0..SLEEP_TIMEOUT {
+        Arch::pause();
+    }
+}
+
+/// Stop everything that registered a teardown, then restart the machine. Has to be called from a
+/// task.
+pub fn reboot() -> ! {
+    shutdown::run();
+    emergency_reboot();
+}
+
+/// Restart the machine straight away, without stopping anything first: through the ACPI reset
+/// register if there is one, then the PS/2 controller, and failing both by triple faulting.
+pub fn emergency_reboot() -> ! {
+    interrupts::disable();
+
+    if let Some(power) = POWER.get().filter(|power| power.fadt.reset_port != 0) {
+        unsafe { Port::new(power.fadt.reset_port).write(power.fadt.reset_value) };
+        wait_for_reset();
+    }
+
+    unsafe {
+        for _ in 0..RESET_TIMEOUT {
+            if PS2_COMMAND.read() & PS2_INPUT_FULL == 0 {
+                break;
+            }
+            Arch::pause();
+        }
+        PS2_COMMAND.write(PS2_PULSE_RESET);
+    }
+    wait_for_reset();
+
+    // With an empty IDT the breakpoint can't be delivered, and neither can the double fault that
+    // follows, which resets the processor
+    log_warn!("Failed to reset, triple faulting");
+    unsafe {
+        lidt(&DescriptorTablePointer {
+            limit: 0,
+            base: CanonicalAddress::new_unsafe(0),
+        })
+    };
+    Arch::breakpoint();
+
+    loop {
+        Arch::halt();
+    }
+}
+
+fn wait_for_reset() {
+    for _ in 0..RESET_TIMEOUT {
+        Arch::pause();
+    }
+}
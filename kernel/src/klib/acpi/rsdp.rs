@@ -1,3 +1,4 @@
+use super::super::phys_mapper::PhysMapper;
 use super::super::util::as_u8_slice;
 
 #[repr(C, packed)]
@@ -19,9 +20,12 @@ pub enum SdtAddr {
 }
 
 impl Rsdp {
-    /// "Get" the rsdp structure, given the rsdp address.
-    pub unsafe fn get(rsdp_addr: usize) -> &'static Self {
-        unsafe { &*(rsdp_addr as *const Self) }
+    /// Map the rsdp structure, given its physical address.
+    pub fn get<'m>(mapper: &'m PhysMapper, rsdp_addr: u64) -> Result<&'m Self, ()> {
+        mapper
+            .map(rsdp_addr, core::mem::size_of::<Self>())?
+            .get(0)
+            .ok_or(())
     }
 
     pub fn validate_signature(&self) -> bool {
@@ -48,7 +52,8 @@ impl Rsdp {
                         .iter()
                         .fold(0u8, |acc, &x| acc.wrapping_add(x));
 
-        if checksum1.wrapping_sub(self.checksum) != self.checksum {
+        // The checksum byte is picked so that everything adds up to zero
+        if checksum1 != 0 {
             return false;
         }
 
@@ -60,6 +65,7 @@ impl Rsdp {
                         .iter()
                         .fold(0u8, |acc, &x| acc.wrapping_add(x));
 
-        checksum2.wrapping_sub(self.extended_checksum) == self.extended_checksum
+        // The extended checksum covers the whole structure, but the first part already sums to 0
+        checksum2 == 0
     }
 }
@@ -0,0 +1,132 @@
+use super::super::once_lock::OnceLock;
+use super::Sdt;
+use alloc::vec::Vec;
+
+// Entry types in the SRAT, after the fixed part
+const ENTRY_PROCESSOR: u8 = 0;
+const ENTRY_MEMORY: u8 = 1;
+const ENTRY_X2APIC_PROCESSOR: u8 = 2;
+
+// A revision field and 8 reserved bytes come right after the header
+const ENTRIES_OFFSET: usize = 12;
+
+// Flags shared by every entry type: entries without it are to be ignored
+const AFFINITY_ENABLED: u32 = 0x1;
+
+const MEMORY_HOT_PLUGGABLE: u32 = 0x2;
+const MEMORY_NON_VOLATILE: u32 = 0x4;
+
+/// Only set if the firmware has an SRAT, which it generally only does on machines with more than
+/// one NUMA node.
+pub static SRAT: OnceLock<Srat> = OnceLock::new();
+
+/// Which proximity domain (NUMA node) a processor belongs to.
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessorAffinity {
+    pub apic_id: u32,
+    pub domain: u32,
+}
+
+/// Which proximity domain a range of physical memory belongs to.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryAffinity {
+    pub base: u64,
+    pub length: u64,
+    pub domain: u32,
+    pub hot_pluggable: bool,
+    pub non_volatile: bool,
+}
+
+/// The enabled entries of the SRAT (System Resource Affinity Table).
+#[derive(Debug)]
+pub struct Srat {
+    pub processors: Vec<ProcessorAffinity>,
+    pub memory: Vec<MemoryAffinity>,
+}
+
+impl Srat {
+    pub fn parse(sdt: &Sdt) -> Result<Self, ()> {
+        if sdt.signature() != *b"SRAT" {
+            return Err(());
+        }
+
+        let body = sdt.body();
+        let read_u32 = |offset| sdt.read::<u32>(offset).ok_or(());
+        let read_u64 = |offset| sdt.read::<u64>(offset).ok_or(());
+
+        let mut srat = Self {
+            processors: Vec::new(),
+            memory: Vec::new(),
+        };
+
+        let mut offset = ENTRIES_OFFSET;
+        while offset + 2 <= body.len() {
+            let entry_type = body[offset];
+            let length = body[offset + 1] as usize;
+
+            if length < 2 || offset + length > body.len() {
+                return Err(());
+            }
+
+            match entry_type {
+                ENTRY_PROCESSOR if length >= 16 => {
+                    // The domain is split into its low byte and the three bytes above it
+                    let high = read_u32(offset + 8)? >> 8;
+                    if read_u32(offset + 4)? & AFFINITY_ENABLED != 0 {
+                        srat.processors.push(ProcessorAffinity {
+                            apic_id: body[offset + 3] as u32,
+                            domain: (high << 8) | body[offset + 2] as u32,
+                        });
+                    }
+                }
+                ENTRY_X2APIC_PROCESSOR if length >= 24 => {
+                    if read_u32(offset + 12)? & AFFINITY_ENABLED != 0 {
+                        srat.processors.push(ProcessorAffinity {
+                            apic_id: read_u32(offset + 8)?,
+                            domain: read_u32(offset + 4)?,
+                        });
+                    }
+                }
+                ENTRY_MEMORY if length >= 40 => {
+                    let flags = read_u32(offset + 28)?;
+                    let size = read_u64(offset + 16)?;
+                    if flags & AFFINITY_ENABLED != 0 && size > 0 {
+                        srat.memory.push(MemoryAffinity {
+                            base: read_u64(offset + 8)?,
+                            length: size,
+                            domain: read_u32(offset + 2)?,
+                            hot_pluggable: flags & MEMORY_HOT_PLUGGABLE != 0,
+                            non_volatile: flags & MEMORY_NON_VOLATILE != 0,
+                        });
+                    }
+                }
+                _ => {}
+            }
+
+            offset += length;
+        }
+
+        Ok(srat)
+    }
+
+    /// The proximity domain physical address `addr` is in, if the SRAT says.
+    pub fn domain_of(&self, addr: u64) -> Option<u32> {
+        self.memory
+            .iter()
+            .find(|range| range.base <= addr && addr - range.base < range.length)
+            .map(|range| range.domain)
+    }
+
+    /// Every proximity domain that has memory or processors in it, in ascending order.
+    pub fn domains(&self) -> Vec<u32> {
+        let mut domains: Vec<u32> = self
+            .memory
+            .iter()
+            .map(|range| range.domain)
+            .chain(self.processors.iter().map(|processor| processor.domain))
+            .collect();
+        domains.sort_unstable();
+        domains.dedup();
+        domains
+    }
+}
@@ -1,33 +1,47 @@
-use super::super::util::as_u8_slice;
-use super::fadt::Fadt;
-use super::SdtHeader;
-use core::slice::from_raw_parts;
-use core::mem::size_of;
-use core::ptr;
-
-#[repr(C)]
-pub struct Xsdt {
-    pub header: SdtHeader,
+use super::super::phys_mapper::PhysMapper;
+use super::rsdp::SdtAddr;
+use super::Sdt;
+
+/// The root table listing all other tables. Old firmware only has an RSDT, which is the same
+/// thing with 32-bit table addresses.
+pub struct Xsdt<'m> {
+    sdt: Sdt<'m>,
+    entry_size: usize,
 }
 
-impl Xsdt {
-    fn get_header_ptrs_start(&self) -> *const u64 {
-        let u8_ptr = unsafe { ((self as *const Xsdt) as *const u8).add(size_of::<SdtHeader>()) };
+impl<'m> Xsdt<'m> {
+    pub fn map(mapper: &'m PhysMapper, addr: SdtAddr) -> Result<Self, ()> {
+        let (sdt, entry_size, signature) = match addr {
+            SdtAddr::Rsdt(addr) => (Sdt::map(mapper, addr)?, 4, *b"RSDT"),
+            SdtAddr::Xsdt(addr) => (Sdt::map(mapper, addr)?, 8, *b"XSDT"),
+        };
 
-        u8_ptr as *const u64
-    }
+        if sdt.signature() != signature {
+            return Err(());
+        }
 
-    fn find_fadt(&self) -> Option<Fadt> {
-        let mut ptr = self.get_header_ptrs_start();
-        
-        for i in 0..self.header.length {
-            let table_ptr = unsafe { ptr::read_unaligned(ptr) };
+        Ok(Self { sdt, entry_size })
+    }
 
-            ptr = unsafe { ptr.add(1) };
+    /// The physical addresses of all tables listed.
+    pub fn entries(&self) -> impl Iterator<Item = u64> + 'm {
+        let entry_size = self.entry_size;
 
+        self.sdt.body().chunks_exact(entry_size).map(move |entry| {
+            let mut bytes = [0u8; 8];
+            bytes[..entry_size].copy_from_slice(entry);
+            u64::from_le_bytes(bytes)
+        })
+    }
 
-        }
+    /// Find and map the first table with the given signature, skipping ones that are broken.
+    pub fn find(&self, mapper: &'m PhysMapper, signature: &[u8; 4]) -> Option<Sdt<'m>> {
+        self.entries()
+            .filter_map(|addr| Sdt::map(mapper, addr).ok())
+            .find(|sdt| &sdt.signature() == signature)
+    }
 
-        None
+    pub fn find_fadt(&self, mapper: &'m PhysMapper) -> Option<Sdt<'m>> {
+        self.find(mapper, b"FACP")
     }
 }
@@ -1,50 +1,237 @@
+use super::super::ata;
 use super::super::pci;
+use super::super::pci::power;
 use super::super::util;
-use super::{DMAState, PortCommandMasks, PortRegisters, Registers};
+use super::{
+    Capability2Masks, CapabilityMasks, DMAState, DevSleepMasks, FBSMasks, PortCommandMasks,
+    PortRegisters, Registers, MAX_PRDS, MAX_PRD_BYTES,
+};
+use crate::arch::x86_64::interrupts::pic::Irq;
+use crate::arch::{Arch, Cpu};
 use crate::klib::ahci::GHCMasks;
+use crate::klib::block;
+use crate::klib::block::BlockDevice;
+use crate::klib::block::IOError;
+use crate::klib::dma::{BounceBuffer, DmaBox};
+use crate::klib::kbox::KBox;
+use crate::klib::mmio::ReadOnly;
 use crate::klib::once_lock::OnceLock;
 use crate::klib::pci::pcistate::PCI_STATE;
-use crate::klib::x86_64::pause;
+use crate::klib::timer;
 use crate::println;
+use crate::task::{self, Priority, WaitQueue};
 use crate::BootInfoFrameAllocator;
-use crate::KERNEL_PAGETABLE;
+use crate::{log_info, log_warn};
 use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use ata::Command as ATACommand;
+use ata::{IdentifyData, RegisterFis};
 use core::marker::PhantomData;
 use core::mem::MaybeUninit;
 use core::ptr::addr_of;
 use core::ptr::addr_of_mut;
-use pci::ide_controller::Command as IDECommand;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
 use pci::pcistate::PCIState;
 use pci::Register;
 use spin::RwLock;
-use util::Volatile;
 use x86_64::instructions::interrupts;
-use x86_64::structures::paging::Mapper;
-use x86_64::structures::paging::Page;
-use x86_64::structures::paging::PageTableFlags;
-use x86_64::structures::paging::PhysFrame;
-use x86_64::structures::paging::Size4KiB;
-use x86_64::PhysAddr;
-use x86_64::VirtAddr;
 
-// TODO: change to dynamic var in ahci state, this is not true for all drives
-const SECTOR_SIZE: u32 = 512;
+// NCQ commands have LBA mode set in the device register, and FUA in its top bit
+const NCQ_DEVICE_LBA: u8 = 0x40;
+const NCQ_DEVICE_FUA: u8 = 0x80;
+
+// The port multiplier itself answers on this port; devices behind it are on 0..=14
+const PM_CONTROL_PORT: u8 = 0xF;
+
+// Port multiplier registers. GSCR registers are global (read through the control port), the
+// PSCR registers exist once for every device port.
+const GSCR_PORT_INFO: u32 = 2;
+const PSCR_SSTATUS: u32 = 0;
+const PSCR_SERROR: u32 = 1;
+const PSCR_SCONTROL: u32 = 2;
+
+// How many timer ticks to wait for the link to a device behind a port multiplier to come up.
+const PM_LINK_TIMEOUT: u64 = 10;
+
+// Offset of the received D2H register FIS in the RFIS area, in dwords. Like the Set Device Bits
+// FIS, its first dword holds the status (bits 16-23) and error (bits 24-31) registers, here from
+// the last non-NCQ command.
+const RFIS_D2H_OFFSET: usize = 0x40 / 4;
+
+// Offset of the received Set Device Bits FIS, in dwords. Its first dword holds the status (bits
+// 16-23) and error (bits 24-31) the device reported with its last NCQ completions.
+const RFIS_SDB_OFFSET: usize = 0x58 / 4;
+
+/// What each bit of the port interrupt status (PxIS) means. The driver counts how often each one
+/// is raised, see `interrupt_counts`.
+pub const INTERRUPT_CAUSES: [(u32, &str); 15] = [
+    (1 << 0, "d2h register fis"),
+    (1 << 1, "pio setup fis"),
+    (1 << 2, "dma setup fis"),
+    (1 << 3, "set device bits fis"),
+    (1 << 4, "unknown fis"),
+    (1 << 5, "descriptor processed"),
+    (1 << 6, "port connect change"),
+    (1 << 22, "phy ready change"),
+    (1 << 23, "bad port multiplier"),
+    (1 << 24, "overflow"),
+    (1 << 26, "interface error"),
+    (1 << 27, "interface fatal error"),
+    (1 << 28, "host bus data error"),
+    (1 << 29, "host bus fatal error"),
+    (1 << 30, "task file error"),
+];
+
+const TASK_FILE_ERROR: u32 = 1 << 30;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+// Times each PxIS bit was seen set, by bit number
+static CAUSE_COUNTS: [AtomicU64; 32] = [NO_INTERRUPTS; 32];
+
+/// How many interrupts each cause in `INTERRUPT_CAUSES` has been behind so far.
+pub fn interrupt_counts() -> impl Iterator<Item = (&'static str, u64)> {
+    INTERRUPT_CAUSES.into_iter().map(|(mask, name)| {
+        let count = CAUSE_COUNTS[mask.trailing_zeros() as usize].load(Ordering::Relaxed);
+        (name, count)
+    })
+}
+
+// Size of the HBA's register window (ABAR)
+const ABAR_SIZE: u64 = 0x1100;
+
+// Iterations of the polling loop in `write_polled` before giving up on the disk
+const POLLED_TIMEOUT: usize = 10_000_000;
+
+// Iterations to wait for each half of the port to stop in `stop`, or for the disk to come back in
+// `resume`
+const PORT_TIMEOUT: usize = 10_000_000;
+
+// Iterations to wait for a port to find its disk after being told to spin up, when the HBA
+// staggers spin-up. A port with nothing on it takes all of them, so this is much shorter.
+const SPIN_UP_TIMEOUT: usize = 1_000_000;
+
+// Iterations to wait for the link to change power state. Commands may be issued with interrupts
+// off, so waking the link for one can't wait on the timer.
+const LINK_POWER_TIMEOUT: usize = 1_000_000;
 
-const CFIS_COMMAND: u32 = 0x8027;
+// SET FEATURES subcommand to enable a SATA feature, and the feature (in the count) for DevSleep
+const ENABLE_SATA_FEATURE: u32 = 0x10;
+const SATA_FEATURE_DEVSLEEP: u32 = 0x09;
+
+// How long a command may take before its caller gets an error instead. Disks can take a while to
+// spin up or to retry a bad sector, so it's generous.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+const PAGE_SIZE: u64 = 4096;
+
+// Bounce buffers are page aligned, so one this big always fits in a single command's PRDT
+const BOUNCE_SIZE: usize = MAX_PRDS * PAGE_SIZE as usize;
 
 static DRIVE_REGISTER: OnceLock<RwLock<&'static mut Registers>> = OnceLock::new();
 
 pub static SATA_DISK0: OnceLock<RwLock<&'static mut AHCIState>> = OnceLock::new();
 
+// Set by the interrupt handler when a disk goes away, for the unplug task to take it out of the
+// block devices
+static UNPLUGGED: AtomicBool = AtomicBool::new(false);
+static UNPLUG: WaitQueue = WaitQueue::new();
+
+// Set by `use_polling`: interrupts stay off, and whoever waits on a command looks for it finishing
+// itself
+static POLLED: AtomicBool = AtomicBool::new(false);
+
 // NCQ slot statuses; i.e., showing which commands have finished.
 // I would love to lower this into AHCIState safely, but rn my brain is cooked and I can't really
 // think of a nice way to do it. this is the quick and dirty way. I don't anticipate any major
 // safety problems from this anyway *at the moment*; eventually this will have to change.
 static mut SLOT_STATUS: [*mut u32; 32] = [core::ptr::null_mut(); 32];
 
+// Slots in use are tracked in a u16, so only the first 16 of the (up to) 32 are used
+const MAX_SLOTS: u32 = 16;
+
+// What a slot's status is set to while its command is in flight. Finished commands get 0, failed
+// ones `SLOT_FAILED` along with the error and status registers (see `CommandError`), and ones the
+// disk took too long with `SLOT_TIMED_OUT`.
+const SLOT_PENDING: u32 = IOError::TryAgain as u32;
+const SLOT_FAILED: u32 = 1 << 16;
+const SLOT_TIMED_OUT: u32 = 1 << 17;
+const SLOT_DEVICE_GONE: u32 = 1 << 18;
+
+/// Why a read or write failed.
+#[derive(Clone, Copy, Debug)]
+pub enum CommandError {
+    /// The disk failed the command, with the ATA status and error registers it reported in the
+    /// received FIS. The bits are in `ata::Status` and `ata::Error`.
+    Device { status: u8, error: u8 },
+    /// No command can do the transfer as asked, e.g. a buffer smaller than a sector.
+    Invalid,
+    /// There was no memory for a bounce buffer.
+    TryAgain,
+    /// The disk didn't finish the command within `COMMAND_TIMEOUT`.
+    TimedOut,
+    /// The disk went away, e.g. it was unplugged.
+    DeviceGone,
+}
+
+impl CommandError {
+    // Decode a slot status the interrupt handler wrote
+    fn from_slot(result: u32) -> Result<(), Self> {
+        match result {
+            0 => Ok(()),
+            SLOT_TIMED_OUT => Err(CommandError::TimedOut),
+            SLOT_DEVICE_GONE => Err(CommandError::DeviceGone),
+            _ => Err(CommandError::Device {
+                status: result as u8,
+                error: (result >> 8) as u8,
+            }),
+        }
+    }
+
+    fn to_slot(status: u8, error: u8) -> u32 {
+        SLOT_FAILED | (error as u32) << 8 | status as u32
+    }
+
+    /// Whether the disk refused the command outright, e.g. one it doesn't support.
+    pub fn aborted(&self) -> bool {
+        self.error_bit(ata::Error::CommandAborted)
+    }
+
+    /// Whether the data on the disk couldn't be read back, even with error correction.
+    pub fn uncorrectable(&self) -> bool {
+        self.error_bit(ata::Error::Uncorrectable)
+    }
+
+    /// Whether the sectors asked for don't exist on the disk.
+    pub fn id_not_found(&self) -> bool {
+        self.error_bit(ata::Error::IDMarkNotFound)
+    }
+
+    fn error_bit(&self, bit: ata::Error) -> bool {
+        matches!(self, CommandError::Device { error, .. } if error & bit as u8 != 0)
+    }
+}
+
+impl From<CommandError> for IOError {
+    fn from(error: CommandError) -> Self {
+        match error {
+            CommandError::Device { .. } => IOError::BadData,
+            CommandError::Invalid => IOError::Invalid,
+            CommandError::TryAgain => IOError::TryAgain,
+            CommandError::TimedOut => IOError::TimedOut,
+            CommandError::DeviceGone => IOError::DeviceGone,
+        }
+    }
+}
+
 #[repr(C)]
 pub struct AHCIState {
-    dma: Box<DMAState>,
+    dma: DmaBox<DMAState>,
     bus: u32,
     slot: u32,
     func: u32,
@@ -58,15 +245,68 @@ pub struct AHCIState {
     // These should remain constant after loading
     pub irq: u32,
     num_sectors: usize,
+    // Whether a port multiplier sits between the port and the disks
+    pm_attached: bool,
+    // BAR 5 and the command register, from `suspend` until `resume` puts them back
+    suspended_pci: Option<(u32, u16)>,
+    devices: Vec<PortDevice>,
     num_ncq_slots: u32,
     slots_full_mask: u32,
 
     // These are modifiable
     num_slots_available: u16,
     slots_outstanding_mask: u16,
+    // What the link was last put in. Commands wake it first if it isn't active.
+    link_power: LinkPower,
+    // Set once the disk is unplugged. The port is stopped, and every command fails from then on.
+    gone: bool,
     // TODO: Add buffer cache
 }
 
+/// Power states of the link between a port and its disk, from the most awake to the least. The
+/// further down, the less power it draws and the longer it takes to wake from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkPower {
+    Active,
+    Partial,
+    Slumber,
+    DevSleep,
+}
+
+impl LinkPower {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "active" => Some(Self::Active),
+            "partial" => Some(Self::Partial),
+            "slumber" => Some(Self::Slumber),
+            "devsleep" => Some(Self::DevSleep),
+            _ => None,
+        }
+    }
+
+    // The interface communication control (PxCMD.ICC) value that asks for this state
+    fn interface_command(self) -> u32 {
+        use PortCommandMasks::*;
+        match self {
+            Self::Active => InterfaceActive as u32,
+            Self::Partial => InterfacePartial as u32,
+            Self::Slumber => InterfaceSlumber as u32,
+            Self::DevSleep => InterfaceDevSleep as u32,
+        }
+    }
+
+    // From the interface power management field of PxSSTS. `None` if there is no link.
+    fn from_sstatus(sstatus: u32) -> Option<Self> {
+        match (sstatus >> 8) & 0xF {
+            1 => Some(Self::Active),
+            2 => Some(Self::Partial),
+            6 => Some(Self::Slumber),
+            8 => Some(Self::DevSleep),
+            _ => None,
+        }
+    }
+}
+
 impl AHCIState {
     /// Create a new object to keep track of AHCI-relevant state
     /// `bus` / `slot` / `func_number`: the relevant PCI bus/slot/function for the AHCI controller
@@ -77,12 +317,13 @@ impl AHCIState {
     /// This should be called only ONCE per drive. Each drive on the AHCI controller has a unique sata port number.
     /// Also, the `regs` argument should ultimately point to valid register memory.
     unsafe fn init(
+        frame_allocator: &mut BootInfoFrameAllocator,
         bus: u32,
         slot: u32,
         func_number: u32,
         sata_port: u32,
         regs: &'static RwLock<&'static mut Registers>,
-    ) -> Box<Self> {
+    ) -> Result<Box<Self>, ()> {
         use PortCommandMasks::*;
 
         let port_reg_ptr = {
@@ -100,9 +341,9 @@ impl AHCIState {
             &mut (*port_reg_ptr)
         };
 
-        let dma: Box<DMAState> = Box::new(unsafe { core::mem::zeroed() });
+        let dma: DmaBox<DMAState> = DmaBox::new_zeroed(frame_allocator)?;
 
-        let mut ahci = Box::new(AHCIState {
+        let mut ahci = KBox::try_new(AHCIState {
             dma,
             bus,
             slot,
@@ -112,11 +353,17 @@ impl AHCIState {
             port_registers: port_reg_ptr,
             irq: 0,
             num_sectors: 0,
+            pm_attached: false,
+            suspended_pci: None,
+            devices: Vec::new(),
             slots_full_mask: 0,
             slots_outstanding_mask: 0,
             num_slots_available: 1,
             num_ncq_slots: 1,
-        });
+            link_power: LinkPower::Active,
+            gone: false,
+        })?
+        .into_box();
 
         unsafe {
             PCI_STATE
@@ -135,12 +382,11 @@ impl AHCIState {
 
         while ahci.port_registers.command_and_status.read() & running_mask != 0 {
             // TODO: Maybe change this to use a wait queue?
-            pause();
+            Arch::pause();
         }
 
-        for (i, ch) in ahci.dma.ch.iter_mut().enumerate() {
-            ch.command_table_address =
-                util::kernel_to_physical_address(addr_of!(ahci.dma.ct[i]) as u64);
+        for i in 0..ahci.dma.ch.len() {
+            ahci.dma.ch[i].command_table_address = ahci.dma.phys_addr_of(addr_of!(ahci.dma.ct[i]));
         }
 
         // Pretty much everything here is unsafe. Look at those pointer derefs!
@@ -150,28 +396,16 @@ impl AHCIState {
             use super::PortCommandMasks::*;
             use super::RStatusMasks::*;
 
-            // println!(
-            //     "Both addresses: {:#x} {:#x}",
-            //     addr_of!(ahci.dma.ch[0]) as u64,
-            //     util::kernel_to_physical_address(addr_of!(ahci.dma.ch[0]) as u64)
-            // );
-
             ahci.port_registers
                 .cmdlist_addr
-                .write(util::kernel_to_physical_address(
-                    addr_of!(ahci.dma.ch[0]) as u64
-                ));
+                .write(ahci.dma.phys_addr_of(addr_of!(ahci.dma.ch[0])));
             ahci.port_registers
                 .rfis_base_addr
-                .write(util::kernel_to_physical_address(
-                    addr_of!(ahci.dma.rfis) as u64
-                ));
+                .write(ahci.dma.phys_addr_of(addr_of!(ahci.dma.rfis)));
 
             ahci.port_registers.serror.write(!0);
-            let command_mask = ahci.port_registers.command_mask.read();
-            ahci.port_registers
-                .command_mask
-                .write(command_mask | (PowerUp as u32));
+            let staggered = ahci.staggered_spin_up();
+            spin_up(ahci.port_registers, staggered);
 
             ahci.port_registers.interrupt_status.write(!0);
 
@@ -180,9 +414,9 @@ impl AHCIState {
                 (*regs_ptr).interrupt_status.write(!0);
             }
 
-            ahci.port_registers
-                .interrupt_enable
-                .write(DeviceToHost as u32 | NCQComplete as u32 | ErrorMask as u32);
+            ahci.port_registers.interrupt_enable.write(
+                DeviceToHost as u32 | NCQComplete as u32 | ErrorMask as u32 | LinkChange as u32,
+            );
 
             ahci.port_registers.command_and_status.write(
                 ahci.port_registers.command_and_status.read() | PortCommandMasks::RFISEnable as u32,
@@ -194,7 +428,28 @@ impl AHCIState {
             while ahci.port_registers.tfd.read() & busy != 0
                 || !sstatus_active(ahci.port_registers.sstatus.read())
             {
-                pause();
+                Arch::pause();
+            }
+
+            // The first D2H FIS from the device has arrived by now, so the signature tells us
+            // whether this is a disk or a port multiplier. PMA may only be changed while the port
+            // is stopped.
+            let capabilities = ahci.drive_registers.read().capabilities.read();
+            let pm_supported = capabilities & CapabilityMasks::PortMultiplier as u32 != 0;
+            ahci.pm_attached = pm_supported && ahci.port_registers.sig.read() == super::SATA_SIG_PM;
+
+            if capabilities & CapabilityMasks::FISBasedSwitching as u32 != 0 {
+                // We only ever talk to one device at a time, which command-based switching
+                // handles fine. FBS would also need a separate RFIS area for every device.
+                ahci.port_registers.fis_switch_control.write(
+                    ahci.port_registers.fis_switch_control.read() & !(FBSMasks::Enable as u32),
+                );
+            }
+
+            if ahci.pm_attached {
+                ahci.port_registers.command_and_status.write(
+                    ahci.port_registers.command_and_status.read() | PortMultiplierAttached as u32,
+                );
             }
 
             ahci.port_registers.command_and_status.write(
@@ -206,7 +461,7 @@ impl AHCIState {
             while ahci.port_registers.command_and_status.read() & InterfaceMask as u32
                 != InterfaceIdle as u32
             {
-                pause();
+                Arch::pause();
             }
 
             // println!("Wait 4");
@@ -215,20 +470,19 @@ impl AHCIState {
                 .command_and_status
                 .write(ahci.port_registers.command_and_status.read() | Start as u32);
 
-            let mut id_buf: [Volatile<u16>; 256] = core::mem::zeroed();
-
-            ahci.dma.ch[slot as usize].num_buffers = 0;
-            ahci.dma.ch[slot as usize].buffer_byte_pos = 0;
-
-            let handle = ahci.push_buffer(0, &mut id_buf);
-            ahci.issue_meta(0, pci::ide_controller::Command::Identify, 0, u32::MAX);
-            ahci.await_basic(0);
-            ahci.clear_slot(handle);
+            if ahci.pm_attached {
+                if ahci.enumerate_port_multiplier().is_err() {
+                    println!(
+                        "AHCI port {}: failed to enumerate port multiplier",
+                        sata_port
+                    );
+                }
+            } else {
+                let device = ahci.identify(0);
+                ahci.devices.push(device);
+            }
 
-            ahci.num_sectors = id_buf[100].read() as usize
-                | ((id_buf[101].read() as usize) << 16)
-                | ((id_buf[102].read() as usize) << 32)
-                | ((id_buf[103].read() as usize) << 48);
+            ahci.num_sectors = ahci.devices.first().map_or(0, |device| device.num_sectors);
             {
                 let drive_regs = ahci.drive_registers.read();
                 // slots per controller
@@ -236,9 +490,11 @@ impl AHCIState {
             }
             // println!("Num ncq slots: {}", ahci.num_ncq_slots);
 
-            if (((id_buf[75].read() & 0x1F) + 1) as u32) < ahci.num_ncq_slots {
-                // slots per disk
-                ahci.num_ncq_slots = ((id_buf[75].read() & 0x1F) + 1) as u32;
+            // slots per disk; every disk on the port shares the same command slots
+            for device in ahci.devices.iter() {
+                if device.queue_depth < ahci.num_ncq_slots {
+                    ahci.num_ncq_slots = device.queue_depth;
+                }
             }
 
             // println!("Num ncq slots: {}", ahci.num_ncq_slots);
@@ -253,15 +509,33 @@ impl AHCIState {
             ahci.num_slots_available = ahci.num_ncq_slots as u16;
 
             // set features
-            ahci.dma.ch[slot as usize].num_buffers = 0;
-            ahci.dma.ch[slot as usize].buffer_byte_pos = 0;
-            ahci.issue_meta(0, pci::ide_controller::Command::SetFeatures, 0x02, u32::MAX); // write cache enable
-            ahci.await_basic(0);
-
-            ahci.dma.ch[slot as usize].num_buffers = 0;
-            ahci.dma.ch[slot as usize].buffer_byte_pos = 0;
-            ahci.issue_meta(0, pci::ide_controller::Command::SetFeatures, 0xAA, u32::MAX); // read lookahead enable
-            ahci.await_basic(0);
+            for i in 0..ahci.devices.len() {
+                let pmp = ahci.devices[i].pmp;
+
+                ahci.dma.ch[0].num_buffers = 0;
+                ahci.dma.ch[0].buffer_byte_pos = 0;
+                ahci.issue_meta(0, ATACommand::SetFeatures, 0x02, u32::MAX, pmp); // write cache enable
+                ahci.await_basic(0);
+
+                ahci.dma.ch[0].num_buffers = 0;
+                ahci.dma.ch[0].buffer_byte_pos = 0;
+                ahci.issue_meta(0, ATACommand::SetFeatures, 0xAA, u32::MAX, pmp); // read lookahead enable
+                ahci.await_basic(0);
+
+                // DevSleep has to be enabled on the disk before the link can be put in it
+                if ahci.devices[i].devsleep && ahci.port_devsleep() {
+                    ahci.dma.ch[0].num_buffers = 0;
+                    ahci.dma.ch[0].buffer_byte_pos = 0;
+                    ahci.issue_meta(
+                        0,
+                        ATACommand::SetFeatures,
+                        ENABLE_SATA_FEATURE,
+                        SATA_FEATURE_DEVSLEEP,
+                        pmp,
+                    );
+                    ahci.await_basic(0);
+                }
+            }
 
             // determine IRQ
 
@@ -272,7 +546,12 @@ impl AHCIState {
                 pci::Register::InterruptLine,
             );
 
-            ahci.irq = intr_line as u32;
+            // 0xFF means the firmware left it unassigned
+            ahci.irq = if intr_line == 0xFF {
+                Irq::Ahci as u32
+            } else {
+                intr_line as u32
+            };
             // println!("AHCI interrupt line is {}", intr_line);
 
             // FIXME: actually register , because this triggers an interrupt
@@ -283,24 +562,237 @@ impl AHCIState {
                 .write(!0);
         }
 
-        ahci
+        Ok(ahci)
     }
 
+    /// Read or write the first disk on this port. Any buffer works: ones too big for a single
+    /// command are split up, and ones the disk can't use directly go through a bounce buffer.
     pub fn read_or_write<'a>(
-        self_lock: &RwLock<&mut Self>,
+        self_lock: &'static RwLock<&'static mut Self>,
         command: Command,
         buf: &'a mut [MaybeUninit<u8>],
         offset: usize,
-    ) -> Result<&'a mut [u8], IOError> {
-        let mut r = IOError::TryAgain as u32;
-        let buf_handle = interrupts::without_interrupts(|| {
-            let mut lock_guard = self_lock.write();
-            (*lock_guard).port_registers.interrupt_status.write(!0);
-            let buf_handle = (*lock_guard).push_buffer(0, buf);
-            unsafe { SLOT_STATUS[0] = addr_of_mut!(r) };
-            (*lock_guard).issue_ncq(0, command, offset / (SECTOR_SIZE as usize), true, 0);
-            buf_handle
-        });
+    ) -> Result<&'a mut [u8], CommandError> {
+        let pmp = self_lock
+            .read()
+            .devices
+            .first()
+            .map_or(0, |device| device.pmp);
+        Self::read_or_write_pmp(self_lock, pmp, command, buf, offset)
+    }
+
+    /// Read or write the disk at port multiplier port `pmp` (0 for a directly attached disk).
+    pub fn read_or_write_pmp<'a>(
+        self_lock: &'static RwLock<&'static mut Self>,
+        pmp: u8,
+        command: Command,
+        buf: &'a mut [MaybeUninit<u8>],
+        offset: usize,
+    ) -> Result<&'a mut [u8], CommandError> {
+        unsafe {
+            Self::transfer(
+                self_lock,
+                pmp,
+                command,
+                buf.as_mut_ptr() as *const u8,
+                buf.len(),
+                offset,
+            )?
+        };
+
+        let buf_ref = unsafe { MaybeUninit::slice_assume_init_mut(buf) };
+
+        Ok(buf_ref)
+    }
+
+    /// Write `buf` to the disk at port multiplier port `pmp`.
+    pub fn write_pmp(
+        self_lock: &'static RwLock<&'static mut Self>,
+        pmp: u8,
+        buf: &[u8],
+        offset: usize,
+    ) -> Result<(), CommandError> {
+        // The disk only ever reads from the buffer for a write, so it doesn't need to be mutable
+        unsafe {
+            Self::transfer(
+                self_lock,
+                pmp,
+                Command::Write,
+                buf.as_ptr(),
+                buf.len(),
+                offset,
+            )
+        }
+    }
+
+    /// Write `buf` to the disk at port multiplier port `pmp` by polling, without relying on
+    /// interrupts or the scheduler, for when the kernel is going down (e.g. a crash dump). Gives up
+    /// after a while instead of hanging if the disk never answers.
+    /// ### Safety
+    /// `buf` must be physically contiguous, and nothing else may use the port until this returns:
+    /// interrupts should be off. Commands that were already in flight are left alone.
+    pub unsafe fn write_polled(
+        &mut self,
+        pmp: u8,
+        buf: &[u8],
+        offset: usize,
+    ) -> Result<(), IOError> {
+        if self.gone {
+            return Err(IOError::DeviceGone);
+        }
+        let slot = self.free_slot().ok_or(IOError::TryAgain)?;
+
+        self.port_registers.interrupt_status.write(!0);
+        self.clear_raw(slot);
+        self.push_raw(slot, buf.as_ptr(), buf.len());
+        let sector = offset / self.sector_size(pmp) as usize;
+        self.issue_ncq(slot, Command::Write, sector, true, 0, pmp);
+
+        let mut result = Err(IOError::TryAgain);
+        for _ in 0..POLLED_TIMEOUT {
+            let interrupt_status = self.port_registers.interrupt_status.read();
+            if interrupt_status & InterruptMasks::FatalErrorMask as u32 != 0 {
+                result = Err(IOError::BadData);
+                break;
+            }
+            if self.port_registers.ncq_active.read() & (1 << slot) == 0 {
+                result = Ok(());
+                break;
+            }
+            Arch::pause();
+        }
+
+        // Even if the disk never finished, there's nobody left to wait for it
+        self.port_registers.interrupt_status.write(!0);
+        self.acknowledge(slot, 0);
+        self.clear_raw(slot);
+
+        result
+    }
+
+    /// How many commands can be in flight on this port at once, shared by every disk on it.
+    pub fn queue_depth(&self) -> usize {
+        self.num_ncq_slots.min(MAX_SLOTS) as usize
+    }
+
+    // A slot with no command in flight, if there is one
+    fn free_slot(&self) -> Option<u32> {
+        (0..self.num_ncq_slots.min(MAX_SLOTS))
+            .find(|slot| self.slots_outstanding_mask & (1 << slot) == 0)
+    }
+
+    /// The `index`th disk on this port, for callers that need to bypass the block device
+    /// registry (e.g. to use `write_polled`).
+    pub fn device(
+        self_lock: &'static RwLock<&'static mut Self>,
+        index: usize,
+    ) -> Option<SataDevice> {
+        let state = self_lock.read();
+        let device = state.devices.get(index)?;
+
+        Some(SataDevice {
+            port: self_lock,
+            pmp: device.pmp,
+            num_sectors: device.num_sectors,
+            sector_size: device.sector_size,
+        })
+    }
+
+    /// ### Safety
+    /// `len` bytes at `addr` must stay valid until this returns, and for a read, nothing else may
+    /// access them in the meantime.
+    unsafe fn transfer(
+        self_lock: &'static RwLock<&'static mut Self>,
+        pmp: u8,
+        command: Command,
+        addr: *const u8,
+        len: usize,
+        offset: usize,
+    ) -> Result<(), CommandError> {
+        let sector_size = self_lock.read().sector_size(pmp) as usize;
+        let mut done = 0;
+
+        // Split up whatever doesn't fit in one command's PRDT, and go through a bounce buffer for
+        // memory the disk can't use directly
+        while done < len {
+            let chunk_addr = addr.add(done);
+            let remaining = len - done;
+
+            let chunk = dma_chunk_len(chunk_addr, remaining, sector_size);
+            if chunk > 0 {
+                Self::transfer_chunk(self_lock, pmp, command, chunk_addr, chunk, offset + done)?;
+                done += chunk;
+                continue;
+            }
+
+            let chunk = remaining.min(BOUNCE_SIZE / sector_size * sector_size);
+            if chunk == 0 {
+                return Err(CommandError::Invalid);
+            }
+
+            let mut bounce = BounceBuffer::new(chunk).ok_or(CommandError::TryAgain)?;
+            if let Command::Write = command {
+                bounce.copy_from_slice(core::slice::from_raw_parts(chunk_addr, chunk));
+            }
+
+            Self::transfer_chunk(
+                self_lock,
+                pmp,
+                command,
+                bounce.as_ptr(),
+                chunk,
+                offset + done,
+            )?;
+
+            if let Command::Read = command {
+                core::ptr::copy_nonoverlapping(bounce.as_ptr(), chunk_addr as *mut u8, chunk);
+            }
+            done += chunk;
+        }
+
+        Ok(())
+    }
+
+    // Transfer with a single command. The buffer has to fit in the PRDT, see `dma_chunk_len`.
+    unsafe fn transfer_chunk(
+        self_lock: &'static RwLock<&'static mut Self>,
+        pmp: u8,
+        command: Command,
+        addr: *const u8,
+        len: usize,
+        offset: usize,
+    ) -> Result<(), CommandError> {
+        let mut r = SLOT_PENDING;
+
+        // Every command gets a slot of its own, so that as many can be in flight as the disk has
+        // NCQ slots for. If they are all taken, wait for one to free up.
+        let slot = loop {
+            let issued = interrupts::without_interrupts(|| {
+                let mut lock_guard = self_lock.write();
+                if (*lock_guard).gone {
+                    return Err(CommandError::DeviceGone);
+                }
+                let Some(slot) = (*lock_guard).free_slot() else {
+                    return Ok(None);
+                };
+
+                // Clearing it with commands in flight could lose their completions
+                if (*lock_guard).slots_outstanding_mask == 0 {
+                    (*lock_guard).port_registers.interrupt_status.write(!0);
+                }
+                (*lock_guard).clear_raw(slot);
+                (*lock_guard).push_raw(slot, addr, len);
+                unsafe { SLOT_STATUS[slot as usize] = addr_of_mut!(r) };
+                let sector = offset / (*lock_guard).sector_size(pmp) as usize;
+                (*lock_guard).issue_ncq(slot, command, sector, true, 0, pmp);
+                Ok(Some(slot))
+            })?;
+
+            if let Some(slot) = issued {
+                break slot;
+            }
+            Arch::pause();
+        };
 
         // println!(
         //     "HBA control: {:#x}",
@@ -318,20 +810,29 @@ impl AHCIState {
 
         let io_ptr = addr_of_mut!(r);
 
+        // The status's address only goes along as a number, to be compared
+        let status_addr = io_ptr as usize;
+        let timeout = timer::after(COMMAND_TIMEOUT, move || {
+            interrupts::without_interrupts(|| unsafe {
+                self_lock.write().time_out(slot, status_addr as *mut u32)
+            });
+        });
+
         // TODO: Replace with wait queues instead of spinning
+        let polled = POLLED.load(Ordering::Relaxed);
         unsafe {
-            while io_ptr.read_volatile() == IOError::TryAgain as u32 {
-                core::arch::x86_64::_mm_pause();
+            while io_ptr.read_volatile() == SLOT_PENDING {
+                if polled {
+                    interrupts::without_interrupts(|| self_lock.write().handle_interrupt());
+                }
+                Arch::pause();
             }
         }
+        timeout.cancel();
 
-        let mut lock_guard = self_lock.write();
-        unsafe { SLOT_STATUS[0] = core::ptr::null_mut() };
-        (*lock_guard).clear_slot(buf_handle);
-
-        let buf_ref = unsafe { MaybeUninit::slice_assume_init_mut(buf) };
-
-        Ok(buf_ref)
+        // The interrupt handler freed the slot when it wrote the result, so it may already be
+        // another command's and is left alone
+        CommandError::from_slot(unsafe { io_ptr.read_volatile() })
     }
 
     pub unsafe fn enable_interrupts(&mut self) {
@@ -350,6 +851,241 @@ impl AHCIState {
             .write(global_hba_control & !(GHCMasks::InterruptEnable as u32));
     }
 
+    /// Leave the controller's interrupts off from now on, including after `resume`, and have
+    /// whoever waits on a command call `handle_interrupt` until it's done. Anything else the
+    /// handler notices, like the disk going away, waits until something calls it, see
+    /// polling.rs.
+    pub unsafe fn use_polling(&mut self) {
+        POLLED.store(true, Ordering::SeqCst);
+        self.disable_interrupts();
+    }
+
+    /// Stop the port, as the spec asks for before the HBA is turned off: clear ST and wait for CR
+    /// to clear, so that no more commands are processed, then clear FRE and wait for FR, so that no
+    /// more FISes are received. Its interrupts are turned off first. Fails if either half doesn't
+    /// stop in time.
+    pub unsafe fn stop(&mut self) -> Result<(), ()> {
+        use super::PortCommandMasks::*;
+
+        self.disable_interrupts();
+        for (enable, running) in [(Start, CommandRunning), (RFISEnable, RFISRunning)] {
+            let command = &mut self.port_registers.command_and_status;
+            command.write(command.read() & !(enable as u32));
+
+            let mut stopped = false;
+            for _ in 0..PORT_TIMEOUT {
+                if command.read() & running as u32 == 0 {
+                    stopped = true;
+                    break;
+                }
+                Arch::pause();
+            }
+            if !stopped {
+                return Err(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop the port before the machine sleeps, saving the parts of the controller's PCI
+    /// configuration that sleeping loses. See `stop`.
+    pub unsafe fn suspend(&mut self) -> Result<(), ()> {
+        let (bus, slot, func) = (self.bus, self.slot, self.func);
+        self.suspended_pci = {
+            let pci = PCI_STATE.lock();
+            Some((
+                pci.config_read_32(bus, slot, func, pci::Register::GDBaseAddress5),
+                pci.config_read_16(bus, slot, func, pci::Register::Command),
+            ))
+        };
+        self.stop()
+    }
+
+    /// Start the port again after the machine woke up, which reset the controller: put back
+    /// what `suspend` saved, redo what `init` set up on the controller and the port, and turn its
+    /// interrupts on, unless it's polled. What was sent to the disks themselves, like the features
+    /// `init` sets, isn't redone. Fails if the disk doesn't come back in time.
+    pub unsafe fn resume(&mut self) -> Result<(), ()> {
+        use super::InterruptMasks::*;
+        use super::PortCommandMasks::*;
+        use super::RStatusMasks::*;
+
+        let (bar, command) = self.suspended_pci.take().ok_or(())?;
+        {
+            let mut pci = PCI_STATE.lock();
+            let (bus, slot, func) = (self.bus, self.slot, self.func);
+            pci.config_write(bus, slot, func, pci::Register::GDBaseAddress5, bar);
+            pci.config_write(bus, slot, func, pci::Register::Command, command);
+        }
+
+        {
+            let mut drive_lock = self.drive_registers.write();
+            (*drive_lock)
+                .global_hba_control
+                .write(GHCMasks::AHCIEnable as u32);
+            (*drive_lock).interrupt_status.write(!0);
+        }
+
+        self.port_registers
+            .cmdlist_addr
+            .write(self.dma.phys_addr_of(addr_of!(self.dma.ch[0])));
+        self.port_registers
+            .rfis_base_addr
+            .write(self.dma.phys_addr_of(addr_of!(self.dma.rfis)));
+        self.port_registers.serror.write(!0);
+        self.port_registers.interrupt_status.write(!0);
+        self.port_registers
+            .interrupt_enable
+            .write(DeviceToHost as u32 | NCQComplete as u32 | ErrorMask as u32 | LinkChange as u32);
+
+        spin_up(self.port_registers, self.staggered_spin_up());
+        // Sleeping took the link down with everything else
+        self.link_power = LinkPower::Active;
+
+        let mut command = RFISEnable as u32;
+        if self.pm_attached {
+            command |= PortMultiplierAttached as u32;
+        }
+        self.port_registers
+            .command_and_status
+            .write(self.port_registers.command_and_status.read() | command);
+
+        let busy = Busy as u32 | DataReq as u32;
+        let mut ready = false;
+        for _ in 0..PORT_TIMEOUT {
+            if self.port_registers.tfd.read() & busy == 0
+                && sstatus_active(self.port_registers.sstatus.read())
+            {
+                ready = true;
+                break;
+            }
+            Arch::pause();
+        }
+        if !ready {
+            return Err(());
+        }
+
+        self.port_registers
+            .command_and_status
+            .write(self.port_registers.command_and_status.read() | Start as u32);
+        if !POLLED.load(Ordering::SeqCst) {
+            self.enable_interrupts();
+        }
+        Ok(())
+    }
+
+    // Whether the HBA leaves each port's disk spun down until it's told to spin it up
+    fn staggered_spin_up(&self) -> bool {
+        self.drive_registers.read().capabilities.read() & CapabilityMasks::StaggeredSpinUp as u32
+            != 0
+    }
+
+    // Whether both the HBA and the port can put the link in DevSleep
+    fn port_devsleep(&self) -> bool {
+        self.drive_registers.read().cap2.read() & Capability2Masks::DevSleep as u32 != 0
+            && self.port_registers.device_sleep.read() & DevSleepMasks::Present as u32 != 0
+    }
+
+    /// Whether the link can be put in `state`. Partial and slumber are up to the HBA. DevSleep
+    /// also needs the port to be wired for it and every disk on it to support it, and a port
+    /// multiplier can't pass it on.
+    pub fn supports_link_power(&self, state: LinkPower) -> bool {
+        let capabilities = self.drive_registers.read().capabilities.read();
+        match state {
+            LinkPower::Active => true,
+            LinkPower::Partial => capabilities & CapabilityMasks::PartialCapable as u32 != 0,
+            LinkPower::Slumber => capabilities & CapabilityMasks::SlumberCapable as u32 != 0,
+            LinkPower::DevSleep => {
+                !self.pm_attached
+                    && self.port_devsleep()
+                    && self.devices.iter().all(|device| device.devsleep)
+            }
+        }
+    }
+
+    /// The state the link is in right now, or `None` if there is no link.
+    pub fn link_power(&self) -> Option<LinkPower> {
+        LinkPower::from_sstatus(self.port_registers.sstatus.read())
+    }
+
+    /// Put the link in `state`. Only an idle port can leave the active state; the next command
+    /// issued wakes the link up again. Fails if the link can't go into `state`, there are
+    /// commands in flight, or the link doesn't get there in time.
+    ///
+    /// ### Safety
+    /// The port has to be started.
+    pub unsafe fn set_link_power(&mut self, state: LinkPower) -> Result<(), ()> {
+        if !self.supports_link_power(state) {
+            return Err(());
+        }
+        if state != LinkPower::Active && self.slots_outstanding_mask != 0 {
+            return Err(());
+        }
+
+        // DevSleep is only entered from slumber
+        if state == LinkPower::DevSleep && self.link_power() != Some(LinkPower::Slumber) {
+            self.request_link_power(LinkPower::Slumber)?;
+        }
+        self.request_link_power(state)
+    }
+
+    // Ask the HBA for `state` and wait for the link to get there. The HBA ignores a new request
+    // until it has taken up the last one, which it shows by clearing ICC.
+    fn request_link_power(&mut self, state: LinkPower) -> Result<(), ()> {
+        use PortCommandMasks::*;
+
+        let command = &mut self.port_registers.command_and_status;
+        if !wait_for(|| command.read() & InterfaceMask as u32 == InterfaceIdle as u32) {
+            return Err(());
+        }
+        command.write((command.read() & !(InterfaceMask as u32)) | state.interface_command());
+
+        let sstatus = &self.port_registers.sstatus;
+        if !wait_for(|| LinkPower::from_sstatus(sstatus.read()) == Some(state)) {
+            return Err(());
+        }
+        self.link_power = state;
+        Ok(())
+    }
+
+    // Bring the link back to active before a command goes out. The HBA would wake it from
+    // partial or slumber by itself, but not from DevSleep.
+    fn wake_link(&mut self) {
+        if self.link_power != LinkPower::Active
+            && self.request_link_power(LinkPower::Active).is_err()
+        {
+            log_warn!("AHCI port {}: the link didn't wake up", self.sata_port);
+        }
+    }
+
+    /// Start the task that takes a disk's block devices away when it's unplugged. Has to be called
+    /// after `task::init`.
+    pub fn start_unplug_watch() {
+        let watch = task::spawn("sata-unplug", Priority::Normal, || {
+            UNPLUG.wait_while(|| !UNPLUGGED.load(Ordering::SeqCst));
+            let Some(port) = SATA_DISK0.get() else {
+                return;
+            };
+
+            let names: Vec<String> = interrupts::without_interrupts(|| {
+                let count = port.read().devices.len();
+                (0..count)
+                    .filter_map(|index| Self::device(port, index))
+                    .map(|device| device.name())
+                    .collect()
+            });
+            log_info!("SATA port unplugged, removing {:?}", names);
+            for name in names {
+                // Nothing to do for one that isn't registered
+                let _ = block::remove(&name);
+            }
+        });
+
+        if watch.is_err() {
+            log_warn!("Couldn't start the SATA unplug task, unplugged disks won't go away");
+        }
+    }
+
     pub unsafe fn new(
         frame_allocator: &mut BootInfoFrameAllocator,
         bus: u32,
@@ -377,26 +1113,11 @@ impl AHCIState {
 
             // println!("going through: {bus}, {slot}, {func}");
 
-            let drive_regs_page: Page<Size4KiB> =
-                Page::containing_address(VirtAddr::new(phys_addr));
-            /*let port_regs_page: Page<Size4KiB> =
-            Page::containing_address(VirtAddr::new(phys_addr + PAGESIZE));*/
-            // FIXME XXX: Need to make sure we don't overlap with allocated frames in the BootInfoFrameAllocator.
-            let drive_frame = PhysFrame::containing_address(PhysAddr::new(phys_addr));
-            // let port_frame = PhysFrame::containing_address(PhysAddr::new(phys_addr));
-
-            let flags =
-                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
-            unsafe {
-                (*KERNEL_PAGETABLE.get().unwrap().write())
-                    .map_to(drive_regs_page, drive_frame, flags, frame_allocator)
-                    .expect("Failed to map AHCI address in pagetable!")
-                    .flush();
-                /*mapper
-                .map_to(drive_regs_page, port_frame, flags, frame_allocator)
-                .expect("Failed to map AHCI address in pagetable!")
-                .flush()*/
-            };
+            power::power_up(bus, slot, func)?;
+
+            // The generic host control registers, then those of all 32 possible ports
+            util::map_mmio(frame_allocator, phys_addr, ABAR_SIZE, "ahci")
+                .expect("Failed to map AHCI address in pagetable!");
 
             // FIXME: This isn't quite ready for multi-drive support. Needs to *ensure* that the
             // same slot is not used twice.
@@ -428,9 +1149,20 @@ impl AHCIState {
                     .add(core::mem::size_of::<Registers>())
                     .add(core::mem::size_of::<PortRegisters>() * ahci_port as usize)
                     as *mut PortRegisters;
-                if (*drive_regs_ptr).port_mask.read() & (1u32 << ahci_port) != 0
-                    && (*port_reg_ptr).sstatus.read() != 0
-                {
+                if (*drive_regs_ptr).port_mask.read() & (1u32 << ahci_port) == 0 {
+                    continue;
+                }
+
+                // With staggered spin-up, a disk only shows up once its port is spun up. Ports
+                // are spun up one at a time, as far as the first disk.
+                let staggered = (*drive_regs_ptr).capabilities.read()
+                    & CapabilityMasks::StaggeredSpinUp as u32
+                    != 0;
+                if staggered {
+                    spin_up(&mut *port_reg_ptr, true);
+                }
+
+                if (*port_reg_ptr).sstatus.read() != 0 {
                     match DRIVE_REGISTER.set(RwLock::new(&mut *drive_regs_ptr)) {
                         Err(_) => {
                             // TODO: This slot has been claimed. Assume *for now* this is the same
@@ -440,9 +1172,18 @@ impl AHCIState {
                         Ok(()) => {
                             // println!("Found one: {ahci_port}");
                             let lock_ref = DRIVE_REGISTER.get().unwrap();
-                            let ahci_state =
-                                unsafe { AHCIState::init(bus, slot, func, ahci_port, lock_ref) };
+                            let ahci_state = unsafe {
+                                AHCIState::init(
+                                    frame_allocator,
+                                    bus,
+                                    slot,
+                                    func,
+                                    ahci_port,
+                                    lock_ref,
+                                )
+                            }?;
                             let _ = SATA_DISK0.set(RwLock::new(Box::<AHCIState>::leak(ahci_state)));
+                            register_devices(SATA_DISK0.get().unwrap());
                             return Ok(());
                         }
                     }
@@ -459,25 +1200,49 @@ impl AHCIState {
     // Must preceed call with clear_slot(slot) and push_buffer(slot).
     // `fua`: If true, then don't acknowledge the write until data has been durably
     // written to disk. `priority`: 0 is normal priority, 2 is high priority
-    fn issue_ncq(&mut self, slot: u32, command: Command, sector: usize, fua: bool, priority: u32) {
-        let nsectors = self.dma.ch[slot as usize].buffer_byte_pos / SECTOR_SIZE;
-        // println!(
-        //     "Sending CFIS {:#x}-{:#x}-{:#x}",
-        //     CFIS_COMMAND | ((command as u32) << 16) | ((nsectors & 0xFF) << 24),
-        //     (sector as u32 & 0xFFFFFF) | (u32::from(fua) << 31) | 0x40000000,
-        //     ((sector >> 24) as u32) | ((nsectors & 0xFF00) << 16)
-        // );
-        self.dma.ct[slot as usize].cfis[0] =
-            CFIS_COMMAND | ((command as u32) << 16) | ((nsectors & 0xFF) << 24);
-        self.dma.ct[slot as usize].cfis[1] =
-            (sector as u32 & 0xFFFFFF) | (u32::from(fua) << 31) | 0x40000000;
-        self.dma.ct[slot as usize].cfis[2] = ((sector >> 24) as u32) | ((nsectors & 0xFF00) << 16);
-        self.dma.ct[slot as usize].cfis[3] = (slot << 3) | (priority << 14);
+    // `pmp`: the port multiplier port of the disk, or 0 if there is no port multiplier
+    fn issue_ncq(
+        &mut self,
+        slot: u32,
+        command: Command,
+        sector: usize,
+        fua: bool,
+        priority: u32,
+        pmp: u8,
+    ) {
+        let nsectors = self.dma.ch[slot as usize].buffer_byte_pos / self.sector_size(pmp);
+        crate::trace!(
+            Ahci,
+            "ncq {:#x} slot {} pmp {} sector {} count {} prds {}",
+            command as u32,
+            slot,
+            pmp,
+            sector,
+            nsectors,
+            self.dma.ch[slot as usize].num_buffers
+        );
+
+        // For NCQ, the sector count goes in the features field, and the count field holds the tag
+        // and priority instead
+        self.write_cfis(
+            slot,
+            RegisterFis {
+                command: command as u8,
+                pmp,
+                features: nsectors as u16,
+                lba: sector as u64,
+                device: NCQ_DEVICE_LBA | if fua { NCQ_DEVICE_FUA } else { 0 },
+                count: ((slot << 3) | (priority << 14)) as u16,
+                ..Default::default()
+            },
+        );
 
         self.dma.ch[slot as usize].flags = 4 /* # words in `cfis` */
             | (CHFlag::Clear as u16)
+            | ((pmp as u16) << 12)
             | (if let Command::Write = command { CHFlag::Write as u16 } else { 0 });
         self.dma.ch[slot as usize].buffer_byte_pos = 0;
+        self.wake_link();
 
         // ensure all previous writes have made it out to memory
         // IMPORTANT: Add this back in when we have multicore and have implemented
@@ -492,17 +1257,16 @@ impl AHCIState {
         self.num_slots_available -= 1;
     }
 
-    fn push_buffer<'b, T: Sized>(&mut self, slot: u32, buf: &'b mut [T]) -> BufferHandle<'b, T> {
-        let phys_addr = util::kernel_to_physical_address(buf.as_mut_ptr() as u64);
-
-        let num_buffers = self.dma.ch[slot as usize].num_buffers;
-        let size = (buf.len() * core::mem::size_of::<T>() - 1) as u32;
-
-        self.dma.ct[slot as usize].prdt[num_buffers as usize].address = phys_addr;
-        self.dma.ct[slot as usize].prdt[num_buffers as usize].data_byte_count = size - 1;
+    fn write_cfis(&mut self, slot: u32, fis: RegisterFis) {
+        self.dma.ct[slot as usize].cfis[..4].copy_from_slice(&fis.to_dwords());
+    }
 
-        self.dma.ch[slot as usize].num_buffers = num_buffers + 1;
-        self.dma.ch[slot as usize].buffer_byte_pos += size;
+    fn push_buffer<'b, T: Sized>(&mut self, slot: u32, buf: &'b mut [T]) -> BufferHandle<'b, T> {
+        self.push_raw(
+            slot,
+            buf.as_mut_ptr() as *const u8,
+            core::mem::size_of_val(buf),
+        );
 
         BufferHandle {
             slot,
@@ -510,54 +1274,161 @@ impl AHCIState {
         }
     }
 
+    // Add `len` bytes at `addr` to the PRDT of `slot`, one PRD for every physically contiguous
+    // piece of it. Prefer `push_buffer`, which ties the buffer's lifetime to the slot.
+    fn push_raw(&mut self, slot: u32, addr: *const u8, len: usize) {
+        for (phys_addr, size) in dma_segments(addr, len) {
+            let num_buffers = self.dma.ch[slot as usize].num_buffers;
+
+            // The byte count in a PRD is one less than the real size
+            self.dma.ct[slot as usize].prdt[num_buffers as usize].address = phys_addr;
+            self.dma.ct[slot as usize].prdt[num_buffers as usize].data_byte_count = size as u32 - 1;
+
+            self.dma.ch[slot as usize].num_buffers = num_buffers + 1;
+            self.dma.ch[slot as usize].buffer_byte_pos += size as u32;
+        }
+    }
+
     pub fn handle_interrupt(&mut self) {
         unsafe {
-            // use super::RStatusMasks;
-            // let is_error = ((*self.port_registers).interrupt_status
-            //     & InterruptMasks::FatalErrorMask as u32
-            //     != 0)
-            //     || ((*self.port_registers).tfd & RStatusMasks::Error as u32 != 0);
-            let mut drive_registers = self.drive_registers.write();
-            self.port_registers.interrupt_status.write(!0);
-            (*drive_registers).interrupt_status.write(!0);
-            let mut acks =
-                self.slots_outstanding_mask & !((*self.port_registers).ncq_active.read() as u16);
-            let mut slot = 0;
-            while acks != 0 {
-                if acks & 1 != 0 {
+            // Only clear what is handled here; anything raised since then interrupts again
+            let status = self.port_registers.interrupt_status.read();
+            self.port_registers.interrupt_status.write(status);
+            {
+                let mut drive_registers = self.drive_registers.write();
+                (*drive_registers)
+                    .interrupt_status
+                    .write(1 << self.sata_port);
+            }
+
+            for bit in 0..32 {
+                if status & (1 << bit) != 0 {
+                    CAUSE_COUNTS[bit].fetch_add(1, Ordering::Relaxed);
+                }
+            }
+
+            // The controller clears a slot's PxSACT bit once a Set Device Bits FIS says the
+            // command finished, and PxCI once it's done with one that isn't queued. Polling calls
+            // this whether anything finished or not, so neither can be taken for granted.
+            let active = (self.port_registers.ncq_active.read()
+                | self.port_registers.command_mask.read()) as u16;
+            let done = self.slots_outstanding_mask & !active;
+            crate::trace!(
+                Ahci,
+                "interrupt status {:#x} outstanding {:#x} done {:#x}",
+                status,
+                self.slots_outstanding_mask,
+                done
+            );
+
+            let unplugged =
+                status & InterruptMasks::LinkChange as u32 != 0 && self.check_unplugged();
+
+            let (failed, failure) = if unplugged {
+                // Whatever was in flight isn't coming back, finished or not
+                log_warn!(
+                    "AHCI port {}: the disk is gone, failing slots {:#x}",
+                    self.sata_port,
+                    self.slots_outstanding_mask
+                );
+                (self.slots_outstanding_mask, SLOT_DEVICE_GONE)
+            } else if status & TASK_FILE_ERROR != 0 {
+                let (ata_status, ata_error) = self.received_error();
+                // With NCQ the device doesn't say which command failed, only that the ones it
+                // hadn't finished won't be. The port stops until it is restarted, which isn't
+                // done yet, so their callers get the error rather than waiting forever.
+                let failed = self.slots_outstanding_mask & active;
+                log_warn!(
+                    "AHCI: task file error, status {:#x} error {:#x}, failing slots {:#x}",
+                    ata_status,
+                    ata_error,
+                    failed
+                );
+                (failed, CommandError::to_slot(ata_status, ata_error))
+            } else {
+                (0, 0)
+            };
+
+            for slot in 0..MAX_SLOTS {
+                if failed & (1 << slot) != 0 {
+                    self.acknowledge(slot, failure);
+                } else if done & (1 << slot) != 0 {
                     self.acknowledge(slot, 0);
                 }
-                acks >>= 1;
-                slot += 1;
             }
         }
     }
 
-    fn issue_meta(
-        &mut self,
-        slot: u32,
-        command: pci::ide_controller::Command,
-        features: u32,
-        count: u32,
-    ) {
-        use pci::ide_controller::Command::*;
+    // After a link change: whether the disk has just gone. If it has, the port is stopped, so that
+    // the HBA gives up on it, and the unplug task is woken to take its devices away. Only the
+    // first time counts.
+    fn check_unplugged(&mut self) -> bool {
+        // PCS and PRCS only clear along with the SError bits behind them
+        self.port_registers.serror.write(!0);
+
+        // A link going into a low power state changes PhyRdy too
+        if self.gone || sstatus_active(self.port_registers.sstatus.read()) {
+            return false;
+        }
+        self.gone = true;
+
+        let command = &mut self.port_registers.command_and_status;
+        command.write(command.read() & !(PortCommandMasks::Start as u32));
+
+        UNPLUGGED.store(true, Ordering::SeqCst);
+        UNPLUG.wake_one();
+        true
+    }
+
+    // The status and error registers the device reported with its error. NCQ commands report
+    // theirs in a Set Device Bits FIS, and anything else in a D2H register FIS, so whichever one
+    // has the error bit set is the one that goes with it.
+    fn received_error(&self) -> (u8, u8) {
+        let sdb = self.dma.rfis.rfis[RFIS_SDB_OFFSET].read();
+        let d2h = self.dma.rfis.rfis[RFIS_D2H_OFFSET].read();
+        let error_bit = (ata::Status::Error as u32) << 16;
+        let fis = if sdb & error_bit != 0 || d2h & error_bit == 0 {
+            sdb
+        } else {
+            d2h
+        };
+
+        ((fis >> 16) as u8, (fis >> 24) as u8)
+    }
+
+    fn issue_meta(&mut self, slot: u32, command: ATACommand, features: u32, count: u32, pmp: u8) {
+        use ATACommand::*;
 
-        let mut num_sectors = self.dma.ch[slot as usize].buffer_byte_pos / SECTOR_SIZE;
+        let mut num_sectors = self.dma.ch[slot as usize].buffer_byte_pos / ata::SECTOR_SIZE;
 
         if let SetFeatures = command {
             if count != u32::MAX {
                 num_sectors = count;
             }
         }
+        crate::trace!(
+            Ahci,
+            "command {:#x} slot {} pmp {} features {:#x} count {}",
+            command as u8,
+            slot,
+            pmp,
+            features,
+            num_sectors
+        );
 
-        self.dma.ct[slot as usize].cfis[0] =
-            CFIS_COMMAND | ((command as u32) << 16) | (features << 24);
-        self.dma.ct[slot as usize].cfis[1] = 0;
-        self.dma.ct[slot as usize].cfis[2] = ((features as u32) & 0xFF00) << 16;
-        self.dma.ct[slot as usize].cfis[3] = num_sectors;
-
-        self.dma.ch[slot as usize].flags = 4 | (CHFlag::Clear as u16);
+        self.write_cfis(
+            slot,
+            RegisterFis {
+                pmp,
+                features: features as u16,
+                count: num_sectors as u16,
+                ..RegisterFis::new(command)
+            },
+        );
+
+        self.dma.ch[slot as usize].flags = 4 | (CHFlag::Clear as u16) | ((pmp as u16) << 12);
         self.dma.ch[slot as usize].buffer_byte_pos = 0;
+        self.wake_link();
 
         // IMPORTANT: Uncomment once multicore and atomic are done
         core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
@@ -569,19 +1440,178 @@ impl AHCIState {
         self.num_slots_available -= 1;
     }
 
+    // Issue a READ/WRITE PORT MULTIPLIER command, accessing register `register` of device port
+    // `port` (or a global register, if `port` is the control port). The register value goes in
+    // the count and LBA fields, low byte first.
+    fn issue_pm_register(
+        &mut self,
+        slot: u32,
+        command: ATACommand,
+        port: u8,
+        register: u32,
+        value: u32,
+    ) {
+        self.write_cfis(
+            slot,
+            RegisterFis {
+                pmp: PM_CONTROL_PORT,
+                features: register as u16,
+                lba: (value >> 8) as u64,
+                device: port & 0xF,
+                count: (value & 0xFF) as u16,
+                ..RegisterFis::new(command)
+            },
+        );
+
+        self.dma.ch[slot as usize].flags =
+            4 | (CHFlag::Clear as u16) | ((PM_CONTROL_PORT as u16) << 12);
+        self.dma.ch[slot as usize].num_buffers = 0;
+        self.dma.ch[slot as usize].buffer_byte_pos = 0;
+
+        core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
+
+        self.port_registers.command_mask.write(1 << slot);
+
+        self.slots_outstanding_mask |= 1 << slot;
+        self.num_slots_available -= 1;
+    }
+
+    unsafe fn read_pm_register(&mut self, port: u8, register: u32) -> Result<u32, ()> {
+        self.issue_pm_register(0, ATACommand::ReadPortMultiplier, port, register, 0);
+        self.await_basic(0);
+
+        if self.port_registers.tfd.read() & super::RStatusMasks::Error as u32 != 0 {
+            return Err(());
+        }
+
+        // The value comes back in the same fields of the D2H register FIS
+        let d2h = &self.dma.rfis.rfis[RFIS_D2H_OFFSET..];
+        Ok((d2h[3].read() & 0xFF) | ((d2h[1].read() & 0xFFFFFF) << 8))
+    }
+
+    unsafe fn write_pm_register(&mut self, port: u8, register: u32, value: u32) -> Result<(), ()> {
+        self.issue_pm_register(0, ATACommand::WritePortMultiplier, port, register, value);
+        self.await_basic(0);
+
+        if self.port_registers.tfd.read() & super::RStatusMasks::Error as u32 != 0 {
+            return Err(());
+        }
+
+        Ok(())
+    }
+
+    // Logical sector size of the disk at port multiplier port `pmp`, which all data transfers to
+    // it are counted in.
+    fn sector_size(&self, pmp: u8) -> u32 {
+        self.devices
+            .iter()
+            .find(|device| device.pmp == pmp)
+            .map_or(ata::SECTOR_SIZE, |device| device.sector_size)
+    }
+
+    // Send IDENTIFY DEVICE to the disk at port multiplier port `pmp`.
+    unsafe fn identify(&mut self, pmp: u8) -> PortDevice {
+        let mut id_buf: [ReadOnly<u16>; 256] = core::mem::zeroed();
+
+        self.dma.ch[0].num_buffers = 0;
+        self.dma.ch[0].buffer_byte_pos = 0;
+
+        let handle = self.push_buffer(0, &mut id_buf);
+        self.issue_meta(0, ATACommand::Identify, 0, u32::MAX, pmp);
+        self.await_basic(0);
+        self.clear_slot(handle);
+
+        let identity = IdentifyData::from_words(core::array::from_fn(|i| id_buf[i].read()));
+
+        PortDevice {
+            pmp,
+            num_sectors: identity.num_sectors() as usize,
+            queue_depth: identity.queue_depth(),
+            sector_size: identity.sector_size(),
+            physical_sector_size: identity.physical_sector_size(),
+            devsleep: identity.supports_devsleep(),
+        }
+    }
+
+    // Bring up the link to each device port of the port multiplier, and identify every disk that
+    // shows up. Needs the port to be started with PMA set.
+    unsafe fn enumerate_port_multiplier(&mut self) -> Result<(), ()> {
+        let num_ports = self.read_pm_register(PM_CONTROL_PORT, GSCR_PORT_INFO)? & 0xF;
+        println!(
+            "AHCI port {}: port multiplier with {} ports",
+            self.sata_port, num_ports
+        );
+
+        for port in 0..(num_ports as u8).min(PM_CONTROL_PORT) {
+            // COMRESET the link (SControl DET = 1, then back to 0)
+            self.write_pm_register(port, PSCR_SCONTROL, 0x1)?;
+            crate::sleep(1);
+            self.write_pm_register(port, PSCR_SCONTROL, 0x0)?;
+
+            let mut sstatus = 0;
+            for _ in 0..PM_LINK_TIMEOUT {
+                sstatus = self.read_pm_register(port, PSCR_SSTATUS)?;
+                if sstatus & 0xF == 3 {
+                    break;
+                }
+                crate::sleep(1);
+            }
+
+            self.write_pm_register(port, PSCR_SERROR, !0)?;
+
+            if sstatus & 0xF != 3 {
+                continue;
+            }
+
+            let device = self.identify(port);
+            println!(
+                "AHCI port {}: disk on port multiplier port {} ({} sectors of {} bytes, {} physical)",
+                self.sata_port,
+                port,
+                device.num_sectors,
+                device.sector_size,
+                device.physical_sector_size
+            );
+            self.devices.push(device);
+        }
+
+        Ok(())
+    }
+
     fn clear_slot<'b, T>(&mut self, handle: BufferHandle<'b, T>) {
-        self.dma.ch[handle.slot as usize].num_buffers = 0;
-        self.dma.ch[handle.slot as usize].buffer_byte_pos = 0;
+        self.clear_raw(handle.slot);
+    }
+
+    fn clear_raw(&mut self, slot: u32) {
+        self.dma.ch[slot as usize].num_buffers = 0;
+        self.dma.ch[slot as usize].buffer_byte_pos = 0;
     }
 
     unsafe fn await_basic(&mut self, slot: u32) {
         while (*self.port_registers).command_mask.read() & (1u32 << slot) != 0 {
-            core::arch::x86_64::_mm_pause();
+            Arch::pause();
         }
 
         unsafe { self.acknowledge(slot, 0) };
     }
 
+    // Give up on the command in `slot` if its caller is still waiting on `status` for it. The slot
+    // stays taken, as the disk may still finish the command, and it can't be told not to until
+    // the port is reset, which isn't done yet. Until then, a read may still land in its buffer.
+    unsafe fn time_out(&mut self, slot: u32, status: *mut u32) {
+        if SLOT_STATUS[slot as usize] != status {
+            return;
+        }
+
+        log_warn!(
+            "AHCI: command in slot {} took over {:?}, giving up on it",
+            slot,
+            COMMAND_TIMEOUT
+        );
+        status.write_volatile(SLOT_TIMED_OUT);
+        SLOT_STATUS[slot as usize] = core::ptr::null_mut();
+    }
+
     unsafe fn acknowledge(&mut self, slot: u32, result: u32) {
         self.slots_outstanding_mask ^= 1u16 << slot;
         self.num_slots_available += 1;
@@ -595,6 +1625,150 @@ impl AHCIState {
     }
 }
 
+// A disk on a port: either the disk directly attached to it, or one behind a port multiplier.
+struct PortDevice {
+    pmp: u8,
+    num_sectors: usize,
+    queue_depth: u32,
+    // Logical sector size, which the disk is addressed in
+    sector_size: u32,
+    // Writes smaller than this make the disk read-modify-write
+    physical_sector_size: u32,
+    // Whether the disk can be put in DevSleep
+    devsleep: bool,
+}
+
+/// One disk on the AHCI controller. Disks behind the same port multiplier share the port's
+/// `AHCIState` (and its command slots), and are told apart by their port multiplier port.
+#[derive(Clone, Copy)]
+pub struct SataDevice {
+    pub port: &'static RwLock<&'static mut AHCIState>,
+    pub pmp: u8,
+    pub num_sectors: usize,
+    pub sector_size: u32,
+}
+
+impl SataDevice {
+    /// What the disk is registered as: "sata<port>" for a directly attached disk, and
+    /// "sata<port>.<pmp>" for one behind a port multiplier.
+    pub fn name(&self) -> String {
+        let state = self.port.read();
+        if state.pm_attached {
+            format!("sata{}.{}", state.sata_port, self.pmp)
+        } else {
+            format!("sata{}", state.sata_port)
+        }
+    }
+}
+
+impl BlockDevice for SataDevice {
+    fn block_size(&self) -> usize {
+        self.sector_size as usize
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.num_sectors
+    }
+
+    fn read<'a>(
+        &self,
+        buf: &'a mut [MaybeUninit<u8>],
+        offset: usize,
+    ) -> Result<&'a mut [u8], IOError> {
+        check_alignment(buf.len(), offset, self.block_size())?;
+        Ok(AHCIState::read_or_write_pmp(
+            self.port,
+            self.pmp,
+            Command::Read,
+            buf,
+            offset,
+        )?)
+    }
+
+    fn write(&self, buf: &[u8], offset: usize) -> Result<(), IOError> {
+        check_alignment(buf.len(), offset, self.block_size())?;
+        Ok(AHCIState::write_pmp(self.port, self.pmp, buf, offset)?)
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.port.read().queue_depth()
+    }
+}
+
+// The physically contiguous pieces of the `len` bytes at `addr`, as (physical address, length),
+// each small enough for one PRD. Stops early at memory that isn't mapped.
+fn dma_segments(addr: *const u8, len: usize) -> impl Iterator<Item = (u64, usize)> {
+    let mut virt = addr as u64;
+    let end = virt + len as u64;
+
+    core::iter::from_fn(move || {
+        if virt >= end {
+            return None;
+        }
+
+        let start = util::try_kernel_to_physical_address(virt)?;
+        let mut size = 0;
+
+        while virt < end && size < MAX_PRD_BYTES {
+            match util::try_kernel_to_physical_address(virt) {
+                Some(phys) if phys == start + size => {}
+                _ => break,
+            }
+
+            let step = (PAGE_SIZE - virt % PAGE_SIZE)
+                .min(end - virt)
+                .min(MAX_PRD_BYTES - size);
+            size += step;
+            virt += step;
+        }
+
+        Some((start, size as usize))
+    })
+}
+
+// How many of the `len` bytes at `addr` one command can transfer, in whole sectors. 0 if the disk
+// can't use the memory directly: PRDs have to start on an even address.
+fn dma_chunk_len(addr: *const u8, len: usize, sector_size: usize) -> usize {
+    if addr as usize % 2 != 0 {
+        return 0;
+    }
+
+    let covered: usize = dma_segments(addr, len)
+        .take(MAX_PRDS)
+        .map(|(_, size)| size)
+        .sum();
+
+    // NCQ commands hold the sector count in 16 bits
+    covered.min(u16::MAX as usize * sector_size) / sector_size * sector_size
+}
+
+fn check_alignment(len: usize, offset: usize, sector_size: usize) -> Result<(), IOError> {
+    if len == 0 || len % sector_size != 0 || offset % sector_size != 0 {
+        return Err(IOError::Invalid);
+    }
+    Ok(())
+}
+
+// Add every disk on the port to the block device registry, under `SataDevice::name`
+fn register_devices(port: &'static RwLock<&'static mut AHCIState>) {
+    let sata_devices: Vec<SataDevice> = port
+        .read()
+        .devices
+        .iter()
+        .map(|device| SataDevice {
+            port,
+            pmp: device.pmp,
+            num_sectors: device.num_sectors,
+            sector_size: device.sector_size,
+        })
+        .collect();
+
+    // Naming them takes the port's lock again
+    for sata_device in sata_devices {
+        block::register(sata_device.name(), Arc::new(sata_device));
+    }
+}
+
 #[repr(transparent)]
 #[must_use]
 struct BufferHandle<'a, T> {
@@ -606,6 +1780,35 @@ fn sstatus_active(sstatus: u32) -> bool {
     return (sstatus & 0x03) == 3 || ((1u32 << ((sstatus & 0xF00) >> 8)) & 0x144) != 0;
 }
 
+// Power the port's disk up. If the HBA staggers spin-up, the disk doesn't start until SUD is set,
+// so wait for the port to see it before going on to spin up any other. Otherwise every port spun
+// up at reset and SUD always reads as set.
+fn spin_up(port: &mut PortRegisters, staggered: bool) {
+    let command = &mut port.command_and_status;
+    command.write(command.read() | PortCommandMasks::PowerUp as u32);
+
+    if staggered {
+        let sstatus = &port.sstatus;
+        for _ in 0..SPIN_UP_TIMEOUT {
+            if sstatus.read() & 0xF != 0 {
+                break;
+            }
+            Arch::pause();
+        }
+    }
+}
+
+// Poll `done` until it's true, for up to `LINK_POWER_TIMEOUT` iterations
+fn wait_for(mut done: impl FnMut() -> bool) -> bool {
+    for _ in 0..LINK_POWER_TIMEOUT {
+        if done() {
+            return true;
+        }
+        Arch::pause();
+    }
+    false
+}
+
 #[repr(u16)]
 enum CHFlag {
     Clear = 0x400,
@@ -618,16 +1821,12 @@ enum InterruptMasks {
     NCQComplete = 0x8,
     ErrorMask = 0x7D800010,
     FatalErrorMask = 0x78000000, // HBFS|HBDS|IFS|TFES
-}
-
-#[repr(u8)]
-pub enum IOError {
-    TryAgain = 12,
-    BadData = 13,
+    LinkChange = 0x400040,       // PRCS|PCS: a disk came or went, or the link went up or down
 }
 
 #[repr(u32)]
+#[derive(Clone, Copy)]
 pub enum Command {
-    Read = IDECommand::ReadFPDMAQueued as u32,
-    Write = IDECommand::WriteFPDMAQueued as u32,
+    Read = ATACommand::ReadFPDMAQueued as u32,
+    Write = ATACommand::WriteFPDMAQueued as u32,
 }
@@ -1,4 +1,4 @@
-use super::util::Volatile;
+use super::mmio::{ReadOnly, ReadWrite};
 
 pub mod ahcistate;
 // Made with help from Chickadee OS source (https://github.com/CS161/chickadee/)
@@ -20,52 +20,56 @@ const NUM_PORT_REGISTERS: usize = 32;
 
 #[repr(C)]
 pub struct PortRegisters {
-    pub cmdlist_addr: Volatile<u64>, // PxCLB -- Port x Command List Base Address
-    pub rfis_base_addr: Volatile<u64>, // PxRFIS -- Port x RFIS Base Address -- the base address of rfis_state
-    pub interrupt_status: Volatile<u32>, // PxIS
-    pub interrupt_enable: Volatile<u32>, // PxIE
-    pub command_and_status: Volatile<u32>, // PxCMD -- Port x Command and Status
-    pub reserved2: u32,                // 0x2C - 0x2F are reserved
-    pub tfd: Volatile<u32>,            // PxTFD -- Port x Task File Data
-    pub sig: Volatile<u32>,            // PxSIG -- Port x Signature
-    pub sstatus: Volatile<u32>,        // PxSSTS -- Port x SATA Status, 0 = no device detected
-    pub scontrol: Volatile<u32>,       // PxSCTL -- Port x SATA Control
-    pub serror: Volatile<u32>,         // PxSERR -- Port x SATA Error
-    pub ncq_active: Volatile<u32>,     // PxSACT -- Port x SATA Active
-    pub command_mask: Volatile<u32>,   // PxCI -- Port x Command Issue
-    pub sata_notification: Volatile<u32>, // PxSNTF -- Port x SATA Notification
-    pub fis_switch_control: Volatile<u32>, // PxFBS -- Port x FIS-based switching control
-    pub device_sleep: Volatile<u32>,   // PxDEVSLP -- Port x Device Sleep
-    pub vendor_specific: [u32; 14],    // PxVS -- Port x Vendor Specific (ignore)
+    pub cmdlist_addr: ReadWrite<u64>, // PxCLB -- Port x Command List Base Address
+    pub rfis_base_addr: ReadWrite<u64>, // PxRFIS -- Port x RFIS Base Address -- the base address of rfis_state
+    pub interrupt_status: ReadWrite<u32>, // PxIS
+    pub interrupt_enable: ReadWrite<u32>, // PxIE
+    pub command_and_status: ReadWrite<u32>, // PxCMD -- Port x Command and Status
+    pub reserved2: u32,                 // 0x2C - 0x2F are reserved
+    pub tfd: ReadOnly<u32>,             // PxTFD -- Port x Task File Data
+    pub sig: ReadOnly<u32>,             // PxSIG -- Port x Signature
+    pub sstatus: ReadOnly<u32>,         // PxSSTS -- Port x SATA Status, 0 = no device detected
+    pub scontrol: ReadWrite<u32>,       // PxSCTL -- Port x SATA Control
+    pub serror: ReadWrite<u32>,         // PxSERR -- Port x SATA Error
+    pub ncq_active: ReadWrite<u32>,     // PxSACT -- Port x SATA Active
+    pub command_mask: ReadWrite<u32>,   // PxCI -- Port x Command Issue
+    pub sata_notification: ReadWrite<u32>, // PxSNTF -- Port x SATA Notification
+    pub fis_switch_control: ReadWrite<u32>, // PxFBS -- Port x FIS-based switching control
+    pub device_sleep: ReadWrite<u32>,   // PxDEVSLP -- Port x Device Sleep
+    pub vendor_specific: [u32; 14],     // PxVS -- Port x Vendor Specific (ignore)
 }
 
 #[repr(C)]
 pub struct Registers {
-    pub capabilities: Volatile<u32>,       // CAP: HBA capabilities [R]
-    pub global_hba_control: Volatile<u32>, // GHC: global HBA control [R/W]
-    pub interrupt_status: Volatile<u32>,   // IS: interrupt status
-    pub port_mask: Volatile<u32>,          // PI: addressable ports
-    pub ahci_version: Volatile<u32>,       // VS: AHCI version
-    pub ccc_control: Volatile<u32>,        // CCC_CTL: Command Completion Coalescing Control
-    pub ccc_port_mask: Volatile<u32>,      // CCC_PORTS
-    pub em_loc: Volatile<u32>,             // EM_LOC: Enclosure Management Location
-    pub em_control: Volatile<u32>,         // EM_CTL: Enclosure Management Control
-    pub cap2: Volatile<u32>,               // CAP2: HBA Capabilities extended
-    pub bohc: Volatile<u32>,               // BOHC: BIOS/OS Handoff Control and Status
-    pub reserved: [u32; 53],               // Vendor specific registers
-                                           // pub port_regs: [PortRegisters; 32],
+    pub capabilities: ReadOnly<u32>,        // CAP: HBA capabilities [R]
+    pub global_hba_control: ReadWrite<u32>, // GHC: global HBA control [R/W]
+    pub interrupt_status: ReadWrite<u32>,   // IS: interrupt status
+    pub port_mask: ReadOnly<u32>,           // PI: addressable ports
+    pub ahci_version: ReadOnly<u32>,        // VS: AHCI version
+    pub ccc_control: ReadWrite<u32>,        // CCC_CTL: Command Completion Coalescing Control
+    pub ccc_port_mask: ReadWrite<u32>,      // CCC_PORTS
+    pub em_loc: ReadOnly<u32>,              // EM_LOC: Enclosure Management Location
+    pub em_control: ReadWrite<u32>,         // EM_CTL: Enclosure Management Control
+    pub cap2: ReadOnly<u32>,                // CAP2: HBA Capabilities extended
+    pub bohc: ReadWrite<u32>,               // BOHC: BIOS/OS Handoff Control and Status
+    pub reserved: [u32; 53],                // Vendor specific registers
+                                            // pub port_regs: [PortRegisters; 32],
 }
 
 #[repr(u32)]
 pub enum PortCommandMasks {
     InterfaceMask = 0xF0000000,
     InterfaceActive = 0x10000000,
+    InterfacePartial = 0x20000000,
+    InterfaceSlumber = 0x60000000,
+    InterfaceDevSleep = 0x80000000,
     InterfaceIdle = 0x0,
+    PortMultiplierAttached = 0x20000,
     CommandRunning = 0x8000,
     RFISRunning = 0x4000,
     RFISEnable = 0x10,
     RFISClear = 0x8,
-    PowerUp = 0x6,
+    PowerUp = 0x6, // POD|SUD. SUD only does anything with staggered spin-up.
     Start = 0x1,
 }
 
@@ -84,6 +88,33 @@ pub enum InterruptMasks {
     FatalErrorMask = 0x78000000, // HBFS|HBDS|IFS|TFES
 }
 
+#[repr(u32)]
+pub enum CapabilityMasks {
+    PortMultiplier = 0x20000,    // SSPM: supports port multipliers
+    FISBasedSwitching = 0x10000, // SFBSS: supports FIS-based switching
+    StaggeredSpinUp = 0x8000000, // SSS: ports only spin up when told to
+    SlumberCapable = 0x4000,     // SSC: links can go into slumber
+    PartialCapable = 0x2000,     // PSC: links can go into partial
+}
+
+#[repr(u32)]
+pub enum Capability2Masks {
+    DevSleep = 0x8, // SDS: supports device sleep
+}
+
+#[repr(u32)]
+pub enum DevSleepMasks {
+    Present = 0x2, // DSP: the port is wired for device sleep
+}
+
+#[repr(u32)]
+pub enum FBSMasks {
+    Enable = 0x1,
+}
+
+// PxSIG of a port multiplier, as opposed to 0x101 for a plain SATA disk
+pub const SATA_SIG_PM: u32 = 0x96690101;
+
 #[repr(u32)]
 pub enum GHCMasks {
     InterruptEnable = 0x2,
@@ -94,6 +125,10 @@ pub enum GHCMasks {
 // The disk drive uses these to communicate with the OS.
 
 // PRD -- this is distinct from the ATA PRD/PRDT
+pub const MAX_PRDS: usize = 16;
+// The byte count has 22 bits
+pub const MAX_PRD_BYTES: u64 = 1 << 22;
+
 #[repr(C)]
 pub struct PRD {
     address: u64,
@@ -109,7 +144,7 @@ pub struct CommandTable {
     pub cfis: [u32; 16], // Command definitions
     pub acmd: [u32; 4],
     pub reserved: [u32; 12],
-    pub prdt: [PRD; 16],
+    pub prdt: [PRD; MAX_PRDS],
 }
 
 #[repr(C)]
@@ -124,7 +159,7 @@ pub struct CommandHeader {
 #[repr(align(256))]
 #[repr(C)]
 pub struct RFISState {
-    pub rfis: [Volatile<u32>; 64],
+    pub rfis: [ReadOnly<u32>; 64],
 }
 
 #[repr(align(1024))]
@@ -0,0 +1,260 @@
+// Allocation patterns to time the heap with, behind the shell's `bench alloc`, for trying out
+// changes to the buddy and slab allocators in the kernel itself rather than guessing at how they
+// do:
+//  - churn: for each of the slab allocator's sizes, a set of live allocations of that size, with
+//    one freed at random and another made over and over
+//  - mixed: the same with sizes all over the place, mostly small but some of them pages
+//  - pipe: a task allocates and another frees what it allocated, handing them over through a
+//    queue, so timer interrupts switch between the two in the middle of allocating and freeing
+//
+// Besides how fast each one goes, it reports how much of the heap its live allocations took up
+// against how much they asked for, and how fragmented the heap was left.
+//
+// Allocations go through `allocator::try_alloc`, so a pattern that runs the heap out counts the
+// failures instead of panicking.
+
+use super::clock;
+use super::rand::Xorshift;
+use crate::allocator;
+use crate::arch::x86_64::interrupts::without_interrupts;
+use crate::arch::{Arch, Cpu};
+use crate::task::{self, Priority, WaitQueue};
+use alloc::alloc::dealloc;
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use spin::Mutex;
+
+// The slab allocator's size classes, see allocator/sleb.rs. Anything bigger comes from the buddy
+// allocator.
+const SLAB_SIZES: [usize; 7] = [32, 64, 128, 256, 512, 1024, 2048];
+
+const ALIGN: usize = 16;
+
+const CHURN_LIVE: usize = 256;
+const CHURN_OPS: usize = 20_000;
+
+const MIXED_LIVE: usize = 64;
+const MIXED_OPS: usize = 20_000;
+
+const PIPE_OBJECTS: usize = 20_000;
+// Allocations waiting in the queue at most
+const PIPE_DEPTH: usize = 64;
+
+const SEED: u64 = 1;
+
+/// How one pattern went.
+pub struct Pattern {
+    pub name: String,
+    /// Allocations and frees
+    pub ops: u64,
+    pub failed: u64,
+    /// Bytes its live allocations asked for at the end, and how much less of the heap the buddy
+    /// allocator had free for them. Slab allocations come out of pages it already gave away.
+    pub requested: u64,
+    pub used: u64,
+    cycles: u64,
+}
+
+/// How a run of every pattern went.
+pub struct Report {
+    pub patterns: Vec<Pattern>,
+    /// Free heap and the largest block of it, before and after
+    pub free_before: (u64, u64),
+    pub free_after: (u64, u64),
+    cycles_per_ms: u64,
+}
+
+impl Report {
+    pub fn ops_per_sec(&self, pattern: &Pattern) -> u64 {
+        pattern.ops * self.cycles_per_ms * 1000 / pattern.cycles.max(1)
+    }
+}
+
+// The allocations a pattern is holding on to
+struct Live {
+    allocations: Vec<(usize, Layout)>,
+    ops: u64,
+    failed: u64,
+}
+
+impl Live {
+    fn new(capacity: usize) -> Self {
+        Self {
+            allocations: Vec::with_capacity(capacity),
+            ops: 0,
+            failed: 0,
+        }
+    }
+
+    fn alloc(&mut self, size: usize) {
+        self.ops += 1;
+        match allocate(size) {
+            Some(allocation) => self.allocations.push(allocation),
+            None => self.failed += 1,
+        }
+    }
+
+    fn free(&mut self, index: usize) {
+        self.ops += 1;
+        let (addr, layout) = self.allocations.swap_remove(index);
+        unsafe { dealloc(addr as *mut u8, layout) };
+    }
+
+    fn requested(&self) -> u64 {
+        self.allocations
+            .iter()
+            .map(|(_, layout)| layout.size() as u64)
+            .sum()
+    }
+}
+
+impl Drop for Live {
+    fn drop(&mut self) {
+        while !self.allocations.is_empty() {
+            self.free(self.allocations.len() - 1);
+        }
+    }
+}
+
+struct Pipe {
+    queue: Mutex<VecDeque<Option<(usize, Layout)>>>,
+    // The consumer waits here for allocations, and the producer for room
+    items: WaitQueue,
+    room: WaitQueue,
+}
+
+/// Run every pattern, one after the other. Fails if the task for the producer/consumer pattern
+/// couldn't be started.
+pub fn run() -> Result<Report, ()> {
+    let free_before = free();
+    let started_ms = clock::uptime_ms();
+    let started_cycles = Arch::timestamp();
+    let mut rng = Xorshift::new(SEED);
+
+    let mut patterns = Vec::new();
+    for size in SLAB_SIZES {
+        let pattern = timed(format!("churn {}", size), CHURN_LIVE, |live| {
+            for _ in 0..CHURN_LIVE {
+                live.alloc(size);
+            }
+            for _ in 0..CHURN_OPS / 2 {
+                if !live.allocations.is_empty() {
+                    live.free(rng.below(live.allocations.len() as u64) as usize);
+                }
+                live.alloc(size);
+            }
+        });
+        patterns.push(pattern);
+    }
+
+    patterns.push(timed(String::from("mixed"), MIXED_LIVE, |live| {
+        for _ in 0..MIXED_OPS {
+            if live.allocations.len() >= MIXED_LIVE {
+                live.free(rng.below(live.allocations.len() as u64) as usize);
+            }
+            live.alloc(mixed_size(&mut rng));
+        }
+    }));
+
+    patterns.push(pipe(&mut rng)?);
+
+    let elapsed_ms = clock::uptime_ms() - started_ms;
+    let cycles_per_ms = (Arch::timestamp() - started_cycles) / elapsed_ms.max(1);
+    Ok(Report {
+        patterns,
+        free_before,
+        free_after: free(),
+        cycles_per_ms: cycles_per_ms.max(1),
+    })
+}
+
+// Run `pattern` with somewhere to keep `capacity` allocations, measuring the heap before the
+// allocations are freed
+fn timed(name: String, capacity: usize, pattern: impl FnOnce(&mut Live)) -> Pattern {
+    let mut live = Live::new(capacity + 1);
+    let (free_before, _) = free();
+    let started = Arch::timestamp();
+    pattern(&mut live);
+    let cycles = Arch::timestamp() - started;
+    let (free_during, _) = free();
+
+    Pattern {
+        name,
+        ops: live.ops,
+        failed: live.failed,
+        requested: live.requested(),
+        used: free_before.saturating_sub(free_during),
+        cycles,
+    }
+}
+
+// Three in four allocations small enough for the slab allocator, most of the rest a page or a
+// few, and now and then a big one
+fn mixed_size(rng: &mut Xorshift) -> usize {
+    match rng.below(100) {
+        0..=74 => 16 + rng.below(2048 - 16) as usize,
+        75..=96 => 2049 + rng.below(16 * 1024 - 2049) as usize,
+        _ => 16 * 1024 + rng.below(48 * 1024) as usize,
+    }
+}
+
+// A task allocating and the calling one freeing, with the allocations going between them through
+// a queue
+fn pipe(rng: &mut Xorshift) -> Result<Pattern, ()> {
+    let pipe = Arc::new(Pipe {
+        queue: Mutex::new(VecDeque::with_capacity(PIPE_DEPTH)),
+        items: WaitQueue::new(),
+        room: WaitQueue::new(),
+    });
+    let sizes: Vec<usize> = (0..PIPE_OBJECTS).map(|_| mixed_size(rng)).collect();
+
+    let started = Arch::timestamp();
+    let producer = pipe.clone();
+    task::spawn("allocbench", Priority::Normal, move || {
+        for size in sizes {
+            let allocation = allocate(size);
+            producer
+                .room
+                .wait_while(|| producer.queue.lock().len() >= PIPE_DEPTH);
+            without_interrupts(|| producer.queue.lock().push_back(allocation));
+            producer.items.wake_one();
+        }
+    })?;
+
+    let mut failed = 0;
+    for _ in 0..PIPE_OBJECTS {
+        pipe.items.wait_while(|| pipe.queue.lock().is_empty());
+        let allocation = without_interrupts(|| pipe.queue.lock().pop_front());
+        pipe.room.wake_one();
+        match allocation.flatten() {
+            Some((addr, layout)) => unsafe { dealloc(addr as *mut u8, layout) },
+            None => failed += 1,
+        }
+    }
+
+    Ok(Pattern {
+        name: String::from("pipe"),
+        ops: PIPE_OBJECTS as u64 * 2,
+        failed,
+        // Everything has been freed by the end
+        requested: 0,
+        used: 0,
+        cycles: Arch::timestamp() - started,
+    })
+}
+
+fn allocate(size: usize) -> Option<(usize, Layout)> {
+    let layout = Layout::from_size_align(size, ALIGN).ok()?;
+    let ptr = allocator::try_alloc(layout).ok()?.as_ptr();
+    // Touch it, as whatever asked for it would
+    unsafe { ptr.write(size as u8) };
+    Some((ptr as usize, layout))
+}
+
+fn free() -> (u64, u64) {
+    allocator::fragmentation().unwrap_or((0, 0))
+}
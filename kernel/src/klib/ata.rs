@@ -0,0 +1,224 @@
+// ATA definitions shared by the IDE and AHCI drivers: command opcodes, the status and error
+// register bits, IDENTIFY DEVICE data, and the register FIS AHCI sends commands in.
+
+/// Sector size for drives that don't report one, and the unit of the count field in non-data
+/// commands.
+pub const SECTOR_SIZE: u32 = 512;
+
+#[derive(Clone, Copy)]
+pub enum Command {
+    ReadPIO = 0x20,
+    ReadPIOExt = 0x24,
+    ReadDMA = 0xC8,
+    ReadDMAExt = 0x25,
+    WritePIO = 0x30,
+    WritePIOExt = 0x34,
+    WriteDMA = 0xCA,
+    WriteDMAExt = 0x35,
+    CacheFlush = 0xE7,
+    CacheFlushExt = 0xEA,
+    Packet = 0xA0,
+    IdentifyPacket = 0xA1,
+    Identify = 0xEC,
+    ReadFPDMAQueued = 0x60,
+    WriteFPDMAQueued = 0x61,
+    SetFeatures = 0xEF,
+    ReadPortMultiplier = 0xE4,
+    WritePortMultiplier = 0xE8,
+}
+
+pub enum Status {
+    Busy = 0x80,
+    ReadyDrive = 0x40,
+    DriveWriteFault = 0x20,
+    DriveSeekComplete = 0x10,
+    DataRequestReady = 0x08,
+    CorrectedData = 0x04,
+    Index = 0x02,
+    Error = 0x01,
+}
+
+pub enum Error {
+    BadBlock = 0x80,
+    Uncorrectable = 0x40,
+    MediaChanged = 0x20,
+    IDMarkNotFound = 0x10,
+    MediaChangeRequest = 0x08,
+    CommandAborted = 0x04,
+    Track0NotFound = 0x02,
+    NoAddressMark = 0x01,
+}
+
+// IDENTIFY DEVICE words
+const ID_GENERAL_CONFIG: usize = 0;
+const ID_MODEL: usize = 27;
+const ID_MODEL_WORDS: usize = 20;
+const ID_CAPABILITIES: usize = 49;
+const ID_MAX_LBA: usize = 60;
+const ID_QUEUE_DEPTH: usize = 75;
+const ID_SATA_CAPABILITIES: usize = 76;
+const ID_SATA_FEATURES: usize = 78;
+const ID_COMMAND_SETS: usize = 82;
+const ID_MAX_LBA_EXT: usize = 100;
+const ID_SECTOR_SIZE: usize = 106;
+const ID_LOGICAL_SECTOR_SIZE: usize = 117;
+
+// Word 0
+const ID_NOT_ATA: u16 = 1 << 15;
+// Word 76
+const ID_NCQ_SUPPORTED: u16 = 1 << 8;
+// Word 78
+const ID_DEVSLEEP_SUPPORTED: u16 = 1 << 8;
+// Word 83, the upper half of the command sets
+const ID_LBA48_SUPPORTED: u32 = 1 << 26;
+// Word 106, physical/logical sector size
+const ID_SECTOR_SIZE_VALID: u16 = 1 << 14;
+const ID_SECTOR_SIZE_INVALID: u16 = 1 << 15;
+const ID_LOGICAL_SECTOR_LONG: u16 = 1 << 12;
+const ID_MULTIPLE_LOGICAL_PER_PHYSICAL: u16 = 1 << 13;
+
+/// The 256 words returned by IDENTIFY DEVICE (or IDENTIFY PACKET DEVICE).
+pub struct IdentifyData {
+    words: [u16; 256],
+}
+
+impl IdentifyData {
+    pub fn from_words(words: [u16; 256]) -> Self {
+        Self { words }
+    }
+
+    /// From the data as it comes off the wire, i.e. little endian words.
+    pub fn from_bytes(bytes: &[u8; 512]) -> Self {
+        Self {
+            words: core::array::from_fn(|i| u16::from_le_bytes([bytes[i * 2], bytes[i * 2 + 1]])),
+        }
+    }
+
+    fn dword(&self, word: usize) -> u32 {
+        self.words[word] as u32 | ((self.words[word + 1] as u32) << 16)
+    }
+
+    pub fn signature(&self) -> u16 {
+        self.words[ID_GENERAL_CONFIG]
+    }
+
+    pub fn is_atapi(&self) -> bool {
+        self.words[ID_GENERAL_CONFIG] & ID_NOT_ATA != 0
+    }
+
+    pub fn capabilities(&self) -> u16 {
+        self.words[ID_CAPABILITIES]
+    }
+
+    /// Words 82 and 83, the supported command sets.
+    pub fn command_sets(&self) -> u32 {
+        self.dword(ID_COMMAND_SETS)
+    }
+
+    pub fn supports_lba48(&self) -> bool {
+        self.command_sets() & ID_LBA48_SUPPORTED != 0
+    }
+
+    pub fn supports_ncq(&self) -> bool {
+        self.words[ID_SATA_CAPABILITIES] & ID_NCQ_SUPPORTED != 0
+    }
+
+    pub fn supports_devsleep(&self) -> bool {
+        self.words[ID_SATA_FEATURES] & ID_DEVSLEEP_SUPPORTED != 0
+    }
+
+    /// How many NCQ commands the drive takes at once.
+    pub fn queue_depth(&self) -> u32 {
+        ((self.words[ID_QUEUE_DEPTH] & 0x1F) + 1) as u32
+    }
+
+    /// Number of logical sectors, from the 48-bit count if the drive has one.
+    pub fn num_sectors(&self) -> u64 {
+        if self.supports_lba48() {
+            (0..4).fold(0, |sectors, i| {
+                sectors | ((self.words[ID_MAX_LBA_EXT + i] as u64) << (16 * i))
+            })
+        } else {
+            self.dword(ID_MAX_LBA) as u64
+        }
+    }
+
+    /// Logical sector size in bytes, which the drive is addressed in.
+    pub fn sector_size(&self) -> u32 {
+        let sector_info = self.words[ID_SECTOR_SIZE];
+        if !self.sector_size_valid() || sector_info & ID_LOGICAL_SECTOR_LONG == 0 {
+            return SECTOR_SIZE;
+        }
+
+        // Given in words
+        self.dword(ID_LOGICAL_SECTOR_SIZE) * 2
+    }
+
+    /// Physical sector size in bytes. Writes smaller than this make the drive read-modify-write.
+    pub fn physical_sector_size(&self) -> u32 {
+        let sector_info = self.words[ID_SECTOR_SIZE];
+        if !self.sector_size_valid() || sector_info & ID_MULTIPLE_LOGICAL_PER_PHYSICAL == 0 {
+            return self.sector_size();
+        }
+
+        self.sector_size() << (sector_info & 0xF)
+    }
+
+    fn sector_size_valid(&self) -> bool {
+        let sector_info = self.words[ID_SECTOR_SIZE];
+        sector_info & (ID_SECTOR_SIZE_VALID | ID_SECTOR_SIZE_INVALID) == ID_SECTOR_SIZE_VALID
+    }
+
+    /// The model name, padded with spaces. The characters of each word are swapped in the data.
+    pub fn model(&self) -> [u8; 40] {
+        let mut model = [0; 2 * ID_MODEL_WORDS];
+        for (i, word) in self.words[ID_MODEL..ID_MODEL + ID_MODEL_WORDS]
+            .iter()
+            .enumerate()
+        {
+            model[i * 2..i * 2 + 2].copy_from_slice(&word.to_be_bytes());
+        }
+        model
+    }
+}
+
+const FIS_TYPE_REG_H2D: u32 = 0x27;
+// Set in a host to device register FIS to update the command register, rather than control
+const FIS_COMMAND: u32 = 0x80;
+
+/// A host to device register FIS, which is how a command is sent to a SATA drive.
+#[derive(Clone, Copy, Default)]
+pub struct RegisterFis {
+    pub command: u8,
+    /// Port multiplier port of the drive, 0 if there is no port multiplier
+    pub pmp: u8,
+    pub features: u16,
+    /// Only the low 48 bits are sent
+    pub lba: u64,
+    pub device: u8,
+    pub count: u16,
+    pub control: u8,
+}
+
+impl RegisterFis {
+    pub fn new(command: Command) -> Self {
+        Self {
+            command: command as u8,
+            ..Default::default()
+        }
+    }
+
+    /// The first four dwords of the FIS, which is all the command FIS area needs; the last one is
+    /// reserved.
+    pub fn to_dwords(&self) -> [u32; 4] {
+        [
+            FIS_TYPE_REG_H2D
+                | ((FIS_COMMAND | (self.pmp as u32 & 0xF)) << 8)
+                | ((self.command as u32) << 16)
+                | ((self.features as u32 & 0xFF) << 24),
+            (self.lba as u32 & 0xFFFFFF) | ((self.device as u32) << 24),
+            ((self.lba >> 24) as u32 & 0xFFFFFF) | ((self.features as u32 & 0xFF00) << 16),
+            self.count as u32 | ((self.control as u32) << 24),
+        ]
+    }
+}
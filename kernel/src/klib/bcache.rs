@@ -0,0 +1,506 @@
+// A cache of a block device's blocks, for filesystems to read and change their metadata through.
+// Changes stay in memory, with the block marked dirty, until the cache is flushed: by `flush` (or
+// `sync`, for every cache), when a dirty block has to make room for another, or by the flusher
+// task, once any of a cache's blocks has been dirty for `DIRTY_EXPIRE` ticks.
+//
+// A flush writes every dirty block, sorted by its `WriteOrder`, so that a crash part way through
+// leaves the disk wasteful rather than inconsistent: blocks are marked allocated before anything
+// is put in them, inodes point at data only once it is there, directory entries name inodes only
+// once those are written, and the free counts in the superblock and group descriptors, which a
+// checker can work out again from the bitmaps, go last.
+//
+// With `paranoid` on the command line, every cached block also carries a CRC32C of its contents,
+// taken when it's read in and whenever it's changed through the cache. The CRC is checked each
+// time the block is used and before it's written back, and a block that was written is read back
+// and checked again, so that a driver or a stray DMA scribbling on blocks gets noticed close to
+// when it happened rather than as a broken filesystem later on.
+
+use super::block::{self, BlockDevice, IOError};
+use super::hash::crc32c;
+use super::once_lock::OnceLock;
+use super::{cmdline, shutdown, suspend};
+use crate::allocator;
+use crate::arch::x86_64::interrupts::without_interrupts;
+use crate::task::{self, Priority, WaitQueue};
+use crate::{log_error, log_info, log_warn, TIMER};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::{Mutex, RwLock};
+
+pub const DEFAULT_CAPACITY: usize = 256;
+
+// In timer ticks (milliseconds)
+const FLUSH_INTERVAL: u64 = 5_000;
+const DIRTY_EXPIRE: u64 = 30_000;
+
+/// What a block holds, which decides when it is written in a flush relative to the others.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WriteOrder {
+    /// Block and inode bitmaps
+    Bitmap,
+    /// File contents, and indirect blocks
+    Data,
+    /// Inode tables
+    Inode,
+    /// Directory contents
+    Directory,
+    /// The superblock and group descriptors
+    Summary,
+}
+
+struct Buffer {
+    data: Vec<u8>,
+    dirty: Option<WriteOrder>,
+    dirtied_at: u64,
+    // Bumped on every change, so a flush can tell whether it wrote the latest contents
+    version: u64,
+    last_used: u64,
+    // With `paranoid`, the CRC32C of `data`, and the tick it was last known to be right at
+    crc: u32,
+    checked_at: u64,
+}
+
+struct Buffers {
+    blocks: BTreeMap<u64, Buffer>,
+    // Bumped on every access, for picking the least recently used block to evict
+    clock: u64,
+}
+
+/// How many blocks a cache has, and how many of them are dirty.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub cached: usize,
+    pub dirty: usize,
+    /// Blocks found not to match their CRC, with `paranoid`
+    pub corrupt: usize,
+}
+
+pub struct BufferCache {
+    device: Arc<dyn BlockDevice>,
+    block_size: usize,
+    capacity: usize,
+    buffers: Mutex<Buffers>,
+    // One flush at a time, so that two can't interleave their writes
+    flushing: AtomicBool,
+    flush_done: WaitQueue,
+    corrupt: AtomicUsize,
+}
+
+static CACHES: RwLock<Vec<Arc<BufferCache>>> = RwLock::new(Vec::new());
+
+static PARANOID: OnceLock<bool> = OnceLock::new();
+
+/// Whether cached blocks are checked against their CRCs.
+pub fn paranoid() -> bool {
+    *PARANOID.get_or_init(|| cmdline::has("paranoid"))
+}
+
+impl BufferCache {
+    /// Cache up to `capacity` blocks of `block_size` bytes of `device`, and have the flusher task
+    /// look after it. `block_size` has to be a multiple of the device's.
+    pub fn new(
+        device: Arc<dyn BlockDevice>,
+        block_size: usize,
+        capacity: usize,
+    ) -> Result<Arc<Self>, ()> {
+        if block_size == 0 || block_size % device.block_size() != 0 || capacity == 0 {
+            return Err(());
+        }
+
+        let cache = Arc::new(Self {
+            device,
+            block_size,
+            capacity,
+            buffers: Mutex::new(Buffers {
+                blocks: BTreeMap::new(),
+                clock: 0,
+            }),
+            flushing: AtomicBool::new(false),
+            flush_done: WaitQueue::new(),
+            corrupt: AtomicUsize::new(0),
+        });
+        CACHES.write().push(cache.clone());
+        Ok(cache)
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        without_interrupts(|| {
+            let buffers = self.buffers.lock();
+            CacheStats {
+                cached: buffers.blocks.len(),
+                dirty: buffers
+                    .blocks
+                    .values()
+                    .filter(|buffer| buffer.dirty.is_some())
+                    .count(),
+                corrupt: self.corrupt.load(Ordering::Relaxed),
+            }
+        })
+    }
+
+    /// Call `f` with the contents of `block`, reading it in first if it isn't cached.
+    pub fn read<R>(&self, block: u64, f: impl FnOnce(&[u8]) -> R) -> Result<R, IOError> {
+        let mut f = Some(f);
+        loop {
+            self.load(block)?;
+            let result = without_interrupts(|| {
+                let mut buffers = self.buffers.lock();
+                let buffers = &mut *buffers;
+                buffers.clock += 1;
+                // None if it was evicted again between loading it and getting here
+                let buffer = buffers.blocks.get_mut(&block)?;
+                buffer.last_used = buffers.clock;
+                self.check(block, buffer, "in the cache");
+                f.take().map(|f| f(&buffer.data))
+            });
+            if let Some(result) = result {
+                return Ok(result);
+            }
+        }
+    }
+
+    /// Change the contents of `block` with `f`, reading it in first if it isn't cached. The block
+    /// is written in the next flush, in the place `order` gives it.
+    pub fn modify<R>(
+        &self,
+        block: u64,
+        order: WriteOrder,
+        f: impl FnOnce(&mut [u8]) -> R,
+    ) -> Result<R, IOError> {
+        let mut f = Some(f);
+        loop {
+            self.load(block)?;
+            let result = without_interrupts(|| {
+                let mut buffers = self.buffers.lock();
+                let buffers = &mut *buffers;
+                buffers.clock += 1;
+                let buffer = buffers.blocks.get_mut(&block)?;
+                buffer.last_used = buffers.clock;
+                buffer.version += 1;
+                if buffer.dirty.is_none() {
+                    buffer.dirtied_at = TIMER.load(Ordering::SeqCst);
+                }
+                // A block that ends up holding something else is written at the earliest place
+                buffer.dirty = Some(buffer.dirty.map_or(order, |dirty| dirty.min(order)));
+                self.check(block, buffer, "in the cache");
+                let result = f.take().map(|f| f(&mut buffer.data));
+                self.rechecksum(buffer);
+                result
+            });
+            if let Some(result) = result {
+                return Ok(result);
+            }
+        }
+    }
+
+    /// Write every dirty block to the device, in `WriteOrder`, and wait for them to be written.
+    /// Blocks that fail to write stay dirty.
+    pub fn flush(&self) -> Result<(), IOError> {
+        while self.flushing.swap(true, Ordering::Acquire) {
+            self.flush_done
+                .wait_while(|| self.flushing.load(Ordering::Relaxed));
+        }
+
+        let result = self.write_dirty();
+
+        self.flushing.store(false, Ordering::Release);
+        self.flush_done.wake_all();
+        result
+    }
+
+    // Whether any block has been dirty for `DIRTY_EXPIRE` ticks
+    fn has_expired(&self, now: u64) -> bool {
+        without_interrupts(|| {
+            self.buffers.lock().blocks.values().any(|buffer| {
+                buffer.dirty.is_some() && now.saturating_sub(buffer.dirtied_at) >= DIRTY_EXPIRE
+            })
+        })
+    }
+
+    fn write_dirty(&self) -> Result<(), IOError> {
+        // Take copies, so that the blocks can be changed while being written. They stay dirty (and
+        // so can't be evicted and read back in stale) until they are written.
+        let mut dirty: Vec<(WriteOrder, u64, u64, Vec<u8>)> = without_interrupts(|| {
+            self.buffers
+                .lock()
+                .blocks
+                .iter_mut()
+                .filter_map(|(&block, buffer)| {
+                    let order = buffer.dirty?;
+                    self.check(block, buffer, "before writing it back");
+                    Some((order, block, buffer.version, buffer.data.clone()))
+                })
+                .collect()
+        });
+        dirty.sort_unstable_by_key(|&(order, block, _, _)| (order, block));
+
+        for (order, block, version, data) in dirty {
+            let written = self.device.write(&data, block as usize * self.block_size);
+            if let Err(err) = written {
+                // Nothing later in the order can go out before this has
+                log_warn!(
+                    "Couldn't write back block {} ({:?}): {:?}",
+                    block,
+                    order,
+                    err
+                );
+                return Err(err);
+            }
+            if paranoid() {
+                self.check_written(block, &data);
+            }
+
+            without_interrupts(|| {
+                if let Some(buffer) = self.buffers.lock().blocks.get_mut(&block) {
+                    if buffer.version == version {
+                        buffer.dirty = None;
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    // Make sure `block` is cached, reading it in (and making room for it) if it isn't
+    fn load(&self, block: u64) -> Result<(), IOError> {
+        if without_interrupts(|| self.buffers.lock().blocks.contains_key(&block)) {
+            return Ok(());
+        }
+
+        let data = self.read_uncached(block)?;
+        while !self.make_room() {
+            // Every block is dirty
+            self.flush()?;
+        }
+
+        without_interrupts(|| {
+            let mut buffers = self.buffers.lock();
+            let last_used = buffers.clock;
+            // Whoever read it in at the same time got there first, and may have changed it since
+            let mut buffer = Buffer {
+                data,
+                dirty: None,
+                dirtied_at: 0,
+                version: 0,
+                last_used,
+                crc: 0,
+                checked_at: 0,
+            };
+            self.rechecksum(&mut buffer);
+            buffers.blocks.entry(block).or_insert(buffer);
+        });
+        Ok(())
+    }
+
+    // Evict the least recently used clean block if the cache is full. Returns whether there is
+    // room now.
+    fn make_room(&self) -> bool {
+        without_interrupts(|| {
+            let mut buffers = self.buffers.lock();
+            if buffers.blocks.len() < self.capacity {
+                return true;
+            }
+
+            let victim = buffers
+                .blocks
+                .iter()
+                .filter(|(_, buffer)| buffer.dirty.is_none())
+                .min_by_key(|(_, buffer)| buffer.last_used)
+                .map(|(&block, _)| block);
+            match victim {
+                Some(block) => {
+                    buffers.blocks.remove(&block);
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+
+    // With `paranoid`, check `buffer` still matches its CRC, reporting it if it doesn't. Either
+    // way its CRC is right from then on, so each change behind the cache's back is reported once.
+    fn check(&self, block: u64, buffer: &mut Buffer, when: &str) {
+        if !paranoid() {
+            return;
+        }
+
+        let now = TIMER.load(Ordering::SeqCst);
+        let crc = crc32c::checksum(&buffer.data);
+        if crc != buffer.crc {
+            self.corrupt.fetch_add(1, Ordering::Relaxed);
+            log_error!(
+                "Block {} (LBA {}) changed {}: CRC {:#010x}, expected {:#010x}, last right {} ms \
+                 ago",
+                block,
+                self.lba(block),
+                when,
+                crc,
+                buffer.crc,
+                now.saturating_sub(buffer.checked_at)
+            );
+            buffer.crc = crc;
+        }
+        buffer.checked_at = now;
+    }
+
+    // Take a new CRC of `buffer` after changing it
+    fn rechecksum(&self, buffer: &mut Buffer) {
+        if paranoid() {
+            buffer.crc = crc32c::checksum(&buffer.data);
+            buffer.checked_at = TIMER.load(Ordering::SeqCst);
+        }
+    }
+
+    // Read back a block that was just written as `data`, and report it if the device has
+    // something else
+    fn check_written(&self, block: u64, data: &[u8]) {
+        let started = TIMER.load(Ordering::SeqCst);
+        let expected = crc32c::checksum(data);
+        match self.read_uncached(block) {
+            Ok(read_back) => {
+                let crc = crc32c::checksum(&read_back);
+                if crc != expected {
+                    self.corrupt.fetch_add(1, Ordering::Relaxed);
+                    log_error!(
+                        "Block {} (LBA {}) reads back wrong after writing: CRC {:#010x}, \
+                         expected {:#010x}, read back in {} ms",
+                        block,
+                        self.lba(block),
+                        crc,
+                        expected,
+                        TIMER.load(Ordering::SeqCst).saturating_sub(started)
+                    );
+                }
+            }
+            Err(err) => log_warn!(
+                "Couldn't read back block {} (LBA {}) to check it: {:?}",
+                block,
+                self.lba(block),
+                err
+            ),
+        }
+    }
+
+    // Where `block` starts on the device, in the device's own blocks
+    fn lba(&self, block: u64) -> u64 {
+        block * (self.block_size / self.device.block_size()) as u64
+    }
+
+    fn read_uncached(&self, block: u64) -> Result<Vec<u8>, IOError> {
+        let mut buf = alloc::vec![MaybeUninit::uninit(); self.block_size];
+        self.device
+            .read(&mut buf, block as usize * self.block_size)
+            .map(|data| data.to_vec())
+    }
+}
+
+/// Flush every cache, e.g. before rebooting.
+pub fn sync() -> Result<(), IOError> {
+    let mut result = Ok(());
+    for cache in caches() {
+        if let Err(err) = cache.flush() {
+            result = Err(err);
+        }
+    }
+    result
+}
+
+/// Every cache, in the order they were made.
+pub fn caches() -> Vec<Arc<BufferCache>> {
+    CACHES.read().clone()
+}
+
+// Drop every clean block, for when the heap runs out. Runs inside the allocation that failed,
+// which may have been made with a cache locked, so caches it can't lock right away are skipped.
+fn shrink() -> usize {
+    let Some(caches) = CACHES.try_read() else {
+        return 0;
+    };
+
+    let mut freed = 0;
+    for cache in caches.iter() {
+        without_interrupts(|| {
+            let Some(mut buffers) = cache.buffers.try_lock() else {
+                return;
+            };
+            buffers.blocks.retain(|_, buffer| {
+                if buffer.dirty.is_none() {
+                    freed += buffer.data.capacity();
+                }
+                buffer.dirty.is_some()
+            });
+        });
+    }
+    freed
+}
+
+// Drop the caches of a device that went away, blocks and all. Dirty blocks have nowhere to go now.
+fn forget_device(name: &str, device: &Arc<dyn BlockDevice>) {
+    let mut gone = Vec::new();
+    CACHES.write().retain(|cache| {
+        let same = Arc::as_ptr(&cache.device) as *const u8 == Arc::as_ptr(device) as *const u8;
+        if same {
+            gone.push(cache.clone());
+        }
+        !same
+    });
+
+    for cache in gone {
+        let lost = cache.stats().dirty;
+        without_interrupts(|| cache.buffers.lock().blocks.clear());
+        if lost > 0 {
+            log_warn!("{} is gone, {} dirty blocks were never written", name, lost);
+        }
+    }
+}
+
+/// Start the task that writes back blocks which have been dirty for too long. Has to be called
+/// after `task::init`.
+pub fn init() {
+    if paranoid() {
+        let how = match crc32c::is_hardware() {
+            true => "SSE4.2",
+            false => "software",
+        };
+        log_info!("Checking cached blocks against their CRC32C ({})", how);
+    }
+
+    let flusher = task::spawn("flusher", Priority::Normal, || loop {
+        task::sleep_ticks(FLUSH_INTERVAL);
+
+        let now = TIMER.load(Ordering::SeqCst);
+        for cache in caches() {
+            // Errors are logged by the flush, and the blocks stay dirty for the next one
+            if cache.has_expired(now) {
+                let _ = cache.flush();
+            }
+        }
+    });
+
+    if flusher.is_err() {
+        log_warn!("Couldn't start the flusher task, dirty blocks are only written by sync");
+    }
+
+    if block::on_remove("block caches", forget_device).is_err() {
+        log_warn!("Couldn't have the block caches told about disks going away");
+    }
+
+    if allocator::register_reclaim("block caches", shrink).is_err() {
+        log_warn!("Couldn't register the block caches to be shrunk when the heap runs out");
+    }
+
+    // Errors are logged by the flush
+    if shutdown::register("block caches", || sync().map_err(|_| ())).is_err() {
+        log_warn!("Couldn't register the block caches for shutdown, they won't be written back");
+    }
+    if suspend::register("block caches", || sync().map_err(|_| ()), || Ok(())).is_err() {
+        log_warn!("Couldn't register the block caches for suspend, they won't be written back");
+    }
+}
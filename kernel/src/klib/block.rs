@@ -0,0 +1,346 @@
+use crate::arch::x86_64::interrupts::without_interrupts;
+use crate::klib::containers::static_vec::StaticVec;
+use crate::task::WaitQueue;
+use crate::{log_debug, log_info};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::mem::MaybeUninit;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::{Mutex, RwLock};
+
+#[repr(u8)]
+#[derive(Debug)]
+pub enum IOError {
+    TryAgain = 12,
+    BadData = 13,
+    Invalid = 14, // e.g. an offset or length that isn't a multiple of the block size
+    ReadOnly = 15,
+    NotFound = 16,
+    NoSpace = 17,
+    TimedOut = 18,
+    DeviceGone = 19, // e.g. the disk was unplugged
+}
+
+/// Anything that can be read and written a block at a time, e.g. a SATA or NVMe disk.
+pub trait BlockDevice: Send + Sync {
+    /// Size in bytes of the smallest unit the device can transfer.
+    fn block_size(&self) -> usize;
+
+    fn num_blocks(&self) -> usize;
+
+    /// Read `buf.len()` bytes starting at byte `offset` of the device. Both should be multiples of
+    /// the block size.
+    fn read<'a>(
+        &self,
+        buf: &'a mut [MaybeUninit<u8>],
+        offset: usize,
+    ) -> Result<&'a mut [u8], IOError>;
+
+    /// Write all of `buf` starting at byte `offset` of the device. Both should be multiples of
+    /// the block size.
+    fn write(&self, buf: &[u8], offset: usize) -> Result<(), IOError>;
+
+    /// How many requests the device can work on at once, e.g. its number of NCQ slots.
+    fn queue_depth(&self) -> usize {
+        1
+    }
+
+    /// Start reading `count` blocks from block `lba`, without waiting for them. Devices with no
+    /// request queue in front of them (see iosched.rs) just do the read before returning.
+    fn submit_read(&self, lba: usize, count: usize) -> IoFuture {
+        let block_size = self.block_size();
+        let mut buf = alloc::vec![MaybeUninit::uninit(); count * block_size];
+        let result = self
+            .read(&mut buf, lba * block_size)
+            .map(|data| data.to_vec());
+        IoFuture::ready(result)
+    }
+
+    /// Start writing `data` from block `lba` on, like `submit_read`.
+    fn submit_write(&self, lba: usize, data: Vec<u8>) -> IoFuture {
+        let result = self
+            .write(&data, lba * self.block_size())
+            .map(|()| Vec::new());
+        IoFuture::ready(result)
+    }
+}
+
+/// What a request ends with: the data for a read, or nothing for a write.
+pub type IoResult = Result<Vec<u8>, IOError>;
+
+type IoCallback = Box<dyn FnOnce(IoResult) + Send>;
+
+/// A block request that may still be in flight. Its result can be waited for by a task, handed to
+/// a callback, or polled for as a `Future`.
+pub struct IoFuture {
+    shared: Arc<IoShared>,
+}
+
+/// Finishes the `IoFuture` it was made with, from whoever carries out the request.
+pub struct IoCompleter {
+    shared: Arc<IoShared>,
+}
+
+struct IoShared {
+    state: Mutex<IoState>,
+    done: WaitQueue,
+}
+
+#[derive(Default)]
+struct IoState {
+    result: Option<IoResult>,
+    callback: Option<IoCallback>,
+    waker: Option<Waker>,
+}
+
+impl IoFuture {
+    /// A request that is still to be carried out, and what to finish it with.
+    pub fn pending() -> (Self, IoCompleter) {
+        let shared = Arc::new(IoShared {
+            state: Mutex::new(IoState::default()),
+            done: WaitQueue::new(),
+        });
+        let completer = IoCompleter {
+            shared: shared.clone(),
+        };
+        (Self { shared }, completer)
+    }
+
+    /// A request that is already done.
+    pub fn ready(result: IoResult) -> Self {
+        let (future, completer) = Self::pending();
+        completer.complete(result);
+        future
+    }
+
+    pub fn is_done(&self) -> bool {
+        without_interrupts(|| self.shared.state.lock().result.is_some())
+    }
+
+    /// Block the current task until the request is done.
+    pub fn wait(self) -> IoResult {
+        let shared = &self.shared;
+        shared
+            .done
+            .wait_while(|| shared.state.lock().result.is_none());
+        without_interrupts(|| shared.state.lock().result.take()).unwrap_or(Err(IOError::TryAgain))
+    }
+
+    /// Call `callback` with the result once the request is done: right away if it already is,
+    /// otherwise from whoever finishes it (e.g. a request queue's worker task).
+    pub fn then(self, callback: impl FnOnce(IoResult) + Send + 'static) {
+        let mut callback: Option<IoCallback> = Some(Box::new(callback));
+        let result = without_interrupts(|| {
+            let mut state = self.shared.state.lock();
+            let result = state.result.take();
+            if result.is_none() {
+                state.callback = callback.take();
+            }
+            result
+        });
+
+        if let (Some(result), Some(callback)) = (result, callback) {
+            callback(result);
+        }
+    }
+}
+
+impl Future for IoFuture {
+    type Output = IoResult;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult> {
+        without_interrupts(|| {
+            let mut state = self.shared.state.lock();
+            match state.result.take() {
+                Some(result) => Poll::Ready(result),
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+    }
+}
+
+impl IoCompleter {
+    pub fn complete(self, result: IoResult) {
+        let mut result = Some(result);
+        let (callback, waker) = without_interrupts(|| {
+            let mut state = self.shared.state.lock();
+            let callback = state.callback.take();
+            if callback.is_none() {
+                state.result = result.take();
+            }
+            (callback, state.waker.take())
+        });
+
+        // Outside of the lock, since the callback may well submit another request
+        if let (Some(callback), Some(result)) = (callback, result) {
+            callback(result);
+        }
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        self.shared.done.wake_all();
+    }
+}
+
+pub struct BlockDeviceEntry {
+    pub name: String,
+    pub device: Arc<dyn BlockDevice>,
+}
+
+/// Read `buf.len()` bytes starting at byte `offset` of `device`, neither of which have to be
+/// multiples of the block size. Blocks that are only partly wanted go through a bounce buffer.
+pub fn read_bytes<'a>(
+    device: &dyn BlockDevice,
+    buf: &'a mut [MaybeUninit<u8>],
+    offset: usize,
+) -> Result<&'a mut [u8], IOError> {
+    crate::trace!(Block, "read {} bytes at {}", buf.len(), offset);
+    let block_size = device.block_size();
+    let mut bounce: Vec<MaybeUninit<u8>> = Vec::new();
+    let mut done = 0;
+
+    while done < buf.len() {
+        let position = offset + done;
+        let skip = position % block_size;
+        let remaining = buf.len() - done;
+
+        if skip == 0 && remaining >= block_size {
+            // Whole blocks can go straight into the caller's buffer
+            let len = remaining - remaining % block_size;
+            device.read(&mut buf[done..done + len], position)?;
+            done += len;
+            continue;
+        }
+
+        if bounce.is_empty() {
+            bounce.resize(block_size, MaybeUninit::uninit());
+        }
+
+        let block = device.read(&mut bounce, position - skip)?;
+        let len = remaining.min(block_size - skip);
+        let wanted = &block[skip..skip + len];
+        for (dst, &src) in buf[done..done + len].iter_mut().zip(wanted) {
+            dst.write(src);
+        }
+        done += len;
+    }
+
+    Ok(unsafe { MaybeUninit::slice_assume_init_mut(buf) })
+}
+
+/// Write all of `buf` starting at byte `offset` of `device`, neither of which have to be
+/// multiples of the block size. Blocks that are only partly written are read first, so the rest
+/// of them is kept.
+pub fn write_bytes(device: &dyn BlockDevice, buf: &[u8], offset: usize) -> Result<(), IOError> {
+    crate::trace!(Block, "write {} bytes at {}", buf.len(), offset);
+    let block_size = device.block_size();
+    let mut bounce: Vec<MaybeUninit<u8>> = Vec::new();
+    let mut done = 0;
+
+    while done < buf.len() {
+        let position = offset + done;
+        let skip = position % block_size;
+        let remaining = buf.len() - done;
+
+        if skip == 0 && remaining >= block_size {
+            let len = remaining - remaining % block_size;
+            device.write(&buf[done..done + len], position)?;
+            done += len;
+            continue;
+        }
+
+        if bounce.is_empty() {
+            bounce.resize(block_size, MaybeUninit::uninit());
+        }
+
+        let block = device.read(&mut bounce, position - skip)?;
+        let len = remaining.min(block_size - skip);
+        block[skip..skip + len].copy_from_slice(&buf[done..done + len]);
+        device.write(block, position - skip)?;
+        done += len;
+    }
+
+    Ok(())
+}
+
+static BLOCK_DEVICES: RwLock<Vec<BlockDeviceEntry>> = RwLock::new(Vec::new());
+
+const MAX_REMOVE_HOOKS: usize = 8;
+
+/// Told about a device that went away, with the name it was registered under and what was
+/// registered, so that whatever was built on it can let go.
+pub type RemoveHook = fn(&str, &Arc<dyn BlockDevice>);
+
+static REMOVE_HOOKS: Mutex<StaticVec<(&'static str, RemoveHook), MAX_REMOVE_HOOKS>> =
+    Mutex::new(StaticVec::new());
+
+/// Make a device available to the rest of the kernel under `name` (e.g. "sata0", "nvme0n1").
+pub fn register(name: String, device: Arc<dyn BlockDevice>) {
+    BLOCK_DEVICES
+        .write()
+        .push(BlockDeviceEntry { name, device });
+}
+
+/// Swap the device registered under `name` for `device`, e.g. to put a request queue in front of
+/// it.
+pub fn replace(name: &str, device: Arc<dyn BlockDevice>) -> Result<(), ()> {
+    let mut devices = BLOCK_DEVICES.write();
+    let entry = devices
+        .iter_mut()
+        .find(|entry| entry.name == name)
+        .ok_or(())?;
+    entry.device = device;
+    Ok(())
+}
+
+/// Have `hook` called for every device that is removed from then on.
+pub fn on_remove(name: &'static str, hook: RemoveHook) -> Result<(), ()> {
+    without_interrupts(|| REMOVE_HOOKS.lock().push((name, hook)).map_err(|_| ()))
+}
+
+/// Take the device registered under `name` away, e.g. because its disk was unplugged, and tell
+/// everything that registered a hook. Whoever still holds the device keeps it, but should expect
+/// its requests to fail with `DeviceGone`.
+pub fn remove(name: &str) -> Result<(), ()> {
+    let entry = {
+        let mut devices = BLOCK_DEVICES.write();
+        let index = devices
+            .iter()
+            .position(|entry| entry.name == name)
+            .ok_or(())?;
+        devices.remove(index)
+    };
+    log_info!("Block device {} removed", entry.name);
+
+    // Outside of the lock, as the hooks may well look devices up
+    let hooks = without_interrupts(|| REMOVE_HOOKS.lock().clone());
+    for (hook_name, hook) in hooks.iter() {
+        log_debug!("Telling {} that {} is gone", hook_name, entry.name);
+        hook(&entry.name, &entry.device);
+    }
+    Ok(())
+}
+
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    BLOCK_DEVICES
+        .read()
+        .iter()
+        .find(|entry| entry.name == name)
+        .map(|entry| entry.device.clone())
+}
+
+/// Every registered device, in the order they were found.
+pub fn devices() -> Vec<(String, Arc<dyn BlockDevice>)> {
+    BLOCK_DEVICES
+        .read()
+        .iter()
+        .map(|entry| (entry.name.clone(), entry.device.clone()))
+        .collect()
+}
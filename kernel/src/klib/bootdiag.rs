@@ -0,0 +1,79 @@
+// Early boot diagnostics, for finding out where boot hangs on a new machine. With them on, `stage`
+// prints a status line as each part of init finishes and waits for a key before the next one
+// starts, so whatever is on the screen when the machine stops says how far it got.
+//
+// They're turned on with `bootdiag` on the command line, or by holding F8 while the kernel starts.
+// Keys are read by polling the PS/2 controller with interrupts off, as the stages before interrupts
+// are enabled have to work too. That takes a PS/2 keyboard, or the firmware emulating one for a USB
+// keyboard.
+
+use crate::klib::cmdline;
+use crate::klib::ps2::controller::Ps2Controller;
+use crate::klib::ps2::keyboard::SpecialKey;
+use crate::println;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+
+// Set 1 scancodes: a key's break code is its make code with the top bit set. Bytes from 0xE0 up are
+// prefixes and replies from the keyboard rather than keys.
+const BREAK: u8 = 0x80;
+const FIRST_NON_KEY: u8 = 0xE0;
+
+// Bytes to look through for F8 being held. The key repeats, so its make code keeps coming.
+const HOTKEY_BYTES: usize = 16;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turn diagnostics on if they were asked for. Has to be called before the first `stage`.
+pub fn init() {
+    let f8 = SpecialKey::F8 as u8;
+    let held = interrupts::without_interrupts(|| {
+        let mut controller = Ps2Controller {};
+        (0..HOTKEY_BYTES)
+            .map_while(|_| controller.nonblocking_read().ok())
+            .any(|byte| byte == f8)
+    });
+
+    if !held && !cmdline::has("bootdiag") {
+        return;
+    }
+
+    ENABLED.store(true, Ordering::Relaxed);
+    println!("Boot diagnostics: press a key after each stage to start the next one");
+    if held {
+        // Otherwise letting go of F8 would count as the key for the first stage
+        wait_for_release(Some(f8 | BREAK));
+    }
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Note that the init stage `name` has finished, and whether it worked. With diagnostics on, this
+/// waits for a key before returning.
+pub fn stage(name: &str, ok: bool) {
+    if !enabled() {
+        return;
+    }
+
+    println!("[{}] {}", if ok { " OK " } else { "FAIL" }, name);
+    wait_for_release(None);
+}
+
+// Wait for a key to be let go of, `key` if given, or any. Going by releases rather than presses
+// means there's no break code left behind for the keyboard driver to see.
+fn wait_for_release(key: Option<u8>) {
+    interrupts::without_interrupts(|| {
+        let mut controller = Ps2Controller {};
+        loop {
+            let Ok(byte) = controller.nonblocking_read() else {
+                continue;
+            };
+            let released = (BREAK + 1..FIRST_NON_KEY).contains(&byte);
+            if released && (key.is_none() || key == Some(byte)) {
+                break;
+            }
+        }
+    });
+}
@@ -0,0 +1,113 @@
+// The timer interrupt, and the kernel's sense of time passing. Channel 0 of the PIT raises IRQ 0
+// `hz` times a second (1000 unless `hz=` on the command line says otherwise), and each interrupt
+// adds a jiffy. `TIMER` is kept at the milliseconds since the timer started from the jiffies, so
+// everything that waits on it goes by milliseconds whatever the rate is.
+//
+// The jiffy count is allowed to wrap. Milliseconds are worked out from it without multiplying it
+// up first, so they can't overflow before the jiffies do.
+
+use crate::arch::ports::Port;
+use crate::klib::{cmdline, suspend};
+use crate::{log_info, log_warn, TIMER};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+
+/// The PIT counts down at this rate, whatever the channel.
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+
+pub const PIT_CHANNEL_0_PORT: u16 = 0x40;
+const PIT_CHANNEL_0: Port<u8> = Port::new(PIT_CHANNEL_0_PORT);
+/// Shared by every channel, so the speaker programs channel 2 through it too.
+pub const PIT_COMMAND_PORT: u16 = 0x43;
+const PIT_COMMAND: Port<u8> = Port::new(PIT_COMMAND_PORT);
+
+// Channel 0, low byte then high byte, mode 2 (rate generator), binary
+const CHANNEL_0_RATE_GENERATOR: u8 = 0b0011_0100;
+
+pub const DEFAULT_HZ: u32 = 1000;
+// The slowest the PIT goes is PIT_FREQUENCY / 65536, about 18.2 Hz. Much faster than the upper
+// bound and the machine does little but take timer interrupts.
+const MIN_HZ: u32 = 19;
+const MAX_HZ: u32 = 10_000;
+
+static HZ: AtomicU32 = AtomicU32::new(DEFAULT_HZ);
+
+static JIFFIES: AtomicU64 = AtomicU64::new(0);
+
+/// Program the PIT to interrupt at the configured rate. Has to be called before interrupts are
+/// enabled.
+pub fn init() {
+    let hz = match cmdline::value("hz").map(str::parse::<u32>) {
+        None => DEFAULT_HZ,
+        Some(Ok(hz)) if (MIN_HZ..=MAX_HZ).contains(&hz) => hz,
+        Some(_) => {
+            log_warn!(
+                "hz= has to be from {} to {}, using {}",
+                MIN_HZ,
+                MAX_HZ,
+                DEFAULT_HZ
+            );
+            DEFAULT_HZ
+        }
+    };
+    HZ.store(hz, Ordering::Relaxed);
+    program();
+    log_info!("Timer interrupt at {} Hz", hz);
+
+    // The PIT forgets its divisor while the machine is asleep
+    suspend::register("pit", || Ok(()), resume).unwrap();
+}
+
+fn program() {
+    let divisor = (PIT_FREQUENCY / hz()).clamp(1, u16::MAX as u32) as u16;
+    interrupts::without_interrupts(|| unsafe {
+        PIT_COMMAND.write(CHANNEL_0_RATE_GENERATOR);
+        PIT_CHANNEL_0.write(divisor as u8);
+        PIT_CHANNEL_0.write((divisor >> 8) as u8);
+    });
+}
+
+fn resume() -> Result<(), ()> {
+    program();
+    Ok(())
+}
+
+/// Called from the timer interrupt. Counts the jiffy and brings `TIMER` up to date, returning the
+/// milliseconds it now says.
+pub fn tick() -> u64 {
+    let jiffies = JIFFIES.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+    let ms = jiffies_to_ms(jiffies);
+    TIMER.store(ms, Ordering::SeqCst);
+    ms
+}
+
+/// Timer interrupts per second.
+pub fn hz() -> u32 {
+    HZ.load(Ordering::Relaxed)
+}
+
+/// Timer interrupts since the timer started, wrapping around.
+pub fn jiffies() -> u64 {
+    JIFFIES.load(Ordering::Relaxed)
+}
+
+/// Jiffies from `earlier` to `later`, right even if the count wrapped around in between.
+pub fn jiffies_between(earlier: u64, later: u64) -> u64 {
+    later.wrapping_sub(earlier)
+}
+
+fn jiffies_to_ms(jiffies: u64) -> u64 {
+    let hz = hz() as u64;
+    (jiffies / hz) * 1000 + (jiffies % hz) * 1000 / hz
+}
+
+/// Milliseconds since the timer started. The same as `TIMER`.
+pub fn uptime_ms() -> u64 {
+    TIMER.load(Ordering::SeqCst)
+}
+
+/// Time since the timer started.
+pub fn uptime() -> Duration {
+    Duration::from_millis(uptime_ms())
+}
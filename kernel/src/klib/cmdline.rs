@@ -0,0 +1,42 @@
+// The kernel command line: options separated by spaces, each either `key=value` or a bare `key`,
+// that change how the kernel behaves without building it with other features. It comes from the
+// bootloader, through `bootinfo`.
+
+use crate::klib::once_lock::OnceLock;
+use crate::log_info;
+
+static CMDLINE: OnceLock<&'static str> = OnceLock::new();
+
+/// Keep the command line the kernel was booted with. Has to be called first thing, as early boot
+/// code looks at it; until then it is empty.
+pub fn init(cmdline: &'static str) {
+    let _ = CMDLINE.set(cmdline);
+}
+
+/// The whole command line.
+pub fn get() -> &'static str {
+    CMDLINE.get().copied().unwrap_or("")
+}
+
+/// The value of the last `key=value` option for `key`. A bare `key` has the value "".
+pub fn value(key: &str) -> Option<&'static str> {
+    get()
+        .split_ascii_whitespace()
+        .filter_map(|option| match option.split_once('=') {
+            Some((name, value)) => (name == key).then_some(value),
+            None => (option == key).then_some(""),
+        })
+        .last()
+}
+
+/// Whether `key` was given at all, bare or with a value.
+pub fn has(key: &str) -> bool {
+    value(key).is_some()
+}
+
+/// Log the command line, if there is one.
+pub fn log() {
+    if !get().is_empty() {
+        log_info!("Command line: {}", get());
+    }
+}
@@ -0,0 +1,168 @@
+// The CMOS: 128 bytes of battery-backed RAM next to the real-time clock, read and written through
+// an index port and a data port. The clock and the firmware's settings take up most of it, but a
+// few bytes are left spare, and the kernel keeps settings there that have to survive a reboot: the
+// console log level, and whether the last boot got as far as the shell.
+//
+// The kernel's bytes have a checksum of their own, like the firmware's settings do. If it doesn't
+// add up (the battery went flat, or the firmware uses those bytes after all) the defaults are used
+// and written back over whatever was there.
+
+use crate::arch::ports::Port;
+use crate::klib::log::{self, Level};
+use crate::log_warn;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const INDEX_PORT: u16 = 0x70;
+const INDEX: Port<u8> = Port::new(INDEX_PORT);
+const DATA: Port<u8> = INDEX.offset(1);
+
+// Bit 7 of the index masks NMIs, and is left clear so they keep coming in
+const INDEX_MASK: u8 = 0x7F;
+
+// The firmware's settings, and their checksum: the sum of the bytes, high byte first
+const FIRMWARE_FIRST: u8 = 0x10;
+const FIRMWARE_LAST: u8 = 0x2D;
+const FIRMWARE_CHECKSUM: u8 = 0x2E;
+
+// The kernel's settings, past everything the standard layout and QEMU's firmware use. The last
+// byte is picked so that all of them add up to 0.
+const SETTINGS_MAGIC: u8 = 0x40;
+const SETTINGS_FLAGS: u8 = 0x41;
+const SETTINGS_LOG_LEVEL: u8 = 0x42;
+const SETTINGS_CHECKSUM: u8 = 0x43;
+
+const MAGIC: u8 = 0x9C;
+
+// Set while booting, and cleared once the shell is up
+const FLAG_BOOTING: u8 = 1 << 0;
+
+// The index port selects the register the data port reads and writes, so the two accesses have to
+// happen together
+static CMOS: Mutex<()> = Mutex::new(());
+
+static LAST_BOOT_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Read CMOS register `register`.
+pub fn read(register: u8) -> u8 {
+    interrupts::without_interrupts(|| {
+        let _cmos = CMOS.lock();
+        unsafe {
+            INDEX.write(register & INDEX_MASK);
+            DATA.read()
+        }
+    })
+}
+
+/// Write `value` to CMOS register `register`. Writing over the clock's or the firmware's registers
+/// can leave the firmware thinking its settings are corrupt on the next boot.
+pub fn write(register: u8, value: u8) {
+    interrupts::without_interrupts(|| {
+        let _cmos = CMOS.lock();
+        unsafe {
+            INDEX.write(register & INDEX_MASK);
+            DATA.write(value);
+        }
+    })
+}
+
+/// Whether the firmware's settings match their checksum. If they don't, the battery has most
+/// likely gone flat.
+pub fn firmware_checksum_valid() -> bool {
+    let sum = (FIRMWARE_FIRST..=FIRMWARE_LAST).fold(0u16, |sum, register| {
+        sum.wrapping_add(read(register) as u16)
+    });
+    let checksum = u16::from_be_bytes([read(FIRMWARE_CHECKSUM), read(FIRMWARE_CHECKSUM + 1)]);
+    sum == checksum
+}
+
+/// The settings the kernel keeps in the CMOS.
+#[derive(Clone, Copy, Debug)]
+pub struct BootSettings {
+    pub log_level: Level,
+    booting: bool,
+}
+
+impl BootSettings {
+    const DEFAULT: Self = Self {
+        log_level: Level::Info,
+        booting: false,
+    };
+
+    /// The settings stored in the CMOS, or the defaults if there aren't any valid ones.
+    pub fn load() -> Self {
+        let bytes = [
+            read(SETTINGS_MAGIC),
+            read(SETTINGS_FLAGS),
+            read(SETTINGS_LOG_LEVEL),
+            read(SETTINGS_CHECKSUM),
+        ];
+        let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        if bytes[0] != MAGIC || sum != 0 {
+            return Self::DEFAULT;
+        }
+
+        match Level::ALL.get(bytes[2] as usize) {
+            Some(&log_level) => Self {
+                log_level,
+                booting: bytes[1] & FLAG_BOOTING != 0,
+            },
+            None => Self::DEFAULT,
+        }
+    }
+
+    pub fn save(&self) {
+        let flags = if self.booting { FLAG_BOOTING } else { 0 };
+        let level = self.log_level as u8;
+        let checksum = 0u8
+            .wrapping_sub(MAGIC)
+            .wrapping_sub(flags)
+            .wrapping_sub(level);
+
+        interrupts::without_interrupts(|| {
+            write(SETTINGS_MAGIC, MAGIC);
+            write(SETTINGS_FLAGS, flags);
+            write(SETTINGS_LOG_LEVEL, level);
+            write(SETTINGS_CHECKSUM, checksum);
+        });
+    }
+}
+
+/// Apply the stored settings and note that a boot has started. If the last one started and never
+/// finished, `last_boot_failed` says so from here on.
+pub fn boot_started() {
+    if !firmware_checksum_valid() {
+        log_warn!("CMOS checksum is wrong, the battery may be flat");
+    }
+
+    let mut settings = BootSettings::load();
+    log::set_console_level(settings.log_level);
+    if settings.booting {
+        LAST_BOOT_FAILED.store(true, Ordering::Relaxed);
+        log_warn!("The last boot didn't finish");
+    }
+
+    settings.booting = true;
+    settings.save();
+}
+
+/// Note that this boot got as far as the shell.
+pub fn boot_finished() {
+    let mut settings = BootSettings::load();
+    settings.booting = false;
+    settings.save();
+}
+
+/// Whether the boot before this one crashed or hung before it finished.
+pub fn last_boot_failed() -> bool {
+    LAST_BOOT_FAILED.load(Ordering::Relaxed)
+}
+
+/// Change the console log level, now and for the boots after this one.
+pub fn set_log_level(level: Level) {
+    log::set_console_level(level);
+    let mut settings = BootSettings::load();
+    settings.log_level = level;
+    settings.save();
+}
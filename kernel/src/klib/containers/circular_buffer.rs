@@ -1,9 +1,11 @@
 use core::mem::MaybeUninit;
 
-/// A circular buffer allocated on the stack (or data section).
+/// A circular buffer allocated on the stack (or data section), used as a FIFO queue: items are
+/// pushed at the back and popped from the front.
 /// Cannot grow in size and only has as much memory as given by N.
 pub struct CircularBuffer<const N: usize, T> {
     size: u32,
+    // Index of the front (oldest) item
     start: u32,
     items: [MaybeUninit<T>; N],
 }
@@ -18,78 +20,120 @@ impl<const N: usize, T> CircularBuffer<N, T> {
         }
     }
 
-    fn front_maybe_uninit_mut(&mut self) -> &mut MaybeUninit<T> {
-        &mut self.items[self.start as usize]
-    }
-
-    fn front_maybe_uninit(&mut self) -> &MaybeUninit<T> {
-        &self.items[self.start as usize]
-    }
-
-    fn back_maybe_uninit_mut(&mut self) -> &mut MaybeUninit<T> {
-        &mut self.items[Self::mod_add(self.start, self.size - 1) as usize]
-    }
-
-    fn back_maybe_uninit(&mut self) -> &MaybeUninit<T> {
-        &self.items[Self::mod_add(self.start, self.size - 1) as usize]
-    }
-
-    fn inc_start(&mut self) {
-        self.start = (self.start + 1) % (N as u32);
+    /// Return whether the circular buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
     }
 
-    fn dec_size(&mut self) {
-        self.size -= 1
+    pub fn is_full(&self) -> bool {
+        self.size as usize == N
     }
 
-    fn inc_size(&mut self) {
-        self.size += 1
+    pub fn len(&self) -> usize {
+        self.size as usize
     }
 
-    /// Return whether the circular buffer is empty.
-    pub fn empty(&self) -> bool {
-        self.size == 0
+    pub const fn capacity(&self) -> usize {
+        N
     }
 
-    /// Push back a new item to the buffer. Or, if the buffer is full, replace the first element in
-    /// the buffer with the item that has been pushed back.
+    /// Push a new item to the back of the buffer. If the buffer is full, the item at the front
+    /// (the oldest one) is dropped to make room.
     pub fn push_back(&mut self, item: T) {
         if N == 0 {
             return;
         }
 
-        if self.size as usize >= N {
-            // We can assume this is safe, since by this point we must have written to the front
-            unsafe { core::ptr::drop_in_place(self.front_maybe_uninit_mut().as_mut_ptr()) };
-            self.front_maybe_uninit_mut().write(item);
-            self.inc_start()
-        } else {
-            self.inc_size();
-            self.back_maybe_uninit_mut().write(item);
+        if self.is_full() {
+            drop(self.pop_front());
         }
+
+        let back = Self::wrap(self.start as usize + self.size as usize);
+        self.items[back].write(item);
+        self.size += 1;
     }
 
-    /// Pop back the last element. If there is no element, return None, else Some(T)
-    pub fn pop_back(&mut self) -> Option<T> {
-        if self.size == 0 {
+    /// Remove and return the item at the front, i.e. the one pushed the longest ago.
+    pub fn pop_front(&mut self) -> Option<T> {
+        if self.is_empty() {
             None
         } else {
-            let item = unsafe { self.back_maybe_uninit().assume_init_read() };
+            Some(unsafe { self.pop_front_unchecked() })
+        }
+    }
+
+    /// Pop the front item without checking that there is one.
+    /// ### Safety
+    /// The buffer must not be empty.
+    pub unsafe fn pop_front_unchecked(&mut self) -> T {
+        let item = self.items[self.start as usize].assume_init_read();
+        self.start = Self::wrap(self.start as usize + 1) as u32;
+        self.size -= 1;
+        item
+    }
 
-            self.dec_size();
+    /// The item that `pop_front` would return, without removing it.
+    pub fn peek_front(&self) -> Option<&T> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(unsafe { self.items[self.start as usize].assume_init_ref() })
+        }
+    }
 
-            Some(item)
+    /// The item `index` places from the front, so 0 is the oldest and `len() - 1` the newest.
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index >= self.len() {
+            return None;
         }
+
+        let index = Self::wrap(self.start as usize + index);
+        Some(unsafe { self.items[index].assume_init_ref() })
+    }
+
+    /// Remove every item, front first. Items the iterator isn't advanced past are still removed
+    /// when it is dropped.
+    pub fn drain(&mut self) -> Drain<'_, N, T> {
+        Drain { buffer: self }
     }
 
-    /// Pop back without checking if there is an element at the location.
-    pub unsafe fn pop_back_unchecked(&mut self) -> T {
-        let item = self.back_maybe_uninit();
-        unsafe { item.assume_init_read() }
+    /// Drop every item.
+    pub fn clear(&mut self) {
+        while self.pop_front().is_some() {}
     }
 
     #[inline]
-    fn mod_add(x: u32, y: u32) -> u32 {
-        (x + y) % (N as u32)
+    fn wrap(index: usize) -> usize {
+        index % N
+    }
+}
+
+impl<const N: usize, T> Drop for CircularBuffer<N, T> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+pub struct Drain<'a, const N: usize, T> {
+    buffer: &'a mut CircularBuffer<N, T>,
+}
+
+impl<'a, const N: usize, T> Iterator for Drain<'a, N, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.buffer.pop_front()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.buffer.len(), Some(self.buffer.len()))
+    }
+}
+
+impl<'a, const N: usize, T> ExactSizeIterator for Drain<'a, N, T> {}
+
+impl<'a, const N: usize, T> Drop for Drain<'a, N, T> {
+    fn drop(&mut self) {
+        self.buffer.clear();
     }
 }
@@ -0,0 +1,235 @@
+use super::{claim, owner_id, release, Linked};
+use core::marker::PhantomData;
+use core::ptr::null_mut;
+use core::sync::atomic::Ordering::Relaxed;
+use core::sync::atomic::{AtomicPtr, AtomicUsize};
+
+/// The link a node embeds for each `List` it can be in.
+pub struct ListLink {
+    owner: AtomicUsize,
+    prev: AtomicPtr<ListLink>,
+    next: AtomicPtr<ListLink>,
+}
+
+impl ListLink {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            owner: AtomicUsize::new(0),
+            prev: AtomicPtr::new(null_mut()),
+            next: AtomicPtr::new(null_mut()),
+        }
+    }
+
+    /// Whether the node is in a list.
+    pub fn is_linked(&self) -> bool {
+        self.owner.load(Relaxed) != 0
+    }
+}
+
+impl Default for ListLink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A doubly linked list of nodes that embed a `ListLink`, e.g. a run queue. Adding and removing
+/// nodes, from either end or anywhere in the middle, takes constant time.
+pub struct List<'a, T: Linked<ListLink>> {
+    id: usize,
+    head: *mut ListLink,
+    tail: *mut ListLink,
+    len: usize,
+    _marker: PhantomData<&'a T>,
+}
+
+impl<'a, T: Linked<ListLink>> List<'a, T> {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            id: 0,
+            head: null_mut(),
+            tail: null_mut(),
+            len: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Only ever called on links of nodes in this list
+    fn node(link: *mut ListLink) -> &'a T {
+        unsafe { &*T::from_link(link) }
+    }
+
+    fn link_ptr(node: &T) -> *mut ListLink {
+        node.link() as *const ListLink as *mut ListLink
+    }
+
+    /// Whether `node` is in this list.
+    pub fn contains(&self, node: &T) -> bool {
+        self.id != 0 && node.link().owner.load(Relaxed) == self.id
+    }
+
+    pub fn front(&self) -> Option<&'a T> {
+        (!self.head.is_null()).then(|| Self::node(self.head))
+    }
+
+    pub fn back(&self) -> Option<&'a T> {
+        (!self.tail.is_null()).then(|| Self::node(self.tail))
+    }
+
+    /// Add a node to the back of the list. If it is already in a list, it is handed back.
+    pub fn push_back(&mut self, node: &'a T) -> Result<(), &'a T> {
+        let tail = self.tail;
+        self.insert_between(node, tail, null_mut())
+    }
+
+    /// Add a node to the front of the list. If it is already in a list, it is handed back.
+    pub fn push_front(&mut self, node: &'a T) -> Result<(), &'a T> {
+        let head = self.head;
+        self.insert_between(node, null_mut(), head)
+    }
+
+    /// Add `node` right after `after`, which has to be in this list. If it isn't, or `node` is
+    /// already in a list, `node` is handed back.
+    pub fn insert_after(&mut self, after: &T, node: &'a T) -> Result<(), &'a T> {
+        if !self.contains(after) {
+            return Err(node);
+        }
+
+        let prev = Self::link_ptr(after);
+        let next = after.link().next.load(Relaxed);
+        self.insert_between(node, prev, next)
+    }
+
+    fn insert_between(
+        &mut self,
+        node: &'a T,
+        prev: *mut ListLink,
+        next: *mut ListLink,
+    ) -> Result<(), &'a T> {
+        let link = node.link();
+        if !claim(&link.owner, owner_id(&mut self.id)) {
+            return Err(node);
+        }
+
+        let ptr = Self::link_ptr(node);
+        link.prev.store(prev, Relaxed);
+        link.next.store(next, Relaxed);
+
+        match unsafe { prev.as_ref() } {
+            Some(prev) => prev.next.store(ptr, Relaxed),
+            None => self.head = ptr,
+        }
+        match unsafe { next.as_ref() } {
+            Some(next) => next.prev.store(ptr, Relaxed),
+            None => self.tail = ptr,
+        }
+
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn pop_front(&mut self) -> Option<&'a T> {
+        let node = self.front()?;
+        self.unlink(node.link());
+        Some(node)
+    }
+
+    pub fn pop_back(&mut self) -> Option<&'a T> {
+        let node = self.back()?;
+        self.unlink(node.link());
+        Some(node)
+    }
+
+    /// Take a node out of the list. Returns false if it wasn't in this list.
+    pub fn remove(&mut self, node: &T) -> bool {
+        if !self.contains(node) {
+            return false;
+        }
+
+        self.unlink(node.link());
+        true
+    }
+
+    fn unlink(&mut self, link: &ListLink) {
+        let prev = link.prev.load(Relaxed);
+        let next = link.next.load(Relaxed);
+
+        match unsafe { prev.as_ref() } {
+            Some(prev) => prev.next.store(next, Relaxed),
+            None => self.head = next,
+        }
+        match unsafe { next.as_ref() } {
+            Some(next) => next.prev.store(prev, Relaxed),
+            None => self.tail = prev,
+        }
+
+        link.prev.store(null_mut(), Relaxed);
+        link.next.store(null_mut(), Relaxed);
+        release(&link.owner);
+        self.len -= 1;
+    }
+
+    /// The nodes from front to back.
+    pub fn iter(&self) -> Iter<'_, 'a, T> {
+        Iter {
+            next: self.head,
+            remaining: self.len,
+            _list: PhantomData,
+        }
+    }
+
+    /// Take every node out of the list.
+    pub fn clear(&mut self) {
+        let mut link = self.head;
+        while let Some(current) = unsafe { link.as_ref() } {
+            link = current.next.load(Relaxed);
+            release(&current.owner);
+        }
+
+        self.head = null_mut();
+        self.tail = null_mut();
+        self.len = 0;
+    }
+}
+
+impl<'a, T: Linked<ListLink>> Drop for List<'a, T> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+// The links are only changed through a &mut List
+unsafe impl<'a, T: Linked<ListLink> + Sync> Send for List<'a, T> {}
+unsafe impl<'a, T: Linked<ListLink> + Sync> Sync for List<'a, T> {}
+
+pub struct Iter<'l, 'a, T: Linked<ListLink>> {
+    next: *mut ListLink,
+    remaining: usize,
+    _list: PhantomData<&'l List<'a, T>>,
+}
+
+impl<'l, 'a, T: Linked<ListLink>> Iterator for Iter<'l, 'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let link = self.next;
+        self.next = unsafe { link.as_ref() }?.next.load(Relaxed);
+        self.remaining -= 1;
+        Some(List::<'a, T>::node(link))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'l, 'a, T: Linked<ListLink>> ExactSizeIterator for Iter<'l, 'a, T> {}
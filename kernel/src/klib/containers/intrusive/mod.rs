@@ -0,0 +1,64 @@
+// Intrusive containers: the links live inside of the nodes themselves, so nothing is allocated
+// when a node is added, and a node can sit in a container without being moved or copied.
+//
+// Containers only hold shared references to their nodes, which have to outlive the container.
+// Every link remembers which container it is in, so handing a container a node that is already
+// in some other container (or removing one that isn't in it) is caught rather than corrupting
+// either of them.
+
+pub mod list;
+pub mod rbtree;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Implemented by node types that embed a link of type `L`, to get from the node to its link
+/// and back. Use `impl_linked!` rather than implementing this by hand.
+/// ### Safety
+/// `link` must return a field of `self`, and `from_link` must give back the node that field is
+/// in.
+pub unsafe trait Linked<L> {
+    fn link(&self) -> &L;
+
+    /// ### Safety
+    /// `link` has to point to the link field of a `Self`.
+    unsafe fn from_link(link: *const L) -> *const Self;
+}
+
+/// Implement `Linked` for a node type, given the name and type of its link field:
+/// `impl_linked!(Task, run_link: ListLink);`
+#[macro_export]
+macro_rules! impl_linked {
+    ($node:ty, $field:ident: $link:ty) => {
+        unsafe impl $crate::klib::containers::intrusive::Linked<$link> for $node {
+            fn link(&self) -> &$link {
+                &self.$field
+            }
+
+            unsafe fn from_link(link: *const $link) -> *const Self {
+                (link as *const u8).sub(core::mem::offset_of!($node, $field)) as *const Self
+            }
+        }
+    };
+}
+
+// IDs for containers, stored in the links of their nodes. 0 means not in any container.
+static NEXT_OWNER: AtomicUsize = AtomicUsize::new(1);
+
+// Containers get their ID the first time a node is added, so they can be made in a const context.
+fn owner_id(id: &mut usize) -> usize {
+    if *id == 0 {
+        *id = NEXT_OWNER.fetch_add(1, Ordering::Relaxed);
+    }
+    *id
+}
+
+// Mark a link as belonging to container `id`. Fails if it is in a container already.
+fn claim(owner: &AtomicUsize, id: usize) -> bool {
+    owner
+        .compare_exchange(0, id, Ordering::Acquire, Ordering::Relaxed)
+        .is_ok()
+}
+
+fn release(owner: &AtomicUsize) {
+    owner.store(0, Ordering::Release);
+}
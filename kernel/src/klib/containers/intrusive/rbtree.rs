@@ -0,0 +1,585 @@
+use super::{claim, owner_id, release, Linked};
+use core::cmp::Ordering;
+use core::marker::PhantomData;
+use core::ptr::null_mut;
+use core::sync::atomic::Ordering::Relaxed;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize};
+
+/// The link a node embeds for each `RbTree` it can be in.
+pub struct TreeLink {
+    owner: AtomicUsize,
+    parent: AtomicPtr<TreeLink>,
+    left: AtomicPtr<TreeLink>,
+    right: AtomicPtr<TreeLink>,
+    red: AtomicBool,
+}
+
+impl TreeLink {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            owner: AtomicUsize::new(0),
+            parent: AtomicPtr::new(null_mut()),
+            left: AtomicPtr::new(null_mut()),
+            right: AtomicPtr::new(null_mut()),
+            red: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether the node is in a tree.
+    pub fn is_linked(&self) -> bool {
+        self.owner.load(Relaxed) != 0
+    }
+}
+
+impl Default for TreeLink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A node that can go in an `RbTree`, which keeps its nodes sorted by key.
+pub trait TreeNode: Linked<TreeLink> {
+    type Key: Ord;
+
+    /// Must not change while the node is in a tree.
+    fn key(&self) -> Self::Key;
+}
+
+// Accessors for links that may be null. Only ever called on links of nodes in the tree.
+type Link = *mut TreeLink;
+
+fn parent(link: Link) -> Link {
+    unsafe { (*link).parent.load(Relaxed) }
+}
+
+fn left(link: Link) -> Link {
+    unsafe { (*link).left.load(Relaxed) }
+}
+
+fn right(link: Link) -> Link {
+    unsafe { (*link).right.load(Relaxed) }
+}
+
+fn set_parent(link: Link, parent: Link) {
+    if let Some(link) = unsafe { link.as_ref() } {
+        link.parent.store(parent, Relaxed);
+    }
+}
+
+fn set_left(link: Link, left: Link) {
+    unsafe { (*link).left.store(left, Relaxed) }
+}
+
+fn set_right(link: Link, right: Link) {
+    unsafe { (*link).right.store(right, Relaxed) }
+}
+
+// Null links count as black leaves
+fn is_red(link: Link) -> bool {
+    unsafe { link.as_ref() }.map_or(false, |link| link.red.load(Relaxed))
+}
+
+fn set_red(link: Link, red: bool) {
+    if let Some(link) = unsafe { link.as_ref() } {
+        link.red.store(red, Relaxed);
+    }
+}
+
+fn minimum(mut link: Link) -> Link {
+    while !left(link).is_null() {
+        link = left(link);
+    }
+    link
+}
+
+fn maximum(mut link: Link) -> Link {
+    while !right(link).is_null() {
+        link = right(link);
+    }
+    link
+}
+
+// The next link in key order, or null for the last one
+fn successor(mut link: Link) -> Link {
+    if !right(link).is_null() {
+        return minimum(right(link));
+    }
+
+    let mut up = parent(link);
+    while !up.is_null() && link == right(up) {
+        link = up;
+        up = parent(up);
+    }
+    up
+}
+
+/// A red-black tree of nodes that embed a `TreeLink`, sorted by `TreeNode::key`, e.g. timers by
+/// deadline or memory regions by start address. Adding, removing and looking up nodes takes
+/// logarithmic time. Nodes with equal keys are allowed, and kept in the order they were added.
+pub struct RbTree<'a, T: TreeNode> {
+    id: usize,
+    root: Link,
+    len: usize,
+    _marker: PhantomData<&'a T>,
+}
+
+impl<'a, T: TreeNode> RbTree<'a, T> {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            id: 0,
+            root: null_mut(),
+            len: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn node(link: Link) -> &'a T {
+        unsafe { &*T::from_link(link) }
+    }
+
+    fn node_or_none(link: Link) -> Option<&'a T> {
+        (!link.is_null()).then(|| Self::node(link))
+    }
+
+    fn link_ptr(node: &T) -> Link {
+        node.link() as *const TreeLink as Link
+    }
+
+    /// Whether `node` is in this tree.
+    pub fn contains(&self, node: &T) -> bool {
+        self.id != 0 && node.link().owner.load(Relaxed) == self.id
+    }
+
+    /// The node with the smallest key.
+    pub fn first(&self) -> Option<&'a T> {
+        (!self.root.is_null()).then(|| Self::node(minimum(self.root)))
+    }
+
+    /// The node with the largest key.
+    pub fn last(&self) -> Option<&'a T> {
+        (!self.root.is_null()).then(|| Self::node(maximum(self.root)))
+    }
+
+    /// A node with the given key.
+    pub fn get(&self, key: &T::Key) -> Option<&'a T> {
+        let mut link = self.root;
+        while !link.is_null() {
+            link = match key.cmp(&Self::node(link).key()) {
+                Ordering::Less => left(link),
+                Ordering::Greater => right(link),
+                Ordering::Equal => return Some(Self::node(link)),
+            };
+        }
+        None
+    }
+
+    /// The first node with a key of at least `key`.
+    pub fn lower_bound(&self, key: &T::Key) -> Option<&'a T> {
+        let mut link = self.root;
+        let mut found = null_mut();
+        while !link.is_null() {
+            if Self::node(link).key() >= *key {
+                found = link;
+                link = left(link);
+            } else {
+                link = right(link);
+            }
+        }
+        Self::node_or_none(found)
+    }
+
+    /// The last node with a key of at most `key`, e.g. the region that could contain an address.
+    pub fn floor(&self, key: &T::Key) -> Option<&'a T> {
+        let mut link = self.root;
+        let mut found = null_mut();
+        while !link.is_null() {
+            if Self::node(link).key() <= *key {
+                found = link;
+                link = right(link);
+            } else {
+                link = left(link);
+            }
+        }
+        Self::node_or_none(found)
+    }
+
+    /// Add a node to the tree. If it is already in a tree, it is handed back.
+    pub fn insert(&mut self, node: &'a T) -> Result<(), &'a T> {
+        let link = node.link();
+        if !claim(&link.owner, owner_id(&mut self.id)) {
+            return Err(node);
+        }
+
+        let new = Self::link_ptr(node);
+        link.left.store(null_mut(), Relaxed);
+        link.right.store(null_mut(), Relaxed);
+        link.red.store(true, Relaxed);
+
+        let key = node.key();
+        let mut up = null_mut();
+        let mut goes_left = false;
+        let mut current = self.root;
+        while !current.is_null() {
+            up = current;
+            goes_left = key < Self::node(current).key();
+            current = if goes_left {
+                left(current)
+            } else {
+                right(current)
+            };
+        }
+
+        link.parent.store(up, Relaxed);
+        if up.is_null() {
+            self.root = new;
+        } else if goes_left {
+            set_left(up, new);
+        } else {
+            set_right(up, new);
+        }
+
+        self.insert_fixup(new);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Remove and return the node with the smallest key.
+    pub fn pop_first(&mut self) -> Option<&'a T> {
+        let node = self.first()?;
+        self.unlink(Self::link_ptr(node));
+        Some(node)
+    }
+
+    /// Take a node out of the tree. Returns false if it wasn't in this tree.
+    pub fn remove(&mut self, node: &T) -> bool {
+        if !self.contains(node) {
+            return false;
+        }
+
+        self.unlink(Self::link_ptr(node));
+        true
+    }
+
+    /// The nodes in key order.
+    pub fn iter(&self) -> Iter<'_, 'a, T> {
+        Iter {
+            next: if self.root.is_null() {
+                null_mut()
+            } else {
+                minimum(self.root)
+            },
+            remaining: self.len,
+            _tree: PhantomData,
+        }
+    }
+
+    /// Take every node out of the tree.
+    pub fn clear(&mut self) {
+        if !self.root.is_null() {
+            // Only the owners are reset, so the links can still be followed
+            let mut link = minimum(self.root);
+            while !link.is_null() {
+                let next = successor(link);
+                release(unsafe { &(*link).owner });
+                link = next;
+            }
+        }
+
+        self.root = null_mut();
+        self.len = 0;
+    }
+
+    /// Check that the tree is still a red-black tree: the root is black, no red node has a red
+    /// child, every path down has as many black nodes, and the links and keys agree.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if is_red(self.root) {
+            return Err("the root is red");
+        }
+        if !self.root.is_null() && !parent(self.root).is_null() {
+            return Err("the root has a parent");
+        }
+        self.black_height(self.root)?;
+
+        let mut count = 0;
+        let mut link = self.root;
+        let mut last = None;
+        if !link.is_null() {
+            link = minimum(link);
+        }
+        while !link.is_null() {
+            let node = Self::node(link);
+            if !self.contains(node) {
+                return Err("a node belongs to some other container");
+            }
+            let key = node.key();
+            if last.as_ref().is_some_and(|last| *last > key) {
+                return Err("the keys are out of order");
+            }
+            last = Some(key);
+            count += 1;
+            link = successor(link);
+        }
+        match count == self.len {
+            true => Ok(()),
+            false => Err("the length is wrong"),
+        }
+    }
+
+    // How many black nodes there are on every path down from `link`, counting the null leaves
+    fn black_height(&self, link: Link) -> Result<usize, &'static str> {
+        if link.is_null() {
+            return Ok(1);
+        }
+        for child in [left(link), right(link)] {
+            if !child.is_null() && parent(child) != link {
+                return Err("a child doesn't point back at its parent");
+            }
+            if is_red(link) && is_red(child) {
+                return Err("a red node has a red child");
+            }
+        }
+
+        let height = self.black_height(left(link))?;
+        if self.black_height(right(link))? != height {
+            return Err("the black heights of two subtrees differ");
+        }
+        Ok(height + !is_red(link) as usize)
+    }
+
+    fn rotate_left(&mut self, link: Link) {
+        let child = right(link);
+        set_right(link, left(child));
+        set_parent(left(child), link);
+        self.replace_child(link, child);
+        set_left(child, link);
+        set_parent(link, child);
+    }
+
+    fn rotate_right(&mut self, link: Link) {
+        let child = left(link);
+        set_left(link, right(child));
+        set_parent(right(child), link);
+        self.replace_child(link, child);
+        set_right(child, link);
+        set_parent(link, child);
+    }
+
+    // Put `new` (which may be null) where `old` is in the tree, as far as old's parent is
+    // concerned. Old's own children are left alone.
+    fn replace_child(&mut self, old: Link, new: Link) {
+        let up = parent(old);
+        if up.is_null() {
+            self.root = new;
+        } else if old == left(up) {
+            set_left(up, new);
+        } else {
+            set_right(up, new);
+        }
+        set_parent(new, up);
+    }
+
+    fn insert_fixup(&mut self, mut link: Link) {
+        while is_red(parent(link)) {
+            // The parent is red, so it isn't the root and there is a grandparent
+            let mut up = parent(link);
+            let grandparent = parent(up);
+
+            if up == left(grandparent) {
+                let uncle = right(grandparent);
+                if is_red(uncle) {
+                    set_red(up, false);
+                    set_red(uncle, false);
+                    set_red(grandparent, true);
+                    link = grandparent;
+                    continue;
+                }
+
+                if link == right(up) {
+                    link = up;
+                    self.rotate_left(link);
+                    up = parent(link);
+                }
+                set_red(up, false);
+                set_red(grandparent, true);
+                self.rotate_right(grandparent);
+            } else {
+                let uncle = left(grandparent);
+                if is_red(uncle) {
+                    set_red(up, false);
+                    set_red(uncle, false);
+                    set_red(grandparent, true);
+                    link = grandparent;
+                    continue;
+                }
+
+                if link == left(up) {
+                    link = up;
+                    self.rotate_right(link);
+                    up = parent(link);
+                }
+                set_red(up, false);
+                set_red(grandparent, true);
+                self.rotate_left(grandparent);
+            }
+        }
+
+        set_red(self.root, false);
+    }
+
+    fn unlink(&mut self, link: Link) {
+        // `child` takes the place of whichever node is taken out of its spot in the tree, and
+        // may be null, so its parent is tracked separately.
+        let mut removed_red = is_red(link);
+        let child;
+        let child_parent;
+
+        if left(link).is_null() {
+            child = right(link);
+            child_parent = parent(link);
+            self.replace_child(link, child);
+        } else if right(link).is_null() {
+            child = left(link);
+            child_parent = parent(link);
+            self.replace_child(link, child);
+        } else {
+            // Two children: the successor, which has no left child, takes its place
+            let next = minimum(right(link));
+            removed_red = is_red(next);
+            child = right(next);
+
+            if parent(next) == link {
+                child_parent = next;
+            } else {
+                child_parent = parent(next);
+                self.replace_child(next, child);
+                set_right(next, right(link));
+                set_parent(right(next), next);
+            }
+
+            self.replace_child(link, next);
+            set_left(next, left(link));
+            set_parent(left(next), next);
+            set_red(next, is_red(link));
+        }
+
+        if !removed_red {
+            self.remove_fixup(child, child_parent);
+        }
+
+        unsafe {
+            (*link).parent.store(null_mut(), Relaxed);
+            (*link).left.store(null_mut(), Relaxed);
+            (*link).right.store(null_mut(), Relaxed);
+            release(&(*link).owner);
+        }
+        self.len -= 1;
+    }
+
+    // `link` is short a black node on its path. A black node was removed, so its sibling can't
+    // be a null leaf.
+    fn remove_fixup(&mut self, mut link: Link, mut up: Link) {
+        while link != self.root && !is_red(link) {
+            if link == left(up) {
+                let mut sibling = right(up);
+                if is_red(sibling) {
+                    set_red(sibling, false);
+                    set_red(up, true);
+                    self.rotate_left(up);
+                    sibling = right(up);
+                }
+
+                if !is_red(left(sibling)) && !is_red(right(sibling)) {
+                    set_red(sibling, true);
+                    link = up;
+                    up = parent(link);
+                    continue;
+                }
+
+                if !is_red(right(sibling)) {
+                    set_red(left(sibling), false);
+                    set_red(sibling, true);
+                    self.rotate_right(sibling);
+                    sibling = right(up);
+                }
+                set_red(sibling, is_red(up));
+                set_red(up, false);
+                set_red(right(sibling), false);
+                self.rotate_left(up);
+            } else {
+                let mut sibling = left(up);
+                if is_red(sibling) {
+                    set_red(sibling, false);
+                    set_red(up, true);
+                    self.rotate_right(up);
+                    sibling = left(up);
+                }
+
+                if !is_red(left(sibling)) && !is_red(right(sibling)) {
+                    set_red(sibling, true);
+                    link = up;
+                    up = parent(link);
+                    continue;
+                }
+
+                if !is_red(left(sibling)) {
+                    set_red(right(sibling), false);
+                    set_red(sibling, true);
+                    self.rotate_left(sibling);
+                    sibling = left(up);
+                }
+                set_red(sibling, is_red(up));
+                set_red(up, false);
+                set_red(left(sibling), false);
+                self.rotate_right(up);
+            }
+
+            link = self.root;
+        }
+
+        set_red(link, false);
+    }
+}
+
+impl<'a, T: TreeNode> Drop for RbTree<'a, T> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+// The links are only changed through a &mut RbTree
+unsafe impl<'a, T: TreeNode + Sync> Send for RbTree<'a, T> {}
+unsafe impl<'a, T: TreeNode + Sync> Sync for RbTree<'a, T> {}
+
+pub struct Iter<'t, 'a, T: TreeNode> {
+    next: Link,
+    remaining: usize,
+    _tree: PhantomData<&'t RbTree<'a, T>>,
+}
+
+impl<'t, 'a, T: TreeNode> Iterator for Iter<'t, 'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let link = self.next;
+        let node = RbTree::<'a, T>::node_or_none(link)?;
+        self.next = successor(link);
+        self.remaining -= 1;
+        Some(node)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'t, 'a, T: TreeNode> ExactSizeIterator for Iter<'t, 'a, T> {}
@@ -1 +1,4 @@
 pub mod circular_buffer;
+pub mod intrusive;
+pub mod static_string;
+pub mod static_vec;
@@ -0,0 +1,100 @@
+use core::fmt;
+use core::ops::Deref;
+
+/// A string with a fixed capacity of N bytes, stored inline rather than on the heap, so it can
+/// be formatted into before the heap is up or inside of interrupt handlers. Whatever doesn't fit
+/// is cut off, always at a character boundary.
+#[derive(Clone, Copy)]
+pub struct StaticString<const N: usize> {
+    len: usize,
+    bytes: [u8; N],
+}
+
+impl<const N: usize> StaticString<N> {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            len: 0,
+            bytes: [0; N],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only whole characters are ever copied in, see push_str
+        unsafe { core::str::from_utf8_unchecked(&self.bytes[..self.len]) }
+    }
+
+    /// Append as much of `s` as fits. Returns whether all of it did.
+    pub fn push_str(&mut self, s: &str) -> bool {
+        let mut count = s.len().min(N - self.len);
+        while !s.is_char_boundary(count) {
+            count -= 1;
+        }
+
+        self.bytes[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        count == s.len()
+    }
+
+    /// Append a character if it fits. Returns whether it did.
+    pub fn push(&mut self, c: char) -> bool {
+        let mut buf = [0; 4];
+        let encoded = c.encode_utf8(&mut buf);
+        if encoded.len() > N - self.len {
+            return false;
+        }
+
+        self.push_str(encoded)
+    }
+
+    /// Remove and return the last character.
+    pub fn pop(&mut self) -> Option<char> {
+        let c = self.as_str().chars().next_back()?;
+        self.len -= c.len_utf8();
+        Some(c)
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> Deref for StaticString<N> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<const N: usize> fmt::Write for StaticString<N> {
+    /// Never fails, so a message that is too long still gets as much of it in as fits.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
+
+impl<const N: usize> fmt::Display for StaticString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<const N: usize> fmt::Debug for StaticString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
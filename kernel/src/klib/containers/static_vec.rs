@@ -0,0 +1,115 @@
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+
+/// A vector with a fixed capacity of N, stored inline rather than on the heap. For lists that
+/// have to be built before the heap is up or inside of interrupt handlers, which can't allocate.
+pub struct StaticVec<T, const N: usize> {
+    len: usize,
+    items: [MaybeUninit<T>; N],
+}
+
+impl<T, const N: usize> StaticVec<T, N> {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            len: 0,
+            // An array of MaybeUninit doesn't need initializing
+            items: unsafe { MaybeUninit::uninit().assume_init() },
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Add an item to the end. If there is no room, the item is handed back.
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(item);
+        }
+
+        self.items[self.len].write(item);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Remove and return the last item.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+
+        self.len -= 1;
+        Some(unsafe { self.items[self.len].assume_init_read() })
+    }
+
+    /// Drop every item past the first `len`.
+    pub fn truncate(&mut self, len: usize) {
+        while self.len > len {
+            drop(self.pop());
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { MaybeUninit::slice_assume_init_ref(&self.items[..self.len]) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        unsafe { MaybeUninit::slice_assume_init_mut(&mut self.items[..self.len]) }
+    }
+}
+
+impl<T, const N: usize> Deref for StaticVec<T, N> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T, const N: usize> DerefMut for StaticVec<T, N> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.as_mut_slice()
+    }
+}
+
+impl<T: Clone, const N: usize> Clone for StaticVec<T, N> {
+    fn clone(&self) -> Self {
+        let mut copy = Self::new();
+        for item in self.iter() {
+            let _ = copy.push(item.clone());
+        }
+        copy
+    }
+}
+
+impl<'a, T, const N: usize> IntoIterator for &'a StaticVec<T, N> {
+    type Item = &'a T;
+    type IntoIter = core::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<T, const N: usize> Drop for StaticVec<T, N> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
@@ -0,0 +1,320 @@
+use crate::allocator;
+use crate::arch::x86_64::cpu;
+use crate::klib::ahci::ahcistate::SataDevice;
+use crate::klib::block::{BlockDevice, IOError};
+use crate::klib::log;
+use crate::klib::once_lock::OnceLock;
+use crate::KERNEL_PAGETABLE;
+use crate::TIMER;
+use crate::{print, println};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::fmt::Write;
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::registers::rflags;
+use x86_64::structures::paging::Translate;
+use x86_64::VirtAddr;
+
+const MAGIC: [u8; 8] = *b"PANODUMP";
+const VERSION: u32 = 1;
+
+/// Bytes reserved at the very end of the disk for the dump, header included.
+pub const DUMP_SIZE: usize = 64 * 1024;
+
+const PAGE_SIZE: usize = 4096;
+
+// Stack frames to follow before giving up, in case the chain loops
+const MAX_FRAMES: usize = 32;
+
+// The dump starts with one sector of header, all little endian:
+//   0   magic
+//   8   version
+//   12  length of the text that follows the header
+//   16  FNV-1a hash of that text
+//   24  milliseconds since boot at the time of the crash
+// The text is UTF-8, split into sections by "[name]" lines. The runner knows this layout too.
+const HEADER_SIZE: usize = 512;
+
+/// A dump read back from the disk.
+pub struct Dump {
+    pub uptime_ms: u64,
+    pub text: String,
+}
+
+struct DumpRegion {
+    disk: SataDevice,
+    offset: usize,
+}
+
+static REGION: OnceLock<DumpRegion> = OnceLock::new();
+
+// Set once the kernel starts dumping, so that a panic while dumping doesn't try again
+static DUMPING: AtomicBool = AtomicBool::new(false);
+
+// The dump is put together here rather than on the heap, which may be what broke. Only its pages
+// are physically contiguous, so it is written a page at a time.
+#[repr(align(4096))]
+struct DumpBuffer {
+    bytes: [u8; DUMP_SIZE],
+}
+
+static mut BUFFER: DumpBuffer = DumpBuffer {
+    bytes: [0; DUMP_SIZE],
+};
+
+#[repr(align(4096))]
+struct Page {
+    bytes: [MaybeUninit<u8>; PAGE_SIZE],
+}
+
+/// Reserve the last `DUMP_SIZE` bytes of `disk` for crash dumps. `used_bytes` is how much of
+/// the start of the disk is already spoken for, e.g. by the filesystem; fails if they overlap.
+pub fn init(disk: SataDevice, used_bytes: usize) -> Result<(), ()> {
+    let disk_size = disk.num_blocks() * disk.block_size();
+    if disk_size < used_bytes + DUMP_SIZE {
+        return Err(());
+    }
+
+    REGION
+        .set(DumpRegion {
+            disk,
+            offset: disk_size - DUMP_SIZE,
+        })
+        .map_err(|_| ())
+}
+
+pub fn enabled() -> bool {
+    REGION.get().is_some()
+}
+
+/// Called from the panic handler, with interrupts off: write out the panic message, registers,
+/// a backtrace, memory statistics and the recent log. Best effort; if the disk is busy, nothing
+/// is written.
+pub fn write(info: &PanicInfo) -> Result<(), ()> {
+    if DUMPING.swap(true, Ordering::SeqCst) {
+        return Err(());
+    }
+    let region = REGION.get().ok_or(())?;
+
+    let buffer = unsafe { &mut *addr_of_mut!(BUFFER) };
+    let (header, body) = buffer.bytes.split_at_mut(HEADER_SIZE);
+
+    let mut writer = Writer { buf: body, len: 0 };
+    // Whatever didn't fit is cut off; the rest is still worth having
+    let _ = write_sections(&mut writer, info);
+    let length = writer.len;
+
+    header.fill(0);
+    header[0..8].copy_from_slice(&MAGIC);
+    header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+    header[12..16].copy_from_slice(&(length as u32).to_le_bytes());
+    header[16..20].copy_from_slice(&fnv1a(&body[..length]).to_le_bytes());
+    header[24..32].copy_from_slice(&TIMER.load(Ordering::SeqCst).to_le_bytes());
+
+    // If the panic happened with the disk locked, its state can't be trusted anyway
+    let mut port = region.disk.port.try_write().ok_or(())?;
+
+    let total = (HEADER_SIZE + length).next_multiple_of(region.disk.block_size());
+    for start in (0..total).step_by(PAGE_SIZE) {
+        let end = (start + PAGE_SIZE).min(total);
+        unsafe {
+            port.write_polled(
+                region.disk.pmp,
+                &buffer.bytes[start..end],
+                region.offset + start,
+            )
+        }
+        .map_err(|_| ())?;
+    }
+
+    Ok(())
+}
+
+/// Read back the dump left by an earlier crash. Returns None if there isn't one.
+pub fn read() -> Result<Option<Dump>, IOError> {
+    let region = REGION.get().ok_or(IOError::Invalid)?;
+    let mut page = Box::new(Page {
+        bytes: [MaybeUninit::uninit(); PAGE_SIZE],
+    });
+
+    let first = region.disk.read(&mut page.bytes, region.offset)?;
+    if first[0..8] != MAGIC || u32::from_le_bytes(first[8..12].try_into().unwrap()) != VERSION {
+        return Ok(None);
+    }
+
+    let length = u32::from_le_bytes(first[12..16].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(first[16..20].try_into().unwrap());
+    let uptime_ms = u64::from_le_bytes(first[24..32].try_into().unwrap());
+    if length > DUMP_SIZE - HEADER_SIZE {
+        return Err(IOError::BadData);
+    }
+
+    let mut text = Vec::with_capacity(length);
+    text.extend_from_slice(&first[HEADER_SIZE..(HEADER_SIZE + length).min(PAGE_SIZE)]);
+
+    let mut offset = PAGE_SIZE;
+    while text.len() < length {
+        let data = region.disk.read(&mut page.bytes, region.offset + offset)?;
+        let wanted = (length - text.len()).min(PAGE_SIZE);
+        text.extend_from_slice(&data[..wanted]);
+        offset += PAGE_SIZE;
+    }
+
+    if fnv1a(&text) != checksum {
+        return Err(IOError::BadData);
+    }
+
+    Ok(Some(Dump {
+        uptime_ms,
+        text: String::from_utf8_lossy(&text).into_owned(),
+    }))
+}
+
+/// Forget the last dump, by wiping its header.
+pub fn clear() -> Result<(), IOError> {
+    let region = REGION.get().ok_or(IOError::Invalid)?;
+    let zeroes = alloc::vec![0u8; region.disk.block_size()];
+    region.disk.write(&zeroes, region.offset)
+}
+
+fn write_sections(w: &mut Writer, info: &PanicInfo) -> fmt::Result {
+    let (rsp, rbp) = (cpu::read_rsp(), cpu::read_rbp());
+
+    writeln!(w, "{}", info)?;
+
+    // These are the panic handler's, not those of whatever faulted, but they still say which
+    // stack and address space it happened on.
+    writeln!(w, "\n[registers]")?;
+    writeln!(
+        w,
+        "rsp {:#018x}  rbp {:#018x}  rflags {:#010x}",
+        rsp,
+        rbp,
+        rflags::read_raw()
+    )?;
+    writeln!(
+        w,
+        "cr0 {:#018x}  cr2 {:#018x}",
+        Cr0::read_raw(),
+        Cr2::read().as_u64()
+    )?;
+    writeln!(
+        w,
+        "cr3 {:#018x}  cr4 {:#018x}",
+        Cr3::read().0.start_address().as_u64(),
+        Cr4::read_raw()
+    )?;
+
+    writeln!(w, "\n[backtrace]")?;
+    backtrace(w, rbp)?;
+
+    writeln!(w, "\n[memory]")?;
+    match allocator::free_bytes() {
+        Some(free) => writeln!(
+            w,
+            "heap {} KiB free of {} KiB",
+            free / 1024,
+            allocator::HEAP_SIZE / 1024
+        )?,
+        None => writeln!(w, "heap allocator was locked")?,
+    }
+
+    writeln!(w, "\n[log]")?;
+    log::write_recent(w, log::NUM_RECORDS)?;
+
+    Ok(())
+}
+
+/// Print the return addresses of the calls that led here to the console. Doesn't allocate or take
+/// any locks, so it works while panicking.
+#[inline(never)]
+pub fn print_backtrace() {
+    print_backtrace_from(cpu::read_rbp());
+}
+
+/// Like `print_backtrace`, but starting from the frame `rbp` points at, e.g. that of the code an
+/// exception interrupted.
+pub fn print_backtrace_from(rbp: u64) {
+    println!("Backtrace:");
+    let _ = backtrace(&mut Console, rbp);
+}
+
+// Follow the chain of saved frame pointers, printing each return address.
+fn backtrace(w: &mut impl Write, mut rbp: u64) -> fmt::Result {
+    for _ in 0..MAX_FRAMES {
+        if rbp == 0 || rbp % 8 != 0 || !is_mapped(rbp) || !is_mapped(rbp + 8) {
+            break;
+        }
+
+        let next = unsafe { (rbp as *const u64).read() };
+        let return_address = unsafe { ((rbp + 8) as *const u64).read() };
+        if return_address == 0 {
+            break;
+        }
+        writeln!(w, "  {:#018x}", return_address)?;
+
+        // Callers' frames are always further up the stack
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+
+    Ok(())
+}
+
+/// Whether `addr` is mapped in the kernel's page table. Doesn't wait for the page table lock; if
+/// it's taken, nothing counts as mapped.
+pub fn is_mapped(addr: u64) -> bool {
+    let Ok(addr) = VirtAddr::try_new(addr) else {
+        return false;
+    };
+    let Some(page_table) = KERNEL_PAGETABLE.get().and_then(|lock| lock.try_read()) else {
+        return false;
+    };
+
+    page_table.translate_addr(addr).is_some()
+}
+
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811C_9DC5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+// Formats into a fixed buffer, dropping whatever doesn't fit.
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Writer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = self.buf.len() - self.len;
+        let count = s.len().min(room);
+        self.buf[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+
+        if count < s.len() {
+            Err(fmt::Error)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+// Writes straight to the console.
+struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        print!("{}", s);
+        Ok(())
+    }
+}
@@ -0,0 +1,308 @@
+// Benchmarks and stress tests for block devices, behind the shell's `bench` and `stress`. Requests
+// go through the device's `submit_read` and `submit_write`, so with a request queue in front of it
+// (see iosched.rs) up to `depth` of them are in flight at once, for the disk to work on several at
+// a time with NCQ. How much was moved is measured against the uptime, and how long each request
+// took in TSC cycles, which the length of the run in milliseconds turns into microseconds.
+//
+// What the stress test writes is worked out from a seed and where on the disk it goes, so it can
+// be checked when it's read back without keeping a copy around: a block that comes back different
+// was corrupted (or written somewhere else) on the way.
+
+use super::block::BlockDevice;
+use super::clock;
+use super::iosched;
+use super::rand::Xorshift;
+use crate::arch::x86_64::interrupts::without_interrupts;
+use crate::arch::{Arch, Cpu};
+use crate::task::WaitQueue;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Most bytes one request of the stress test moves.
+pub const MAX_REQUEST_SIZE: usize = 1024 * 1024;
+
+// Latencies kept for working out percentiles. Past this many requests, each one replaces a kept
+// one at random, so the ones kept stay a fair sample.
+const MAX_LATENCIES: usize = 16384;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Sequential,
+    Random,
+}
+
+pub struct BenchOptions {
+    pub access: Access,
+    pub write: bool,
+    /// Requests in flight at once
+    pub depth: usize,
+    /// Bytes per request, a multiple of the device's block size
+    pub request_size: usize,
+    pub duration_ms: u64,
+    /// Where the random requests go, and what is written
+    pub seed: u64,
+}
+
+pub struct StressOptions {
+    pub depth: usize,
+    /// Bytes from the start of the device to write and check
+    pub size: usize,
+    pub passes: usize,
+    pub seed: u64,
+}
+
+/// How a run went.
+#[derive(Default)]
+pub struct Report {
+    pub requests: u64,
+    pub bytes: u64,
+    pub errors: u64,
+    /// Blocks read back that weren't what was written, and the first of them
+    pub mismatches: u64,
+    pub first_mismatch: Option<usize>,
+    pub elapsed_ms: u64,
+    // Sorted, in microseconds
+    latencies: Vec<u64>,
+}
+
+impl Report {
+    /// KiB moved per second.
+    pub fn kib_per_sec(&self) -> u64 {
+        self.bytes * 1000 / 1024 / self.elapsed_ms.max(1)
+    }
+
+    pub fn iops(&self) -> u64 {
+        self.requests * 1000 / self.elapsed_ms.max(1)
+    }
+
+    /// The latency in microseconds that `percent` percent of the requests took at most.
+    pub fn percentile(&self, percent: usize) -> Option<u64> {
+        let last = self.latencies.len().checked_sub(1)?;
+        Some(self.latencies[last * percent.min(100) / 100])
+    }
+
+    fn add(&mut self, other: Report) {
+        self.requests += other.requests;
+        self.bytes += other.bytes;
+        self.errors += other.errors;
+        self.mismatches += other.mismatches;
+        self.first_mismatch = self.first_mismatch.or(other.first_mismatch);
+        self.elapsed_ms += other.elapsed_ms;
+        self.latencies.extend(other.latencies);
+        self.latencies.sort_unstable();
+    }
+}
+
+struct Request {
+    lba: usize,
+    count: usize,
+    write: bool,
+}
+
+struct Progress {
+    in_flight: usize,
+    report: Report,
+    // In TSC cycles until the run is over
+    latencies: Vec<u64>,
+    rng: Xorshift,
+}
+
+struct Shared {
+    progress: Mutex<Progress>,
+    done: WaitQueue,
+}
+
+/// Move data to or from `device` as fast as it goes for `options.duration_ms`. Reads aren't
+/// checked, as there's no telling what was on the disk. Fails if the requests don't fit the
+/// device.
+pub fn bench(device: &Arc<dyn BlockDevice>, options: &BenchOptions) -> Result<Report, ()> {
+    let count = blocks_per_request(device.as_ref(), options.request_size)?;
+    // Requests start on multiples of their size, so that random ones don't overlap
+    let slots = device.num_blocks() / count;
+
+    let mut rng = Xorshift::new(options.seed);
+    let mut next_slot = 0;
+    let started = clock::uptime_ms();
+    let next = || {
+        if clock::uptime_ms() - started >= options.duration_ms {
+            return None;
+        }
+        let slot = match options.access {
+            Access::Sequential => {
+                let slot = next_slot;
+                next_slot = (next_slot + 1) % slots;
+                slot
+            }
+            Access::Random => rng.below(slots as u64) as usize,
+        };
+        Some(Request {
+            lba: slot * count,
+            count,
+            write: options.write,
+        })
+    };
+
+    Ok(run(device, options.depth, options.seed, false, next))
+}
+
+/// Write patterns over the first `options.size` bytes of `device` and read them back, checking
+/// every block, `options.passes` times over. Each pass uses a different pattern and cuts the
+/// region into requests of random sizes, issued in random order. Fails if the region doesn't fit
+/// the device.
+pub fn stress(device: &Arc<dyn BlockDevice>, options: &StressOptions) -> Result<Report, ()> {
+    let block_size = device.block_size();
+    let blocks = blocks_per_request(device.as_ref(), options.size)?;
+    let max_count = MAX_REQUEST_SIZE / block_size;
+
+    let mut rng = Xorshift::new(options.seed);
+    let mut report = Report::default();
+    for pass in 0..options.passes {
+        let seed = options.seed.wrapping_add(pass as u64);
+        for write in [true, false] {
+            let mut requests = Vec::new();
+            let mut lba = 0;
+            while lba < blocks {
+                let count = (1 + rng.below(max_count as u64) as usize).min(blocks - lba);
+                requests.push(Request { lba, count, write });
+                lba += count;
+            }
+            rng.shuffle(&mut requests);
+
+            let mut requests = requests.into_iter();
+            let next = || requests.next();
+            report.add(run(device, options.depth, seed, true, next));
+        }
+    }
+    Ok(report)
+}
+
+// How many blocks of `device` make `size` bytes
+fn blocks_per_request(device: &dyn BlockDevice, size: usize) -> Result<usize, ()> {
+    let block_size = device.block_size();
+    if size == 0 || size % block_size != 0 || size > device.num_blocks() * block_size {
+        return Err(());
+    }
+    Ok(size / block_size)
+}
+
+// Issue what `next` comes up with, keeping up to `depth` requests in flight, until it comes up
+// with nothing. Writes write the pattern for `seed`, and with `verify` reads are checked against
+// it.
+fn run(
+    device: &Arc<dyn BlockDevice>,
+    depth: usize,
+    seed: u64,
+    verify: bool,
+    mut next: impl FnMut() -> Option<Request>,
+) -> Report {
+    let block_size = device.block_size();
+    // More than this and the queue turns requests away
+    let depth = depth.clamp(1, iosched::MAX_PER_SUBMITTER);
+    let shared = Arc::new(Shared {
+        progress: Mutex::new(Progress {
+            in_flight: 0,
+            report: Report::default(),
+            latencies: Vec::new(),
+            rng: Xorshift::new(seed),
+        }),
+        done: WaitQueue::new(),
+    });
+
+    let started_ms = clock::uptime_ms();
+    let started_cycles = Arch::timestamp();
+    loop {
+        shared
+            .done
+            .wait_while(|| shared.progress.lock().in_flight >= depth);
+        let Some(request) = next() else {
+            break;
+        };
+        without_interrupts(|| shared.progress.lock().in_flight += 1);
+
+        let bytes = request.count * block_size;
+        let issued = Arch::timestamp();
+        let future = if request.write {
+            let mut data = alloc::vec![0; bytes];
+            for (i, block) in data.chunks_exact_mut(block_size).enumerate() {
+                fill(block, seed, request.lba + i);
+            }
+            device.submit_write(request.lba, data)
+        } else {
+            device.submit_read(request.lba, request.count)
+        };
+
+        let shared = shared.clone();
+        future.then(move |result| {
+            let latency = Arch::timestamp() - issued;
+            let mismatch = match &result {
+                Ok(data) if verify && !request.write => {
+                    find_mismatch(data, block_size, seed, request.lba)
+                }
+                _ => None,
+            };
+
+            without_interrupts(|| {
+                let progress = &mut *shared.progress.lock();
+                let report = &mut progress.report;
+                progress.in_flight -= 1;
+                report.requests += 1;
+                match result {
+                    Ok(_) => report.bytes += bytes as u64,
+                    Err(_) => report.errors += 1,
+                }
+                if let Some(lba) = mismatch {
+                    report.mismatches += 1;
+                    report.first_mismatch = report.first_mismatch.or(Some(lba));
+                }
+
+                let seen = report.requests as usize;
+                if seen <= MAX_LATENCIES {
+                    progress.latencies.push(latency);
+                } else {
+                    let slot = progress.rng.below(seen as u64) as usize;
+                    if slot < MAX_LATENCIES {
+                        progress.latencies[slot] = latency;
+                    }
+                }
+            });
+            shared.done.wake_all();
+        });
+    }
+    shared
+        .done
+        .wait_while(|| shared.progress.lock().in_flight > 0);
+
+    let elapsed_ms = clock::uptime_ms() - started_ms;
+    let cycles_per_ms = ((Arch::timestamp() - started_cycles) / elapsed_ms.max(1)).max(1);
+    let (mut report, latencies) = without_interrupts(|| {
+        let mut progress = shared.progress.lock();
+        let latencies = core::mem::take(&mut progress.latencies);
+        (core::mem::take(&mut progress.report), latencies)
+    });
+
+    report.elapsed_ms = elapsed_ms;
+    report.latencies = latencies
+        .iter()
+        .map(|cycles| cycles * 1000 / cycles_per_ms)
+        .collect();
+    report.latencies.sort_unstable();
+    report
+}
+
+// What block `lba` holds for `seed`
+fn fill(block: &mut [u8], seed: u64, lba: usize) {
+    let mut rng = Xorshift::new(seed ^ (lba as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+    for word in block.chunks_exact_mut(8) {
+        word.copy_from_slice(&rng.next_u64().to_le_bytes());
+    }
+}
+
+// The first block of `data`, read from `lba` on, that isn't what `fill` would put there
+fn find_mismatch(data: &[u8], block_size: usize, seed: u64, lba: usize) -> Option<usize> {
+    let mut expected = alloc::vec![0; block_size];
+    data.chunks(block_size).enumerate().find_map(|(i, block)| {
+        fill(&mut expected, seed, lba + i);
+        (block != expected.as_slice()).then_some(lba + i)
+    })
+}
@@ -0,0 +1,137 @@
+use crate::arch::x86_64::nx;
+use crate::BootInfoFrameAllocator;
+use crate::KERNEL_PAGETABLE;
+use alloc::alloc::{alloc_zeroed, dealloc};
+use core::alloc::Layout;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Memory for a structure the device reads or writes on its own, such as a command list or a
+/// received FIS area. Unlike a `Box` from the heap, the memory is physically contiguous, aligned
+/// to what `T` asks for (at least a page), and mapped uncached at its physical address, so device
+/// addresses for any part of it can be worked out with `phys_addr_of`.
+///
+/// The frames are never given back, so this is for descriptors set up once per device.
+pub struct DmaBox<T> {
+    ptr: *mut T,
+    _marker: PhantomData<T>,
+}
+
+impl<T> DmaBox<T> {
+    /// ### Safety
+    /// All zeroes has to be a valid `T`.
+    pub unsafe fn new_zeroed(frame_allocator: &mut BootInfoFrameAllocator) -> Result<Self, ()> {
+        let num_pages = (size_of::<T>() as u64).div_ceil(PAGE_SIZE).max(1);
+        let align = (align_of::<T>() as u64).max(PAGE_SIZE);
+
+        let first_frame = frame_allocator
+            .allocate_contiguous(num_pages as usize, align)
+            .ok_or(())?;
+        let phys_addr = first_frame.start_address().as_u64();
+
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::NO_EXECUTE;
+        nx::check(flags);
+
+        {
+            let mut page_table = KERNEL_PAGETABLE.get().ok_or(())?.write();
+
+            for i in 0..num_pages {
+                let frame: PhysFrame<Size4KiB> = first_frame + i;
+                let page = Page::containing_address(VirtAddr::new(phys_addr + i * PAGE_SIZE));
+
+                match page_table.map_to(page, frame, flags, frame_allocator) {
+                    Ok(flush) => flush.flush(),
+                    // Someone else already identity mapped it, which is just as good
+                    Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => {}
+                    Err(_) => return Err(()),
+                }
+            }
+        }
+
+        let ptr = phys_addr as *mut T;
+        core::ptr::write_bytes(ptr as *mut u8, 0, (num_pages * PAGE_SIZE) as usize);
+
+        Ok(Self {
+            ptr,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn phys_addr(&self) -> u64 {
+        self.ptr as u64
+    }
+
+    /// The address the device should use for `field`, which has to point into this box.
+    pub fn phys_addr_of<U>(&self, field: *const U) -> u64 {
+        let offset = field as u64 - self.ptr as u64;
+        debug_assert!(offset < size_of::<T>() as u64);
+
+        self.phys_addr() + offset
+    }
+}
+
+impl<T> Deref for DmaBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.ptr }
+    }
+}
+
+impl<T> DerefMut for DmaBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.ptr }
+    }
+}
+
+unsafe impl<T: Send> Send for DmaBox<T> {}
+unsafe impl<T: Sync> Sync for DmaBox<T> {}
+
+/// A page aligned buffer from the heap, for transfers to or from memory a device can't use
+/// directly (e.g. a buffer that isn't aligned). Unlike a `DmaBox` it is only virtually
+/// contiguous, so every page of it may need a descriptor of its own.
+pub struct BounceBuffer {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+impl BounceBuffer {
+    /// Returns `None` if the heap is out of memory.
+    pub fn new(len: usize) -> Option<Self> {
+        let layout = Layout::from_size_align(len.max(1), PAGE_SIZE as usize).ok()?;
+        let ptr = NonNull::new(unsafe { alloc_zeroed(layout) })?;
+        Some(Self { ptr, layout })
+    }
+}
+
+impl Deref for BounceBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl DerefMut for BounceBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for BounceBuffer {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+unsafe impl Send for BounceBuffer {}
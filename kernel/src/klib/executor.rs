@@ -0,0 +1,153 @@
+// A small executor for async kernel code, so that a driver can await what an interrupt finishes
+// (e.g. `device.submit_read(lba, 1).await`) instead of spinning on it or tying up a task of its
+// own. Futures are handed over with `spawn`, and `pump` polls the ones that have been woken since
+// it last ran. The executor task pumps whenever anything is woken, but any other loop (like one
+// running before tasks are) can pump as well.
+//
+// Wakers only queue up the future's id, so they can be woken from interrupt handlers. Polling
+// allocates, so it never happens in one.
+
+use crate::arch::x86_64::interrupts::without_interrupts;
+use crate::klib::containers::circular_buffer::CircularBuffer;
+use crate::log_warn;
+use crate::task::{self, Priority, WaitQueue};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, RawWaker, RawWakerVTable, Waker};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+// Wakeups past this are lost, and everything gets polled instead
+const MAX_READY: usize = 64;
+
+type BoxedFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+struct Ready {
+    ids: CircularBuffer<MAX_READY, u64>,
+    overflowed: bool,
+}
+
+lazy_static! {
+    // Locked from interrupt handlers, so only ever with interrupts disabled
+    static ref READY: Mutex<Ready> = Mutex::new(Ready {
+        ids: CircularBuffer::new(),
+        overflowed: false,
+    });
+}
+
+// Every future that hasn't finished, except for the one being polled
+static FUTURES: Mutex<BTreeMap<u64, BoxedFuture>> = Mutex::new(BTreeMap::new());
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// One pump at a time, so that a future taken out to be polled can't look finished to another one
+static PUMPING: AtomicBool = AtomicBool::new(false);
+
+// The executor task waits here for something to be woken
+static WORK: WaitQueue = WaitQueue::new();
+
+// A waker's data is the id of its future, so there is nothing to clone or drop
+static WAKER_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(clone_waker, wake_waker, wake_waker, drop_waker);
+
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &WAKER_VTABLE)
+}
+
+unsafe fn wake_waker(data: *const ()) {
+    wake(data as u64);
+}
+
+unsafe fn drop_waker(_data: *const ()) {}
+
+fn waker(id: u64) -> Waker {
+    unsafe { Waker::from_raw(RawWaker::new(id as *const (), &WAKER_VTABLE)) }
+}
+
+fn wake(id: u64) {
+    without_interrupts(|| {
+        let mut ready = READY.lock();
+        if ready.ids.is_full() {
+            ready.overflowed = true;
+        } else {
+            ready.ids.push_back(id);
+        }
+    });
+    WORK.wake_one();
+}
+
+/// Run `future` on the executor, starting with the next pump.
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    without_interrupts(|| FUTURES.lock().insert(id, Box::pin(future)));
+    wake(id);
+}
+
+/// Poll every future that has been woken, until none are left. Returns how many polls that took,
+/// or 0 if something else is already pumping. Can't be called from an interrupt handler.
+pub fn pump() -> usize {
+    if PUMPING.swap(true, Ordering::Acquire) {
+        return 0;
+    }
+
+    let mut polled = 0;
+    loop {
+        let ids: Vec<u64> = without_interrupts(|| {
+            let mut ready = READY.lock();
+            if core::mem::take(&mut ready.overflowed) {
+                // Some wakeups were lost, so any of them could be ready
+                ready.ids.clear();
+                FUTURES.lock().keys().copied().collect()
+            } else {
+                ready.ids.drain().collect()
+            }
+        });
+        if ids.is_empty() {
+            break;
+        }
+
+        for id in ids {
+            // None if it finished already, and was woken again since
+            let Some(mut future) = without_interrupts(|| FUTURES.lock().remove(&id)) else {
+                continue;
+            };
+
+            let waker = waker(id);
+            if future
+                .as_mut()
+                .poll(&mut Context::from_waker(&waker))
+                .is_pending()
+            {
+                without_interrupts(|| FUTURES.lock().insert(id, future));
+            }
+            polled += 1;
+        }
+    }
+
+    PUMPING.store(false, Ordering::Release);
+    polled
+}
+
+/// How many futures haven't finished yet.
+pub fn pending() -> usize {
+    without_interrupts(|| FUTURES.lock().len())
+}
+
+/// Start the task that pumps whenever a future is woken. Has to be called after `task::init`.
+pub fn init() {
+    let executor = task::spawn("executor", Priority::Normal, || loop {
+        WORK.wait_while(|| {
+            let ready = READY.lock();
+            ready.ids.is_empty() && !ready.overflowed
+        });
+        pump();
+    });
+
+    if executor.is_err() {
+        log_warn!("Couldn't start the executor task, futures only run when something pumps");
+    }
+}
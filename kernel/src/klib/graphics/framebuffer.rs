@@ -1,12 +1,32 @@
+use super::glyphs::{self, Shape};
+use super::image::Image;
+use super::{Color, DisplayInfo, PixelLayout};
+use crate::arch::{Arch, Cpu};
+use crate::bootinfo::Framebuffer;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use noto_sans_mono_bitmap::{
     get_raster, get_raster_width, FontWeight, RasterHeight, RasterizedChar,
 };
-use bootloader_api::info::{FrameBuffer, FrameBufferInfo, PixelFormat};
-use core::fmt::Write;
-use spin::once::Once;
-use core::fmt;
+use spin::Mutex;
+
+// Only ever locked with interrupts off, so that a print from an interrupt handler can't find it
+// held by the code it interrupted.
+static CONSOLE: Mutex<Console> = Mutex::new(Console {
+    writer: None,
+    drained: 0,
+});
 
-static mut FRAMEBUFFER: Once<FrameBufferWriter> = spin::Once::new();
+// How many times a print tries for the console before leaving its output in the early buffer
+const LOCK_TRIES: usize = 10_000;
+
+// Size of the early buffer, in bytes
+const EARLY_SIZE: usize = 4096;
+
+// The most text cells whose colors are kept, enough for a 4K screen
+const MAX_CELLS: usize = 64 * 1024;
 
 const LINE_SPACING: usize = 2;
 
@@ -14,6 +34,9 @@ const LETTER_SPACING: usize = 0;
 
 const BORDER_PADDING: usize = 1;
 
+// Tab stops are every this many columns
+const TAB_WIDTH: usize = 8;
+
 const CHAR_RASTER_HEIGHT: RasterHeight = RasterHeight::Size20;
 
 const CHAR_RASTER_WIDTH: usize = get_raster_width(FontWeight::Regular, CHAR_RASTER_HEIGHT);
@@ -22,34 +45,224 @@ const FONT_WEIGHT: FontWeight = FontWeight::Regular;
 
 pub const BACKSPACE: char = 0x08 as char;
 
+/// Moves the cursor back a character without erasing it, for redrawing an edited line.
+pub const CURSOR_LEFT: char = 0x11 as char;
+
+/// The colors text can be drawn in, the same 16 as on a VGA text console.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TextColor {
+    Black = 0,
+    Blue = 1,
+    Green = 2,
+    Cyan = 3,
+    Red = 4,
+    Magenta = 5,
+    Brown = 6,
+    LightGray = 7,
+    DarkGray = 8,
+    LightBlue = 9,
+    LightGreen = 10,
+    LightCyan = 11,
+    LightRed = 12,
+    Pink = 13,
+    Yellow = 14,
+    White = 15,
+}
+
+// What each `TextColor` looks like, as VGA draws them
+const PALETTE: [Color; 16] = [
+    Color::gray(0x00),
+    rgb(0x00, 0x00, 0xAA),
+    rgb(0x00, 0xAA, 0x00),
+    rgb(0x00, 0xAA, 0xAA),
+    rgb(0xAA, 0x00, 0x00),
+    rgb(0xAA, 0x00, 0xAA),
+    rgb(0xAA, 0x55, 0x00),
+    Color::gray(0xAA),
+    Color::gray(0x55),
+    rgb(0x55, 0x55, 0xFF),
+    rgb(0x55, 0xFF, 0x55),
+    rgb(0x55, 0xFF, 0xFF),
+    rgb(0xFF, 0x55, 0x55),
+    rgb(0xFF, 0x55, 0xFF),
+    rgb(0xFF, 0xFF, 0x55),
+    Color::gray(0xFF),
+];
+
+const fn rgb(red: u8, green: u8, blue: u8) -> Color {
+    Color { red, green, blue }
+}
+
+// A foreground and a background color, packed like a VGA text console's attribute byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ColorCode(u8);
+
+impl ColorCode {
+    const DEFAULT: Self = Self::new(TextColor::White, TextColor::Black);
+
+    const fn new(foreground: TextColor, background: TextColor) -> Self {
+        Self(((background as u8) << 4) | foreground as u8)
+    }
+
+    fn foreground(self) -> Color {
+        PALETTE[(self.0 & 0xF) as usize]
+    }
+
+    fn background(self) -> Color {
+        PALETTE[(self.0 >> 4) as usize]
+    }
+
+    // A glyph's pixel of `intensity`: the background at 0, the foreground at 0xFF
+    fn blend(self, intensity: u8) -> Color {
+        let (foreground, background) = (self.foreground(), self.background());
+        let channel = |foreground: u8, background: u8| {
+            let (foreground, background) = (foreground as u32, background as u32);
+            ((foreground * intensity as u32 + background * (0xFF - intensity as u32)) / 0xFF) as u8
+        };
+        rgb(
+            channel(foreground.red, background.red),
+            channel(foreground.green, background.green),
+            channel(foreground.blue, background.blue),
+        )
+    }
+}
+
 fn get_rasterized_char(ch: char) -> RasterizedChar {
     get_raster(ch, FONT_WEIGHT, CHAR_RASTER_HEIGHT).unwrap()
 }
 
 pub struct FrameBufferWriter {
     framebuffer: &'static mut [u8],
-    info: FrameBufferInfo,
+    info: DisplayInfo,
     x: usize,
     y: usize,
+    // What text is drawn in now, and what each cell was last drawn in, a row at a time. Empty if
+    // the screen has more than `MAX_CELLS` of them.
+    color: ColorCode,
+    cells: &'static mut [ColorCode],
+    columns: usize,
+}
+
+struct Console {
+    writer: Option<FrameBufferWriter>,
+    // How much of the early buffer has been written out
+    drained: usize,
+}
+
+impl Console {
+    // Write out whatever went into the early buffer since last time
+    fn drain_early(&mut self) {
+        let Some(writer) = self.writer.as_mut() else {
+            return;
+        };
+
+        // Only if every reservation has been filled in, see `EarlyBuffer`
+        let written = EARLY.written.load(Ordering::Acquire);
+        if written == self.drained || EARLY.reserved.load(Ordering::Relaxed) != written {
+            return;
+        }
+
+        let bytes = unsafe {
+            let start = (EARLY.bytes.get() as *const u8).add(self.drained);
+            core::slice::from_raw_parts(start, written - self.drained)
+        };
+        // Only whole strings are ever put in, so this should be valid UTF-8
+        let _ = write!(writer, "{}", Lossy(bytes));
+        self.drained = written;
+    }
 }
 
-/// Initialize the framebuffer.
+// Where output goes while the console can't be used: before the framebuffer is set up, or when a
+// print can't get the lock (another CPU holding it for long, or a print from inside a print).
+// Writers reserve a range with `reserved` without taking any lock, copy their bytes in, and then
+// add to `written`, so everything up to `written` is filled in when the two are equal. It is never
+// reset; once it is full, fallback output is dropped.
+struct EarlyBuffer {
+    bytes: UnsafeCell<[u8; EARLY_SIZE]>,
+    reserved: AtomicUsize,
+    written: AtomicUsize,
+}
+
+// Every writer only touches the range it reserved
+unsafe impl Sync for EarlyBuffer {}
+
+static EARLY: EarlyBuffer = EarlyBuffer {
+    bytes: UnsafeCell::new([0; EARLY_SIZE]),
+    reserved: AtomicUsize::new(0),
+    written: AtomicUsize::new(0),
+};
+
+// The colors of the text cells, which the one writer there is takes for itself
+struct Cells {
+    cells: UnsafeCell<[ColorCode; MAX_CELLS]>,
+    taken: AtomicBool,
+}
+
+// Only the writer that took them ever touches the cells
+unsafe impl Sync for Cells {}
+
+static CELLS: Cells = Cells {
+    // Zeros keep it out of the kernel image; `clear` fills in the real colors
+    cells: UnsafeCell::new([ColorCode(0); MAX_CELLS]),
+    taken: AtomicBool::new(false),
+};
+
+struct EarlyWriter;
+
+impl fmt::Write for EarlyWriter {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        let len = string.len();
+        let start = EARLY
+            .reserved
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |reserved| {
+                (reserved + len <= EARLY_SIZE).then_some(reserved + len)
+            })
+            .map_err(|_| fmt::Error)?;
+
+        unsafe {
+            let dest = (EARLY.bytes.get() as *mut u8).add(start);
+            dest.copy_from_nonoverlapping(string.as_ptr(), len);
+        }
+        EARLY.written.fetch_add(len, Ordering::Release);
+        Ok(())
+    }
+}
+
+/// Initialize the framebuffer, and write out anything printed before now.
 /// SAFETY: This function should only be called once, in one thread. ALSO: This should be
 /// called immediately after booting.
-pub unsafe fn init_framebuffer(framebuffer: &'static mut FrameBuffer) {
-    let info = framebuffer.info();
-    unsafe { FRAMEBUFFER.call_once(|| FrameBufferWriter::new(framebuffer.buffer_mut(), info) ) };
+pub unsafe fn init_framebuffer(framebuffer: Framebuffer) {
+    let writer = FrameBufferWriter::new(framebuffer.buffer, framebuffer.info);
+    Arch::without_interrupts(|| {
+        let mut console = CONSOLE.lock();
+        console.writer = Some(writer);
+        console.drain_early();
+    });
 }
 
 impl FrameBufferWriter {
-    fn new(framebuffer: &'static mut [u8], info: FrameBufferInfo) -> Self {
+    fn new(framebuffer: &'static mut [u8], info: DisplayInfo) -> Self {
         let mut writer = Self {
             framebuffer,
             info,
             x: BORDER_PADDING,
             y: BORDER_PADDING,
+            color: ColorCode::DEFAULT,
+            cells: &mut [],
+            columns: 0,
         };
 
+        // The console is up long before the heap, and is written to for as long as the kernel
+        // runs, so the colors can't go in the early allocator's arena either
+        let (columns, rows) = writer.text_size();
+        if columns * rows <= MAX_CELLS && !CELLS.taken.swap(true, Ordering::Relaxed) {
+            let cells = unsafe { &mut *CELLS.cells.get() };
+            writer.cells = &mut cells[..columns * rows];
+            writer.columns = columns;
+        }
+
         writer.clear();
         writer
     }
@@ -58,6 +271,25 @@ impl FrameBufferWriter {
         self.x = BORDER_PADDING;
         self.y = BORDER_PADDING;
         self.framebuffer.fill(0);
+        self.cells.fill(ColorCode::DEFAULT);
+    }
+
+    // The colors of the cell at the current position, if it's on the screen
+    fn cell(&mut self) -> Option<&mut ColorCode> {
+        let column = self.x.saturating_sub(BORDER_PADDING) / (CHAR_RASTER_WIDTH + LETTER_SPACING);
+        let row = self.y.saturating_sub(BORDER_PADDING) / (CHAR_RASTER_HEIGHT.val() + LINE_SPACING);
+        if column >= self.columns {
+            return None;
+        }
+        self.cells.get_mut(row * self.columns + column)
+    }
+
+    // Remember the cell at the current position as drawn in the current colors
+    fn color_cell(&mut self) {
+        let color = self.color;
+        if let Some(cell) = self.cell() {
+            *cell = color;
+        }
     }
 
     fn newline(&mut self) {
@@ -66,6 +298,17 @@ impl FrameBufferWriter {
     }
 
     fn backspace(&mut self) {
+        self.cursor_left();
+        self.color_cell();
+
+        for (y, row) in get_rasterized_char(' ').raster().iter().enumerate() {
+            for (x, byte) in row.iter().enumerate() {
+                self.write_pixel(self.x + x, self.y + y, *byte);
+            }
+        }
+    }
+
+    fn cursor_left(&mut self) {
         if self.x <= BORDER_PADDING {
             if self.y > BORDER_PADDING {
                 self.y -= CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
@@ -74,10 +317,21 @@ impl FrameBufferWriter {
         } else {
             self.x -= CHAR_RASTER_WIDTH + LETTER_SPACING;
         }
+    }
 
-        for (y, row) in get_rasterized_char(' ').raster().iter().enumerate() {
-            for (x, byte) in row.iter().enumerate() {
-                self.write_pixel(self.x + x, self.y + y, *byte);
+    // Draw (or with an intensity of 0, erase) the cursor: a bar under the current character, in
+    // the gap between lines that no character is ever drawn in. It's drawn in the current colors,
+    // and erased to the background the cell was drawn on.
+    fn draw_cursor(&mut self, intensity: u8) {
+        let cell = self.cell().map_or(ColorCode::DEFAULT, |cell| *cell);
+        let color = match intensity {
+            0 => cell.background(),
+            _ => self.color.blend(intensity),
+        };
+        let top = self.y + CHAR_RASTER_HEIGHT.val();
+        for y in top..(top + LINE_SPACING).min(self.height()) {
+            for x in self.x..(self.x + CHAR_RASTER_WIDTH).min(self.width()) {
+                self.write_color(x, y, color);
             }
         }
     }
@@ -86,6 +340,23 @@ impl FrameBufferWriter {
         self.info.width
     }
 
+    // Columns and rows of text that fit without wrapping to the next line or clearing the screen
+    fn text_size(&self) -> (usize, usize) {
+        let columns =
+            self.width().saturating_sub(BORDER_PADDING + 1) / (CHAR_RASTER_WIDTH + LETTER_SPACING);
+        let rows = self
+            .height()
+            .saturating_sub(CHAR_RASTER_HEIGHT.val() + 2 * BORDER_PADDING + 1)
+            / (CHAR_RASTER_HEIGHT.val() + LINE_SPACING)
+            + 1;
+        (columns, rows)
+    }
+
+    fn move_to(&mut self, column: usize, row: usize) {
+        self.x = BORDER_PADDING + column * (CHAR_RASTER_WIDTH + LETTER_SPACING);
+        self.y = BORDER_PADDING + row * (CHAR_RASTER_HEIGHT.val() + LINE_SPACING);
+    }
+
     pub fn height(&self) -> usize {
         self.info.height
     }
@@ -93,7 +364,15 @@ impl FrameBufferWriter {
     fn write_char(&mut self, ch: char) {
         match ch {
             '\n' => self.newline(),
+            '\r' => self.x = BORDER_PADDING,
+            '\t' => {
+                let column = (self.x - BORDER_PADDING) / (CHAR_RASTER_WIDTH + LETTER_SPACING);
+                for _ in column % TAB_WIDTH..TAB_WIDTH {
+                    self.write_char(' ');
+                }
+            }
             BACKSPACE => self.backspace(),
+            CURSOR_LEFT => self.cursor_left(),
             ch => {
                 let new_x = self.x + CHAR_RASTER_WIDTH;
                 if new_x >= self.width() {
@@ -106,12 +385,32 @@ impl FrameBufferWriter {
                     self.clear();
                 }
 
-                self.write_rendered_char(get_rasterized_char(ch));
+                match get_raster(ch, FONT_WEIGHT, CHAR_RASTER_HEIGHT) {
+                    Some(rendered_char) => self.write_rendered_char(rendered_char),
+                    None => self.write_shape(glyphs::shape(ch)),
+                }
+            }
+        }
+    }
+
+    // Draw a character the font has no glyph for. It goes on down through the gap below the line,
+    // so that lines and blocks meet the ones on the next line.
+    fn write_shape(&mut self, shape: Shape) {
+        self.color_cell();
+        let height = CHAR_RASTER_HEIGHT.val();
+        let rows = (height + LINE_SPACING).min(self.height().saturating_sub(self.y));
+        for y in 0..rows {
+            for x in 0..CHAR_RASTER_WIDTH {
+                let intensity = shape.intensity(x, y, CHAR_RASTER_WIDTH, height);
+                self.write_pixel(self.x + x, self.y + y, intensity);
             }
         }
+
+        self.x += CHAR_RASTER_WIDTH + LETTER_SPACING;
     }
 
     fn write_rendered_char(&mut self, rendered_char: RasterizedChar) {
+        self.color_cell();
         for (y, row) in rendered_char.raster().iter().enumerate() {
             for (x, byte) in row.iter().enumerate() {
                 self.write_pixel(self.x + x, self.y + y, *byte);
@@ -122,12 +421,12 @@ impl FrameBufferWriter {
     }
 
     fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
+        self.write_color(x, y, self.color.blend(intensity));
+    }
+
+    fn write_color(&mut self, x: usize, y: usize, color: Color) {
         let pixel_offset = y * self.info.stride + x;
-        let color = match self.info.pixel_format {
-            PixelFormat::Bgr => [intensity, intensity, intensity, 0],
-            PixelFormat::U8 => [if intensity > 200 { 0xFF } else { 0x0 }, 0, 0, 0],
-            _ => [intensity, intensity, intensity, 0],
-        };
+        let color = encode_pixel(self.info.layout, color);
 
         let bytes_per_pixel = self.info.bytes_per_pixel;
         let byte_offset = bytes_per_pixel * pixel_offset;
@@ -137,6 +436,62 @@ impl FrameBufferWriter {
         let _ = unsafe { core::ptr::read_volatile(&self.framebuffer[byte_offset]) };
     }
 
+    // Draw `image` as big as it fits on the screen, in the middle, on black. The text goes on at the
+    // bottom line, so the image stays up until the screen fills and is cleared.
+    fn draw_image(&mut self, image: &Image) {
+        let (width, height) = (self.width(), self.height());
+        // Whichever side of the image is the tighter fit decides the scale
+        let (scaled_width, scaled_height) = if image.width * height <= image.height * width {
+            (image.width * height / image.height, height)
+        } else {
+            (width, image.height * width / image.width)
+        };
+        let left = (width - scaled_width) / 2;
+        let top = (height - scaled_height) / 2;
+
+        self.framebuffer.fill(0);
+        for y in 0..scaled_height {
+            let source_y = y * image.height / scaled_height;
+            for x in 0..scaled_width {
+                let source_x = x * image.width / scaled_width;
+                self.write_color(left + x, top + y, image.pixel(source_x, source_y));
+            }
+        }
+
+        self.x = BORDER_PADDING;
+        self.y = height.saturating_sub(CHAR_RASTER_HEIGHT.val() + LINE_SPACING + BORDER_PADDING);
+    }
+
+    pub fn display_info(&self) -> DisplayInfo {
+        self.info
+    }
+}
+
+/// The bytes of one pixel of `color` in the given layout. Only the first `bytes_per_pixel` of
+/// them are meant to be written out.
+pub fn encode_pixel(layout: PixelLayout, color: Color) -> [u8; 4] {
+    match layout {
+        PixelLayout::Rgb => [color.red, color.green, color.blue, 0],
+        PixelLayout::Bgr => [color.blue, color.green, color.red, 0],
+        PixelLayout::Grayscale => [color.luminance(), 0, 0, 0],
+        PixelLayout::BitMask {
+            red_position,
+            green_position,
+            blue_position,
+        } => {
+            let channel = |value: u8, position: u8| (value as u32).checked_shl(position as u32);
+            let pixel = [
+                channel(color.red, red_position),
+                channel(color.green, green_position),
+                channel(color.blue, blue_position),
+            ]
+            .into_iter()
+            .fold(0u32, |pixel, channel| pixel | channel.unwrap_or(0));
+
+            pixel.to_le_bytes()
+        }
+        PixelLayout::Unsupported => [0; 4],
+    }
 }
 
 unsafe impl Send for FrameBufferWriter {}
@@ -144,17 +499,117 @@ unsafe impl Sync for FrameBufferWriter {}
 
 impl fmt::Write for FrameBufferWriter {
     fn write_str(&mut self, string: &str) -> fmt::Result {
+        self.draw_cursor(0);
         for ch in string.chars() {
             self.write_char(ch)
         }
+        self.draw_cursor(0xFF);
 
         Ok(())
     }
 }
 
+// Run `f` on the console's writer, with the cursor hidden. None if there is no framebuffer.
+fn with_writer<R>(f: impl FnOnce(&mut FrameBufferWriter) -> R) -> Option<R> {
+    Arch::without_interrupts(|| {
+        let mut console = CONSOLE.lock();
+        let writer = console.writer.as_mut()?;
+        writer.draw_cursor(0);
+        let result = f(writer);
+        writer.draw_cursor(0xFF);
+        Some(result)
+    })
+}
+
+pub fn display_info() -> Option<DisplayInfo> {
+    with_writer(|writer| writer.display_info())
+}
+
+/// Draw `image` over the whole screen, scaled to fit. Fails if there is no framebuffer.
+pub fn draw_image(image: &Image) -> Result<(), ()> {
+    with_writer(|writer| writer.draw_image(image)).ok_or(())
+}
+
+/// How many columns and rows of text fit on the screen, for programs that place text themselves
+/// with `set_cursor`. Writing past the last column wraps, and past the last row clears the screen.
+pub fn text_size() -> Option<(usize, usize)> {
+    with_writer(|writer| writer.text_size())
+}
+
+/// Draw text printed from now on in `foreground` on `background`, until `reset_color`.
+pub fn set_color(foreground: TextColor, background: TextColor) {
+    with_writer(|writer| writer.color = ColorCode::new(foreground, background));
+}
+
+/// Go back to drawing text white on black.
+pub fn reset_color() {
+    with_writer(|writer| writer.color = ColorCode::DEFAULT);
+}
+
+/// Blank the screen, and go back to the top left.
+pub fn clear_screen() {
+    with_writer(FrameBufferWriter::clear);
+}
+
+/// Have the text printed next start at `column` and `row`, counting from 0 at the top left.
+pub fn set_cursor(column: usize, row: usize) {
+    with_writer(|writer| writer.move_to(column, row));
+}
+
+/// Make the console usable no matter what it was in the middle of, for the panic handler. Whoever
+/// held it may leave a half-drawn line behind.
+/// ### Safety
+/// Nothing may be printing on another CPU, e.g. because every other CPU is halted.
+pub unsafe fn force_unlock() {
+    if CONSOLE.is_locked() {
+        CONSOLE.force_unlock();
+    }
+}
+
+/// Print `bytes` as UTF-8, with anything that isn't valid shown as U+FFFD.
+pub fn print_bytes(bytes: &[u8]) {
+    _print(format_args!("{}", Lossy(bytes)));
+}
+
+// Formats as its bytes would print as UTF-8, with each run that isn't valid replaced by U+FFFD
+struct Lossy<'a>(&'a [u8]);
+
+impl fmt::Display for Lossy<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for chunk in self.0.utf8_chunks() {
+            f.write_str(chunk.valid())?;
+            if !chunk.invalid().is_empty() {
+                f.write_char(char::REPLACEMENT_CHARACTER)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
-    unsafe { FRAMEBUFFER.get_mut_unchecked().write_fmt(args).unwrap(); };
+    #[cfg(feature = "qemu")]
+    crate::klib::qemu::debugcon_write(args);
+
+    Arch::without_interrupts(|| {
+        let console = (0..LOCK_TRIES).find_map(|_| {
+            let console = CONSOLE.try_lock();
+            if console.is_none() {
+                Arch::pause();
+            }
+            console
+        });
+
+        match console {
+            Some(mut console) if console.writer.is_some() => {
+                console.drain_early();
+                let _ = console.writer.as_mut().unwrap().write_fmt(args);
+            }
+            _ => {
+                let _ = EarlyWriter.write_fmt(args);
+            }
+        }
+    });
 }
 
 #[macro_export]
@@ -0,0 +1,183 @@
+// Shapes for the characters the font doesn't have. The box drawing characters and block elements
+// text UIs draw frames and bars with are made of lines and fills scaled to the character cell, so
+// that they join up with the cells around them; anything else is drawn as an empty box.
+
+use core::ops::Range;
+
+/// How thick one of a box drawing character's lines is.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Weight {
+    Empty,
+    Light,
+    Heavy,
+    Double,
+}
+
+pub enum Shape {
+    /// Lines from the middle of the cell out to its edges: up, right, down and left
+    Lines([Weight; 4]),
+    /// The part of the cell between these eighths of its width and height, at an intensity
+    Fill {
+        columns: Range<usize>,
+        rows: Range<usize>,
+        intensity: u8,
+    },
+    /// A box, for characters there's nothing better for
+    Missing,
+}
+
+/// The shape to draw for `ch` in place of a glyph.
+pub fn shape(ch: char) -> Shape {
+    box_lines(ch)
+        .map(Shape::Lines)
+        .or_else(|| block(ch))
+        .unwrap_or(Shape::Missing)
+}
+
+// Up, right, down and left
+fn box_lines(ch: char) -> Option<[Weight; 4]> {
+    const O: Weight = Weight::Empty;
+    const L: Weight = Weight::Light;
+    const H: Weight = Weight::Heavy;
+    const D: Weight = Weight::Double;
+
+    let lines = match ch {
+        // Dashed lines are drawn solid
+        '─' | '┄' | '┈' | '╌' => [O, L, O, L],
+        '│' | '┆' | '┊' | '╎' => [L, O, L, O],
+        '┌' | '╭' => [O, L, L, O],
+        '┐' | '╮' => [O, O, L, L],
+        '└' | '╰' => [L, L, O, O],
+        '┘' | '╯' => [L, O, O, L],
+        '├' => [L, L, L, O],
+        '┤' => [L, O, L, L],
+        '┬' => [O, L, L, L],
+        '┴' => [L, L, O, L],
+        '┼' => [L, L, L, L],
+        '╴' => [O, O, O, L],
+        '╵' => [L, O, O, O],
+        '╶' => [O, L, O, O],
+        '╷' => [O, O, L, O],
+
+        '━' | '┅' | '┉' | '╍' => [O, H, O, H],
+        '┃' | '┇' | '┋' | '╏' => [H, O, H, O],
+        '┏' => [O, H, H, O],
+        '┓' => [O, O, H, H],
+        '┗' => [H, H, O, O],
+        '┛' => [H, O, O, H],
+        '┣' => [H, H, H, O],
+        '┫' => [H, O, H, H],
+        '┳' => [O, H, H, H],
+        '┻' => [H, H, O, H],
+        '╋' => [H, H, H, H],
+        '╸' => [O, O, O, H],
+        '╹' => [H, O, O, O],
+        '╺' => [O, H, O, O],
+        '╻' => [O, O, H, O],
+
+        '═' => [O, D, O, D],
+        '║' => [D, O, D, O],
+        '╔' => [O, D, D, O],
+        '╗' => [O, O, D, D],
+        '╚' => [D, D, O, O],
+        '╝' => [D, O, O, D],
+        '╠' => [D, D, D, O],
+        '╣' => [D, O, D, D],
+        '╦' => [O, D, D, D],
+        '╩' => [D, D, O, D],
+        '╬' => [D, D, D, D],
+
+        _ => return None,
+    };
+    Some(lines)
+}
+
+fn block(ch: char) -> Option<Shape> {
+    let fill = |columns, rows| Shape::Fill {
+        columns,
+        rows,
+        intensity: 0xFF,
+    };
+    let shade = |intensity| Shape::Fill {
+        columns: 0..8,
+        rows: 0..8,
+        intensity,
+    };
+
+    let shape = match ch {
+        '▀' => fill(0..8, 0..4),
+        // Lower one eighth up to the full block
+        '▁'..='█' => fill(0..8, 8 - (ch as usize - 0x2580)..8),
+        // Left seven eighths down to one eighth
+        '▉'..='▏' => fill(0..8 - (ch as usize - 0x2588), 0..8),
+        '▐' => fill(4..8, 0..8),
+        '░' => shade(0x40),
+        '▒' => shade(0x80),
+        '▓' => shade(0xC0),
+        '▔' => fill(0..8, 0..1),
+        '▕' => fill(7..8, 0..8),
+        _ => return None,
+    };
+    Some(shape)
+}
+
+impl Shape {
+    /// How bright pixel (`x`, `y`) of a `width` by `height` cell is. Rows below the cell carry on
+    /// whatever reaches its bottom edge, for the gap between lines of text.
+    pub fn intensity(&self, x: usize, y: usize, width: usize, height: usize) -> u8 {
+        let on = match self {
+            Shape::Lines([up, right, down, left]) => {
+                let (x, y) = (x as isize, y as isize);
+                let (center_x, center_y) = ((width / 2) as isize, (height / 2) as isize);
+                // Far enough past the middle to meet the lines going across it
+                let across = reach(*left).max(reach(*right));
+                let along = reach(*up).max(reach(*down));
+
+                (y <= center_y + across && on_line(x - center_x, *up))
+                    || (y >= center_y - across && on_line(x - center_x, *down))
+                    || (x <= center_x + along && on_line(y - center_y, *left))
+                    || (x >= center_x - along && on_line(y - center_y, *right))
+            }
+            Shape::Fill {
+                columns,
+                rows,
+                intensity,
+            } => {
+                let in_columns = x * 8 >= columns.start * width && x * 8 < columns.end * width;
+                let in_rows =
+                    y * 8 >= rows.start * height && (rows.end == 8 || y * 8 < rows.end * height);
+                return if in_columns && in_rows { *intensity } else { 0 };
+            }
+            Shape::Missing => {
+                let (right, bottom) = (width.saturating_sub(2), height.saturating_sub(3));
+                (1..=right).contains(&x)
+                    && (2..=bottom).contains(&y)
+                    && (x == 1 || x == right || y == 2 || y == bottom)
+            }
+        };
+        if on {
+            0xFF
+        } else {
+            0
+        }
+    }
+}
+
+// Whether a pixel `offset` away from the middle of a line of `weight`, across it, is part of it
+fn on_line(offset: isize, weight: Weight) -> bool {
+    match weight {
+        Weight::Empty => false,
+        Weight::Light => offset == 0,
+        Weight::Heavy => offset.abs() <= 1,
+        Weight::Double => offset.abs() == 2,
+    }
+}
+
+// How far a line of `weight` reaches either side of the middle
+fn reach(weight: Weight) -> isize {
+    match weight {
+        Weight::Empty | Weight::Light => 0,
+        Weight::Heavy => 1,
+        Weight::Double => 2,
+    }
+}
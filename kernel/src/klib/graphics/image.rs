@@ -0,0 +1,212 @@
+// Simple uncompressed images: BMP with 8 (paletted), 24 or 32 bits per pixel, and binary PPM
+// (P6). Pixels are read straight out of the file's bytes when asked for, rather than decoded into
+// a buffer of their own, as the heap isn't big enough to hold a large image twice.
+
+use super::Color;
+
+// Offsets into a BMP's file header and the BITMAPINFOHEADER after it
+const BMP_PIXELS_OFFSET: usize = 10;
+const BMP_HEADER_SIZE: usize = 14;
+const BMP_WIDTH: usize = 18;
+const BMP_HEIGHT: usize = 22;
+const BMP_BITS_PER_PIXEL: usize = 28;
+const BMP_COMPRESSION: usize = 30;
+const BMP_COLORS_USED: usize = 46;
+const BMP_INFO_HEADER_SIZE: usize = 40;
+const BMP_UNCOMPRESSED: u32 = 0;
+
+// Big enough for any screen, and small enough that sizes can't overflow
+const MAX_DIMENSION: usize = 1 << 14;
+
+pub struct Image<'a> {
+    pub width: usize,
+    pub height: usize,
+    encoding: Encoding<'a>,
+}
+
+enum Encoding<'a> {
+    Bmp {
+        pixels: &'a [u8],
+        row_size: usize,
+        bits_per_pixel: u16,
+        // Rows are stored bottom first, unless the height is negative
+        bottom_up: bool,
+        // Blue, green, red and a padding byte per color
+        palette: &'a [u8],
+    },
+    Ppm {
+        pixels: &'a [u8],
+        max_value: u16,
+    },
+}
+
+impl<'a> Image<'a> {
+    /// Make sense of `data` as a BMP or PPM file.
+    pub fn decode(data: &'a [u8]) -> Result<Self, ()> {
+        match data.get(..2) {
+            Some(b"BM") => Self::decode_bmp(data),
+            Some(b"P6") => Self::decode_ppm(data),
+            _ => Err(()),
+        }
+    }
+
+    fn decode_bmp(data: &'a [u8]) -> Result<Self, ()> {
+        let read_u16 = |offset: usize| -> Result<u16, ()> {
+            let bytes = data.get(offset..offset + 2).ok_or(())?;
+            Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+        };
+        let read_u32 = |offset: usize| -> Result<u32, ()> {
+            let bytes = data.get(offset..offset + 4).ok_or(())?;
+            Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+        };
+
+        let info_header_size = read_u32(BMP_HEADER_SIZE)? as usize;
+        let width = read_u32(BMP_WIDTH)? as i32;
+        let height = read_u32(BMP_HEIGHT)? as i32;
+        let bits_per_pixel = read_u16(BMP_BITS_PER_PIXEL)?;
+        if info_header_size < BMP_INFO_HEADER_SIZE
+            || read_u32(BMP_COMPRESSION)? != BMP_UNCOMPRESSED
+            || !matches!(bits_per_pixel, 8 | 24 | 32)
+        {
+            return Err(());
+        }
+
+        let bottom_up = height > 0;
+        let (width, height) = (
+            width.unsigned_abs() as usize,
+            height.unsigned_abs() as usize,
+        );
+        if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+            return Err(());
+        }
+
+        // Rows are padded to a multiple of 4 bytes
+        let row_size = (width * bits_per_pixel as usize).div_ceil(32) * 4;
+        let start = read_u32(BMP_PIXELS_OFFSET)? as usize;
+        let pixels = data.get(start..start + row_size * height).ok_or(())?;
+
+        let palette = match bits_per_pixel {
+            8 => {
+                let colors = match read_u32(BMP_COLORS_USED)? {
+                    0 => 256,
+                    colors => (colors as usize).min(256),
+                };
+                let palette_start = BMP_HEADER_SIZE + info_header_size;
+                data.get(palette_start..palette_start + colors * 4)
+                    .ok_or(())?
+            }
+            _ => &[],
+        };
+
+        Ok(Self {
+            width,
+            height,
+            encoding: Encoding::Bmp {
+                pixels,
+                row_size,
+                bits_per_pixel,
+                bottom_up,
+                palette,
+            },
+        })
+    }
+
+    fn decode_ppm(data: &'a [u8]) -> Result<Self, ()> {
+        // The magic number, the width, the height and the maximum value, separated by whitespace
+        // and comments, and then a single whitespace byte before the pixels
+        let mut position = 2;
+        let mut fields = [0; 3];
+        for field in fields.iter_mut() {
+            loop {
+                match data.get(position).ok_or(())? {
+                    b'#' => {
+                        while data.get(position).ok_or(())? != &b'\n' {
+                            position += 1;
+                        }
+                    }
+                    byte if byte.is_ascii_whitespace() => position += 1,
+                    _ => break,
+                }
+            }
+
+            let digits = data[position..]
+                .iter()
+                .take_while(|byte| byte.is_ascii_digit())
+                .count();
+            let text = core::str::from_utf8(&data[position..position + digits]).map_err(|_| ())?;
+            *field = text.parse::<usize>().map_err(|_| ())?;
+            position += digits;
+        }
+
+        let [width, height, max_value] = fields;
+        if !data.get(position).ok_or(())?.is_ascii_whitespace()
+            || width == 0
+            || height == 0
+            || width > MAX_DIMENSION
+            || height > MAX_DIMENSION
+            || max_value == 0
+            || max_value > u16::MAX as usize
+        {
+            return Err(());
+        }
+        position += 1;
+
+        // Two bytes per sample if they go past 255
+        let sample_size = if max_value > 255 { 2 } else { 1 };
+        let pixels = data
+            .get(position..position + width * height * 3 * sample_size)
+            .ok_or(())?;
+
+        Ok(Self {
+            width,
+            height,
+            encoding: Encoding::Ppm {
+                pixels,
+                max_value: max_value as u16,
+            },
+        })
+    }
+
+    /// The color at (`x`, `y`), counting from the top left. Both have to be in the image.
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        match self.encoding {
+            Encoding::Bmp {
+                pixels,
+                row_size,
+                bits_per_pixel,
+                bottom_up,
+                palette,
+            } => {
+                let row = if bottom_up { self.height - 1 - y } else { y };
+                let offset = row * row_size + x * bits_per_pixel as usize / 8;
+                let bgr = match bits_per_pixel {
+                    // Out of range indices are black
+                    8 => {
+                        let index = pixels[offset] as usize;
+                        palette.get(index * 4..index * 4 + 3).unwrap_or(&[0; 3])
+                    }
+                    _ => &pixels[offset..],
+                };
+                Color {
+                    red: bgr[2],
+                    green: bgr[1],
+                    blue: bgr[0],
+                }
+            }
+            Encoding::Ppm { pixels, max_value } => {
+                let sample = |index: usize| match max_value {
+                    0..=255 => pixels[index] as u32,
+                    _ => u16::from_be_bytes([pixels[index * 2], pixels[index * 2 + 1]]) as u32,
+                };
+                let scale =
+                    |value: u32| (value.min(max_value as u32) * 255 / max_value as u32) as u8;
+                let index = (y * self.width + x) * 3;
+                Color {
+                    red: scale(sample(index)),
+                    green: scale(sample(index + 1)),
+                    blue: scale(sample(index + 2)),
+                }
+            }
+        }
+    }
+}
@@ -1 +1,57 @@
 pub mod framebuffer;
+pub mod glyphs;
+pub mod image;
+
+pub use framebuffer::display_info;
+
+/// How the color channels of a pixel are laid out in framebuffer memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelLayout {
+    /// One byte each of red, green and blue, then padding
+    Rgb,
+    /// One byte each of blue, green and red, then padding
+    Bgr,
+    /// A single byte of intensity
+    Grayscale,
+    /// Each channel is a byte at the given bit offset into the pixel
+    BitMask {
+        red_position: u8,
+        green_position: u8,
+        blue_position: u8,
+    },
+    /// A format we don't know how to draw to
+    Unsupported,
+}
+
+/// The geometry and pixel format of the display, independent of how the bootloader describes it.
+#[derive(Debug, Clone, Copy)]
+pub struct DisplayInfo {
+    pub width: usize,
+    pub height: usize,
+    /// Pixels per line in memory, which may be more than `width`
+    pub stride: usize,
+    pub bytes_per_pixel: usize,
+    pub layout: PixelLayout,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl Color {
+    pub const fn gray(intensity: u8) -> Self {
+        Self {
+            red: intensity,
+            green: intensity,
+            blue: intensity,
+        }
+    }
+
+    /// Perceived brightness, for grayscale displays
+    pub fn luminance(&self) -> u8 {
+        ((self.red as u32 * 299 + self.green as u32 * 587 + self.blue as u32 * 114) / 1000) as u8
+    }
+}
@@ -0,0 +1,29 @@
+// CRC-32 (the IEEE polynomial), as gzip, zlib, PNG and GPT headers use it. It goes a byte at a
+// time through a table; the `crc32` instruction only does CRC32C, see crc32c.rs.
+
+use super::make_table;
+
+// Bit-reversed, as the CRC is worked out least significant bit first
+const POLYNOMIAL: u32 = 0xEDB8_8320;
+
+static TABLE: [u32; 256] = make_table(POLYNOMIAL);
+
+/// A CRC-32 being worked out over data that comes in pieces.
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Self(!0)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 = TABLE[((self.0 ^ byte as u32) & 0xFF) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    /// The CRC-32 of everything so far.
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
@@ -0,0 +1,76 @@
+// CRC32C (the Castagnoli polynomial), for noticing data that changed when it shouldn't have. With
+// SSE4.2 the processor works it out eight bytes at a time with the `crc32` instruction, which only
+// touches general purpose registers, so it's fine in the kernel; otherwise it goes a byte at a
+// time through a table.
+
+use super::make_table;
+use crate::arch::x86_64::cpu;
+use crate::klib::once_lock::OnceLock;
+
+// Bit-reversed, as the CRC is worked out least significant bit first
+const POLYNOMIAL: u32 = 0x82F6_3B78;
+
+static TABLE: [u32; 256] = make_table(POLYNOMIAL);
+
+static HARDWARE: OnceLock<bool> = OnceLock::new();
+
+/// A CRC32C being worked out over data that comes in pieces.
+pub struct Crc32c(u32);
+
+impl Crc32c {
+    pub const fn new() -> Self {
+        Self(!0)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0 = if is_hardware() {
+            unsafe { update_hardware(self.0, data) }
+        } else {
+            update_software(self.0, data)
+        };
+    }
+
+    /// The CRC32C of everything so far.
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+/// Whether the processor computes the CRC itself.
+pub fn is_hardware() -> bool {
+    *HARDWARE.get_or_init(cpu::has_sse42)
+}
+
+/// The CRC32C of `data`.
+pub fn checksum(data: &[u8]) -> u32 {
+    let mut crc = Crc32c::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/// The CRC32C of `data` worked out through the table, whatever the processor has; for checking
+/// the two ways against each other.
+pub fn checksum_software(data: &[u8]) -> u32 {
+    !update_software(!0, data)
+}
+
+fn update_software(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc = TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+// Has to only be called if the processor has SSE4.2
+unsafe fn update_hardware(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = crc as u64;
+    let mut words = data.chunks_exact(8);
+    for word in &mut words {
+        crc = cpu::crc32_u64(crc, u64::from_le_bytes(word.try_into().unwrap()));
+    }
+    let mut crc = crc as u32;
+    for &byte in words.remainder() {
+        crc = cpu::crc32_u8(crc, byte);
+    }
+    crc
+}
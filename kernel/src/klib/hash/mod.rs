@@ -0,0 +1,32 @@
+// Checksums and hashes. None of them need the heap, so they can be used anywhere, and all of them
+// can be worked out a piece at a time or over a whole buffer at once.
+//  - crc32: the CRC-32 of gzip, zlib, PNG and GPT, for checking data against what a format says
+//    it should be
+//  - crc32c: the Castagnoli CRC, which the processor works out itself with SSE4.2, for the block
+//    cache's integrity checks
+//  - sha256: SHA-256, for files that have to be exactly the ones expected
+
+pub mod crc32;
+pub mod crc32c;
+pub mod sha256;
+
+// The table for working out a CRC a byte at a time, for a bit-reversed `poly`
+pub(super) const fn make_table(poly: u32) -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ poly
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
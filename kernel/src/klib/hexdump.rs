@@ -0,0 +1,51 @@
+// Formats bytes the way `hexdump -C` does: an offset, sixteen bytes in hex, and the same bytes
+// as ASCII, with a dot for anything that isn't printable.
+//
+//   00000400  00 10 00 00 00 40 00 00  33 03 00 00 6b 35 00 00  |.....@..3...k5..|
+
+use core::fmt;
+
+const BYTES_PER_LINE: usize = 16;
+
+/// Displays `bytes` as a hex dump, numbering the lines from `offset`. Ends with a newline unless
+/// `bytes` is empty.
+pub struct HexDump<'a> {
+    bytes: &'a [u8],
+    offset: u64,
+}
+
+pub fn hexdump(bytes: &[u8], offset: u64) -> HexDump<'_> {
+    HexDump { bytes, offset }
+}
+
+impl fmt::Display for HexDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, line) in self.bytes.chunks(BYTES_PER_LINE).enumerate() {
+            write!(f, "{:08x} ", self.offset + (i * BYTES_PER_LINE) as u64)?;
+
+            for column in 0..BYTES_PER_LINE {
+                // An extra space down the middle
+                if column % 8 == 0 {
+                    write!(f, " ")?;
+                }
+                match line.get(column) {
+                    Some(byte) => write!(f, "{:02x} ", byte)?,
+                    None => write!(f, "   ")?,
+                }
+            }
+
+            write!(f, " |")?;
+            for &byte in line {
+                let ch = if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                };
+                write!(f, "{}", ch)?;
+            }
+            writeln!(f, "|")?;
+        }
+
+        Ok(())
+    }
+}
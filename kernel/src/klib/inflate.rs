@@ -0,0 +1,463 @@
+// DEFLATE (RFC 1951) and the gzip files wrapped around it (RFC 1952), for reading compressed files
+// off the disk. The compressed data has to be in memory, but what comes out of it is handed over
+// as it's decoded, a buffer at a time, so nothing bigger than the 32 KiB window back references
+// reach into has to be held on to.
+//
+// Codes are decoded a bit at a time, walking the canonical code lengths as in zlib's puff, which
+// is slow but small, and needs no tables beyond the code lengths themselves.
+
+use super::hash::crc32::Crc32;
+use crate::allocator;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+// How far back a match can reach
+const WINDOW_SIZE: usize = 32 * 1024;
+
+const MAX_CODE_LENGTH: usize = 15;
+const NUM_LITERALS: usize = 288;
+const NUM_DISTANCES: usize = 30;
+const END_OF_BLOCK: u16 = 256;
+
+// Lengths and distances of matches: the base for each symbol, and how many extra bits are added
+// to it
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; NUM_DISTANCES] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; NUM_DISTANCES] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// The order a dynamic block's code length code lengths come in
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+// gzip's header
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const GZIP_DEFLATE: u8 = 8;
+const GZIP_HEADER_SIZE: usize = 10;
+const GZIP_TRAILER_SIZE: usize = 8;
+const FHCRC: u8 = 1 << 1;
+const FEXTRA: u8 = 1 << 2;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The data ends before the stream does
+    Truncated,
+    /// A block or header that doesn't follow the format
+    Corrupt,
+    /// A match reaching back before the start of the data
+    BadDistance,
+    /// What came out doesn't match the gzip trailer's CRC or size
+    BadChecksum,
+    /// More came out than there's room for
+    TooBig,
+    /// No room on the heap for the window
+    NoMemory,
+}
+
+// The compressed data, read least significant bit first
+struct Bits<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl<'a> Bits<'a> {
+    fn bits(&mut self, count: u32) -> Result<u32, Error> {
+        while self.count < count {
+            let byte = *self.data.get(self.position).ok_or(Error::Truncated)?;
+            self.position += 1;
+            self.buffer |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1u64 << count) - 1) as u32;
+        self.buffer >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    // Drop what's left of the current byte. Fewer than 8 bits are ever held, so that's all of
+    // them.
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        &self.data[self.position..]
+    }
+}
+
+// A canonical Huffman code, as how many codes there are of each length and the symbols in code
+// order
+struct Huffman {
+    counts: [u16; MAX_CODE_LENGTH + 1],
+    symbols: [u16; NUM_LITERALS],
+}
+
+impl Huffman {
+    const EMPTY: Self = Self {
+        counts: [0; MAX_CODE_LENGTH + 1],
+        symbols: [0; NUM_LITERALS],
+    };
+
+    // The code with these lengths for symbols 0 on, where 0 means the symbol isn't used. Fails if
+    // there are more codes of some length than fit; codes that don't use up every bit pattern
+    // are fine, as long as the patterns left over never turn up.
+    fn new(lengths: &[u8]) -> Result<Self, Error> {
+        let mut code = Self::EMPTY;
+        for &length in lengths {
+            code.counts[length as usize] += 1;
+        }
+
+        let mut left: i32 = 1;
+        for length in 1..=MAX_CODE_LENGTH {
+            left = (left << 1) - code.counts[length] as i32;
+            if left < 0 {
+                return Err(Error::Corrupt);
+            }
+        }
+
+        let mut offsets = [0; MAX_CODE_LENGTH + 1];
+        for length in 1..MAX_CODE_LENGTH {
+            offsets[length + 1] = offsets[length] + code.counts[length];
+        }
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                code.symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Ok(code)
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, Error> {
+        // The first code of the current length, and where its symbols start
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..=MAX_CODE_LENGTH {
+            code |= bits.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(Error::Corrupt)
+    }
+}
+
+enum State {
+    // At the start of a block
+    Header,
+    // In a stored block, with this many bytes of it left
+    Stored(usize),
+    // In a compressed block, between symbols
+    Codes,
+    // Copying a match from this far back
+    Copy { distance: usize, left: usize },
+    Done,
+}
+
+/// Decompresses a raw DEFLATE stream, a buffer at a time.
+pub struct Inflate<'a> {
+    input: Bits<'a>,
+    state: State,
+    last_block: bool,
+    literals: Huffman,
+    distances: Huffman,
+    // The last `WINDOW_SIZE` bytes that came out, for matches to copy from
+    window: Box<[u8; WINDOW_SIZE]>,
+    written: usize,
+}
+
+impl<'a> Inflate<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, Error> {
+        let window = unsafe { allocator::try_box_zeroed() }.map_err(|()| Error::NoMemory)?;
+        Ok(Self {
+            input: Bits {
+                data,
+                position: 0,
+                buffer: 0,
+                count: 0,
+            },
+            state: State::Header,
+            last_block: false,
+            literals: Huffman::EMPTY,
+            distances: Huffman::EMPTY,
+            window,
+            written: 0,
+        })
+    }
+
+    /// Decompress into `out`, returning how much of it was filled. Less than all of it only at the
+    /// end of the stream, and 0 once it has ended.
+    pub fn read(&mut self, out: &mut [u8]) -> Result<usize, Error> {
+        let mut filled = 0;
+        while filled < out.len() {
+            let byte = match self.state {
+                State::Header => {
+                    self.start_block()?;
+                    continue;
+                }
+                State::Done => break,
+                State::Stored(0) => {
+                    self.state = State::Header;
+                    continue;
+                }
+                State::Copy { left: 0, .. } => {
+                    self.state = State::Codes;
+                    continue;
+                }
+                State::Stored(left) => {
+                    self.state = State::Stored(left - 1);
+                    self.input.bits(8)? as u8
+                }
+                State::Copy { distance, left } => {
+                    self.state = State::Copy {
+                        distance,
+                        left: left - 1,
+                    };
+                    self.window[(self.written - distance) % WINDOW_SIZE]
+                }
+                State::Codes => match self.literals.decode(&mut self.input)? {
+                    symbol if symbol < END_OF_BLOCK => symbol as u8,
+                    END_OF_BLOCK => {
+                        self.state = State::Header;
+                        continue;
+                    }
+                    symbol => {
+                        self.start_match(symbol)?;
+                        continue;
+                    }
+                },
+            };
+
+            out[filled] = byte;
+            filled += 1;
+            self.window[self.written % WINDOW_SIZE] = byte;
+            self.written += 1;
+        }
+        Ok(filled)
+    }
+
+    /// How many bytes have come out so far.
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    /// What's after the stream, once it has ended.
+    pub fn rest(&self) -> &'a [u8] {
+        self.input.rest()
+    }
+
+    fn start_block(&mut self) -> Result<(), Error> {
+        if self.last_block {
+            self.state = State::Done;
+            return Ok(());
+        }
+        self.last_block = self.input.bits(1)? == 1;
+
+        self.state = match self.input.bits(2)? {
+            0 => {
+                self.input.align();
+                let length = self.input.bits(16)?;
+                let complement = self.input.bits(16)?;
+                if length != !complement & 0xFFFF {
+                    return Err(Error::Corrupt);
+                }
+                State::Stored(length as usize)
+            }
+            1 => {
+                let mut lengths = [0; NUM_LITERALS + NUM_DISTANCES];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..NUM_LITERALS].fill(8);
+                lengths[NUM_LITERALS..].fill(5);
+                self.literals = Huffman::new(&lengths[..NUM_LITERALS])?;
+                self.distances = Huffman::new(&lengths[NUM_LITERALS..])?;
+                State::Codes
+            }
+            2 => {
+                self.read_dynamic_codes()?;
+                State::Codes
+            }
+            _ => return Err(Error::Corrupt),
+        };
+        Ok(())
+    }
+
+    // The code lengths at the start of a dynamic block, themselves Huffman coded
+    fn read_dynamic_codes(&mut self) -> Result<(), Error> {
+        let num_literals = self.input.bits(5)? as usize + 257;
+        let num_distances = self.input.bits(5)? as usize + 1;
+        let num_code_lengths = self.input.bits(4)? as usize + 4;
+        if num_literals > 286 || num_distances > NUM_DISTANCES {
+            return Err(Error::Corrupt);
+        }
+
+        let mut code_lengths = [0; 19];
+        for &symbol in &CODE_LENGTH_ORDER[..num_code_lengths] {
+            code_lengths[symbol] = self.input.bits(3)? as u8;
+        }
+        let code_lengths = Huffman::new(&code_lengths)?;
+
+        let total = num_literals + num_distances;
+        let mut lengths = [0u8; NUM_LITERALS + NUM_DISTANCES];
+        let mut i = 0;
+        while i < total {
+            let symbol = code_lengths.decode(&mut self.input)?;
+            let (length, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 => {
+                    let previous = lengths[i.checked_sub(1).ok_or(Error::Corrupt)?];
+                    (previous, 3 + self.input.bits(2)? as usize)
+                }
+                17 => (0, 3 + self.input.bits(3)? as usize),
+                _ => (0, 11 + self.input.bits(7)? as usize),
+            };
+            if i + repeat > total {
+                return Err(Error::Corrupt);
+            }
+            lengths[i..i + repeat].fill(length);
+            i += repeat;
+        }
+
+        // Without an end of block code the block could never end
+        if lengths[END_OF_BLOCK as usize] == 0 {
+            return Err(Error::Corrupt);
+        }
+        self.literals = Huffman::new(&lengths[..num_literals])?;
+        self.distances = Huffman::new(&lengths[num_literals..total])?;
+        Ok(())
+    }
+
+    // A length symbol and the distance after it
+    fn start_match(&mut self, symbol: u16) -> Result<(), Error> {
+        let index = (symbol - END_OF_BLOCK - 1) as usize;
+        if index >= LENGTH_BASE.len() {
+            return Err(Error::Corrupt);
+        }
+        let length =
+            LENGTH_BASE[index] as usize + self.input.bits(LENGTH_EXTRA[index] as u32)? as usize;
+
+        let index = self.distances.decode(&mut self.input)? as usize;
+        if index >= NUM_DISTANCES {
+            return Err(Error::Corrupt);
+        }
+        let distance =
+            DISTANCE_BASE[index] as usize + self.input.bits(DISTANCE_EXTRA[index] as u32)? as usize;
+        if distance > self.written {
+            return Err(Error::BadDistance);
+        }
+
+        self.state = State::Copy {
+            distance,
+            left: length,
+        };
+        Ok(())
+    }
+}
+
+/// Decompresses a gzip file, a buffer at a time, checking it against the CRC and size at its end.
+/// Only the first member is read, which is all there is unless files were concatenated.
+pub struct Gunzip<'a> {
+    inflate: Inflate<'a>,
+    crc: Crc32,
+    checked: bool,
+}
+
+impl<'a> Gunzip<'a> {
+    /// Fails if `data` doesn't start with a gzip header for DEFLATE data.
+    pub fn new(data: &'a [u8]) -> Result<Self, Error> {
+        let header = data.get(..GZIP_HEADER_SIZE).ok_or(Error::Truncated)?;
+        if header[..2] != GZIP_MAGIC || header[2] != GZIP_DEFLATE {
+            return Err(Error::Corrupt);
+        }
+        let flags = header[3];
+
+        let mut position = GZIP_HEADER_SIZE;
+        if flags & FEXTRA != 0 {
+            let length = data.get(position..position + 2).ok_or(Error::Truncated)?;
+            position += 2 + u16::from_le_bytes([length[0], length[1]]) as usize;
+        }
+        // The file's original name and a comment, both zero terminated
+        for field in [FNAME, FCOMMENT] {
+            if flags & field != 0 {
+                let rest = data.get(position..).ok_or(Error::Truncated)?;
+                position += rest.iter().position(|&b| b == 0).ok_or(Error::Truncated)? + 1;
+            }
+        }
+        if flags & FHCRC != 0 {
+            position += 2;
+        }
+
+        let deflated = data.get(position..).ok_or(Error::Truncated)?;
+        Ok(Self {
+            inflate: Inflate::new(deflated)?,
+            crc: Crc32::new(),
+            checked: false,
+        })
+    }
+
+    /// Decompress into `out`, like `Inflate::read`. The trailer is checked once the end is
+    /// reached.
+    pub fn read(&mut self, out: &mut [u8]) -> Result<usize, Error> {
+        let filled = self.inflate.read(out)?;
+        self.crc.update(&out[..filled]);
+
+        if filled < out.len() && !self.checked {
+            let trailer = self
+                .inflate
+                .rest()
+                .get(..GZIP_TRAILER_SIZE)
+                .ok_or(Error::Truncated)?;
+            let crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+            let size = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+            if crc != self.crc.finish() || size != self.inflate.written() as u32 {
+                return Err(Error::BadChecksum);
+            }
+            self.checked = true;
+        }
+        Ok(filled)
+    }
+}
+
+/// Decompress the whole of a gzip file, if it comes to at most `limit` bytes.
+pub fn gunzip(data: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+    // The size modulo 4 GiB, from the trailer, which is only a hint until the CRC is checked
+    let size = match data.len().checked_sub(4) {
+        Some(start) => u32::from_le_bytes(data[start..].try_into().unwrap()) as usize,
+        None => return Err(Error::Truncated),
+    };
+    if size > limit {
+        return Err(Error::TooBig);
+    }
+
+    let mut gunzip = Gunzip::new(data)?;
+    let mut out = alloc::vec![0; size];
+
+    let filled = gunzip.read(&mut out)?;
+    // Anything past the size the trailer gave means it was wrong
+    if filled == size && gunzip.read(&mut [0])? != 0 {
+        return Err(Error::BadChecksum);
+    }
+    out.truncate(filled);
+    Ok(out)
+}
@@ -0,0 +1,181 @@
+// Boot as a graph of stages rather than one long function. Each stage names the stages that have
+// to have run before it, and `run` works out an order from that, keeping the order they're listed
+// in wherever nothing says otherwise. A stage that fails has everything that needs it skipped, so
+// a failure during bring-up is put down to the stage it happened in rather than to whatever
+// tripped over it later. Each stage is timed, for `/proc/init`.
+//
+// Stages run one after another on the boot CPU. As each only relies on what it says it needs,
+// ones that don't need each other could later run on other CPUs at the same time.
+//
+// Nothing here allocates, as the first stages run before the heap is up.
+
+use crate::arch::{Arch, Cpu};
+use crate::klib::bootdiag;
+use crate::klib::containers::static_vec::StaticVec;
+use crate::{log_info, log_warn, TIMER};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const MAX_STAGES: usize = 48;
+
+// The timer has to have been running for at least this many ticks (milliseconds) for the TSC to
+// be measured against it, to turn stage times into milliseconds
+const CALIBRATION_TICKS: u64 = 10;
+
+static RECORDS: Mutex<StaticVec<Record, MAX_STAGES>> = Mutex::new(StaticVec::new());
+
+// TSC cycles per millisecond, 0 until a run has measured it
+static CYCLES_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+/// A part of boot, run with the state boot passes between stages.
+pub struct Stage<C> {
+    pub name: &'static str,
+    /// Stages that have to have run, and worked, first. Stages that aren't in the list, e.g.
+    /// drivers left out of the build, are taken to have been run.
+    pub after: &'static [&'static str],
+    pub run: fn(&mut C) -> Result<(), ()>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    Failed,
+    /// Not run, as a stage it needs failed or was skipped itself
+    Skipped,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Outcome::Ok => "ok",
+            Outcome::Failed => "failed",
+            Outcome::Skipped => "skipped",
+        };
+        f.write_str(name)
+    }
+}
+
+/// How a stage went.
+#[derive(Clone, Copy, Debug)]
+pub struct Record {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    /// TSC cycles the stage took
+    pub cycles: u64,
+    // Timer ticks and TSC when the stage started, for measuring the TSC
+    started_ticks: u64,
+    started_cycles: u64,
+}
+
+impl Record {
+    /// How long the stage took in microseconds, if the TSC has been measured against the timer.
+    pub fn micros(&self) -> Option<u64> {
+        match CYCLES_PER_MS.load(Ordering::Relaxed) {
+            0 => None,
+            per_ms => Some(self.cycles * 1000 / per_ms),
+        }
+    }
+}
+
+/// Run `stages` in an order that has every stage after those it needs. Panics if they need each
+/// other in a cycle, as no order would do.
+pub fn run<C>(stages: &[Stage<C>], context: &mut C) {
+    assert!(stages.len() <= MAX_STAGES, "Too many init stages");
+
+    let outcome_of = |outcomes: &[Option<Outcome>], name: &str| {
+        let index = stages.iter().position(|stage| stage.name == name)?;
+        Some(outcomes[index])
+    };
+    let mut outcomes = [None; MAX_STAGES];
+
+    // The first stage listed whose dependencies have all run, until there are none left
+    while let Some(next) = (0..stages.len()).find(|&i| {
+        outcomes[i].is_none()
+            && stages[i]
+                .after
+                .iter()
+                .all(|dep| outcome_of(&outcomes, dep) != Some(None))
+    }) {
+        let stage = &stages[next];
+        let started_ticks = TIMER.load(Ordering::Relaxed);
+        let started_cycles = Arch::timestamp();
+
+        let missing = stage.after.iter().find(|dep| {
+            matches!(
+                outcome_of(&outcomes, dep),
+                Some(Some(Outcome::Failed | Outcome::Skipped))
+            )
+        });
+        let outcome = match missing {
+            Some(dep) => {
+                log_warn!("Skipping {}, as {} didn't come up", stage.name, dep);
+                Outcome::Skipped
+            }
+            None => match (stage.run)(context) {
+                Ok(()) => Outcome::Ok,
+                Err(()) => {
+                    log_warn!("Init stage {} failed", stage.name);
+                    Outcome::Failed
+                }
+            },
+        };
+        outcomes[next] = Some(outcome);
+
+        let record = Record {
+            name: stage.name,
+            outcome,
+            cycles: Arch::timestamp() - started_cycles,
+            started_ticks,
+            started_cycles,
+        };
+        // Can't fail, there's a record for each stage and no more stages than records
+        let _ = interrupts::without_interrupts(|| RECORDS.lock().push(record));
+
+        bootdiag::stage(stage.name, outcome == Outcome::Ok);
+    }
+
+    if let Some(stuck) = (0..stages.len()).find(|&i| outcomes[i].is_none()) {
+        panic!(
+            "Init stage {} is part of a dependency cycle",
+            stages[stuck].name
+        );
+    }
+
+    calibrate();
+    let per_ms = CYCLES_PER_MS.load(Ordering::Relaxed);
+    let records = records();
+    let slowest = records.iter().max_by_key(|record| record.cycles);
+    if let Some(slowest) = slowest.filter(|_| per_ms > 0) {
+        let total: u64 = records.iter().map(|record| record.cycles).sum();
+        log_info!(
+            "Init took {} ms, the slowest stage was {} at {} ms",
+            total / per_ms,
+            slowest.name,
+            slowest.cycles / per_ms
+        );
+    }
+}
+
+// Measure the TSC against the timer, from the first stage that started with the timer running to
+// now
+fn calibrate() {
+    let now_ticks = TIMER.load(Ordering::Relaxed);
+    let now_cycles = Arch::timestamp();
+
+    let records = records();
+    let Some(first) = records.iter().find(|record| record.started_ticks > 0) else {
+        return;
+    };
+    let ticks = now_ticks - first.started_ticks;
+    if ticks >= CALIBRATION_TICKS {
+        let per_ms = (now_cycles - first.started_cycles) / ticks;
+        CYCLES_PER_MS.store(per_ms, Ordering::Relaxed);
+    }
+}
+
+/// How every stage that has run went, in the order they ran.
+pub fn records() -> StaticVec<Record, MAX_STAGES> {
+    interrupts::without_interrupts(|| RECORDS.lock().clone())
+}
@@ -0,0 +1,202 @@
+// Input event bus. Drivers hand their events to `publish`, and every subscriber that wants an
+// event gets a copy of it in its own queue, so e.g. the shell and a debug hotkey handler can both
+// listen to the keyboard without stealing keys from each other.
+//
+// Who gets an event is decided by the subscribers' priorities (SysRq keys aside, see `sysrq`):
+// - `Hotkey` subscribers are offered it first. The first one whose filter takes it consumes it,
+//   and it goes to no `Normal` subscriber.
+// - `Normal` subscribers all get it, unless one of them holds the grab, in which case only that
+//   one does. Hotkeys keep working while something has the grab.
+// - `Monitor` subscribers get everything their filter takes, consumed or not.
+
+use super::containers::circular_buffer::CircularBuffer;
+use super::ps2::keyboard::{KeyEvent, Modifiers, KEYBOARD};
+use super::sysrq;
+use crate::task::WaitQueue;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Events a subscriber hasn't gotten to yet. Past this, its oldest events are dropped.
+const QUEUE_SIZE: usize = 64;
+
+#[derive(Clone, Copy)]
+pub enum InputEvent {
+    Key(KeyEvent),
+    Mouse(MouseEvent),
+}
+
+/// Relative motion of a pointing device, and which of its buttons are down (bit 0 is the left
+/// button, 1 the right and 2 the middle one).
+#[derive(Clone, Copy, Default)]
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    pub buttons: u8,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Monitor,
+    Normal,
+    Hotkey,
+}
+
+/// Which events a subscriber wants.
+pub type Filter = fn(&InputEvent) -> bool;
+
+/// A filter that takes everything.
+pub fn all(_event: &InputEvent) -> bool {
+    true
+}
+
+struct Subscriber {
+    id: u64,
+    priority: Priority,
+    filter: Filter,
+    queue: Mutex<CircularBuffer<QUEUE_SIZE, InputEvent>>,
+    wait: WaitQueue,
+}
+
+impl Subscriber {
+    fn deliver(&self, event: InputEvent) {
+        self.queue.lock().push_back(event);
+        self.wait.wake_all();
+    }
+}
+
+// Sorted by priority, highest first. Only ever locked with interrupts off, as `publish` is called
+// from interrupt handlers.
+static SUBSCRIBERS: Mutex<Vec<Arc<Subscriber>>> = Mutex::new(Vec::new());
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// ID of the subscriber holding the grab, 0 if none
+static GRAB: AtomicU64 = AtomicU64::new(0);
+
+// The modifiers of the last key event published, None before the first one. Only ever locked
+// with interrupts off, like `SUBSCRIBERS`.
+static MODIFIERS: Mutex<Option<Modifiers>> = Mutex::new(None);
+
+/// Start getting the events `filter` takes. They stop once the subscription is dropped.
+pub fn subscribe(priority: Priority, filter: Filter) -> Subscription {
+    let subscriber = Arc::new(Subscriber {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        priority,
+        filter,
+        queue: Mutex::new(CircularBuffer::new()),
+        wait: WaitQueue::new(),
+    });
+
+    interrupts::without_interrupts(|| {
+        let mut subscribers = SUBSCRIBERS.lock();
+        let index = subscribers
+            .iter()
+            .position(|other| other.priority < priority)
+            .unwrap_or(subscribers.len());
+        subscribers.insert(index, subscriber.clone());
+    });
+
+    Subscription { subscriber }
+}
+
+/// Hand an event to the subscribers that should get it. Safe to call from interrupt handlers.
+pub fn publish(event: InputEvent) {
+    interrupts::without_interrupts(|| {
+        // SysRq keys go before everything, and to no one else
+        if let InputEvent::Key(key) = &event {
+            *MODIFIERS.lock() = Some(key.modifiers);
+            if sysrq::handle(key) {
+                return;
+            }
+        }
+
+        let grab = GRAB.load(Ordering::Relaxed);
+        let mut consumed = false;
+
+        for subscriber in SUBSCRIBERS.lock().iter() {
+            if !(subscriber.filter)(&event) {
+                continue;
+            }
+
+            let wanted = match subscriber.priority {
+                Priority::Hotkey => !consumed,
+                Priority::Normal => !consumed && (grab == 0 || grab == subscriber.id),
+                Priority::Monitor => true,
+            };
+            if !wanted {
+                continue;
+            }
+
+            subscriber.deliver(event);
+            if subscriber.priority == Priority::Hotkey {
+                consumed = true;
+            }
+        }
+    });
+}
+
+/// Which modifiers are held and which locks are on, as of the last key event published, from any
+/// keyboard. For code that needs to know outside of a key event, e.g. when a mouse button is
+/// clicked; key events carry their own.
+pub fn modifiers() -> Modifiers {
+    interrupts::without_interrupts(|| MODIFIERS.lock().unwrap_or_default())
+}
+
+/// Publish every key the keyboard has queued up. Called after new keys come in, whether from the
+/// PS/2 keyboard or a USB one.
+pub fn pump() {
+    loop {
+        let key = interrupts::without_interrupts(|| KEYBOARD.lock().pop_key());
+        match key {
+            Some(key) => publish(InputEvent::Key(key)),
+            None => break,
+        }
+    }
+}
+
+pub struct Subscription {
+    subscriber: Arc<Subscriber>,
+}
+
+impl Subscription {
+    /// The oldest event not taken yet, if there is one.
+    pub fn try_next(&self) -> Option<InputEvent> {
+        interrupts::without_interrupts(|| self.subscriber.queue.lock().pop_front())
+    }
+
+    /// The oldest event not taken yet, blocking until there is one.
+    pub fn next(&self) -> InputEvent {
+        loop {
+            if let Some(event) = self.try_next() {
+                return event;
+            }
+
+            self.subscriber
+                .wait
+                .wait_while(|| self.subscriber.queue.lock().is_empty());
+        }
+    }
+
+    /// Take the grab, so that other `Normal` subscribers stop getting events until it is let go.
+    /// Returns false if someone else has it.
+    pub fn grab(&self) -> bool {
+        let id = self.subscriber.id;
+        GRAB.compare_exchange(0, id, Ordering::SeqCst, Ordering::SeqCst)
+            .map_or_else(|holder| holder == id, |_| true)
+    }
+
+    pub fn release_grab(&self) {
+        let _ = GRAB.compare_exchange(self.subscriber.id, 0, Ordering::SeqCst, Ordering::SeqCst);
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.release_grab();
+        let id = self.subscriber.id;
+        interrupts::without_interrupts(|| SUBSCRIBERS.lock().retain(|other| other.id != id));
+    }
+}
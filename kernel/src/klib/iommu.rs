@@ -0,0 +1,292 @@
+// Intel VT-d IOMMUs, as described by the DMAR. Drivers hand devices physical addresses, so an
+// IOMMU the firmware left translating DMA with its own tables would send the kernel's DMA
+// somewhere else, or block it. Units that are translating are switched over to pass-through, where
+// every device's DMA goes to the address it names, or turned off if they can't do that. Units that
+// aren't translating are left alone, and all of them are reported.
+//
+// Interrupt remapping is turned off as well, as MSIs are programmed in the compatibility format,
+// which a unit remapping interrupts may block.
+
+use crate::arch::{Arch, Cpu};
+use crate::klib::acpi::dmar::{HardwareUnit, DMAR};
+use crate::klib::dma::DmaBox;
+use crate::klib::once_lock::OnceLock;
+use crate::klib::util;
+use crate::{log_info, log_warn, BootInfoFrameAllocator};
+use alloc::vec::Vec;
+use core::fmt;
+
+// Register offsets from a unit's base
+const VERSION_REGISTER: u64 = 0x00;
+const CAPABILITY_REGISTER: u64 = 0x08;
+const EXTENDED_CAPABILITY_REGISTER: u64 = 0x10;
+const GLOBAL_COMMAND_REGISTER: u64 = 0x18;
+const GLOBAL_STATUS_REGISTER: u64 = 0x1C;
+const ROOT_TABLE_REGISTER: u64 = 0x20;
+const CONTEXT_COMMAND_REGISTER: u64 = 0x28;
+// The IOTLB invalidate register is 8 bytes into the block the extended capabilities point at
+const IOTLB_INVALIDATE_OFFSET: u64 = 8;
+
+const REGISTERS_SIZE: u64 = 0x1000;
+
+// Capabilities
+const CAP_WRITE_BUFFER_FLUSH: u64 = 1 << 4;
+const CAP_ADDRESS_WIDTHS_SHIFT: u64 = 8;
+const CAP_ADDRESS_WIDTHS_MASK: u64 = 0x1F;
+
+// Extended capabilities
+const ECAP_INTERRUPT_REMAPPING: u64 = 1 << 3;
+const ECAP_PASS_THROUGH: u64 = 1 << 6;
+const ECAP_IOTLB_OFFSET_SHIFT: u64 = 8;
+const ECAP_IOTLB_OFFSET_MASK: u64 = 0x3FF;
+
+// Global command and status bits, in the same places in both registers
+const TRANSLATION: u32 = 1 << 31;
+const SET_ROOT_TABLE: u32 = 1 << 30;
+const WRITE_BUFFER_FLUSH: u32 = 1 << 27;
+const QUEUED_INVALIDATION: u32 = 1 << 26;
+const INTERRUPT_REMAPPING: u32 = 1 << 25;
+// The status bits that are also settings, rather than the one-shot commands, which have to be
+// written back as they are along with a command
+const STATUS_SETTINGS: u32 = 0x96FF_FFFF;
+
+// Context and IOTLB invalidation: start it, and the global granularity
+const INVALIDATE_CONTEXT: u64 = 1 << 63;
+const CONTEXT_GLOBAL: u64 = 1 << 61;
+const INVALIDATE_IOTLB: u64 = 1 << 63;
+const IOTLB_GLOBAL: u64 = 1 << 60;
+const IOTLB_DRAIN: u64 = (1 << 49) | (1 << 48);
+
+// Root and context entries
+const ENTRY_PRESENT: u64 = 1;
+const CONTEXT_PASS_THROUGH: u64 = 0b10 << 2;
+const DOMAIN_SHIFT: u64 = 8;
+// Every device shares the one domain
+const DOMAIN: u64 = 1;
+
+// Iterations to wait for a unit to carry out a command
+const COMMAND_TIMEOUT: usize = 10_000_000;
+
+static UNITS: OnceLock<Vec<Unit>> = OnceLock::new();
+
+/// What the kernel did with an IOMMU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Translation was off, and was left off
+    Off,
+    /// Translation was on, and now passes every device's DMA through
+    PassThrough,
+    /// Translation was on, and the unit couldn't pass DMA through, so it was turned off
+    TurnedOff,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Mode::Off => "off",
+            Mode::PassThrough => "pass-through",
+            Mode::TurnedOff => "turned off",
+        };
+        f.write_str(name)
+    }
+}
+
+/// An IOMMU, as the kernel found and left it.
+#[derive(Debug, Clone, Copy)]
+pub struct Unit {
+    /// The physical address of its registers
+    pub base: u64,
+    pub segment: u16,
+    /// Major and minor
+    pub version: (u8, u8),
+    pub mode: Mode,
+    /// Whether the firmware had interrupt remapping on, which was turned off
+    pub interrupt_remapping: bool,
+}
+
+// A root or context table: 256 entries of 16 bytes
+#[repr(C, align(4096))]
+struct Table([u64; 512]);
+
+struct Registers {
+    base: u64,
+    iotlb: u64,
+}
+
+impl Registers {
+    fn read32(&self, register: u64) -> u32 {
+        unsafe { ((self.base + register) as *const u32).read_volatile() }
+    }
+
+    fn read64(&self, register: u64) -> u64 {
+        unsafe { ((self.base + register) as *const u64).read_volatile() }
+    }
+
+    fn write32(&self, register: u64, value: u32) {
+        unsafe { ((self.base + register) as *mut u32).write_volatile(value) }
+    }
+
+    fn write64(&self, register: u64, value: u64) {
+        unsafe { ((self.base + register) as *mut u64).write_volatile(value) }
+    }
+
+    // Issue a global command, keeping the other settings as they are, and wait for the status
+    // to say it's done
+    fn command(&self, command: u32, done: impl Fn(u32) -> bool) -> Result<(), ()> {
+        let settings = self.read32(GLOBAL_STATUS_REGISTER) & STATUS_SETTINGS;
+        self.write32(GLOBAL_COMMAND_REGISTER, settings | command);
+        self.wait(|| done(self.read32(GLOBAL_STATUS_REGISTER)))
+    }
+
+    // Turn a global setting off and wait for it to go
+    fn turn_off(&self, setting: u32) -> Result<(), ()> {
+        let settings = self.read32(GLOBAL_STATUS_REGISTER) & STATUS_SETTINGS;
+        self.write32(GLOBAL_COMMAND_REGISTER, settings & !setting);
+        self.wait(|| self.read32(GLOBAL_STATUS_REGISTER) & setting == 0)
+    }
+
+    fn wait(&self, done: impl Fn() -> bool) -> Result<(), ()> {
+        for _ in 0..COMMAND_TIMEOUT {
+            if done() {
+                return Ok(());
+            }
+            Arch::pause();
+        }
+        Err(())
+    }
+}
+
+/// Find the IOMMUs in the DMAR, if there is one, and make sure DMA reaches the physical addresses
+/// drivers give their devices. Has to be called after the ACPI tables are parsed and before any
+/// driver starts DMA.
+pub fn init(frame_allocator: &mut BootInfoFrameAllocator) {
+    let Some(dmar) = DMAR.get() else {
+        return;
+    };
+
+    log_info!(
+        "DMAR: {} IOMMUs, {}-bit DMA addresses, {} reserved regions",
+        dmar.units.len(),
+        dmar.host_address_width,
+        dmar.reserved_memory.len()
+    );
+
+    let mut units = Vec::new();
+    for hardware_unit in dmar.units.iter() {
+        match unsafe { init_unit(frame_allocator, hardware_unit) } {
+            Ok(unit) => {
+                log_info!(
+                    "IOMMU at {:#x}: version {}.{}, translation {}",
+                    unit.base,
+                    unit.version.0,
+                    unit.version.1,
+                    unit.mode
+                );
+                units.push(unit);
+            }
+            Err(()) => log_warn!(
+                "IOMMU at {:#x}: couldn't take it over, DMA may not work",
+                hardware_unit.base
+            ),
+        }
+    }
+
+    let _ = UNITS.set(units);
+}
+
+/// The IOMMUs `init` found.
+pub fn units() -> &'static [Unit] {
+    UNITS.get().map_or(&[], |units| units.as_slice())
+}
+
+unsafe fn init_unit(
+    frame_allocator: &mut BootInfoFrameAllocator,
+    hardware_unit: &HardwareUnit,
+) -> Result<Unit, ()> {
+    let base = hardware_unit.base;
+    util::map_mmio(frame_allocator, base, REGISTERS_SIZE, "iommu")?;
+
+    let mut registers = Registers {
+        base: util::physical_to_kernel_address(base),
+        iotlb: 0,
+    };
+    let extended = registers.read64(EXTENDED_CAPABILITY_REGISTER);
+    registers.iotlb = ((extended >> ECAP_IOTLB_OFFSET_SHIFT) & ECAP_IOTLB_OFFSET_MASK) * 16;
+    if registers.iotlb + 16 > REGISTERS_SIZE {
+        util::map_mmio(frame_allocator, base + registers.iotlb, 16, "iommu")?;
+    }
+
+    let version = registers.read32(VERSION_REGISTER);
+    let status = registers.read32(GLOBAL_STATUS_REGISTER);
+    let mut unit = Unit {
+        base,
+        segment: hardware_unit.segment,
+        version: ((version >> 4) as u8 & 0xF, version as u8 & 0xF),
+        mode: Mode::Off,
+        interrupt_remapping: extended & ECAP_INTERRUPT_REMAPPING != 0
+            && status & INTERRUPT_REMAPPING != 0,
+    };
+
+    if unit.interrupt_remapping {
+        registers.turn_off(INTERRUPT_REMAPPING)?;
+    }
+
+    if status & TRANSLATION == 0 {
+        return Ok(unit);
+    }
+
+    // Invalidating through the registers isn't allowed while the invalidation queue is on
+    let can_pass_through = extended & ECAP_PASS_THROUGH != 0 && status & QUEUED_INVALIDATION == 0;
+    unit.mode = if can_pass_through && pass_through(frame_allocator, &registers).is_ok() {
+        Mode::PassThrough
+    } else {
+        registers.turn_off(TRANSLATION)?;
+        Mode::TurnedOff
+    };
+
+    Ok(unit)
+}
+
+// Point the unit at tables that pass every device through, then throw away what it had cached
+// from the firmware's tables
+unsafe fn pass_through(
+    frame_allocator: &mut BootInfoFrameAllocator,
+    registers: &Registers,
+) -> Result<(), ()> {
+    let capabilities = registers.read64(CAPABILITY_REGISTER);
+
+    // Pass-through entries still have to give an address width the unit supports: bit n of the
+    // supported widths is width n in the entry
+    let widths = (capabilities >> CAP_ADDRESS_WIDTHS_SHIFT) & CAP_ADDRESS_WIDTHS_MASK;
+    let width = (1..4).rev().find(|&n| widths & (1 << n) != 0).ok_or(())?;
+
+    // Every bus gets the same context table. The tables are never freed.
+    let mut root: DmaBox<Table> = DmaBox::new_zeroed(frame_allocator)?;
+    let mut context: DmaBox<Table> = DmaBox::new_zeroed(frame_allocator)?;
+    for entry in context.0.chunks_exact_mut(2) {
+        entry[0] = ENTRY_PRESENT | CONTEXT_PASS_THROUGH;
+        entry[1] = width | (DOMAIN << DOMAIN_SHIFT);
+    }
+    for entry in root.0.chunks_exact_mut(2) {
+        entry[0] = context.phys_addr() | ENTRY_PRESENT;
+    }
+
+    if capabilities & CAP_WRITE_BUFFER_FLUSH != 0 {
+        registers.command(WRITE_BUFFER_FLUSH, |status| {
+            status & WRITE_BUFFER_FLUSH == 0
+        })?;
+    }
+
+    registers.write64(ROOT_TABLE_REGISTER, root.phys_addr());
+    registers.command(SET_ROOT_TABLE, |status| status & SET_ROOT_TABLE != 0)?;
+
+    registers.write64(
+        CONTEXT_COMMAND_REGISTER,
+        INVALIDATE_CONTEXT | CONTEXT_GLOBAL,
+    );
+    registers.wait(|| registers.read64(CONTEXT_COMMAND_REGISTER) & INVALIDATE_CONTEXT == 0)?;
+
+    let iotlb = registers.iotlb + IOTLB_INVALIDATE_OFFSET;
+    registers.write64(iotlb, INVALIDATE_IOTLB | IOTLB_GLOBAL | IOTLB_DRAIN);
+    registers.wait(|| registers.read64(iotlb) & INVALIDATE_IOTLB == 0)
+}
@@ -0,0 +1,417 @@
+// Request queues for block devices. Once tasks are running, every registered block device is put
+// behind a `RequestQueue`, and the device's worker tasks take requests off of it and issue them,
+// instead of whichever task wants the disk grabbing the driver's lock and issuing them itself.
+// There are as many workers as requests the device can work on at once (e.g. NCQ slots), so a
+// disk that can reorder requests itself gets several of them at a time. Requests are submitted
+// through the queue's `BlockDevice` implementation: `read` and `write` wait for them to be done,
+// and `submit_read` and `submit_write` return an `IoFuture` straight away.
+//
+// Which request goes next is up to the queue's policy: the oldest one (`Fifo`), or the nearest one
+// in the direction the disk is sweeping in, turning around when there is nothing further that way
+// (`Look`). Either way a task can't have more than `MAX_PER_SUBMITTER` requests queued, and it
+// doesn't get more than `MAX_STREAK` of them in a row while other tasks are waiting.
+
+use super::block::{self, BlockDevice, IOError, IoCompleter, IoFuture};
+use crate::arch::x86_64::interrupts::without_interrupts;
+use crate::log_warn;
+use crate::task::{self, Priority, TaskId, WaitQueue};
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::MaybeUninit;
+use spin::{Mutex, RwLock};
+
+const MAX_PENDING: usize = 64;
+/// Most requests one task can have queued at once.
+pub const MAX_PER_SUBMITTER: usize = 16;
+const MAX_STREAK: usize = 4;
+const MAX_WORKERS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    Fifo,
+    Look,
+}
+
+/// What a queue has done so far.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QueueStats {
+    pub completed: u64,
+    pub errors: u64,
+    /// Bytes moved by the requests that succeeded
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Bytes between where each request started and where the one before it ended
+    pub seek_distance: u64,
+}
+
+enum Operation {
+    Read(usize),
+    Write(Vec<u8>),
+}
+
+struct Request {
+    // Order of submission, for `Fifo`
+    sequence: u64,
+    offset: usize,
+    operation: Operation,
+    submitter: Option<TaskId>,
+    on_complete: IoCompleter,
+}
+
+impl Request {
+    fn len(&self) -> usize {
+        match &self.operation {
+            Operation::Read(len) => *len,
+            Operation::Write(data) => data.len(),
+        }
+    }
+}
+
+struct Pending {
+    requests: Vec<Request>,
+    policy: Policy,
+    next_sequence: u64,
+    // Where the last request ended, and which way `Look` is sweeping
+    head: usize,
+    ascending: bool,
+    // Who the last request was from, and how many in a row were
+    last_submitter: Option<TaskId>,
+    streak: usize,
+    stats: QueueStats,
+}
+
+impl Pending {
+    fn is_full_for(&self, submitter: Option<TaskId>) -> bool {
+        let queued = self
+            .requests
+            .iter()
+            .filter(|request| request.submitter == submitter)
+            .count();
+        self.requests.len() >= MAX_PENDING || queued >= MAX_PER_SUBMITTER
+    }
+
+    // Add `request` to the queue, or hand it back if there is no room for it
+    fn push(&mut self, mut request: Request) -> Result<(), Request> {
+        if self.is_full_for(request.submitter) {
+            return Err(request);
+        }
+
+        request.sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.requests.push(request);
+        Ok(())
+    }
+
+    // Take the request that should go next off the queue
+    fn pick(&mut self) -> Option<Request> {
+        let last = self.last_submitter;
+        let others_waiting = self
+            .requests
+            .iter()
+            .any(|request| request.submitter != last);
+        let skip_last = self.streak >= MAX_STREAK && others_waiting;
+        let eligible = |request: &Request| !(skip_last && request.submitter == last);
+
+        let index = match self.policy {
+            Policy::Fifo => self
+                .requests
+                .iter()
+                .enumerate()
+                .filter(|(_, request)| eligible(request))
+                .min_by_key(|(_, request)| request.sequence)
+                .map(|(index, _)| index),
+            Policy::Look => {
+                let head = self.head;
+                let nearest = |ascending: bool| {
+                    self.requests
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, request)| eligible(request))
+                        .filter(|(_, request)| (request.offset >= head) == ascending)
+                        .min_by_key(|(_, request)| request.offset.abs_diff(head))
+                        .map(|(index, _)| index)
+                };
+
+                match nearest(self.ascending) {
+                    Some(index) => Some(index),
+                    None => {
+                        self.ascending = !self.ascending;
+                        nearest(self.ascending)
+                    }
+                }
+            }
+        }?;
+
+        let request = self.requests.swap_remove(index);
+
+        if request.submitter == self.last_submitter {
+            self.streak += 1;
+        } else {
+            self.last_submitter = request.submitter;
+            self.streak = 1;
+        }
+        self.stats.seek_distance += request.offset.abs_diff(self.head) as u64;
+        self.head = request.offset + request.len();
+
+        Some(request)
+    }
+}
+
+pub struct RequestQueue {
+    device: Arc<dyn BlockDevice>,
+    pending: Mutex<Pending>,
+    // The workers wait here for requests, and submitters for room in the queue
+    work: WaitQueue,
+    room: WaitQueue,
+    workers: Mutex<Vec<TaskId>>,
+}
+
+impl RequestQueue {
+    /// Put a queue in front of `device`, and spawn the tasks that issue its requests: one for
+    /// every request the device can work on at once, up to `MAX_WORKERS`.
+    pub fn start(name: &str, device: Arc<dyn BlockDevice>) -> Result<Arc<Self>, ()> {
+        let num_workers = device.queue_depth().clamp(1, MAX_WORKERS);
+        let queue = Arc::new(Self {
+            device,
+            pending: Mutex::new(Pending {
+                requests: Vec::with_capacity(MAX_PENDING),
+                policy: Policy::Look,
+                next_sequence: 0,
+                head: 0,
+                ascending: true,
+                last_submitter: None,
+                streak: 0,
+                stats: QueueStats::default(),
+            }),
+            work: WaitQueue::new(),
+            room: WaitQueue::new(),
+            workers: Mutex::new(Vec::with_capacity(num_workers)),
+        });
+
+        // Task names are static, and queues are never torn down
+        let task_name: &'static str = Box::leak(format!("io-{}", name).into_boxed_str());
+        for _ in 0..num_workers {
+            let worker = queue.clone();
+            let Ok(id) = task::spawn(task_name, Priority::Normal, move || worker.run()) else {
+                break;
+            };
+            without_interrupts(|| queue.workers.lock().push(id));
+        }
+
+        if without_interrupts(|| queue.workers.lock().is_empty()) {
+            return Err(());
+        }
+        Ok(queue)
+    }
+
+    pub fn policy(&self) -> Policy {
+        without_interrupts(|| self.pending.lock().policy)
+    }
+
+    /// Takes effect from the next request on.
+    pub fn set_policy(&self, policy: Policy) {
+        without_interrupts(|| self.pending.lock().policy = policy);
+    }
+
+    /// The number of requests waiting, and what the queue has done so far.
+    pub fn stats(&self) -> (usize, QueueStats) {
+        without_interrupts(|| {
+            let pending = self.pending.lock();
+            (pending.requests.len(), pending.stats)
+        })
+    }
+
+    // Queue a request. With `wait_for_room`, a full queue is waited on, otherwise the request
+    // fails with `TryAgain`.
+    fn submit(&self, offset: usize, operation: Operation, wait_for_room: bool) -> IoFuture {
+        let (future, on_complete) = IoFuture::pending();
+        let block_size = self.device.block_size();
+        let submitter = task::current();
+        let mut request = Request {
+            sequence: 0,
+            offset,
+            operation,
+            submitter,
+            on_complete,
+        };
+        if offset % block_size != 0 || request.len() % block_size != 0 {
+            request.on_complete.complete(Err(IOError::Invalid));
+            return future;
+        }
+
+        loop {
+            if wait_for_room {
+                self.room
+                    .wait_while(|| self.pending.lock().is_full_for(submitter));
+            }
+
+            match without_interrupts(|| self.pending.lock().push(request)) {
+                Ok(()) => break,
+                // Someone else may have taken the room between waking up and getting here
+                Err(returned) if wait_for_room => request = returned,
+                Err(returned) => {
+                    returned.on_complete.complete(Err(IOError::TryAgain));
+                    return future;
+                }
+            }
+        }
+
+        self.work.wake_one();
+        future
+    }
+
+    // Whether the caller has to go straight to the device rather than wait on the queue: if
+    // nothing could switch to a worker, or the caller is one (e.g. in a callback)
+    fn bypass(&self) -> bool {
+        let current = task::current();
+        !task::is_running()
+            || without_interrupts(|| {
+                let workers = self.workers.lock();
+                workers.is_empty() || matches!(current, Some(id) if workers.contains(&id))
+            })
+    }
+
+    fn run(&self) {
+        loop {
+            self.work
+                .wait_while(|| self.pending.lock().requests.is_empty());
+
+            let Some(request) = without_interrupts(|| self.pending.lock().pick()) else {
+                continue;
+            };
+            self.room.wake_all();
+
+            crate::trace!(
+                Block,
+                "dispatch {} bytes at {}",
+                request.len(),
+                request.offset
+            );
+            let len = request.len() as u64;
+            let write = matches!(request.operation, Operation::Write(_));
+            let result = match request.operation {
+                Operation::Read(len) => {
+                    let mut buf = alloc::vec![MaybeUninit::uninit(); len];
+                    self.device
+                        .read(&mut buf, request.offset)
+                        .map(|data| data.to_vec())
+                }
+                Operation::Write(data) => self
+                    .device
+                    .write(&data, request.offset)
+                    .map(|()| Vec::new()),
+            };
+
+            without_interrupts(|| {
+                let stats = &mut self.pending.lock().stats;
+                stats.completed += 1;
+                match (&result, write) {
+                    (Err(_), _) => stats.errors += 1,
+                    (Ok(_), false) => stats.bytes_read += len,
+                    (Ok(_), true) => stats.bytes_written += len,
+                }
+            });
+
+            request.on_complete.complete(result);
+        }
+    }
+}
+
+impl BlockDevice for RequestQueue {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.device.num_blocks()
+    }
+
+    fn read<'a>(
+        &self,
+        buf: &'a mut [MaybeUninit<u8>],
+        offset: usize,
+    ) -> Result<&'a mut [u8], IOError> {
+        if self.bypass() {
+            return self.device.read(buf, offset);
+        }
+
+        let data = self
+            .submit(offset, Operation::Read(buf.len()), true)
+            .wait()?;
+        for (dst, &src) in buf.iter_mut().zip(&data) {
+            dst.write(src);
+        }
+        Ok(unsafe { MaybeUninit::slice_assume_init_mut(buf) })
+    }
+
+    fn write(&self, buf: &[u8], offset: usize) -> Result<(), IOError> {
+        if self.bypass() {
+            return self.device.write(buf, offset);
+        }
+
+        self.submit(offset, Operation::Write(buf.to_vec()), true)
+            .wait()
+            .map(|_| ())
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.device.queue_depth()
+    }
+
+    /// Fails with `TryAgain` if the queue is full, or the calling task already has as many
+    /// requests queued as it is allowed.
+    fn submit_read(&self, lba: usize, count: usize) -> IoFuture {
+        let block_size = self.device.block_size();
+        self.submit(lba * block_size, Operation::Read(count * block_size), false)
+    }
+
+    /// Fails like `submit_read`.
+    fn submit_write(&self, lba: usize, data: Vec<u8>) -> IoFuture {
+        let offset = lba * self.device.block_size();
+        self.submit(offset, Operation::Write(data), false)
+    }
+}
+
+static QUEUES: RwLock<Vec<(String, Arc<RequestQueue>)>> = RwLock::new(Vec::new());
+
+/// Put a request queue in front of every registered block device, so that from then on everything
+/// that gets them through `block::get` goes through the queues. Has to be called after
+/// `task::init`, as each queue has a task of its own.
+pub fn init() {
+    if block::on_remove("request queues", forget_queue).is_err() {
+        log_warn!("Couldn't have the request queues told about disks going away");
+    }
+
+    for (name, device) in block::devices() {
+        let Ok(queue) = RequestQueue::start(&name, device) else {
+            log_warn!("Couldn't start a request queue for {}", name);
+            continue;
+        };
+
+        if block::replace(&name, queue.clone()).is_ok() {
+            QUEUES.write().push((name, queue));
+        }
+    }
+}
+
+// A device that went away takes its queue out of the list. Its tasks stay, failing whatever is
+// still queued, as queues are never torn down.
+fn forget_queue(name: &str, _device: &Arc<dyn BlockDevice>) {
+    QUEUES.write().retain(|(queue_name, _)| queue_name != name);
+}
+
+pub fn get(name: &str) -> Option<Arc<RequestQueue>> {
+    QUEUES
+        .read()
+        .iter()
+        .find(|(queue_name, _)| queue_name == name)
+        .map(|(_, queue)| queue.clone())
+}
+
+/// Every queue, in the order their devices were registered.
+pub fn queues() -> Vec<(String, Arc<RequestQueue>)> {
+    QUEUES.read().clone()
+}
@@ -0,0 +1,128 @@
+// Heap pointers whose constructors return an error when the heap is full, rather than panicking
+// like `Box::new` and `Arc::new` do (see `allocator::try_alloc`), for driver setup that can leave
+// a device out and carry on booting. Small values come out of the slab allocator like any other
+// allocation.
+
+use crate::allocator;
+use alloc::alloc::dealloc;
+use alloc::boxed::Box;
+use core::alloc::Layout;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+
+/// A `Box` that can fail to be made. Turns into a plain `Box` with `into_box`.
+pub struct KBox<T>(Box<T>);
+
+impl<T> KBox<T> {
+    /// `value` on the heap, or an error (and `value` dropped) if there is no room for it.
+    pub fn try_new(value: T) -> Result<Self, ()> {
+        let ptr = allocator::try_alloc(Layout::new::<T>())?.cast::<T>();
+        unsafe {
+            ptr.as_ptr().write(value);
+            Ok(Self(Box::from_raw(ptr.as_ptr())))
+        }
+    }
+
+    /// A zeroed `T` on the heap, for the large DMA structures that would otherwise be built on the
+    /// stack first.
+    /// ### Safety
+    /// All zeroes has to be a valid `T`.
+    pub unsafe fn try_new_zeroed() -> Result<Self, ()> {
+        allocator::try_box_zeroed().map(Self)
+    }
+
+    pub fn into_box(self) -> Box<T> {
+        self.0
+    }
+}
+
+impl<T> Deref for KBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for KBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+struct ArcInner<T> {
+    count: AtomicUsize,
+    value: T,
+}
+
+/// A reference counted pointer like `Arc` that can fail to be made. There are no weak references,
+/// and it can't hold a `dyn` value.
+pub struct KArc<T> {
+    ptr: NonNull<ArcInner<T>>,
+    _marker: PhantomData<ArcInner<T>>,
+}
+
+// Like `Arc`: clones on other CPUs share the value, and the last one drops it
+unsafe impl<T: Send + Sync> Send for KArc<T> {}
+unsafe impl<T: Send + Sync> Sync for KArc<T> {}
+
+impl<T> KArc<T> {
+    /// `value` on the heap, or an error (and `value` dropped) if there is no room for it.
+    pub fn try_new(value: T) -> Result<Self, ()> {
+        let ptr = allocator::try_alloc(Layout::new::<ArcInner<T>>())?.cast::<ArcInner<T>>();
+        unsafe {
+            ptr.as_ptr().write(ArcInner {
+                count: AtomicUsize::new(1),
+                value,
+            });
+        }
+        Ok(Self {
+            ptr,
+            _marker: PhantomData,
+        })
+    }
+
+    /// How many `KArc`s point at the value.
+    pub fn count(this: &Self) -> usize {
+        this.inner().count.load(Ordering::Relaxed)
+    }
+
+    fn inner(&self) -> &ArcInner<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> Clone for KArc<T> {
+    fn clone(&self) -> Self {
+        self.inner().count.fetch_add(1, Ordering::Relaxed);
+        Self {
+            ptr: self.ptr,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Drop for KArc<T> {
+    fn drop(&mut self) {
+        if self.inner().count.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+
+        // Every other clone's use of the value happens before it is dropped
+        fence(Ordering::Acquire);
+        unsafe {
+            core::ptr::drop_in_place(self.ptr.as_ptr());
+            dealloc(self.ptr.as_ptr() as *mut u8, Layout::new::<ArcInner<T>>());
+        }
+    }
+}
+
+impl<T> Deref for KArc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner().value
+    }
+}
@@ -0,0 +1,65 @@
+// Where the kernel's big regions of virtual memory go. Each one has a range of its own to go in,
+// and is put somewhere at random in it at boot, so that an overflow or a leaked pointer doesn't
+// say where anything else is. At least `GUARD_GAP` is left unmapped on either side of a region,
+// so running off the end of one faults rather than reaching into the next.
+//
+// `nokaslr` on the command line puts every region at the bottom of its range instead, for
+// comparing addresses between boots when debugging. `/proc/layout` lists where they all went.
+
+use crate::klib::cmdline;
+use crate::klib::containers::static_vec::StaticVec;
+use crate::klib::rand;
+use crate::log_info;
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const GUARD_GAP: u64 = 1 << 30;
+
+const MAX_REGIONS: usize = 8;
+
+static REGIONS: Mutex<StaticVec<Region, MAX_REGIONS>> = Mutex::new(StaticVec::new());
+
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: u64,
+    pub size: u64,
+}
+
+/// Whether regions are put at random.
+pub fn randomized() -> bool {
+    !cmdline::has("nokaslr")
+}
+
+/// Pick where `size` bytes go in `range`, aligned to `align`, leaving `GUARD_GAP` on either side,
+/// and note it down as `name`. Panics if `range` is too small for that, which is a mistake in
+/// the range rather than something that depends on the machine.
+pub fn place(name: &'static str, range: Range<u64>, size: u64, align: u64) -> u64 {
+    let first = (range.start + GUARD_GAP).next_multiple_of(align);
+    let last = range
+        .end
+        .checked_sub(GUARD_GAP + size)
+        .filter(|&last| last >= first)
+        .unwrap_or_else(|| panic!("No room for the {} in its range", name));
+    let slots = (last - first) / align + 1;
+
+    let slot = if randomized() {
+        rand::get_random_u64() % slots
+    } else {
+        0
+    };
+    let start = first + slot * align;
+
+    let region = Region { name, start, size };
+    if interrupts::without_interrupts(|| REGIONS.lock().push(region)).is_err() {
+        panic!("Too many regions in the layout");
+    }
+    log_info!("{} at {:#x}", name, start);
+    start
+}
+
+/// Every region placed so far, in the order they were placed.
+pub fn regions() -> StaticVec<Region, MAX_REGIONS> {
+    interrupts::without_interrupts(|| REGIONS.lock().clone())
+}
@@ -0,0 +1,204 @@
+use super::clock;
+use super::containers::static_string::StaticString;
+use super::graphics::framebuffer::{self, TextColor};
+use super::time::Timestamp;
+use alloc::vec::Vec;
+use core::fmt;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// How many records are kept before the oldest start being overwritten.
+pub const NUM_RECORDS: usize = 256;
+
+// Longer messages are cut off
+const MESSAGE_SIZE: usize = 120;
+
+static LOG: Mutex<Ring> = Mutex::new(Ring {
+    records: [Record::EMPTY; NUM_RECORDS],
+    next: 0,
+    len: 0,
+});
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+// Records less severe than this are only kept in the ring, not printed
+static CONSOLE_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+impl Level {
+    pub const ALL: [Level; 4] = [Level::Error, Level::Warn, Level::Info, Level::Debug];
+
+    /// The level called `name`, in any case.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|level| level.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+
+    // What records of the level are printed in, foreground and background, so that errors and
+    // warnings stand out
+    fn colors(self) -> (TextColor, TextColor) {
+        match self {
+            Level::Error => (TextColor::White, TextColor::Red),
+            Level::Warn => (TextColor::Black, TextColor::Yellow),
+            Level::Info => (TextColor::White, TextColor::Black),
+            Level::Debug => (TextColor::DarkGray, TextColor::Black),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Record {
+    /// Milliseconds since the timer started
+    pub uptime_ms: u64,
+    pub level: Level,
+    pub module: &'static str,
+    pub message: StaticString<MESSAGE_SIZE>,
+}
+
+impl Record {
+    const EMPTY: Self = Self {
+        uptime_ms: 0,
+        level: Level::Debug,
+        module: "",
+        message: StaticString::new(),
+    };
+}
+
+/// Stamped with the uptime, or with `{:#}` the time of day, if it's known.
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Every module is in this crate, so its name is just noise
+        let module = self
+            .module
+            .split_once("::")
+            .map_or(self.module, |(_, rest)| rest);
+        match Timestamp::at_uptime(self.uptime_ms).filter(|_| f.alternate()) {
+            Some(time) => write!(f, "[{}] ", time)?,
+            None => write!(
+                f,
+                "[{:>5}.{:03}] ",
+                self.uptime_ms / 1000,
+                self.uptime_ms % 1000
+            )?,
+        }
+        write!(f, "{:<5} {}: {}", self.level.name(), module, self.message)
+    }
+}
+
+struct Ring {
+    records: [Record; NUM_RECORDS],
+    next: usize,
+    len: usize,
+}
+
+impl Ring {
+    fn push(&mut self, record: Record) {
+        self.records[self.next] = record;
+        self.next = (self.next + 1) % NUM_RECORDS;
+        self.len = (self.len + 1).min(NUM_RECORDS);
+    }
+
+    // The last `count` records, oldest first
+    fn recent(&self, count: usize) -> impl Iterator<Item = &Record> {
+        let count = count.min(self.len);
+        let start = (self.next + NUM_RECORDS - count) % NUM_RECORDS;
+        (0..count).map(move |i| &self.records[(start + i) % NUM_RECORDS])
+    }
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, module: &'static str, args: fmt::Arguments) {
+    let mut record = Record {
+        uptime_ms: clock::uptime_ms(),
+        level,
+        module,
+        ..Record::EMPTY
+    };
+    let _ = record.message.write_fmt(args);
+
+    interrupts::without_interrupts(|| LOG.lock().push(record));
+
+    if level <= console_level() {
+        let (foreground, background) = level.colors();
+        interrupts::without_interrupts(|| {
+            framebuffer::set_color(foreground, background);
+            crate::print!("{}", args);
+            framebuffer::reset_color();
+            crate::println!();
+        });
+    }
+}
+
+/// The least severe level that is printed as well as kept in the ring. Info to begin with.
+pub fn console_level() -> Level {
+    Level::ALL[CONSOLE_LEVEL.load(Ordering::Relaxed) as usize]
+}
+
+pub fn set_console_level(level: Level) {
+    CONSOLE_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// A copy of the last `count` records, oldest first.
+pub fn recent(count: usize) -> Vec<Record> {
+    // Allocated up front, as the log is locked from interrupt handlers too
+    let mut records = Vec::with_capacity(count.min(NUM_RECORDS));
+    interrupts::without_interrupts(|| records.extend(LOG.lock().recent(count).copied()));
+    records
+}
+
+/// Write out the last `count` records without allocating, for the panic path, which can't wait
+/// on the lock if the panic happened while it was held.
+pub fn write_recent(w: &mut impl Write, count: usize) -> fmt::Result {
+    let Some(log) = LOG.try_lock() else {
+        return writeln!(w, "log was locked");
+    };
+
+    for record in log.recent(count) {
+        writeln!(w, "{}", record)?;
+    }
+    Ok(())
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => (
+        $crate::klib::log::_log($level, module_path!(), format_args!($($arg)*))
+    );
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => ($crate::log!($crate::klib::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => ($crate::log!($crate::klib::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => ($crate::log!($crate::klib::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => ($crate::log!($crate::klib::log::Level::Debug, $($arg)*));
+}
@@ -0,0 +1,74 @@
+// Register wrappers for memory-mapped I/O.
+//
+// Register structs are never constructed; they are placed over device memory by casting a pointer
+// to the register block. Every access goes through a raw pointer to the wrapper itself, so the
+// value is never copied out of device memory by accident, and is surrounded by a compiler fence so
+// that e.g. filling in a command in RAM can't be moved past the write that tells the device about it.
+// x86 doesn't reorder accesses to uncached memory, so no hardware fence is needed on top of that.
+
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{compiler_fence, Ordering};
+
+#[inline(always)]
+unsafe fn read<T: Copy>(ptr: *const T) -> T {
+    let value = read_volatile(ptr);
+    compiler_fence(Ordering::Acquire);
+    value
+}
+
+#[inline(always)]
+unsafe fn write<T: Copy>(ptr: *mut T, value: T) {
+    compiler_fence(Ordering::Release);
+    write_volatile(ptr, value);
+}
+
+/// A register that can only be read, e.g. a capabilities or status register.
+#[repr(transparent)]
+pub struct ReadOnly<T: Copy> {
+    value: T,
+}
+
+impl<T: Copy> ReadOnly<T> {
+    #[inline]
+    pub fn read(&self) -> T {
+        unsafe { read(&self.value as *const T) }
+    }
+}
+
+/// A register that can only be written, e.g. a doorbell. Reading one back is either meaningless
+/// or has side effects.
+#[repr(transparent)]
+pub struct WriteOnly<T: Copy> {
+    value: T,
+}
+
+impl<T: Copy> WriteOnly<T> {
+    #[inline]
+    pub fn write(&mut self, value: T) {
+        unsafe { write(&mut self.value as *mut T, value) }
+    }
+}
+
+#[repr(transparent)]
+pub struct ReadWrite<T: Copy> {
+    value: T,
+}
+
+impl<T: Copy> ReadWrite<T> {
+    #[inline]
+    pub fn read(&self) -> T {
+        unsafe { read(&self.value as *const T) }
+    }
+
+    #[inline]
+    pub fn write(&mut self, value: T) {
+        unsafe { write(&mut self.value as *mut T, value) }
+    }
+
+    /// Read the register, pass the value through `f`, and write the result back.
+    #[inline]
+    pub fn modify(&mut self, f: impl FnOnce(T) -> T) {
+        let value = self.read();
+        self.write(f(value));
+    }
+}
@@ -1,13 +1,59 @@
 pub mod ahci;
+pub mod allocbench;
+pub mod ata;
+pub mod bcache;
+pub mod block;
+pub mod bootdiag;
+pub mod clock;
+pub mod cmdline;
+pub mod cmos;
+pub mod crashdump;
+pub mod diskbench;
+pub mod dma;
+pub mod executor;
 pub mod graphics;
-pub mod idt;
+pub mod hash;
+pub mod hexdump;
+pub mod inflate;
+pub mod initgraph;
+pub mod input;
+pub mod iommu;
+pub mod iosched;
+pub mod kbox;
+pub mod layout;
+pub mod log;
+pub mod mmio;
+#[cfg(feature = "driver-nvme")]
+pub mod nvme;
 pub mod once_lock;
 pub mod pci;
-pub mod pic;
+pub mod phys_mapper;
+pub mod polling;
+pub mod profiler;
 pub mod ps2;
+#[cfg(feature = "qemu")]
+pub mod qemu;
+pub mod rand;
+pub mod serial;
+pub mod shutdown;
+pub mod speaker;
+pub mod stack_protector;
+pub mod suspend;
+pub mod sysrq;
+pub mod telemetry;
+pub mod time;
+pub mod timer;
+pub mod tlb;
+pub mod trace;
+pub mod uaccess;
+#[cfg(feature = "driver-xhci")]
+pub mod usb;
 pub mod util;
+pub mod uuid;
+pub mod version;
 pub mod vga_console;
-pub mod x86_64;
+#[cfg(feature = "driver-xhci")]
+pub mod xhci;
 
 pub mod acpi;
 
@@ -0,0 +1,121 @@
+use super::mmio::{ReadOnly, ReadWrite};
+
+pub mod nvmestate;
+// Written against the NVM Express Base Specification, revision 1.4
+
+// Controller registers, at the start of BAR 0. The doorbells follow at DOORBELL_BASE.
+#[repr(C)]
+pub struct Registers {
+    pub capabilities: ReadOnly<u64>, // CAP: controller capabilities [R]
+    pub version: ReadOnly<u32>,      // VS
+    pub interrupt_mask_set: ReadWrite<u32>, // INTMS (only for pin/MSI interrupts)
+    pub interrupt_mask_clear: ReadWrite<u32>, // INTMC
+    pub controller_config: ReadWrite<u32>, // CC
+    pub reserved: u32,
+    pub controller_status: ReadOnly<u32>,       // CSTS
+    pub subsystem_reset: ReadWrite<u32>,        // NSSR
+    pub admin_queue_attributes: ReadWrite<u32>, // AQA: sizes of the admin queues, 0-based
+    pub admin_sq_addr: ReadWrite<u64>, // ASQ: physical address of the admin submission queue
+    pub admin_cq_addr: ReadWrite<u64>, // ACQ: physical address of the admin completion queue
+}
+
+pub const DOORBELL_BASE: u64 = 0x1000;
+
+#[repr(u64)]
+pub enum CapabilityMasks {
+    MaxQueueEntries = 0xFFFF,   // MQES, 0-based
+    DoorbellStride = 0xF << 32, // DSTRD: doorbells are (4 << DSTRD) bytes apart
+    NVMCommandSet = 1 << 37,    // CSS bit 0
+    MinPageSize = 0xF << 48,    // MPSMIN: 2 ^ (12 + MPSMIN)
+}
+
+#[repr(u32)]
+pub enum ConfigMasks {
+    Enable = 0x1,
+    // 2^6 = 64 byte submission entries, 2^4 = 16 byte completion entries
+    QueueEntrySizes = (6 << 16) | (4 << 20),
+}
+
+#[repr(u32)]
+pub enum StatusMasks {
+    Ready = 0x1,
+    FatalStatus = 0x2,
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub enum AdminCommand {
+    CreateIOSubmissionQueue = 0x01,
+    CreateIOCompletionQueue = 0x05,
+    Identify = 0x06,
+    SetFeatures = 0x09,
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub enum IOCommand {
+    Write = 0x01,
+    Read = 0x02,
+}
+
+// Values of CDW10 for Identify
+#[repr(u32)]
+pub enum IdentifyCNS {
+    Namespace = 0x0,
+    Controller = 0x1,
+    ActiveNamespaces = 0x2,
+}
+
+pub const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct SubmissionEntry {
+    pub opcode: u8,
+    pub flags: u8,
+    pub command_id: u16,
+    pub nsid: u32,
+    pub reserved: u64,
+    pub metadata: u64,
+    pub prp1: u64, // Physical address of the data (or of the first page of it)
+    pub prp2: u64, // Physical address of the second page, if the data crosses a page boundary
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct CompletionEntry {
+    pub result: u32, // Command specific
+    pub reserved: u32,
+    pub sq_head: u16,
+    pub sq_id: u16,
+    pub command_id: u16,
+    pub status: u16, // Bit 0 is the phase tag, the rest is the status field (0 on success)
+}
+
+// Entries per queue. Small enough that each queue fits in a single page.
+pub const QUEUE_SIZE: usize = 64;
+
+#[repr(align(4096))]
+#[repr(C)]
+pub struct SubmissionQueue {
+    pub entries: [SubmissionEntry; QUEUE_SIZE],
+}
+
+#[repr(align(4096))]
+#[repr(C)]
+pub struct CompletionQueue {
+    pub entries: [ReadOnly<CompletionEntry>; QUEUE_SIZE],
+}
+
+// Identify data is always 4 KiB long. Aligning it to a page means one PRP entry covers it.
+#[repr(align(4096))]
+#[repr(C)]
+pub struct IdentifyData {
+    pub bytes: [u8; 4096],
+}
@@ -0,0 +1,621 @@
+use super::super::pci;
+use super::super::util;
+use super::{
+    AdminCommand, CapabilityMasks, CompletionQueue, ConfigMasks, IOCommand, IdentifyCNS,
+    IdentifyData, Registers, StatusMasks, SubmissionEntry, SubmissionQueue, DOORBELL_BASE,
+    FEATURE_NUMBER_OF_QUEUES, QUEUE_SIZE,
+};
+use crate::allocator;
+use crate::arch::{Arch, Cpu};
+use crate::klib::block;
+use crate::klib::block::BlockDevice;
+use crate::klib::block::IOError;
+use crate::klib::mmio::WriteOnly;
+use crate::klib::once_lock::OnceLock;
+use crate::klib::pci::msix;
+use crate::klib::pci::pcistate::PCI_STATE;
+use crate::klib::pci::power;
+use crate::task;
+use crate::task::WaitQueue;
+use crate::BootInfoFrameAllocator;
+use crate::{log_info, log_warn};
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::MaybeUninit;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicU8, Ordering};
+use pci::pcistate::PCIState;
+use pci::Register;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Interrupt vector for I/O completions, delivered through MSI-X table entry 0. Whatever vector was
+/// handed to `new`, 0 before that.
+pub static MSIX_VECTOR: AtomicU8 = AtomicU8::new(0);
+
+const PAGE_SIZE: usize = 4096;
+
+const ADMIN_QUEUE_ID: u16 = 0;
+const IO_QUEUE_ID: u16 = 1;
+
+// A queue with n entries can only hold n - 1 commands, since a full queue would look empty.
+const IO_SLOTS: usize = QUEUE_SIZE - 1;
+const IO_SLOTS_MASK: u64 = (1 << IO_SLOTS) - 1;
+
+pub static NVME0: OnceLock<Mutex<NVMeState>> = OnceLock::new();
+
+// Tasks waiting for an I/O command to complete
+static IO_WAIT: WaitQueue = WaitQueue::new();
+
+struct QueuePair {
+    sq: Box<SubmissionQueue>,
+    cq: Box<CompletionQueue>,
+    sq_tail: u16,
+    cq_head: u16,
+    // Flips every time the completion queue wraps around, so that new entries can be told from
+    // old ones.
+    phase: bool,
+    sq_doorbell: &'static mut WriteOnly<u32>,
+    cq_doorbell: &'static mut WriteOnly<u32>,
+}
+
+impl QueuePair {
+    /// ### Safety
+    /// `regs_base` must point to the mapped controller registers, including the doorbells for
+    /// queue `id`.
+    unsafe fn new(id: u16, regs_base: u64, doorbell_stride: u64) -> Self {
+        let sq_doorbell = regs_base + DOORBELL_BASE + (2 * id as u64) * doorbell_stride;
+        let cq_doorbell = regs_base + DOORBELL_BASE + (2 * id as u64 + 1) * doorbell_stride;
+
+        Self {
+            sq: Box::new(core::mem::zeroed()),
+            cq: Box::new(core::mem::zeroed()),
+            sq_tail: 0,
+            cq_head: 0,
+            phase: true,
+            sq_doorbell: &mut *(sq_doorbell as *mut WriteOnly<u32>),
+            cq_doorbell: &mut *(cq_doorbell as *mut WriteOnly<u32>),
+        }
+    }
+
+    fn sq_address(&self) -> u64 {
+        util::kernel_to_physical_address(addr_of!(*self.sq) as u64)
+    }
+
+    fn cq_address(&self) -> u64 {
+        util::kernel_to_physical_address(addr_of!(*self.cq) as u64)
+    }
+
+    fn submit(&mut self, entry: SubmissionEntry) {
+        self.sq.entries[self.sq_tail as usize] = entry;
+        self.sq_tail = (self.sq_tail + 1) % QUEUE_SIZE as u16;
+
+        // The entry has to be in memory before the controller hears about it
+        core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
+
+        self.sq_doorbell.write(self.sq_tail as u32);
+    }
+
+    /// Take the next completion off of the queue, if there is one.
+    fn poll(&mut self) -> Option<super::CompletionEntry> {
+        let entry = self.cq.entries[self.cq_head as usize].read();
+        if (entry.status & 1 != 0) != self.phase {
+            return None;
+        }
+
+        self.cq_head += 1;
+        if self.cq_head as usize == QUEUE_SIZE {
+            self.cq_head = 0;
+            self.phase = !self.phase;
+        }
+
+        self.cq_doorbell.write(self.cq_head as u32);
+
+        Some(entry)
+    }
+}
+
+struct Namespace {
+    nsid: u32,
+    num_blocks: usize,
+    block_size: usize,
+}
+
+pub struct NVMeState {
+    registers: &'static mut Registers,
+    admin: QueuePair,
+    io: QueuePair,
+    next_admin_id: u16,
+    namespaces: Vec<Namespace>,
+
+    // Whether I/O completions raise an interrupt. If not, they are polled for.
+    pub msix: bool,
+
+    // I/O commands are identified by their slot in these. A slot is taken from when its bit is
+    // set in `io_pending` until whoever submitted it sees its bit in `io_done`.
+    io_pending: u64,
+    io_done: u64,
+    io_status: [u16; IO_SLOTS],
+}
+
+impl NVMeState {
+    /// Find the first NVMe controller at or after the PCI address `bus` / `slot` / `func`, bring
+    /// it up, and register each of its namespaces as a block device ("nvme0n1", "nvme0n2", ...).
+    /// ### Safety
+    /// Should be called only once, after the local APIC and the kernel page table are set up.
+    /// There should be a handler for `vector`, which completions are raised on if the controller
+    /// supports MSI-X.
+    pub unsafe fn new(
+        frame_allocator: &mut BootInfoFrameAllocator,
+        bus: u32,
+        slot: u32,
+        func: u32,
+        vector: u8,
+    ) -> Result<(), ()> {
+        let mut pci = PCIState::new();
+        let mut addr_opt = Some((bus, slot, func));
+
+        while let Some((bus, slot, func)) = addr_opt {
+            let subclass = pci.config_read_16(bus, slot, func, pci::Register::Subclass);
+            if subclass != 0x0108 {
+                addr_opt = pci.next_addr(bus, slot, func);
+                continue;
+            }
+
+            let nvme = Self::init(frame_allocator, bus, slot, func, vector)?;
+            NVME0.set(Mutex::new(nvme)).map_err(|_| ())?;
+
+            let controller = NVME0.get().unwrap();
+            for namespace in controller.lock().namespaces.iter() {
+                let device = NVMeNamespace {
+                    controller,
+                    nsid: namespace.nsid,
+                    num_blocks: namespace.num_blocks,
+                    block_size: namespace.block_size,
+                };
+                block::register(format!("nvme0n{}", namespace.nsid), Arc::new(device));
+            }
+
+            return Ok(());
+        }
+
+        Err(())
+    }
+
+    unsafe fn init(
+        frame_allocator: &mut BootInfoFrameAllocator,
+        bus: u32,
+        slot: u32,
+        func: u32,
+        vector: u8,
+    ) -> Result<Self, ()> {
+        power::power_up(bus, slot, func)?;
+
+        let phys_addr = PCI_STATE.lock().bar_address(bus, slot, func, 0);
+        if phys_addr == 0 {
+            return Err(());
+        }
+
+        // Memory space + bus master, and no pin interrupts; we either use MSI-X or poll.
+        PCI_STATE
+            .lock()
+            .config_write(bus, slot, func, Register::Command, 0x406u16);
+
+        util::map_mmio(frame_allocator, phys_addr, PAGE_SIZE as u64, "nvme")?;
+        let regs_base = util::physical_to_kernel_address(phys_addr);
+        let registers = &mut *(regs_base as *mut Registers);
+
+        let capabilities = registers.capabilities.read();
+        let doorbell_stride =
+            4u64 << ((capabilities & CapabilityMasks::DoorbellStride as u64) >> 32);
+        let max_entries = (capabilities & CapabilityMasks::MaxQueueEntries as u64) + 1;
+        let min_page_size =
+            1u64 << (12 + ((capabilities & CapabilityMasks::MinPageSize as u64) >> 48));
+
+        if capabilities & CapabilityMasks::NVMCommandSet as u64 == 0
+            || max_entries < QUEUE_SIZE as u64
+            || min_page_size > PAGE_SIZE as u64
+        {
+            log_warn!("NVMe: unsupported controller (CAP {:#x})", capabilities);
+            return Err(());
+        }
+
+        // Doorbells for the admin queue and the one I/O queue
+        util::map_mmio(
+            frame_allocator,
+            phys_addr,
+            DOORBELL_BASE + 4 * doorbell_stride,
+            "nvme",
+        )?;
+
+        let mut nvme = Self {
+            registers,
+            admin: QueuePair::new(ADMIN_QUEUE_ID, regs_base, doorbell_stride),
+            io: QueuePair::new(IO_QUEUE_ID, regs_base, doorbell_stride),
+            next_admin_id: 0,
+            namespaces: Vec::new(),
+            msix: false,
+            io_pending: 0,
+            io_done: 0,
+            io_status: [0; IO_SLOTS],
+        };
+
+        // The admin queues can only be set up while the controller is disabled
+        nvme.registers
+            .controller_config
+            .write(nvme.registers.controller_config.read() & !(ConfigMasks::Enable as u32));
+        nvme.wait_ready(false)?;
+
+        let queue_size = (QUEUE_SIZE - 1) as u32;
+        nvme.registers
+            .admin_queue_attributes
+            .write((queue_size << 16) | queue_size);
+        nvme.registers.admin_sq_addr.write(nvme.admin.sq_address());
+        nvme.registers.admin_cq_addr.write(nvme.admin.cq_address());
+
+        // NVM command set, 4 KiB pages, round robin arbitration
+        nvme.registers
+            .controller_config
+            .write(ConfigMasks::QueueEntrySizes as u32 | ConfigMasks::Enable as u32);
+        nvme.wait_ready(true)?;
+
+        let controller = nvme.identify(IdentifyCNS::Controller, 0)?;
+        log_info!(
+            "NVMe: {} (serial {})",
+            identify_string(&controller.bytes[24..64]),
+            identify_string(&controller.bytes[4..24])
+        );
+
+        // Ask for one I/O queue of each kind (0-based)
+        nvme.admin_command(SubmissionEntry {
+            opcode: AdminCommand::SetFeatures as u8,
+            cdw10: FEATURE_NUMBER_OF_QUEUES,
+            cdw11: 0,
+            ..Default::default()
+        })?;
+
+        // The admin queue is always polled, and it's done with by the time the I/O queue starts
+        // raising interrupts.
+        nvme.msix = msix::enable(frame_allocator, bus, slot, func, 0, vector).is_ok();
+        MSIX_VECTOR.store(vector, Ordering::Relaxed);
+        if !nvme.msix {
+            log_info!("NVMe: no MSI-X, polling for completions");
+        }
+
+        nvme.admin_command(SubmissionEntry {
+            opcode: AdminCommand::CreateIOCompletionQueue as u8,
+            prp1: nvme.io.cq_address(),
+            cdw10: (queue_size << 16) | IO_QUEUE_ID as u32,
+            // Interrupt vector 0, interrupts enabled (bit 1), physically contiguous (bit 0)
+            cdw11: (u32::from(nvme.msix) << 1) | 1,
+            ..Default::default()
+        })?;
+
+        nvme.admin_command(SubmissionEntry {
+            opcode: AdminCommand::CreateIOSubmissionQueue as u8,
+            prp1: nvme.io.sq_address(),
+            cdw10: (queue_size << 16) | IO_QUEUE_ID as u32,
+            // Completions go to the queue above, physically contiguous
+            cdw11: ((IO_QUEUE_ID as u32) << 16) | 1,
+            ..Default::default()
+        })?;
+
+        let active = nvme.identify(IdentifyCNS::ActiveNamespaces, 0)?;
+        for nsid_bytes in active.bytes.chunks_exact(4) {
+            let nsid = u32::from_le_bytes(nsid_bytes.try_into().unwrap());
+            if nsid == 0 {
+                break;
+            }
+
+            let data = nvme.identify(IdentifyCNS::Namespace, nsid)?;
+            let num_blocks = u64::from_le_bytes(data.bytes[0..8].try_into().unwrap()) as usize;
+
+            // The low 4 bits of FLBAS pick which of the LBA formats at byte 128 is in use
+            let format_index = (data.bytes[26] & 0xF) as usize;
+            let format_offset = 128 + 4 * format_index;
+            let block_shift = data.bytes[format_offset + 2];
+            let block_size = 1usize << block_shift;
+
+            if block_size > PAGE_SIZE {
+                log_warn!(
+                    "NVMe: skipping namespace {}, block size {} is too big",
+                    nsid,
+                    block_size
+                );
+                continue;
+            }
+
+            log_info!(
+                "NVMe: namespace {} has {} blocks of {} bytes",
+                nsid,
+                num_blocks,
+                block_size
+            );
+
+            nvme.namespaces.push(Namespace {
+                nsid,
+                num_blocks,
+                block_size,
+            });
+        }
+
+        Ok(nvme)
+    }
+
+    fn wait_ready(&self, ready: bool) -> Result<(), ()> {
+        loop {
+            let status = self.registers.controller_status.read();
+            if status & StatusMasks::FatalStatus as u32 != 0 {
+                return Err(());
+            }
+            if (status & StatusMasks::Ready as u32 != 0) == ready {
+                return Ok(());
+            }
+            Arch::pause();
+        }
+    }
+
+    // Run an admin command to completion. Returns the command specific result.
+    fn admin_command(&mut self, mut entry: SubmissionEntry) -> Result<u32, ()> {
+        entry.command_id = self.next_admin_id;
+        self.next_admin_id = self.next_admin_id.wrapping_add(1);
+        self.admin.submit(entry);
+
+        loop {
+            if let Some(completion) = self.admin.poll() {
+                if completion.command_id != entry.command_id {
+                    continue;
+                }
+
+                return match completion.status >> 1 {
+                    0 => Ok(completion.result),
+                    _ => Err(()),
+                };
+            }
+
+            if self.registers.controller_status.read() & StatusMasks::FatalStatus as u32 != 0 {
+                return Err(());
+            }
+            Arch::pause();
+        }
+    }
+
+    fn identify(&mut self, cns: IdentifyCNS, nsid: u32) -> Result<Box<IdentifyData>, ()> {
+        let data: Box<IdentifyData> = unsafe { allocator::try_box_zeroed()? };
+
+        self.admin_command(SubmissionEntry {
+            opcode: AdminCommand::Identify as u8,
+            nsid,
+            prp1: util::kernel_to_physical_address(addr_of!(*data) as u64),
+            cdw10: cns as u32,
+            ..Default::default()
+        })?;
+
+        Ok(data)
+    }
+
+    // Put a command on the I/O queue. Returns its slot, or None if the queue is full.
+    fn submit_io(&mut self, mut entry: SubmissionEntry) -> Option<u16> {
+        let free = !self.io_pending & IO_SLOTS_MASK;
+        if free == 0 {
+            return None;
+        }
+
+        let slot = free.trailing_zeros() as u16;
+        self.io_pending |= 1 << slot;
+
+        entry.command_id = slot;
+        crate::trace!(
+            Nvme,
+            "submit slot {} opcode {:#x} nsid {} lba {} blocks {}",
+            slot,
+            entry.opcode,
+            entry.nsid,
+            (entry.cdw11 as u64) << 32 | entry.cdw10 as u64,
+            (entry.cdw12 & 0xFFFF) + 1
+        );
+        self.io.submit(entry);
+
+        Some(slot)
+    }
+
+    // Collect every finished command on the I/O queue.
+    fn reap_io(&mut self) {
+        while let Some(completion) = self.io.poll() {
+            let slot = completion.command_id as usize;
+            crate::trace!(
+                Nvme,
+                "complete slot {} status {:#x}",
+                slot,
+                completion.status >> 1
+            );
+            if slot < IO_SLOTS {
+                self.io_status[slot] = completion.status >> 1;
+                self.io_done |= 1 << slot;
+            }
+        }
+    }
+
+    fn io_finished(&self, slot: u16) -> bool {
+        self.io_done & (1 << slot) != 0
+    }
+
+    // If the command in `slot` has finished, free the slot and return the command's status.
+    fn take_io(&mut self, slot: u16) -> Option<u16> {
+        if !self.io_finished(slot) {
+            return None;
+        }
+
+        self.io_done &= !(1 << slot);
+        self.io_pending &= !(1 << slot);
+        Some(self.io_status[slot as usize])
+    }
+}
+
+/// Called from the MSI-X interrupt handler: collect finished I/O and wake up whoever was waiting
+/// on it.
+pub fn handle_interrupt() {
+    if let Some(controller) = NVME0.get() {
+        controller.lock().reap_io();
+    }
+
+    IO_WAIT.wake_all();
+}
+
+// Run one I/O command to completion, blocking the current task until it's done.
+fn run_io(controller: &Mutex<NVMeState>, entry: SubmissionEntry) -> Result<(), IOError> {
+    let slot = loop {
+        match interrupts::without_interrupts(|| controller.lock().submit_io(entry)) {
+            Some(slot) => break slot,
+            None => task::yield_now(),
+        }
+    };
+
+    let status = loop {
+        let (status, msix) = interrupts::without_interrupts(|| {
+            let mut nvme = controller.lock();
+            if !nvme.msix {
+                nvme.reap_io();
+            }
+            (nvme.take_io(slot), nvme.msix)
+        });
+
+        match status {
+            Some(status) => break status,
+            None if msix => IO_WAIT.wait_while(|| !controller.lock().io_finished(slot)),
+            None => Arch::pause(),
+        }
+    };
+
+    match status {
+        0 => Ok(()),
+        _ => Err(IOError::BadData),
+    }
+}
+
+/// Read or write `len` bytes at `addr`, starting at block `lba` of the namespace.
+/// ### Safety
+/// `len` bytes at `addr` must stay valid until this returns, and for a read, nothing else may
+/// access them in the meantime.
+unsafe fn transfer(
+    controller: &Mutex<NVMeState>,
+    command: IOCommand,
+    nsid: u32,
+    block_size: usize,
+    mut addr: u64,
+    len: usize,
+    mut lba: u64,
+) -> Result<(), IOError> {
+    let mut remaining = len;
+
+    while remaining > 0 {
+        let page_offset = addr as usize % PAGE_SIZE;
+        if page_offset % 4 != 0 {
+            // PRP entries have to be dword aligned
+            return Err(IOError::Invalid);
+        }
+
+        // Without a PRP list, one command can only cover the rest of this page plus the next one
+        let max_chunk = (2 * PAGE_SIZE - page_offset) / block_size * block_size;
+        let chunk = remaining.min(max_chunk);
+        let num_blocks = chunk / block_size;
+
+        let prp2 = if page_offset + chunk > PAGE_SIZE {
+            util::kernel_to_physical_address(addr - page_offset as u64 + PAGE_SIZE as u64)
+        } else {
+            0
+        };
+
+        run_io(
+            controller,
+            SubmissionEntry {
+                opcode: command as u8,
+                nsid,
+                prp1: util::kernel_to_physical_address(addr),
+                prp2,
+                cdw10: lba as u32,
+                cdw11: (lba >> 32) as u32,
+                cdw12: (num_blocks - 1) as u32, // 0-based
+                ..Default::default()
+            },
+        )?;
+
+        addr += chunk as u64;
+        remaining -= chunk;
+        lba += num_blocks as u64;
+    }
+
+    Ok(())
+}
+
+// Identify strings are ASCII, padded out with spaces.
+fn identify_string(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes).unwrap_or("?").trim()
+}
+
+/// One namespace of an NVMe controller, i.e. what shows up as a disk.
+pub struct NVMeNamespace {
+    controller: &'static Mutex<NVMeState>,
+    nsid: u32,
+    num_blocks: usize,
+    block_size: usize,
+}
+
+impl NVMeNamespace {
+    fn check_alignment(&self, len: usize, offset: usize) -> Result<(), IOError> {
+        if len == 0 || len % self.block_size != 0 || offset % self.block_size != 0 {
+            return Err(IOError::Invalid);
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for NVMeNamespace {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.num_blocks
+    }
+
+    fn read<'a>(
+        &self,
+        buf: &'a mut [MaybeUninit<u8>],
+        offset: usize,
+    ) -> Result<&'a mut [u8], IOError> {
+        self.check_alignment(buf.len(), offset)?;
+
+        unsafe {
+            transfer(
+                self.controller,
+                IOCommand::Read,
+                self.nsid,
+                self.block_size,
+                buf.as_mut_ptr() as u64,
+                buf.len(),
+                (offset / self.block_size) as u64,
+            )?;
+
+            Ok(MaybeUninit::slice_assume_init_mut(buf))
+        }
+    }
+
+    fn write(&self, buf: &[u8], offset: usize) -> Result<(), IOError> {
+        self.check_alignment(buf.len(), offset)?;
+
+        unsafe {
+            transfer(
+                self.controller,
+                IOCommand::Write,
+                self.nsid,
+                self.block_size,
+                buf.as_ptr() as u64,
+                buf.len(),
+                (offset / self.block_size) as u64,
+            )
+        }
+    }
+}
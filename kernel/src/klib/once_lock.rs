@@ -167,6 +167,31 @@ impl<T> OnceLock<T> {
         }
     }
 
+    /// Gets the reference to the underlying value, blocking until some other code has
+    /// initialized the cell.
+    ///
+    /// This spins, so it must not be called where the initializing code can't get to run, e.g.
+    /// from an interrupt handler on the CPU that is doing the initializing. It can't sleep on a
+    /// `WaitQueue`: cells are used before the heap and the scheduler are up, and waiting on one
+    /// allocates.
+    #[inline]
+    pub fn wait(&self) -> &T {
+        self.once.wait();
+        // Safe b/c the once has completed
+        unsafe { self.get_unchecked() }
+    }
+
+    /// Gets the reference to the underlying value, panicking with `msg` if the cell is empty or
+    /// being initialized. For values whose absence is a bug rather than something to handle.
+    #[inline]
+    #[track_caller]
+    pub fn get_or_panic_with(&self, msg: &str) -> &T {
+        match self.get() {
+            Some(value) => value,
+            None => panic!("{}", msg),
+        }
+    }
+
     /// Sets the contents of this cell to `value`.
     ///
     /// May block if another thread is currently attempting to initialize the cell. The cell is
@@ -1,8 +1,22 @@
-use super::super::super::sleep;
-use super::super::util::Volatile;
-use super::super::x86_64::{port_read_u8, port_write_u8};
+use super::super::ata::{Command, IdentifyData, Status};
+use crate::arch::ports::{self, Port};
+use crate::log_warn;
 use crate::print;
 use crate::println;
+use crate::TIMER;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// Timer ticks to wait for a drive to raise its interrupt before giving up on it
+const IRQ_TIMEOUT: u64 = 100;
+
+// Set by the IRQ 14 and 15 handlers. These live outside of the controller because the handlers
+// can't take its lock while a command on the channel is waiting for them.
+static IRQ_INVOKED: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+
+/// Called from the IRQ 14 (primary) and IRQ 15 (secondary) handlers.
+pub fn handle_interrupt(channel: ChannelType) {
+    IRQ_INVOKED[channel as usize].store(true, Ordering::SeqCst);
+}
 
 #[repr(C)]
 pub struct IDEController {
@@ -11,7 +25,6 @@ pub struct IDEController {
     prdts: [PRDT; 4],
     buffer: [u8; 2048],
     atapi_packet: [u8; 12],
-    irq_invoked: Volatile<bool>,
     bus: u8,
     slot: u8,
     mode: Mode,
@@ -33,12 +46,14 @@ impl IDEController {
 
         let result = unsafe {
             match reg_type {
-                RegisterType::LowLevel => port_read_u8(channel.io_base + u16_reg),
-                RegisterType::HighLevel => port_read_u8(channel.io_base + u16_reg - 0x06),
+                RegisterType::LowLevel => Port::<u8>::new(channel.io_base + u16_reg).read(),
+                RegisterType::HighLevel => Port::<u8>::new(channel.io_base + u16_reg - 0x06).read(),
                 RegisterType::DeviceControlOrStatus => {
-                    port_read_u8(channel.control + u16_reg - 0x0A)
+                    Port::<u8>::new(channel.control + u16_reg - 0x0A).read()
+                }
+                RegisterType::BusMasterIDE => {
+                    Port::<u8>::new(channel.bus_master_ide + u16_reg - 0x0E).read()
                 }
-                RegisterType::BusMasterIDE => port_read_u8(channel.bus_master_ide + u16_reg - 0x0E),
             }
         };
 
@@ -64,13 +79,13 @@ impl IDEController {
 
         unsafe {
             match reg_type {
-                RegisterType::LowLevel => port_write_u8(channel.io_base + u16_reg, data),
-                RegisterType::HighLevel => port_write_u8(channel.io_base + u16_reg - 0x06, data),
+                RegisterType::LowLevel => Port::new(channel.io_base + u16_reg).write(data),
+                RegisterType::HighLevel => Port::new(channel.io_base + u16_reg - 0x06).write(data),
                 RegisterType::DeviceControlOrStatus => {
-                    port_write_u8(channel.control + u16_reg - 0x0A, data)
+                    Port::new(channel.control + u16_reg - 0x0A).write(data)
                 }
                 RegisterType::BusMasterIDE => {
-                    port_write_u8(channel.bus_master_ide + u16_reg - 0x0E, data)
+                    Port::new(channel.bus_master_ide + u16_reg - 0x0E).write(data)
                 }
             }
         };
@@ -80,7 +95,39 @@ impl IDEController {
         }
     }
 
-    pub fn read_buffer(&mut self, channel: ChannelType, reg: Register, count: u32) {}
+    /// Read `count` words from the data register into the controller's buffer (PIO).
+    pub fn read_buffer(&mut self, channel: ChannelType, reg: Register, count: u32) {
+        let port: Port<u16> =
+            Port::new(self.channel_registers[channel as usize].io_base + reg as u16);
+        let count = (count as usize).min(self.buffer.len() / 2);
+
+        for i in 0..count {
+            let word = unsafe { port.read() };
+            self.buffer[i * 2..i * 2 + 2].copy_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    /// Wait for the drive on `channel` to raise its interrupt, sleeping instead of polling the
+    /// status register. Returns the status, which reading also acknowledges the interrupt with,
+    /// or an error if the drive didn't answer in time.
+    pub fn wait_irq(&mut self, channel: ChannelType) -> Result<u8, ()> {
+        let deadline = TIMER.load(Ordering::SeqCst) + IRQ_TIMEOUT;
+
+        while !IRQ_INVOKED[channel as usize].swap(false, Ordering::SeqCst) {
+            if TIMER.load(Ordering::SeqCst) >= deadline {
+                return Err(());
+            }
+            crate::sleep(1);
+        }
+
+        Ok(self.read(channel, Register::CommandOrStatus))
+    }
+
+    // Start `command` on `channel`, forgetting about any interrupt from a previous one.
+    fn issue_command(&mut self, channel: ChannelType, command: Command) {
+        IRQ_INVOKED[channel as usize].store(false, Ordering::SeqCst);
+        self.write(channel, Register::CommandOrStatus, command as u8);
+    }
 
     pub fn read_drive_dma(channel: ChannelType) {}
 
@@ -106,10 +153,18 @@ impl IDEController {
         controller.channel_registers[1].control = 0x376;
         controller.channel_registers[1].bus_master_ide = bar_4 + 8;
 
-        let disable_interrupt = ControlBits::InterruptDisable as u8;
+        // Both channels' compatibility mode ports, and the bus master registers from BAR 4
+        for (first, count) in [(0x1F0, 8), (0x3F6, 1), (0x170, 8), (0x376, 1), (bar_4, 16)] {
+            if ports::claim(first, count, "ide").is_err() {
+                log_warn!("IDE ports from {:#x} are already claimed", first);
+            }
+        }
 
-        controller.write(ChannelType::Primary, Register::Control, disable_interrupt);
-        controller.write(ChannelType::Secondary, Register::Control, disable_interrupt);
+        // Drives tell us they are done through IRQ 14 and 15
+        controller.channel_registers[0].no_interrupts = false;
+        controller.channel_registers[1].no_interrupts = false;
+        controller.write(ChannelType::Primary, Register::Control, 0);
+        controller.write(ChannelType::Secondary, Register::Control, 0);
         controller.detect_drives();
         controller
     }
@@ -135,11 +190,9 @@ impl IDEController {
 
                 self.write(channel, Register::HDDevSel, select_master);
 
-                sleep(1);
-
-                self.write(channel, Register::CommandOrStatus, Command::Identify as u8);
+                crate::sleep(1);
 
-                sleep(1);
+                self.issue_command(channel, Command::Identify);
                 let drive = if i == 0 {
                     "Master drive"
                 } else {
@@ -152,23 +205,18 @@ impl IDEController {
                     continue;
                 }
 
-                let mut had_error = false;
-
-                loop {
-                    let status = self.read(channel, Register::CommandOrStatus);
-                    if status & Status::Error as u8 != 0 {
+                // ATAPI drives abort IDENTIFY, which still raises an interrupt
+                let had_error = match self.wait_irq(channel) {
+                    Ok(status) if status & Status::Error as u8 == 0 => false,
+                    Ok(_) => {
                         println!("Drive {} {} had an error", i, j);
-                        had_error = true;
-                        break;
+                        true
                     }
-
-                    let busy = status & Status::Busy as u8;
-                    let ready = status & Status::DataRequestReady as u8;
-
-                    if busy == 0 && ready != 0 {
-                        break;
+                    Err(()) => {
+                        println!("{} {} channel timed out", drive, channel_type);
+                        continue;
                     }
-                }
+                };
 
                 let mut if_type = InterfaceType::ATA;
 
@@ -189,61 +237,36 @@ impl IDEController {
                         );
                     }
 
-                    self.write(
-                        channel,
-                        Register::CommandOrStatus,
-                        Command::IdentifyPacket as u8,
-                    );
-                    sleep(1);
+                    self.issue_command(channel, Command::IdentifyPacket);
+                    if self.wait_irq(channel).is_err() {
+                        println!("{} {} channel timed out", drive, channel_type);
+                        continue;
+                    }
                 }
 
                 self.read_buffer(channel, Register::Data, 256);
 
-                let buf_ptr = self.buffer.as_ptr();
+                let identity = IdentifyData::from_bytes(self.buffer[..512].try_into().unwrap());
 
                 self.devices[count].reserved = true;
                 self.devices[count].interface_type = if_type;
                 self.devices[count].channel_type = channel;
                 self.devices[count].control_type = control_type;
-                self.devices[count].drive_signature =
-                    unsafe { *(buf_ptr.add(IdentityField::DeviceType as usize) as *const u16) };
-                self.devices[count].capabilities =
-                    unsafe { *(buf_ptr.add(IdentityField::Capabilities as usize) as *const u16) };
-                self.devices[count].command_sets =
-                    unsafe { *(buf_ptr.add(IdentityField::CommandSets as usize) as *const u32) };
-
-                print!("Addressing scheme: ");
-
-                if self.devices[count].command_sets & (1 << 26) != 0 {
-                    println!("48-bit");
-                    self.devices[count].size =
-                        unsafe { *(buf_ptr.add(IdentityField::MaxLBAExt as usize) as *const u32) };
+                self.devices[count].drive_signature = identity.signature();
+                self.devices[count].capabilities = identity.capabilities();
+                self.devices[count].command_sets = identity.command_sets();
+                self.devices[count].size = identity.num_sectors();
+
+                if identity.supports_lba48() {
+                    println!("Addressing scheme: 48-bit");
                 } else {
-                    println!("32-bit");
-                    self.devices[count].size =
-                        unsafe { *(buf_ptr.add(IdentityField::MaxLBA as usize) as *const u32) };
+                    println!("Addressing scheme: 32-bit");
                 }
 
-                print!("Name: ");
-
-                for k in (0..40).step_by(2) {
-                    self.devices[count].model[k] =
-                        self.buffer[IdentityField::Model as usize + k + 1];
-                    self.devices[count].model[k + 1] =
-                        self.buffer[IdentityField::Model as usize + k];
-
-                    if self.devices[count].model[k] == 0 {
-                        break;
-                    }
-                    print!("{}", self.devices[count].model[k] as char);
-
-                    if self.devices[count].model[k + 1] == 0 {
-                        break;
-                    }
-                    print!("{}", self.devices[count].model[k + 1] as char);
-                }
+                self.devices[count].model[..40].copy_from_slice(&identity.model());
+                let model = core::str::from_utf8(&self.devices[count].model[..40]).unwrap_or("?");
+                println!("Name: {}", model.trim());
 
-                println!();
                 self.devices[count].model[40] = 0;
                 println!(
                     "Size: {} total sectors, or {} MB",
@@ -268,7 +291,7 @@ pub enum RegisterType {
 
 #[repr(C)]
 struct Device {
-    size: u32,         // size in sectors
+    size: u64,         // size in sectors
     command_sets: u32, // supported command sets
     drive_signature: u16,
     capabilities: u16,
@@ -279,48 +302,6 @@ struct Device {
     reserved: bool,
 }
 
-enum Status {
-    Busy = 0x80,
-    ReadyDrive = 0x40,
-    DriveWriteFault = 0x20,
-    DriveSeekComplete = 0x10,
-    DataRequestReady = 0x08,
-    CorrectedData = 0x04,
-    Index = 0x02,
-    Error = 0x01,
-}
-
-enum Error {
-    BadBlock = 0x80,
-    Uncorrectable = 0x40,
-    MediaChanged = 0x20,
-    IDMarkNotFound = 0x10,
-    MediaChangeRequest = 0x08,
-    CommandAborted = 0x04,
-    Track0NotFound = 0x02,
-    NoAddressMark = 0x01,
-}
-
-#[derive(Clone, Copy)]
-pub enum Command {
-    ReadPIO = 0x20,
-    ReadPIOExt = 0x24,
-    ReadDMA = 0xC8,
-    ReadDMAExt = 0x25,
-    WritePIO = 0x30,
-    WritePIOExt = 0x34,
-    WriteDMA = 0xCA,
-    WriteDMAExt = 0x35,
-    CacheFlush = 0xE7,
-    CacheFlushExt = 0xEA,
-    Packet = 0xA0,
-    IdentifyPacket = 0xA1,
-    Identify = 0xEC,
-    ReadFPDMAQueued = 0x60,
-    WriteFPDMAQueued = 0x61,
-    SetFeatures = 0xEF,
-}
-
 #[derive(Clone, Copy)]
 pub enum Register {
     Data = 0x00,
@@ -382,20 +363,6 @@ pub enum ChannelType {
     Secondary = 0x1,
 }
 
-enum IdentityField {
-    DeviceType = 0,
-    Cylinders = 2,
-    Heads = 6,
-    Sectors = 12,
-    Serial = 20,
-    Model = 54,
-    Capabilities = 98,
-    FieldValid = 106,
-    MaxLBA = 120,
-    CommandSets = 164,
-    MaxLBAExt = 200,
-}
-
 enum ControlBits {
     HighOrderByte = 0b10000000,    // 1 = Use 48-LBA addressing
     SoftwareReset = 0b00000100,    // 1 = Reset the device
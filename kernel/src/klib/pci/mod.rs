@@ -1,8 +1,11 @@
+#[cfg(feature = "driver-ide")]
 pub mod ide_controller;
+pub mod msix;
 pub mod pcistate;
+pub mod power;
 use bitfield::bitfield;
 
-const CONFIG_ADDRESS: u32 = 0xCF8;
+pub const CONFIG_ADDRESS: u32 = 0xCF8;
 const CONFIG_DATA: u32 = 0xCFC;
 const NO_VENDOR: u16 = 0xFFFF;
 const NO_DEVICE: u16 = 0xFFFF;
@@ -39,6 +42,14 @@ pub enum Register {
     MaxLatency = 0x3F,
 }
 
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub enum CapabilityId {
+    PowerManagement = 0x01,
+    PciExpress = 0x10,
+    MsiX = 0x11,
+}
+
 #[repr(u8)]
 enum HeaderType {
     GeneralDevice = 0x0,
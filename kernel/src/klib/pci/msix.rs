@@ -0,0 +1,69 @@
+use super::pcistate::PCI_STATE;
+use super::CapabilityId;
+use crate::arch::x86_64::interrupts::apic;
+use crate::klib::util;
+use crate::BootInfoFrameAllocator;
+
+// Message control is the upper half of the first dword of the capability
+const MSIX_ENABLE: u32 = 1 << 31;
+const MSIX_FUNCTION_MASK: u32 = 1 << 30;
+
+const TABLE_ENTRY_SIZE: u64 = 16;
+
+/// Route MSI-X table entry `entry` of a PCI function to interrupt `vector` on this CPU's local
+/// APIC, and turn on MSI-X for the function. Other entries stay masked, as they are after reset.
+/// Fails if the function has no MSI-X capability, or not that many entries.
+/// ### Safety
+/// The local APIC must be initialized, and there should be a handler for `vector` before the
+/// device is told to use the entry.
+pub unsafe fn enable(
+    frame_allocator: &mut BootInfoFrameAllocator,
+    bus: u32,
+    slot: u32,
+    func: u32,
+    entry: u16,
+    vector: u8,
+) -> Result<(), ()> {
+    let mut pci = PCI_STATE.lock();
+
+    let cap = pci
+        .find_capability(bus, slot, func, CapabilityId::MsiX)
+        .ok_or(())?;
+    let header = pci.config_read_32_at(bus, slot, func, cap);
+    let table_size = ((header >> 16) & 0x7FF) + 1;
+
+    if entry as u32 >= table_size {
+        return Err(());
+    }
+
+    // The table lives in one of the function's BARs: the low 3 bits pick which, the rest is the
+    // offset into it.
+    let table_info = pci.config_read_32_at(bus, slot, func, cap + 4);
+    let bar = (table_info & 0x7) as u8;
+    let table_phys = pci.bar_address(bus, slot, func, bar) + (table_info & !0x7) as u64;
+    util::map_mmio(
+        frame_allocator,
+        table_phys,
+        table_size as u64 * TABLE_ENTRY_SIZE,
+        "msi-x table",
+    )?;
+
+    let apic_id = apic::LOCAL_APIC.get().ok_or(())?.id();
+    let table_entry = (util::physical_to_kernel_address(table_phys)
+        + entry as u64 * TABLE_ENTRY_SIZE) as *mut u32;
+
+    table_entry.write_volatile((apic::MSI_ADDRESS_BASE | ((apic_id as u64) << 12)) as u32);
+    table_entry.add(1).write_volatile(0);
+    table_entry.add(2).write_volatile(vector as u32);
+    table_entry.add(3).write_volatile(0); // unmask
+
+    pci.config_write_32_at(
+        bus,
+        slot,
+        func,
+        cap,
+        (header | MSIX_ENABLE) & !MSIX_FUNCTION_MASK,
+    );
+
+    Ok(())
+}
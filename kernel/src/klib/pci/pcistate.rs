@@ -1,20 +1,90 @@
 use super::Register;
-use crate::klib::pci::{CommandRegister, CONFIG_ADDRESS, CONFIG_DATA};
+use crate::arch::ports::{Port, PortValue};
+use crate::klib::containers::static_vec::StaticVec;
+use crate::klib::pci::{
+    CapabilityId, CommandRegister, StatusRegister, CONFIG_ADDRESS, CONFIG_DATA, NO_VENDOR,
+};
 use spin::mutex::Mutex;
-use x86_64::instructions::port::Port;
-use x86_64::structures::port::PortWrite;
 
 const MAX_BUSES: u32 = 0x8;
 const MAX_SLOTS: u32 = 32;
 const MAX_FUNCS: u32 = 8;
+// Room for every function on a handful of buses, which is plenty for the machines we run on
+const MAX_DEVICES: usize = 64;
 
-pub struct PCIState {}
+/// A function found on the bus during enumeration.
+#[derive(Clone, Copy, Debug)]
+pub struct Device {
+    pub bus: u32,
+    pub slot: u32,
+    pub func: u32,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+}
+
+pub struct PCIState {
+    // Filled in by enumerate, before the heap has to be up
+    devices: StaticVec<Device, MAX_DEVICES>,
+}
 
-pub static PCI_STATE: Mutex<PCIState> = Mutex::new(PCIState {});
+pub static PCI_STATE: Mutex<PCIState> = Mutex::new(PCIState::new());
 
 impl PCIState {
-    pub fn new() -> Self {
-        Self {}
+    pub const fn new() -> Self {
+        Self {
+            devices: StaticVec::new(),
+        }
+    }
+
+    /// Every function found by the last call to `enumerate`.
+    pub fn devices(&self) -> &[Device] {
+        &self.devices
+    }
+
+    /// The first device with the given class and subclass, and programming interface if given.
+    pub fn find(&self, class: u8, subclass: u8, prog_if: Option<u8>) -> Option<Device> {
+        self.devices
+            .iter()
+            .find(|device| {
+                device.class == class
+                    && device.subclass == subclass
+                    && prog_if.map_or(true, |prog_if| device.prog_if == prog_if)
+            })
+            .copied()
+    }
+
+    /// Scan the buses and record every function present. Returns how many were found; past
+    /// `MAX_DEVICES` the rest are left out.
+    pub unsafe fn enumerate(&mut self) -> usize {
+        self.devices.clear();
+
+        let mut addr_opt = Some((0, 0, 0));
+        while let Some((bus, slot, func)) = addr_opt {
+            let vendor_id = self.config_read_16(bus, slot, func, Register::VendorId);
+            if vendor_id != NO_VENDOR {
+                let device = Device {
+                    bus,
+                    slot,
+                    func,
+                    vendor_id,
+                    device_id: self.config_read_16(bus, slot, func, Register::DeviceId),
+                    class: self.config_read_8(bus, slot, func, Register::ClassCode),
+                    subclass: self.config_read_8(bus, slot, func, Register::Subclass),
+                    prog_if: self.config_read_8(bus, slot, func, Register::ProgIF),
+                };
+
+                if self.devices.push(device).is_err() {
+                    break;
+                }
+            }
+
+            addr_opt = self.next_addr(bus, slot, func);
+        }
+
+        self.devices.len()
     }
 
     pub unsafe fn config_read_32(
@@ -26,10 +96,10 @@ impl PCIState {
     ) -> u32 {
         let address = pci_address(bus, slot, func_number, offset);
 
-        let mut address_port = Port::new(CONFIG_ADDRESS as u16);
+        let address_port: Port<u32> = Port::new(CONFIG_ADDRESS as u16);
         address_port.write(address);
 
-        let mut data_port: Port<u32> = Port::new(CONFIG_DATA as u16);
+        let data_port: Port<u32> = Port::new(CONFIG_DATA as u16);
 
         // Magic: read the first word (16 bits) of the data register
         return data_port.read();
@@ -44,10 +114,10 @@ impl PCIState {
     ) -> u16 {
         let address = pci_address(bus, slot, func_number, offset);
 
-        let mut address_port = Port::new(CONFIG_ADDRESS as u16);
+        let address_port: Port<u32> = Port::new(CONFIG_ADDRESS as u16);
         address_port.write(address);
 
-        let mut data_port: Port<u32> = Port::new(CONFIG_DATA as u16);
+        let data_port: Port<u32> = Port::new(CONFIG_DATA as u16);
 
         // Magic: read the first word (16 bits) of the data register
         return ((data_port.read() >> ((offset as u8 & 2) * 8)) & 0xFFFF) as u16;
@@ -78,17 +148,102 @@ impl PCIState {
         offset: Register,
         data: T,
     ) where
-        T: PortWrite,
+        T: PortValue,
     {
         let address = pci_address(bus as u32, slot as u32, func_number as u32, offset);
 
-        let mut address_port = Port::new(CONFIG_ADDRESS as u16);
+        let address_port: Port<u32> = Port::new(CONFIG_ADDRESS as u16);
         address_port.write(address);
-        let mut data_port: Port<T> = Port::new(CONFIG_DATA as u16);
+        let data_port: Port<T> = Port::new(CONFIG_DATA as u16);
 
         unsafe { data_port.write(data) }
     }
 
+    /// Read the dword at byte `offset` of the configuration space. For registers that aren't in
+    /// `Register`, e.g. ones inside of a capability.
+    pub unsafe fn config_read_32_at(
+        &self,
+        bus: u32,
+        slot: u32,
+        func_number: u32,
+        offset: u8,
+    ) -> u32 {
+        let address = pci_address_at(bus, slot, func_number, offset);
+
+        let address_port: Port<u32> = Port::new(CONFIG_ADDRESS as u16);
+        address_port.write(address);
+
+        let data_port: Port<u32> = Port::new(CONFIG_DATA as u16);
+        data_port.read()
+    }
+
+    pub unsafe fn config_write_32_at(
+        &mut self,
+        bus: u32,
+        slot: u32,
+        func_number: u32,
+        offset: u8,
+        data: u32,
+    ) {
+        let address = pci_address_at(bus, slot, func_number, offset);
+
+        let address_port: Port<u32> = Port::new(CONFIG_ADDRESS as u16);
+        address_port.write(address);
+
+        let data_port: Port<u32> = Port::new(CONFIG_DATA as u16);
+        data_port.write(data)
+    }
+
+    /// Walk the capability list for a capability with the given ID. Returns its offset in the
+    /// configuration space.
+    pub unsafe fn find_capability(
+        &self,
+        bus: u32,
+        slot: u32,
+        func_number: u32,
+        id: CapabilityId,
+    ) -> Option<u8> {
+        let status = StatusRegister(self.config_read_16(bus, slot, func_number, Register::Status));
+        if !status.capabilities_list() {
+            return None;
+        }
+
+        let mut offset =
+            self.config_read_8(bus, slot, func_number, Register::CapabilitiesPointer) & 0xFC;
+
+        // Capabilities live in the 192 bytes after the header, so a list any longer than this
+        // must be looping.
+        for _ in 0..48 {
+            if offset == 0 {
+                return None;
+            }
+
+            let header = self.config_read_32_at(bus, slot, func_number, offset);
+            if header as u8 == id as u8 {
+                return Some(offset);
+            }
+
+            offset = (header >> 8) as u8 & 0xFC;
+        }
+
+        None
+    }
+
+    /// The physical address that base address register `bar` (0-5) points to, for a memory BAR.
+    pub unsafe fn bar_address(&self, bus: u32, slot: u32, func_number: u32, bar: u8) -> u64 {
+        let bar_offset = Register::GDBaseAddress0 as u8 + bar * 4;
+        let low = self.config_read_32_at(bus, slot, func_number, bar_offset);
+
+        // Bits 2:1 say whether this is a 64-bit BAR, in which case the next one is the upper half
+        let high = if (low >> 1) & 0b11 == 0b10 {
+            self.config_read_32_at(bus, slot, func_number, bar_offset + 4)
+        } else {
+            0
+        };
+
+        ((high as u64) << 32) | (low & 0xFFFFFFF0) as u64
+    }
+
     pub unsafe fn enable_interrupts(&mut self, bus: u32, slot: u32, func_number: u32) {
         let bytes = self.config_read_16(bus, slot, func_number, Register::Command);
 
@@ -137,7 +292,11 @@ impl PCIState {
 }
 
 fn pci_address(bus: u32, slot: u32, func_number: u32, offset: Register) -> u32 {
-    let offset_u32 = offset as u8 as u32;
+    pci_address_at(bus, slot, func_number, offset as u8)
+}
+
+fn pci_address_at(bus: u32, slot: u32, func_number: u32, offset: u8) -> u32 {
+    let offset_u32 = offset as u32;
 
     // Layout:
     // Bit 31: Enable bit
@@ -0,0 +1,323 @@
+// PCI power management and function resets. A driver calls `power_up` on its function before
+// touching it, as firmware may have left it in a low power state where its registers don't
+// respond; that also has the function put in D3hot when the kernel shuts down. A driver whose
+// device has wedged can `reset` it, after which it has to set the device up again from scratch.
+//
+// Both going from D3hot to D0 and a reset may clear the configuration header, BARs included, so
+// they save it first and put it back after.
+
+use super::pcistate::PCI_STATE;
+use super::CapabilityId;
+use crate::klib::containers::static_vec::StaticVec;
+use crate::klib::shutdown;
+use crate::{log_info, log_warn};
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Power management capabilities, the upper half of the capability's first dword
+const PMC_D1_SUPPORT: u32 = 1 << 25;
+const PMC_D2_SUPPORT: u32 = 1 << 26;
+
+// Power management control/status register
+const PMCSR: u8 = 4;
+const PMCSR_STATE_MASK: u32 = 0b11;
+const PMCSR_NO_SOFT_RESET: u32 = 1 << 3;
+// Cleared by writing 1, so it's left out of writes that don't mean to
+const PMCSR_PME_STATUS: u32 = 1 << 15;
+
+// PCI Express capability: device capabilities, then device control with device status above it
+const DEVICE_CAPABILITIES: u8 = 4;
+const DEVICE_CONTROL: u8 = 8;
+const FLR_CAPABLE: u32 = 1 << 28;
+const INITIATE_FLR: u32 = 1 << 15;
+const TRANSACTIONS_PENDING: u32 = 1 << (16 + 5);
+
+// Bridge header: bus numbers, and bridge control above the interrupt line and pin
+const BUS_NUMBERS: u8 = 0x18;
+const BRIDGE_CONTROL: u8 = 0x3C;
+const SECONDARY_BUS_RESET: u32 = 1 << (16 + 6);
+const BRIDGE_CLASS: (u8, u8) = (0x06, 0x04);
+
+// How long each change takes before the function may be touched again, in milliseconds
+const D3HOT_DELAY: u64 = 10;
+const D2_DELAY: u64 = 1;
+const RESET_DELAY: u64 = 100;
+const BUS_RESET_HOLD: u64 = 2;
+// Polls for outstanding requests to finish before an FLR, and the time between them
+const PENDING_POLLS: usize = 10;
+const PENDING_POLL_INTERVAL: u64 = 10;
+
+// The header's dwords from Command to the interrupt line
+const HEADER_START: u8 = 0x04;
+const HEADER_DWORDS: usize = 15;
+
+const MAX_POWERED: usize = 16;
+
+// Functions `power_up` was called on, to be put in D3hot at shutdown
+static POWERED: Mutex<StaticVec<(u32, u32, u32), MAX_POWERED>> = Mutex::new(StaticVec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerState {
+    D0,
+    D1,
+    D2,
+    D3Hot,
+}
+
+impl PowerState {
+    fn from_bits(bits: u32) -> Self {
+        match bits & PMCSR_STATE_MASK {
+            0 => PowerState::D0,
+            1 => PowerState::D1,
+            2 => PowerState::D2,
+            _ => PowerState::D3Hot,
+        }
+    }
+}
+
+impl fmt::Display for PowerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PowerState::D0 => "D0",
+            PowerState::D1 => "D1",
+            PowerState::D2 => "D2",
+            PowerState::D3Hot => "D3hot",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Have the functions drivers powered up put in D3hot at shutdown, after the drivers have stopped
+/// them. Has to be called before any driver registers its own teardown.
+pub fn init() {
+    if shutdown::register("pci power", power_down).is_err() {
+        log_warn!("Couldn't register PCI power management for shutdown");
+    }
+}
+
+/// The power state of a function, or `None` if it has no power management capability, in which
+/// case it is always in D0.
+pub fn state(bus: u32, slot: u32, func: u32) -> Option<PowerState> {
+    let pci = PCI_STATE.lock();
+    let control = unsafe {
+        let cap = pci.find_capability(bus, slot, func, CapabilityId::PowerManagement)?;
+        pci.config_read_32_at(bus, slot, func, cap + PMCSR)
+    };
+    Some(PowerState::from_bits(control))
+}
+
+/// Put a function in `state`, waiting as long as the spec asks before it can be used again.
+/// Fails if the function has no power management capability, doesn't support `state`, or didn't
+/// take it. Waits on the timer, so interrupts have to be on.
+/// ### Safety
+/// The function's driver has to be done with it before leaving D0, as its registers stop
+/// responding.
+pub unsafe fn set_state(bus: u32, slot: u32, func: u32, state: PowerState) -> Result<(), ()> {
+    let (cap, old) = {
+        let pci = PCI_STATE.lock();
+        let cap = pci
+            .find_capability(bus, slot, func, CapabilityId::PowerManagement)
+            .ok_or(())?;
+        let capabilities = pci.config_read_32_at(bus, slot, func, cap);
+        let supported = match state {
+            PowerState::D1 => capabilities & PMC_D1_SUPPORT != 0,
+            PowerState::D2 => capabilities & PMC_D2_SUPPORT != 0,
+            PowerState::D0 | PowerState::D3Hot => true,
+        };
+        if !supported {
+            return Err(());
+        }
+        (cap, pci.config_read_32_at(bus, slot, func, cap + PMCSR))
+    };
+
+    if PowerState::from_bits(old) == state {
+        return Ok(());
+    }
+
+    // Leaving D3hot resets the function unless it says otherwise
+    let waking = PowerState::from_bits(old) == PowerState::D3Hot;
+    let header = (waking && old & PMCSR_NO_SOFT_RESET == 0).then(|| save_header(bus, slot, func));
+
+    let control = (old & !(PMCSR_STATE_MASK | PMCSR_PME_STATUS)) | state as u32;
+    PCI_STATE
+        .lock()
+        .config_write_32_at(bus, slot, func, cap + PMCSR, control);
+
+    if waking || state == PowerState::D3Hot {
+        crate::sleep(D3HOT_DELAY);
+    } else if state == PowerState::D2 || PowerState::from_bits(old) == PowerState::D2 {
+        crate::sleep(D2_DELAY);
+    }
+
+    if let Some(header) = header {
+        restore_header(bus, slot, func, &header);
+    }
+
+    match self::state(bus, slot, func) {
+        Some(new) if new == state => Ok(()),
+        _ => Err(()),
+    }
+}
+
+/// Put a function in D0 if it isn't, ready for its driver to use, and have it put in D3hot at
+/// shutdown. A function without the power management capability is always in D0.
+/// ### Safety
+/// Should be called once, by the function's driver, before it uses the function.
+pub unsafe fn power_up(bus: u32, slot: u32, func: u32) -> Result<(), ()> {
+    match state(bus, slot, func) {
+        None => return Ok(()),
+        Some(PowerState::D0) => {}
+        Some(old) => {
+            log_info!(
+                "PCI {:02x}:{:02x}.{}: powering up from {}",
+                bus,
+                slot,
+                func,
+                old
+            );
+            set_state(bus, slot, func, PowerState::D0)?;
+        }
+    }
+
+    let pushed = interrupts::without_interrupts(|| POWERED.lock().push((bus, slot, func)));
+    if pushed.is_err() {
+        log_warn!(
+            "PCI {:02x}:{:02x}.{}: won't be powered down at shutdown",
+            bus,
+            slot,
+            func
+        );
+    }
+    Ok(())
+}
+
+/// Reset a single function, with a function level reset if it has one, or failing that by
+/// resetting the bus below its bridge if nothing else is on it. The header is put back after,
+/// but everything else, MSI-X included, has to be set up again. Fails if neither works. Waits on
+/// the timer, so interrupts have to be on.
+/// ### Safety
+/// The function's driver has to have stopped using it, and can't assume anything about its state
+/// after.
+pub unsafe fn reset(bus: u32, slot: u32, func: u32) -> Result<(), ()> {
+    let header = save_header(bus, slot, func);
+
+    let reset = function_level_reset(bus, slot, func).or_else(|()| bus_reset(bus, slot));
+    if reset.is_ok() {
+        restore_header(bus, slot, func, &header);
+        log_info!("PCI {:02x}:{:02x}.{}: reset", bus, slot, func);
+    }
+    reset
+}
+
+unsafe fn function_level_reset(bus: u32, slot: u32, func: u32) -> Result<(), ()> {
+    let cap = {
+        let pci = PCI_STATE.lock();
+        let cap = pci
+            .find_capability(bus, slot, func, CapabilityId::PciExpress)
+            .ok_or(())?;
+        if pci.config_read_32_at(bus, slot, func, cap + DEVICE_CAPABILITIES) & FLR_CAPABLE == 0 {
+            return Err(());
+        }
+        cap
+    };
+
+    // Requests still in flight are lost by the reset, and their completions could confuse the
+    // function afterwards
+    for _ in 0..PENDING_POLLS {
+        let status = PCI_STATE
+            .lock()
+            .config_read_32_at(bus, slot, func, cap + DEVICE_CONTROL);
+        if status & TRANSACTIONS_PENDING == 0 {
+            break;
+        }
+        crate::sleep(PENDING_POLL_INTERVAL);
+    }
+
+    {
+        let mut pci = PCI_STATE.lock();
+        // Only the control half is written, as status bits are cleared by writing 1
+        let control = pci.config_read_32_at(bus, slot, func, cap + DEVICE_CONTROL) & 0xFFFF;
+        pci.config_write_32_at(
+            bus,
+            slot,
+            func,
+            cap + DEVICE_CONTROL,
+            control | INITIATE_FLR,
+        );
+    }
+    crate::sleep(RESET_DELAY);
+    Ok(())
+}
+
+// Reset everything on `bus` through the secondary bus reset of the bridge above it. Refused if a
+// device in another slot is on the bus too, as it would be reset along with this one.
+unsafe fn bus_reset(bus: u32, slot: u32) -> Result<(), ()> {
+    let bridge = {
+        let pci = PCI_STATE.lock();
+        if pci
+            .devices()
+            .iter()
+            .any(|device| device.bus == bus && device.slot != slot)
+        {
+            return Err(());
+        }
+
+        pci.devices()
+            .iter()
+            .filter(|device| (device.class, device.subclass) == BRIDGE_CLASS)
+            .find(|device| {
+                let numbers =
+                    pci.config_read_32_at(device.bus, device.slot, device.func, BUS_NUMBERS);
+                (numbers >> 8) & 0xFF == bus
+            })
+            .copied()
+            .ok_or(())?
+    };
+
+    let (b, s, f) = (bridge.bus, bridge.slot, bridge.func);
+    let control = PCI_STATE.lock().config_read_32_at(b, s, f, BRIDGE_CONTROL);
+    let reset = control | SECONDARY_BUS_RESET;
+    PCI_STATE
+        .lock()
+        .config_write_32_at(b, s, f, BRIDGE_CONTROL, reset);
+    crate::sleep(BUS_RESET_HOLD);
+    PCI_STATE
+        .lock()
+        .config_write_32_at(b, s, f, BRIDGE_CONTROL, control);
+    crate::sleep(RESET_DELAY);
+    Ok(())
+}
+
+unsafe fn save_header(bus: u32, slot: u32, func: u32) -> [u32; HEADER_DWORDS] {
+    let pci = PCI_STATE.lock();
+    let mut header = [0; HEADER_DWORDS];
+    for (i, dword) in header.iter_mut().enumerate() {
+        *dword = pci.config_read_32_at(bus, slot, func, HEADER_START + i as u8 * 4);
+    }
+    header
+}
+
+// Last to first, so that the command register only turns decoding back on once the BARs are
+// back. Only dwords that changed are written, which leaves e.g. BIST alone.
+unsafe fn restore_header(bus: u32, slot: u32, func: u32, header: &[u32; HEADER_DWORDS]) {
+    let mut pci = PCI_STATE.lock();
+    for (i, &dword) in header.iter().enumerate().rev() {
+        let offset = HEADER_START + i as u8 * 4;
+        if pci.config_read_32_at(bus, slot, func, offset) != dword {
+            pci.config_write_32_at(bus, slot, func, offset, dword);
+        }
+    }
+}
+
+fn power_down() -> Result<(), ()> {
+    let powered = interrupts::without_interrupts(|| POWERED.lock().clone());
+    let mut result = Ok(());
+    for &(bus, slot, func) in powered.iter() {
+        if unsafe { set_state(bus, slot, func, PowerState::D3Hot) }.is_err() {
+            log_warn!("PCI {:02x}:{:02x}.{}: couldn't power down", bus, slot, func);
+            result = Err(());
+        }
+    }
+    result
+}
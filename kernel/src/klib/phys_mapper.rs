@@ -0,0 +1,145 @@
+use crate::arch::x86_64::paging;
+use crate::arch::x86_64::paging::MappingSize;
+use crate::BootInfoFrameAllocator;
+use crate::KERNEL_PAGETABLE;
+use core::cell::RefCell;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::slice::from_raw_parts;
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::{PageSize, PageTableFlags, Size4KiB, Translate};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Hands out views of physical memory, such as firmware tables, through the physical memory
+/// mapping set up by the bootloader. The bootloader only maps what the memory map describes, so
+/// pages that aren't mapped yet are mapped at the same offset on demand.
+///
+/// Mappings are never torn down, but the regions handed out borrow the mapper so that they can't
+/// be kept around past the code that asked for them.
+pub struct PhysMapper<'f> {
+    offset: u64,
+    frame_allocator: RefCell<&'f mut BootInfoFrameAllocator>,
+}
+
+impl<'f> PhysMapper<'f> {
+    /// Returns `None` if the kernel page table hasn't been set up yet.
+    pub fn new(frame_allocator: &'f mut BootInfoFrameAllocator) -> Option<Self> {
+        let offset = KERNEL_PAGETABLE.get()?.read().phys_offset().as_u64();
+
+        Some(Self {
+            offset,
+            frame_allocator: RefCell::new(frame_allocator),
+        })
+    }
+
+    /// Map `len` bytes of physical memory starting at `phys_addr`.
+    pub fn map(&self, phys_addr: u64, len: usize) -> Result<PhysRegion<'_>, ()> {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+        let start = self.map_with(phys_addr, len, flags)?;
+
+        Ok(PhysRegion {
+            virt: start,
+            phys: phys_addr,
+            len,
+            _mapper: PhantomData,
+        })
+    }
+
+    /// Map `len` bytes of physical memory starting at `phys_addr` so that they can be written,
+    /// for the few firmware structures the kernel fills in, and return the virtual address. The
+    /// mapping outlives the mapper. Fails if part of the range was already mapped read-only.
+    pub fn map_writable(&self, phys_addr: u64, len: usize) -> Result<u64, ()> {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let start = self.map_with(phys_addr, len, flags)?;
+
+        // Pages that were mapped already keep the flags they had
+        let page_table = KERNEL_PAGETABLE.get().ok_or(())?.read();
+        let mut addr = start & !(Size4KiB::SIZE - 1);
+        while addr < start + len as u64 {
+            match page_table.translate(VirtAddr::new(addr)) {
+                TranslateResult::Mapped { flags, .. }
+                    if flags.contains(PageTableFlags::WRITABLE) => {}
+                _ => return Err(()),
+            }
+            addr += Size4KiB::SIZE;
+        }
+
+        Ok(start)
+    }
+
+    // Map the range with `flags`, leaving pages that are already mapped alone, and return the
+    // virtual address of `phys_addr`
+    fn map_with(&self, phys_addr: u64, len: usize, flags: PageTableFlags) -> Result<u64, ()> {
+        if len == 0 {
+            return Err(());
+        }
+
+        // Both ends of the range have to be canonical addresses
+        let start = self.offset.checked_add(phys_addr).ok_or(())?;
+        let end = start.checked_add(len as u64).ok_or(())?;
+        let virt = VirtAddr::try_new(start).map_err(|_| ())?;
+        VirtAddr::try_new(end).map_err(|_| ())?;
+
+        let mut page_table = KERNEL_PAGETABLE.get().ok_or(())?.write();
+
+        // Like the bootloader's own mapping, use 2MiB pages where the range allows
+        paging::map_physical(
+            &mut page_table,
+            &mut self.frame_allocator.borrow_mut(),
+            virt,
+            PhysAddr::new(phys_addr),
+            len as u64,
+            flags,
+            MappingSize::Huge,
+        )
+        .map_err(|_| ())?;
+
+        Ok(start)
+    }
+}
+
+/// A mapped range of physical memory. All accesses are bounds checked against the range.
+pub struct PhysRegion<'m> {
+    virt: u64,
+    phys: u64,
+    len: usize,
+    _mapper: PhantomData<&'m ()>,
+}
+
+impl<'m> PhysRegion<'m> {
+    pub fn phys_addr(&self) -> u64 {
+        self.phys
+    }
+
+    pub fn size(&self) -> usize {
+        self.len
+    }
+
+    pub fn as_bytes(&self) -> &'m [u8] {
+        unsafe { from_raw_parts(self.virt as *const u8, self.len) }
+    }
+
+    /// A reference to a `T` at `offset` bytes into the region, if it fits and is aligned.
+    /// `T` should be a plain-old-data type that is valid for any bit pattern.
+    pub fn get<T>(&self, offset: usize) -> Option<&'m T> {
+        let end = offset.checked_add(size_of::<T>())?;
+        let addr = self.virt + offset as u64;
+
+        if end > self.len || addr % align_of::<T>() as u64 != 0 {
+            return None;
+        }
+
+        Some(unsafe { &*(addr as *const T) })
+    }
+
+    /// Copy out a `T` at `offset` bytes into the region, which doesn't need to be aligned.
+    pub fn read<T: Copy>(&self, offset: usize) -> Option<T> {
+        let end = offset.checked_add(size_of::<T>())?;
+
+        if end > self.len {
+            return None;
+        }
+
+        Some(unsafe { ((self.virt + offset as u64) as *const T).read_unaligned() })
+    }
+}
@@ -0,0 +1,34 @@
+// Polled mode, for machines where a device's interrupts never arrive. With `poll=<driver>,...` on
+// the command line (`poll=all` for every driver that can), the driver leaves its interrupt off and
+// a timer calls what the interrupt handler would have every `INTERVAL` instead, to look for
+// finished work itself. It's slower and keeps the timer task busy, but the kernel stays usable
+// while the interrupt routing is being debugged.
+//
+// The drivers that can be polled:
+//  - ahci: PxCI and PxSACT, for finished commands (waiting for a command also looks at them
+//    itself, so the disk works before the timer task runs)
+//  - ps2: the controller's status port, for bytes from the keyboard
+
+use crate::klib::{cmdline, timer};
+use crate::log_info;
+use core::time::Duration;
+
+/// How often polled drivers are looked at.
+pub const INTERVAL: Duration = Duration::from_millis(1);
+
+/// Whether `driver` should be polled rather than rely on its interrupt.
+pub fn is_polled(driver: &str) -> bool {
+    cmdline::value("poll").is_some_and(|drivers| {
+        drivers
+            .split(',')
+            .any(|name| name == driver || name == "all")
+    })
+}
+
+/// Call `poll` from the timer task every `INTERVAL` from now on, in place of `driver`'s interrupt
+/// handler. Has to be called once the heap is up; the polling starts once the timer task is
+/// running.
+pub fn start(driver: &'static str, poll: impl FnMut() + Send + 'static) {
+    timer::every(INTERVAL, poll).detach();
+    log_info!("Polling {} instead of waiting for its interrupts", driver);
+}
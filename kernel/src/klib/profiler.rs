@@ -0,0 +1,127 @@
+// Sampling profiler. While it runs, every timer tick records the address the interrupted code was
+// at, so the places the kernel spends its time show up as the addresses with the most samples.
+//
+// Samples go into a ring per processor, made of atomics so that recording one never has to take a
+// lock or allocate. Once a ring is full the oldest samples are overwritten.
+
+use crate::arch::x86_64::interrupts::apic::LOCAL_APIC;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+/// Samples kept per processor, a bit under a minute at the default tick rate.
+pub const NUM_SAMPLES: usize = 1024;
+
+// Processors with a higher local APIC ID share the last ring
+const MAX_CPUS: usize = 8;
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+struct CpuSamples {
+    // Total samples taken; the next one goes in at this modulo NUM_SAMPLES
+    count: AtomicUsize,
+    rips: [AtomicU64; NUM_SAMPLES],
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_SAMPLE: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_CPU: CpuSamples = CpuSamples {
+    count: AtomicUsize::new(0),
+    rips: [NO_SAMPLE; NUM_SAMPLES],
+};
+
+static SAMPLES: [CpuSamples; MAX_CPUS] = [EMPTY_CPU; MAX_CPUS];
+
+impl CpuSamples {
+    fn push(&self, rip: u64) {
+        let index = self.count.fetch_add(1, Ordering::Relaxed) % NUM_SAMPLES;
+        self.rips[index].store(rip, Ordering::Relaxed);
+    }
+
+    fn len(&self) -> usize {
+        self.count.load(Ordering::Relaxed).min(NUM_SAMPLES)
+    }
+
+    // Oldest first
+    fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+        let count = self.count.load(Ordering::Relaxed);
+        let start = count.saturating_sub(NUM_SAMPLES);
+        (start..count).map(move |i| self.rips[i % NUM_SAMPLES].load(Ordering::Relaxed))
+    }
+
+    fn clear(&self) {
+        self.count.store(0, Ordering::Relaxed);
+    }
+}
+
+fn current_cpu() -> usize {
+    LOCAL_APIC
+        .get()
+        .map_or(0, |apic| (apic.id() as usize).min(MAX_CPUS - 1))
+}
+
+/// Throw away the samples from the last run and start taking new ones.
+pub fn start() {
+    RUNNING.store(false, Ordering::SeqCst);
+    for cpu in SAMPLES.iter() {
+        cpu.clear();
+    }
+    RUNNING.store(true, Ordering::SeqCst);
+}
+
+/// Stop taking samples. The ones taken so far are kept until the next `start`.
+pub fn stop() {
+    RUNNING.store(false, Ordering::SeqCst);
+}
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+/// Called from the timer interrupt with the address it interrupted.
+pub fn record(rip: u64) {
+    if RUNNING.load(Ordering::Relaxed) {
+        SAMPLES[current_cpu()].push(rip);
+    }
+}
+
+/// How many samples are kept, across all processors.
+pub fn num_samples() -> usize {
+    SAMPLES.iter().map(CpuSamples::len).sum()
+}
+
+/// The `count` addresses with the most samples, as (address, samples), most first.
+///
+/// Samples are counted by exact address, as there is no symbol table to group them into functions
+/// yet; nearby addresses with many samples are usually the same hot loop.
+pub fn hotspots(count: usize) -> Vec<(u64, usize)> {
+    let mut counts = BTreeMap::new();
+    for rip in SAMPLES.iter().flat_map(CpuSamples::iter) {
+        *counts.entry(rip).or_insert(0) += 1;
+    }
+
+    let mut hotspots: Vec<(u64, usize)> = counts.into_iter().collect();
+    hotspots.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    hotspots.truncate(count);
+    hotspots
+}
+
+/// Write out every sample, one line per processor, so they can be pulled out of the console log
+/// and processed elsewhere.
+pub fn dump(w: &mut impl Write) -> fmt::Result {
+    for (cpu, samples) in SAMPLES.iter().enumerate() {
+        if samples.len() == 0 {
+            continue;
+        }
+
+        write!(w, "cpu{}:", cpu)?;
+        for rip in samples.iter() {
+            write!(w, " {:x}", rip)?;
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
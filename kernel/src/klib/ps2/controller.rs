@@ -1,9 +1,13 @@
 use lazy_static::lazy_static;
-use crate::klib::x86_64;
+use crate::arch::ports::Port;
+use crate::arch::{Arch, PortIo};
 use spin::Mutex;
 
-const DATA_PORT: u16            = 0x60;
-const CMD_STATUS_REGISTER: u16  = 0x64;
+pub const DATA_PORT: u16            = 0x60;
+pub const CMD_STATUS_REGISTER: u16  = 0x64;
+
+const DATA: Port<u8>                = Port::new(DATA_PORT);
+const CMD_STATUS: Port<u8>          = Port::new(CMD_STATUS_REGISTER);
 
 const ENABLE_FIRST_PORT: u8     = 0xAE;
 const DISABLE_FIRST_PORT: u8    = 0xAD;
@@ -20,22 +24,27 @@ impl Ps2Controller {
     /// Enable the first PS2 port. This is the only port that can be reliably enabled.
     pub fn enable_first(&mut self) {
         unsafe { 
-            x86_64::port_write_u8(CMD_STATUS_REGISTER, ENABLE_FIRST_PORT);
-            x86_64::port_write_u8(CMD_STATUS_REGISTER, 0x60);
-            while x86_64::port_read_u8(CMD_STATUS_REGISTER) & 0b10 != 0 {
-                x86_64::io_wait();
+            CMD_STATUS.write(ENABLE_FIRST_PORT);
+            CMD_STATUS.write(0x60);
+            while CMD_STATUS.read() & 0b10 != 0 {
+                Arch::io_wait();
             }
-            x86_64::port_write_u8(CMD_STATUS_REGISTER, 0b101)
+            CMD_STATUS.write(0b101)
         }
     }
 
+    /// Whether the controller has a byte waiting to be read, going by its status register.
+    pub fn has_byte(&mut self) -> bool {
+        unsafe { CMD_STATUS.read() & 0b1 != 0 }
+    }
+
     /// Start a non-blocking read of the port. This will attempt to read a byte from the first
     /// device port, returning None if a byte is not received within three attempts.
     pub fn nonblocking_read(&mut self) -> Result<u8, ()> {
         let mut count = 0;
         unsafe {
-            while (x86_64::port_read_u8(CMD_STATUS_REGISTER) & 0b1) != 1 && count < 3 {
-                x86_64::io_wait();
+            while (CMD_STATUS.read() & 0b1) != 1 && count < 3 {
+                Arch::io_wait();
                 count += 1
             }
         }
@@ -43,7 +52,7 @@ impl Ps2Controller {
         if count == 3 {
             Err(())
         } else {
-            unsafe { Ok(x86_64::port_read_u8(DATA_PORT)) }
+            unsafe { Ok(DATA.read()) }
         }
     }
 
@@ -51,14 +60,14 @@ impl Ps2Controller {
     pub fn nonblocking_write(&mut self, val: u8) -> Result<(), ()> {
         let mut count = 0;
         unsafe {
-            while (x86_64::port_read_u8(CMD_STATUS_REGISTER) & 0b10) != 1 && count < 3 {
-                x86_64::io_wait();
+            while (CMD_STATUS.read() & 0b10) != 1 && count < 3 {
+                Arch::io_wait();
                 count += 1
             }
         }
 
 
-        unsafe { x86_64::port_write_u8(DATA_PORT, val) };
+        unsafe { DATA.write(val) };
 
         if count == 3 {
             Err(())
@@ -71,13 +80,13 @@ impl Ps2Controller {
     /// an unsafe operation (can end up giving junk data)
     #[inline]
     pub unsafe fn read_raw(&mut self) -> u8 {
-        unsafe { x86_64::port_read_u8(DATA_PORT) }
+        unsafe { DATA.read() }
     }
 
     /// Write a byte to this PS/2 controller. Does not check when a byte is ready to write or not, so this is
     /// an unsafe operation
     #[inline]
     pub unsafe fn write_raw(&mut self, byte: u8) {
-        unsafe { x86_64::port_write_u8(DATA_PORT, byte) }
+        unsafe { DATA.write(byte) }
     }
 }
@@ -3,9 +3,22 @@ use lazy_static::lazy_static;
 use crate::klib::containers::circular_buffer;
 use circular_buffer::CircularBuffer;
 use crate::klib::ps2::controller::Ps2Controller;
+use crate::klib::{input, polling, timer};
+use crate::arch::x86_64::interrupts::without_interrupts;
+use crate::{log_warn, TIMER};
+use core::sync::atomic::Ordering;
+use core::time::Duration;
 
 const RELEASE_GAP: u8 = 0x80;
 
+// What the keyboard answers a command (and each of its data bytes) with
+const ACK: u8 = 0xFA;
+const RESEND: u8 = 0xFE;
+
+// A command that isn't acknowledged within this long is sent again, up to `MAX_RETRIES` times
+const COMMAND_TIMEOUT: Duration = Duration::from_millis(100);
+const MAX_RETRIES: u8 = 3;
+
 const KEY_TABLE: [u8; 256] = [
     b'\0', b'\0', b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', b'9', b'0', b'-', b'=', b'\0', b'\0',
     b'q', b'w', b'e', b'r', b't', b'y', b'u', b'i', b'o', b'p', b'[', b']', b'\0', b'\0', b'a', b's',
@@ -48,10 +61,115 @@ lazy_static! {
     pub static ref KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard::new());
 }
 
+/// Send commands the keyboard doesn't acknowledge in time again. Has to be called once the heap
+/// is up; the checks start once the timer task is running.
+pub fn start_command_timeouts() {
+    timer::every(COMMAND_TIMEOUT, || {
+        let now = TIMER.load(Ordering::SeqCst);
+        // If sending it again fails, the next check tries again
+        let _ = without_interrupts(|| KEYBOARD.lock().check_command_timeout(now));
+    })
+    .detach();
+}
+
+/// Read what the keyboard sends from a timer rather than its interrupt, for `poll=ps2`. Has to be
+/// called once the heap is up.
+pub fn start_polling() {
+    polling::start("ps2", || {
+        without_interrupts(|| {
+            let mut keyboard = KEYBOARD.lock();
+            while keyboard.has_byte() {
+                if let Ok(byte) = keyboard.read_byte() {
+                    let _ = keyboard.handle_byte(byte);
+                }
+            }
+        });
+        input::pump();
+    });
+}
+
 pub struct Keyboard {
-    key_buffer: CircularBuffer<256, KeyCode>,
+    key_buffer: CircularBuffer<256, KeyEvent>,
     cmd_buffer: CircularBuffer<256, Command>,
     controller: Ps2Controller,
+    modifiers: Modifiers,
+    // Lock keys that are down right now, as LED bits, so that typematic repeats of a held lock key
+    // don't keep toggling it
+    locks_held: u8,
+    // The command the keyboard hasn't acknowledged yet, how many more ACKs it owes for it, when it
+    // was (last) sent, and how many times it has been sent again
+    in_flight: Option<Command>,
+    acks_expected: u8,
+    sent_at: u64,
+    retries: u8,
+}
+
+/// A key, along with the modifiers that were in effect when it was pressed or released.
+#[derive(Copy, Clone)]
+pub struct KeyEvent {
+    pub key: KeyCode,
+    pub modifiers: Modifiers,
+}
+
+impl KeyEvent {
+    /// The character a key press types. None for releases, and keys that don't type anything.
+    pub fn ascii(&self) -> Option<char> {
+        let KeyCode::AsciiDown(key) = self.key else {
+            return None;
+        };
+
+        // Caps lock only applies to letters, and shift undoes it
+        let shifted = if key.get().is_ascii_alphabetic() {
+            self.modifiers.shift() != self.modifiers.caps_lock
+        } else {
+            self.modifiers.shift()
+        };
+
+        let ch = if shifted { key.get_shifted() } else { key.get() };
+        Some(ch as char)
+    }
+}
+
+/// Which modifier keys are held down and which lock keys are on.
+#[derive(Copy, Clone, Default)]
+pub struct Modifiers {
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_ctrl: bool,
+    pub right_ctrl: bool,
+    pub left_alt: bool,
+    pub right_alt: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers {
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+
+    pub fn alt(&self) -> bool {
+        self.left_alt || self.right_alt
+    }
+
+    pub fn leds(&self) -> LEDState {
+        let mut leds = LEDState::new();
+        if self.scroll_lock {
+            leds.enable_scroll_lock();
+        }
+        if self.num_lock {
+            leds.enable_num_lock();
+        }
+        if self.caps_lock {
+            leds.enable_caps_lock();
+        }
+        leds
+    }
 }
 
 
@@ -61,6 +179,12 @@ impl Keyboard {
             key_buffer: CircularBuffer::new(),
             cmd_buffer: CircularBuffer::new(),
             controller: Ps2Controller {},
+            modifiers: Modifiers::default(),
+            locks_held: 0,
+            in_flight: None,
+            acks_expected: 0,
+            sent_at: 0,
+            retries: 0,
         }
     }
 
@@ -68,8 +192,10 @@ impl Keyboard {
         self.controller.enable_first()
     }
 
+    /// Send `command` once the keyboard has acknowledged every command before it.
     pub fn enqueue_command(&mut self, command: Command) -> Result<(), ()> {
-        if self.cmd_buffer.empty() {
+        if self.in_flight.is_none() {
+            self.retries = 0;
             self.send_command(command)
         } else {
             self.cmd_buffer.push_back(command);
@@ -77,10 +203,51 @@ impl Keyboard {
         }
     }
 
+    /// Take a byte from the keyboard: either an answer to the command in flight, or a scancode.
+    pub fn handle_byte(&mut self, byte: u8) -> Result<(), ()> {
+        match byte {
+            ACK if self.in_flight.is_some() => {
+                self.acks_expected = self.acks_expected.saturating_sub(1);
+                if self.acks_expected == 0 {
+                    self.in_flight = None;
+                    self.send_next_command()?;
+                }
+                Ok(())
+            },
+            RESEND if self.in_flight.is_some() => self.retry(),
+            _ => self.push_key(byte),
+        }
+    }
+
+    /// Send the command in flight again if the keyboard has taken too long to acknowledge it.
+    pub fn check_command_timeout(&mut self, now: u64) -> Result<(), ()> {
+        let timeout = COMMAND_TIMEOUT.as_millis() as u64;
+        if self.in_flight.is_some() && now.saturating_sub(self.sent_at) >= timeout {
+            self.retry()
+        } else {
+            Ok(())
+        }
+    }
+
+    // Send the command in flight again, or give up on it and go on to the next one
+    fn retry(&mut self) -> Result<(), ()> {
+        let Some(command) = self.in_flight.take() else {
+            return Ok(());
+        };
+
+        if self.retries < MAX_RETRIES {
+            self.retries += 1;
+            self.send_command(command)
+        } else {
+            log_warn!("PS/2 keyboard didn't take command {:#x}", u8::from(command));
+            self.send_next_command()
+        }
+    }
+
     pub fn push_key(&mut self, byte: u8) -> Result<(), ()> {
         match KeyCode::from_byte(byte) {
             Some(key) => {
-                self.key_buffer.push_back(key);
+                self.push_keycode(key);
                 Ok(())
             },
             None => {
@@ -89,13 +256,82 @@ impl Keyboard {
         }
     }
 
-    pub fn pop_key(&mut self) -> Option<KeyCode> {
-        self.key_buffer.pop_back()
+    /// For keys that didn't come from the PS/2 controller, e.g. from a USB keyboard.
+    pub fn push_keycode(&mut self, key: KeyCode) {
+        self.update_modifiers(&key);
+        self.key_buffer.push_back(KeyEvent {
+            key,
+            modifiers: self.modifiers,
+        });
+    }
+
+    pub fn pop_key(&mut self) -> Option<KeyEvent> {
+        self.key_buffer.pop_front()
+    }
+
+    /// The modifier state as of the last key that was pushed.
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    fn update_modifiers(&mut self, key: &KeyCode) {
+        use KeyCode::*;
+
+        let modifiers = &mut self.modifiers;
+        match key {
+            SpecialDown(SpecialKey::LeftShift) => modifiers.left_shift = true,
+            SpecialUp(SpecialKey::LeftShift) => modifiers.left_shift = false,
+            SpecialDown(SpecialKey::RightShift) => modifiers.right_shift = true,
+            SpecialUp(SpecialKey::RightShift) => modifiers.right_shift = false,
+            SpecialDown(SpecialKey::LeftCtrl) => modifiers.left_ctrl = true,
+            SpecialUp(SpecialKey::LeftCtrl) => modifiers.left_ctrl = false,
+            SpecialDown(SpecialKey::LeftAlt) => modifiers.left_alt = true,
+            SpecialUp(SpecialKey::LeftAlt) => modifiers.left_alt = false,
+            ExtendedDown(ExtendedKeyCode::RightCtrl) => modifiers.right_ctrl = true,
+            ExtendedUp(ExtendedKeyCode::RightCtrl) => modifiers.right_ctrl = false,
+            ExtendedDown(ExtendedKeyCode::RightAlt) => modifiers.right_alt = true,
+            ExtendedUp(ExtendedKeyCode::RightAlt) => modifiers.right_alt = false,
+            SpecialDown(SpecialKey::CapsLock) => self.press_lock(LEDState::CAPS_LOCK),
+            SpecialUp(SpecialKey::CapsLock) => self.locks_held &= !LEDState::CAPS_LOCK,
+            SpecialDown(SpecialKey::NumberLock) => self.press_lock(LEDState::NUM_LOCK),
+            SpecialUp(SpecialKey::NumberLock) => self.locks_held &= !LEDState::NUM_LOCK,
+            SpecialDown(SpecialKey::ScrollLock) => self.press_lock(LEDState::SCROLL_LOCK),
+            SpecialUp(SpecialKey::ScrollLock) => self.locks_held &= !LEDState::SCROLL_LOCK,
+            _ => {}
+        }
+    }
+
+    // Toggle a lock key and light its LED to match. `lock` is the key's LED bit.
+    fn press_lock(&mut self, lock: u8) {
+        if self.locks_held & lock != 0 {
+            return;
+        }
+        self.locks_held |= lock;
+
+        match lock {
+            LEDState::CAPS_LOCK => self.modifiers.caps_lock = !self.modifiers.caps_lock,
+            LEDState::NUM_LOCK => self.modifiers.num_lock = !self.modifiers.num_lock,
+            _ => self.modifiers.scroll_lock = !self.modifiers.scroll_lock,
+        }
+
+        // Nothing to do about it if the keyboard doesn't take the command; the state is still right
+        let _ = self.enqueue_command(Command::SetLEDs(self.modifiers.leds()));
+    }
+
+    pub fn has_key(&self) -> bool {
+        !self.key_buffer.is_empty()
     }
 
     pub fn send_next_command(&mut self) -> Result<(), ()> {
-        match self.cmd_buffer.pop_back() {
-            Some(command) => self.send_command(command),
+        if self.in_flight.is_some() {
+            return Ok(());
+        }
+
+        match self.cmd_buffer.pop_front() {
+            Some(command) => {
+                self.retries = 0;
+                self.send_command(command)
+            },
             None => Ok(())
         }
     }
@@ -103,6 +339,14 @@ impl Keyboard {
     fn send_command(&mut self, command: Command) -> Result<(), ()> {
         use Command::*;
 
+        // Even if a write fails, the timeout sends it again
+        self.in_flight = Some(command);
+        self.acks_expected = match command {
+            SetLEDs(_) | GetSetScanCodeSet(_) => 2,
+            _ => 1,
+        };
+        self.sent_at = TIMER.load(Ordering::SeqCst);
+
         self.controller.nonblocking_write(command.into())?;
         match command {
             SetLEDs(state) => self.controller.nonblocking_write(state.0)?,
@@ -116,6 +360,10 @@ impl Keyboard {
     pub fn read_byte(&mut self) -> Result<u8, ()> {
         self.controller.nonblocking_read()
     }
+
+    pub fn has_byte(&mut self) -> bool {
+        self.controller.has_byte()
+    }
 }
 
 #[repr(u8)]
@@ -146,6 +394,10 @@ impl From<Command> for u8 {
 pub struct LEDState(u8);
 
 impl LEDState {
+    const SCROLL_LOCK: u8 = 0b1;
+    const NUM_LOCK: u8 = 0b10;
+    const CAPS_LOCK: u8 = 0b100;
+
     #[inline]
     pub fn new() -> Self {
         Self(0)
@@ -153,19 +405,19 @@ impl LEDState {
     
     #[inline]
     pub fn enable_scroll_lock(&mut self) -> &mut Self {
-        self.0 |= 0b1;
+        self.0 |= Self::SCROLL_LOCK;
         self
     }
 
     #[inline]
     pub fn enable_num_lock(&mut self) -> &mut Self {
-        self.0 |= 0b10;
+        self.0 |= Self::NUM_LOCK;
         self
     }
 
     #[inline]
     pub fn enable_caps_lock(&mut self) -> &mut Self {
-        self.0 |= 0b100;
+        self.0 |= Self::CAPS_LOCK;
         self
     }
 }
@@ -179,6 +431,7 @@ pub enum ScanCodeSet {
     Three = 3,
 }
 
+#[derive(Copy, Clone)]
 pub enum KeyCode {
     AsciiUp(AsciiKey),
     AsciiDown(AsciiKey),
@@ -189,6 +442,24 @@ pub enum KeyCode {
 }
 
 impl KeyCode {
+    /// Build a key code from a set 1 make code, without going through the byte stream. `extended`
+    /// is for the keys whose scancodes are prefixed with 0xE0.
+    pub fn from_scancode(scancode: u8, extended: bool, released: bool) -> Option<Self> {
+        use KeyCode::*;
+        if extended {
+            let code = ExtendedKeyCode::try_from(scancode).ok()?;
+            if released {
+                Some(ExtendedUp(code))
+            } else {
+                Some(ExtendedDown(code))
+            }
+        } else if released {
+            Self::from_byte(scancode | RELEASE_GAP)
+        } else {
+            Self::from_byte(scancode)
+        }
+    }
+
     fn from_byte(byte: u8) -> Option<Self> {
         use KeyCode::*;
         if let Ok(special_key) = SpecialKey::try_from(byte) {
@@ -225,6 +496,7 @@ impl KeyCode {
     }
 }
 
+#[derive(Copy, Clone)]
 #[repr(transparent)]
 pub struct AsciiKey {
     idx: u8,
@@ -334,6 +606,7 @@ pub enum Key {
     NextIsExtended  = 0xE0,
 }
 
+#[derive(Copy, Clone)]
 #[repr(u8)]
 pub enum ExtendedKeyCode {
     PreviousTrack = 0x10,
@@ -349,6 +622,7 @@ pub enum ExtendedKeyCode {
     WwwHome       = 0x32,
     KeypadSlash   = 0x35,
     RightAlt      = 0x38,
+    Home          = 0x47,
     CursorUp      = 0x48,
     PageUp        = 0x49,
     CursorLeft    = 0x4B,
@@ -358,6 +632,7 @@ pub enum ExtendedKeyCode {
     PageDown      = 0x51,
     Insert        = 0x52,
     Delete        = 0x53,
+    LeftGui       = 0x5B,
     RightGui      = 0x5C,
     Apps          = 0x5D,
     AcpiPower     = 0x5E,
@@ -374,6 +649,7 @@ pub enum ExtendedKeyCode {
     MediaSelect   = 0x6D,
 }
 
+#[derive(Copy, Clone)]
 #[repr(u8)]
 pub enum SpecialKey {
     Esc             = 0x01,
@@ -440,6 +716,7 @@ impl TryFrom<u8> for ExtendedKeyCode {
             0x32 => Ok(WwwHome),
             0x35 => Ok(KeypadSlash),
             0x38 => Ok(RightAlt),
+            0x47 => Ok(Home),
             0x48 => Ok(CursorUp),
             0x49 => Ok(PageUp),
             0x4B => Ok(CursorLeft),
@@ -449,6 +726,7 @@ impl TryFrom<u8> for ExtendedKeyCode {
             0x51 => Ok(PageDown),
             0x52 => Ok(Insert),
             0x53 => Ok(Delete),
+            0x5B => Ok(LeftGui),
             0x5C => Ok(RightGui),
             0x5D => Ok(Apps),
             0x5E => Ok(AcpiPower),
@@ -0,0 +1,58 @@
+// Devices only QEMU has, built with the `qemu` feature, which `cargo run` then adds to the machine:
+// isa-debug-exit, which exits QEMU with a status when written to, and the debugcon, a port whose
+// bytes QEMU writes out to a file on the host. Everything printed goes to the debugcon as well
+// as the screen, from the first line on, so it is there even if the kernel never gets as far as
+// the framebuffer.
+//
+// Boot tests pass `qemu-exit` on the command line to have QEMU exit once the kernel has booted,
+// with success, or when it panics, with failure.
+
+use super::cmdline;
+use crate::arch::ports::Port;
+use crate::arch::{Arch, Cpu};
+use core::fmt;
+
+/// Where the runner puts isa-debug-exit. QEMU exits with (code << 1) | 1.
+pub const EXIT_PORT: u16 = 0xF4;
+
+/// The debugcon's port, the same as Bochs' port 0xE9 hack.
+pub const DEBUGCON_PORT: u16 = 0xE9;
+
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub enum ExitCode {
+    Success = 0x10,
+    Failure = 0x11,
+}
+
+/// Whether QEMU should be exited once the kernel has booted or has panicked: with `qemu-exit` on
+/// the command line, and always in self-test builds.
+pub fn exits_when_done() -> bool {
+    cfg!(feature = "selftest") || cmdline::has("qemu-exit")
+}
+
+/// Exit QEMU. Halts forever if there is no isa-debug-exit device.
+pub fn exit(code: ExitCode) -> ! {
+    unsafe { Port::<u8>::new(EXIT_PORT).write(code as u8) };
+    loop {
+        Arch::halt();
+    }
+}
+
+struct Debugcon;
+
+impl fmt::Write for Debugcon {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        let port = Port::<u8>::new(DEBUGCON_PORT);
+        for byte in string.bytes() {
+            unsafe { port.write(byte) };
+        }
+        Ok(())
+    }
+}
+
+/// Write to the debugcon. Takes no locks, so it works anywhere, but output from CPUs printing at
+/// the same time is mixed together.
+pub fn debugcon_write(args: fmt::Arguments) {
+    let _ = fmt::Write::write_fmt(&mut Debugcon, args);
+}
@@ -0,0 +1,223 @@
+use crate::arch::x86_64::cpu;
+use crate::arch::{Arch, Cpu, PortIo};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Intel recommends giving up on RDRAND after 10 failures in a row; RDSEED can legitimately run
+// dry for longer, but we have other sources to fall back on.
+const HARDWARE_RETRIES: usize = 10;
+
+// Samples of TSC jitter taken when the generator is first seeded
+const JITTER_SAMPLES: usize = 64;
+
+const CHACHA_ROUNDS: usize = 20;
+const CHACHA_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646E, 0x7962_2D32, 0x6B20_6574];
+
+static RNG: Mutex<Option<Rng>> = Mutex::new(None);
+
+// Mixed into the key on every request. Interrupt handlers xor TSC readings in here, spread out
+// so that back to back interrupts don't just cancel each other out.
+static INTERRUPT_POOL: [AtomicU64; 4] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+static POOL_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    RdSeed,
+    RdRand,
+    Jitter, // TSC jitter and interrupt timing only
+}
+
+/// A ChaCha20 keystream generator that rekeys itself after every request, so that the state
+/// can't be used to work out earlier output.
+struct Rng {
+    key: [u32; 8],
+    counter: u64,
+    source: Source,
+}
+
+impl Rng {
+    fn new() -> Self {
+        let source = detect_source();
+        let mut rng = Self {
+            key: [0; 8],
+            counter: 0,
+            source,
+        };
+
+        for (i, word) in rng.key.iter_mut().enumerate() {
+            *word = jitter_sample().rotate_left(i as u32 * 8) as u32;
+        }
+        for _ in 0..JITTER_SAMPLES {
+            let sample = jitter_sample();
+            rng.key[(sample as usize) % 8] ^= sample as u32;
+            rng.rekey();
+        }
+
+        rng
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        self.reseed();
+
+        for chunk in buf.chunks_mut(64) {
+            let block = self.block();
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+
+        self.rekey();
+    }
+
+    // Mix in whatever fresh entropy there is
+    fn reseed(&mut self) {
+        for (i, word) in self.key.iter_mut().enumerate() {
+            let hardware = match self.source {
+                Source::RdSeed => rdseed().or_else(rdrand).unwrap_or(0),
+                Source::RdRand => rdrand().unwrap_or(0),
+                Source::Jitter => 0,
+            };
+            let pool = INTERRUPT_POOL[i % INTERRUPT_POOL.len()].load(Ordering::Relaxed);
+
+            *word ^= (hardware ^ pool ^ Arch::timestamp().rotate_left(i as u32 * 8)) as u32;
+        }
+    }
+
+    // Replace the key with keystream that nobody gets to see
+    fn rekey(&mut self) {
+        let block = self.block();
+        for (word, bytes) in self.key.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+    }
+
+    fn block(&mut self) -> [u8; 64] {
+        let mut state = [0u32; 16];
+        state[..4].copy_from_slice(&CHACHA_CONSTANTS);
+        state[4..12].copy_from_slice(&self.key);
+        state[12] = self.counter as u32;
+        state[13] = (self.counter >> 32) as u32;
+        // The nonce stays 0; the key changes often enough anyway
+
+        self.counter = self.counter.wrapping_add(1);
+
+        let mut working = state;
+        for _ in 0..CHACHA_ROUNDS / 2 {
+            quarter_round(&mut working, 0, 4, 8, 12);
+            quarter_round(&mut working, 1, 5, 9, 13);
+            quarter_round(&mut working, 2, 6, 10, 14);
+            quarter_round(&mut working, 3, 7, 11, 15);
+            quarter_round(&mut working, 0, 5, 10, 15);
+            quarter_round(&mut working, 1, 6, 11, 12);
+            quarter_round(&mut working, 2, 7, 8, 13);
+            quarter_round(&mut working, 3, 4, 9, 14);
+        }
+
+        let mut out = [0u8; 64];
+        for (i, bytes) in out.chunks_exact_mut(4).enumerate() {
+            bytes.copy_from_slice(&working[i].wrapping_add(state[i]).to_le_bytes());
+        }
+        out
+    }
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+fn detect_source() -> Source {
+    if cpu::has_rdseed() && rdseed().is_some() {
+        return Source::RdSeed;
+    }
+    if cpu::has_rdrand() && rdrand().is_some() {
+        return Source::RdRand;
+    }
+    Source::Jitter
+}
+
+// Only once `detect_source` has found RDRAND
+fn rdrand() -> Option<u64> {
+    (0..HARDWARE_RETRIES).find_map(|_| unsafe { cpu::rdrand() })
+}
+
+// Only once `detect_source` has found RDSEED
+fn rdseed() -> Option<u64> {
+    (0..HARDWARE_RETRIES).find_map(|_| unsafe { cpu::rdseed() })
+}
+
+// How long a port write takes varies a little each time, especially under emulation
+fn jitter_sample() -> u64 {
+    let start = Arch::timestamp();
+    unsafe { Arch::io_wait() };
+    let end = Arch::timestamp();
+    end.wrapping_sub(start).wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ end
+}
+
+/// Seed the generator now rather than on first use. Returns where its entropy comes from.
+pub fn init() -> Source {
+    interrupts::without_interrupts(|| RNG.lock().get_or_insert_with(Rng::new).source)
+}
+
+/// Called from interrupt handlers: the exact time an interrupt arrives is a little
+/// unpredictable.
+pub fn add_interrupt_entropy() {
+    let index = POOL_INDEX.fetch_add(1, Ordering::Relaxed) % INTERRUPT_POOL.len();
+    let sample = Arch::timestamp();
+    let _ = INTERRUPT_POOL[index].fetch_update(Ordering::Relaxed, Ordering::Relaxed, |pool| {
+        Some(pool.rotate_left(13) ^ sample)
+    });
+}
+
+/// Fill `buf` with random bytes. Fine for keys and IDs, as long as the source isn't `Jitter`.
+pub fn get_random_bytes(buf: &mut [u8]) {
+    interrupts::without_interrupts(|| RNG.lock().get_or_insert_with(Rng::new).fill(buf));
+}
+
+pub fn get_random_u64() -> u64 {
+    let mut bytes = [0u8; 8];
+    get_random_bytes(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+/// A fast generator that always comes up with the same numbers for the same seed, for benchmarks
+/// that should do the same thing every run. Nothing about it is hard to guess, so it's no good for
+/// keys.
+pub struct Xorshift(u64);
+
+impl Xorshift {
+    pub fn new(seed: u64) -> Self {
+        // Zero would stay zero forever
+        Self(seed | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number from 0 up to but not including `bound`, which can't be 0.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+
+    /// Put `items` in a random order.
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = self.below(i as u64 + 1) as usize;
+            items.swap(i, j);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+// 16550 UARTs, the serial ports at fixed I/O ports on PCs. Only sending is supported, by polling:
+// nothing reads from a serial port yet, so there are no interrupts to take.
+
+use crate::arch::ports::Port;
+use crate::arch::{Arch, Cpu};
+
+/// The I/O ports of the second serial port.
+pub const COM2_PORT: u16 = 0x2F8;
+
+/// How many ports in a row each UART has.
+pub const NUM_PORTS: u16 = 8;
+
+// Register offsets. With DLAB set in the line control register, the first two are the divisor.
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+const LINE_DLAB: u8 = 0x80;
+const LINE_8N1: u8 = 0x03;
+// Enable and clear both FIFOs, interrupt at 14 bytes
+const FIFO_ENABLE: u8 = 0xC7;
+const MODEM_LOOPBACK: u8 = 0x10;
+// DTR, RTS and OUT2
+const MODEM_NORMAL: u8 = 0x0B;
+const STATUS_TRANSMIT_EMPTY: u8 = 0x20;
+
+// The UART's clock divided by 16, i.e. the fastest baud rate
+const BASE_BAUD: u32 = 115_200;
+
+// Sent in loopback mode to see whether anything is there
+const PROBE_BYTE: u8 = 0xAE;
+
+pub struct Uart {
+    base: Port<u8>,
+}
+
+impl Uart {
+    /// Set up the UART at `port` for `baud` baud, 8 data bits, no parity and one stop bit. None if
+    /// there is no UART there, which is found out by sending a byte to itself.
+    /// ### Safety
+    /// Nothing else may be driving the UART at `port`.
+    pub unsafe fn probe(port: u16, baud: u32) -> Option<Self> {
+        let uart = Self {
+            base: Port::new(port),
+        };
+        let divisor = (BASE_BAUD / baud.clamp(1, BASE_BAUD)) as u16;
+
+        uart.register(INTERRUPT_ENABLE).write(0);
+        uart.register(LINE_CONTROL).write(LINE_DLAB);
+        uart.register(DATA).write(divisor as u8);
+        uart.register(INTERRUPT_ENABLE).write((divisor >> 8) as u8);
+        uart.register(LINE_CONTROL).write(LINE_8N1);
+        uart.register(FIFO_CONTROL).write(FIFO_ENABLE);
+
+        uart.register(MODEM_CONTROL).write(MODEM_LOOPBACK);
+        uart.register(DATA).write(PROBE_BYTE);
+        if uart.register(DATA).read() != PROBE_BYTE {
+            return None;
+        }
+
+        uart.register(MODEM_CONTROL).write(MODEM_NORMAL);
+        Some(uart)
+    }
+
+    /// Send `bytes`, waiting for room in the FIFO as needed.
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            unsafe {
+                while self.register(LINE_STATUS).read() & STATUS_TRANSMIT_EMPTY == 0 {
+                    Arch::pause();
+                }
+                self.register(DATA).write(byte);
+            }
+        }
+    }
+
+    fn register(&self, offset: u16) -> Port<u8> {
+        self.base.offset(offset)
+    }
+}
@@ -0,0 +1,48 @@
+// An orderly shutdown. Each subsystem that needs stopping registers a teardown as it's brought up,
+// and `run` calls them in the reverse order, so that everything is stopped before what it was
+// built on: block caches are written back before the disks under them stop, and interrupts are
+// masked last. `pm::shutdown` and `pm::reboot` run it before touching the hardware.
+//
+// Teardowns are plain functions in a fixed-size list, so they can be registered before the heap
+// is up.
+
+use crate::klib::containers::static_vec::StaticVec;
+use crate::{log_info, log_warn};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const MAX_TEARDOWNS: usize = 16;
+
+/// Stops a subsystem. An error is logged, and the rest of the teardowns still run.
+pub type Teardown = fn() -> Result<(), ()>;
+
+static TEARDOWNS: Mutex<StaticVec<(&'static str, Teardown), MAX_TEARDOWNS>> =
+    Mutex::new(StaticVec::new());
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Have `teardown` run when the kernel shuts down, before everything registered earlier.
+pub fn register(name: &'static str, teardown: Teardown) -> Result<(), ()> {
+    interrupts::without_interrupts(|| TEARDOWNS.lock().push((name, teardown)).map_err(|_| ()))
+}
+
+/// Run every teardown, the last registered first. Has to be called from a task with interrupts
+/// on, as writing back caches waits for the disks. Only the first call does anything.
+pub fn run() {
+    if STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    loop {
+        let Some((name, teardown)) = interrupts::without_interrupts(|| TEARDOWNS.lock().pop())
+        else {
+            break;
+        };
+
+        log_info!("Stopping {}", name);
+        if teardown().is_err() {
+            log_warn!("Couldn't stop {} cleanly", name);
+        }
+    }
+}
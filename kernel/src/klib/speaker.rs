@@ -0,0 +1,46 @@
+use crate::arch::ports::Port;
+use crate::klib::clock::{PIT_COMMAND_PORT, PIT_FREQUENCY};
+use x86_64::instructions::interrupts;
+
+pub const PIT_CHANNEL_2_PORT: u16 = 0x42;
+const PIT_CHANNEL_2: Port<u8> = Port::new(PIT_CHANNEL_2_PORT);
+const PIT_COMMAND: Port<u8> = Port::new(PIT_COMMAND_PORT);
+
+// Channel 2, low byte then high byte, mode 3 (square wave), binary
+const CHANNEL_2_SQUARE_WAVE: u8 = 0b1011_0110;
+
+// Bit 0 gates channel 2, bit 1 connects its output to the speaker
+pub const SPEAKER_CONTROL_PORT: u16 = 0x61;
+const SPEAKER_CONTROL: Port<u8> = Port::new(SPEAKER_CONTROL_PORT);
+const SPEAKER_ENABLE: u8 = 0b11;
+
+/// Start the PC speaker playing a square wave at roughly `frequency` Hz, until `stop` is called.
+/// Frequencies outside of what the PIT can divide down to are clamped.
+pub fn start(frequency: u32) {
+    let divisor = (PIT_FREQUENCY / frequency.max(1)).clamp(1, u16::MAX as u32) as u16;
+
+    interrupts::without_interrupts(|| unsafe {
+        PIT_COMMAND.write(CHANNEL_2_SQUARE_WAVE);
+        PIT_CHANNEL_2.write(divisor as u8);
+        PIT_CHANNEL_2.write((divisor >> 8) as u8);
+
+        let control = SPEAKER_CONTROL.read();
+        SPEAKER_CONTROL.write(control | SPEAKER_ENABLE);
+    });
+}
+
+pub fn stop() {
+    interrupts::without_interrupts(|| unsafe {
+        let control = SPEAKER_CONTROL.read();
+        SPEAKER_CONTROL.write(control & !SPEAKER_ENABLE);
+    });
+}
+
+/// Play a tone for at least `milliseconds`, sleeping the current task in the meantime. If two
+/// tasks beep at once, whichever started last picks the frequency and the first to finish stops
+/// both.
+pub fn beep(frequency: u32, milliseconds: u64) {
+    start(frequency);
+    crate::sleep(milliseconds);
+    stop();
+}
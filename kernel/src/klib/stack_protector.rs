@@ -0,0 +1,25 @@
+// Support for `-Z stack-protector`, which the kernel is built with (see .cargo/config.toml).
+// Functions with arrays or locals whose address is taken copy `__stack_chk_guard` in between
+// their locals and their return address on entry, and check that it is still there before
+// returning. If it isn't, something wrote past the end of a local, and they call
+// `__stack_chk_fail` rather than return through what may be an overwritten return address.
+//
+// The canary can't be randomized once the kernel is running, since the frames already on the
+// stack (kernel_main's, for one) would then fail the check when they return. It has a zero byte
+// in it so that string copies running off the end of a buffer stop short of reproducing it.
+
+use crate::arch::x86_64::cpu;
+
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static __stack_chk_guard: u64 = 0x5EC7_A9B1_00C4_E2D3;
+
+/// Called instead of returning by a function whose canary was overwritten.
+#[no_mangle]
+pub extern "C" fn __stack_chk_fail() -> ! {
+    // Our own frame is intact even though the caller's isn't, so this return address is good,
+    // whereas the backtrace the panic handler prints may not get any further than it. No locks
+    // are taken (e.g. to say which task this is), as the caller may have been holding them.
+    let caller = unsafe { ((cpu::read_rbp() + 8) as *const u64).read() };
+    panic!("Stack smashing detected before {:#x}", caller)
+}
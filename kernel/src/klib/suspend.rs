@@ -0,0 +1,70 @@
+// Stopping devices for suspend to RAM and starting them again after. Like shutdown.rs, each
+// subsystem registers its hooks as it's brought up, and they are suspended in the reverse order
+// and resumed in the order they were registered in. Unlike a teardown, a suspend has to be
+// undone, so a device that won't suspend has the ones before it resumed and the suspend fails.
+
+use crate::klib::containers::static_vec::StaticVec;
+use crate::{log_info, log_warn};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const MAX_DEVICES: usize = 16;
+
+/// Stops a device before sleeping, or starts it again after.
+pub type Hook = fn() -> Result<(), ()>;
+
+#[derive(Clone, Copy)]
+struct Device {
+    name: &'static str,
+    suspend: Hook,
+    resume: Hook,
+}
+
+static DEVICES: Mutex<StaticVec<Device, MAX_DEVICES>> = Mutex::new(StaticVec::new());
+
+/// Have `suspend` run before sleeping, after everything registered later, and `resume` after
+/// waking, before everything registered later.
+pub fn register(name: &'static str, suspend: Hook, resume: Hook) -> Result<(), ()> {
+    let device = Device {
+        name,
+        suspend,
+        resume,
+    };
+    interrupts::without_interrupts(|| DEVICES.lock().push(device).map_err(|_| ()))
+}
+
+fn devices() -> StaticVec<Device, MAX_DEVICES> {
+    interrupts::without_interrupts(|| DEVICES.lock().clone())
+}
+
+/// Suspend every device, the last registered first. Has to be called from a task with interrupts
+/// on, as writing back caches waits for the disks. If one fails, the ones already suspended are
+/// resumed.
+pub fn suspend_devices() -> Result<(), ()> {
+    let devices = devices();
+
+    for (index, device) in devices.iter().enumerate().rev() {
+        log_info!("Suspending {}", device.name);
+        if (device.suspend)().is_err() {
+            log_warn!("Couldn't suspend {}", device.name);
+            resume(&devices[index + 1..]);
+            return Err(());
+        }
+    }
+
+    Ok(())
+}
+
+/// Resume every device, in the order they were registered in. Has to be called with interrupts
+/// off, as the interrupt controllers are among them.
+pub fn resume_devices() {
+    resume(&devices());
+}
+
+fn resume(devices: &[Device]) {
+    for device in devices {
+        if (device.resume)().is_err() {
+            log_warn!("Couldn't resume {}", device.name);
+        }
+    }
+}
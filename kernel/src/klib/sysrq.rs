@@ -0,0 +1,101 @@
+// Debug hotkeys in the spirit of Linux's magic SysRq: Ctrl+Alt+<letter>. They are handled by the
+// keyboard interrupt itself rather than by a task, so they keep working while the shell is stuck,
+// or even the scheduler, as long as interrupts still come in. Keys they handle are not passed on
+// to anyone else.
+
+use super::acpi::pm;
+use super::ps2::keyboard::{KeyCode, KeyEvent};
+use crate::allocator;
+use crate::arch::x86_64::interrupts::{idt, vectors};
+use crate::println;
+use crate::task;
+
+const ACTIONS: &[(u8, &str, fn())] = &[
+    (b'h', "help", help),
+    (b't', "list tasks", tasks),
+    (b'm', "memory", memory),
+    (b'i', "interrupt counts", interrupt_counts),
+    (b'c', "crash (panic with a backtrace)", crash),
+    (b'b', "reboot", reboot),
+];
+
+/// Run the action for a key, if it is a SysRq key. Returns whether it was one. Called with
+/// interrupts off.
+pub fn handle(event: &KeyEvent) -> bool {
+    if !(event.modifiers.ctrl() && event.modifiers.alt()) {
+        return false;
+    }
+
+    let ch = match event.key {
+        KeyCode::AsciiDown(key) | KeyCode::AsciiUp(key) => key.get(),
+        _ => return false,
+    };
+    let Some(&(_, _, action)) = ACTIONS.iter().find(|&&(key, _, _)| key == ch) else {
+        return false;
+    };
+
+    if let KeyCode::AsciiDown(_) = event.key {
+        println!();
+        action();
+    }
+    true
+}
+
+fn help() {
+    println!("SysRq: Ctrl+Alt+");
+    for &(key, name, _) in ACTIONS {
+        println!("  {}  {}", key as char, name);
+    }
+}
+
+fn tasks() {
+    // The scheduler lock is only ever held with interrupts off, so it can't be held under us, but
+    // the heap lock can be, and listing the tasks allocates
+    if allocator::is_locked() {
+        println!("SysRq: the heap is locked, can't list tasks");
+        return;
+    }
+
+    println!("{:>4} {:<12} {:<9} {:>8}", "ID", "NAME", "STATE", "CPU");
+    for info in task::snapshot() {
+        let state = match info.state {
+            task::TaskState::Running => "running",
+            task::TaskState::Ready => "ready",
+            task::TaskState::Blocked => "blocked",
+            task::TaskState::Sleeping(_) => "sleeping",
+            task::TaskState::Dead => "dead",
+        };
+        println!(
+            "{:>4} {:<12} {:<9} {:>8}",
+            info.id, info.name, state, info.stats.runtime
+        );
+    }
+}
+
+fn memory() {
+    match allocator::free_bytes() {
+        Some(free) => println!(
+            "heap {} KiB free of {} KiB",
+            free / 1024,
+            allocator::HEAP_SIZE / 1024
+        ),
+        None => println!("SysRq: the heap is locked"),
+    }
+}
+
+fn interrupt_counts() {
+    println!("{:>6} {:>12}  {}", "VECTOR", "COUNT", "OWNER");
+    for (vector, count) in idt::counts() {
+        let owner = vectors::owner(vector).unwrap_or("-");
+        println!("{:>#6x} {:>12}  {}", vector, count, owner);
+    }
+}
+
+fn crash() {
+    panic!("Crash requested through SysRq");
+}
+
+fn reboot() {
+    println!("SysRq: rebooting");
+    pm::emergency_reboot();
+}
@@ -0,0 +1,96 @@
+// Stats sent out of the machine as it runs, for tracking how kernel changes affect the allocator
+// and the disks. Every `telemetry=<ms>` milliseconds (a second by default) a line of JSON goes
+// out of the second serial port: the uptime as "t", then a flat set of numbers from the heap, the
+// block caches and each request queue. Names ending in `_total` count up from boot; the rest are
+// what the value was at the time. `cargo run -- telemetry` gives QEMU a second serial port writing
+// to a file, and sums the file up once QEMU exits.
+//
+// Nothing is sent unless there's a UART on COM2, so machines without one don't notice, and
+// `notelemetry` keeps quiet on ones that have one.
+
+use crate::allocator;
+use crate::arch::ports;
+use crate::klib::serial::{self, Uart};
+use crate::klib::{bcache, clock, cmdline, iosched};
+use crate::log_info;
+use crate::task::{self, Priority};
+use alloc::string::String;
+use core::fmt::{self, Write};
+
+const DEFAULT_INTERVAL_MS: u64 = 1000;
+// Sending a line takes a few milliseconds at this rate
+const MIN_INTERVAL_MS: u64 = 10;
+const BAUD: u32 = 115_200;
+
+/// Start sending stats, if there is somewhere to send them. Has to be called after `task::init`.
+/// Fails if the task sending them couldn't be started.
+pub fn start() -> Result<(), ()> {
+    if cmdline::has("notelemetry") {
+        return Ok(());
+    }
+    let interval = cmdline::value("telemetry")
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_INTERVAL_MS)
+        .max(MIN_INTERVAL_MS);
+
+    if ports::claim(serial::COM2_PORT, serial::NUM_PORTS, "telemetry").is_err() {
+        return Ok(());
+    }
+    let Some(mut uart) = (unsafe { Uart::probe(serial::COM2_PORT, BAUD) }) else {
+        ports::release(serial::COM2_PORT);
+        return Ok(());
+    };
+
+    task::spawn("telemetry", Priority::Idle, move || {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            sample(&mut line);
+            uart.write(line.as_bytes());
+            task::sleep_ticks(interval);
+        }
+    })?;
+
+    log_info!("Sending telemetry on COM2 every {} ms", interval);
+    Ok(())
+}
+
+// One line of stats, newline included
+fn sample(line: &mut String) {
+    let _ = write!(line, "{{\"t\":{}", clock::uptime_ms());
+
+    // Skipped if someone is in the middle of allocating
+    if let Some((free, largest)) = allocator::fragmentation() {
+        metric(line, format_args!("heap.free_bytes"), free);
+        metric(line, format_args!("heap.largest_free_bytes"), largest);
+    }
+
+    let (mut cached, mut dirty, mut corrupt) = (0, 0, 0);
+    for cache in bcache::caches() {
+        let stats = cache.stats();
+        cached += stats.cached;
+        dirty += stats.dirty;
+        corrupt += stats.corrupt;
+    }
+    metric(line, format_args!("cache.cached_blocks"), cached as u64);
+    metric(line, format_args!("cache.dirty_blocks"), dirty as u64);
+    metric(line, format_args!("cache.corrupt_total"), corrupt as u64);
+
+    for (name, queue) in iosched::queues() {
+        let (pending, stats) = queue.stats();
+        let mut disk = |suffix: &str, value: u64| {
+            metric(line, format_args!("disk.{}.{}", name, suffix), value)
+        };
+        disk("pending", pending as u64);
+        disk("completed_total", stats.completed);
+        disk("errors_total", stats.errors);
+        disk("read_bytes_total", stats.bytes_read);
+        disk("written_bytes_total", stats.bytes_written);
+    }
+
+    line.push_str("}\n");
+}
+
+fn metric(line: &mut String, name: fmt::Arguments, value: u64) {
+    let _ = write!(line, ",\"{}\":{}", name, value);
+}
@@ -0,0 +1,246 @@
+// The time of day, and how times and lengths of time are shown. The RTC is read once at boot and
+// the time since then is worked out from the uptime, so reading the time never goes near the CMOS.
+//
+// The RTC is taken to be on UTC, and its two digit year to be from 2000 to 2099: where the century
+// is kept isn't standard, and if the FADT says, it's only once ACPI is up, long after the clock.
+
+use super::{clock, cmos};
+use crate::{log_info, log_warn};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// RTC registers
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_STATUS_A: u8 = 0x0A;
+const RTC_STATUS_B: u8 = 0x0B;
+
+// Status A: the clock is being updated, and what's read may be half before and half after
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+// Status B
+const HOURS_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+// The top bit of the hours, in 12 hour mode
+const PM: u8 = 1 << 7;
+
+// An update takes about 2 ms, and reading the status takes a microsecond or so. Without an RTC the
+// status reads as all ones, and it would never finish.
+const MAX_STATUS_READS: usize = 100_000;
+// Reads are repeated until two in a row agree, in case an update started in between
+const MAX_READS: usize = 8;
+
+const SECONDS_PER_MINUTE: u64 = 60;
+const SECONDS_PER_HOUR: u64 = 60 * SECONDS_PER_MINUTE;
+const SECONDS_PER_DAY: u64 = 24 * SECONDS_PER_HOUR;
+
+// The time when the uptime was 0, or 0 if the RTC couldn't be read
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// A time of day, to the second.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    // Since the start of 1970, UTC
+    seconds: u64,
+}
+
+/// A length of time, to the millisecond.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Duration {
+    ms: u64,
+}
+
+// A timestamp split up the way people write it
+struct Date {
+    year: u64,
+    month: u64,
+    day: u64,
+    hour: u64,
+    minute: u64,
+    second: u64,
+}
+
+/// Read the RTC, for the time the kernel booted at. Has to be called once the clock is running.
+pub fn init() {
+    match read_rtc() {
+        Some(now) => {
+            let uptime = clock::uptime_ms() / 1000;
+            let boot = now.seconds.saturating_sub(uptime);
+            BOOT_TIME.store(boot, Ordering::Relaxed);
+            log_info!("Booted at {}", Timestamp::from_unix(boot));
+        }
+        None => log_warn!("Couldn't read the RTC, the time of day is unknown"),
+    }
+}
+
+impl Timestamp {
+    pub const fn from_unix(seconds: u64) -> Self {
+        Self { seconds }
+    }
+
+    /// The time now, if the RTC could be read at boot.
+    pub fn now() -> Option<Self> {
+        Self::at_uptime(clock::uptime_ms())
+    }
+
+    /// The time the kernel booted at.
+    pub fn boot() -> Option<Self> {
+        Self::at_uptime(0)
+    }
+
+    /// The time it was when the uptime was `uptime_ms`, e.g. when a log record was made.
+    pub fn at_uptime(uptime_ms: u64) -> Option<Self> {
+        match BOOT_TIME.load(Ordering::Relaxed) {
+            0 => None,
+            boot => Some(Self::from_unix(boot + uptime_ms / 1000)),
+        }
+    }
+
+    fn from_date(date: &Date) -> Self {
+        // Counted from March, so that leap days come at the end of the year
+        let year = if date.month <= 2 {
+            date.year - 1
+        } else {
+            date.year
+        };
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let march_month = (date.month + 9) % 12;
+        let day_of_year = (153 * march_month + 2) / 5 + date.day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        // 1970-01-01 is this many days after 0000-03-01
+        let days = (era * 146_097 + day_of_era).saturating_sub(719_468);
+
+        Self::from_unix(
+            days * SECONDS_PER_DAY
+                + date.hour * SECONDS_PER_HOUR
+                + date.minute * SECONDS_PER_MINUTE
+                + date.second,
+        )
+    }
+
+    fn date(self) -> Date {
+        let seconds = self.seconds % SECONDS_PER_DAY;
+        let days = self.seconds / SECONDS_PER_DAY + 719_468;
+        let era = days / 146_097;
+        let day_of_era = days % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let march_month = (5 * day_of_year + 2) / 153;
+        let month = (march_month + 2) % 12 + 1;
+        let year = era * 400 + year_of_era + (month <= 2) as u64;
+
+        Date {
+            year,
+            month,
+            day: day_of_year - (153 * march_month + 2) / 5 + 1,
+            hour: seconds / SECONDS_PER_HOUR,
+            minute: seconds % SECONDS_PER_HOUR / SECONDS_PER_MINUTE,
+            second: seconds % SECONDS_PER_MINUTE,
+        }
+    }
+}
+
+/// e.g. "2024-03-09 14:05:00", on UTC.
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let date = self.date();
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            date.year, date.month, date.day, date.hour, date.minute, date.second
+        )
+    }
+}
+
+impl Duration {
+    pub const fn from_millis(ms: u64) -> Self {
+        Self { ms }
+    }
+
+    /// How long the kernel has been running.
+    pub fn uptime() -> Self {
+        Self::from_millis(clock::uptime_ms())
+    }
+}
+
+/// The two biggest units there's any of, e.g. "850 ms", "12.345 s", "5m 03s", "2h 05m" or
+/// "3d 02h".
+impl fmt::Display for Duration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.ms / 1000;
+        let (days, hours) = (seconds / SECONDS_PER_DAY, seconds / SECONDS_PER_HOUR % 24);
+        let (minutes, seconds) = (seconds / SECONDS_PER_MINUTE % 60, seconds % 60);
+
+        if days > 0 {
+            write!(f, "{}d {:02}h", days, hours)
+        } else if hours > 0 {
+            write!(f, "{}h {:02}m", hours, minutes)
+        } else if minutes > 0 {
+            write!(f, "{}m {:02}s", minutes, seconds)
+        } else if seconds > 0 {
+            write!(f, "{}.{:03} s", seconds, self.ms % 1000)
+        } else {
+            write!(f, "{} ms", self.ms)
+        }
+    }
+}
+
+fn read_rtc() -> Option<Timestamp> {
+    let mut last = None;
+    for _ in 0..MAX_READS {
+        (0..MAX_STATUS_READS).find(|_| cmos::read(RTC_STATUS_A) & UPDATE_IN_PROGRESS == 0)?;
+        let registers = [
+            RTC_SECONDS,
+            RTC_MINUTES,
+            RTC_HOURS,
+            RTC_DAY,
+            RTC_MONTH,
+            RTC_YEAR,
+        ]
+        .map(cmos::read);
+        if last == Some(registers) {
+            return from_rtc(registers, cmos::read(RTC_STATUS_B));
+        }
+        last = Some(registers);
+    }
+    None
+}
+
+// The time in the RTC's registers, which are in BCD unless `status_b` says otherwise
+fn from_rtc(registers: [u8; 6], status_b: u8) -> Option<Timestamp> {
+    let [second, minute, hour, day, month, year] = registers;
+    let decode = |value: u8| match status_b & BINARY {
+        0 => (value >> 4) as u64 * 10 + (value & 0x0F) as u64,
+        _ => value as u64,
+    };
+
+    let mut hour_of_day = decode(hour & !PM);
+    if status_b & HOURS_24 == 0 {
+        // 12 is midnight and noon
+        hour_of_day %= 12;
+        if hour & PM != 0 {
+            hour_of_day += 12;
+        }
+    }
+
+    let date = Date {
+        year: 2000 + decode(year),
+        month: decode(month),
+        day: decode(day),
+        hour: hour_of_day,
+        minute: decode(minute),
+        second: decode(second),
+    };
+    let valid = (1..=12).contains(&date.month)
+        && (1..=31).contains(&date.day)
+        && date.hour < 24
+        && date.minute < 60
+        && date.second < 60
+        && date.year < 2100;
+    valid.then(|| Timestamp::from_date(&date))
+}
@@ -0,0 +1,199 @@
+// Timers for drivers: `after` runs a closure once some time has gone by, and `every` runs one over
+// and over, until the `Timer` handle they return is dropped or cancelled. The closures run on the
+// timer task rather than in the timer interrupt, so they can take locks and allocate like any
+// other task code. All the interrupt does is wake the task when the next timer is due.
+//
+// Timers go by timer ticks (milliseconds), so durations are rounded up to whole ticks.
+//
+// The timers waiting to go off are kept in an intrusive tree by deadline, and the tree holds one
+// reference to each. Nothing is allocated or freed with the tree locked: the timer interrupt can
+// preempt a task in the middle of allocating, and the timer task outranks it.
+
+use crate::arch::x86_64::interrupts::without_interrupts;
+use crate::impl_linked;
+use crate::klib::containers::intrusive::rbtree::{RbTree, TreeLink, TreeNode};
+use crate::task::{self, Priority, WaitQueue};
+use crate::{log_warn, TIMER};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+
+type Callback = Box<dyn FnMut() + Send>;
+
+struct Entry {
+    link: TreeLink,
+    // Only changed while the entry is out of the tree
+    deadline: AtomicU64,
+    // In ticks, for timers that go off over and over
+    period: Option<u64>,
+    // Only ever run by the timer task
+    callback: Mutex<Callback>,
+    // Set with the timers locked, so that a timer that is running isn't put back afterwards
+    cancelled: AtomicBool,
+}
+
+impl_linked!(Entry, link: TreeLink);
+
+impl TreeNode for Entry {
+    type Key = u64;
+
+    fn key(&self) -> u64 {
+        self.deadline.load(Ordering::Relaxed)
+    }
+}
+
+static TIMERS: Mutex<RbTree<'static, Entry>> = Mutex::new(RbTree::new());
+
+// Tick the earliest timer is due at, u64::MAX if there are none
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+// The timer task waits here for a timer to be due
+static DUE: WaitQueue = WaitQueue::new();
+
+/// A timer that has been set. Dropping it cancels the timer, unless it was detached.
+#[must_use = "dropping a Timer cancels it"]
+pub struct Timer {
+    entry: Arc<Entry>,
+}
+
+impl Timer {
+    /// Stop the timer. If its closure is running right now, that run still finishes.
+    pub fn cancel(self) {}
+
+    /// Let the timer go on without a handle. One that goes off over and over never stops.
+    pub fn detach(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let removed = without_interrupts(|| {
+            let mut timers = TIMERS.lock();
+            self.entry.cancelled.store(true, Ordering::Relaxed);
+            let removed = timers.remove(&self.entry);
+            update_next_deadline(&timers);
+            removed
+        });
+        // The tree's reference, and then this one, are dropped outside of the lock, as the
+        // callback may own anything
+        if removed {
+            drop(unsafe { Arc::from_raw(Arc::as_ptr(&self.entry)) });
+        }
+    }
+}
+
+fn ticks(duration: Duration) -> u64 {
+    (duration.as_micros().div_ceil(1000) as u64).max(1)
+}
+
+fn add(deadline: u64, period: Option<u64>, callback: Callback) -> Timer {
+    let entry = Arc::new(Entry {
+        link: TreeLink::new(),
+        deadline: AtomicU64::new(deadline),
+        period,
+        callback: Mutex::new(callback),
+        cancelled: AtomicBool::new(false),
+    });
+
+    insert(entry.clone());
+    Timer { entry }
+}
+
+// Hand `entry` to the tree, unless its timer has been cancelled
+fn insert(entry: Arc<Entry>) {
+    let refused = without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        if entry.cancelled.load(Ordering::Relaxed) {
+            return Some(entry);
+        }
+        // Taken back with `Arc::from_raw` by whoever takes it out of the tree
+        let _ = timers.insert(unsafe { &*Arc::into_raw(entry) });
+        update_next_deadline(&timers);
+        None
+    });
+    drop(refused);
+}
+
+// Has to be called with the timers locked, after changing them
+fn update_next_deadline(timers: &RbTree<Entry>) {
+    let next = timers.first().map_or(u64::MAX, |entry| entry.key());
+    NEXT_DEADLINE.store(next, Ordering::SeqCst);
+}
+
+/// Run `f` once, on the timer task, after `delay`.
+pub fn after(delay: Duration, f: impl FnOnce() + Send + 'static) -> Timer {
+    let mut f = Some(f);
+    let deadline = TIMER.load(Ordering::SeqCst) + ticks(delay);
+    add(
+        deadline,
+        None,
+        Box::new(move || {
+            if let Some(f) = f.take() {
+                f();
+            }
+        }),
+    )
+}
+
+/// Run `f` on the timer task every `period`, starting one `period` from now. If the task falls
+/// behind, runs that were missed are skipped rather than made up for.
+pub fn every(period: Duration, f: impl FnMut() + Send + 'static) -> Timer {
+    let period = ticks(period);
+    let deadline = TIMER.load(Ordering::SeqCst) + period;
+    add(deadline, Some(period), Box::new(f))
+}
+
+/// Called from the timer interrupt, to wake the timer task if a timer is due.
+pub fn tick(now: u64) {
+    if now >= NEXT_DEADLINE.load(Ordering::SeqCst) {
+        DUE.wake_one();
+    }
+}
+
+// Run every timer that is due, earliest first
+fn run_due() {
+    loop {
+        let now = TIMER.load(Ordering::SeqCst);
+        let due = without_interrupts(|| {
+            let mut timers = TIMERS.lock();
+            if timers.first()?.key() > now {
+                return None;
+            }
+            let entry = timers.pop_first()?;
+            update_next_deadline(&timers);
+            Some(entry as *const Entry)
+        });
+        let Some(entry) = due else {
+            break;
+        };
+        // The tree's reference, which is now this task's
+        let entry = unsafe { Arc::from_raw(entry) };
+
+        (entry.callback.lock())();
+
+        // Unless it was cancelled, a periodic timer goes back in for its next run and a one-shot
+        // one is done, and dropped outside of the lock like in `Timer::drop`
+        if let Some(period) = entry.period {
+            let deadline = (entry.key() + period).max(now + 1);
+            entry.deadline.store(deadline, Ordering::Relaxed);
+            insert(entry);
+        }
+    }
+}
+
+/// Start the task timers run on. Has to be called after `task::init`; timers set before then go
+/// off once it is.
+pub fn init() {
+    // High priority, so that timeouts still go off while other tasks are busy
+    let timers = task::spawn("timers", Priority::High, || loop {
+        DUE.wait_while(|| TIMER.load(Ordering::SeqCst) < NEXT_DEADLINE.load(Ordering::SeqCst));
+        run_due();
+    });
+
+    if timers.is_err() {
+        log_warn!("Couldn't start the timer task, timers won't go off");
+    }
+}
@@ -0,0 +1,179 @@
+use crate::arch::x86_64::interrupts::apic::LOCAL_APIC;
+use crate::arch::x86_64::nx;
+use crate::arch::{Arch, Cpu};
+use crate::KERNEL_PAGETABLE;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, RwLockWriteGuard};
+use x86_64::instructions::tlb;
+use x86_64::structures::paging::mapper::{FlagUpdateError, MapToError, MapperFlush, UnmapError};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+/// Other processors get this IPI when they have to drop mappings from their TLBs.
+pub const SHOOTDOWN_VECTOR: u8 = 0xFD;
+
+// Past this many pages, flushing everything is cheaper than invalidating pages one by one
+const MAX_SINGLE_FLUSHES: u64 = 32;
+
+// Bit n is set if the processor with local APIC ID n has the kernel page table loaded, and so
+// may have any of its mappings cached. Only the first 64 APIC IDs can be tracked.
+static ACTIVE_CPUS: AtomicU64 = AtomicU64::new(0);
+
+// One shootdown at a time: the range to flush and the processors that haven't done it yet
+static SHOOTDOWN_LOCK: Mutex<()> = Mutex::new(());
+static SHOOTDOWN_START: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_END: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_PENDING: AtomicU64 = AtomicU64::new(0);
+
+fn current_cpu() -> Option<u8> {
+    LOCAL_APIC.get().map(|apic| apic.id()).filter(|&id| id < 64)
+}
+
+/// Mark the calling processor as using the kernel page table. Every processor has to do this
+/// once it has switched to it and can take the shootdown IPI.
+pub fn register_cpu() {
+    if let Some(id) = current_cpu() {
+        ACTIVE_CPUS.fetch_or(1 << id, Ordering::SeqCst);
+    }
+}
+
+fn flush_local(start: u64, end: u64) {
+    if (end - start) / Size4KiB::SIZE > MAX_SINGLE_FLUSHES {
+        tlb::flush_all();
+        return;
+    }
+
+    let mut addr = start;
+    while addr < end {
+        tlb::flush(VirtAddr::new(addr));
+        addr += Size4KiB::SIZE;
+    }
+}
+
+/// Invalidate `[start, end)` in the TLBs of every processor that may have it cached, and wait
+/// until they all have.
+pub fn shootdown(start: VirtAddr, end: VirtAddr) {
+    let (start, end) = (start.as_u64(), end.as_u64());
+    flush_local(start, end);
+
+    let others = ACTIVE_CPUS.load(Ordering::SeqCst) & !current_cpu().map_or(0, |id| 1 << id);
+    let Some(apic) = LOCAL_APIC.get().filter(|_| others != 0) else {
+        return;
+    };
+
+    let _lock = SHOOTDOWN_LOCK.lock();
+    SHOOTDOWN_START.store(start, Ordering::SeqCst);
+    SHOOTDOWN_END.store(end, Ordering::SeqCst);
+    SHOOTDOWN_PENDING.store(others, Ordering::SeqCst);
+
+    for id in (0..64).filter(|id| others & (1 << id) != 0) {
+        apic.send_ipi(id, SHOOTDOWN_VECTOR);
+    }
+
+    while SHOOTDOWN_PENDING.load(Ordering::SeqCst) != 0 {
+        Arch::pause();
+    }
+}
+
+/// Called from the shootdown IPI handler.
+pub fn handle_shootdown() {
+    let start = SHOOTDOWN_START.load(Ordering::SeqCst);
+    let end = SHOOTDOWN_END.load(Ordering::SeqCst);
+    flush_local(start, end);
+
+    if let Some(id) = current_cpu() {
+        SHOOTDOWN_PENDING.fetch_and(!(1 << id), Ordering::SeqCst);
+    }
+
+    if let Some(apic) = LOCAL_APIC.get() {
+        apic.end_of_interrupt();
+    }
+}
+
+/// Write access to the kernel page table. Every mapping that is removed or changed through the
+/// guard is flushed from all TLBs before the guard (and so the page table lock) is dropped, so
+/// stale translations can't outlive it.
+///
+/// Other processors have to take the shootdown IPI while the guard is held, so they must not wait
+/// for the page table lock with interrupts disabled.
+pub struct MappingGuard {
+    page_table: RwLockWriteGuard<'static, OffsetPageTable<'static>>,
+    // The range of addresses to shoot down, empty if start >= end
+    flush_start: u64,
+    flush_end: u64,
+}
+
+impl MappingGuard {
+    /// Returns `None` if the kernel page table hasn't been set up yet.
+    pub fn lock() -> Option<Self> {
+        Some(Self {
+            page_table: KERNEL_PAGETABLE.get()?.write(),
+            flush_start: u64::MAX,
+            flush_end: 0,
+        })
+    }
+
+    fn defer_flush<S: PageSize>(&mut self, page: Page<S>, flush: MapperFlush<S>) {
+        flush.ignore();
+        self.flush_start = self.flush_start.min(page.start_address().as_u64());
+        self.flush_end = self.flush_end.max(page.start_address().as_u64() + S::SIZE);
+    }
+
+    /// Map a page that isn't mapped yet. Nothing can have it cached, so there is nothing to
+    /// shoot down beyond the local flush.
+    /// ### Safety
+    /// Same as `Mapper::map_to`.
+    pub unsafe fn map(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        nx::check(flags);
+        self.page_table
+            .map_to(page, frame, flags, frame_allocator)
+            .map(|flush| flush.flush())
+    }
+
+    /// Unmap a page, returning the frame it was mapped to. The frame may only be reused once the
+    /// guard is dropped.
+    pub fn unmap<S: PageSize>(&mut self, page: Page<S>) -> Result<PhysFrame<S>, UnmapError>
+    where
+        OffsetPageTable<'static>: Mapper<S>,
+    {
+        let (frame, flush) = self.page_table.unmap(page)?;
+        self.defer_flush(page, flush);
+        Ok(frame)
+    }
+
+    /// Change the flags of a mapped page, e.g. to make it read only.
+    /// ### Safety
+    /// Same as `Mapper::update_flags`.
+    pub unsafe fn update_flags<S: PageSize>(
+        &mut self,
+        page: Page<S>,
+        flags: PageTableFlags,
+    ) -> Result<(), FlagUpdateError>
+    where
+        OffsetPageTable<'static>: Mapper<S>,
+    {
+        nx::check(flags);
+        let flush = self.page_table.update_flags(page, flags)?;
+        self.defer_flush(page, flush);
+        Ok(())
+    }
+}
+
+impl Drop for MappingGuard {
+    fn drop(&mut self) {
+        if self.flush_start < self.flush_end {
+            shootdown(
+                VirtAddr::new(self.flush_start),
+                VirtAddr::new(self.flush_end),
+            );
+        }
+    }
+}
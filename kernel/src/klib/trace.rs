@@ -0,0 +1,174 @@
+// Event tracing. Trace points (see `trace!`) record short timestamped events into a ring shared by
+// the whole kernel, for following what a driver did around some point in time, e.g. which
+// commands the AHCI driver issued before a hang. Each subsystem's trace points can be turned on
+// and off at runtime, and cost a single atomic load while they are off.
+//
+// Recording an event never takes a lock, so trace points can go in interrupt handlers and with
+// locks held. Every slot of the ring has a sequence number that is odd while the slot is being
+// written, which lets readers skip slots that are being overwritten under them.
+
+use super::containers::static_string::StaticString;
+use crate::TIMER;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// How many events are kept before the oldest start being overwritten.
+pub const NUM_EVENTS: usize = 512;
+
+// Longer messages are cut off
+const MESSAGE_SIZE: usize = 80;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subsystem {
+    Ahci,
+    Nvme,
+    Block,
+    Sched,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 4] = [
+        Subsystem::Ahci,
+        Subsystem::Nvme,
+        Subsystem::Block,
+        Subsystem::Sched,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Subsystem::Ahci => "ahci",
+            Subsystem::Nvme => "nvme",
+            Subsystem::Block => "block",
+            Subsystem::Sched => "sched",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|subsys| subsys.name() == name)
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+// Bit n is set if subsystem n is traced
+static ENABLED: AtomicU32 = AtomicU32::new(0);
+
+pub fn enable(subsys: Subsystem) {
+    ENABLED.fetch_or(subsys.bit(), Ordering::Relaxed);
+}
+
+pub fn disable(subsys: Subsystem) {
+    ENABLED.fetch_and(!subsys.bit(), Ordering::Relaxed);
+}
+
+#[inline]
+pub fn is_enabled(subsys: Subsystem) -> bool {
+    ENABLED.load(Ordering::Relaxed) & subsys.bit() != 0
+}
+
+#[derive(Clone, Copy)]
+pub struct Event {
+    pub ticks: u64,
+    pub subsys: Subsystem,
+    pub message: StaticString<MESSAGE_SIZE>,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{:>8}] {:<5} {}",
+            self.ticks,
+            self.subsys.name(),
+            self.message
+        )
+    }
+}
+
+struct Slot {
+    // 2n + 1 while event n is being written into the slot, 2n + 2 once it has been
+    seq: AtomicU64,
+    event: UnsafeCell<Event>,
+}
+
+// Only written by whoever claimed the slot through NEXT, see `_trace`
+unsafe impl Sync for Slot {}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Slot = Slot {
+    seq: AtomicU64::new(0),
+    event: UnsafeCell::new(Event {
+        ticks: 0,
+        subsys: Subsystem::Ahci,
+        message: StaticString::new(),
+    }),
+};
+
+static RING: [Slot; NUM_EVENTS] = [EMPTY_SLOT; NUM_EVENTS];
+
+// The number of the next event, which goes in slot NEXT % NUM_EVENTS
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+#[doc(hidden)]
+pub fn _trace(subsys: Subsystem, args: fmt::Arguments) {
+    let mut event = Event {
+        ticks: TIMER.load(Ordering::Relaxed),
+        subsys,
+        message: StaticString::new(),
+    };
+    let _ = event.message.write_fmt(args);
+
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    let slot = &RING[n as usize % NUM_EVENTS];
+
+    slot.seq.store(2 * n + 1, Ordering::Relaxed);
+    core::sync::atomic::fence(Ordering::Release);
+    unsafe { slot.event.get().write_volatile(event) };
+    slot.seq.store(2 * n + 2, Ordering::Release);
+}
+
+/// A copy of the last `count` events, oldest first. Events that were being overwritten while
+/// they were copied are left out.
+pub fn recent(count: usize) -> Vec<Event> {
+    let next = NEXT.load(Ordering::Acquire);
+    let count = (count as u64).min(next).min(NUM_EVENTS as u64);
+
+    (next - count..next)
+        .filter_map(|n| {
+            let slot = &RING[n as usize % NUM_EVENTS];
+            if slot.seq.load(Ordering::Acquire) != 2 * n + 2 {
+                return None;
+            }
+
+            let event = unsafe { slot.event.get().read_volatile() };
+            core::sync::atomic::fence(Ordering::Acquire);
+            (slot.seq.load(Ordering::Relaxed) == 2 * n + 2).then_some(event)
+        })
+        .collect()
+}
+
+/// Forget every event recorded so far.
+pub fn clear() {
+    for slot in RING.iter() {
+        slot.seq.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Record an event for a subsystem, if it is being traced:
+/// `trace!(Ahci, "issue slot {} sector {}", slot, sector)`
+#[macro_export]
+macro_rules! trace {
+    ($subsys:ident, $($arg:tt)*) => {
+        if $crate::klib::trace::is_enabled($crate::klib::trace::Subsystem::$subsys) {
+            $crate::klib::trace::_trace(
+                $crate::klib::trace::Subsystem::$subsys,
+                format_args!($($arg)*),
+            );
+        }
+    };
+}
@@ -0,0 +1,93 @@
+// Copying between the kernel and user memory, for system calls to use instead of following user
+// pointers themselves. A user range is only touched if all of it is in the lower half and every
+// page of it is mapped for user mode (and writable, to copy into it) in the current address space,
+// which is kept from changing until the copy is done. With SMAP on, these are the only places the
+// kernel can touch user memory at all.
+
+use crate::arch::x86_64::smap;
+use crate::KERNEL_PAGETABLE;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::VirtAddr;
+
+/// Where the lower half of the address space, which user memory is in, ends.
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Fill `dst` from user memory at `src`. Fails without copying anything if any of it isn't user
+/// memory.
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), ()> {
+    let len = dst.len();
+    with_user_range(src, len, false, || unsafe {
+        core::ptr::copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(), len);
+    })
+}
+
+/// Copy `src` to user memory at `dst`. Fails without copying anything if any of it isn't
+/// writable user memory.
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), ()> {
+    with_user_range(dst, src.len(), true, || unsafe {
+        core::ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut u8, src.len());
+    })
+}
+
+// Check `len` bytes from `addr` are user memory, and run `copy` with access to it allowed
+fn with_user_range(addr: u64, len: usize, write: bool, copy: impl FnOnce()) -> Result<(), ()> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = addr
+        .checked_add(len as u64)
+        .filter(|&end| end <= USER_END)
+        .ok_or(())?;
+
+    let mut needed = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        needed |= PageTableFlags::WRITABLE;
+    }
+
+    // Held through the copy, so that nothing can be unmapped in the meantime
+    let page_table = KERNEL_PAGETABLE.get().ok_or(())?.read();
+    let phys_offset = page_table.phys_offset();
+    let mut page = addr & !(PAGE_SIZE - 1);
+    while page < end {
+        match walk_flags(phys_offset, VirtAddr::new(page)) {
+            Some(flags) if flags.contains(needed) => {}
+            _ => return Err(()),
+        }
+        page += PAGE_SIZE;
+    }
+
+    smap::user_access(copy);
+    Ok(())
+}
+
+// The flags of every entry on the way down to `addr` ANDed together, or None if it isn't mapped.
+// The CPU only lets user mode in, or lets it write, if every level allows it, not just the last.
+fn walk_flags(phys_offset: VirtAddr, addr: VirtAddr) -> Option<PageTableFlags> {
+    let indices = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let mut table = phys_offset + Cr3::read().0.start_address().as_u64();
+    let mut flags = PageTableFlags::all();
+
+    for (level, index) in indices.into_iter().enumerate() {
+        let entry = &unsafe { &*table.as_ptr::<PageTable>() }[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        flags &= entry.flags();
+
+        // A 1 GiB or 2 MiB page ends the walk early
+        let huge = level > 0 && entry.flags().contains(PageTableFlags::HUGE_PAGE);
+        if huge || level == indices.len() - 1 {
+            return Some(flags);
+        }
+        table = phys_offset + entry.addr().as_u64();
+    }
+    None
+}
@@ -0,0 +1,158 @@
+use super::{RequestType, SetupPacket};
+use crate::klib::ps2::keyboard::{KeyCode, Keyboard};
+
+// Interface class/subclass/protocol of a keyboard that speaks the boot protocol
+pub const CLASS_HID: u8 = 0x03;
+pub const SUBCLASS_BOOT: u8 = 0x01;
+pub const PROTOCOL_KEYBOARD: u8 = 0x01;
+
+// Boot protocol reports are always 8 bytes: modifiers, a reserved byte, then up to 6 keys
+pub const BOOT_REPORT_SIZE: usize = 8;
+
+#[repr(u8)]
+pub enum HidRequest {
+    SetProtocol = 0x0B,
+}
+
+pub fn set_protocol(interface: u8, boot: bool) -> SetupPacket {
+    SetupPacket {
+        request_type: RequestType::Class as u8 | RequestType::Interface as u8,
+        request: HidRequest::SetProtocol as u8,
+        value: u16::from(!boot),
+        index: interface as u16,
+        length: 0,
+    }
+}
+
+// Usage IDs of keys in a report that mean something went wrong rather than a key press
+const ERROR_ROLL_OVER: u8 = 0x01;
+
+// Set 1 scancodes of the modifier bits, in order, and whether they're extended
+const MODIFIERS: [(u8, bool); 8] = [
+    (0x1D, false), // left ctrl
+    (0x2A, false), // left shift
+    (0x38, false), // left alt
+    (0x5B, true),  // left gui
+    (0x1D, true),  // right ctrl
+    (0x36, false), // right shift
+    (0x38, true),  // right alt
+    (0x5C, true),  // right gui
+];
+
+/// Decodes boot protocol keyboard reports into the same key codes that PS/2 keyboards produce.
+pub struct BootKeyboard {
+    last_report: [u8; BOOT_REPORT_SIZE],
+}
+
+impl BootKeyboard {
+    pub fn new() -> Self {
+        Self {
+            last_report: [0; BOOT_REPORT_SIZE],
+        }
+    }
+
+    /// Compare a report with the previous one and queue a key event for everything that was
+    /// pressed or released in between.
+    pub fn handle_report(&mut self, report: &[u8; BOOT_REPORT_SIZE], keyboard: &mut Keyboard) {
+        // The whole report is garbage if too many keys are down at once
+        if report[2..].contains(&ERROR_ROLL_OVER) {
+            return;
+        }
+
+        let last = self.last_report;
+        let changed_modifiers = last[0] ^ report[0];
+
+        for (bit, &(scancode, extended)) in MODIFIERS.iter().enumerate() {
+            if changed_modifiers & (1 << bit) == 0 {
+                continue;
+            }
+            let released = last[0] & (1 << bit) != 0;
+            push(keyboard, scancode, extended, released);
+        }
+
+        for &usage in last[2..].iter().filter(|&&usage| usage != 0) {
+            if !report[2..].contains(&usage) {
+                if let Some((scancode, extended)) = usage_to_scancode(usage) {
+                    push(keyboard, scancode, extended, true);
+                }
+            }
+        }
+
+        for &usage in report[2..].iter().filter(|&&usage| usage != 0) {
+            if !last[2..].contains(&usage) {
+                if let Some((scancode, extended)) = usage_to_scancode(usage) {
+                    push(keyboard, scancode, extended, false);
+                }
+            }
+        }
+
+        self.last_report = *report;
+    }
+}
+
+fn push(keyboard: &mut Keyboard, scancode: u8, extended: bool, released: bool) {
+    if let Some(key) = KeyCode::from_scancode(scancode, extended, released) {
+        keyboard.push_keycode(key);
+    }
+}
+
+/// Translate a usage ID from the keyboard/keypad page into a set 1 scancode, plus whether it is
+/// one of the keys that are prefixed with 0xE0.
+fn usage_to_scancode(usage: u8) -> Option<(u8, bool)> {
+    // a through z
+    const LETTERS: [u8; 26] = [
+        0x1E, 0x30, 0x2E, 0x20, 0x12, 0x21, 0x22, 0x23, 0x17, 0x24, 0x25, 0x26, 0x32, 0x31, 0x18,
+        0x19, 0x10, 0x13, 0x1F, 0x14, 0x16, 0x2F, 0x11, 0x2D, 0x15, 0x2C,
+    ];
+    // keypad 1 through 9, then 0 and the period
+    const KEYPAD: [u8; 11] = [
+        0x4F, 0x50, 0x51, 0x4B, 0x4C, 0x4D, 0x47, 0x48, 0x49, 0x52, 0x53,
+    ];
+
+    let scancode = match usage {
+        0x04..=0x1D => LETTERS[(usage - 0x04) as usize],
+        0x1E..=0x27 => usage - 0x1E + 0x02, // 1 through 9, then 0
+        0x28 => 0x1C,                       // enter
+        0x29 => 0x01,                       // escape
+        0x2A => 0x0E,                       // backspace
+        0x2B => 0x0F,                       // tab
+        0x2C => 0x39,                       // space
+        0x2D => 0x0C,                       // -
+        0x2E => 0x0D,                       // =
+        0x2F => 0x1A,                       // [
+        0x30 => 0x1B,                       // ]
+        0x31 | 0x32 => 0x2B,                // \ and the non-US #
+        0x33 => 0x27,                       // ;
+        0x34 => 0x28,                       // '
+        0x35 => 0x29,                       // `
+        0x36 => 0x33,                       // ,
+        0x37 => 0x34,                       // .
+        0x38 => 0x35,                       // /
+        0x39 => 0x3A,                       // caps lock
+        0x3A..=0x43 => usage - 0x3A + 0x3B, // F1 through F10
+        0x44 => 0x57,                       // F11
+        0x45 => 0x58,                       // F12
+        0x47 => 0x46,                       // scroll lock
+        0x49 => return Some((0x52, true)),  // insert
+        0x4A => return Some((0x47, true)),  // home
+        0x4B => return Some((0x49, true)),  // page up
+        0x4C => return Some((0x53, true)),  // delete
+        0x4D => return Some((0x4F, true)),  // end
+        0x4E => return Some((0x51, true)),  // page down
+        0x4F => return Some((0x4D, true)),  // right
+        0x50 => return Some((0x4B, true)),  // left
+        0x51 => return Some((0x50, true)),  // down
+        0x52 => return Some((0x48, true)),  // up
+        0x53 => 0x45,                       // num lock
+        0x54 => return Some((0x35, true)),  // keypad /
+        0x55 => 0x37,                       // keypad *
+        0x56 => 0x4A,                       // keypad -
+        0x57 => 0x4E,                       // keypad +
+        0x58 => return Some((0x1C, true)),  // keypad enter
+        0x59..=0x63 => KEYPAD[(usage - 0x59) as usize],
+        0x65 => return Some((0x5D, true)), // application
+        _ => return None,
+    };
+
+    Some((scancode, false))
+}
@@ -0,0 +1,128 @@
+pub mod hid;
+// Bits of USB 2.0 chapter 9 that don't depend on the host controller
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl SetupPacket {
+    pub fn as_u64(&self) -> u64 {
+        self.request_type as u64
+            | (self.request as u64) << 8
+            | (self.value as u64) << 16
+            | (self.index as u64) << 32
+            | (self.length as u64) << 48
+    }
+
+    pub fn is_in(&self) -> bool {
+        self.request_type & RequestType::DeviceToHost as u8 != 0
+    }
+}
+
+// Bits of bmRequestType
+#[repr(u8)]
+pub enum RequestType {
+    DeviceToHost = 0x80,
+    Class = 0x20,
+    Interface = 0x01,
+}
+
+#[repr(u8)]
+pub enum Request {
+    GetDescriptor = 0x06,
+    SetConfiguration = 0x09,
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorType {
+    Device = 0x01,
+    Configuration = 0x02,
+    Interface = 0x04,
+    Endpoint = 0x05,
+}
+
+pub const CONFIGURATION_DESCRIPTOR_SIZE: usize = 9;
+
+pub fn get_descriptor(descriptor_type: DescriptorType, index: u8, length: u16) -> SetupPacket {
+    SetupPacket {
+        request_type: RequestType::DeviceToHost as u8,
+        request: Request::GetDescriptor as u8,
+        value: ((descriptor_type as u16) << 8) | index as u16,
+        index: 0,
+        length,
+    }
+}
+
+pub fn set_configuration(value: u8) -> SetupPacket {
+    SetupPacket {
+        request_type: 0,
+        request: Request::SetConfiguration as u8,
+        value: value as u16,
+        index: 0,
+        length: 0,
+    }
+}
+
+/// An interrupt IN endpoint of some interface.
+#[derive(Clone, Copy)]
+pub struct InterruptEndpoint {
+    pub number: u8,
+    pub max_packet_size: u16,
+    pub interval: u8, // bInterval, whose unit depends on the device speed
+}
+
+/// Walk a full configuration descriptor (with its interface and endpoint descriptors) looking
+/// for an interface of the given class/subclass/protocol with an interrupt IN endpoint. Returns
+/// the interface number and the endpoint.
+pub fn find_interface(
+    config: &[u8],
+    class: u8,
+    subclass: u8,
+    protocol: u8,
+) -> Option<(u8, InterruptEndpoint)> {
+    let mut interface = None;
+    let mut pos = 0;
+
+    while pos + 2 <= config.len() {
+        let length = config[pos] as usize;
+        if length < 2 || pos + length > config.len() {
+            break;
+        }
+        let descriptor = &config[pos..pos + length];
+
+        match descriptor[1] {
+            t if t == DescriptorType::Interface as u8 && length >= 9 => {
+                interface = (descriptor[5] == class
+                    && descriptor[6] == subclass
+                    && descriptor[7] == protocol)
+                    .then_some(descriptor[2]);
+            }
+            t if t == DescriptorType::Endpoint as u8 && length >= 7 => {
+                // An IN endpoint (bit 7 of the address) of the interrupt type
+                if let Some(number) = interface {
+                    if descriptor[2] & 0x80 != 0 && descriptor[3] & 0x3 == 0x3 {
+                        let endpoint = InterruptEndpoint {
+                            number: descriptor[2] & 0xF,
+                            max_packet_size: u16::from_le_bytes([descriptor[4], descriptor[5]])
+                                & 0x7FF,
+                            interval: descriptor[6],
+                        };
+                        return Some((number, endpoint));
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        pos += length;
+    }
+
+    None
+}
@@ -1,12 +1,18 @@
-use crate::println;
+use crate::arch::x86_64::{nx, reserved};
+use crate::arch::{Arch, Paging};
+use crate::BootInfoFrameAllocator;
 use crate::KERNEL_PAGETABLE;
 use core::mem::size_of;
 use core::ops::BitAnd;
 use core::ops::DerefMut;
 use core::slice::from_raw_parts;
+use x86_64::structures::paging::mapper::MapToError;
 use x86_64::structures::paging::Mapper;
 use x86_64::structures::paging::Page;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::structures::paging::PhysFrame;
 use x86_64::structures::paging::Size4KiB;
+use x86_64::PhysAddr;
 use x86_64::VirtAddr;
 
 pub fn as_u8_slice<T: Sized>(obj: &T) -> &[u8] {
@@ -15,15 +21,12 @@ pub fn as_u8_slice<T: Sized>(obj: &T) -> &[u8] {
 
 #[inline]
 pub fn kernel_to_physical_address(addr: u64) -> u64 {
-    let offset = addr & 0xFFF;
-    // println!("offset: {offset}");
-    let pt_lock = KERNEL_PAGETABLE.get().unwrap().read();
-    (*pt_lock)
-        .translate_page(Page::<Size4KiB>::containing_address(VirtAddr::new(addr)))
-        .unwrap()
-        .start_address()
-        .as_u64()
-        + offset
+    try_kernel_to_physical_address(addr).unwrap()
+}
+
+/// Like `kernel_to_physical_address`, but `None` if `addr` isn't mapped.
+pub fn try_kernel_to_physical_address(addr: u64) -> Option<u64> {
+    Arch::translate(addr)
 }
 
 #[inline]
@@ -31,27 +34,42 @@ pub fn physical_to_kernel_address(addr: u64) -> u64 {
     addr
 }
 
-#[repr(transparent)]
-#[derive(Clone, Copy)]
-pub struct Volatile<T: Clone + Copy> {
-    data: T,
-}
-
-impl<T: Clone + Copy> Volatile<T> {
-    #[inline]
-    pub fn new(data: T) -> Self {
-        Self { data }
+/// Map `size` bytes of device memory starting at `phys_addr` to the same virtual address (see
+/// `physical_to_kernel_address`), with caching disabled, and reserve it for `owner`. Pages that
+/// are already mapped are left alone, so that e.g. two register blocks sharing a page can both be
+/// mapped. Fails without mapping anything if the range is RAM or reserved for something other
+/// than device registers.
+/// ### Safety
+/// `phys_addr` should point to device registers.
+pub unsafe fn map_mmio(
+    frame_allocator: &mut BootInfoFrameAllocator,
+    phys_addr: u64,
+    size: u64,
+    owner: &'static str,
+) -> Result<(), ()> {
+    if frame_allocator.overlaps_usable(phys_addr, size) {
+        return Err(());
     }
+    reserved::reserve(phys_addr, size, reserved::Kind::Mmio, owner)?;
 
-    #[inline]
-    pub fn read(&self) -> T {
-        let ptr = &self.data as *const T;
-        unsafe { core::ptr::read_volatile(ptr) }
-    }
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::NO_EXECUTE;
+    nx::check(flags);
+    let first: Page<Size4KiB> = Page::containing_address(VirtAddr::new(phys_addr));
+    let last: Page<Size4KiB> = Page::containing_address(VirtAddr::new(phys_addr + size - 1));
+
+    let mut page_table = KERNEL_PAGETABLE.get().ok_or(())?.write();
 
-    #[inline]
-    pub fn write(&mut self, data: T) {
-        let ptr = &mut self.data as *mut T;
-        unsafe { core::ptr::write_volatile(ptr, data) }
+    for page in Page::range_inclusive(first, last) {
+        let frame = PhysFrame::containing_address(PhysAddr::new(page.start_address().as_u64()));
+        match page_table.map_to(page, frame, flags, frame_allocator) {
+            Ok(flush) => flush.flush(),
+            Err(MapToError::PageAlreadyMapped(_)) => {}
+            Err(_) => return Err(()),
+        }
     }
+
+    Ok(())
 }
@@ -0,0 +1,81 @@
+// UUIDs (RFC 4122), as filesystems and partition tables name themselves and what's in them with.
+// They're kept as the 16 bytes in the order they're written out in, which is how ext2 stores
+// them; GPT stores the first three fields little endian instead, see `from_guid_bytes`.
+
+use core::fmt;
+
+/// A UUID, compared and ordered by its bytes.
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Uuid(pub [u8; 16]);
+
+impl Uuid {
+    /// All zeros, e.g. an unused GPT entry's type.
+    pub const NIL: Uuid = Uuid([0; 16]);
+
+    // GPT partition types
+    /// C12A7328-F81F-11D2-BA4B-00A0C93EC93B
+    #[allow(dead_code)]
+    pub const EFI_SYSTEM_PARTITION: Uuid = Uuid([
+        0xC1, 0x2A, 0x73, 0x28, 0xF8, 0x1F, 0x11, 0xD2, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9,
+        0x3B,
+    ]);
+    /// 0FC63DAF-8483-4772-8E79-3D69D8477DE4
+    #[allow(dead_code)]
+    pub const LINUX_FILESYSTEM: Uuid = Uuid([
+        0x0F, 0xC6, 0x3D, 0xAF, 0x84, 0x83, 0x47, 0x72, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D,
+        0xE4,
+    ]);
+
+    /// The UUID in `text`, e.g. "0fc63daf-8483-4772-8e79-3d69d8477de4", in either case.
+    pub fn parse(text: &str) -> Result<Self, ()> {
+        let text = text.as_bytes();
+        if text.len() != 36 {
+            return Err(());
+        }
+
+        let mut bytes = [0; 16];
+        let mut digits = text.iter().enumerate().filter_map(|(i, &ch)| match i {
+            8 | 13 | 18 | 23 => (ch != b'-').then_some(None),
+            _ => Some((ch as char).to_digit(16)),
+        });
+        for byte in &mut bytes {
+            let high = digits.next().flatten().ok_or(())?;
+            let low = digits.next().flatten().ok_or(())?;
+            *byte = ((high << 4) | low) as u8;
+        }
+        Ok(Self(bytes))
+    }
+
+    /// The UUID in a GUID as GPT and UEFI store them, with the first three fields little endian.
+    #[allow(dead_code)]
+    pub const fn from_guid_bytes(bytes: [u8; 16]) -> Self {
+        let [a0, a1, a2, a3, b0, b1, c0, c1, rest @ ..] = bytes;
+        let [d0, d1, d2, d3, d4, d5, d6, d7] = rest;
+        Self([
+            a3, a2, a1, a0, b1, b0, c1, c0, d0, d1, d2, d3, d4, d5, d6, d7,
+        ])
+    }
+
+    pub fn is_nil(&self) -> bool {
+        *self == Self::NIL
+    }
+}
+
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                f.write_str("-")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
@@ -0,0 +1,50 @@
+// What this kernel image is: its version, the commit and toolchain it was built from, when, and
+// with which features. kernel/build.rs works these out. The same line is kept in a section of its
+// own, .buildinfo, so that an image can be told apart from the others without booting it, with
+// `objcopy -O binary --only-section=.buildinfo <kernel> /dev/stdout`.
+
+use crate::log_info;
+
+pub const NAME: &str = "panopticon";
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// The short hash of the commit, ending in "-dirty" if there were uncommitted changes.
+pub const GIT_HASH: &str = env!("KERNEL_GIT_HASH");
+/// When build.rs last ran, in UTC.
+pub const BUILD_TIME: &str = env!("KERNEL_BUILD_TIME");
+
+const BUILD_INFO_TEXT: &str = concat!(
+    "panopticon ",
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("KERNEL_GIT_HASH"),
+    ") built ",
+    env!("KERNEL_BUILD_TIME"),
+    " with ",
+    env!("KERNEL_RUSTC_VERSION"),
+    ", features: ",
+    env!("KERNEL_FEATURES"),
+    "\n"
+);
+
+#[used]
+#[link_section = ".buildinfo"]
+static BUILD_INFO: [u8; BUILD_INFO_TEXT.len()] = {
+    let text = BUILD_INFO_TEXT.as_bytes();
+    let mut bytes = [0; BUILD_INFO_TEXT.len()];
+    let mut i = 0;
+    while i < bytes.len() {
+        bytes[i] = text[i];
+        i += 1;
+    }
+    bytes
+};
+
+/// Everything about the build on one line, including the rustc version and the features.
+pub fn build_info() -> &'static str {
+    BUILD_INFO_TEXT.trim_end()
+}
+
+/// Log the line the kernel introduces itself with.
+pub fn log() {
+    log_info!("{} {} ({}), built {}", NAME, VERSION, GIT_HASH, BUILD_TIME);
+}
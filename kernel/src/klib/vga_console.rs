@@ -1,6 +1,7 @@
 use core::fmt;
 use spin::Mutex;
-use crate::klib::x86_64;
+use crate::arch::ports::Port;
+use crate::arch::{Arch, Cpu};
 use volatile::Volatile;
 use lazy_static::lazy_static;
 
@@ -12,8 +13,8 @@ const CONSOLE_ADDRESS: usize = 0xb8000;
 const BUFFER_WIDTH: usize = 80;
 const BUFFER_HEIGHT: usize = 25;
 
-const SET_REGISTER: u16 = 0x3D4;
-const CURSOR_CONTROL: u16 = 0x3D5;
+const SET_REGISTER: Port<u8> = Port::new(0x3D4);
+const CURSOR_CONTROL: Port<u8> = Port::new(0x3D5);
 const CURSOR_START: u8 = 0x0A;
 const CURSOR_END: u8 = 0xB;
 const CURSOR_LOCATION_HIGH: u8 = 0xE;
@@ -32,19 +33,19 @@ pub struct ConsoleWriter {
 impl ConsoleWriter {
     pub fn new() -> Self {
         unsafe {
-            x86_64::port_write_u8(SET_REGISTER, CURSOR_START);
-            x86_64::port_write_u8(CURSOR_CONTROL, 0x20);
-            x86_64::port_write_u8(SET_REGISTER, CURSOR_START);
+            SET_REGISTER.write(CURSOR_START);
+            CURSOR_CONTROL.write(0x20);
+            SET_REGISTER.write(CURSOR_START);
             // Upper two bits are reserved
-            let mut existing = x86_64::port_read_u8(CURSOR_CONTROL) & 0xC;
+            let mut existing = CURSOR_CONTROL.read() & 0xC;
             // Enable cursor (bit 5 set to 0) and set start position to 0
-            x86_64::port_write_u8(CURSOR_CONTROL, existing);
+            CURSOR_CONTROL.write(existing);
 
-            x86_64::port_write_u8(SET_REGISTER, CURSOR_END);
+            SET_REGISTER.write(CURSOR_END);
             // Upper three bits are reserved for cursor end
-            existing = x86_64::port_read_u8(CURSOR_CONTROL) & 0xE;
+            existing = CURSOR_CONTROL.read() & 0xE;
             // Set end position to 15 (take up entire block)
-            x86_64::port_write_u8(CURSOR_CONTROL, existing);
+            CURSOR_CONTROL.write(existing);
         }
 
         let buffer = unsafe { &mut *(CONSOLE_ADDRESS as *mut Buffer) };
@@ -75,10 +76,10 @@ impl ConsoleWriter {
         let pos_hi = (pos >> 8) as u8;
 
         unsafe {
-            x86_64::port_write_u8(SET_REGISTER, CURSOR_LOCATION_LOW);
-            x86_64::port_write_u8(CURSOR_CONTROL, pos_lo);
-            x86_64::port_write_u8(SET_REGISTER, CURSOR_LOCATION_HIGH);
-            x86_64::port_write_u8(CURSOR_CONTROL, pos_hi);
+            SET_REGISTER.write(CURSOR_LOCATION_LOW);
+            CURSOR_CONTROL.write(pos_lo);
+            SET_REGISTER.write(CURSOR_LOCATION_HIGH);
+            CURSOR_CONTROL.write(pos_hi);
         }
     }
 
@@ -235,5 +236,5 @@ macro_rules! println {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    x86_64::without_interrupts(|| CONSOLE_WRITER.lock().write_fmt(args).unwrap())
+    Arch::without_interrupts(|| CONSOLE_WRITER.lock().write_fmt(args).unwrap())
 }
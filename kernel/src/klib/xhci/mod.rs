@@ -0,0 +1,262 @@
+use super::mmio::{ReadOnly, ReadWrite};
+
+pub mod xhcistate;
+// Written against the eXtensible Host Controller Interface specification, revision 1.2
+
+// Capability registers, at the start of BAR 0 [all read only]
+#[repr(C)]
+pub struct CapabilityRegisters {
+    pub length: ReadOnly<u8>, // CAPLENGTH: the operational registers start this far into the BAR
+    pub reserved: u8,
+    pub version: ReadOnly<u16>,             // HCIVERSION
+    pub structural_params_1: ReadOnly<u32>, // HCSPARAMS1: slots, interrupters, ports
+    pub structural_params_2: ReadOnly<u32>, // HCSPARAMS2: event ring segments, scratchpads
+    pub structural_params_3: ReadOnly<u32>, // HCSPARAMS3: exit latencies
+    pub capability_params_1: ReadOnly<u32>, // HCCPARAMS1
+    pub doorbell_offset: ReadOnly<u32>,     // DBOFF
+    pub runtime_offset: ReadOnly<u32>,      // RTSOFF
+    pub capability_params_2: ReadOnly<u32>, // HCCPARAMS2
+}
+
+// Operational registers, at BAR 0 + CAPLENGTH. The port registers follow at PORT_REGISTERS_BASE.
+#[repr(C)]
+pub struct OperationalRegisters {
+    pub command: ReadWrite<u32>,  // USBCMD
+    pub status: ReadWrite<u32>,   // USBSTS
+    pub page_size: ReadOnly<u32>, // PAGESIZE: bit n set means 2^(n + 12) byte pages are supported
+    pub reserved: [u32; 2],
+    pub notification_control: ReadWrite<u32>, // DNCTRL
+    pub command_ring_control: ReadWrite<u64>, // CRCR
+    pub reserved_2: [u32; 4],
+    pub device_context_base_addr: ReadWrite<u64>, // DCBAAP
+    pub config: ReadWrite<u32>,                   // CONFIG
+}
+
+pub const PORT_REGISTERS_BASE: u64 = 0x400;
+
+// One set per root hub port, numbered from 1
+#[repr(C)]
+pub struct PortRegisters {
+    pub status_control: ReadWrite<u32>,       // PORTSC
+    pub power_management: ReadWrite<u32>,     // PORTPMSC
+    pub link_info: ReadWrite<u32>,            // PORTLI
+    pub hardware_lpm_control: ReadWrite<u32>, // PORTHLPMC
+}
+
+// Runtime registers start at BAR 0 + RTSOFF; the interrupter register sets start this far in.
+pub const INTERRUPTERS_BASE: u64 = 0x20;
+
+#[repr(C)]
+pub struct InterrupterRegisters {
+    pub management: ReadWrite<u32>,          // IMAN
+    pub moderation: ReadWrite<u32>, // IMOD: minimum time between interrupts, in 250ns units
+    pub event_ring_segments: ReadWrite<u32>, // ERSTSZ
+    pub reserved: u32,
+    pub event_ring_segment_table: ReadWrite<u64>, // ERSTBA
+    pub event_ring_dequeue: ReadWrite<u64>,       // ERDP
+}
+
+#[repr(u32)]
+pub enum StructuralParamsMasks {
+    MaxSlots = 0xFF,
+    MaxPorts = 0xFF << 24,
+}
+
+#[repr(u32)]
+pub enum ScratchpadMasks {
+    High = 0x1F << 21,
+    Low = 0x1F << 27,
+}
+
+#[repr(u32)]
+pub enum CapabilityParamsMasks {
+    ContextSize = 0x4, // CSZ: contexts are 64 bytes instead of 32
+}
+
+#[repr(u32)]
+pub enum CommandMasks {
+    Run = 0x1,
+    Reset = 0x2,
+    InterrupterEnable = 0x4,
+}
+
+#[repr(u32)]
+pub enum StatusMasks {
+    Halted = 0x1,
+    HostSystemError = 0x4,
+    EventInterrupt = 0x8,
+    PortChange = 0x10,
+    NotReady = 0x800,
+}
+
+#[repr(u32)]
+pub enum PortStatusMasks {
+    Connected = 0x1,
+    Enabled = 0x2, // Writing a 1 disables the port, so never write it back
+    Reset = 0x10,
+    Power = 0x200,
+    Speed = 0xF << 10,
+    ConnectChange = 0x20000,
+    ResetChange = 0x200000,
+    // Every bit that is cleared by writing a 1 to it
+    ChangeBits = 0xFE0000,
+    // Bits that keep their value when written back: port power, indicators, wake enables
+    Preserve = 0xE00C200,
+}
+
+#[repr(u32)]
+pub enum InterrupterMasks {
+    Pending = 0x1, // IP, cleared by writing a 1
+    Enable = 0x2,
+}
+
+// Event handler busy, in ERDP. Cleared by writing a 1.
+pub const EVENT_HANDLER_BUSY: u64 = 0x8;
+
+// Values of the port speed field
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PortSpeed {
+    Full = 1,
+    Low = 2,
+    High = 3,
+    Super = 4,
+}
+
+impl TryFrom<u32> for PortSpeed {
+    type Error = ();
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(PortSpeed::Full),
+            2 => Ok(PortSpeed::Low),
+            3 => Ok(PortSpeed::High),
+            4 => Ok(PortSpeed::Super),
+            _ => Err(()),
+        }
+    }
+}
+
+impl PortSpeed {
+    /// What the control endpoint's max packet size has to be assumed to be until the device
+    /// descriptor says otherwise.
+    pub fn default_max_packet_size(self) -> u16 {
+        match self {
+            PortSpeed::Low | PortSpeed::Full => 8,
+            PortSpeed::High => 64,
+            PortSpeed::Super => 512,
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TrbType {
+    Normal = 1,
+    SetupStage = 2,
+    DataStage = 3,
+    StatusStage = 4,
+    Link = 6,
+    EnableSlot = 9,
+    AddressDevice = 11,
+    ConfigureEndpoint = 12,
+    EvaluateContext = 13,
+    TransferEvent = 32,
+    CommandCompletion = 33,
+    PortStatusChange = 34,
+}
+
+// Bits of the TRB control dword
+#[repr(u32)]
+pub enum TrbMasks {
+    Cycle = 0x1,
+    ToggleCycle = 0x2, // Link TRBs only
+    InterruptOnShortPacket = 0x4,
+    InterruptOnCompletion = 0x20,
+    ImmediateData = 0x40,
+    Type = 0x3F << 10,
+    DirectionIn = 0x10000, // Data and status stage TRBs
+}
+
+// Transfer type of a setup stage TRB, in bits 17:16
+pub const SETUP_NO_DATA: u32 = 0;
+pub const SETUP_OUT_DATA: u32 = 2 << 16;
+pub const SETUP_IN_DATA: u32 = 3 << 16;
+
+// Completion codes, in bits 31:24 of an event's status dword
+pub const COMPLETION_SUCCESS: u8 = 1;
+pub const COMPLETION_SHORT_PACKET: u8 = 13;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Trb {
+    pub parameter: u64,
+    pub status: u32,
+    pub control: u32,
+}
+
+impl Trb {
+    pub fn trb_type(&self) -> u8 {
+        ((self.control & TrbMasks::Type as u32) >> 10) as u8
+    }
+
+    pub fn completion_code(&self) -> u8 {
+        (self.status >> 24) as u8
+    }
+
+    // Slot ID of an event
+    pub fn slot(&self) -> u8 {
+        (self.control >> 24) as u8
+    }
+
+    // Endpoint (device context index) of a transfer event
+    pub fn endpoint(&self) -> u8 {
+        ((self.control >> 16) & 0x1F) as u8
+    }
+}
+
+// TRBs per ring, including the link TRB at the end of transfer and command rings. Small enough
+// that each ring fits in a single page.
+pub const RING_SIZE: usize = 256;
+
+#[repr(align(4096))]
+#[repr(C)]
+pub struct TrbRing {
+    pub trbs: [Trb; RING_SIZE], // Shared with the controller, so only touched through pointers
+}
+
+#[repr(align(64))]
+#[repr(C)]
+pub struct EventRingSegment {
+    pub address: u64,
+    pub size: u32,
+    pub reserved: u32,
+}
+
+// Used for the device context base address array (entry 0 points to the scratchpad buffer array,
+// entry n to the device context of slot n) and for the scratchpad buffer array itself.
+#[repr(align(4096))]
+#[repr(C)]
+pub struct AddressArray {
+    pub entries: [u64; 256],
+}
+
+#[repr(align(4096))]
+#[repr(C)]
+pub struct Page {
+    pub bytes: [u8; 4096],
+}
+
+// A device context (32 contexts) or an input context (33 contexts) with either context size.
+// They are accessed a dword at a time, since the layout depends on the context size.
+#[repr(align(4096))]
+#[repr(C)]
+pub struct ContextPage {
+    pub dwords: [ReadWrite<u32>; 1024],
+}
+
+#[repr(u32)]
+pub enum EndpointType {
+    Control = 4,
+    InterruptIn = 7,
+}
@@ -0,0 +1,839 @@
+use super::super::pci;
+use super::super::util;
+use super::{
+    AddressArray, CapabilityParamsMasks, CapabilityRegisters, CommandMasks, ContextPage,
+    EndpointType, EventRingSegment, InterrupterMasks, InterrupterRegisters, OperationalRegisters,
+    Page, PortRegisters, PortSpeed, PortStatusMasks, ScratchpadMasks, StatusMasks,
+    StructuralParamsMasks, Trb, TrbMasks, TrbRing, TrbType, COMPLETION_SHORT_PACKET,
+    COMPLETION_SUCCESS, EVENT_HANDLER_BUSY, INTERRUPTERS_BASE, PORT_REGISTERS_BASE, RING_SIZE,
+    SETUP_IN_DATA, SETUP_NO_DATA, SETUP_OUT_DATA,
+};
+use crate::allocator;
+use crate::arch::{Arch, Cpu};
+use crate::klib::mmio::WriteOnly;
+use crate::klib::once_lock::OnceLock;
+use crate::klib::pci::msix;
+use crate::klib::pci::pcistate::PCI_STATE;
+use crate::klib::pci::power;
+use crate::klib::ps2::keyboard::KEYBOARD;
+use crate::klib::usb;
+use crate::klib::usb::hid;
+use crate::klib::usb::hid::BootKeyboard;
+use crate::klib::usb::{DescriptorType, InterruptEndpoint, SetupPacket};
+use crate::BootInfoFrameAllocator;
+use crate::TIMER;
+use crate::{log_info, log_warn};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{fence, AtomicU8, Ordering};
+use pci::pcistate::PCIState;
+use pci::Register;
+use spin::Mutex;
+
+/// Interrupt vector for events, delivered through MSI-X table entry 0. Whatever vector was
+/// handed to `new`, 0 before that.
+pub static MSIX_VECTOR: AtomicU8 = AtomicU8::new(0);
+
+const PAGE_SIZE: usize = 4096;
+
+// In timer ticks
+const RESET_TIMEOUT: u64 = 100;
+const PORT_RESET_TIMEOUT: u64 = 50;
+const COMMAND_TIMEOUT: u64 = 100;
+
+// Device context index of the default control endpoint
+const CONTROL_ENDPOINT: u8 = 1;
+
+// Slot and endpoint contexts are only ever given 3 retries for errors
+const ERROR_COUNT: u32 = 3 << 1;
+
+// Ask for at most one interrupt per millisecond
+const INTERRUPT_MODERATION: u32 = 4000;
+
+pub static XHCI0: OnceLock<Mutex<XHCIState>> = OnceLock::new();
+
+/// A command or transfer ring: we produce TRBs, the controller consumes them.
+struct Ring {
+    ring: Box<TrbRing>,
+    enqueue: usize,
+    cycle: bool,
+}
+
+impl Ring {
+    fn new() -> Self {
+        let mut ring = Self {
+            ring: Box::new(unsafe { core::mem::zeroed() }),
+            enqueue: 0,
+            cycle: true,
+        };
+
+        // The last TRB points back at the start, and tells the controller to flip its cycle bit
+        // when it goes around.
+        let link = Trb {
+            parameter: ring.address(),
+            status: 0,
+            control: (TrbType::Link as u32) << 10 | TrbMasks::ToggleCycle as u32,
+        };
+        ring.write(RING_SIZE - 1, link);
+
+        ring
+    }
+
+    fn address(&self) -> u64 {
+        util::kernel_to_physical_address(addr_of!(*self.ring) as u64)
+    }
+
+    fn write(&mut self, index: usize, trb: Trb) {
+        let slot = addr_of_mut!(self.ring.trbs[index]);
+
+        // The cycle bit is what hands the TRB over to the controller, so it has to go in last
+        unsafe {
+            addr_of_mut!((*slot).parameter).write_volatile(trb.parameter);
+            addr_of_mut!((*slot).status).write_volatile(trb.status);
+            fence(Ordering::Release);
+            addr_of_mut!((*slot).control).write_volatile(trb.control);
+        }
+    }
+
+    /// Put a TRB on the ring. Returns its physical address, which is what events refer to it by.
+    fn push(&mut self, mut trb: Trb) -> u64 {
+        trb.control = (trb.control & !(TrbMasks::Cycle as u32)) | u32::from(self.cycle);
+
+        let address = self.address() + (self.enqueue * size_of::<Trb>()) as u64;
+        self.write(self.enqueue, trb);
+        self.enqueue += 1;
+
+        if self.enqueue == RING_SIZE - 1 {
+            let mut link = unsafe { addr_of!(self.ring.trbs[RING_SIZE - 1]).read_volatile() };
+            link.control = (link.control & !(TrbMasks::Cycle as u32)) | u32::from(self.cycle);
+            self.write(RING_SIZE - 1, link);
+
+            self.enqueue = 0;
+            self.cycle = !self.cycle;
+        }
+
+        address
+    }
+}
+
+/// The one event ring, a single segment long. The controller produces, we consume.
+struct EventRing {
+    ring: Box<TrbRing>,
+    segment: Box<EventRingSegment>,
+    dequeue: usize,
+    cycle: bool,
+}
+
+impl EventRing {
+    fn new() -> Self {
+        let ring: Box<TrbRing> = Box::new(unsafe { core::mem::zeroed() });
+        let segment = Box::new(EventRingSegment {
+            address: util::kernel_to_physical_address(addr_of!(*ring) as u64),
+            size: RING_SIZE as u32,
+            reserved: 0,
+        });
+
+        Self {
+            ring,
+            segment,
+            dequeue: 0,
+            cycle: true,
+        }
+    }
+
+    fn segment_address(&self) -> u64 {
+        util::kernel_to_physical_address(addr_of!(*self.segment) as u64)
+    }
+
+    fn dequeue_address(&self) -> u64 {
+        util::kernel_to_physical_address(addr_of!(self.ring.trbs[self.dequeue]) as u64)
+    }
+
+    /// Take the next event off of the ring, if there is one.
+    fn poll(&mut self) -> Option<Trb> {
+        let slot = addr_of!(self.ring.trbs[self.dequeue]);
+
+        // Same as when producing, but backwards: the cycle bit first, then the rest
+        let control = unsafe { addr_of!((*slot).control).read_volatile() };
+        if (control & TrbMasks::Cycle as u32 != 0) != self.cycle {
+            return None;
+        }
+        fence(Ordering::Acquire);
+        let trb = unsafe { slot.read_volatile() };
+
+        self.dequeue += 1;
+        if self.dequeue == RING_SIZE {
+            self.dequeue = 0;
+            self.cycle = !self.cycle;
+        }
+
+        Some(trb)
+    }
+}
+
+#[repr(align(64))]
+#[repr(C)]
+struct ReportBuffer {
+    bytes: [u8; hid::BOOT_REPORT_SIZE],
+}
+
+/// The interrupt IN endpoint of a boot protocol keyboard. There is always one report transfer
+/// queued on it, which is requeued as soon as it completes.
+struct KeyboardEndpoint {
+    index: u8, // device context index
+    ring: Ring,
+    report: Box<ReportBuffer>,
+    decoder: BootKeyboard,
+}
+
+impl KeyboardEndpoint {
+    fn queue_report(&mut self) {
+        self.ring.push(Trb {
+            parameter: util::kernel_to_physical_address(addr_of!(*self.report) as u64),
+            status: hid::BOOT_REPORT_SIZE as u32,
+            control: (TrbType::Normal as u32) << 10
+                | TrbMasks::InterruptOnShortPacket as u32
+                | TrbMasks::InterruptOnCompletion as u32,
+        });
+    }
+
+    fn report(&self) -> [u8; hid::BOOT_REPORT_SIZE] {
+        unsafe { addr_of!(self.report.bytes).read_volatile() }
+    }
+}
+
+struct Device {
+    slot: u8,
+    port: u8,
+    speed: PortSpeed,
+    context: Box<ContextPage>, // The output device context, written by the controller
+    control: Ring,
+    keyboard: Option<KeyboardEndpoint>,
+}
+
+/// An xHCI controller, and the devices plugged directly into its root hub ports. Hubs aren't
+/// supported, and the only class driver is the HID boot protocol keyboard.
+pub struct XHCIState {
+    operational: &'static mut OperationalRegisters,
+    interrupter: &'static mut InterrupterRegisters,
+    ports_base: u64,
+    doorbells_base: u64,
+    num_ports: u8,
+    context_dwords: usize, // Size of one slot/endpoint context
+    dcbaa: Box<AddressArray>,
+    scratchpad_array: Box<AddressArray>,
+    scratchpads: Vec<Box<Page>>,
+    command_ring: Ring,
+    event_ring: EventRing,
+    devices: Vec<Device>,
+
+    // Whether events raise an interrupt. If not, `handle_interrupt` should be called
+    // periodically instead.
+    pub msix: bool,
+}
+
+impl XHCIState {
+    /// Find the first xHCI controller at or after the PCI address `bus` / `slot` / `func`, bring
+    /// it up, and set up any keyboards plugged into it. Their keys go into `KEYBOARD`, the same as
+    /// a PS/2 keyboard's.
+    /// ### Safety
+    /// Should be called only once, after the local APIC and the kernel page table are set up.
+    /// There should be a handler for `vector`, which events are raised on if the controller
+    /// supports MSI-X.
+    pub unsafe fn new(
+        frame_allocator: &mut BootInfoFrameAllocator,
+        bus: u32,
+        slot: u32,
+        func: u32,
+        vector: u8,
+    ) -> Result<(), ()> {
+        let mut pci = PCIState::new();
+        let mut addr_opt = Some((bus, slot, func));
+
+        while let Some((bus, slot, func)) = addr_opt {
+            let subclass = pci.config_read_16(bus, slot, func, pci::Register::Subclass);
+            let prog_if = pci.config_read_8(bus, slot, func, pci::Register::ProgIF);
+            if subclass != 0x0C03 || prog_if != 0x30 {
+                addr_opt = pci.next_addr(bus, slot, func);
+                continue;
+            }
+
+            let xhci = Self::init(frame_allocator, bus, slot, func, vector)?;
+            XHCI0.set(Mutex::new(xhci)).map_err(|_| ())?;
+
+            return Ok(());
+        }
+
+        Err(())
+    }
+
+    unsafe fn init(
+        frame_allocator: &mut BootInfoFrameAllocator,
+        bus: u32,
+        slot: u32,
+        func: u32,
+        vector: u8,
+    ) -> Result<Self, ()> {
+        power::power_up(bus, slot, func)?;
+
+        let phys_addr = PCI_STATE.lock().bar_address(bus, slot, func, 0);
+        if phys_addr == 0 {
+            return Err(());
+        }
+
+        // Memory space + bus master, and no pin interrupts; we either use MSI-X or poll.
+        PCI_STATE
+            .lock()
+            .config_write(bus, slot, func, Register::Command, 0x406u16);
+
+        util::map_mmio(frame_allocator, phys_addr, PAGE_SIZE as u64, "xhci")?;
+        let base = util::physical_to_kernel_address(phys_addr);
+        let capabilities = &*(base as *const CapabilityRegisters);
+
+        let cap_length = capabilities.length.read() as u64;
+        let params_1 = capabilities.structural_params_1.read();
+        let max_slots = (params_1 & StructuralParamsMasks::MaxSlots as u32) as u8;
+        let num_ports = ((params_1 & StructuralParamsMasks::MaxPorts as u32) >> 24) as u8;
+
+        let params_2 = capabilities.structural_params_2.read();
+        let num_scratchpads = (((params_2 & ScratchpadMasks::High as u32) >> 21) << 5
+            | (params_2 & ScratchpadMasks::Low as u32) >> 27)
+            as usize;
+
+        let context_size = match capabilities.capability_params_1.read()
+            & CapabilityParamsMasks::ContextSize as u32
+        {
+            0 => 32,
+            _ => 64,
+        };
+
+        let doorbell_offset = (capabilities.doorbell_offset.read() & !0x3) as u64;
+        let runtime_offset = (capabilities.runtime_offset.read() & !0x1F) as u64;
+
+        // Everything up to the last port, interrupter 0, and the doorbell of the last slot
+        let end = (cap_length + PORT_REGISTERS_BASE + num_ports as u64 * 16)
+            .max(runtime_offset + INTERRUPTERS_BASE + size_of::<InterrupterRegisters>() as u64)
+            .max(doorbell_offset + 4 * (max_slots as u64 + 1));
+        util::map_mmio(frame_allocator, phys_addr, end, "xhci")?;
+
+        let operational = &mut *((base + cap_length) as *mut OperationalRegisters);
+        if operational.page_size.read() & 0x1 == 0 {
+            log_warn!("xHCI: controller doesn't support 4 KiB pages");
+            return Err(());
+        }
+
+        // The controller has to be halted before it can be reset
+        operational
+            .command
+            .write(operational.command.read() & !(CommandMasks::Run as u32));
+        wait_until(RESET_TIMEOUT, || {
+            operational.status.read() & StatusMasks::Halted as u32 != 0
+        })?;
+
+        operational.command.write(CommandMasks::Reset as u32);
+        wait_until(RESET_TIMEOUT, || {
+            operational.command.read() & CommandMasks::Reset as u32 == 0
+                && operational.status.read() & StatusMasks::NotReady as u32 == 0
+        })?;
+
+        let mut xhci = Self {
+            operational,
+            interrupter: &mut *((base + runtime_offset + INTERRUPTERS_BASE)
+                as *mut InterrupterRegisters),
+            ports_base: base + cap_length + PORT_REGISTERS_BASE,
+            doorbells_base: base + doorbell_offset,
+            num_ports,
+            context_dwords: context_size / 4,
+            dcbaa: Box::new(core::mem::zeroed()),
+            scratchpad_array: Box::new(core::mem::zeroed()),
+            scratchpads: Vec::new(),
+            command_ring: Ring::new(),
+            event_ring: EventRing::new(),
+            devices: Vec::new(),
+            msix: false,
+        };
+
+        // The controller may want some memory of its own, which it gets through entry 0
+        if num_scratchpads > xhci.scratchpad_array.entries.len() {
+            log_warn!("xHCI: too many scratchpad buffers ({})", num_scratchpads);
+            return Err(());
+        }
+        for i in 0..num_scratchpads {
+            let page: Box<Page> = Box::new(core::mem::zeroed());
+            xhci.scratchpad_array.entries[i] =
+                util::kernel_to_physical_address(addr_of!(*page) as u64);
+            xhci.scratchpads.push(page);
+        }
+        if num_scratchpads > 0 {
+            xhci.dcbaa.entries[0] =
+                util::kernel_to_physical_address(addr_of!(*xhci.scratchpad_array) as u64);
+        }
+
+        let config = xhci.operational.config.read();
+        xhci.operational
+            .config
+            .write((config & !0xFF) | max_slots as u32);
+        xhci.operational
+            .device_context_base_addr
+            .write(util::kernel_to_physical_address(
+                addr_of!(*xhci.dcbaa) as u64
+            ));
+        // Ring cycle state starts at 1, matching the ring's cycle bit
+        xhci.operational
+            .command_ring_control
+            .write(xhci.command_ring.address() | 0x1);
+
+        xhci.interrupter.event_ring_segments.write(1);
+        xhci.interrupter
+            .event_ring_dequeue
+            .write(xhci.event_ring.dequeue_address());
+        xhci.interrupter
+            .event_ring_segment_table
+            .write(xhci.event_ring.segment_address());
+        xhci.interrupter.moderation.write(INTERRUPT_MODERATION);
+
+        xhci.msix = msix::enable(frame_allocator, bus, slot, func, 0, vector).is_ok();
+        MSIX_VECTOR.store(vector, Ordering::Relaxed);
+        if !xhci.msix {
+            log_info!("xHCI: no MSI-X, polling for events");
+        }
+
+        xhci.operational.command.write(CommandMasks::Run as u32);
+        wait_until(RESET_TIMEOUT, || {
+            xhci.operational.status.read() & StatusMasks::Halted as u32 == 0
+        })?;
+
+        // Devices are set up with interrupts off, polling for each command and transfer
+        xhci.enumerate_ports();
+
+        if xhci.msix {
+            xhci.interrupter
+                .management
+                .write(InterrupterMasks::Enable as u32 | InterrupterMasks::Pending as u32);
+            xhci.operational
+                .command
+                .write(CommandMasks::Run as u32 | CommandMasks::InterrupterEnable as u32);
+        }
+
+        Ok(xhci)
+    }
+
+    fn port(&self, port: u8) -> &'static mut PortRegisters {
+        unsafe { &mut *((self.ports_base + (port as u64 - 1) * 16) as *mut PortRegisters) }
+    }
+
+    fn ring_doorbell(&self, slot: u8, target: u8) {
+        // Whatever was put on the ring has to be in memory before the controller looks at it
+        fence(Ordering::Release);
+        let doorbell = (self.doorbells_base + 4 * slot as u64) as *mut WriteOnly<u32>;
+        unsafe { (*doorbell).write(target as u32) };
+    }
+
+    fn enumerate_ports(&mut self) {
+        for port in 1..=self.num_ports {
+            let status = self.port(port).status_control.read();
+            if status & PortStatusMasks::Connected as u32 == 0 {
+                continue;
+            }
+
+            let speed = match self.reset_port(port) {
+                Ok(speed) => speed,
+                Err(()) => {
+                    log_warn!("xHCI: couldn't reset port {}", port);
+                    continue;
+                }
+            };
+
+            if self.setup_device(port, speed).is_err() {
+                log_warn!("xHCI: couldn't set up the device on port {}", port);
+            }
+        }
+    }
+
+    // Get a port with something plugged in to the enabled state. Returns the device's speed.
+    fn reset_port(&mut self, port: u8) -> Result<PortSpeed, ()> {
+        let registers = self.port(port);
+        let status = registers.status_control.read();
+
+        // USB 3 ports are enabled as soon as the link trains; USB 2 ones need a reset first.
+        if status & PortStatusMasks::Enabled as u32 == 0 {
+            registers
+                .status_control
+                .write((status & PortStatusMasks::Preserve as u32) | PortStatusMasks::Reset as u32);
+            wait_until(PORT_RESET_TIMEOUT, || {
+                registers.status_control.read() & PortStatusMasks::ResetChange as u32 != 0
+            })?;
+        }
+
+        // Acknowledge whatever changed, so that the change bits don't keep raising events
+        let status = registers.status_control.read();
+        registers.status_control.write(
+            (status & PortStatusMasks::Preserve as u32)
+                | (status & PortStatusMasks::ChangeBits as u32),
+        );
+
+        if status & PortStatusMasks::Enabled as u32 == 0 {
+            return Err(());
+        }
+
+        PortSpeed::try_from((status & PortStatusMasks::Speed as u32) >> 10)
+    }
+
+    fn setup_device(&mut self, port: u8, speed: PortSpeed) -> Result<(), ()> {
+        let slot = self
+            .command(Trb {
+                control: (TrbType::EnableSlot as u32) << 10,
+                ..Default::default()
+            })?
+            .slot();
+
+        let mut device = Device {
+            slot,
+            port,
+            speed,
+            context: unsafe { allocator::try_box_zeroed()? },
+            control: Ring::new(),
+            keyboard: None,
+        };
+        self.dcbaa.entries[slot as usize] =
+            util::kernel_to_physical_address(addr_of!(*device.context) as u64);
+
+        // Give the device an address. The control endpoint's max packet size is a guess until
+        // we've read the start of the device descriptor.
+        let mut max_packet_size = speed.default_max_packet_size();
+        let mut input: Box<ContextPage> = unsafe { allocator::try_box_zeroed()? };
+        self.set_add_flags(&mut input, 0b11);
+        self.set_slot_context(&mut input, &device, CONTROL_ENDPOINT);
+        self.set_control_endpoint(&mut input, &device, max_packet_size);
+        self.command(Trb {
+            parameter: util::kernel_to_physical_address(addr_of!(*input) as u64),
+            control: (TrbType::AddressDevice as u32) << 10 | (slot as u32) << 24,
+            ..Default::default()
+        })?;
+
+        let mut buffer: Box<Page> = unsafe { allocator::try_box_zeroed()? };
+
+        self.control_transfer(
+            &mut device,
+            usb::get_descriptor(DescriptorType::Device, 0, 8),
+            &mut buffer,
+        )?;
+
+        // For USB 3 devices this is an exponent, but those already start out with the right one
+        let descriptor_packet_size = buffer.bytes[7] as u16;
+        if speed != PortSpeed::Super && descriptor_packet_size != max_packet_size {
+            max_packet_size = descriptor_packet_size;
+
+            let mut input: Box<ContextPage> = Box::new(unsafe { core::mem::zeroed() });
+            self.set_add_flags(&mut input, 0b10);
+            self.set_control_endpoint(&mut input, &device, max_packet_size);
+            self.command(Trb {
+                parameter: util::kernel_to_physical_address(addr_of!(*input) as u64),
+                control: (TrbType::EvaluateContext as u32) << 10 | (slot as u32) << 24,
+                ..Default::default()
+            })?;
+        }
+
+        // The configuration descriptor is followed by all of its interfaces and endpoints
+        self.control_transfer(
+            &mut device,
+            usb::get_descriptor(
+                DescriptorType::Configuration,
+                0,
+                usb::CONFIGURATION_DESCRIPTOR_SIZE as u16,
+            ),
+            &mut buffer,
+        )?;
+        let total_length =
+            (u16::from_le_bytes([buffer.bytes[2], buffer.bytes[3]]) as usize).min(PAGE_SIZE) as u16;
+        let configuration = buffer.bytes[5];
+
+        self.control_transfer(
+            &mut device,
+            usb::get_descriptor(DescriptorType::Configuration, 0, total_length),
+            &mut buffer,
+        )?;
+
+        let boot_keyboard = usb::find_interface(
+            &buffer.bytes[..total_length as usize],
+            hid::CLASS_HID,
+            hid::SUBCLASS_BOOT,
+            hid::PROTOCOL_KEYBOARD,
+        );
+
+        match boot_keyboard {
+            Some((interface, endpoint)) => {
+                self.setup_keyboard(&mut device, &mut buffer, configuration, interface, endpoint)?;
+                log_info!("USB: keyboard on port {}", port);
+            }
+            None => log_info!("USB: ignoring the device on port {}", port),
+        }
+
+        self.devices.push(device);
+        Ok(())
+    }
+
+    fn setup_keyboard(
+        &mut self,
+        device: &mut Device,
+        buffer: &mut Page,
+        configuration: u8,
+        interface: u8,
+        endpoint: InterruptEndpoint,
+    ) -> Result<(), ()> {
+        // IN endpoints have odd context indices
+        let index = endpoint.number * 2 + 1;
+        let keyboard = KeyboardEndpoint {
+            index,
+            ring: Ring::new(),
+            report: Box::new(ReportBuffer {
+                bytes: [0; hid::BOOT_REPORT_SIZE],
+            }),
+            decoder: BootKeyboard::new(),
+        };
+
+        let mut input: Box<ContextPage> = Box::new(unsafe { core::mem::zeroed() });
+        self.set_add_flags(&mut input, 0b1 | 1 << index);
+        self.set_slot_context(&mut input, device, index);
+
+        let context = self.context_dwords * (index as usize + 1);
+        let max_packet_size = endpoint.max_packet_size as u32;
+        input.dwords[context].write(endpoint_interval(device.speed, endpoint.interval) << 16);
+        input.dwords[context + 1]
+            .write(ERROR_COUNT | (EndpointType::InterruptIn as u32) << 3 | max_packet_size << 16);
+        write_dequeue_pointer(&mut input, context, keyboard.ring.address());
+        // Average TRB length, and the most the endpoint will send per interval
+        input.dwords[context + 4].write(max_packet_size << 16 | max_packet_size);
+
+        self.command(Trb {
+            parameter: util::kernel_to_physical_address(addr_of!(*input) as u64),
+            control: (TrbType::ConfigureEndpoint as u32) << 10 | (device.slot as u32) << 24,
+            ..Default::default()
+        })?;
+
+        self.control_transfer(device, usb::set_configuration(configuration), buffer)?;
+        self.control_transfer(device, hid::set_protocol(interface, true), buffer)?;
+
+        device.keyboard = Some(keyboard);
+        if let Some(keyboard) = device.keyboard.as_mut() {
+            keyboard.queue_report();
+        }
+        self.ring_doorbell(device.slot, index);
+
+        Ok(())
+    }
+
+    fn set_add_flags(&self, input: &mut ContextPage, flags: u32) {
+        // The input control context comes first; the add flags are its second dword
+        input.dwords[1].write(flags);
+    }
+
+    // Fill in the slot context (input context 1) for a device on a root hub port
+    fn set_slot_context(&self, input: &mut ContextPage, device: &Device, last_endpoint: u8) {
+        let context = self.context_dwords;
+        input.dwords[context].write((last_endpoint as u32) << 27 | (device.speed as u32) << 20);
+        input.dwords[context + 1].write((device.port as u32) << 16);
+    }
+
+    // Fill in the control endpoint's context (input context 2)
+    fn set_control_endpoint(&self, input: &mut ContextPage, device: &Device, max_packet_size: u16) {
+        let context = self.context_dwords * 2;
+        input.dwords[context + 1].write(
+            ERROR_COUNT | (EndpointType::Control as u32) << 3 | (max_packet_size as u32) << 16,
+        );
+        write_dequeue_pointer(input, context, device.control.address());
+        // Average TRB length: setup packets are 8 bytes
+        input.dwords[context + 4].write(8);
+    }
+
+    // Run a command to completion, returning its completion event.
+    fn command(&mut self, trb: Trb) -> Result<Trb, ()> {
+        let address = self.command_ring.push(trb);
+        self.ring_doorbell(0, 0);
+
+        let event = self.wait_event(TrbType::CommandCompletion, address)?;
+        match event.completion_code() {
+            COMPLETION_SUCCESS => Ok(event),
+            code => {
+                log_warn!("xHCI: command {} failed with code {}", trb.trb_type(), code);
+                Err(())
+            }
+        }
+    }
+
+    // Run a request on a device's control endpoint. Data, if any, goes to/from `buffer`.
+    fn control_transfer(
+        &mut self,
+        device: &mut Device,
+        setup: SetupPacket,
+        buffer: &mut Page,
+    ) -> Result<(), ()> {
+        let length = setup.length as u32;
+        let (transfer_type, data_direction) = match (length, setup.is_in()) {
+            (0, _) => (SETUP_NO_DATA, 0),
+            (_, true) => (SETUP_IN_DATA, TrbMasks::DirectionIn as u32),
+            (_, false) => (SETUP_OUT_DATA, 0),
+        };
+
+        device.control.push(Trb {
+            parameter: setup.as_u64(),
+            status: 8,
+            control: (TrbType::SetupStage as u32) << 10
+                | TrbMasks::ImmediateData as u32
+                | transfer_type,
+        });
+
+        if length > 0 {
+            device.control.push(Trb {
+                parameter: util::kernel_to_physical_address(buffer.bytes.as_mut_ptr() as u64),
+                status: length,
+                control: (TrbType::DataStage as u32) << 10 | data_direction,
+            });
+        }
+
+        // The status stage goes the other way from the data, or in if there is none
+        let status_direction = match data_direction {
+            0 => TrbMasks::DirectionIn as u32,
+            _ => 0,
+        };
+        let status = device.control.push(Trb {
+            control: (TrbType::StatusStage as u32) << 10
+                | status_direction
+                | TrbMasks::InterruptOnCompletion as u32,
+            ..Default::default()
+        });
+
+        self.ring_doorbell(device.slot, CONTROL_ENDPOINT);
+
+        let event = self.wait_event(TrbType::TransferEvent, status)?;
+        match event.completion_code() {
+            COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET => Ok(()),
+            code => {
+                log_warn!(
+                    "USB: request {:#x} failed with code {}",
+                    setup.request,
+                    code
+                );
+                Err(())
+            }
+        }
+    }
+
+    // Poll the event ring for the event about the TRB at `trb_address`, dropping any others.
+    // Only for use while interrupts from the controller are off.
+    fn wait_event(&mut self, trb_type: TrbType, trb_address: u64) -> Result<Trb, ()> {
+        let deadline = TIMER.load(Ordering::SeqCst) + COMMAND_TIMEOUT;
+
+        loop {
+            while let Some(event) = self.event_ring.poll() {
+                self.interrupter
+                    .event_ring_dequeue
+                    .write(self.event_ring.dequeue_address() | EVENT_HANDLER_BUSY);
+
+                if event.trb_type() == trb_type as u8 && event.parameter == trb_address {
+                    return Ok(event);
+                }
+            }
+
+            if TIMER.load(Ordering::SeqCst) > deadline {
+                return Err(());
+            }
+            Arch::pause();
+        }
+    }
+
+    fn handle_events(&mut self) {
+        self.interrupter
+            .management
+            .write(self.interrupter.management.read() | InterrupterMasks::Pending as u32);
+        self.operational
+            .status
+            .write(StatusMasks::EventInterrupt as u32);
+
+        while let Some(event) = self.event_ring.poll() {
+            if event.trb_type() == TrbType::TransferEvent as u8 {
+                self.handle_transfer(event);
+            }
+        }
+
+        self.interrupter
+            .event_ring_dequeue
+            .write(self.event_ring.dequeue_address() | EVENT_HANDLER_BUSY);
+    }
+
+    fn handle_transfer(&mut self, event: Trb) {
+        let Some(device) = self
+            .devices
+            .iter_mut()
+            .find(|device| device.slot == event.slot())
+        else {
+            return;
+        };
+        let Some(keyboard) = device.keyboard.as_mut() else {
+            return;
+        };
+        if event.endpoint() != keyboard.index {
+            return;
+        }
+
+        match event.completion_code() {
+            COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET => {
+                let report = keyboard.report();
+                keyboard
+                    .decoder
+                    .handle_report(&report, &mut KEYBOARD.lock());
+            }
+            code => {
+                // Most likely a stall, which would need the endpoint to be reset
+                log_warn!("USB: keyboard transfer failed with code {}", code);
+                return;
+            }
+        }
+
+        keyboard.queue_report();
+        let (slot, index) = (device.slot, keyboard.index);
+        self.ring_doorbell(slot, index);
+    }
+}
+
+/// Called from the MSI-X interrupt handler, or periodically if there is no MSI-X: turn keyboard
+/// reports into key presses. The caller should wake up whoever is waiting for keys.
+pub fn handle_interrupt() {
+    if let Some(controller) = XHCI0.get() {
+        controller.lock().handle_events();
+    }
+}
+
+fn wait_until<F: Fn() -> bool>(timeout: u64, condition: F) -> Result<(), ()> {
+    let deadline = TIMER.load(Ordering::SeqCst) + timeout;
+
+    while !condition() {
+        if TIMER.load(Ordering::SeqCst) > deadline {
+            return Err(());
+        }
+        Arch::pause();
+    }
+
+    Ok(())
+}
+
+// The TR dequeue pointer lives in dwords 2 and 3 of an endpoint context, along with the
+// dequeue cycle state in bit 0.
+fn write_dequeue_pointer(input: &mut ContextPage, context: usize, ring_address: u64) {
+    input.dwords[context + 2].write(ring_address as u32 | 0x1);
+    input.dwords[context + 3].write((ring_address >> 32) as u32);
+}
+
+// Endpoint contexts want the interval as 2^n * 125us. Full and low speed devices give it in
+// milliseconds, faster ones already as n + 1.
+fn endpoint_interval(speed: PortSpeed, interval: u8) -> u32 {
+    match speed {
+        PortSpeed::Low | PortSpeed::Full => {
+            let frames = (interval.max(1) as u32) * 8;
+            31 - frames.leading_zeros()
+        }
+        PortSpeed::High | PortSpeed::Super => (interval.clamp(1, 16) - 1) as u32,
+    }
+}
@@ -11,42 +11,104 @@
 #![feature(offset_of)]
 
 mod allocator;
+mod arch;
+mod bootinfo;
+mod config;
 mod fs;
 mod klib;
-mod memory;
-use bootloader_api::config::{BootloaderConfig, Mapping};
-use bootloader_api::{entry_point, BootInfo};
+#[cfg(feature = "selftest")]
+mod selftest;
+mod shell;
+mod task;
+use arch::ports;
+use arch::x86_64::fpu;
+use arch::x86_64::interrupts::apic;
+use arch::x86_64::interrupts::exceptions;
+use arch::x86_64::interrupts::idt;
+use arch::x86_64::interrupts::machine_check;
+use arch::x86_64::interrupts::pic;
+use arch::x86_64::interrupts::pic::Irq;
+use arch::x86_64::interrupts::vectors;
+use arch::x86_64::memory_map;
+use arch::x86_64::nx;
+use arch::x86_64::paging::init_page_table;
+use arch::x86_64::paging::BootInfoFrameAllocator;
+use arch::x86_64::reserved;
+use arch::x86_64::smap;
+use arch::x86_64::wakeup;
+use bootinfo::BootInfo;
 use core::mem::MaybeUninit;
-use fs::ext2::Superblock;
+#[cfg(feature = "fs-ext2")]
+use fs::ext2::{self, Ext2Fs, MountOptions, Superblock};
 use idt::StackFrame;
+use klib::acpi;
+use klib::acpi::pm;
 use klib::acpi::rsdp::Rsdp;
+#[cfg(feature = "driver-ahci")]
 use klib::ahci::ahcistate::AHCIState;
+#[cfg(feature = "driver-ahci")]
 use klib::ahci::ahcistate::SATA_DISK0;
+use klib::ata::Command::ReadFPDMAQueued;
+use klib::bcache;
+use klib::bootdiag;
+use klib::clock;
+use klib::cmdline;
+use klib::cmos;
+use klib::crashdump;
+use klib::executor;
 use klib::graphics::framebuffer;
-use klib::idt;
+use klib::initgraph::{self, Stage};
+use klib::input;
+use klib::input::InputEvent;
+use klib::iommu;
+use klib::iosched;
+#[cfg(feature = "driver-nvme")]
+use klib::nvme::nvmestate;
+#[cfg(feature = "driver-nvme")]
+use klib::nvme::nvmestate::NVMeState;
 use klib::once_lock::OnceLock;
-use klib::pci::ide_controller::Command::ReadFPDMAQueued;
-use klib::pic;
-use klib::pic::Irq;
+use klib::pci;
+#[cfg(feature = "driver-ide")]
+use klib::pci::ide_controller;
+#[cfg(feature = "driver-ide")]
+use klib::pci::ide_controller::ChannelType;
+use klib::pci::pcistate::PCI_STATE;
+use klib::phys_mapper::PhysMapper;
+use klib::polling;
+use klib::profiler;
 use klib::ps2;
-use memory::init_page_table;
-use memory::BootInfoFrameAllocator;
+#[cfg(feature = "qemu")]
+use klib::qemu;
+use klib::rand;
+use klib::shutdown;
+use klib::speaker;
+use klib::suspend;
+use klib::telemetry;
+use klib::time;
+use klib::timer;
+use klib::tlb;
+#[cfg(feature = "fs-ext2")]
+use klib::uuid::Uuid;
+use klib::version;
+#[cfg(feature = "driver-xhci")]
+use klib::xhci::xhcistate;
+#[cfg(feature = "driver-xhci")]
+use klib::xhci::xhcistate::XHCIState;
 use pic::PIC;
+use pic::PIC_IRQ_OFFSET;
+use ps2::keyboard::ExtendedKeyCode;
 use ps2::keyboard::KeyCode;
+use ps2::keyboard::KeyEvent;
 use ps2::keyboard::SpecialKey;
 use ps2::keyboard::KEYBOARD;
-use spin::RwLock;
+use shell::Shell;
+use spin::{Mutex, RwLock};
 use x86_64::instructions::interrupts;
-use x86_64::structures::paging::FrameAllocator;
-use x86_64::structures::paging::Mapper;
 use x86_64::structures::paging::OffsetPageTable;
-use x86_64::structures::paging::Page;
-use x86_64::structures::paging::PageTableFlags;
-use x86_64::structures::paging::Size4KiB;
 use x86_64::VirtAddr;
 
 extern crate alloc;
-use core::sync::atomic::AtomicU64;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 /*
 lazy_static! {
@@ -63,210 +125,923 @@ lazy_static! {
 
 static mut IDT: MaybeUninit<idt::DescriptorTable> = MaybeUninit::uninit();
 
+// Milliseconds since the timer started, kept up to date by `clock::tick`
 static TIMER: AtomicU64 = AtomicU64::new(0);
 
 static KERNEL_PAGETABLE: OnceLock<RwLock<OffsetPageTable<'static>>> = OnceLock::new();
 
-fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+fn kernel_main(boot_info: BootInfo) -> ! {
     init(boot_info);
+    // Booting is as deep as the boot stack is likely to get
+    task::log_stack_usage();
+    cmos::boot_finished();
+    #[cfg(feature = "qemu")]
+    if qemu::exits_when_done() {
+        qemu::exit(qemu::ExitCode::Success);
+    }
+
+    let mut shell = Shell::new();
+    shell.prompt();
 
+    let keys = input::subscribe(input::Priority::Normal, |event| {
+        matches!(event, InputEvent::Key(_))
+    });
     loop {
-        interrupts::without_interrupts(|| {
-            if let Some(key) = KEYBOARD.lock().pop_key() {
-                print_key(key)
-            }
-        });
-        x86_64::instructions::hlt();
+        if let InputEvent::Key(key) = keys.next() {
+            handle_key(&mut shell, key);
+        }
     }
 }
 
-pub static BOOTLOADER_CONFIG: BootloaderConfig = {
-    let mut config = BootloaderConfig::new_default();
-    config.mappings.physical_memory = Some(Mapping::Dynamic);
-    config
-};
-
-entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
-
-fn print_key(key: KeyCode) {
+fn handle_key(shell: &mut Shell, event: KeyEvent) {
     use KeyCode::*;
-    static mut LSHIFT_PRESSED: bool = false;
-    static mut RSHIFT_PRESSED: bool = false;
-    static mut CAPS_PRESSED: bool = false;
-
-    match key {
-        AsciiDown(key) => {
-            let shift_pressed = unsafe { LSHIFT_PRESSED || RSHIFT_PRESSED || CAPS_PRESSED };
-            let ch = if shift_pressed {
-                key.get_shifted()
-            } else {
-                key.get()
-            };
-
-            print!("{}", ch as char);
-        }
 
-        SpecialDown(SpecialKey::Enter) => print!("\n"),
-        SpecialDown(SpecialKey::Backspace) => print!("\x08"),
-        SpecialDown(SpecialKey::LeftShift) => unsafe { LSHIFT_PRESSED = true },
-        SpecialDown(SpecialKey::RightShift) => unsafe { RSHIFT_PRESSED = true },
-        SpecialDown(SpecialKey::CapsLock) => unsafe { CAPS_PRESSED = !CAPS_PRESSED },
-        SpecialUp(SpecialKey::LeftShift) => unsafe { LSHIFT_PRESSED = false },
-        SpecialUp(SpecialKey::RightShift) => unsafe { RSHIFT_PRESSED = false },
+    if let Some(ch) = event.ascii() {
+        shell.input_char(ch);
+        return;
+    }
+
+    match event.key {
+        SpecialDown(SpecialKey::Enter) => shell.enter(),
+        SpecialDown(SpecialKey::Backspace) => shell.backspace(),
+        ExtendedDown(ExtendedKeyCode::Delete) => shell.delete(),
+        ExtendedDown(ExtendedKeyCode::CursorLeft) => shell.cursor_left(),
+        ExtendedDown(ExtendedKeyCode::CursorRight) => shell.cursor_right(),
+        ExtendedDown(ExtendedKeyCode::Home) => shell.home(),
+        ExtendedDown(ExtendedKeyCode::End) => shell.end(),
+        ExtendedDown(ExtendedKeyCode::CursorUp) => shell.history_prev(),
+        ExtendedDown(ExtendedKeyCode::CursorDown) => shell.history_next(),
         _ => {}
     }
 }
 
-fn init(boot_info: &'static mut BootInfo) {
-    unsafe { framebuffer::init_framebuffer(boot_info.framebuffer.as_mut().unwrap()) };
+// What boot's stages hand on to the stages after them
+struct Boot {
+    info: BootInfo,
+    idt: &'static mut idt::DescriptorTable,
+    frame_allocator: Option<BootInfoFrameAllocator>,
+    mapper: Option<OffsetPageTable<'static>>,
+    // If the last boot never reached the shell, leave out the drivers most likely to have hung it
+    safe_mode: bool,
+    #[cfg(feature = "driver-nvme")]
+    nvme_vector: u8,
+    #[cfg(feature = "driver-xhci")]
+    xhci_vector: u8,
+}
 
-    // let rsdp = unsafe { Rsdp::get(rsdp_addr as usize) };
+impl Boot {
+    // For stages that run after "memory map"
+    fn frame_allocator(&mut self) -> &mut BootInfoFrameAllocator {
+        self.frame_allocator
+            .as_mut()
+            .expect("No frame allocator yet")
+    }
+}
+
+// Listed in the order they used to run in, which `initgraph::run` keeps to where it can
+const STAGES: &[Stage<Boot>] = &[
+    Stage {
+        name: "IDT",
+        after: &[],
+        run: init_idt,
+    },
+    Stage {
+        name: "CPU",
+        after: &["IDT"],
+        run: init_cpu,
+    },
+    Stage {
+        name: "PIC",
+        after: &["IDT"],
+        run: init_pic,
+    },
+    Stage {
+        name: "clock",
+        after: &["PIC"],
+        run: init_clock,
+    },
+    Stage {
+        name: "keyboard",
+        after: &["PIC"],
+        run: init_keyboard,
+    },
+    Stage {
+        name: "memory map",
+        after: &[],
+        run: init_memory_map,
+    },
+    Stage {
+        name: "paging",
+        after: &["memory map"],
+        run: init_paging,
+    },
+    // Before the heap, which is put somewhere at random
+    Stage {
+        name: "random",
+        after: &[],
+        run: init_random,
+    },
+    Stage {
+        name: "heap",
+        after: &["paging", "random"],
+        run: init_heap,
+    },
+    Stage {
+        name: "boot state",
+        after: &["heap"],
+        run: init_boot_state,
+    },
+    Stage {
+        name: "initramfs",
+        after: &["heap"],
+        run: init_initramfs,
+    },
+    Stage {
+        name: "interrupts",
+        after: &["IDT", "PIC", "clock"],
+        run: enable_interrupts,
+    },
+    Stage {
+        name: "kernel page table",
+        after: &["paging", "heap"],
+        run: init_kernel_page_table,
+    },
+    Stage {
+        name: "wakeup",
+        after: &["kernel page table"],
+        run: init_wakeup,
+    },
+    Stage {
+        name: "local APIC",
+        after: &["IDT", "kernel page table"],
+        run: init_local_apic,
+    },
+    Stage {
+        name: "ACPI",
+        after: &["heap", "kernel page table"],
+        run: init_acpi,
+    },
+    Stage {
+        name: "IOMMU",
+        after: &["ACPI"],
+        run: init_iommu,
+    },
+    Stage {
+        name: "power button",
+        after: &["ACPI", "interrupts"],
+        run: init_power_button,
+    },
+    Stage {
+        name: "PCI",
+        after: &["heap"],
+        run: init_pci,
+    },
+    #[cfg(feature = "driver-ahci")]
+    Stage {
+        name: "AHCI",
+        after: &["PCI", "IOMMU", "interrupts"],
+        run: init_ahci,
+    },
+    #[cfg(feature = "driver-nvme")]
+    Stage {
+        name: "NVMe",
+        after: &["PCI", "IOMMU", "local APIC", "boot state"],
+        run: init_nvme,
+    },
+    #[cfg(feature = "driver-xhci")]
+    Stage {
+        name: "xHCI",
+        after: &["PCI", "IOMMU", "local APIC", "boot state"],
+        run: init_xhci,
+    },
+    Stage {
+        name: "stacks",
+        after: &["heap", "kernel page table", "random"],
+        run: init_stacks,
+    },
+    #[cfg(feature = "selftest")]
+    Stage {
+        name: "selftest",
+        after: &["stacks"],
+        run: run_selftest,
+    },
+    Stage {
+        name: "tasks",
+        after: &["stacks", "interrupts"],
+        run: init_tasks,
+    },
+    Stage {
+        name: "hotkeys",
+        after: &["tasks", "keyboard"],
+        run: start_hotkeys,
+    },
+    Stage {
+        name: "telemetry",
+        after: &["tasks"],
+        run: start_telemetry,
+    },
+    #[cfg(feature = "driver-ahci")]
+    Stage {
+        name: "sata-unplug",
+        after: &["tasks", "AHCI"],
+        run: start_sata_unplug,
+    },
+    #[cfg(feature = "driver-xhci")]
+    Stage {
+        name: "usb-poll",
+        after: &["tasks", "xHCI"],
+        run: start_usb_poll,
+    },
+];
+
+fn init(mut boot_info: BootInfo) {
+    cmdline::init(boot_info.cmdline);
+    unsafe { framebuffer::init_framebuffer(boot_info.framebuffer.take().unwrap()) };
+    version::log();
+    bootdiag::init();
 
     let idt = unsafe {
         IDT.write(Default::default());
         IDT.assume_init_mut()
     };
 
+    let mut boot = Boot {
+        info: boot_info,
+        idt,
+        frame_allocator: None,
+        mapper: None,
+        safe_mode: false,
+        #[cfg(feature = "driver-nvme")]
+        nvme_vector: 0,
+        #[cfg(feature = "driver-xhci")]
+        xhci_vector: 0,
+    };
+    initgraph::run(STAGES, &mut boot);
+}
+
+fn init_idt(boot: &mut Boot) -> Result<(), ()> {
+    let idt = &mut *boot.idt;
+    exceptions::install(idt);
     idt.breakpoint.set_handler_fn(breakpoint_handler);
-    idt.double_fault.set_handler_fn(double_fault_handler);
+    idt.device_not_available
+        .set_handler_fn(device_not_available_handler);
     idt.user_interrupts[Irq::Timer as usize].set_handler_fn(timer_handler);
     idt.user_interrupts[Irq::Keyboard as usize].set_handler_fn(keyboard_handler);
+    idt.user_interrupts[Irq::Spurious as usize].set_handler_fn(pic_spurious_handler);
+    #[cfg(feature = "driver-ide")]
+    {
+        idt.user_interrupts[Irq::PrimaryAta as usize].set_handler_fn(ide_primary_handler);
+        idt.user_interrupts[Irq::SecondaryAta as usize].set_handler_fn(ide_secondary_handler);
+    }
+    idt.user_interrupts[apic::SPURIOUS_VECTOR as usize - 32].set_handler_fn(spurious_handler);
+    idt.user_interrupts[tlb::SHOOTDOWN_VECTOR as usize - 32].set_handler_fn(tlb_shootdown_handler);
+
+    // The fixed vectors go first, so that nothing else is handed them
+    vectors::reserve_range(PIC_IRQ_OFFSET, 16, "pic").unwrap();
+    vectors::reserve(apic::SPURIOUS_VECTOR, "apic spurious").unwrap();
+    vectors::reserve(tlb::SHOOTDOWN_VECTOR, "tlb shootdown").unwrap();
+
+    // So do the ports at fixed addresses. Port 0x61 gates the speaker and says why an NMI came in.
+    ports::claim(pic::BASE_COMMAND_PORT, 2, "pic").unwrap();
+    ports::claim(pic::HIGHER_COMMAND_PORT, 2, "pic").unwrap();
+    ports::claim(clock::PIT_CHANNEL_0_PORT, 1, "pit").unwrap();
+    ports::claim(clock::PIT_COMMAND_PORT, 1, "pit, speaker").unwrap();
+    ports::claim(ps2::controller::DATA_PORT, 1, "ps2").unwrap();
+    ports::claim(ps2::controller::CMD_STATUS_REGISTER, 1, "ps2").unwrap();
+    ports::claim(speaker::PIT_CHANNEL_2_PORT, 1, "speaker").unwrap();
+    ports::claim(speaker::SPEAKER_CONTROL_PORT, 1, "speaker, nmi").unwrap();
+    ports::claim(pci::CONFIG_ADDRESS as u16, 8, "pci config").unwrap();
+    ports::claim(cmos::INDEX_PORT, 2, "cmos").unwrap();
+    #[cfg(feature = "qemu")]
+    {
+        ports::claim(qemu::EXIT_PORT, 4, "qemu exit").unwrap();
+        ports::claim(qemu::DEBUGCON_PORT, 1, "qemu debugcon").unwrap();
+    }
+
+    #[cfg(feature = "driver-nvme")]
+    {
+        boot.nvme_vector = vectors::allocate("nvme").unwrap();
+        idt.user_interrupts[boot.nvme_vector as usize - 32].set_handler_fn(nvme_handler);
+    }
+    #[cfg(feature = "driver-xhci")]
+    {
+        boot.xhci_vector = vectors::allocate("xhci").unwrap();
+        idt.user_interrupts[boot.xhci_vector as usize - 32].set_handler_fn(xhci_handler);
+    }
 
     idt.load();
+    Ok(())
+}
+
+fn init_cpu(_boot: &mut Boot) -> Result<(), ()> {
+    machine_check::init();
+    fpu::init();
+    smap::init();
+    Ok(())
+}
+
+fn init_pic(_boot: &mut Boot) -> Result<(), ()> {
     unsafe {
         let mut pic_guard = PIC.lock();
         pic_guard.initialize();
-        pic_guard.enable_all();
+        pic_guard.disable();
+        pic_guard.unmask_irq(Irq::Timer);
+        if !polling::is_polled("ps2") {
+            pic_guard.unmask_irq(Irq::Keyboard);
+        }
+        if cfg!(feature = "driver-ide") {
+            pic_guard.unmask_irq(Irq::PrimaryAta);
+            pic_guard.unmask_irq(Irq::SecondaryAta);
+        }
     };
-    {
-        let mut keyboard = KEYBOARD.lock();
-        keyboard.enable();
-    }
+    shutdown::register("interrupts", mask_interrupts).unwrap();
+    suspend::register("pic", suspend_pic, resume_pic).unwrap();
+    Ok(())
+}
+
+fn init_clock(_boot: &mut Boot) -> Result<(), ()> {
+    clock::init();
+    time::init();
+    Ok(())
+}
+
+fn init_keyboard(_boot: &mut Boot) -> Result<(), ()> {
+    KEYBOARD.lock().enable();
+    Ok(())
+}
+
+fn init_memory_map(boot: &mut Boot) -> Result<(), ()> {
+    let regions = boot.info.memory_regions;
 
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    // Before any frames are handed out, so that none of them can be reserved ones
+    reserved::init(regions, boot.info.kernel_addr, boot.info.kernel_len);
+    wakeup::reserve(regions);
+    let memory_map = memory_map::init(regions);
+    boot.frame_allocator = Some(unsafe { BootInfoFrameAllocator::init(memory_map) });
+    Ok(())
+}
 
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+fn init_paging(boot: &mut Boot) -> Result<(), ()> {
+    // Before anything is mapped NO_EXECUTE
+    nx::enable();
+    let phys_mem_offset = VirtAddr::new(boot.info.physical_memory_offset);
     let mut mapper = unsafe { init_page_table(phys_mem_offset) };
+    unsafe { nx::protect_kernel(&mut mapper, boot.info.kernel_addr) };
+    boot.mapper = Some(mapper);
+    Ok(())
+}
+
+fn init_heap(boot: &mut Boot) -> Result<(), ()> {
+    let mapper = boot.mapper.as_mut().ok_or(())?;
+    let frame_allocator = boot.frame_allocator.as_mut().ok_or(())?;
+    allocator::init_heap(mapper, frame_allocator).map_err(|_| ())?;
+    // Anything boot needs from here on can go on the heap
+    allocator::early::seal(mapper);
+    Ok(())
+}
 
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Failed to initialize heap");
+fn init_boot_state(boot: &mut Boot) -> Result<(), ()> {
+    config::log_features();
+    cmdline::log();
+    cmos::boot_started();
+    fs::procfs::init();
 
+    boot.safe_mode = cmos::last_boot_failed() || cmdline::has("safe");
+    if boot.safe_mode {
+        log_warn!("Safe mode: not starting NVMe or USB");
+    }
+    Ok(())
+}
+
+fn init_initramfs(boot: &mut Boot) -> Result<(), ()> {
+    let Some(image) = boot.info.initramfs else {
+        return Ok(());
+    };
+    let initramfs = fs::initramfs::Initramfs::new(image)?;
+    fs::mount_initramfs(initramfs)
+}
+
+fn enable_interrupts(_boot: &mut Boot) -> Result<(), ()> {
     interrupts::enable();
+    Ok(())
+}
 
-    let rsdp_addr = boot_info.rsdp_addr.into_option().unwrap();
-    let rsdp_page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(rsdp_addr));
-    let frame = frame_allocator
-        .allocate_frame()
-        .expect("Failed to map rsdp");
-    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-    unsafe {
-        mapper
-            .map_to(rsdp_page, frame, flags, &mut frame_allocator)
-            .expect("Failed to map rsdp")
-            .flush()
+fn init_kernel_page_table(boot: &mut Boot) -> Result<(), ()> {
+    let mapper = boot.mapper.take().ok_or(())?;
+    let _ = KERNEL_PAGETABLE.set(RwLock::new(mapper));
+    Ok(())
+}
+
+fn init_wakeup(boot: &mut Boot) -> Result<(), ()> {
+    let result = wakeup::init(boot.frame_allocator());
+    if result.is_err() {
+        log_warn!("No wakeup trampoline, can't suspend");
+    }
+    result
+}
+
+fn init_local_apic(boot: &mut Boot) -> Result<(), ()> {
+    unsafe { apic::init(boot.frame_allocator())? };
+    tlb::register_cpu();
+    suspend::register("local apic", || Ok(()), apic::resume).unwrap();
+    Ok(())
+}
+
+fn init_random(_boot: &mut Boot) -> Result<(), ()> {
+    log_info!("Random numbers from {:?}", rand::init());
+    Ok(())
+}
+
+fn init_acpi(boot: &mut Boot) -> Result<(), ()> {
+    let rsdp_addr = boot.info.rsdp_addr.ok_or(())?;
+    log_info!("Rsdp addr is {:x}", rsdp_addr);
+
+    let phys_mapper = PhysMapper::new(boot.frame_allocator()).ok_or(())?;
+    match Rsdp::get(&phys_mapper, rsdp_addr) {
+        Ok(rsdp) => log_info!("Rsdp validation returns {}", rsdp.validate_checksum()),
+        Err(()) => log_error!("Failed to map the rsdp"),
+    }
+
+    acpi::init(&phys_mapper, rsdp_addr)?;
+    log_info!(
+        "Found {} other processors, timer IRQ is GSI {:?}",
+        apic::application_processors().count(),
+        apic::legacy_irq_route(Irq::Timer as u8).map(|route| route.gsi)
+    );
+    Ok(())
+}
+
+// Before any driver hands its device a DMA address
+fn init_iommu(boot: &mut Boot) -> Result<(), ()> {
+    iommu::init(boot.frame_allocator());
+    Ok(())
+}
+
+fn init_power_button(boot: &mut Boot) -> Result<(), ()> {
+    let Some(irq) = pm::sci_irq() else {
+        return Ok(());
     };
 
-    println!("Rsdp addr is {:x}", rsdp_addr);
+    let idt = &mut *boot.idt;
+    interrupts::without_interrupts(|| {
+        idt.user_interrupts[irq as usize].set_handler_fn(sci_handler);
+        unsafe { PIC.lock().unmask_line(irq) };
+    });
 
-    // let ide_controller = IDEController::new();
+    match pm::enable() {
+        Ok(()) => log_info!("Power button enabled on IRQ {}", irq),
+        Err(()) => log_warn!("Failed to switch to ACPI mode"),
+    }
+    Ok(())
+}
 
-    let _ = KERNEL_PAGETABLE.set(RwLock::new(mapper));
+fn init_pci(_boot: &mut Boot) -> Result<(), ()> {
+    {
+        let mut pci = PCI_STATE.lock();
+        let count = unsafe { pci.enumerate() };
+        log_info!("Found {} PCI functions", count);
+        for device in pci.devices() {
+            log_debug!(
+                "{:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}:{:02x}:{:02x}",
+                device.bus,
+                device.slot,
+                device.func,
+                device.vendor_id,
+                device.device_id,
+                device.class,
+                device.subclass,
+                device.prog_if
+            );
+        }
+    }
+    // Before the drivers, so that their functions are only powered down once they've stopped
+    pci::power::init();
+    Ok(())
+}
 
-    let rsdp = unsafe { Rsdp::get(rsdp_addr as usize) };
-    println!("Rsdp validation returns {}", rsdp.validate_checksum());
-    println!("Attempting to get ahci state");
-    let _ = unsafe { AHCIState::new(&mut frame_allocator, 0, 0, 0) };
+#[cfg(feature = "driver-nvme")]
+fn init_nvme(boot: &mut Boot) -> Result<(), ()> {
+    let vector = boot.nvme_vector;
+    if boot.safe_mode {
+        free_vector(boot.idt, vector);
+        return Ok(());
+    }
 
-    match SATA_DISK0.get() {
-        Some(disk_lock) => {
-            {
-                let mut disk = disk_lock.write();
+    let result = unsafe { NVMeState::new(boot.frame_allocator(), 0, 0, 0, vector) };
+    match result {
+        Ok(()) => log_info!("Initialized NVMe controller"),
+        Err(()) => free_vector(boot.idt, vector),
+    }
+    result
+}
 
-                interrupts::without_interrupts(|| {
-                    idt.user_interrupts[disk.irq as usize].set_handler_fn(ahci_handler);
-                });
+#[cfg(feature = "driver-xhci")]
+fn init_xhci(boot: &mut Boot) -> Result<(), ()> {
+    let vector = boot.xhci_vector;
+    if boot.safe_mode {
+        free_vector(boot.idt, vector);
+        return Ok(());
+    }
 
-                unsafe { disk.enable_interrupts() };
-                println!(
-                    "Initialized AHCI disk, interrupts enabled: {}",
-                    interrupts::are_enabled()
-                );
-            }
+    let result = unsafe { XHCIState::new(boot.frame_allocator(), 0, 0, 0, vector) };
+    match result {
+        Ok(()) => log_info!("Initialized xHCI controller"),
+        Err(()) => free_vector(boot.idt, vector),
+    }
+    result
+}
 
-            // let mut buf: [MaybeUninit<u8>; 1024] = [MaybeUninit::uninit(); 1024];
-            // let res = AHCIState::read_or_write(disk_lock, ReadFPDMAQueued, &mut buf, 0);
+fn init_stacks(boot: &mut Boot) -> Result<(), ()> {
+    task::stack::init(boot.frame_allocator());
+    Ok(())
+}
 
-            let maybe_superblock = Superblock::new(disk_lock);
+#[cfg(feature = "selftest")]
+fn run_selftest(boot: &mut Boot) -> Result<(), ()> {
+    selftest::run(boot.frame_allocator())
+}
 
-            let superblock = match maybe_superblock {
-                Ok(sb) => sb,
-                Err(_) => panic!("Failed to read bytes from disk"),
-            };
+fn init_tasks(_boot: &mut Boot) -> Result<(), ()> {
+    unsafe { task::init() };
+    timer::init();
+    ps2::keyboard::start_command_timeouts();
+    if polling::is_polled("ps2") {
+        ps2::keyboard::start_polling();
+    }
+    machine_check::start_reporting();
+    pm::start_power_button();
+    iosched::init();
+    bcache::init();
+    executor::init();
+    Ok(())
+}
 
-            println!("Read superblock into disk");
-            println!("Superblock: {:?}", superblock);
+// Debug hotkeys, which work whatever else has the keyboard
+fn start_hotkeys(_boot: &mut Boot) -> Result<(), ()> {
+    task::spawn("hotkeys", task::Priority::High, || {
+        let hotkeys = input::subscribe(input::Priority::Hotkey, |event| {
+            matches!(
+                event,
+                InputEvent::Key(KeyEvent {
+                    key: KeyCode::SpecialDown(SpecialKey::F12)
+                        | KeyCode::SpecialUp(SpecialKey::F12),
+                    ..
+                })
+            )
+        });
+        loop {
+            if let InputEvent::Key(KeyEvent {
+                key: KeyCode::SpecialDown(SpecialKey::F12),
+                ..
+            }) = hotkeys.next()
+            {
+                println!();
+                shell::run_line("ps");
+            }
         }
-        None => panic!("Failed to initialize AHCI disk"),
+    })
+    .map(|_| ())
+}
+
+fn start_telemetry(_boot: &mut Boot) -> Result<(), ()> {
+    telemetry::start()
+}
+
+#[cfg(feature = "driver-ahci")]
+fn start_sata_unplug(_boot: &mut Boot) -> Result<(), ()> {
+    AHCIState::start_unplug_watch();
+    // Nothing else would notice the disk going away
+    if let (true, Some(disk_lock)) = (polling::is_polled("ahci"), SATA_DISK0.get()) {
+        polling::start("ahci", || {
+            interrupts::without_interrupts(|| disk_lock.write().handle_interrupt())
+        });
+    }
+    Ok(())
+}
+
+// Without MSI-X nothing tells us about new keyboard reports, so go and look for them
+#[cfg(feature = "driver-xhci")]
+fn start_usb_poll(_boot: &mut Boot) -> Result<(), ()> {
+    // Not there in safe mode
+    let Some(xhci) = xhcistate::XHCI0.get() else {
+        return Ok(());
     };
+    if xhci.lock().msix {
+        return Ok(());
+    }
+
+    task::spawn("usb-poll", task::Priority::Normal, || loop {
+        interrupts::without_interrupts(xhcistate::handle_interrupt);
+        input::pump();
+        sleep(1);
+    })
+    .map(|_| ())
 }
 
 use core::panic::PanicInfo;
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    interrupts::disable();
+    // Whatever was printing when we panicked isn't coming back to finish. Only this CPU runs.
+    unsafe { framebuffer::force_unlock() };
     println!("{}", info);
+    crashdump::print_backtrace();
+
+    #[cfg(feature = "qemu")]
+    if qemu::exits_when_done() {
+        qemu::exit(qemu::ExitCode::Failure);
+    }
+
+    if crashdump::write(info).is_ok() {
+        println!("Wrote crash dump to disk");
+    }
+
     loop {}
 }
 
 extern "x86-interrupt" fn keyboard_handler(_stack_frame: StackFrame) {
+    idt::count(PIC_IRQ_OFFSET + Irq::Keyboard as u8);
+    rand::add_interrupt_entropy();
     {
         let mut keyboard = KEYBOARD.lock();
         let key = { keyboard.read_byte() };
 
         match key {
             Ok(byte) => {
-                let _ = keyboard.push_key(byte);
+                let _ = keyboard.handle_byte(byte);
             }
             Err(_) => println!("Couldn't get key"),
         }
-
-        let _ = keyboard.send_next_command();
     }
 
+    input::pump();
+
     unsafe { PIC.lock().end_of_interrupt(Irq::Keyboard as u8) }
+
+    task::scheduler::preempt_if_needed();
 }
 
-extern "x86-interrupt" fn timer_handler(_stack_frame: StackFrame) {
-    use core::sync::atomic::Ordering::*;
-    let time = TIMER.load(SeqCst);
-    let _ = TIMER.compare_exchange_weak(time, time + 1, SeqCst, SeqCst);
+extern "x86-interrupt" fn timer_handler(stack_frame: StackFrame) {
+    idt::count(PIC_IRQ_OFFSET + Irq::Timer as u8);
+    rand::add_interrupt_entropy();
+    profiler::record(stack_frame.rip());
+    timer::tick(clock::tick());
     unsafe { PIC.lock().end_of_interrupt(Irq::Timer as u8) }
+
+    // May switch to another task; we will come back here when this one is scheduled again.
+    task::scheduler::tick();
 }
 
-fn sleep(milliseconds: u64) {
-    use core::sync::atomic::Ordering::*;
-    let time = TIMER.load(SeqCst);
-    while TIMER.load(SeqCst) < time + milliseconds {
-        // TODO: Do something else instead of just waiting; do other scheduled tasks.
-        unsafe { klib::x86_64::io_wait() };
-    }
+// Registered first, so it runs last: the teardowns before it may wait on interrupts
+fn mask_interrupts() -> Result<(), ()> {
+    interrupts::without_interrupts(|| unsafe { PIC.lock().disable() });
+    Ok(())
 }
 
-extern "x86-interrupt" fn ahci_handler(_stack_frame: StackFrame) {
+// The masks the PIC had before suspending, as it forgets them while asleep
+static PIC_MASKS: Mutex<(u8, u8)> = Mutex::new((0xFF, 0xFF));
+
+fn suspend_pic() -> Result<(), ()> {
+    interrupts::without_interrupts(|| unsafe {
+        let mut pic = PIC.lock();
+        *PIC_MASKS.lock() = pic.read_interrupt_masks();
+        pic.disable();
+    });
+    Ok(())
+}
+
+fn resume_pic() -> Result<(), ()> {
+    interrupts::without_interrupts(|| unsafe {
+        let mut pic = PIC.lock();
+        pic.initialize();
+        let (base, higher) = *PIC_MASKS.lock();
+        pic.write_interrupt_masks(base, higher);
+    });
+    Ok(())
+}
+
+#[cfg(feature = "driver-ahci")]
+fn stop_ahci() -> Result<(), ()> {
+    let disk = SATA_DISK0.get().ok_or(())?;
+    unsafe { disk.write().stop() }
+}
+
+#[cfg(feature = "driver-ahci")]
+fn suspend_ahci() -> Result<(), ()> {
+    let disk = SATA_DISK0.get().ok_or(())?;
+    unsafe { disk.write().suspend() }
+}
+
+#[cfg(feature = "driver-ahci")]
+fn resume_ahci() -> Result<(), ()> {
+    let disk = SATA_DISK0.get().ok_or(())?;
+    unsafe { disk.write().resume() }
+}
+
+// Bring up the first AHCI disk, and find where crash dumps can go on it
+#[cfg(feature = "driver-ahci")]
+fn init_ahci(boot: &mut Boot) -> Result<(), ()> {
+    log_info!("Attempting to get ahci state");
+    let _ = unsafe { AHCIState::new(boot.frame_allocator(), 0, 0, 0) };
+
+    let idt = &mut *boot.idt;
     match SATA_DISK0.get() {
         Some(disk_lock) => {
-            let mut lock_guard = disk_lock.write();
-            (*lock_guard).handle_interrupt();
-            unsafe { PIC.lock().end_of_interrupt((*lock_guard).irq as u8) };
+            {
+                let mut disk = disk_lock.write();
+
+                if polling::is_polled("ahci") {
+                    unsafe { disk.use_polling() };
+                } else {
+                    interrupts::without_interrupts(|| {
+                        idt.user_interrupts[disk.irq as usize].set_handler_fn(ahci_handler);
+                        unsafe { PIC.lock().unmask_line(disk.irq as u8) };
+                    });
+                    unsafe { disk.enable_interrupts() };
+                }
+                shutdown::register("ahci", stop_ahci).unwrap();
+                suspend::register("ahci", suspend_ahci, resume_ahci).unwrap();
+                log_info!(
+                    "Initialized AHCI disk, interrupts enabled: {}",
+                    interrupts::are_enabled()
+                );
+            }
+
+            // let mut buf: [MaybeUninit<u8>; 1024] = [MaybeUninit::uninit(); 1024];
+            // let res = AHCIState::read_or_write(disk_lock, ReadFPDMAQueued, &mut buf, 0);
+
+            let disk = AHCIState::device(disk_lock, 0).expect("No disk on the AHCI port");
+
+            // Without knowing where the filesystem ends, there's nowhere safe for crash dumps
+            #[cfg(feature = "fs-ext2")]
+            {
+                let maybe_superblock = Superblock::new(&disk);
+
+                let superblock = match maybe_superblock {
+                    Ok(sb) => sb,
+                    Err(_) => panic!("Failed to read bytes from disk"),
+                };
+
+                log_info!("Read superblock into disk");
+                log_info!("Superblock: {:?}", superblock);
+
+                match Ext2Fs::mount(&disk, MountOptions::default()) {
+                    Ok(ext2_fs) => {
+                        if let Err(err) = ext2::check(&ext2_fs, &disk) {
+                            log_warn!("Couldn't check the filesystem: {:?}", err);
+                        }
+                        if is_wanted_root(&ext2_fs) {
+                            let _ = fs::mount_root(ext2_fs, &disk.name());
+                        }
+                    }
+                    Err(err) => log_warn!("Couldn't mount the filesystem: {:?}", err),
+                }
+
+                // Whatever is past the end of the filesystem is free for crash dumps, if where
+                // that is can be trusted
+                let crashdump = superblock.check_geometry().map_err(|_| ()).and_then(|()| {
+                    let fs_size = superblock.blocks_count as u64 * superblock.block_size();
+                    crashdump::init(disk, fs_size as usize)
+                });
+                match crashdump {
+                    Ok(()) => log_info!("Crash dumps go to the end of the disk"),
+                    Err(()) => log_warn!("No room for crash dumps on the disk"),
+                }
+            }
+            #[cfg(not(feature = "fs-ext2"))]
+            let _ = disk;
         }
-        None => {
-            panic!("Unexpected call to AHCI handler");
+        None => return Err(()),
+    };
+    Ok(())
+}
+
+// Whether `fs` is the root `root=UUID=<uuid>` on the command line asks for. Without it, any
+// filesystem will do.
+#[cfg(feature = "fs-ext2")]
+fn is_wanted_root(fs: &Ext2Fs) -> bool {
+    let Some(wanted) = cmdline::value("root").and_then(|root| root.strip_prefix("UUID=")) else {
+        return true;
+    };
+    match Uuid::parse(wanted) {
+        Ok(uuid) if uuid == fs.uuid() => true,
+        Ok(_) => {
+            log_warn!(
+                "Not mounting {} as the root, {} was asked for",
+                fs.uuid(),
+                wanted
+            );
+            false
+        }
+        Err(()) => {
+            log_warn!(
+                "root=UUID={} isn't a UUID, mounting whatever is there",
+                wanted
+            );
+            true
         }
     }
 }
 
-extern "x86-interrupt" fn double_fault_handler(stack_frame: StackFrame, error_code: u64) -> ! {
-    println!("Double Fault: {:#?}\n{}", stack_frame, error_code);
-    loop {}
+// Take the handler off a vector no device ended up using, and give it back
+#[cfg(any(feature = "driver-nvme", feature = "driver-xhci"))]
+fn free_vector(idt: &mut idt::DescriptorTable, vector: u8) {
+    interrupts::without_interrupts(|| {
+        idt.user_interrupts[vector as usize - 32] = Default::default();
+    });
+    vectors::free(vector);
+}
+
+fn sleep(milliseconds: u64) {
+    task::sleep_ticks(milliseconds);
+}
+
+#[cfg(feature = "driver-ahci")]
+extern "x86-interrupt" fn ahci_handler(_stack_frame: StackFrame) {
+    rand::add_interrupt_entropy();
+    let disk_lock = SATA_DISK0.get_or_panic_with("Unexpected call to AHCI handler");
+    let mut lock_guard = disk_lock.write();
+    idt::count(PIC_IRQ_OFFSET + lock_guard.irq as u8);
+    (*lock_guard).handle_interrupt();
+    unsafe { PIC.lock().end_of_interrupt((*lock_guard).irq as u8) };
+}
+
+#[cfg(feature = "driver-ide")]
+extern "x86-interrupt" fn ide_primary_handler(_stack_frame: StackFrame) {
+    idt::count(PIC_IRQ_OFFSET + Irq::PrimaryAta as u8);
+    ide_controller::handle_interrupt(ChannelType::Primary);
+    unsafe { PIC.lock().end_of_interrupt(Irq::PrimaryAta as u8) }
+}
+
+#[cfg(feature = "driver-ide")]
+extern "x86-interrupt" fn ide_secondary_handler(_stack_frame: StackFrame) {
+    idt::count(PIC_IRQ_OFFSET + Irq::SecondaryAta as u8);
+    if unsafe { PIC.lock().is_spurious(Irq::SecondaryAta as u8) } {
+        return;
+    }
+    ide_controller::handle_interrupt(ChannelType::Secondary);
+    unsafe { PIC.lock().end_of_interrupt(Irq::SecondaryAta as u8) }
+}
+
+extern "x86-interrupt" fn sci_handler(_stack_frame: StackFrame) {
+    pm::handle_sci();
+
+    if let Some(irq) = pm::sci_irq() {
+        idt::count(PIC_IRQ_OFFSET + irq);
+        unsafe { PIC.lock().end_of_interrupt(irq) };
+    }
+}
+
+#[cfg(feature = "driver-nvme")]
+extern "x86-interrupt" fn nvme_handler(_stack_frame: StackFrame) {
+    idt::count(nvmestate::MSIX_VECTOR.load(Ordering::Relaxed));
+    rand::add_interrupt_entropy();
+    nvmestate::handle_interrupt();
+
+    if let Some(local_apic) = apic::LOCAL_APIC.get() {
+        local_apic.end_of_interrupt();
+    }
+
+    task::scheduler::preempt_if_needed();
+}
+
+#[cfg(feature = "driver-xhci")]
+extern "x86-interrupt" fn xhci_handler(_stack_frame: StackFrame) {
+    idt::count(xhcistate::MSIX_VECTOR.load(Ordering::Relaxed));
+    rand::add_interrupt_entropy();
+    xhcistate::handle_interrupt();
+    input::pump();
+
+    if let Some(local_apic) = apic::LOCAL_APIC.get() {
+        local_apic.end_of_interrupt();
+    }
+
+    task::scheduler::preempt_if_needed();
+}
+
+extern "x86-interrupt" fn pic_spurious_handler(_stack_frame: StackFrame) {
+    idt::count(PIC_IRQ_OFFSET + Irq::Spurious as u8);
+    // Nothing is on the line, but acknowledge it anyway if it somehow was a real interrupt
+    let mut pic = PIC.lock();
+    if !unsafe { pic.is_spurious(Irq::Spurious as u8) } {
+        unsafe { pic.end_of_interrupt(Irq::Spurious as u8) };
+    }
+}
+
+extern "x86-interrupt" fn spurious_handler(_stack_frame: StackFrame) {
+    idt::count(apic::SPURIOUS_VECTOR);
+}
+
+extern "x86-interrupt" fn tlb_shootdown_handler(_stack_frame: StackFrame) {
+    idt::count(tlb::SHOOTDOWN_VECTOR);
+    tlb::handle_shootdown();
+}
+
+// FPU state is switched eagerly and CR0.TS is never set, so this only happens if the FPU got
+// turned off
+extern "x86-interrupt" fn device_not_available_handler(stack_frame: StackFrame) {
+    panic!("FPU used while it is unavailable: {:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: StackFrame) {
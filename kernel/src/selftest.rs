@@ -0,0 +1,723 @@
+// Boot-time self-tests, built with the `selftest` feature. They run once the heap and the kernel
+// page table are up, print a line per test, and then exit QEMU through its isa-debug-exit device
+// (see klib/qemu.rs) so that `cargo run --features selftest` exits with whether they all passed.
+
+use crate::allocator;
+use crate::arch::x86_64::fpu::{self, FpuState};
+use crate::arch::x86_64::interrupts::idt::{EntryOptions, GateType, PrivilegeLevel};
+use crate::arch::x86_64::paging::BootInfoFrameAllocator;
+use crate::fs::ext2::{Ext2Fs, MountOptions, Superblock};
+use crate::klib::block::{BlockDevice, IOError};
+use crate::klib::containers::circular_buffer::CircularBuffer;
+use crate::klib::containers::intrusive::list::{List, ListLink};
+use crate::klib::containers::intrusive::rbtree::{RbTree, TreeLink, TreeNode};
+use crate::klib::hash::crc32::Crc32;
+use crate::klib::hash::crc32c::{self, Crc32c};
+use crate::klib::hash::sha256;
+use crate::klib::inflate;
+use crate::klib::once_lock::OnceLock;
+use crate::klib::qemu::{self, ExitCode};
+use crate::klib::rand::Xorshift;
+use crate::klib::tlb::MappingGuard;
+use crate::klib::util;
+use crate::task::stack::{self, Stack};
+use crate::KERNEL_PAGETABLE;
+use crate::{impl_linked, println};
+use alloc::alloc::{alloc, dealloc};
+use alloc::format;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::structures::paging::{FrameAllocator, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+// A page nothing else maps, for the paging tests
+const SCRATCH_ADDR: u64 = 0x_5555_5550_0000;
+
+// A 1 MiB ext2 filesystem with 1 KiB blocks, 4 groups of 16 256-byte inodes, and /dir/file00 to
+// /dir/file39 holding "file 00\n" to "file 39\n", so the files' inodes are spread over every
+// group. Made with
+//
+//     mkdir -p root/dir && for i in $(seq -w 0 39); do echo "file $i" > root/dir/file$i; done
+//     mke2fs -t ext2 -b 1024 -g 256 -N 64 -I 256 -d root ext2.img 1024 && gzip -9 -n ext2.img
+const EXT2_IMAGE: &[u8] = include_bytes!("../testdata/ext2.img.gz");
+const EXT2_FILES: usize = 40;
+
+type TestResult = Result<(), &'static str>;
+
+struct Test {
+    name: &'static str,
+    run: fn(&mut BootInfoFrameAllocator) -> TestResult,
+}
+
+const TESTS: &[Test] = &[
+    Test {
+        name: "buddy alloc/free patterns",
+        run: buddy_patterns,
+    },
+    Test {
+        name: "buddy coalescing",
+        run: buddy_coalescing,
+    },
+    Test {
+        name: "buddy merges with lower buddies",
+        run: buddy_merge_order,
+    },
+    Test {
+        name: "buddy free list heads",
+        run: buddy_list_heads,
+    },
+    Test {
+        name: "sleb size classes",
+        run: sleb_size_classes,
+    },
+    Test {
+        name: "circular buffer",
+        run: circular_buffer,
+    },
+    Test {
+        name: "intrusive list",
+        run: intrusive_list,
+    },
+    Test {
+        name: "intrusive rb-tree",
+        run: intrusive_rbtree,
+    },
+    Test {
+        name: "once lock",
+        run: once_lock,
+    },
+    Test {
+        name: "paging map/unmap",
+        run: paging,
+    },
+    Test {
+        name: "idt entry options",
+        run: entry_options,
+    },
+    Test {
+        name: "fpu state save/restore",
+        run: fpu_state,
+    },
+    Test {
+        name: "mapped stacks",
+        run: mapped_stacks,
+    },
+    Test {
+        name: "ext2 mke2fs image",
+        run: ext2_image,
+    },
+    Test {
+        name: "ext2 bad geometry",
+        run: ext2_bad_geometry,
+    },
+    Test {
+        name: "hash known answers",
+        run: hash_known_answers,
+    },
+];
+
+// Fails the test with the line and condition if the condition doesn't hold
+macro_rules! check {
+    ($cond:expr) => {
+        if !$cond {
+            return Err(concat!("line ", line!(), ": ", stringify!($cond)));
+        }
+    };
+}
+
+/// Run every test, then exit QEMU with whether they all passed.
+pub fn run(frame_allocator: &mut BootInfoFrameAllocator) -> ! {
+    println!("Running {} self-tests", TESTS.len());
+
+    let mut failed = 0;
+    for test in TESTS {
+        match (test.run)(frame_allocator) {
+            Ok(()) => println!("[PASS] {}", test.name),
+            Err(reason) => {
+                println!("[FAIL] {}: {}", test.name, reason);
+                failed += 1;
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", TESTS.len() - failed, failed);
+    qemu::exit(if failed == 0 {
+        ExitCode::Success
+    } else {
+        ExitCode::Failure
+    })
+}
+
+fn free_bytes() -> Result<u64, &'static str> {
+    allocator::free_bytes().ok_or("heap is locked")
+}
+
+// Allocate from the heap, checking the block is usable and aligned
+fn alloc_checked(layout: Layout) -> Result<*mut u8, &'static str> {
+    let ptr = unsafe { alloc(layout) };
+    if ptr.is_null() {
+        return Err("allocation failed");
+    }
+    if ptr as usize % layout.align() != 0 {
+        return Err("allocation is misaligned");
+    }
+
+    unsafe { ptr.write_bytes(0x5A, layout.size()) };
+    Ok(ptr)
+}
+
+// Whether none of the blocks overlap
+fn disjoint(blocks: &[(*mut u8, Layout)]) -> bool {
+    blocks.iter().enumerate().all(|(i, &(a, a_layout))| {
+        blocks[i + 1..].iter().all(|&(b, b_layout)| {
+            let (a, b) = (a as usize, b as usize);
+            a + a_layout.size() <= b || b + b_layout.size() <= a
+        })
+    })
+}
+
+fn buddy_patterns(_frames: &mut BootInfoFrameAllocator) -> TestResult {
+    let before = free_bytes()?;
+    let sizes = [4096, 8192, 4096, 16384, 4096, 32768, 8192, 4096];
+
+    let mut blocks = Vec::with_capacity(sizes.len());
+    for &size in sizes.iter() {
+        let layout = Layout::from_size_align(size, 4096).unwrap();
+        blocks.push((alloc_checked(layout)?, layout));
+    }
+    check!(disjoint(&blocks));
+    check!(free_bytes()? < before);
+
+    // Every other block first, then the rest backwards, so that buddies come back out of order
+    for &(ptr, layout) in blocks.iter().step_by(2) {
+        unsafe { dealloc(ptr, layout) };
+    }
+    for &(ptr, layout) in blocks.iter().skip(1).step_by(2).rev() {
+        unsafe { dealloc(ptr, layout) };
+    }
+
+    drop(blocks);
+    check!(free_bytes()? == before);
+    Ok(())
+}
+
+fn buddy_coalescing(_frames: &mut BootInfoFrameAllocator) -> TestResult {
+    let page = Layout::from_size_align(4096, 4096).unwrap();
+    let before = allocator::free_blocks().ok_or("heap is locked")?;
+
+    // Split blocks all the way down to pages, then free the pages. Once every buddy has merged
+    // back, the free lists are the same as they started out.
+    let mut pages = [core::ptr::null_mut(); 64];
+    for ptr in pages.iter_mut() {
+        *ptr = alloc_checked(page)?;
+    }
+    check!(allocator::free_blocks() != Some(before));
+
+    for &ptr in pages.iter().rev().step_by(2).chain(pages.iter().step_by(2)) {
+        unsafe { dealloc(ptr, page) };
+    }
+    check!(allocator::free_blocks() == Some(before));
+
+    Ok(())
+}
+
+fn buddy_merge_order(_frames: &mut BootInfoFrameAllocator) -> TestResult {
+    let page = Layout::from_size_align(4096, 4096).unwrap();
+    let before = allocator::free_blocks().ok_or("heap is locked")?;
+
+    let mut pages = [core::ptr::null_mut(); 16];
+    for ptr in pages.iter_mut() {
+        *ptr = alloc_checked(page)?;
+    }
+
+    // In the order they were handed out, so that lower buddies are freed before upper ones
+    for &ptr in pages.iter() {
+        unsafe { dealloc(ptr, page) };
+        allocator::validate().map_err(|error| error.problem)?;
+    }
+    check!(allocator::free_blocks() == Some(before));
+
+    Ok(())
+}
+
+fn buddy_list_heads(_frames: &mut BootInfoFrameAllocator) -> TestResult {
+    let page = Layout::from_size_align(4096, 4096).unwrap();
+    let before = allocator::free_blocks().ok_or("heap is locked")?;
+
+    let mut pages = [core::ptr::null_mut(); 16];
+    for ptr in pages.iter_mut() {
+        *ptr = alloc_checked(page)?;
+    }
+
+    // Lower halves of pairs of buddies that were both handed out, with their upper halves
+    let offset = |ptr: *mut u8| ptr as u64 - allocator::heap_start();
+    let pairs: Vec<(*mut u8, *mut u8)> = pages
+        .iter()
+        .filter(|&&lower| offset(lower) & allocator::PAGESIZE == 0)
+        .filter_map(|&lower| {
+            let upper = pages
+                .iter()
+                .find(|&&upper| offset(upper) == offset(lower) + allocator::PAGESIZE)?;
+            Some((lower, *upper))
+        })
+        .collect();
+    check!(pairs.len() >= 2);
+    let (first, first_buddy) = pairs[0];
+    let (second, second_buddy) = pairs[1];
+
+    // Both go on the free list for single pages, as their buddies are still in use. Taking the
+    // second back off the head leaves the first at the head.
+    unsafe {
+        dealloc(first, page);
+        dealloc(second, page);
+    }
+    let again = alloc_checked(page)?;
+    allocator::validate().map_err(|error| error.problem)?;
+
+    // Merging the first with its buddy takes it off the list from the head
+    unsafe { dealloc(first_buddy, page) };
+    allocator::validate().map_err(|error| error.problem)?;
+
+    let taken = [first, first_buddy, second, second_buddy];
+    for &ptr in pages.iter().filter(|ptr| !taken.contains(ptr)) {
+        unsafe { dealloc(ptr, page) };
+    }
+    unsafe {
+        dealloc(again, page);
+        dealloc(second_buddy, page);
+    }
+    allocator::validate().map_err(|error| error.problem)?;
+    check!(allocator::free_blocks() == Some(before));
+
+    Ok(())
+}
+
+fn sleb_size_classes(_frames: &mut BootInfoFrameAllocator) -> TestResult {
+    // The smallest, largest and one in-between size of each class
+    const SIZES: [usize; 12] = [1, 32, 33, 64, 100, 128, 200, 256, 512, 1000, 1024, 2048];
+
+    let before = free_bytes()?;
+    let mut blocks = Vec::with_capacity(2 * SIZES.len());
+    for &size in SIZES.iter() {
+        let layout = Layout::from_size_align(size, 16).unwrap();
+        for _ in 0..2 {
+            blocks.push((alloc_checked(layout)?, layout));
+        }
+    }
+    check!(disjoint(&blocks));
+
+    // Every block keeps its own contents
+    for (i, &(ptr, layout)) in blocks.iter().enumerate() {
+        unsafe { ptr.write_bytes(i as u8, layout.size()) };
+    }
+    for (i, &(ptr, layout)) in blocks.iter().enumerate() {
+        let bytes = unsafe { core::slice::from_raw_parts(ptr, layout.size()) };
+        check!(bytes.iter().all(|&b| b == i as u8));
+    }
+
+    for &(ptr, layout) in blocks.iter() {
+        unsafe { dealloc(ptr, layout) };
+    }
+    drop(blocks);
+
+    // All of that came out of the slab allocator's own pages, not the buddy allocator
+    check!(free_bytes()? == before);
+    Ok(())
+}
+
+static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+struct CountsDrops(u32);
+
+impl Drop for CountsDrops {
+    fn drop(&mut self) {
+        DROPS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn circular_buffer(_frames: &mut BootInfoFrameAllocator) -> TestResult {
+    let mut buffer: CircularBuffer<4, u32> = CircularBuffer::new();
+    check!(buffer.is_empty() && buffer.pop_front().is_none());
+
+    // Wrap around a few times
+    for i in 0..10 {
+        buffer.push_back(i);
+        check!(buffer.pop_front() == Some(i));
+    }
+
+    // Once full, pushing drops the oldest item
+    for i in 0..6 {
+        buffer.push_back(i);
+    }
+    check!(buffer.is_full() && buffer.len() == 4);
+    check!(buffer.peek_front() == Some(&2));
+    check!(buffer.get(3) == Some(&5) && buffer.get(4).is_none());
+    check!(buffer.drain().eq(2..6));
+    check!(buffer.is_empty());
+
+    DROPS.store(0, Ordering::Relaxed);
+    {
+        let mut buffer: CircularBuffer<4, CountsDrops> = CircularBuffer::new();
+        for i in 0..6 {
+            buffer.push_back(CountsDrops(i));
+        }
+        check!(DROPS.load(Ordering::Relaxed) == 2);
+        check!(buffer.pop_front().map(|item| item.0) == Some(2));
+        check!(DROPS.load(Ordering::Relaxed) == 3);
+    }
+    check!(DROPS.load(Ordering::Relaxed) == 6);
+
+    Ok(())
+}
+
+struct Node {
+    value: u32,
+    list_link: ListLink,
+    tree_link: TreeLink,
+}
+
+impl Node {
+    fn new(value: u32) -> Self {
+        Self {
+            value,
+            list_link: ListLink::new(),
+            tree_link: TreeLink::new(),
+        }
+    }
+}
+
+impl_linked!(Node, list_link: ListLink);
+impl_linked!(Node, tree_link: TreeLink);
+
+impl TreeNode for Node {
+    type Key = u32;
+
+    fn key(&self) -> u32 {
+        self.value
+    }
+}
+
+fn intrusive_list(_frames: &mut BootInfoFrameAllocator) -> TestResult {
+    let nodes: Vec<Node> = (0..8).map(Node::new).collect();
+    let mut list: List<Node> = List::new();
+    check!(list.is_empty() && list.pop_front().is_none());
+
+    for node in &nodes[2..6] {
+        check!(list.push_back(node).is_ok());
+    }
+    check!(list.push_front(&nodes[1]).is_ok());
+    check!(list.insert_after(&nodes[3], &nodes[7]).is_ok());
+    check!(list.iter().map(|node| node.value).eq([1, 2, 3, 7, 4, 5]));
+    check!(list.len() == 6 && list.iter().len() == 6);
+
+    // Nodes can only be in one list at a time, and only removed from the one they're in
+    check!(list.push_back(&nodes[2]).is_err());
+    check!(list.remove(&nodes[3]) && !list.remove(&nodes[3]));
+    check!(!list.remove(&nodes[0]));
+    check!(list.insert_after(&nodes[0], &nodes[6]).is_err() && !nodes[6].list_link.is_linked());
+    check!(list.iter().map(|node| node.value).eq([1, 2, 7, 4, 5]));
+
+    let mut other: List<Node> = List::new();
+    check!(other.push_back(&nodes[2]).is_err() && !other.remove(&nodes[2]));
+
+    check!(list.pop_front().map(|node| node.value) == Some(1));
+    check!(list.pop_back().map(|node| node.value) == Some(5));
+    check!(list.front().map(|node| node.value) == Some(2));
+    check!(list.back().map(|node| node.value) == Some(4));
+    check!(!nodes[1].list_link.is_linked() && !nodes[5].list_link.is_linked());
+
+    list.clear();
+    check!(list.is_empty() && nodes.iter().all(|node| !node.list_link.is_linked()));
+    check!(other.push_back(&nodes[2]).is_ok() && other.contains(&nodes[2]));
+    Ok(())
+}
+
+fn intrusive_rbtree(_frames: &mut BootInfoFrameAllocator) -> TestResult {
+    const COUNT: u32 = 200;
+    let mut rng = Xorshift::new(0x5EED);
+
+    // Inserted in a random order
+    let mut nodes: Vec<Node> = (0..COUNT).map(Node::new).collect();
+    rng.shuffle(&mut nodes);
+    let mut tree: RbTree<Node> = RbTree::new();
+    for node in &nodes {
+        check!(tree.insert(node).is_ok());
+        tree.validate()?;
+    }
+    check!(tree.len() == COUNT as usize);
+    check!(tree.iter().map(|node| node.value).eq(0..COUNT));
+    check!(tree.insert(&nodes[0]).is_err());
+    check!(tree.get(&17).map(|node| node.value) == Some(17) && tree.get(&COUNT).is_none());
+    check!(tree.first().map(|node| node.value) == Some(0));
+    check!(tree.last().map(|node| node.value) == Some(COUNT - 1));
+
+    // Then half of them removed in another random order
+    let mut order: Vec<usize> = (0..nodes.len()).collect();
+    rng.shuffle(&mut order);
+    let (removed, kept) = order.split_at(order.len() / 2);
+    for &i in removed {
+        check!(tree.remove(&nodes[i]) && !tree.remove(&nodes[i]));
+        tree.validate()?;
+    }
+    let mut expected: Vec<u32> = kept.iter().map(|&i| nodes[i].value).collect();
+    expected.sort_unstable();
+    check!(tree.len() == expected.len());
+    check!(tree
+        .iter()
+        .map(|node| node.value)
+        .eq(expected.iter().copied()));
+    check!(removed.iter().all(|&i| !nodes[i].tree_link.is_linked()));
+
+    for &value in &expected {
+        check!(tree.pop_first().map(|node| node.value) == Some(value));
+        tree.validate()?;
+    }
+    check!(tree.is_empty() && tree.first().is_none());
+    Ok(())
+}
+
+fn once_lock(_frames: &mut BootInfoFrameAllocator) -> TestResult {
+    let lock: OnceLock<u32> = OnceLock::new();
+    check!(lock.get().is_none());
+    check!(lock.set(1).is_ok());
+    check!(lock.set(2) == Err(2));
+    check!(lock.get() == Some(&1));
+    check!(*lock.get_or_init(|| 3) == 1);
+
+    let lock: OnceLock<u32> = OnceLock::new();
+    check!(*lock.get_or_init(|| 4) == 4);
+    check!(lock.into_inner() == Some(4));
+    Ok(())
+}
+
+fn paging(frames: &mut BootInfoFrameAllocator) -> TestResult {
+    let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(SCRATCH_ADDR));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let phys_offset = KERNEL_PAGETABLE
+        .get()
+        .ok_or("no kernel page table")?
+        .read()
+        .phys_offset()
+        .as_u64();
+
+    check!(util::try_kernel_to_physical_address(SCRATCH_ADDR).is_none());
+
+    // The frame is never given back, as the boot frame allocator can't take frames back
+    let frame = frames.allocate_frame().ok_or("out of frames")?;
+    let frame_addr = frame.start_address().as_u64();
+    {
+        let mut guard = MappingGuard::lock().ok_or("no kernel page table")?;
+        unsafe { guard.map(page, frame, flags, frames) }.map_err(|_| "map failed")?;
+    }
+    check!(util::try_kernel_to_physical_address(SCRATCH_ADDR + 0x123) == Some(frame_addr + 0x123));
+
+    // What goes in through the new mapping comes out of the frame
+    let words = SCRATCH_ADDR as *mut u64;
+    let alias = (phys_offset + frame_addr) as *const u64;
+    for i in 0..512 {
+        unsafe { words.add(i).write_volatile(i as u64 * 0x0101_0101) };
+    }
+    for i in 0..512 {
+        check!(unsafe { alias.add(i).read_volatile() } == i as u64 * 0x0101_0101);
+    }
+
+    {
+        let mut guard = MappingGuard::lock().ok_or("no kernel page table")?;
+        check!(matches!(guard.unmap(page), Ok(unmapped) if unmapped == frame));
+    }
+    check!(util::try_kernel_to_physical_address(SCRATCH_ADDR).is_none());
+
+    Ok(())
+}
+
+fn entry_options(_frames: &mut BootInfoFrameAllocator) -> TestResult {
+    let mut options = EntryOptions::minimal();
+    check!(options.bits() == 0x0E00);
+    check!(!options.present() && options.gate_type() == GateType::Interrupt);
+
+    // Each field can be set and changed back without touching the others
+    options.set_present(true);
+    options.set_privilege_level(PrivilegeLevel::User);
+    options.set_gate_type(GateType::Trap);
+    unsafe { options.set_stack_index(6) };
+    check!(options.bits() == 0xEF07);
+
+    options.disable_interrupts_when_invoked(true);
+    check!(options.bits() == 0xEE07);
+    options.set_privilege_level(PrivilegeLevel::Kernel);
+    check!(options.bits() == 0x8E07);
+    unsafe { options.set_stack_index(0) };
+    check!(options.stack_index() == Some(0) && options.bits() == 0x8E01);
+    options.clear_stack_index();
+    check!(options.stack_index().is_none());
+    options.set_present(false);
+    check!(options.bits() == 0x0E00);
+
+    Ok(())
+}
+
+fn fpu_state(_frames: &mut BootInfoFrameAllocator) -> TestResult {
+    // Round toward zero, everything still masked
+    const CHANGED_MXCSR: u32 = fpu::DEFAULT_MXCSR | 0x6000;
+
+    let mut saved = FpuState::new();
+    unsafe {
+        fpu::set_mxcsr(CHANGED_MXCSR);
+        saved.save();
+
+        FpuState::new().restore();
+        check!(fpu::mxcsr() == fpu::DEFAULT_MXCSR);
+
+        saved.restore();
+        check!(fpu::mxcsr() == CHANGED_MXCSR);
+
+        fpu::set_mxcsr(fpu::DEFAULT_MXCSR);
+    }
+
+    Ok(())
+}
+
+fn mapped_stacks(_frames: &mut BootInfoFrameAllocator) -> TestResult {
+    // Roughly 600 bytes of stack per level
+    #[inline(never)]
+    fn sum_to(n: u64) -> u64 {
+        let frame = core::hint::black_box([n; 64]);
+        match n {
+            0 => 0,
+            _ => frame[63] + sum_to(n - 1),
+        }
+    }
+
+    let stack = Stack::map(3 * 4096 + 1).map_err(|_| "map failed")?;
+    let bottom = stack.bottom() as u64;
+    check!(stack.len() == 4 * 4096);
+    check!(util::try_kernel_to_physical_address(bottom).is_some());
+    check!(util::try_kernel_to_physical_address(stack.top() - 1).is_some());
+    // The guard page
+    check!(util::try_kernel_to_physical_address(bottom - 1).is_none());
+    drop(stack);
+    check!(util::try_kernel_to_physical_address(bottom).is_none());
+
+    // Deeper than the boot stack these tests run on
+    check!(stack::with_stack(256 * 1024, || sum_to(200)) == Ok(20100));
+    check!(stack::with_stack(stack::MAX_STACK_SIZE + 1, || ()).is_err());
+
+    Ok(())
+}
+
+// A read-only disk in memory
+struct MemoryDisk(Vec<u8>);
+
+impl BlockDevice for MemoryDisk {
+    fn block_size(&self) -> usize {
+        512
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.0.len() / 512
+    }
+
+    fn read<'a>(
+        &self,
+        buf: &'a mut [MaybeUninit<u8>],
+        offset: usize,
+    ) -> Result<&'a mut [u8], IOError> {
+        let data = self
+            .0
+            .get(offset..offset + buf.len())
+            .ok_or(IOError::Invalid)?;
+        for (dst, &src) in buf.iter_mut().zip(data) {
+            dst.write(src);
+        }
+        Ok(unsafe { MaybeUninit::slice_assume_init_mut(buf) })
+    }
+
+    fn write(&self, _buf: &[u8], _offset: usize) -> Result<(), IOError> {
+        Err(IOError::ReadOnly)
+    }
+}
+
+fn ext2_disk() -> Result<MemoryDisk, &'static str> {
+    let image =
+        inflate::gunzip(EXT2_IMAGE, 1024 * 1024).map_err(|_| "couldn't unpack the image")?;
+    Ok(MemoryDisk(image))
+}
+
+fn ext2_image(_frames: &mut BootInfoFrameAllocator) -> TestResult {
+    let disk = ext2_disk()?;
+    let fs = Ext2Fs::mount(&disk, MountOptions::default()).map_err(|_| "mount failed")?;
+    let inodes_per_group = Superblock::new(&disk)
+        .map_err(|_| "no superblock")?
+        .inodes_per_group;
+
+    let mut buf = [MaybeUninit::uninit(); 16];
+    let mut last_group = 0;
+    for i in 0..EXT2_FILES {
+        let path = format!("/dir/file{:02}", i);
+        let inode = fs.lookup(&disk, &path).map_err(|_| "lookup failed")?;
+        let data = fs
+            .read_file(&disk, inode, 0, &mut buf)
+            .map_err(|_| "read failed")?;
+        check!(*data == *format!("file {:02}\n", i).as_bytes());
+        last_group = last_group.max((inode - 1) / inodes_per_group);
+    }
+    // The table of every group has to have been found, not just the first one's
+    check!(last_group >= 1);
+    check!(fs.lookup(&disk, "/dir/file40").is_err());
+    Ok(())
+}
+
+fn ext2_bad_geometry(_frames: &mut BootInfoFrameAllocator) -> TestResult {
+    // Byte offsets in the superblock, which starts 1024 bytes in
+    const BLOCKS_COUNT: usize = 1024 + 4;
+    const LOG_BLOCK_SIZE: usize = 1024 + 24;
+    const BLOCKS_PER_GROUP: usize = 1024 + 32;
+    const INODE_SIZE: usize = 1024 + 88;
+
+    let corruptions: [(usize, &[u8]); 7] = [
+        (LOG_BLOCK_SIZE, &[7]),
+        (LOG_BLOCK_SIZE, &[40]),
+        (BLOCKS_PER_GROUP, &[0, 0]),
+        (BLOCKS_COUNT, &[0xFF, 0xFF, 0xFF, 0xFF]),
+        (INODE_SIZE, &[0, 0]),
+        (INODE_SIZE, &[200, 0]),
+        (INODE_SIZE, &[0, 8]),
+    ];
+    for (offset, bytes) in corruptions {
+        let mut disk = ext2_disk()?;
+        disk.0[offset..offset + bytes.len()].copy_from_slice(bytes);
+        check!(matches!(
+            Ext2Fs::mount(&disk, MountOptions::default()),
+            Err(IOError::BadData)
+        ));
+    }
+    Ok(())
+}
+
+fn hash_known_answers(_frames: &mut BootInfoFrameAllocator) -> TestResult {
+    const CHECK: &[u8] = b"123456789";
+    const ABC_SHA256: [u8; sha256::DIGEST_SIZE] = [
+        0xBA, 0x78, 0x16, 0xBF, 0x8F, 0x01, 0xCF, 0xEA, 0x41, 0x41, 0x40, 0xDE, 0x5D, 0xAE, 0x22,
+        0x23, 0xB0, 0x03, 0x61, 0xA3, 0x96, 0x17, 0x7A, 0x9C, 0xB4, 0x10, 0xFF, 0x61, 0xF2, 0x00,
+        0x15, 0xAD,
+    ];
+
+    let mut crc = Crc32::new();
+    crc.update(CHECK);
+    check!(crc.finish() == 0xCBF4_3926);
+
+    check!(crc32c::checksum(CHECK) == 0xE306_9283);
+    check!(crc32c::checksum_software(CHECK) == 0xE306_9283);
+    // Long enough for the hardware to go a word at a time, split off the word boundaries
+    let data: Vec<u8> = (0..=255).collect();
+    let mut crc = Crc32c::new();
+    crc.update(&data[..13]);
+    crc.update(&data[13..]);
+    check!(crc.finish() == crc32c::checksum_software(&data));
+
+    check!(sha256::digest(b"abc") == ABC_SHA256);
+
+    Ok(())
+}
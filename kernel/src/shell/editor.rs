@@ -0,0 +1,315 @@
+// A small full-screen text editor for the console, like a much smaller nano, run by the `edit`
+// command. It runs in a task of its own, which takes the keyboard with an input grab, and the
+// shell waits for it to quit. Files are read and saved through `fs`, so they can only be saved
+// where `fs::write` can write them.
+//
+// All of the screen but the bottom row shows the text, and the bottom row is the status line.
+// Lines wider than the screen scroll sideways as the cursor moves along them.
+
+use crate::fs;
+use crate::klib::block::IOError;
+use crate::klib::graphics::framebuffer;
+use crate::klib::input::{self, InputEvent};
+use crate::klib::ps2::keyboard::{ExtendedKeyCode, KeyCode, KeyEvent, SpecialKey};
+use crate::print;
+use crate::task::{self, Priority, WaitQueue};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const TAB_WIDTH: usize = 4;
+
+struct Editor {
+    path: String,
+    // Bytes and columns line up: anything that isn't printable ASCII is shown as '?'
+    lines: Vec<Vec<u8>>,
+    // Where the cursor is in the text
+    row: usize,
+    column: usize,
+    // The first line and column on screen
+    top: usize,
+    left: usize,
+    // Size of the text area, which leaves out the status line
+    columns: usize,
+    rows: usize,
+    modified: bool,
+    // Set by Ctrl+Q with unsaved changes, so that a second one quits anyway
+    quitting: bool,
+    message: String,
+    // Whether every line has to be drawn again, rather than just the cursor's
+    redraw_all: bool,
+}
+
+/// Edit the file at `path` until the editor is quit. The editor runs in a task of its own; the
+/// caller is blocked until it is done.
+pub fn run(path: &str) -> Result<(), ()> {
+    let (columns, rows) = framebuffer::text_size().ok_or(())?;
+    if rows < 2 || columns == 0 {
+        return Err(());
+    }
+
+    let done = Arc::new((AtomicBool::new(false), WaitQueue::new()));
+    let finished = done.clone();
+    let path = String::from(path);
+    task::spawn("editor", Priority::Normal, move || {
+        Editor::open(path, columns, rows - 1).run();
+        finished.0.store(true, Ordering::SeqCst);
+        finished.1.wake_all();
+    })?;
+
+    let (quit, quit_queue) = &*done;
+    while !quit.load(Ordering::SeqCst) {
+        quit_queue.wait_while(|| !quit.load(Ordering::SeqCst));
+    }
+    Ok(())
+}
+
+impl Editor {
+    fn open(path: String, columns: usize, rows: usize) -> Self {
+        let (lines, message) = match fs::read(&path) {
+            // Splitting "a\n" gives "a" and "", so joining the lines back up gives the same file
+            Ok(data) => (
+                data.split(|&byte| byte == b'\n')
+                    .map(<[u8]>::to_vec)
+                    .collect(),
+                String::from("^S save  ^Q quit"),
+            ),
+            Err(IOError::NotFound) => (alloc::vec![Vec::new()], String::from("New file")),
+            Err(err) => (
+                alloc::vec![Vec::new()],
+                format!("Couldn't read the file: {:?}", err),
+            ),
+        };
+
+        Self {
+            path,
+            lines,
+            row: 0,
+            column: 0,
+            top: 0,
+            left: 0,
+            columns,
+            rows,
+            modified: false,
+            quitting: false,
+            message,
+            redraw_all: true,
+        }
+    }
+
+    fn run(&mut self) {
+        let keys = input::subscribe(input::Priority::Normal, |event| {
+            matches!(event, InputEvent::Key(_))
+        });
+        if !keys.grab() {
+            return;
+        }
+
+        framebuffer::clear_screen();
+        self.draw();
+        loop {
+            let InputEvent::Key(key) = keys.next() else {
+                continue;
+            };
+            if !self.handle_key(key) {
+                break;
+            }
+            self.draw();
+        }
+
+        keys.release_grab();
+        framebuffer::clear_screen();
+    }
+
+    // Returns false once the editor should quit
+    fn handle_key(&mut self, event: KeyEvent) -> bool {
+        use KeyCode::*;
+
+        if event.modifiers.ctrl() {
+            match event.ascii() {
+                Some('s' | 'S') => self.save(),
+                Some('q' | 'Q') => {
+                    if !self.modified || self.quitting {
+                        return false;
+                    }
+                    self.quitting = true;
+                    self.message = String::from("Unsaved changes! ^Q again to quit anyway");
+                }
+                _ => {}
+            }
+            return true;
+        }
+        self.quitting = false;
+
+        if let Some(ch) = event.ascii() {
+            self.insert(ch as u8);
+            return true;
+        }
+
+        match event.key {
+            SpecialDown(SpecialKey::Enter) => self.split_line(),
+            SpecialDown(SpecialKey::Backspace) => {
+                if self.column > 0 {
+                    self.column -= 1;
+                    self.lines[self.row].remove(self.column);
+                    self.modified = true;
+                } else if self.row > 0 {
+                    self.row -= 1;
+                    self.column = self.lines[self.row].len();
+                    self.join_next_line();
+                }
+            }
+            ExtendedDown(ExtendedKeyCode::Delete) => {
+                if self.column < self.lines[self.row].len() {
+                    self.lines[self.row].remove(self.column);
+                    self.modified = true;
+                } else {
+                    self.join_next_line();
+                }
+            }
+            SpecialDown(SpecialKey::Tab) => {
+                for _ in 0..TAB_WIDTH - self.column % TAB_WIDTH {
+                    self.insert(b' ');
+                }
+            }
+            ExtendedDown(ExtendedKeyCode::CursorLeft) => {
+                if self.column > 0 {
+                    self.column -= 1;
+                } else if self.row > 0 {
+                    self.row -= 1;
+                    self.column = self.lines[self.row].len();
+                }
+            }
+            ExtendedDown(ExtendedKeyCode::CursorRight) => {
+                if self.column < self.lines[self.row].len() {
+                    self.column += 1;
+                } else if self.row + 1 < self.lines.len() {
+                    self.row += 1;
+                    self.column = 0;
+                }
+            }
+            ExtendedDown(ExtendedKeyCode::CursorUp) => self.move_rows(-1),
+            ExtendedDown(ExtendedKeyCode::CursorDown) => self.move_rows(1),
+            ExtendedDown(ExtendedKeyCode::PageUp) => self.move_rows(-(self.rows as isize)),
+            ExtendedDown(ExtendedKeyCode::PageDown) => self.move_rows(self.rows as isize),
+            ExtendedDown(ExtendedKeyCode::Home) => self.column = 0,
+            ExtendedDown(ExtendedKeyCode::End) => self.column = self.lines[self.row].len(),
+            _ => {}
+        }
+        true
+    }
+
+    fn insert(&mut self, byte: u8) {
+        self.lines[self.row].insert(self.column, byte);
+        self.column += 1;
+        self.modified = true;
+    }
+
+    fn split_line(&mut self) {
+        let rest = self.lines[self.row].split_off(self.column);
+        self.row += 1;
+        self.column = 0;
+        self.lines.insert(self.row, rest);
+        self.modified = true;
+        self.redraw_all = true;
+    }
+
+    // Append the line after the cursor's to it
+    fn join_next_line(&mut self) {
+        if self.row + 1 >= self.lines.len() {
+            return;
+        }
+        let next = self.lines.remove(self.row + 1);
+        self.lines[self.row].extend_from_slice(&next);
+        self.modified = true;
+        self.redraw_all = true;
+    }
+
+    // Move the cursor up (negative) or down by `rows` lines, staying in the text
+    fn move_rows(&mut self, rows: isize) {
+        let last = self.lines.len() - 1;
+        self.row = self.row.saturating_add_signed(rows).min(last);
+        self.column = self.column.min(self.lines[self.row].len());
+    }
+
+    fn save(&mut self) {
+        let data = self.lines.join(&b'\n');
+        self.message = match fs::write(&self.path, &data) {
+            Ok(()) => {
+                self.modified = false;
+                format!("Wrote {} bytes", data.len())
+            }
+            Err(IOError::ReadOnly) => String::from("Can't save: the filesystem is read-only"),
+            Err(IOError::NotFound) => String::from("Can't save: files can't be created yet"),
+            Err(IOError::NoSpace) => {
+                String::from("Can't save: files can't grow past the blocks they have yet")
+            }
+            Err(IOError::Invalid) => {
+                String::from("Can't save: files can't shrink out of the blocks they have yet")
+            }
+            Err(err) => format!("Can't save: {:?}", err),
+        };
+    }
+
+    // Scroll so that the cursor is on screen, and draw whatever changed
+    fn draw(&mut self) {
+        let (top, left) = (self.top, self.left);
+        if self.row < self.top {
+            self.top = self.row;
+        } else if self.row >= self.top + self.rows {
+            self.top = self.row + 1 - self.rows;
+        }
+        if self.column < self.left {
+            self.left = self.column;
+        } else if self.column >= self.left + self.columns {
+            self.left = self.column + 1 - self.columns;
+        }
+
+        if self.redraw_all || (top, left) != (self.top, self.left) {
+            for screen_row in 0..self.rows {
+                self.draw_line(screen_row);
+            }
+            self.redraw_all = false;
+        } else {
+            self.draw_line(self.row - self.top);
+        }
+
+        let modified = if self.modified { " [modified]" } else { "" };
+        let status = format!(
+            "{}{}  line {}, column {}  {}",
+            self.path,
+            modified,
+            self.row + 1,
+            self.column + 1,
+            self.message
+        );
+        framebuffer::set_cursor(0, self.rows);
+        self.print_padded(status.as_bytes());
+
+        framebuffer::set_cursor(self.column - self.left, self.row - self.top);
+    }
+
+    fn draw_line(&self, screen_row: usize) {
+        let text = self
+            .lines
+            .get(self.top + screen_row)
+            .and_then(|line| line.get(self.left..))
+            .unwrap_or(&[]);
+        framebuffer::set_cursor(0, screen_row);
+        self.print_padded(text);
+    }
+
+    // Print exactly a screen's width of `text`, cut off or padded with spaces
+    fn print_padded(&self, text: &[u8]) {
+        let row: String = (0..self.columns)
+            .map(|column| match text.get(column) {
+                Some(&byte) if byte == b' ' || byte.is_ascii_graphic() => byte as char,
+                Some(_) => '?',
+                None => ' ',
+            })
+            .collect();
+        print!("{}", row);
+    }
+}
@@ -0,0 +1,172 @@
+// The line being typed into the shell, and the commands typed before it. The console has no way
+// to move its cursor to an arbitrary spot, so every edit is drawn by stepping the cursor back
+// with `CURSOR_LEFT` and writing out whatever moved.
+
+use crate::klib::containers::circular_buffer::CircularBuffer;
+use crate::klib::graphics::framebuffer::{BACKSPACE, CURSOR_LEFT};
+use crate::print;
+use alloc::string::String;
+
+// How many previous commands are kept. Past this, the oldest ones are forgotten.
+const HISTORY_SIZE: usize = 32;
+
+pub struct LineEditor {
+    // Only ever holds ASCII, so bytes and characters on screen line up
+    line: String,
+    max_len: usize,
+    // Where in `line` the next character goes
+    cursor: usize,
+    history: CircularBuffer<HISTORY_SIZE, String>,
+    // Which history entry is being shown, if any
+    browsing: Option<usize>,
+    // What was typed before going into the history, to come back to at the end of it
+    draft: String,
+}
+
+impl LineEditor {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            line: String::with_capacity(max_len),
+            max_len,
+            cursor: 0,
+            history: CircularBuffer::new(),
+            browsing: None,
+            draft: String::new(),
+        }
+    }
+
+    pub fn insert(&mut self, ch: char) {
+        if !ch.is_ascii() || self.line.len() >= self.max_len {
+            return;
+        }
+
+        self.line.insert(self.cursor, ch);
+        self.cursor += 1;
+        self.redraw_tail(self.cursor - 1, 0);
+    }
+
+    /// Remove the character before the cursor.
+    pub fn backspace(&mut self) {
+        if self.cursor == 0 {
+            return;
+        }
+
+        self.cursor -= 1;
+        self.line.remove(self.cursor);
+        print!("{}", CURSOR_LEFT);
+        self.redraw_tail(self.cursor, 1);
+    }
+
+    /// Remove the character under the cursor.
+    pub fn delete(&mut self) {
+        if self.cursor == self.line.len() {
+            return;
+        }
+
+        self.line.remove(self.cursor);
+        self.redraw_tail(self.cursor, 1);
+    }
+
+    pub fn left(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            print!("{}", CURSOR_LEFT);
+        }
+    }
+
+    pub fn right(&mut self) {
+        if self.cursor < self.line.len() {
+            print!("{}", &self.line[self.cursor..self.cursor + 1]);
+            self.cursor += 1;
+        }
+    }
+
+    pub fn home(&mut self) {
+        move_left(self.cursor);
+        self.cursor = 0;
+    }
+
+    pub fn end(&mut self) {
+        print!("{}", &self.line[self.cursor..]);
+        self.cursor = self.line.len();
+    }
+
+    /// Show the command before the one being shown.
+    pub fn history_prev(&mut self) {
+        let index = match self.browsing {
+            Some(0) => return,
+            Some(index) => index - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = self.line.clone();
+                self.history.len() - 1
+            }
+        };
+
+        self.browsing = Some(index);
+        let entry = self.history.get(index).cloned().unwrap_or_default();
+        self.replace(entry);
+    }
+
+    /// Show the command after the one being shown, or what was being typed after the last one.
+    pub fn history_next(&mut self) {
+        let Some(index) = self.browsing else {
+            return;
+        };
+
+        let entry = if index + 1 < self.history.len() {
+            self.browsing = Some(index + 1);
+            self.history.get(index + 1).cloned().unwrap_or_default()
+        } else {
+            self.browsing = None;
+            core::mem::take(&mut self.draft)
+        };
+        self.replace(entry);
+    }
+
+    /// Hand over the finished line, remembering it unless it is blank or the same as the last
+    /// command.
+    pub fn take(&mut self) -> String {
+        let line = core::mem::replace(&mut self.line, String::with_capacity(self.max_len));
+        self.cursor = 0;
+        self.browsing = None;
+        self.draft.clear();
+
+        let last = self.history.len().checked_sub(1);
+        let repeated = last.and_then(|last| self.history.get(last)) == Some(&line);
+        if !line.trim().is_empty() && !repeated {
+            self.history.push_back(line.clone());
+        }
+
+        line
+    }
+
+    // Write out the line from `from` on, blank out the `erased` characters after it that the
+    // line got shorter by, and step back to the cursor
+    fn redraw_tail(&self, from: usize, erased: usize) {
+        print!("{}", &self.line[from..]);
+        for _ in 0..erased {
+            print!(" ");
+        }
+        move_left(self.line.len() + erased - self.cursor);
+    }
+
+    // Swap the whole line out for `line`, leaving the cursor at its end
+    fn replace(&mut self, line: String) {
+        self.end();
+        for _ in 0..self.line.len() {
+            print!("{}", BACKSPACE);
+        }
+
+        self.line = line;
+        self.line.truncate(self.max_len);
+        self.cursor = self.line.len();
+        print!("{}", self.line);
+    }
+}
+
+fn move_left(count: usize) {
+    for _ in 0..count {
+        print!("{}", CURSOR_LEFT);
+    }
+}
@@ -0,0 +1,1187 @@
+mod editor;
+mod line;
+
+use crate::allocator;
+use crate::arch::x86_64::memory_map;
+use crate::arch::x86_64::reserved;
+use crate::config;
+use crate::fs::{self, procfs};
+use crate::klib::acpi::pm;
+use crate::klib::ahci::ahcistate;
+use crate::klib::allocbench;
+use crate::klib::bcache;
+use crate::klib::block::{self, BlockDevice, IOError};
+use crate::klib::cmos;
+use crate::klib::crashdump;
+use crate::klib::diskbench::{self, Access, BenchOptions, StressOptions};
+use crate::klib::executor;
+use crate::klib::graphics::image::Image;
+use crate::klib::graphics::{self, framebuffer};
+use crate::klib::hash::sha256;
+use crate::klib::hexdump::hexdump;
+use crate::klib::inflate::{self, Gunzip};
+use crate::klib::iosched::{self, Policy};
+use crate::klib::log::{self, Level};
+use crate::klib::profiler;
+use crate::klib::speaker;
+use crate::klib::time::{Duration, Timestamp};
+use crate::klib::trace;
+use crate::klib::version;
+use crate::print;
+use crate::println;
+use crate::task;
+use crate::TIMER;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::MaybeUninit;
+use core::sync::atomic::Ordering;
+use line::LineEditor;
+use x86_64::instructions::interrupts;
+
+const PROMPT: &str = "> ";
+const MAX_LINE: usize = 256;
+
+// Most bytes `readsec` and `hexdump` will read in one go
+const MAX_DUMP: usize = 64 * 1024;
+
+// Most a gzipped image can unpack to, a quarter of the heap
+const MAX_GUNZIPPED: usize = 1024 * 1024;
+
+// How much of a gzipped file zcat decompresses at a time
+const ZCAT_CHUNK: usize = 4096;
+
+const BENCH_USAGE: &str = "Usage: bench alloc\n       \
+    bench disk <disk> [seq|rand] [read|write] [depth=<n>] [bs=<bytes>] [time=<ms>]";
+const STRESS_USAGE: &str =
+    "Usage: stress disk <disk> [depth=<n>] [size=<bytes>] [passes=<n>] [seed=<n>]";
+
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: fn(&[&str]),
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "list available commands",
+        run: help,
+    },
+    Command {
+        name: "ps",
+        help: "list tasks with scheduler statistics",
+        run: ps,
+    },
+    Command {
+        name: "top",
+        help: "alias for ps",
+        run: ps,
+    },
+    Command {
+        name: "heap",
+        help: "show free heap memory by block size, and check the allocator for corruption",
+        run: heap,
+    },
+    Command {
+        name: "meminfo",
+        help: "show physical memory by type and NUMA node",
+        run: meminfo,
+    },
+    Command {
+        name: "memmap",
+        help: "list reserved physical memory",
+        run: memmap,
+    },
+    Command {
+        name: "ahcistat",
+        help: "count AHCI interrupts by cause",
+        run: ahcistat,
+    },
+    Command {
+        name: "linkpower",
+        help: "linkpower [active|partial|slumber|devsleep]: show or set the AHCI disk's link power",
+        run: linkpower,
+    },
+    Command {
+        name: "lsblk",
+        help: "list block devices, and the UUIDs of the filesystems on them",
+        run: lsblk,
+    },
+    Command {
+        name: "iosched",
+        help: "iosched [<disk> fifo|look]: show block request queues, or pick how one is ordered",
+        run: iosched_command,
+    },
+    Command {
+        name: "sync",
+        help: "write every dirty cached block back to its disk",
+        run: sync,
+    },
+    Command {
+        name: "dropcaches",
+        help: "free whatever the caches can give back, as if the heap had run out",
+        run: drop_caches,
+    },
+    Command {
+        name: "cat",
+        help: "cat <file>: print a file",
+        run: cat,
+    },
+    Command {
+        name: "stat",
+        help: "stat <file>: show a file's size and when it was last changed",
+        run: stat,
+    },
+    Command {
+        name: "zcat",
+        help: "zcat <file>: print a gzipped file",
+        run: zcat,
+    },
+    Command {
+        name: "sha256sum",
+        help: "sha256sum <file>...: print the SHA-256 of files",
+        run: sha256sum,
+    },
+    Command {
+        name: "view",
+        help: "view <file>: show a BMP or PPM image (.gz too) on the screen, until the next line",
+        run: view,
+    },
+    Command {
+        name: "edit",
+        help: "edit <file>: edit a text file, ^S to save and ^Q to quit",
+        run: edit,
+    },
+    Command {
+        name: "readsec",
+        help: "readsec <disk> <lba> [count]: dump sectors of a block device",
+        run: readsec,
+    },
+    Command {
+        name: "hexdump",
+        help: "hexdump <disk> <offset> [length]: dump bytes of a block device",
+        run: hexdump_command,
+    },
+    Command {
+        name: "bench",
+        help: "bench alloc|disk <disk> [seq|rand] [read|write] [<key>=<n>]: time heap or disk",
+        run: bench,
+    },
+    Command {
+        name: "stress",
+        help: "stress disk <disk> [depth=<n>] [size=<bytes>] [passes=<n>]: write, read and check",
+        run: stress,
+    },
+    Command {
+        name: "uname",
+        help: "uname [-a]: show the kernel's name, or everything about how it was built",
+        run: uname,
+    },
+    Command {
+        name: "uptime",
+        help: "show how long the kernel has been running, and the time",
+        run: uptime,
+    },
+    Command {
+        name: "config",
+        help: "list the features this kernel was built with",
+        run: config,
+    },
+    Command {
+        name: "display",
+        help: "show the framebuffer resolution and pixel format",
+        run: display,
+    },
+    Command {
+        name: "beep",
+        help: "beep [hz] [ms]: play a tone on the PC speaker",
+        run: beep,
+    },
+    Command {
+        name: "crashdump",
+        help: "crashdump [clear]: show or erase the dump from the last panic",
+        run: show_crashdump,
+    },
+    Command {
+        name: "dmesg",
+        help: "dmesg [-T] [count]: show the kernel log, or its last entries (-T: times of day)",
+        run: dmesg,
+    },
+    Command {
+        name: "loglevel",
+        help: "loglevel [error|warn|info|debug]: show or set what the log prints",
+        run: loglevel,
+    },
+    Command {
+        name: "profile",
+        help: "profile start|stop|report [count]|dump: sample where the kernel spends its time",
+        run: profile,
+    },
+    Command {
+        name: "trace",
+        help: "trace [on|off <subsystem|all>] [dump [count]] [clear]: record driver events",
+        run: trace_command,
+    },
+    Command {
+        name: "suspend",
+        help: "suspend to RAM until the power button is pressed (experimental)",
+        run: suspend,
+    },
+    Command {
+        name: "poweroff",
+        help: "turn the machine off",
+        run: poweroff,
+    },
+];
+
+/// A minimal line-based kernel shell. Keys are fed in one at a time by the console input loop,
+/// and a command is run once Enter is pressed. The line can be edited anywhere with the arrow
+/// keys, Home, End, Backspace and Delete, and Up and Down go through the commands run before.
+pub struct Shell {
+    editor: LineEditor,
+}
+
+impl Shell {
+    pub fn new() -> Self {
+        Self {
+            editor: LineEditor::new(MAX_LINE),
+        }
+    }
+
+    pub fn prompt(&self) {
+        print!("{}", PROMPT);
+    }
+
+    pub fn input_char(&mut self, ch: char) {
+        self.editor.insert(ch);
+    }
+
+    pub fn backspace(&mut self) {
+        self.editor.backspace();
+    }
+
+    pub fn delete(&mut self) {
+        self.editor.delete();
+    }
+
+    pub fn cursor_left(&mut self) {
+        self.editor.left();
+    }
+
+    pub fn cursor_right(&mut self) {
+        self.editor.right();
+    }
+
+    pub fn home(&mut self) {
+        self.editor.home();
+    }
+
+    pub fn end(&mut self) {
+        self.editor.end();
+    }
+
+    pub fn history_prev(&mut self) {
+        self.editor.history_prev();
+    }
+
+    pub fn history_next(&mut self) {
+        self.editor.history_next();
+    }
+
+    pub fn enter(&mut self) {
+        self.editor.end();
+        println!();
+        run_line(&self.editor.take());
+        self.prompt();
+    }
+}
+
+/// Run a command line as if it had been typed in.
+pub fn run_line(line: &str) {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let Some(&name) = args.first() else {
+        return;
+    };
+
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => (command.run)(&args[1..]),
+        None => println!("Unknown command: {}. Try `help`.", name),
+    }
+}
+
+fn help(_args: &[&str]) {
+    for command in COMMANDS {
+        println!("{:<10} {}", command.name, command.help);
+    }
+}
+
+fn ps(_args: &[&str]) {
+    let uptime = TIMER.load(Ordering::SeqCst).max(1);
+    println!(
+        "{:>4} {:<12} {:<9} {:<7} {:>8} {:>5} {:>8} {:>7} {:>13}",
+        "ID", "NAME", "STATE", "PRIO", "CPU", "CPU%", "WAIT", "WAKES", "STACK"
+    );
+
+    for info in task::snapshot() {
+        let state = match info.state {
+            task::TaskState::Running => "running",
+            task::TaskState::Ready => "ready",
+            task::TaskState::Blocked => "blocked",
+            task::TaskState::Sleeping(_) => "sleeping",
+            task::TaskState::Dead => "dead",
+        };
+        let priority = match info.priority {
+            task::Priority::High => "high",
+            task::Priority::Normal => "normal",
+            task::Priority::Idle => "idle",
+        };
+
+        print!(
+            "{:>4} {:<12} {:<9} {:<7} {:>8} {:>4}% {:>8} {:>7} ",
+            info.id,
+            info.name,
+            state,
+            priority,
+            info.stats.runtime,
+            info.stats.runtime * 100 / uptime,
+            info.stats.wait_time,
+            info.stats.wake_count,
+        );
+
+        match (info.stack_used, info.stack_size) {
+            (Some(used), Some(size)) => println!("{:>6}/{:<6}", used, size),
+            _ => println!("{:>13}", "-"),
+        }
+    }
+
+    let futures = executor::pending();
+    if futures > 0 {
+        println!("{} futures waiting on the executor", futures);
+    }
+}
+
+fn lsblk(_args: &[&str]) {
+    println!(
+        "{:<10} {:>6} {:>12} {:>10}  UUID",
+        "NAME", "BLOCK", "BLOCKS", "SIZE"
+    );
+
+    for (name, device) in block::devices() {
+        let size = device.block_size() as u64 * device.num_blocks() as u64;
+        print!(
+            "{:<10} {:>6} {:>12} {:>7} MiB",
+            name,
+            device.block_size(),
+            device.num_blocks(),
+            size / (1024 * 1024)
+        );
+        match fs::uuid(&*device) {
+            Some(uuid) => println!("  {}", uuid),
+            None => println!(),
+        }
+    }
+}
+
+fn iosched_command(args: &[&str]) {
+    match args {
+        [] => {}
+        [name, policy] => {
+            let Some(queue) = iosched::get(name) else {
+                println!("No request queue for {}", name);
+                return;
+            };
+            match *policy {
+                "fifo" => queue.set_policy(Policy::Fifo),
+                "look" => queue.set_policy(Policy::Look),
+                _ => {
+                    println!("Policies are fifo and look");
+                    return;
+                }
+            }
+        }
+        _ => {
+            println!("Usage: iosched [<disk> fifo|look]");
+            return;
+        }
+    }
+
+    println!(
+        "{:<10} {:<6} {:>7} {:>10} {:>7} {:>12}",
+        "NAME", "POLICY", "PENDING", "COMPLETED", "ERRORS", "SEEK KIB"
+    );
+    for (name, queue) in iosched::queues() {
+        let (pending, stats) = queue.stats();
+        let policy = match queue.policy() {
+            Policy::Fifo => "fifo",
+            Policy::Look => "look",
+        };
+        println!(
+            "{:<10} {:<6} {:>7} {:>10} {:>7} {:>12}",
+            name,
+            policy,
+            pending,
+            stats.completed,
+            stats.errors,
+            stats.seek_distance / 1024
+        );
+    }
+}
+
+fn sync(_args: &[&str]) {
+    let caches = bcache::caches();
+    let dirty: usize = caches.iter().map(|cache| cache.stats().dirty).sum();
+
+    match bcache::sync() {
+        Ok(()) => println!("Wrote {} blocks from {} caches", dirty, caches.len()),
+        Err(err) => println!("Sync failed: {:?}", err),
+    }
+}
+
+fn drop_caches(_args: &[&str]) {
+    let freed = allocator::reclaim();
+    println!("Freed {} KiB", freed / 1024);
+}
+
+fn cat(args: &[&str]) {
+    let [path] = args else {
+        println!("Usage: cat <file>");
+        return;
+    };
+
+    match fs::read(path) {
+        Ok(contents) => framebuffer::print_bytes(&contents),
+        Err(IOError::NotFound) if path.starts_with(procfs::MOUNT_POINT) => {
+            println!("No such file: {}. The files are:", path);
+            for name in procfs::files() {
+                println!("{}/{}", procfs::MOUNT_POINT, name);
+            }
+        }
+        Err(err) => println!("Couldn't read {}: {:?}", path, err),
+    }
+}
+
+fn stat(args: &[&str]) {
+    let [path] = args else {
+        println!("Usage: stat <file>");
+        return;
+    };
+
+    match fs::stat(path) {
+        Ok(stat) => {
+            println!("Size:     {} bytes", stat.size);
+            match stat.modified {
+                Some(modified) => println!("Modified: {} UTC", modified),
+                None => println!("Modified: unknown"),
+            }
+        }
+        Err(err) => println!("Couldn't stat {}: {:?}", path, err),
+    }
+}
+
+fn zcat(args: &[&str]) {
+    let [path] = args else {
+        println!("Usage: zcat <file>");
+        return;
+    };
+
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("Couldn't read {}: {:?}", path, err);
+            return;
+        }
+    };
+    let mut gunzip = match Gunzip::new(&data) {
+        Ok(gunzip) => gunzip,
+        Err(err) => {
+            println!("{} isn't a gzip file: {:?}", path, err);
+            return;
+        }
+    };
+
+    let mut buf = alloc::vec![0; ZCAT_CHUNK];
+    // Bytes at the start of `buf` left over from the last chunk: a UTF-8 character it cut off
+    let mut kept = 0;
+    loop {
+        let filled = match gunzip.read(&mut buf[kept..]) {
+            Ok(read) => kept + read,
+            Err(err) => {
+                println!();
+                println!("Couldn't decompress {}: {:?}", path, err);
+                return;
+            }
+        };
+        if filled < buf.len() {
+            framebuffer::print_bytes(&buf[..filled]);
+            return;
+        }
+
+        let complete = match core::str::from_utf8(&buf) {
+            Err(err) if err.error_len().is_none() => err.valid_up_to(),
+            _ => buf.len(),
+        };
+        framebuffer::print_bytes(&buf[..complete]);
+        buf.copy_within(complete.., 0);
+        kept = buf.len() - complete;
+    }
+}
+
+fn sha256sum(args: &[&str]) {
+    if args.is_empty() {
+        println!("Usage: sha256sum <file>...");
+        return;
+    }
+
+    for path in args {
+        match fs::read(path) {
+            Ok(data) => {
+                for byte in sha256::digest(&data) {
+                    print!("{:02x}", byte);
+                }
+                println!("  {}", path);
+            }
+            Err(err) => println!("Couldn't read {}: {:?}", path, err),
+        }
+    }
+}
+
+fn view(args: &[&str]) {
+    let [path] = args else {
+        println!("Usage: view <file>");
+        return;
+    };
+
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) => {
+            println!("Couldn't read {}: {:?}", path, err);
+            return;
+        }
+    };
+    let data = if path.ends_with(".gz") {
+        match inflate::gunzip(&data, MAX_GUNZIPPED) {
+            Ok(data) => data,
+            Err(err) => {
+                println!("Couldn't decompress {}: {:?}", path, err);
+                return;
+            }
+        }
+    } else {
+        data
+    };
+    let Ok(image) = Image::decode(&data) else {
+        println!("{} isn't a BMP or PPM image this can show", path);
+        return;
+    };
+    if framebuffer::draw_image(&image).is_err() {
+        println!("No framebuffer");
+    }
+}
+
+fn edit(args: &[&str]) {
+    let [path] = args else {
+        println!("Usage: edit <file>");
+        return;
+    };
+
+    if editor::run(path).is_err() {
+        println!("Couldn't start the editor");
+    }
+}
+
+fn readsec(args: &[&str]) {
+    let (name, lba, count) = match args {
+        [name, lba] => (name, parse_number(lba), Some(1)),
+        [name, lba, count] => (name, parse_number(lba), parse_number(count)),
+        _ => (&"", None, None),
+    };
+    let (Some(lba), Some(count)) = (lba, count) else {
+        println!("Usage: readsec <disk> <lba> [count]");
+        return;
+    };
+    let Some(device) = block::get(name) else {
+        println!("No block device named {}", name);
+        return;
+    };
+
+    let block_size = device.block_size();
+    if lba.saturating_add(count) > device.num_blocks() as u64 {
+        println!("{} only has {} sectors", name, device.num_blocks());
+        return;
+    }
+    if count == 0 || count as usize * block_size > MAX_DUMP {
+        println!("Can read 1 to {} sectors at a time", MAX_DUMP / block_size);
+        return;
+    }
+
+    let offset = lba as usize * block_size;
+    let mut buf = Vec::new();
+    buf.resize(count as usize * block_size, MaybeUninit::uninit());
+    match device.read(&mut buf, offset) {
+        Ok(bytes) => print!("{}", hexdump(bytes, offset as u64)),
+        Err(err) => println!("Read failed: {:?}", err),
+    }
+}
+
+// Like `readsec`, but for any range of bytes. There is no VFS to open files through yet, so the
+// dump is of the disk itself; e.g. `hexdump sata0 1024 1024` shows an ext2 superblock.
+fn hexdump_command(args: &[&str]) {
+    let (name, offset, length) = match args {
+        [name, offset] => (name, parse_number(offset), Some(256)),
+        [name, offset, length] => (name, parse_number(offset), parse_number(length)),
+        _ => (&"", None, None),
+    };
+    let (Some(offset), Some(length)) = (offset, length) else {
+        println!("Usage: hexdump <disk> <offset> [length]");
+        return;
+    };
+    let Some(device) = block::get(name) else {
+        println!("No block device named {}", name);
+        return;
+    };
+
+    let size = device.block_size() as u64 * device.num_blocks() as u64;
+    let length = length.min(size.saturating_sub(offset)).min(MAX_DUMP as u64);
+    if length == 0 {
+        println!("Nothing to read at {} of {}", offset, name);
+        return;
+    }
+
+    let mut buf = Vec::new();
+    buf.resize(length as usize, MaybeUninit::uninit());
+    match block::read_bytes(device.as_ref(), &mut buf, offset as usize) {
+        Ok(bytes) => print!("{}", hexdump(bytes, offset)),
+        Err(err) => println!("Read failed: {:?}", err),
+    }
+}
+
+// A number in decimal, or in hex with a 0x in front
+fn parse_number(arg: &str) -> Option<u64> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+// A number like `parse_number`, in KiB with a k after it, or in MiB with an m
+fn parse_size(arg: &str) -> Option<u64> {
+    let (number, unit) = match arg.strip_suffix(['k', 'K']) {
+        Some(number) => (number, 1024),
+        None => match arg.strip_suffix(['m', 'M']) {
+            Some(number) => (number, 1024 * 1024),
+            None => (arg, 1),
+        },
+    };
+    parse_number(number)?.checked_mul(unit)
+}
+
+// Split `bench` and `stress` arguments into words and `key=value` settings
+fn parse_settings<'a>(args: &[&'a str]) -> Option<(Vec<&'a str>, Vec<(&'a str, u64)>)> {
+    let mut words = Vec::new();
+    let mut settings = Vec::new();
+    for arg in args {
+        match arg.split_once('=') {
+            Some((key, value)) => settings.push((key, parse_size(value)?)),
+            None => words.push(*arg),
+        }
+    }
+    Some((words, settings))
+}
+
+// The disk to benchmark, if there is one by that name. Disks with a filesystem on them can only
+// be read from.
+fn bench_device(name: &str, write: bool) -> Option<Arc<dyn BlockDevice>> {
+    let Some(device) = block::get(name) else {
+        println!("No block device named {}", name);
+        return None;
+    };
+    if write && fs::mounts().iter().any(|mount| mount.source == name) {
+        println!(
+            "{} has a filesystem mounted from it, so it can't be written to",
+            name
+        );
+        return None;
+    }
+    Some(device)
+}
+
+fn bench(args: &[&str]) {
+    match args {
+        ["alloc"] => bench_alloc(),
+        ["disk", name, rest @ ..] => bench_disk(name, rest),
+        _ => println!("{}", BENCH_USAGE),
+    }
+}
+
+fn bench_alloc() {
+    let Ok(report) = allocbench::run() else {
+        println!("Couldn't start the producer task");
+        return;
+    };
+
+    println!(
+        "{:<12} {:>8} {:>10} {:>6} {:>13} {:>8}",
+        "PATTERN", "OPS", "OPS/SEC", "FAILED", "REQUESTED KIB", "USED KIB"
+    );
+    for pattern in &report.patterns {
+        println!(
+            "{:<12} {:>8} {:>10} {:>6} {:>13} {:>8}",
+            pattern.name,
+            pattern.ops,
+            report.ops_per_sec(pattern),
+            pattern.failed,
+            pattern.requested / 1024,
+            pattern.used / 1024
+        );
+    }
+
+    let ((free_before, largest_before), (free_after, largest_after)) =
+        (report.free_before, report.free_after);
+    println!(
+        "Free heap {} KiB before, {} KiB after; largest free block {} KiB before, {} KiB after",
+        free_before / 1024,
+        free_after / 1024,
+        largest_before / 1024,
+        largest_after / 1024
+    );
+}
+
+fn bench_disk(name: &str, args: &[&str]) {
+    let Some((words, settings)) = parse_settings(args) else {
+        println!("{}", BENCH_USAGE);
+        return;
+    };
+
+    let mut options = BenchOptions {
+        access: Access::Sequential,
+        write: false,
+        depth: 8,
+        request_size: 64 * 1024,
+        duration_ms: 2000,
+        seed: 1,
+    };
+    for word in words {
+        match word {
+            "seq" => options.access = Access::Sequential,
+            "rand" => options.access = Access::Random,
+            "read" => options.write = false,
+            "write" => options.write = true,
+            _ => {
+                println!("{}", BENCH_USAGE);
+                return;
+            }
+        }
+    }
+    for (key, value) in settings {
+        match key {
+            "depth" => options.depth = value as usize,
+            "bs" => options.request_size = value as usize,
+            "time" => options.duration_ms = value,
+            "seed" => options.seed = value,
+            _ => {
+                println!("{}", BENCH_USAGE);
+                return;
+            }
+        }
+    }
+
+    let Some(device) = bench_device(name, options.write) else {
+        return;
+    };
+    let Ok(report) = diskbench::bench(&device, &options) else {
+        println!(
+            "Requests have to be a multiple of {} bytes, and fit on {}",
+            device.block_size(),
+            name
+        );
+        return;
+    };
+    print_bench_report(&report);
+}
+
+fn stress(args: &[&str]) {
+    let ["disk", name, rest @ ..] = args else {
+        println!("{}", STRESS_USAGE);
+        return;
+    };
+    let Some((words, settings)) = parse_settings(rest) else {
+        println!("{}", STRESS_USAGE);
+        return;
+    };
+    if !words.is_empty() {
+        println!("{}", STRESS_USAGE);
+        return;
+    }
+
+    let mut options = StressOptions {
+        depth: 8,
+        size: 16 * 1024 * 1024,
+        passes: 2,
+        seed: 1,
+    };
+    for (key, value) in settings {
+        match key {
+            "depth" => options.depth = value as usize,
+            "size" => options.size = value as usize,
+            "passes" => options.passes = value as usize,
+            "seed" => options.seed = value,
+            _ => {
+                println!("{}", STRESS_USAGE);
+                return;
+            }
+        }
+    }
+
+    let Some(device) = bench_device(name, true) else {
+        return;
+    };
+    let Ok(report) = diskbench::stress(&device, &options) else {
+        println!(
+            "The size has to be a multiple of {} bytes, and fit on {}",
+            device.block_size(),
+            name
+        );
+        return;
+    };
+    print_bench_report(&report);
+    match report.first_mismatch {
+        Some(lba) => println!(
+            "{} blocks read back wrong, the first at block {}",
+            report.mismatches, lba
+        ),
+        None => println!("Every block read back as written"),
+    }
+}
+
+fn print_bench_report(report: &diskbench::Report) {
+    let kib = report.kib_per_sec();
+    println!(
+        "{}.{:02} MiB/s, {} IOPS ({} requests in {} ms, {} failed)",
+        kib / 1024,
+        kib % 1024 * 100 / 1024,
+        report.iops(),
+        report.requests,
+        report.elapsed_ms,
+        report.errors
+    );
+    let percentiles = [50, 90, 99, 100].map(|percent| report.percentile(percent));
+    if let [Some(p50), Some(p90), Some(p99), Some(max)] = percentiles {
+        println!(
+            "Latency: p50 {} us, p90 {} us, p99 {} us, max {} us",
+            p50, p90, p99, max
+        );
+    }
+}
+
+fn heap(_args: &[&str]) {
+    let (Some((free, largest)), Some(counts)) =
+        (allocator::fragmentation(), allocator::free_blocks())
+    else {
+        println!("The heap is locked");
+        return;
+    };
+
+    println!(
+        "{} KiB free of {} KiB, largest free block {} KiB",
+        free / 1024,
+        allocator::HEAP_SIZE / 1024,
+        largest / 1024
+    );
+    if free > 0 {
+        println!("Fragmentation: {}%", 100 - largest * 100 / free);
+    }
+
+    println!("{:>10} {:>6}", "BLOCK", "FREE");
+    for (order, count) in counts.iter().enumerate() {
+        let size = (allocator::PAGESIZE << order) / 1024;
+        println!("{:>6} KiB {:>6}", size, count);
+    }
+
+    match allocator::validate() {
+        Ok(()) => println!("Free lists are consistent"),
+        Err(error) => println!("Heap is corrupted: {}", error),
+    }
+}
+
+fn meminfo(_args: &[&str]) {
+    let (total, usable) = memory_map::ram();
+    println!(
+        "{} MiB of RAM, {} MiB usable",
+        total / (1024 * 1024),
+        usable / (1024 * 1024)
+    );
+
+    println!(
+        "{:>18} {:>18} {:<12} {:>10} {}",
+        "START", "END", "TYPE", "SIZE", "NODE"
+    );
+    for region in memory_map::regions() {
+        let node = region
+            .domain()
+            .map_or(String::from("-"), |node| format!("{}", node));
+        println!(
+            "{:>#18x} {:>#18x} {:<12} {:>6} KiB {}",
+            region.start,
+            region.end,
+            format!("{:?}", region.region_type),
+            region.size() / 1024,
+            node
+        );
+    }
+
+    for (node, bytes) in memory_map::ram_by_domain() {
+        println!("Node {}: {} MiB usable", node, bytes / (1024 * 1024));
+    }
+
+    // Windows drivers mapped, which the firmware's map may not mention
+    for range in reserved::ranges() {
+        if range.kind == reserved::Kind::Mmio {
+            println!(
+                "{:>#18x} {:>#18x} {:<12} {:>6} KiB {}",
+                range.start,
+                range.end,
+                "Mmio",
+                (range.end - range.start) / 1024,
+                range.owner
+            );
+        }
+    }
+}
+
+fn memmap(_args: &[&str]) {
+    println!("{:>18} {:>18} {:<10} {}", "START", "END", "KIND", "OWNER");
+    for range in reserved::ranges() {
+        println!(
+            "{:>#18x} {:>#18x} {:<10} {}",
+            range.start,
+            range.end,
+            format!("{:?}", range.kind),
+            range.owner
+        );
+    }
+}
+
+fn uname(args: &[&str]) {
+    match args {
+        [] => println!("{}", version::NAME),
+        ["-a"] => println!("{}", version::build_info()),
+        _ => println!("Usage: uname [-a]"),
+    }
+}
+
+fn uptime(_args: &[&str]) {
+    print!("up {}", Duration::uptime());
+    match (Timestamp::now(), Timestamp::boot()) {
+        (Some(now), Some(boot)) => println!(", booted {}, now {} UTC", boot, now),
+        _ => println!(", time of day unknown"),
+    }
+}
+
+fn config(_args: &[&str]) {
+    for &(name, enabled) in config::FEATURES {
+        println!("{:<12} {}", name, if enabled { "on" } else { "off" });
+    }
+}
+
+fn ahcistat(_args: &[&str]) {
+    for (cause, count) in ahcistate::interrupt_counts() {
+        println!("{:<22} {:>10}", cause, count);
+    }
+}
+
+fn linkpower(args: &[&str]) {
+    let Some(disk) = ahcistate::SATA_DISK0.get() else {
+        println!("No AHCI disk");
+        return;
+    };
+
+    match args {
+        [] => match interrupts::without_interrupts(|| disk.read().link_power()) {
+            Some(state) => println!("{:?}", state),
+            None => println!("No link"),
+        },
+        [name] => {
+            let Some(state) = ahcistate::LinkPower::from_name(name) else {
+                println!("Usage: linkpower [active|partial|slumber|devsleep]");
+                return;
+            };
+            let result =
+                interrupts::without_interrupts(|| unsafe { disk.write().set_link_power(state) });
+            if result.is_err() {
+                println!("Couldn't put the link in {:?}", state);
+            }
+        }
+        _ => println!("Usage: linkpower [active|partial|slumber|devsleep]"),
+    }
+}
+
+fn display(_args: &[&str]) {
+    match graphics::display_info() {
+        Some(info) => println!(
+            "{}x{}, stride {}, {} bytes per pixel, {:?}",
+            info.width, info.height, info.stride, info.bytes_per_pixel, info.layout
+        ),
+        None => println!("No framebuffer"),
+    }
+}
+
+fn beep(args: &[&str]) {
+    let frequency = args.first().map_or(Ok(440), |arg| arg.parse());
+    let milliseconds = args.get(1).map_or(Ok(200), |arg| arg.parse());
+
+    match (frequency, milliseconds) {
+        (Ok(frequency), Ok(milliseconds)) => speaker::beep(frequency, milliseconds),
+        _ => println!("Usage: beep [hz] [ms]"),
+    }
+}
+
+fn show_crashdump(args: &[&str]) {
+    if !crashdump::enabled() {
+        println!("No crash dump region on this disk");
+        return;
+    }
+
+    if args.first() == Some(&"clear") {
+        match crashdump::clear() {
+            Ok(()) => println!("Crash dump cleared"),
+            Err(err) => println!("Couldn't clear the crash dump: {:?}", err),
+        }
+        return;
+    }
+
+    match crashdump::read() {
+        Ok(Some(dump)) => {
+            println!("Crash {} ms after boot:", dump.uptime_ms);
+            println!("{}", dump.text);
+        }
+        Ok(None) => println!("No crash dump"),
+        Err(err) => println!("Couldn't read the crash dump: {:?}", err),
+    }
+}
+
+fn dmesg(args: &[&str]) {
+    let (time_of_day, args) = match args {
+        ["-T", rest @ ..] => (true, rest),
+        _ => (false, args),
+    };
+
+    match args.first().map_or(Ok(log::NUM_RECORDS), |arg| arg.parse()) {
+        Ok(count) => {
+            for record in log::recent(count) {
+                match time_of_day {
+                    true => println!("{:#}", record),
+                    false => println!("{}", record),
+                }
+            }
+        }
+        Err(_) => println!("Usage: dmesg [-T] [count]"),
+    }
+}
+
+fn loglevel(args: &[&str]) {
+    match args.first().map(|arg| Level::from_name(arg)) {
+        None => println!("{}", log::console_level().name()),
+        Some(Some(level)) => cmos::set_log_level(level),
+        Some(None) => println!("Usage: loglevel [error|warn|info|debug]"),
+    }
+}
+
+fn profile(args: &[&str]) {
+    match args {
+        ["start"] => {
+            profiler::start();
+            println!("Profiling started");
+        }
+        ["stop"] => {
+            profiler::stop();
+            println!("Profiling stopped, {} samples", profiler::num_samples());
+        }
+        ["report", rest @ ..] => match rest.first().map_or(Ok(10), |arg| arg.parse()) {
+            Ok(count) => {
+                let total = profiler::num_samples();
+                println!("{:>18} {:>7} {:>5}", "ADDRESS", "SAMPLES", "%");
+                for (rip, samples) in profiler::hotspots(count) {
+                    println!("{:>#18x} {:>7} {:>4}%", rip, samples, samples * 100 / total);
+                }
+            }
+            Err(_) => println!("Usage: profile report [count]"),
+        },
+        ["dump"] => {
+            let mut out = String::new();
+            let _ = profiler::dump(&mut out);
+            print!("{}", out);
+        }
+        _ => {
+            let state = if profiler::is_running() {
+                "running"
+            } else {
+                "stopped"
+            };
+            println!(
+                "Usage: profile start|stop|report [count]|dump (currently {})",
+                state
+            );
+        }
+    }
+}
+
+fn trace_command(args: &[&str]) {
+    match args {
+        [] => {
+            for subsys in trace::Subsystem::ALL {
+                let state = if trace::is_enabled(subsys) {
+                    "on"
+                } else {
+                    "off"
+                };
+                println!("{:<6} {}", subsys.name(), state);
+            }
+        }
+        [switch @ ("on" | "off"), name] => {
+            let subsystems: Vec<trace::Subsystem> = match *name {
+                "all" => trace::Subsystem::ALL.to_vec(),
+                _ => match trace::Subsystem::from_name(name) {
+                    Some(subsys) => alloc::vec![subsys],
+                    None => {
+                        println!("Unknown subsystem: {}", name);
+                        return;
+                    }
+                },
+            };
+
+            for subsys in subsystems {
+                if *switch == "on" {
+                    trace::enable(subsys);
+                } else {
+                    trace::disable(subsys);
+                }
+            }
+        }
+        ["dump", rest @ ..] => match rest
+            .first()
+            .map_or(Ok(trace::NUM_EVENTS), |arg| arg.parse())
+        {
+            Ok(count) => {
+                for event in trace::recent(count) {
+                    println!("{}", event);
+                }
+            }
+            Err(_) => println!("Usage: trace dump [count]"),
+        },
+        ["clear"] => trace::clear(),
+        _ => println!("Usage: trace [on|off <subsystem|all>] [dump [count]] [clear]"),
+    }
+}
+
+fn suspend(_args: &[&str]) {
+    match pm::suspend() {
+        Ok(()) => println!("Woke up"),
+        Err(()) => println!("Couldn't suspend"),
+    }
+}
+
+fn poweroff(_args: &[&str]) {
+    pm::shutdown();
+}
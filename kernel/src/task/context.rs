@@ -0,0 +1,90 @@
+use crate::arch::x86_64::fpu::FpuState;
+use core::arch::global_asm;
+
+// Callee-saved registers are pushed onto the old task's stack, the old stack pointer is stored
+// through `old_rsp`, and then the same registers are popped off of the new task's stack. The
+// `ret` at the end returns into wherever the new task last called `switch_context` from (or into
+// `task_trampoline`, for a task that has never run).
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    "",
+    ".global task_trampoline",
+    "task_trampoline:",
+    "mov rdi, r12",
+    "call {start}",
+    "ud2",
+    start = sym super::task_start,
+);
+
+extern "C" {
+    /// Save the current task's registers and stack pointer into `old_rsp`, and resume the task
+    /// whose stack pointer is `new_rsp`.
+    /// ### Safety
+    /// Interrupts must be disabled, no locks may be held by the caller, and `new_rsp` must point
+    /// to a stack that was either set up by `Context::prepare` or saved by a previous switch.
+    pub fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+
+    fn task_trampoline();
+}
+
+/// Number of registers pushed by `switch_context`, plus the return address.
+const INITIAL_FRAME_WORDS: usize = 7;
+const R12_SLOT: usize = 3;
+
+#[repr(C)]
+pub struct Context {
+    pub rsp: u64,
+    /// Saved and restored around `switch_context` by `schedule`
+    pub fpu: FpuState,
+}
+
+impl Context {
+    pub const fn empty() -> Self {
+        Self {
+            rsp: 0,
+            fpu: FpuState::new(),
+        }
+    }
+
+    /// Build the initial stack frame for a task that has not run yet, so that the first
+    /// `switch_context` into it "returns" into `task_trampoline` with `arg` in r12.
+    /// ### Safety
+    /// `stack_top` must be the (exclusive) end of a writable stack of at least 64 bytes that is
+    /// not in use by anything else.
+    pub unsafe fn prepare(stack_top: u64, arg: u64) -> Self {
+        // The return address sits just below a 16-byte aligned top, so that the stack is aligned
+        // properly when the trampoline calls into Rust.
+        let top = stack_top & !0xF;
+        let frame = (top - (INITIAL_FRAME_WORDS * 8) as u64) as *mut u64;
+
+        for i in 0..INITIAL_FRAME_WORDS {
+            frame.add(i).write(0);
+        }
+
+        frame.add(R12_SLOT).write(arg);
+        frame
+            .add(INITIAL_FRAME_WORDS - 1)
+            .write(task_trampoline as usize as u64);
+
+        Self {
+            rsp: frame as u64,
+            fpu: FpuState::new(),
+        }
+    }
+}
@@ -0,0 +1,437 @@
+mod context;
+pub mod scheduler;
+pub mod stack;
+
+use crate::arch::x86_64::cpu;
+use crate::arch::{Arch, Cpu, Paging, PortIo};
+use crate::log_info;
+use crate::TIMER;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use context::Context;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use scheduler::SCHEDULER;
+use spin::Mutex;
+use stack::Stack;
+use x86_64::instructions::interrupts;
+
+pub const KERNEL_STACK_SIZE: usize = 16 * 1024;
+
+/// Size of the stack the bootloader starts the kernel on, which the boot task keeps running on.
+pub const BOOT_STACK_SIZE: u64 = 80 * 1024;
+
+// Fresh stacks are filled with this, so the deepest point a stack has reached can be found later
+// by looking for the first byte that was overwritten.
+const STACK_FILL: u8 = 0xA5;
+
+// How far below its own stack pointer `fill_boot_stack` stops filling, to leave room for what it
+// calls and for interrupts
+const BOOT_FILL_MARGIN: u64 = 1024;
+
+// Lowest address of the boot stack, once it has been filled
+static BOOT_STACK_BOTTOM: AtomicU64 = AtomicU64::new(0);
+
+const NUM_PRIORITIES: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Lower value = higher priority. A runnable task is never preempted by a task of lower priority,
+/// and whenever a higher-priority task becomes runnable it preempts the current one.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    High = 0,
+    Normal = 1,
+    Idle = 2,
+}
+
+impl Priority {
+    /// Length of a time slice in milliseconds.
+    pub fn time_slice(&self) -> u64 {
+        match self {
+            Priority::High => 20,
+            Priority::Normal => 10,
+            Priority::Idle => 5,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskState {
+    Running,
+    Ready,
+    Blocked,
+    Sleeping(u64), // Tick to wake up at
+    Dead,
+}
+
+/// Scheduler accounting for a single task. All times are in milliseconds.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TaskStats {
+    /// Time spent running on the CPU
+    pub runtime: u64,
+    /// Time spent runnable, but waiting for another task to give up the CPU
+    pub wait_time: u64,
+    /// Number of times the task was woken up after blocking or sleeping
+    pub wake_count: u64,
+    /// Number of times the task was switched to
+    pub switches: u64,
+}
+
+pub struct Task {
+    id: TaskId,
+    name: &'static str,
+    priority: Priority,
+    state: TaskState,
+    context: Context,
+    // None for the boot task, which runs on the stack the bootloader gave us.
+    stack: Option<Stack>,
+    slice_remaining: u64,
+    ready_since: u64,
+    stats: TaskStats,
+}
+
+impl Task {
+    fn new(id: TaskId, name: &'static str, priority: Priority) -> Self {
+        Self {
+            id,
+            name,
+            priority,
+            state: TaskState::Ready,
+            context: Context::empty(),
+            stack: None,
+            slice_remaining: priority.time_slice(),
+            ready_since: 0,
+            stats: TaskStats::default(),
+        }
+    }
+
+    // Lowest address and size of the task's stack. None for the boot task if its stack wasn't
+    // filled, since nothing can be told about it then.
+    fn stack_bounds(&self) -> Option<(*const u8, usize)> {
+        match &self.stack {
+            Some(stack) => Some((stack.bottom(), stack.len())),
+            None => boot_stack().filter(|_| self.id == TaskId(0)),
+        }
+    }
+}
+
+/// Deepest the stack from `bottom` up has ever been, in bytes.
+/// ### Safety
+/// The stack has to stay allocated while it's looked at.
+unsafe fn stack_high_water(bottom: *const u8, size: usize) -> usize {
+    // The stack may be the one in use right now, so it isn't borrowed as a slice
+    let untouched = (0..size)
+        .take_while(|&i| bottom.add(i).read_volatile() == STACK_FILL)
+        .count();
+    size - untouched
+}
+
+/// A copy of a task's state and statistics, for reporting (e.g. by `ps`).
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: &'static str,
+    pub priority: Priority,
+    pub state: TaskState,
+    pub stats: TaskStats,
+    pub stack_used: Option<usize>,
+    pub stack_size: Option<usize>,
+    // Where the stack starts, for working out `stack_used` once the scheduler is unlocked
+    stack_bottom: Option<usize>,
+}
+
+impl From<&Task> for TaskInfo {
+    fn from(task: &Task) -> Self {
+        Self {
+            id: task.id,
+            name: task.name,
+            priority: task.priority,
+            state: task.state,
+            stats: task.stats,
+            stack_used: None,
+            stack_size: task.stack_bounds().map(|(_, size)| size),
+            stack_bottom: task.stack_bounds().map(|(bottom, _)| bottom as usize),
+        }
+    }
+}
+
+type TaskEntry = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fill the unused part of the boot stack with `STACK_FILL`, so that the boot task's stack usage
+/// can be measured like that of any other task.
+/// ### Safety
+/// Has to be called on the boot stack, with interrupts disabled, before anything has been deeper
+/// on it than the caller (i.e. first thing in the bootloader's entry point, see `bootinfo`).
+#[inline(never)]
+pub unsafe fn fill_boot_stack() {
+    let rsp = cpu::read_rsp();
+    // The bootloader starts the kernel at the top of the stack, which is on a page boundary
+    let top = (rsp + Arch::PAGE_SIZE - 1) & !(Arch::PAGE_SIZE - 1);
+    let bottom = top - BOOT_STACK_SIZE;
+
+    let end = rsp - BOOT_FILL_MARGIN;
+    let mut addr = bottom;
+    while addr < end {
+        (addr as *mut u8).write_volatile(STACK_FILL);
+        addr += 1;
+    }
+
+    BOOT_STACK_BOTTOM.store(bottom, Ordering::Relaxed);
+}
+
+fn boot_stack() -> Option<(*const u8, usize)> {
+    match BOOT_STACK_BOTTOM.load(Ordering::Relaxed) {
+        0 => None,
+        bottom => Some((bottom as *const u8, BOOT_STACK_SIZE as usize)),
+    }
+}
+
+/// Log how much of its stack every task has used so far.
+pub fn log_stack_usage() {
+    for info in snapshot() {
+        if let (Some(used), Some(size)) = (info.stack_used, info.stack_size) {
+            log_info!(
+                "Task {} ({}) used {} of {} bytes of stack",
+                info.id,
+                info.name,
+                used,
+                size
+            );
+        }
+    }
+}
+
+/// Turn the currently running code into the first task and start scheduling. The caller is
+/// registered as "kmain" with normal priority, and an idle task is spawned which halts the CPU
+/// whenever nothing else is runnable.
+/// ### Safety
+/// Should only be called once, after the heap is initialized.
+pub unsafe fn init() {
+    let mut main_task = Box::new(Task::new(TaskId(0), "kmain", Priority::Normal));
+    main_task.state = TaskState::Running;
+
+    let _ = SCHEDULER.set(Mutex::new(scheduler::Scheduler::new(main_task)));
+
+    spawn("idle", Priority::Idle, idle).expect("Failed to spawn idle task");
+}
+
+fn idle() {
+    let mut zombies = Vec::with_capacity(scheduler::MAX_TASKS);
+    loop {
+        reap(&mut zombies);
+        Arch::halt();
+    }
+}
+
+/// Free the stacks of tasks that have exited, using `zombies` to hold them until the scheduler is
+/// unlocked.
+#[allow(clippy::vec_box)]
+fn reap(zombies: &mut Vec<Box<Task>>) {
+    interrupts::without_interrupts(|| {
+        if let Some(lock) = SCHEDULER.get() {
+            lock.lock().take_zombies(zombies);
+        }
+    });
+
+    zombies.clear();
+}
+
+/// Spawn a new kernel task running `f`. Returns Err if there are already too many tasks.
+pub fn spawn<F>(name: &'static str, priority: Priority, f: F) -> Result<TaskId, ()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_on(Stack::heap(KERNEL_STACK_SIZE), name, priority, f)
+}
+
+/// Spawn a new kernel task running `f` on a mapped stack of `stack_size` bytes (up to
+/// `stack::MAX_STACK_SIZE`), which faults rather than overflowing into other memory. Returns Err
+/// if there are already too many tasks, or the stack can't be mapped.
+pub fn spawn_with_stack<F>(
+    name: &'static str,
+    priority: Priority,
+    stack_size: usize,
+    f: F,
+) -> Result<TaskId, ()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_on(Stack::map(stack_size)?, name, priority, f)
+}
+
+fn spawn_on<F>(stack: Stack, name: &'static str, priority: Priority, f: F) -> Result<TaskId, ()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let entry: Box<TaskEntry> = Box::new(Box::new(f));
+    let entry_ptr = Box::into_raw(entry);
+
+    // Everything is allocated before the scheduler is locked; the ID is filled in once it is
+    let mut task = Box::new(Task::new(TaskId(0), name, priority));
+    task.context = unsafe { Context::prepare(stack.top(), entry_ptr as u64) };
+    task.stack = Some(stack);
+
+    let added = interrupts::without_interrupts(|| {
+        let Some(lock) = SCHEDULER.get() else {
+            return Err(task);
+        };
+        let mut sched = lock.lock();
+        task.id = sched.next_id();
+        sched.add(task)
+    });
+
+    // A task that never ran still owns its entry point. Unmapping a mapped stack may have to wait
+    // for other processors, so it is only freed once interrupts are back on.
+    added.map_err(|task| {
+        drop(unsafe { Box::from_raw(entry_ptr) });
+        drop(task);
+    })
+}
+
+/// First Rust code a new task runs, called from `task_trampoline`.
+extern "C" fn task_start(entry: *mut TaskEntry) -> ! {
+    // We got here from `schedule`, which always runs with interrupts disabled.
+    interrupts::enable();
+
+    let entry = unsafe { Box::from_raw(entry) };
+    (*entry)();
+
+    exit()
+}
+
+/// End the current task. Its stack is freed later by the idle task.
+pub fn exit() -> ! {
+    interrupts::disable();
+
+    if let Some(lock) = SCHEDULER.get() {
+        lock.lock().exit_current();
+    }
+
+    unsafe { scheduler::schedule() };
+    unreachable!("Dead task was scheduled again");
+}
+
+/// Whether tasks can be switched yet (i.e. `init` has been called).
+pub fn is_running() -> bool {
+    SCHEDULER.get().is_some()
+}
+
+pub fn current() -> Option<TaskId> {
+    interrupts::without_interrupts(|| SCHEDULER.get().map(|lock| lock.lock().current()))
+}
+
+/// Snapshot of every live task, ordered by id. Nothing is allocated and no stack is scanned with
+/// the scheduler locked, as a task holding the heap lock couldn't run to let go of it.
+pub fn snapshot() -> Vec<TaskInfo> {
+    let Some(lock) = SCHEDULER.get() else {
+        return Vec::new();
+    };
+
+    let mut infos = Vec::with_capacity(scheduler::MAX_TASKS);
+    interrupts::without_interrupts(|| lock.lock().snapshot(&mut infos));
+    for info in infos.iter_mut() {
+        if let (Some(bottom), Some(size)) = (info.stack_bottom, info.stack_size) {
+            // Nothing is reaped until `end_snapshot`, so the stack is still there
+            info.stack_used = Some(unsafe { stack_high_water(bottom as *const u8, size) });
+        }
+    }
+    interrupts::without_interrupts(|| lock.lock().end_snapshot());
+    infos
+}
+
+/// Give up the rest of this time slice to another task of the same or higher priority.
+pub fn yield_now() {
+    interrupts::without_interrupts(|| unsafe { scheduler::schedule() });
+}
+
+/// Sleep for at least `ticks` timer ticks (milliseconds). Falls back to spinning before the
+/// scheduler is up.
+pub fn sleep_ticks(ticks: u64) {
+    let wake_tick = TIMER.load(Ordering::SeqCst) + ticks;
+
+    match SCHEDULER.get() {
+        Some(lock) => interrupts::without_interrupts(|| {
+            lock.lock().sleep_current(wake_tick);
+            unsafe { scheduler::schedule() };
+        }),
+        None => {
+            while TIMER.load(Ordering::SeqCst) < wake_tick {
+                unsafe { Arch::io_wait() };
+            }
+        }
+    }
+}
+
+/// Make a blocked or sleeping task runnable again. Safe to call from interrupt handlers; if the
+/// woken task has a higher priority, the handler should finish with
+/// `scheduler::preempt_if_needed()`.
+pub fn wake(id: TaskId) {
+    interrupts::without_interrupts(|| {
+        if let Some(lock) = SCHEDULER.get() {
+            lock.lock().wake(id);
+        }
+    });
+}
+
+/// A list of tasks blocked waiting on some event, e.g. an interrupt.
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<TaskId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Block the current task until `wake_one` or `wake_all` is called, as long as `condition`
+    /// still holds. The condition is checked with interrupts disabled, so a wakeup from an
+    /// interrupt handler can't slip in between checking it and going to sleep.
+    pub fn wait_while<F: Fn() -> bool>(&self, condition: F) {
+        interrupts::without_interrupts(|| {
+            if !condition() {
+                return;
+            }
+
+            let sched_lock = match SCHEDULER.get() {
+                Some(lock) => lock,
+                None => {
+                    // Nothing to switch to, so just wait for the next interrupt
+                    interrupts::enable_and_hlt();
+                    interrupts::disable();
+                    return;
+                }
+            };
+
+            {
+                let mut sched = sched_lock.lock();
+                self.waiters.lock().push_back(sched.current());
+                sched.block_current();
+            }
+
+            unsafe { scheduler::schedule() };
+        })
+    }
+
+    pub fn wake_one(&self) {
+        if let Some(id) = interrupts::without_interrupts(|| self.waiters.lock().pop_front()) {
+            wake(id);
+        }
+    }
+
+    pub fn wake_all(&self) {
+        while let Some(id) = interrupts::without_interrupts(|| self.waiters.lock().pop_front()) {
+            wake(id);
+        }
+    }
+}
@@ -0,0 +1,291 @@
+use super::context::{switch_context, Context};
+use super::{Priority, Task, TaskId, TaskInfo, TaskState, NUM_PRIORITIES};
+use crate::klib::clock;
+use crate::klib::once_lock::OnceLock;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Upper bound on the number of live tasks. The run queues are allocated up front with this
+/// capacity, so that nothing called from the timer interrupt ever has to allocate.
+pub const MAX_TASKS: usize = 64;
+
+pub static SCHEDULER: OnceLock<Mutex<Scheduler>> = OnceLock::new();
+
+// Tasks are always boxed, since `switch_context` is handed a pointer to the saved stack pointer
+// inside of them, which has to stay put when the task moves between collections.
+pub struct Scheduler {
+    tasks: BTreeMap<TaskId, Box<Task>>,
+    run_queues: [VecDeque<TaskId>; NUM_PRIORITIES],
+    sleeping: Vec<TaskId>,
+    #[allow(clippy::vec_box)]
+    zombies: Vec<Box<Task>>,
+    current: TaskId,
+    next_id: u64,
+    need_resched: bool,
+    // Snapshots still looking at the tasks' stacks, which have to be kept until they're done
+    snapshots: usize,
+    // When `tick` last charged the running task
+    last_tick: u64,
+}
+
+impl Scheduler {
+    /// Create a scheduler whose first task is whatever is currently running (i.e. the boot
+    /// stack), as `main_task`.
+    pub fn new(main_task: Box<Task>) -> Self {
+        let id = main_task.id;
+        let mut tasks = BTreeMap::new();
+        tasks.insert(id, main_task);
+
+        Self {
+            tasks,
+            run_queues: core::array::from_fn(|_| VecDeque::with_capacity(MAX_TASKS)),
+            sleeping: Vec::with_capacity(MAX_TASKS),
+            zombies: Vec::with_capacity(MAX_TASKS),
+            current: id,
+            next_id: id.0 + 1,
+            need_resched: false,
+            snapshots: 0,
+            last_tick: clock::uptime_ms(),
+        }
+    }
+
+    pub fn next_id(&mut self) -> TaskId {
+        let id = TaskId(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    pub fn current(&self) -> TaskId {
+        self.current
+    }
+
+    pub fn current_task(&mut self) -> &mut Task {
+        // The current task can never be missing from the map; it is only removed on reaping,
+        // which never happens to the running task.
+        self.tasks.get_mut(&self.current).unwrap()
+    }
+
+    pub fn add(&mut self, task: Box<Task>) -> Result<TaskId, Box<Task>> {
+        if self.tasks.len() >= MAX_TASKS {
+            return Err(task);
+        }
+
+        let id = task.id;
+        let priority = task.priority;
+        self.tasks.insert(id, task);
+        self.make_ready(id, priority);
+        Ok(id)
+    }
+
+    fn make_ready(&mut self, id: TaskId, priority: Priority) {
+        if let Some(task) = self.tasks.get_mut(&id) {
+            task.state = TaskState::Ready;
+            task.ready_since = clock::uptime_ms();
+        }
+        self.run_queues[priority as usize].push_back(id);
+
+        if priority < self.current_priority() {
+            self.need_resched = true;
+        }
+    }
+
+    fn current_priority(&self) -> Priority {
+        match self.tasks.get(&self.current) {
+            Some(task) if task.state == TaskState::Running => task.priority,
+            // If the current task is going to sleep, anything is better than it
+            _ => Priority::Idle,
+        }
+    }
+
+    /// Wake up a blocked or sleeping task. Does nothing if the task is already runnable.
+    pub fn wake(&mut self, id: TaskId) {
+        let priority = match self.tasks.get(&id) {
+            Some(task) => match task.state {
+                TaskState::Blocked | TaskState::Sleeping(_) => task.priority,
+                _ => return,
+            },
+            None => return,
+        };
+
+        if let Some(pos) = self.sleeping.iter().position(|&t| t == id) {
+            self.sleeping.swap_remove(pos);
+        }
+
+        if let Some(task) = self.tasks.get_mut(&id) {
+            task.stats.wake_count += 1;
+        }
+
+        self.make_ready(id, priority);
+    }
+
+    /// Take the current task off the CPU until `wake` is called on it.
+    pub fn block_current(&mut self) {
+        self.current_task().state = TaskState::Blocked;
+    }
+
+    /// Take the current task off the CPU until the timer reaches `wake_tick`.
+    pub fn sleep_current(&mut self, wake_tick: u64) {
+        self.current_task().state = TaskState::Sleeping(wake_tick);
+        let id = self.current;
+        self.sleeping.push(id);
+    }
+
+    pub fn exit_current(&mut self) {
+        self.current_task().state = TaskState::Dead;
+    }
+
+    /// Move every task that has exited into `reaped`, which has room for `MAX_TASKS` of them so
+    /// that nothing is allocated here. Called from task context so that the stacks can be freed
+    /// without the allocator lock ever being taken from an interrupt.
+    #[allow(clippy::vec_box)]
+    pub fn take_zombies(&mut self, reaped: &mut Vec<Box<Task>>) {
+        if self.snapshots > 0 {
+            return;
+        }
+
+        let current = self.current;
+        let mut i = 0;
+        while i < self.zombies.len() && reaped.len() < reaped.capacity() {
+            if self.zombies[i].id != current {
+                reaped.push(self.zombies.swap_remove(i));
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Copy every task's details into `infos`, without allocating: anything past its capacity is
+    /// left out. No task is reaped until `end_snapshot`, so their stacks can be looked at after the
+    /// scheduler is unlocked.
+    pub fn snapshot(&mut self, infos: &mut Vec<TaskInfo>) {
+        self.snapshots += 1;
+        let room = infos.capacity() - infos.len();
+        let tasks = self.tasks.values().take(room);
+        infos.extend(tasks.map(|task| TaskInfo::from(&**task)));
+    }
+
+    pub fn end_snapshot(&mut self) {
+        self.snapshots -= 1;
+    }
+
+    /// Per-tick accounting: charge the running task for the time since the last tick, and wake
+    /// any sleepers that are due. Returns whether the current task should be preempted.
+    fn tick(&mut self, now: u64) -> bool {
+        let elapsed = now - self.last_tick;
+        self.last_tick = now;
+        let task = self.current_task();
+        task.stats.runtime += elapsed;
+        task.slice_remaining = task.slice_remaining.saturating_sub(elapsed);
+        let slice_expired = task.slice_remaining == 0;
+
+        let mut i = 0;
+        while i < self.sleeping.len() {
+            let id = self.sleeping[i];
+            match self.tasks.get(&id).map(|t| t.state) {
+                Some(TaskState::Sleeping(wake_tick)) if wake_tick > now => i += 1,
+                Some(TaskState::Sleeping(_)) => self.wake(id),
+                _ => {
+                    self.sleeping.swap_remove(i);
+                }
+            }
+        }
+
+        let resched = slice_expired || self.need_resched;
+        self.need_resched = false;
+        resched
+    }
+
+    /// Pick the next task to run. Returns the context to save the current task into and the one
+    /// to switch to, or None if the current task should just keep running.
+    fn pick_next(&mut self) -> Option<(*mut Context, *const Context)> {
+        let current = self.current;
+        let (state, priority) = {
+            let task = self.current_task();
+            (task.state, task.priority)
+        };
+
+        if state == TaskState::Running {
+            self.make_ready(current, priority);
+        }
+
+        self.need_resched = false;
+
+        let next = self.run_queues.iter_mut().find_map(|queue| queue.pop_front())?;
+
+        let now = clock::uptime_ms();
+        let next_task = self.tasks.get_mut(&next)?;
+        next_task.state = TaskState::Running;
+        next_task.slice_remaining = next_task.priority.time_slice();
+        next_task.stats.wait_time += now.saturating_sub(next_task.ready_since);
+        let new_context = &next_task.context as *const Context;
+
+        if next == current {
+            return None;
+        }
+
+        next_task.stats.switches += 1;
+
+        self.current = next;
+        crate::trace!(Sched, "switch {:?} -> {:?}", current, next);
+
+        let old_task = self.tasks.get_mut(&current)?;
+        let old_context = &mut old_task.context as *mut Context;
+
+        if old_task.state == TaskState::Dead {
+            // It is still running on its own stack until the switch, so it can only be freed
+            // later by some other task.
+            let dead = self.tasks.remove(&current)?;
+            self.zombies.push(dead);
+            let dead_context = &mut self.zombies.last_mut()?.context as *mut Context;
+            return Some((dead_context, new_context));
+        }
+
+        Some((old_context, new_context))
+    }
+}
+
+/// Switch to the best runnable task, if it isn't the current one.
+/// ### Safety
+/// Must be called with interrupts disabled and no scheduler lock held.
+pub unsafe fn schedule() {
+    let switch = match SCHEDULER.get() {
+        Some(lock) => lock.lock().pick_next(),
+        None => None,
+    };
+
+    // Tasks are boxed and only freed by another task after they are switched away from, so both
+    // contexts stay put even though the lock has been dropped
+    if let Some((old, new)) = switch {
+        (*old).fpu.save();
+        (*new).fpu.restore();
+        switch_context(&mut (*old).rsp, (*new).rsp);
+    }
+}
+
+/// Called from the timer interrupt, after the end of interrupt has been sent.
+pub fn tick() {
+    let resched = match SCHEDULER.get() {
+        Some(lock) => lock.lock().tick(clock::uptime_ms()),
+        None => false,
+    };
+
+    if resched {
+        unsafe { schedule() };
+    }
+}
+
+/// Reschedule if something woken up from an interrupt handler outranks the current task.
+/// Should be called at the very end of an interrupt handler, after the end of interrupt.
+pub fn preempt_if_needed() {
+    let resched = match SCHEDULER.get() {
+        Some(lock) => lock.lock().need_resched,
+        None => false,
+    };
+
+    if resched {
+        unsafe { schedule() };
+    }
+}
@@ -0,0 +1,242 @@
+// Stacks for tasks that need more than the `KERNEL_STACK_SIZE` they get from the heap, and for
+// running known-deep code (like page table walks) on a stack of its own. Each big stack gets a slot
+// of its own in the stacks region, and is mapped at the top of it. The rest of the slot, at least
+// a page, is left unmapped, so that running off the bottom of the stack faults rather than quietly
+// overwriting whatever is below it. The region is put somewhere at random in `STACKS_RANGE`, and
+// stacks are given a free slot at random, so where a task's stack is can't be guessed from the
+// order tasks started in.
+//
+// Frames can't be allocated once boot is over (and the boot frame allocator can't take them back
+// anyway), so they come from a pool that `init` sets aside, and go back to it when a stack is
+// freed.
+
+use super::STACK_FILL;
+use crate::arch::x86_64::interrupts::without_interrupts;
+use crate::arch::x86_64::paging::BootInfoFrameAllocator;
+use crate::klib::tlb::MappingGuard;
+use crate::klib::{layout, rand};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::page::PageRange;
+use x86_64::structures::paging::{FrameAllocator, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+pub const MAX_STACK_SIZE: usize = 1024 * 1024;
+
+const PAGE_SIZE: u64 = 4096;
+
+// Nothing else maps anything here
+const STACKS_RANGE: Range<u64> = 0x_6666_0000_0000..0x_6766_0000_0000;
+// Room for the biggest stack, and a guard page below it
+const SLOT_SIZE: u64 = MAX_STACK_SIZE as u64 + PAGE_SIZE;
+// One per bit of `Pool::slots`
+const NUM_SLOTS: usize = 64;
+const REGION_SIZE: u64 = NUM_SLOTS as u64 * SLOT_SIZE;
+
+// Where `init` put the region
+static STACKS_START: AtomicU64 = AtomicU64::new(0);
+
+// 2 MiB
+const POOL_FRAMES: usize = 512;
+// Page tables a mapping may need on the way down to the stack's pages
+const MAX_TABLE_FRAMES: usize = 3;
+
+struct Pool {
+    frames: Vec<PhysFrame>,
+    // Bit n is set if slot n is in use
+    slots: u64,
+}
+
+static POOL: Mutex<Pool> = Mutex::new(Pool {
+    frames: Vec::new(),
+    slots: 0,
+});
+
+// The frames taken out of the pool for one stack
+struct Frames(Vec<PhysFrame>);
+
+unsafe impl FrameAllocator<Size4KiB> for Frames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.0.pop()
+    }
+}
+
+// Switch to the stack whose top is `stack_top`, call `f(arg)` on it, and switch back. rbp is
+// callee-saved, so it holds on to the old stack pointer across the call.
+global_asm!(
+    ".global call_on_stack",
+    "call_on_stack:",
+    "push rbp",
+    "mov rbp, rsp",
+    "and rdx, -16",
+    "mov rsp, rdx",
+    "call rsi",
+    "mov rsp, rbp",
+    "pop rbp",
+    "ret",
+);
+
+extern "C" {
+    fn call_on_stack(arg: *mut u8, f: extern "C" fn(*mut u8), stack_top: u64);
+}
+
+/// Pick where big stacks go and set aside the frames they are mapped with. Until then, mapping
+/// one fails.
+pub fn init(frame_allocator: &mut BootInfoFrameAllocator) {
+    let start = layout::place("stacks", STACKS_RANGE, REGION_SIZE, PAGE_SIZE);
+    STACKS_START.store(start, Ordering::Relaxed);
+
+    let frames: Vec<PhysFrame> = (0..POOL_FRAMES)
+        .map_while(|_| frame_allocator.allocate_frame())
+        .collect();
+    without_interrupts(|| POOL.lock().frames = frames);
+}
+
+pub struct Stack {
+    memory: Memory,
+}
+
+enum Memory {
+    Heap(Box<[u8]>),
+    Mapped { slot: usize, pages: usize },
+}
+
+impl Stack {
+    /// A stack of `size` bytes from the heap, with nothing to catch it overflowing.
+    pub fn heap(size: usize) -> Self {
+        Self {
+            memory: Memory::Heap(alloc::vec![STACK_FILL; size].into_boxed_slice()),
+        }
+    }
+
+    /// Map a stack of `size` bytes, rounded up to whole pages, with unmapped memory below it.
+    /// Fails if `size` is over `MAX_STACK_SIZE`, or there are no slots or frames left.
+    pub fn map(size: usize) -> Result<Self, ()> {
+        if size == 0 || size > MAX_STACK_SIZE {
+            return Err(());
+        }
+        let pages = size.div_ceil(PAGE_SIZE as usize);
+
+        // Take everything the mapping needs up front, so that the pool isn't locked while mapping.
+        // The first free slot from a random one on.
+        let first = if layout::randomized() {
+            rand::get_random_u64() as usize % NUM_SLOTS
+        } else {
+            0
+        };
+        let (slot, mut frames) = without_interrupts(|| {
+            let mut pool = POOL.lock();
+            let slot = (0..NUM_SLOTS)
+                .map(|i| (first + i) % NUM_SLOTS)
+                .find(|slot| pool.slots & (1 << slot) == 0)?;
+            let needed = pages + MAX_TABLE_FRAMES;
+            if pool.frames.len() < needed {
+                return None;
+            }
+            pool.slots |= 1 << slot;
+            let start = pool.frames.len() - needed;
+            Some((slot, Frames(pool.frames.split_off(start))))
+        })
+        .ok_or(())?;
+
+        // If mapping fails part way through, dropping the stack unmaps what was mapped
+        let stack = Self {
+            memory: Memory::Mapped { slot, pages },
+        };
+        let mapped = match MappingGuard::lock() {
+            Some(mut guard) => stack.pages().try_for_each(|page| {
+                let flags =
+                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+                let frame = frames.allocate_frame().ok_or(())?;
+                unsafe { guard.map(page, frame, flags, &mut frames) }.map_err(|_| {
+                    frames.0.push(frame);
+                })
+            }),
+            None => Err(()),
+        };
+        without_interrupts(|| POOL.lock().frames.append(&mut frames.0));
+        mapped?;
+
+        unsafe { core::ptr::write_bytes(stack.bottom() as *mut u8, STACK_FILL, stack.len()) };
+        Ok(stack)
+    }
+
+    /// Lowest address of the stack.
+    pub fn bottom(&self) -> *const u8 {
+        match &self.memory {
+            Memory::Heap(stack) => stack.as_ptr(),
+            Memory::Mapped { slot, pages } => {
+                let slot_end =
+                    STACKS_START.load(Ordering::Relaxed) + (*slot as u64 + 1) * SLOT_SIZE;
+                (slot_end - *pages as u64 * PAGE_SIZE) as *const u8
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.memory {
+            Memory::Heap(stack) => stack.len(),
+            Memory::Mapped { pages, .. } => pages * PAGE_SIZE as usize,
+        }
+    }
+
+    /// Address just past the end of the stack, where the stack pointer starts.
+    pub fn top(&self) -> u64 {
+        self.bottom() as u64 + self.len() as u64
+    }
+
+    // Empty for a heap stack
+    fn pages(&self) -> PageRange<Size4KiB> {
+        let bottom = Page::containing_address(VirtAddr::new(self.bottom() as u64));
+        match self.memory {
+            Memory::Heap(_) => Page::range(bottom, bottom),
+            Memory::Mapped { pages, .. } => Page::range(bottom, bottom + pages as u64),
+        }
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        let Memory::Mapped { slot, .. } = self.memory else {
+            return;
+        };
+
+        let mut frames = Vec::with_capacity(self.len() / PAGE_SIZE as usize);
+        if let Some(mut guard) = MappingGuard::lock() {
+            // Pages that never got mapped fail to unmap, which is fine
+            for page in self.pages() {
+                if let Ok(frame) = guard.unmap(page) {
+                    frames.push(frame);
+                }
+            }
+        }
+
+        // Dropping the guard shot the mappings down, so the frames can be used again
+        without_interrupts(|| {
+            let mut pool = POOL.lock();
+            pool.frames.append(&mut frames);
+            pool.slots &= !(1 << slot);
+        });
+    }
+}
+
+/// Run `f` on a freshly mapped stack of `size` bytes, for code that is known to go deeper than a
+/// task's stack allows. Fails like `Stack::map`, without calling `f`. Can't be called with
+/// interrupts disabled, as mapping the stack may have to wait for other processors.
+pub fn with_stack<F: FnOnce() -> R, R>(size: usize, f: F) -> Result<R, ()> {
+    // The closure goes in, and its result comes back out, through `state`
+    extern "C" fn run<F: FnOnce() -> R, R>(state: *mut u8) {
+        let (f, result) = unsafe { &mut *(state as *mut (Option<F>, Option<R>)) };
+        *result = f.take().map(|f| f());
+    }
+
+    let stack = Stack::map(size)?;
+    let mut state: (Option<F>, Option<R>) = (Some(f), None);
+    let state_ptr = &mut state as *mut (Option<F>, Option<R>) as *mut u8;
+    unsafe { call_on_stack(state_ptr, run::<F, R>, stack.top()) };
+    state.1.ok_or(())
+}
@@ -1,6 +1,115 @@
+mod qemu_trace;
+mod telemetry;
+
 use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// Where `cargo run -- trace` has QEMU write its trace, unless given another file
+const DEFAULT_TRACE_PATH: &str = "qemu-trace.log";
+
+// Where `cargo run -- telemetry` has the kernel's telemetry written, unless given another file
+const DEFAULT_TELEMETRY_PATH: &str = "telemetry.jsonl";
+
+// Where the debugcon's output goes with the `qemu` feature
+const DEBUGCON_PATH: &str = "debugcon.log";
+
+// What QEMU exits with when the kernel exits it through isa-debug-exit, see
+// kernel/src/klib/qemu.rs
+const QEMU_EXIT_SUCCESS: i32 = (0x10 << 1) | 1;
+const QEMU_EXIT_FAILURE: i32 = (0x11 << 1) | 1;
+
+// QEMU's AHCI controller has this many ports. The main disk is on the first one, and disks
+// added with `--disk` go on the rest in order.
+const AHCI_PORTS: usize = 6;
+
+// Has to match the layout in kernel/src/klib/crashdump.rs
+const CRASHDUMP_SIZE: u64 = 64 * 1024;
+const CRASHDUMP_HEADER_SIZE: usize = 512;
+const CRASHDUMP_MAGIC: &[u8; 8] = b"PANODUMP";
+
+// Flags that can go anywhere on the command line, along with what's left once they're taken out
+struct Options {
+    // `--disk <image>`, which can be given more than once
+    disks: Vec<String>,
+    // `--snapshot`
+    snapshot: bool,
+    args: Vec<String>,
+}
+
+impl Options {
+    fn parse(mut cli_args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options {
+            disks: Vec::new(),
+            snapshot: false,
+            args: Vec::new(),
+        };
+
+        while let Some(arg) = cli_args.next() {
+            match arg.as_str() {
+                "--disk" => {
+                    let path = cli_args.next().ok_or("--disk needs a disk image")?;
+                    options.disks.push(path);
+                }
+                "--snapshot" => options.snapshot = true,
+                _ => options.args.push(arg),
+            }
+        }
+
+        if options.disks.len() > AHCI_PORTS - 1 {
+            return Err(format!(
+                "at most {} extra disks fit on the AHCI controller",
+                AHCI_PORTS - 1
+            ));
+        }
+        Ok(options)
+    }
+}
 
 fn main() {
+    // `--disk <image>` attaches another disk image to the next free AHCI port, e.g. an ext2 and a
+    // FAT32 image to test filesystems on. `--snapshot` has QEMU keep every write in a temporary
+    // file, so that tests that write to the disks leave the images as they were (crash dumps
+    // included).
+    let options = match Options::parse(std::env::args()) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(2);
+        }
+    };
+
+    // `cargo run -- crashdump [disk image]` prints the dump from the last panic instead of booting
+    let cli_args = &options.args;
+    if cli_args.get(1).map(String::as_str) == Some("crashdump") {
+        let path = cli_args.get(2).map_or("img/disk.img", String::as_str);
+        if let Err(message) = print_crashdump(path) {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // `cargo run -- trace [log file]` has QEMU write its AHCI trace to a file instead of stdout,
+    // and prints a line for each command as they complete
+    let trace_path = (cli_args.get(1).map(String::as_str) == Some("trace")).then(|| {
+        cli_args
+            .get(2)
+            .map_or(DEFAULT_TRACE_PATH, String::as_str)
+            .to_string()
+    });
+
+    // `cargo run -- telemetry [file]` gives the machine a second serial port, which the kernel
+    // sends its stats out of, writing to a file that's summed up once QEMU exits
+    let telemetry_path = (cli_args.get(1).map(String::as_str) == Some("telemetry")).then(|| {
+        cli_args
+            .get(2)
+            .map_or(DEFAULT_TELEMETRY_PATH, String::as_str)
+            .to_string()
+    });
+
     // read env variables that were set in build script
     // let uefi_path = env!("UEFI_PATH");
     let bios_path = env!("BIOS_PATH");
@@ -22,8 +131,37 @@ fn main() {
         .arg("file=img/disk.img,if=none,format=raw,id=maindisk");
     cmd.arg("-device").arg("ahci,id=ahci");
     cmd.arg("-device").arg("ide-hd,drive=maindisk,bus=ahci.0");
-    cmd.arg("-d")
-        .arg("trace:ahci_port_write,trace:ahci_check_irq,trace:ahci_port_read,trace:handle_cmd_*");
+    for (i, path) in options.disks.iter().enumerate() {
+        let port = i + 1;
+        cmd.arg("-drive")
+            .arg(format!("file={path},if=none,format=raw,id=disk{port}"));
+        cmd.arg("-device")
+            .arg(format!("ide-hd,drive=disk{port},bus=ahci.{port}"));
+    }
+    if options.snapshot {
+        cmd.arg("-snapshot");
+    }
+    if let Some(path) = &trace_path {
+        // Start with an empty file, so only this run gets summarized
+        let _ = std::fs::remove_file(path);
+        cmd.arg("-d").arg(qemu_trace::EVENTS);
+        cmd.arg("-D").arg(path);
+        cmd.arg("-msg").arg("timestamp=on");
+    }
+    if let Some(path) = &telemetry_path {
+        // COM1 goes nowhere, so the file is on COM2
+        cmd.arg("-serial").arg("null");
+        cmd.arg("-serial").arg(format!("file:{path}"));
+    }
+    #[cfg(feature = "qemu")]
+    {
+        cmd.arg("-device")
+            .arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
+        cmd.arg("-debugcon").arg(format!("file:{DEBUGCON_PATH}"));
+    }
+    // The kernel can trace AHCI command flow itself too (`trace on ahci`, then `trace dump`)
+    // cmd.arg("-d")
+    //     .arg("trace:ahci_port_write,trace:ahci_check_irq,trace:ahci_port_read,trace:handle_cmd_*");
     // cmd.arg("-d").arg("trace:handle_cmd_*");
     // cmd.arg("-d").arg("trace:ahci_trigger_irq");
     // cmd.arg("-d").arg("int");
@@ -42,8 +180,72 @@ fn main() {
     args.iter()
         .for_each(|arg| println!("{}", arg.to_str().unwrap()));
     let mut child = cmd.spawn().unwrap();
+
+    let stop_following = Arc::new(AtomicBool::new(false));
+    let follower = trace_path.map(|path| qemu_trace::follow(path, stop_following.clone()));
+
     // let mut cmd = std::process::Command::new("gdb");
     // let mut gdb_child = cmd.spawn().unwrap();
     // gdb_child.wait().unwrap();
-    child.wait().unwrap();
+    let status = child.wait().unwrap();
+
+    stop_following.store(true, Ordering::SeqCst);
+    if let Some(follower) = follower {
+        follower.join().unwrap();
+    }
+    if let Some(path) = &telemetry_path {
+        telemetry::summarize(path);
+    }
+
+    // QEMU closed any other way only counts as failing when the self-tests should have exited it
+    let failed = match status.code() {
+        Some(QEMU_EXIT_SUCCESS) => false,
+        Some(QEMU_EXIT_FAILURE) => true,
+        _ => cfg!(feature = "selftest"),
+    };
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+fn print_crashdump(path: &str) -> Result<(), String> {
+    let mut file = File::open(path).map_err(|e| format!("couldn't open {}: {}", path, e))?;
+    let len = file
+        .metadata()
+        .map_err(|e| format!("couldn't read {}: {}", path, e))?
+        .len();
+    let Some(start) = len.checked_sub(CRASHDUMP_SIZE) else {
+        return Err(format!("{} is too small to hold a crash dump", path));
+    };
+
+    let mut dump = vec![0u8; CRASHDUMP_SIZE as usize];
+    file.seek(SeekFrom::Start(start))
+        .and_then(|_| file.read_exact(&mut dump))
+        .map_err(|e| format!("couldn't read {}: {}", path, e))?;
+
+    let (header, body) = dump.split_at(CRASHDUMP_HEADER_SIZE);
+    if &header[0..8] != CRASHDUMP_MAGIC {
+        println!("no crash dump in {}", path);
+        return Ok(());
+    }
+
+    let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    let length = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(header[16..20].try_into().unwrap());
+    let uptime_ms = u64::from_le_bytes(header[24..32].try_into().unwrap());
+
+    let text = &body[..length.min(body.len())];
+    let hash = text.iter().fold(0x811C_9DC5u32, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    });
+
+    println!(
+        "crash dump version {}, {} ms after boot",
+        version, uptime_ms
+    );
+    if hash != checksum {
+        println!("(checksum mismatch, the dump may be incomplete)");
+    }
+    println!("{}", String::from_utf8_lossy(text));
+    Ok(())
 }
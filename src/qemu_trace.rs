@@ -0,0 +1,219 @@
+// Follows the AHCI trace QEMU writes to its log file and boils it down to one line per NCQ
+// command: which slot (tag) it used, where on the disk it went, how much it moved, how long the
+// disk took, and how long after that the interrupt was raised.
+//
+// QEMU log lines look like `1234@1700000000.123456:ncq_finish ahci(0x...)[0][tag:3]: ...` with
+// `-msg timestamp=on`; the `pid@time:` prefix is left out without it, in which case there are no
+// latencies to report.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// The QEMU trace events the summary is built from.
+pub const EVENTS: &str = "trace:execute_ncq_command_*,trace:ncq_finish,trace:ahci_trigger_irq";
+
+// QEMU reports NCQ transfers in 512 byte sectors, whatever the disk's logical sector size
+const SECTOR_SIZE: u64 = 512;
+
+// How long to wait for QEMU to write more before looking at the file again
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+struct Command {
+    write: bool,
+    lba: u64,
+    sectors: u64,
+    issued: Option<f64>,
+    finished: Option<f64>,
+}
+
+#[derive(Default)]
+pub struct Summarizer {
+    // Commands by tag, from when QEMU starts them until the interrupt for them is raised
+    pending: HashMap<u32, Command>,
+    commands: u64,
+    bytes: u64,
+}
+
+impl Summarizer {
+    /// Take in one line of the log. Returns the summary of a command if this line completed one.
+    pub fn feed(&mut self, line: &str) -> Option<String> {
+        let (time, event, message) = split_line(line)?;
+
+        if let Some(direction) = event.strip_prefix("execute_ncq_command_") {
+            let write = match direction {
+                "read" => false,
+                "write" => true,
+                _ => return None,
+            };
+            let command = Command {
+                write,
+                lba: number_after(message, "LBA ")?,
+                sectors: number_before(message, " sectors")?,
+                issued: time,
+                finished: None,
+            };
+            self.pending.insert(tag(message)?, command);
+            return None;
+        }
+
+        match event {
+            "ncq_finish" => {
+                let command = self.pending.get_mut(&tag(message)?)?;
+                command.finished = time;
+                None
+            }
+            // The driver only takes interrupts for NCQ completions, so any interrupt is for every
+            // command that had finished by then
+            "ahci_trigger_irq" => {
+                let done: Vec<u32> = self
+                    .pending
+                    .iter()
+                    .filter(|(_, command)| command.finished.is_some())
+                    .map(|(&tag, _)| tag)
+                    .collect();
+
+                let lines: Vec<String> = done
+                    .into_iter()
+                    .filter_map(|tag| {
+                        let command = self.pending.remove(&tag)?;
+                        Some(self.summarize(tag, &command, time))
+                    })
+                    .collect();
+                (!lines.is_empty()).then(|| lines.join("\n"))
+            }
+            _ => None,
+        }
+    }
+
+    fn summarize(&mut self, tag: u32, command: &Command, irq: Option<f64>) -> String {
+        let bytes = command.sectors * SECTOR_SIZE;
+        self.commands += 1;
+        self.bytes += bytes;
+
+        format!(
+            "slot {:>2} {:<5} LBA {:>10} {:>8} bytes  disk {:>9}  irq {:>9}",
+            tag,
+            if command.write { "write" } else { "read" },
+            command.lba,
+            bytes,
+            latency(command.issued, command.finished),
+            latency(command.finished, irq),
+        )
+    }
+
+    /// Commands that never got an interrupt, and totals for the whole run.
+    pub fn finish(&self) -> String {
+        let mut lines = Vec::new();
+        let mut stuck: Vec<_> = self.pending.iter().collect();
+        stuck.sort_by_key(|(&tag, _)| tag);
+        for (tag, command) in stuck {
+            lines.push(format!(
+                "slot {:>2} {:<5} LBA {:>10} never {}",
+                tag,
+                if command.write { "write" } else { "read" },
+                command.lba,
+                if command.finished.is_some() {
+                    "got an interrupt"
+                } else {
+                    "finished"
+                },
+            ));
+        }
+
+        lines.push(format!(
+            "{} commands completed, {} bytes",
+            self.commands, self.bytes
+        ));
+        lines.join("\n")
+    }
+}
+
+/// Print summaries for the log at `path` as QEMU writes it, until `stop` is set and everything
+/// written so far has been read.
+pub fn follow(path: String, stop: Arc<AtomicBool>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        // QEMU creates the file once it starts up
+        let file = loop {
+            match File::open(&path) {
+                Ok(file) => break file,
+                Err(_) if !stop.load(Ordering::SeqCst) => thread::sleep(POLL_INTERVAL),
+                Err(_) => return,
+            }
+        };
+
+        let mut reader = BufReader::new(file);
+        let mut summarizer = Summarizer::default();
+        let mut line = String::new();
+
+        loop {
+            // Check before reading, so nothing written before QEMU exited is missed
+            let stopping = stop.load(Ordering::SeqCst);
+
+            match reader.read_line(&mut line) {
+                Ok(0) | Err(_) if stopping => break,
+                Ok(0) | Err(_) => thread::sleep(POLL_INTERVAL),
+                // Only a partial line so far; the rest is read in on the next go
+                Ok(_) if !line.ends_with('\n') => {}
+                Ok(_) => {
+                    if let Some(summary) = summarizer.feed(&line) {
+                        println!("{}", summary);
+                    }
+                    line.clear();
+                }
+            }
+        }
+
+        println!("{}", summarizer.finish());
+    })
+}
+
+// Split a line into its timestamp (in seconds, if there is one), event name, and message
+fn split_line(line: &str) -> Option<(Option<f64>, &str, &str)> {
+    let line = line.trim_end();
+    let (time, rest) = match line.split_once(':') {
+        Some((prefix, rest)) if prefix.contains('@') => {
+            let time = prefix.split_once('@')?.1.parse().ok();
+            (time, rest)
+        }
+        _ => (None, line),
+    };
+
+    let (event, message) = rest.split_once(' ').unwrap_or((rest, ""));
+    Some((time, event, message))
+}
+
+// The NCQ tag, which QEMU prints as either `[tag:3]` or `tag 3`
+fn tag(message: &str) -> Option<u32> {
+    number_after(message, "tag:")
+        .or_else(|| number_after(message, "tag "))
+        .map(|tag| tag as u32)
+}
+
+fn number_after(message: &str, label: &str) -> Option<u64> {
+    let start = message.find(label)? + label.len();
+    let digits: String = message[start..]
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect();
+    digits.parse().ok()
+}
+
+fn number_before(message: &str, label: &str) -> Option<u64> {
+    let end = message.find(label)?;
+    let start = message[..end]
+        .rfind(|c: char| !c.is_ascii_digit())
+        .map_or(0, |i| i + 1);
+    message[start..end].parse().ok()
+}
+
+fn latency(from: Option<f64>, to: Option<f64>) -> String {
+    match (from, to) {
+        (Some(from), Some(to)) => format!("{:.0}us", (to - from) * 1e6),
+        _ => "-".to_string(),
+    }
+}
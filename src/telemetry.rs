@@ -0,0 +1,145 @@
+// Sums up the telemetry the kernel sent out of its second serial port, see
+// kernel/src/klib/telemetry.rs. Each line is a flat JSON object: "t" is the uptime in
+// milliseconds, and every other entry is a number. Names ending in `_total` count up from boot, so
+// what's shown for them is how fast they went up; the rest are shown as their lowest, average and
+// highest values. Either way a sparkline shows how it went over the run.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+// How many characters wide a sparkline gets at most
+const SPARKLINE_WIDTH: usize = 40;
+
+struct Sample {
+    time: f64,
+    value: f64,
+}
+
+/// Print a summary of the telemetry in the file at `path`.
+pub fn summarize(path: &str) {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) => {
+            println!("no telemetry in {}: {}", path, err);
+            return;
+        }
+    };
+
+    let mut metrics: BTreeMap<String, Vec<Sample>> = BTreeMap::new();
+    let mut lines = 0;
+    for line in BufReader::new(file).lines().map_while(Result::ok) {
+        // A line QEMU cut off as it exited, or noise from before the UART was set up
+        let Some(entries) = parse_line(&line) else {
+            continue;
+        };
+        let Some(time) = entries
+            .iter()
+            .find(|(name, _)| name == "t")
+            .map(|&(_, t)| t / 1000.0)
+        else {
+            continue;
+        };
+        for (name, value) in entries {
+            if name != "t" {
+                metrics
+                    .entry(name)
+                    .or_default()
+                    .push(Sample { time, value });
+            }
+        }
+        lines += 1;
+    }
+
+    if lines == 0 {
+        println!("no telemetry in {}", path);
+        return;
+    }
+    println!("{} samples from {}", lines, path);
+
+    let width = metrics.keys().map(String::len).max().unwrap_or(0);
+    for (name, samples) in &metrics {
+        let summary = if name.ends_with("_total") {
+            counter(samples)
+        } else {
+            gauge(samples)
+        };
+        println!("{:width$}  {}", name, summary, width = width);
+    }
+}
+
+// The rate a counter went up at over the whole run, with a sparkline of how much it went up by
+// between samples
+fn counter(samples: &[Sample]) -> String {
+    let (first, last) = (&samples[0], &samples[samples.len() - 1]);
+    let elapsed = last.time - first.time;
+    let rate = if elapsed > 0.0 {
+        (last.value - first.value) / elapsed
+    } else {
+        0.0
+    };
+    let deltas: Vec<f64> = samples
+        .windows(2)
+        .map(|pair| (pair[1].value - pair[0].value).max(0.0))
+        .collect();
+    format!(
+        "{:>12.1}/s  total {:<12}  {}",
+        rate,
+        last.value,
+        sparkline(&deltas)
+    )
+}
+
+fn gauge(samples: &[Sample]) -> String {
+    let values: Vec<f64> = samples.iter().map(|sample| sample.value).collect();
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let avg = values.iter().sum::<f64>() / values.len() as f64;
+    format!(
+        "min {:<12} avg {:<12.1} max {:<12}  {}",
+        min,
+        avg,
+        max,
+        sparkline(&values)
+    )
+}
+
+// Averaged into buckets if there are more values than fit
+fn sparkline(values: &[f64]) -> String {
+    if values.is_empty() {
+        return String::new();
+    }
+    let buckets: Vec<f64> = values
+        .chunks(values.len().div_ceil(SPARKLINE_WIDTH))
+        .map(|chunk| chunk.iter().sum::<f64>() / chunk.len() as f64)
+        .collect();
+
+    let min = buckets.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = buckets.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    buckets
+        .iter()
+        .map(|&value| {
+            if max > min {
+                let level = (value - min) / (max - min) * (SPARKS.len() - 1) as f64;
+                SPARKS[level.round() as usize]
+            } else {
+                SPARKS[0]
+            }
+        })
+        .collect()
+}
+
+// The entries of a line like `{"t":1000,"heap.free_bytes":12345}`. The kernel only ever sends
+// flat objects of numbers with no escapes in the names, so that's all this understands.
+fn parse_line(line: &str) -> Option<Vec<(String, f64)>> {
+    let body = line.trim().strip_prefix('{')?.strip_suffix('}')?;
+    body.split(',')
+        .map(|entry| {
+            let (name, value) = entry.split_once(':')?;
+            let name = name.trim().strip_prefix('"')?.strip_suffix('"')?;
+            Some((name.to_string(), value.trim().parse().ok()?))
+        })
+        .collect()
+}